@@ -19,6 +19,7 @@ use cros_fdt::Result;
 // This is a Battery related constant
 use devices::bat::GOLDFISHBAT_MMIO_LEN;
 use devices::pl030::PL030_AMBA_ID;
+use devices::tpm::TPM_TIS_MMIO_SIZE;
 use devices::IommuDevType;
 use devices::PciAddress;
 use devices::PciInterruptPin;
@@ -589,6 +590,14 @@ fn create_vmwdt_node(fdt: &mut Fdt, vmwdt_cfg: VmWdtConfig) -> Result<()> {
     Ok(())
 }
 
+fn create_tpm_tis_node(fdt: &mut Fdt, base: u64) -> Result<()> {
+    let reg = [base, TPM_TIS_MMIO_SIZE];
+    let tpm_node = fdt.root_mut().subnode_mut(&format!("tpm@{:x}", base))?;
+    tpm_node.set_prop("compatible", "tcg,tpm-tis-mmio")?;
+    tpm_node.set_prop("reg", &reg)?;
+    Ok(())
+}
+
 // Add a node path to __symbols__ node of the FDT, so it can be referenced by an overlay.
 fn add_symbols_entry(fdt: &mut Fdt, symbol: &str, path: &str) -> Result<()> {
     // Ensure the path points to a valid node with a defined phandle
@@ -625,6 +634,7 @@ fn add_symbols_entry(fdt: &mut Fdt, symbol: &str, path: &str) -> Result<()> {
 /// * `swiotlb` - Reserve a memory pool for DMA. Tuple of base address and size.
 /// * `bat_mmio_base_and_irq` - The battery base address and irq number
 /// * `vmwdt_cfg` - The virtual watchdog configuration
+/// * `tpm_tis_base` - The base address of the TPM TIS device, if any
 /// * `dump_device_tree_blob` - Option path to write DTB to
 /// * `vm_generator` - Callback to add additional nodes to DTB. create_vm uses Aarch64Vm::create_fdt
 pub fn create_fdt(
@@ -651,6 +661,7 @@ pub fn create_fdt(
     swiotlb: Option<(Option<GuestAddress>, u64)>,
     bat_mmio_base_and_irq: Option<(u64, u32)>,
     vmwdt_cfg: VmWdtConfig,
+    tpm_tis_base: Option<u64>,
     dump_device_tree_blob: Option<PathBuf>,
     vm_generator: &impl Fn(&mut Fdt, &BTreeMap<&str, u32>) -> cros_fdt::Result<()>,
     dynamic_power_coefficient: BTreeMap<usize, u32>,
@@ -702,6 +713,9 @@ pub fn create_fdt(
         create_battery_node(&mut fdt, bat_mmio_base, bat_irq)?;
     }
     create_vmwdt_node(&mut fdt, vmwdt_cfg)?;
+    if let Some(base) = tpm_tis_base {
+        create_tpm_tis_node(&mut fdt, base)?;
+    }
     create_kvm_cpufreq_node(&mut fdt)?;
     vm_generator(&mut fdt, &phandles)?;
     if !cpu_frequencies.is_empty() {
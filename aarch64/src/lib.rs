@@ -24,6 +24,8 @@ use base::MemoryMappingBuilder;
 use base::SendTube;
 use devices::serial_device::SerialHardware;
 use devices::serial_device::SerialParameters;
+use devices::tpm::TpmTis;
+use devices::tpm::TPM_TIS_MMIO_SIZE;
 use devices::vmwdt::VMWDT_DEFAULT_CLOCK_HZ;
 use devices::vmwdt::VMWDT_DEFAULT_TIMEOUT_SEC;
 use devices::Bus;
@@ -195,6 +197,9 @@ const AARCH64_VMWDT_ADDR: u64 = 0x3000;
 // The virtual watchdog device gets one 4k page
 const AARCH64_VMWDT_SIZE: u64 = 0x1000;
 
+// Place the TPM TIS device at pages 4 to 8
+const AARCH64_TPM_TIS_ADDR: u64 = 0x4000;
+
 // PCI MMIO configuration region base address.
 const AARCH64_PCI_CFG_BASE: u64 = 0x10000;
 // PCI MMIO configuration region size.
@@ -289,6 +294,8 @@ pub enum Error {
     RegisterIrqfd(base::Error),
     #[error("error registering PCI bus: {0}")]
     RegisterPci(BusError),
+    #[error("error registering TPM TIS device: {0}")]
+    RegisterTpmTis(BusError),
     #[error("error registering virtual cpufreq device: {0}")]
     RegisterVirtCpufreq(BusError),
     #[error("error registering virtual socket device: {0}")]
@@ -601,6 +608,20 @@ impl arch::LinuxArch for AArch64 {
             _vm_evt_wrtube,
         )?;
 
+        let tpm_tis_base = match components.tpm.take() {
+            Some(backend) => {
+                mmio_bus
+                    .insert(
+                        Arc::new(Mutex::new(TpmTis::new(backend))),
+                        AARCH64_TPM_TIS_ADDR,
+                        TPM_TIS_MMIO_SIZE,
+                    )
+                    .map_err(Error::RegisterTpmTis)?;
+                Some(AARCH64_TPM_TIS_ADDR)
+            }
+            None => None,
+        };
+
         let com_evt_1_3 = devices::IrqEdgeEvent::new().map_err(Error::CreateEvent)?;
         let com_evt_2_4 = devices::IrqEdgeEvent::new().map_err(Error::CreateEvent)?;
         arch::add_serial_devices(
@@ -753,6 +774,7 @@ impl arch::LinuxArch for AArch64 {
             }),
             bat_mmio_base_and_irq,
             vmwdt_cfg,
+            tpm_tis_base,
             dump_device_tree_blob,
             &|writer, phandles| vm.create_fdt(writer, phandles),
             components.dynamic_power_coefficient,
@@ -31,6 +31,7 @@ use base::FileGetLen;
 use base::FileReadWriteAtVolatile;
 use base::SendTube;
 use base::Tube;
+use devices::tpm::TpmBackend;
 use devices::virtio::VirtioDevice;
 use devices::BarRange;
 use devices::Bus;
@@ -321,6 +322,9 @@ pub struct VmComponents {
     pub ac_adapter: bool,
     pub acpi_sdts: Vec<SDT>,
     pub android_fstab: Option<File>,
+    /// A kernel loaded along the firmware given by `VmImage::Bios`, for the firmware to boot.
+    #[cfg(target_arch = "riscv64")]
+    pub bios_payload: Option<File>,
     pub bootorder_fw_cfg_blob: Vec<u8>,
     #[cfg(target_arch = "x86_64")]
     pub break_linux_pci_config_io: bool,
@@ -331,6 +335,8 @@ pub struct VmComponents {
         any(target_os = "android", target_os = "linux")
     ))]
     pub cpu_frequencies: BTreeMap<usize, Vec<u32>>,
+    #[cfg(target_arch = "riscv64")]
+    pub cpu_isa: RiscvIsaOptions,
     pub delay_rt: bool,
     pub dynamic_power_coefficient: BTreeMap<usize, u32>,
     pub extra_kernel_params: Vec<String>,
@@ -363,6 +369,9 @@ pub struct VmComponents {
     #[cfg(target_arch = "x86_64")]
     pub smbios: SmbiosOptions,
     pub swiotlb: Option<u64>,
+    /// The backend of a TPM to add to the platform devices, with a CRB interface on x86_64 and a
+    /// TIS interface elsewhere.
+    pub tpm: Option<Box<dyn TpmBackend>>,
     pub vcpu_affinity: Option<VcpuAffinity>,
     pub vcpu_count: usize,
     pub vm_image: VmImage,
@@ -1335,6 +1344,49 @@ pub struct SmbiosOptions {
     pub oem_strings: Vec<String>,
 }
 
+/// ISA extensions to enable (`true`) or disable (`false`) in the riscv64 VCPUs. The extensions
+/// that aren't given keep the default of the host.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, FromKeyValues, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct RiscvIsaOptions {
+    pub a: Option<bool>,
+    pub c: Option<bool>,
+    pub d: Option<bool>,
+    pub f: Option<bool>,
+    pub v: Option<bool>,
+    pub svpbmt: Option<bool>,
+    pub sstc: Option<bool>,
+    pub svinval: Option<bool>,
+    pub zihintpause: Option<bool>,
+    pub zicbom: Option<bool>,
+    pub zbb: Option<bool>,
+}
+
+#[cfg(target_arch = "riscv64")]
+impl RiscvIsaOptions {
+    /// Returns the extensions that were given, and whether they should be enabled.
+    pub fn extensions(&self) -> Vec<(hypervisor::IsaExtension, bool)> {
+        use hypervisor::IsaExtension;
+
+        [
+            (IsaExtension::A, self.a),
+            (IsaExtension::C, self.c),
+            (IsaExtension::D, self.d),
+            (IsaExtension::F, self.f),
+            (IsaExtension::V, self.v),
+            (IsaExtension::Svpbmt, self.svpbmt),
+            (IsaExtension::Sstc, self.sstc),
+            (IsaExtension::Svinval, self.svinval),
+            (IsaExtension::Zihintpause, self.zihintpause),
+            (IsaExtension::Zicbom, self.zicbom),
+            (IsaExtension::Zbb, self.zbb),
+        ]
+        .into_iter()
+        .filter_map(|(ext, enable)| Some((ext, enable?)))
+        .collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use serde_keyvalue::from_key_values;
@@ -1362,6 +1414,22 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn parse_riscv_isa_options() {
+        let res: RiscvIsaOptions = from_key_values("zicbom,sstc=false").unwrap();
+        assert_eq!(
+            res,
+            RiscvIsaOptions {
+                zicbom: Some(true),
+                sstc: Some(false),
+                ..Default::default()
+            }
+        );
+
+        // Extensions that can't be configured are refused.
+        from_key_values::<RiscvIsaOptions>("zicbom,h").expect_err("parsed the h extension");
+    }
+
     #[test]
     fn deserialize_cpuset_serde_kv() {
         let res: CpuSet = from_key_values("[0,4,7]").unwrap();
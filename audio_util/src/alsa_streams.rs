@@ -0,0 +1,537 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Audio streams played and captured through ALSA PCMs.
+//!
+//! Besides sound cards, ALSA PCMs can reach a PipeWire or PulseAudio server, through the `pipewire`
+//! and `pulse` PCMs of their ALSA plugins.
+
+use std::time::Duration;
+
+use alsa::pcm::Access;
+use alsa::pcm::Format;
+use alsa::pcm::Frames;
+use alsa::pcm::HwParams;
+use alsa::pcm::State;
+use alsa::Direction;
+use alsa::ValueOr;
+use alsa::PCM;
+use async_trait::async_trait;
+use audio_streams::capture::AsyncCaptureBuffer;
+use audio_streams::capture::AsyncCaptureBufferStream;
+use audio_streams::AsyncBufferCommit;
+use audio_streams::AsyncPlaybackBuffer;
+use audio_streams::AsyncPlaybackBufferStream;
+use audio_streams::AudioStreamsExecutor;
+use audio_streams::BoxError;
+use audio_streams::NoopStreamControl;
+use audio_streams::PlaybackBufferStream;
+use audio_streams::SampleFormat;
+use audio_streams::StreamControl;
+use audio_streams::StreamEffect;
+use audio_streams::StreamSource;
+use audio_streams::StreamSourceGenerator;
+use base::warn;
+use thiserror::Error as ThisError;
+
+// Number of periods held by the buffer of a PCM.
+const BUFFER_PERIODS: usize = 4;
+// Shortest time to wait for a PCM, so that streams don't spin when it is almost ready.
+const MIN_WAIT: Duration = Duration::from_millis(1);
+
+#[derive(ThisError, Debug)]
+pub enum AlsaError {
+    #[error("ALSA PCM {0} has a buffer of {1} frames, smaller than a period of {2} frames")]
+    BufferTooSmall(String, Frames, usize),
+    #[error("Failed to configure ALSA PCM {0}: {1}")]
+    Configure(String, alsa::Error),
+    #[error("Failed to open ALSA PCM {0}: {1}")]
+    Open(String, alsa::Error),
+    #[error("Failed to read from ALSA PCM: {0}")]
+    Read(alsa::Error),
+    #[error("Failed to start ALSA PCM: {0}")]
+    Start(alsa::Error),
+    #[error("ALSA PCM {0} doesn't support a rate of {1} Hz, the nearest is {2} Hz")]
+    UnsupportedRate(String, u32, u32),
+    #[error("Not implemented")]
+    Unimplemented,
+    #[error("Failed to wait for ALSA PCM: {0}")]
+    Wait(alsa::Error),
+    #[error("Failed to write to ALSA PCM: {0}")]
+    Write(alsa::Error),
+}
+
+fn alsa_format(format: SampleFormat) -> Format {
+    match format {
+        SampleFormat::U8 => Format::U8,
+        SampleFormat::S16LE => Format::S16LE,
+        SampleFormat::S24LE => Format::S24LE,
+        SampleFormat::S32LE => Format::S32LE,
+    }
+}
+
+fn frames_duration(frames: usize, frame_rate: u32) -> Duration {
+    Duration::from_micros(frames as u64 * 1_000_000 / u64::from(frame_rate.max(1)))
+}
+
+// Opens the `device` PCM in non-blocking mode, with a buffer of a few periods of `period_frames`.
+fn open_pcm(
+    device: &str,
+    direction: Direction,
+    num_channels: usize,
+    format: SampleFormat,
+    frame_rate: u32,
+    period_frames: usize,
+) -> Result<PCM, AlsaError> {
+    let pcm =
+        PCM::new(device, direction, true).map_err(|e| AlsaError::Open(device.to_owned(), e))?;
+    let configure_error = |e| AlsaError::Configure(device.to_owned(), e);
+
+    let hwp = HwParams::any(&pcm).map_err(configure_error)?;
+    hwp.set_access(Access::RWInterleaved)
+        .map_err(configure_error)?;
+    hwp.set_format(alsa_format(format))
+        .map_err(configure_error)?;
+    hwp.set_channels(num_channels as u32)
+        .map_err(configure_error)?;
+    hwp.set_rate(frame_rate, ValueOr::Nearest)
+        .map_err(configure_error)?;
+    hwp.set_period_size_near(period_frames as Frames, ValueOr::Nearest)
+        .map_err(configure_error)?;
+    hwp.set_buffer_size_near((period_frames * BUFFER_PERIODS) as Frames)
+        .map_err(configure_error)?;
+    pcm.hw_params(&hwp).map_err(configure_error)?;
+
+    // The nearest rate the PCM supports would play and capture at the wrong speed, since the
+    // samples aren't resampled.
+    let actual_rate = hwp.get_rate().map_err(configure_error)?;
+    if actual_rate != frame_rate {
+        return Err(AlsaError::UnsupportedRate(
+            device.to_owned(),
+            frame_rate,
+            actual_rate,
+        ));
+    }
+
+    // The stream waits for a whole period to be available, which never happens with a buffer
+    // smaller than that.
+    let buffer_frames = hwp.get_buffer_size().map_err(configure_error)?;
+    if buffer_frames < period_frames as Frames {
+        return Err(AlsaError::BufferTooSmall(
+            device.to_owned(),
+            buffer_frames,
+            period_frames,
+        ));
+    }
+
+    if direction == Direction::Playback {
+        // Start playing as soon as a period is written, instead of when the buffer is full.
+        let swp = pcm.sw_params_current().map_err(configure_error)?;
+        swp.set_start_threshold(period_frames as Frames)
+            .map_err(configure_error)?;
+        pcm.sw_params(&swp).map_err(configure_error)?;
+    }
+    drop(hwp);
+
+    Ok(pcm)
+}
+
+// Recovers `pcm` from an underrun, overrun or suspend, which leave it prepared to start over.
+// Playback PCMs start again once enough frames are written to them, but capture PCMs only start
+// when asked to.
+fn recover(pcm: &PCM, direction: Direction, e: alsa::Error) -> Result<(), alsa::Error> {
+    pcm.try_recover(e, true)?;
+    if direction == Direction::Capture && pcm.state() == State::Prepared {
+        pcm.start()?;
+    }
+    Ok(())
+}
+
+// Waits until `frames` frames can be written to or read from `pcm` without blocking.
+async fn wait_avail(
+    pcm: &PCM,
+    direction: Direction,
+    frames: usize,
+    frame_rate: u32,
+    ex: &dyn AudioStreamsExecutor,
+) -> Result<(), BoxError> {
+    loop {
+        match pcm.avail_update() {
+            Ok(avail) if avail as usize >= frames => return Ok(()),
+            Ok(avail) => {
+                ex.delay(frames_duration(frames - avail as usize, frame_rate).max(MIN_WAIT))
+                    .await?
+            }
+            Err(e) => recover(pcm, direction, e).map_err(AlsaError::Wait)?,
+        }
+    }
+}
+
+/// An audio stream playing the buffers filled by its user on an ALSA PCM.
+pub struct AlsaPlaybackStream {
+    pcm: PCM,
+    frame_size: usize,
+    frame_rate: u32,
+    buffer: Vec<u8>,
+    buffer_commit: AlsaPlaybackBufferCommit,
+}
+
+impl AlsaPlaybackStream {
+    fn new(pcm: PCM, frame_size: usize, frame_rate: u32, buffer_size: usize) -> Self {
+        AlsaPlaybackStream {
+            pcm,
+            frame_size,
+            frame_rate,
+            buffer: vec![0; buffer_size * frame_size],
+            buffer_commit: Default::default(),
+        }
+    }
+
+    // Writes the frames committed in the buffer to the PCM.
+    async fn write_committed_frames(
+        &mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(), BoxError> {
+        let mut frames = &self.buffer[..self.buffer_commit.take() * self.frame_size];
+        while !frames.is_empty() {
+            let remaining_frames = frames.len() / self.frame_size;
+            wait_avail(
+                &self.pcm,
+                Direction::Playback,
+                remaining_frames,
+                self.frame_rate,
+                ex,
+            )
+            .await?;
+            match self.pcm.io_bytes().writei(frames) {
+                Ok(written) => frames = &frames[written * self.frame_size..],
+                Err(e) => self.pcm.try_recover(e, true).map_err(AlsaError::Write)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncPlaybackBufferStream for AlsaPlaybackStream {
+    async fn next_playback_buffer<'a>(
+        &'a mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<AsyncPlaybackBuffer<'a>, BoxError> {
+        // The user asks for the next buffer right after committing the previous one.
+        self.write_committed_frames(ex).await?;
+
+        // Pace the user at the rate of the PCM, by waiting until the whole buffer fits in it.
+        let buffer_frames = self.buffer.len() / self.frame_size;
+        wait_avail(
+            &self.pcm,
+            Direction::Playback,
+            buffer_frames,
+            self.frame_rate,
+            ex,
+        )
+        .await?;
+
+        Ok(AsyncPlaybackBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.buffer_commit,
+        )?)
+    }
+}
+
+// Records how many frames were written to the buffer of an `AlsaPlaybackStream`. They are sent to
+// the PCM when the next buffer is requested, since the buffer is lent to the user until then.
+#[derive(Default)]
+struct AlsaPlaybackBufferCommit {
+    nframes: usize,
+}
+
+impl AlsaPlaybackBufferCommit {
+    fn take(&mut self) -> usize {
+        std::mem::take(&mut self.nframes)
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncBufferCommit for AlsaPlaybackBufferCommit {
+    async fn commit(&mut self, nframes: usize) {
+        self.nframes = nframes;
+    }
+}
+
+/// An audio stream filling buffers with the frames captured from an ALSA PCM.
+pub struct AlsaCaptureStream {
+    pcm: PCM,
+    frame_size: usize,
+    frame_rate: u32,
+    buffer: Vec<u8>,
+    buffer_commit: AlsaCaptureBufferCommit,
+}
+
+impl AlsaCaptureStream {
+    fn new(pcm: PCM, frame_size: usize, frame_rate: u32, buffer_size: usize) -> Self {
+        AlsaCaptureStream {
+            pcm,
+            frame_size,
+            frame_rate,
+            buffer: vec![0; buffer_size * frame_size],
+            buffer_commit: AlsaCaptureBufferCommit,
+        }
+    }
+
+    fn start_if_prepared(&self) -> Result<(), AlsaError> {
+        // Capture PCMs are prepared when opened, and need an explicit start.
+        if self.pcm.state() == State::Prepared {
+            self.pcm.start().map_err(AlsaError::Start)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncCaptureBufferStream for AlsaCaptureStream {
+    async fn next_capture_buffer<'a>(
+        &'a mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<AsyncCaptureBuffer<'a>, BoxError> {
+        self.start_if_prepared()?;
+        let mut offset = 0;
+        while offset < self.buffer.len() {
+            let remaining_frames = (self.buffer.len() - offset) / self.frame_size;
+            wait_avail(
+                &self.pcm,
+                Direction::Capture,
+                remaining_frames,
+                self.frame_rate,
+                ex,
+            )
+            .await?;
+            match self.pcm.io_bytes().readi(&mut self.buffer[offset..]) {
+                Ok(read) => offset += read * self.frame_size,
+                Err(e) => recover(&self.pcm, Direction::Capture, e).map_err(AlsaError::Read)?,
+            }
+        }
+
+        Ok(AsyncCaptureBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.buffer_commit,
+        )?)
+    }
+}
+
+// Frames are read from the PCM before the buffer is given to the user, so there is nothing left
+// to do once it is done with it.
+struct AlsaCaptureBufferCommit;
+
+#[async_trait(?Send)]
+impl AsyncBufferCommit for AlsaCaptureBufferCommit {
+    async fn commit(&mut self, _nframes: usize) {}
+}
+
+struct AlsaStreamSource {
+    device: String,
+}
+
+impl StreamSource for AlsaStreamSource {
+    fn new_playback_stream(
+        &mut self,
+        _num_channels: usize,
+        _format: SampleFormat,
+        _frame_rate: u32,
+        _buffer_size: usize,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn PlaybackBufferStream>), BoxError> {
+        Err(Box::new(AlsaError::Unimplemented))
+    }
+
+    fn new_async_playback_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn AsyncPlaybackBufferStream>), BoxError> {
+        let pcm = open_pcm(
+            &self.device,
+            Direction::Playback,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+        )?;
+        let frame_size = format.sample_bytes() * num_channels;
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(AlsaPlaybackStream::new(
+                pcm,
+                frame_size,
+                frame_rate,
+                buffer_size,
+            )),
+        ))
+    }
+
+    fn new_async_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        effects: &[StreamEffect],
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn AsyncCaptureBufferStream>), BoxError> {
+        if !effects.is_empty() {
+            warn!(
+                "ALSA capture streams don't support effects, ignoring {:?}",
+                effects
+            );
+        }
+        let pcm = open_pcm(
+            &self.device,
+            Direction::Capture,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+        )?;
+        let frame_size = format.sample_bytes() * num_channels;
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(AlsaCaptureStream::new(
+                pcm,
+                frame_size,
+                frame_rate,
+                buffer_size,
+            )),
+        ))
+    }
+}
+
+/// `AlsaStreamSourceGenerator` is a struct that implements [`StreamSourceGenerator`]
+/// for `AlsaStreamSource`.
+pub struct AlsaStreamSourceGenerator {
+    /// Name of the ALSA PCM the streams are played on or captured from, e.g. `default` or
+    /// `hw:0,0`.
+    device: String,
+}
+
+impl AlsaStreamSourceGenerator {
+    /// Creates a new `AlsaStreamSourceGenerator` for the ALSA PCM named `device`.
+    ///
+    /// The PCM is opened when a stream is created, so that it is only held while it is used.
+    pub fn new(device: String) -> Self {
+        AlsaStreamSourceGenerator { device }
+    }
+}
+
+impl StreamSourceGenerator for AlsaStreamSourceGenerator {
+    fn generate(&self) -> Result<Box<dyn StreamSource>, BoxError> {
+        Ok(Box::new(AlsaStreamSource {
+            device: self.device.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::os::unix::net::UnixStream;
+
+    use audio_streams::AsyncStream;
+    use futures::executor::block_on;
+
+    use super::*;
+
+    struct TestExecutor;
+
+    #[async_trait(?Send)]
+    impl AudioStreamsExecutor for TestExecutor {
+        fn async_unix_stream(&self, _f: UnixStream) -> io::Result<AsyncStream> {
+            panic!("Not Implemented");
+        }
+
+        async fn delay(&self, dur: Duration) -> io::Result<()> {
+            std::thread::sleep(dur);
+            Ok(())
+        }
+    }
+
+    // The `null` PCM is part of the default configuration of ALSA, and plays or captures silence
+    // without any sound card.
+    fn null_stream_source() -> Box<dyn StreamSource> {
+        AlsaStreamSourceGenerator::new("null".to_owned())
+            .generate()
+            .unwrap()
+    }
+
+    #[test]
+    fn playback_to_null_pcm() {
+        let ex = TestExecutor;
+        let (_, mut stream) = null_stream_source()
+            .new_async_playback_stream(2, SampleFormat::S16LE, 48000, 480, &ex)
+            .unwrap();
+        block_on(async {
+            for _ in 0..BUFFER_PERIODS * 2 {
+                let mut buffer = stream.next_playback_buffer(&ex).await.unwrap();
+                assert_eq!(buffer.frame_capacity(), 480);
+                buffer.copy_cb(480 * 4, |buf| buf.fill(0x55)).unwrap();
+                buffer.commit().await;
+            }
+        });
+    }
+
+    #[test]
+    fn capture_from_null_pcm() {
+        let ex = TestExecutor;
+        let (_, mut stream) = null_stream_source()
+            .new_async_capture_stream(2, SampleFormat::S16LE, 48000, 480, &[], &ex)
+            .unwrap();
+        block_on(async {
+            for _ in 0..BUFFER_PERIODS * 2 {
+                let mut buffer = stream.next_capture_buffer(&ex).await.unwrap();
+                let mut samples = Vec::new();
+                buffer
+                    .copy_cb(480 * 4, |buf| samples.extend_from_slice(buf))
+                    .unwrap();
+                assert_eq!(samples, vec![0; 480 * 4]);
+                buffer.commit().await;
+            }
+        });
+    }
+
+    #[test]
+    fn capture_restarts_after_overrun() {
+        let pcm = open_pcm(
+            "null",
+            Direction::Capture,
+            2,
+            SampleFormat::S16LE,
+            48000,
+            480,
+        )
+        .unwrap();
+        pcm.start().unwrap();
+        // The null PCM never overruns by itself, so report an overrun like `readi` would.
+        recover(
+            &pcm,
+            Direction::Capture,
+            alsa::Error::new("snd_pcm_readi", libc::EPIPE),
+        )
+        .unwrap();
+        assert_eq!(pcm.state(), State::Running);
+    }
+
+    #[test]
+    fn open_unknown_pcm() {
+        let ex = TestExecutor;
+        let mut source = AlsaStreamSourceGenerator::new("crosvm-unknown-pcm".to_owned())
+            .generate()
+            .unwrap();
+        assert!(source
+            .new_async_playback_stream(2, SampleFormat::S16LE, 48000, 480, &ex)
+            .is_err());
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Conversions of interleaved PCM frames between sample formats, channel counts and frame rates.
+//!
+//! Samples are converted through 32 bits signed samples, with their most significant bits aligned,
+//! so that converting to a wider format and back is lossless.
+
+use audio_streams::SampleFormat;
+
+/// Layout of a buffer of interleaved PCM frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PcmFormat {
+    pub format: SampleFormat,
+    pub num_channels: usize,
+    pub frame_rate: u32,
+}
+
+impl PcmFormat {
+    /// Number of bytes in a single frame.
+    pub fn frame_size(&self) -> usize {
+        self.format.sample_bytes() * self.num_channels
+    }
+}
+
+/// Decodes `data`, made of `format` samples, to 32 bits samples. Trailing bytes that don't make a
+/// whole sample are ignored.
+pub fn decode_samples(format: SampleFormat, data: &[u8]) -> Vec<i32> {
+    let chunks = data.chunks_exact(format.sample_bytes());
+    match format {
+        SampleFormat::U8 => chunks.map(|s| (s[0] as i32 - 128) << 24).collect(),
+        SampleFormat::S16LE => chunks
+            .map(|s| (i16::from_le_bytes([s[0], s[1]]) as i32) << 16)
+            .collect(),
+        // The 24 bits samples are held in the least significant bytes of 32 bits words.
+        SampleFormat::S24LE => chunks
+            .map(|s| i32::from_le_bytes([s[0], s[1], s[2], s[3]]) << 8)
+            .collect(),
+        SampleFormat::S32LE => chunks
+            .map(|s| i32::from_le_bytes([s[0], s[1], s[2], s[3]]))
+            .collect(),
+    }
+}
+
+/// Encodes 32 bits `samples` to `format` samples, keeping their most significant bits.
+pub fn encode_samples(format: SampleFormat, samples: &[i32]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(samples.len() * format.sample_bytes());
+    for &sample in samples {
+        match format {
+            SampleFormat::U8 => data.push(((sample >> 24) + 128) as u8),
+            SampleFormat::S16LE => data.extend_from_slice(&((sample >> 16) as i16).to_le_bytes()),
+            SampleFormat::S24LE => data.extend_from_slice(&(sample >> 8).to_le_bytes()),
+            SampleFormat::S32LE => data.extend_from_slice(&sample.to_le_bytes()),
+        }
+    }
+    data
+}
+
+/// Converts interleaved frames of `from_channels` samples to frames of `to_channels` samples.
+///
+/// Mono frames are copied to every channel, and frames are mixed down by averaging their channels
+/// to mono. Otherwise, channels are kept in order and missing ones repeat the first channels.
+pub fn remix_channels(samples: &[i32], from_channels: usize, to_channels: usize) -> Vec<i32> {
+    if from_channels == to_channels || from_channels == 0 {
+        return samples.to_vec();
+    }
+    let mut remixed = Vec::with_capacity(samples.len() / from_channels * to_channels);
+    for frame in samples.chunks_exact(from_channels) {
+        if to_channels == 1 {
+            let sum: i64 = frame.iter().map(|&s| i64::from(s)).sum();
+            remixed.push((sum / from_channels as i64) as i32);
+        } else {
+            remixed.extend((0..to_channels).map(|channel| frame[channel % from_channels]));
+        }
+    }
+    remixed
+}
+
+/// Resamples interleaved frames of `num_channels` samples from `from_rate` to `to_rate`, by linear
+/// interpolation between the closest frames.
+pub fn resample(samples: &[i32], num_channels: usize, from_rate: u32, to_rate: u32) -> Vec<i32> {
+    if from_rate == to_rate || num_channels == 0 || from_rate == 0 {
+        return samples.to_vec();
+    }
+    let from_frames = samples.len() / num_channels;
+    if from_frames == 0 {
+        return Vec::new();
+    }
+    let to_frames = (from_frames as u64 * u64::from(to_rate) / u64::from(from_rate)) as usize;
+    let mut resampled = Vec::with_capacity(to_frames * num_channels);
+    for frame in 0..to_frames {
+        let position = frame as u64 * u64::from(from_rate);
+        let index = (position / u64::from(to_rate)) as usize;
+        let fraction = (position % u64::from(to_rate)) as f64 / f64::from(to_rate);
+        let next = (index + 1).min(from_frames - 1);
+        for channel in 0..num_channels {
+            let current = f64::from(samples[index * num_channels + channel]);
+            let following = f64::from(samples[next * num_channels + channel]);
+            resampled.push((current + (following - current) * fraction).round() as i32);
+        }
+    }
+    resampled
+}
+
+/// Converts the interleaved frames in `data` from the `from` layout to the `to` layout.
+pub fn convert(data: &[u8], from: PcmFormat, to: PcmFormat) -> Vec<u8> {
+    if from == to {
+        return data.to_vec();
+    }
+    let samples = decode_samples(from.format, data);
+    let samples = remix_channels(&samples, from.num_channels, to.num_channels);
+    let samples = resample(&samples, to.num_channels, from.frame_rate, to.frame_rate);
+    encode_samples(to.format, &samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_format_round_trip() {
+        for format in [
+            SampleFormat::U8,
+            SampleFormat::S16LE,
+            SampleFormat::S24LE,
+            SampleFormat::S32LE,
+        ] {
+            let samples = vec![i32::MIN, -(1 << 24), 0, 1 << 24, 0x7f00_0000];
+            let decoded = decode_samples(format, &encode_samples(format, &samples));
+            assert_eq!(decoded, samples, "{}", format);
+        }
+    }
+
+    #[test]
+    fn convert_sample_format() {
+        let from = PcmFormat {
+            format: SampleFormat::U8,
+            num_channels: 1,
+            frame_rate: 8000,
+        };
+        let to = PcmFormat {
+            format: SampleFormat::S16LE,
+            ..from
+        };
+        assert_eq!(
+            convert(&[0, 128, 255], from, to),
+            [0x00, 0x80, 0x00, 0x00, 0x00, 0x7f]
+        );
+    }
+
+    #[test]
+    fn remix_mono_and_stereo() {
+        assert_eq!(remix_channels(&[1, 2], 1, 2), [1, 1, 2, 2]);
+        assert_eq!(remix_channels(&[1, 3, -4, 8], 2, 1), [2, 2]);
+        assert_eq!(
+            remix_channels(&[1, 2, 3, 4], 2, 4),
+            [1, 2, 1, 2, 3, 4, 3, 4]
+        );
+    }
+
+    #[test]
+    fn resample_rates() {
+        // Upsampling interpolates between frames, and holds the last one.
+        assert_eq!(resample(&[0, 100], 1, 1, 2), [0, 50, 100, 100]);
+        assert_eq!(
+            resample(&[0, 0, 100, -100], 2, 1, 2),
+            [0, 0, 50, -50, 100, -100, 100, -100]
+        );
+        // Downsampling skips frames.
+        assert_eq!(resample(&[0, 10, 20, 30], 1, 48000, 24000), [0, 20]);
+        assert_eq!(resample(&[0, 10, 20], 1, 44100, 44100), [0, 10, 20]);
+    }
+}
@@ -3,7 +3,9 @@
 // found in the LICENSE file.
 
 use std::fs::File;
+use std::io::BufReader;
 use std::io::Error as IOError;
+use std::io::Read;
 use std::slice;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
@@ -12,6 +14,8 @@ use std::time::Duration;
 use std::time::Instant;
 
 use async_trait::async_trait;
+use audio_streams::capture::AsyncCaptureBuffer;
+use audio_streams::capture::AsyncCaptureBufferStream;
 use audio_streams::AsyncBufferCommit;
 use audio_streams::AsyncPlaybackBuffer;
 use audio_streams::AsyncPlaybackBufferStream;
@@ -20,6 +24,7 @@ use audio_streams::BoxError;
 use audio_streams::NoopStreamControl;
 use audio_streams::SampleFormat;
 use audio_streams::StreamControl;
+use audio_streams::StreamEffect;
 use audio_streams::StreamSource;
 use audio_streams::StreamSourceGenerator;
 use base::warn;
@@ -29,12 +34,20 @@ use base::MemoryMappingBuilder;
 use base::MmapError;
 use thiserror::Error as ThisError;
 
+use crate::convert::convert;
+use crate::convert::encode_samples;
+use crate::convert::PcmFormat;
+
 #[derive(ThisError, Debug)]
 pub enum Error {
     #[error("Failed to build memory mapping: {0}")]
     BuildMemoryMapping(MmapError),
     #[error("Failed to clone file descriptor: {0}")]
     Clone(IOError),
+    #[error("Invalid WAV file: {0}")]
+    InvalidWav(hound::Error),
+    #[error("Failed to read file: {0}")]
+    ReadFile(IOError),
     #[error("Not implemented")]
     Unimplemented,
 }
@@ -267,3 +280,368 @@ impl AudioMemoryMapping {
         self.memory_mapping.size()
     }
 }
+
+/// An Audio Stream that fills capture buffers with the frames of a file.
+///
+/// Once all the frames have been captured, `FileCaptureStream` starts over from the first frame
+/// if it loops, and captures silence otherwise.
+pub struct FileCaptureStream {
+    /// Frames of the file, converted to the format of the stream.
+    frames: Vec<u8>,
+    /// Number of bytes of `frames` that have been captured.
+    offset: usize,
+    /// Whether to start over from the first frame after the last one.
+    looping: bool,
+    /// A silent sample in the format of the stream.
+    silence: Vec<u8>,
+    /// The capture buffer given to the user.
+    buffer: Vec<u8>,
+    /// Number of bytes in a single audio frame.
+    frame_size: usize,
+
+    /// Duration of an audio in milliseconds for the current `buffer_size`.
+    interval_ms: Duration,
+    /// Time marker of correct time to return next buffer.
+    next_frame: Duration,
+    /// Timestamp that records when the stream starts.
+    start_time: Option<Instant>,
+    /// Type that will be called before the buffer is dropped.
+    buffer_drop: FileCaptureBufferCommit,
+}
+
+impl FileCaptureStream {
+    fn new(
+        frames: Vec<u8>,
+        looping: bool,
+        format: SampleFormat,
+        frame_size: usize,
+        buffer_mem_length: usize,
+        interval_ms: Duration,
+    ) -> Self {
+        FileCaptureStream {
+            frames,
+            offset: 0,
+            looping,
+            silence: encode_samples(format, &[0]),
+            buffer: vec![0; buffer_mem_length],
+            frame_size,
+
+            interval_ms,
+            next_frame: interval_ms,
+            start_time: None,
+            buffer_drop: FileCaptureBufferCommit,
+        }
+    }
+
+    fn fill_buffer(&mut self) {
+        let mut filled = 0;
+        while filled < self.buffer.len() {
+            if self.offset == self.frames.len() {
+                if !self.looping || self.frames.is_empty() {
+                    break;
+                }
+                self.offset = 0;
+            }
+            let len = (self.buffer.len() - filled).min(self.frames.len() - self.offset);
+            self.buffer[filled..filled + len]
+                .copy_from_slice(&self.frames[self.offset..self.offset + len]);
+            filled += len;
+            self.offset += len;
+        }
+        for sample in self.buffer[filled..].chunks_exact_mut(self.silence.len()) {
+            sample.copy_from_slice(&self.silence);
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncCaptureBufferStream for FileCaptureStream {
+    async fn next_capture_buffer<'a>(
+        &'a mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<AsyncCaptureBuffer<'a>, BoxError> {
+        if let Some(start_time) = self.start_time {
+            let elapsed = start_time.elapsed();
+            if elapsed < self.next_frame {
+                ex.delay(self.next_frame - elapsed).await?;
+            }
+            self.next_frame += self.interval_ms;
+        } else {
+            self.start_time = Some(Instant::now());
+            self.next_frame = self.interval_ms;
+        }
+
+        self.fill_buffer();
+
+        Ok(AsyncCaptureBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.buffer_drop,
+        )?)
+    }
+}
+
+struct FileCaptureStreamSource {
+    frames: Arc<Vec<u8>>,
+    format: PcmFormat,
+    looping: bool,
+}
+
+impl StreamSource for FileCaptureStreamSource {
+    fn new_playback_stream(
+        &mut self,
+        _num_channels: usize,
+        _format: SampleFormat,
+        _frame_rate: u32,
+        _buffer_size: usize,
+    ) -> Result<
+        (
+            Box<dyn StreamControl>,
+            Box<dyn audio_streams::PlaybackBufferStream>,
+        ),
+        BoxError,
+    > {
+        Err(Box::new(Error::Unimplemented))
+    }
+
+    fn new_async_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn AsyncCaptureBufferStream>), BoxError> {
+        let stream_format = PcmFormat {
+            format,
+            num_channels,
+            frame_rate,
+        };
+        let frame_size = stream_format.frame_size();
+        let mut frames = convert(&self.frames, self.format, stream_format);
+        frames.truncate(frames.len() - frames.len() % frame_size);
+
+        let buffer_mem_length = buffer_size * frame_size;
+        let interval_ms = Duration::from_millis(buffer_size as u64 * 1000 / frame_rate as u64);
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(FileCaptureStream::new(
+                frames,
+                self.looping,
+                format,
+                frame_size,
+                buffer_mem_length,
+                interval_ms,
+            )),
+        ))
+    }
+}
+
+/// `FileCaptureStreamSourceGenerator` is a struct that implements [`StreamSourceGenerator`]
+/// for `FileCaptureStreamSource`.
+pub struct FileCaptureStreamSourceGenerator {
+    /// Frames read from the file.
+    frames: Arc<Vec<u8>>,
+    /// Layout of `frames`, which are converted to the layout of each stream.
+    format: PcmFormat,
+    /// Whether streams start over from the first frame after the last one, instead of capturing
+    /// silence.
+    looping: bool,
+}
+
+impl FileCaptureStreamSourceGenerator {
+    /// Creates a new `FileCaptureStreamSourceGenerator` capturing the frames of a WAV file.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The WAV file, with integer or floating point samples.
+    /// * `looping` - Whether to start over after the last frame, instead of capturing silence.
+    pub fn from_wav(file: File, looping: bool) -> Result<Self, Error> {
+        let reader = hound::WavReader::new(BufReader::new(file)).map_err(Error::InvalidWav)?;
+        let spec = reader.spec();
+        // Align the samples on their most significant bits, as 32 bits samples.
+        let samples = match spec.sample_format {
+            hound::SampleFormat::Int => {
+                let shift = 32 - u32::from(spec.bits_per_sample);
+                reader
+                    .into_samples::<i32>()
+                    .map(|sample| sample.map(|sample| sample << shift))
+                    .collect::<Result<Vec<_>, _>>()
+            }
+            hound::SampleFormat::Float => reader
+                .into_samples::<f32>()
+                .map(|sample| {
+                    sample.map(|sample| {
+                        (f64::from(sample).clamp(-1.0, 1.0) * f64::from(i32::MAX)) as i32
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>(),
+        }
+        .map_err(Error::InvalidWav)?;
+
+        Ok(FileCaptureStreamSourceGenerator {
+            frames: Arc::new(encode_samples(SampleFormat::S32LE, &samples)),
+            format: PcmFormat {
+                format: SampleFormat::S32LE,
+                num_channels: spec.channels as usize,
+                frame_rate: spec.sample_rate,
+            },
+            looping,
+        })
+    }
+
+    /// Creates a new `FileCaptureStreamSourceGenerator` capturing the frames of a raw PCM file.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The file, made of interleaved frames.
+    /// * `format` - The layout of the frames in `file`.
+    /// * `looping` - Whether to start over after the last frame, instead of capturing silence.
+    pub fn from_raw(mut file: File, format: PcmFormat, looping: bool) -> Result<Self, Error> {
+        let mut frames = Vec::new();
+        file.read_to_end(&mut frames).map_err(Error::ReadFile)?;
+        Ok(FileCaptureStreamSourceGenerator {
+            frames: Arc::new(frames),
+            format,
+            looping,
+        })
+    }
+}
+
+impl StreamSourceGenerator for FileCaptureStreamSourceGenerator {
+    fn generate(&self) -> Result<Box<dyn StreamSource>, BoxError> {
+        Ok(Box::new(FileCaptureStreamSource {
+            frames: self.frames.clone(),
+            format: self.format,
+            looping: self.looping,
+        }))
+    }
+}
+
+struct FileCaptureBufferCommit;
+
+#[async_trait(?Send)]
+impl AsyncBufferCommit for FileCaptureBufferCommit {
+    async fn commit(&mut self, _nframes: usize) {}
+}
+
+// The test executor only implements the functions of the executors of Linux.
+#[cfg(all(test, any(target_os = "android", target_os = "linux")))]
+mod tests {
+    use std::io;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    use audio_streams::AsyncStream;
+    use futures::executor::block_on;
+
+    use super::*;
+
+    struct TestExecutor;
+
+    #[async_trait(?Send)]
+    impl AudioStreamsExecutor for TestExecutor {
+        fn async_unix_stream(&self, _f: UnixStream) -> io::Result<AsyncStream> {
+            panic!("Not Implemented");
+        }
+
+        async fn delay(&self, dur: Duration) -> io::Result<()> {
+            std::thread::sleep(dur);
+            Ok(())
+        }
+    }
+
+    // Captures `num_buffers` buffers of 2 stereo S16LE frames at 8kHz from `generator`.
+    fn capture(generator: &FileCaptureStreamSourceGenerator, num_buffers: usize) -> Vec<u8> {
+        let ex = TestExecutor;
+        let (_, mut stream) = generator
+            .generate()
+            .unwrap()
+            .new_async_capture_stream(2, SampleFormat::S16LE, 8000, 2, &[], &ex)
+            .unwrap();
+        let mut captured = Vec::new();
+        block_on(async {
+            for _ in 0..num_buffers {
+                let mut buffer = stream.next_capture_buffer(&ex).await.unwrap();
+                buffer
+                    .copy_cb(8, |buf| captured.extend_from_slice(buf))
+                    .unwrap();
+                buffer.commit().await;
+            }
+        });
+        captured
+    }
+
+    fn raw_file(data: &[u8]) -> File {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(data).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file
+    }
+
+    const MONO_U8: PcmFormat = PcmFormat {
+        format: SampleFormat::U8,
+        num_channels: 1,
+        frame_rate: 8000,
+    };
+
+    #[test]
+    fn capture_raw_then_silence() {
+        let generator =
+            FileCaptureStreamSourceGenerator::from_raw(raw_file(&[255, 0, 255]), MONO_U8, false)
+                .unwrap();
+        assert_eq!(
+            capture(&generator, 2),
+            [
+                0x00, 0x7f, 0x00, 0x7f, 0x00, 0x80, 0x00, 0x80, // Two frames
+                0x00, 0x7f, 0x00, 0x7f, 0x00, 0x00, 0x00, 0x00, // Last frame and silence
+            ]
+        );
+    }
+
+    #[test]
+    fn capture_raw_looping() {
+        let generator =
+            FileCaptureStreamSourceGenerator::from_raw(raw_file(&[255, 0, 255]), MONO_U8, true)
+                .unwrap();
+        assert_eq!(
+            capture(&generator, 2),
+            [
+                0x00, 0x7f, 0x00, 0x7f, 0x00, 0x80, 0x00, 0x80, // Two frames
+                0x00, 0x7f, 0x00, 0x7f, 0x00, 0x7f, 0x00, 0x7f, // Last frame and first frame
+            ]
+        );
+    }
+
+    #[test]
+    fn capture_wav() {
+        let mut file = tempfile::tempfile().unwrap();
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 16000,
+            bits_per_sample: 24,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::new(&mut file, spec).unwrap();
+        // Every other frame is dropped by the conversion to 8kHz.
+        for sample in [0x100, -0x100, 0, 0, 0x7fff00, -0x800000, 0, 0] {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+
+        let generator = FileCaptureStreamSourceGenerator::from_wav(file, false).unwrap();
+        assert_eq!(
+            capture(&generator, 1),
+            [0x01, 0x00, 0xff, 0xff, 0xff, 0x7f, 0x00, 0x80]
+        );
+    }
+
+    #[test]
+    fn invalid_wav() {
+        assert!(FileCaptureStreamSourceGenerator::from_wav(raw_file(b"RIFF"), false).is_err());
+    }
+}
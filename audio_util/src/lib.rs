@@ -2,7 +2,16 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+#[cfg(all(feature = "alsa", any(target_os = "android", target_os = "linux")))]
+mod alsa_streams;
+pub mod convert;
 mod file_streams;
 
+#[cfg(all(feature = "alsa", any(target_os = "android", target_os = "linux")))]
+pub use alsa_streams::AlsaError;
+#[cfg(all(feature = "alsa", any(target_os = "android", target_os = "linux")))]
+pub use alsa_streams::AlsaStreamSourceGenerator;
+pub use convert::PcmFormat;
 pub use file_streams::Error;
+pub use file_streams::FileCaptureStreamSourceGenerator;
 pub use file_streams::FileStreamSourceGenerator;
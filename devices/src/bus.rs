@@ -26,6 +26,7 @@ use serde::Deserialize;
 use serde::Serialize;
 use sync::Mutex;
 use thiserror::Error;
+use vm_control::snapshot_format::SnapshotDevice;
 
 #[cfg(feature = "stats")]
 use crate::bus_stats::BusOperation;
@@ -461,6 +462,26 @@ impl Bus {
         Ok(())
     }
 
+    /// Returns the ID and label of each device on the bus, in the order they are snapshotted.
+    pub fn snapshot_device_list(&self) -> Vec<SnapshotDevice> {
+        self.unique_devices()
+            .into_iter()
+            .map(|device_entry| match device_entry {
+                BusDeviceEntry::OuterSync(dev) => {
+                    let dev = dev.lock();
+                    SnapshotDevice {
+                        id: u32::from(dev.device_id()),
+                        name: dev.debug_label(),
+                    }
+                }
+                BusDeviceEntry::InnerSync(dev) => SnapshotDevice {
+                    id: u32::from(dev.device_id()),
+                    name: dev.debug_label(),
+                },
+            })
+            .collect()
+    }
+
     pub fn snapshot_devices(
         &self,
         mut add_snapshot: impl FnMut(u32, serde_json::Value),
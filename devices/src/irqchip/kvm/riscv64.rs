@@ -4,6 +4,8 @@
 
 use std::sync::Arc;
 
+use anyhow::bail;
+use anyhow::Context;
 use base::errno_result;
 use base::ioctl_with_ref;
 use base::AsRawDescriptor;
@@ -17,6 +19,8 @@ use hypervisor::DeviceKind;
 use hypervisor::IrqRoute;
 use hypervisor::Vm;
 use kvm_sys::*;
+use serde::Deserialize;
+use serde::Serialize;
 use sync::Mutex;
 
 use crate::IrqChip;
@@ -64,6 +68,65 @@ const KVM_DEV_RISCV_AIA_GRP_ADDR: u32 = 1;
 
 const KVM_DEV_RISCV_AIA_GRP_CTRL: u32 = 2;
 
+const KVM_DEV_RISCV_AIA_GRP_APLIC: u32 = 3;
+
+// APLIC registers, as offsets in its MMIO region.
+const APLIC_DOMAINCFG: u32 = 0x0000;
+const APLIC_SOURCECFG_BASE: u32 = 0x0004;
+const APLIC_SETIP_BASE: u32 = 0x1c00;
+const APLIC_SETIE_BASE: u32 = 0x1e00;
+const APLIC_TARGET_BASE: u32 = 0x3004;
+
+const KVM_DEV_RISCV_AIA_GRP_IMSIC: u32 = 4;
+const KVM_DEV_RISCV_AIA_IMSIC_ISEL_BITS: u32 = 12;
+
+// IMSIC registers, as indirect register numbers (`siselect` values).
+const IMSIC_EIDELIVERY: u32 = 0x70;
+const IMSIC_EITHRESHOLD: u32 = 0x72;
+const IMSIC_EIP0: u32 = 0x80;
+const IMSIC_EIE0: u32 = 0xc0;
+
+// Returns the APLIC registers holding the state of `num_sources` interrupt sources, in the order
+// they are restored, with the domain configuration, which enables interrupts, last.
+fn aplic_state_registers(num_sources: usize) -> Vec<u32> {
+    let num_sources = num_sources as u32;
+    // There is one pending and enable bit per source, and source 0 doesn't exist.
+    let num_words = (num_sources + 1 + 31) / 32;
+    let mut regs = Vec::new();
+    regs.extend((1..=num_sources).map(|i| APLIC_SOURCECFG_BASE + 4 * (i - 1)));
+    regs.extend((1..=num_sources).map(|i| APLIC_TARGET_BASE + 4 * (i - 1)));
+    regs.extend((0..num_words).map(|i| APLIC_SETIP_BASE + 4 * i));
+    regs.extend((0..num_words).map(|i| APLIC_SETIE_BASE + 4 * i));
+    regs.push(APLIC_DOMAINCFG);
+    regs
+}
+
+// Returns the IMSIC registers holding the state of `num_ids` interrupt identities, in the order
+// they are restored, with the delivery enable last.
+fn imsic_state_registers(num_ids: usize) -> Vec<u32> {
+    // The pending and enable bits are held by 64 bits registers, which only have even numbers on
+    // riscv64. Identity 0 doesn't exist.
+    let num_words = (num_ids as u32 + 1 + 63) / 64;
+    let mut regs = vec![IMSIC_EITHRESHOLD];
+    regs.extend((0..num_words).map(|i| IMSIC_EIP0 + 2 * i));
+    regs.extend((0..num_words).map(|i| IMSIC_EIE0 + 2 * i));
+    regs.push(IMSIC_EIDELIVERY);
+    regs
+}
+
+const fn aia_imsic_attr(hart: usize, isel: u32) -> u64 {
+    ((hart as u64) << KVM_DEV_RISCV_AIA_IMSIC_ISEL_BITS) | isel as u64
+}
+
+/// State of the AIA, saved in snapshots.
+#[derive(Serialize, Deserialize)]
+struct AiaSnapshot {
+    /// APLIC registers, as pairs of offset and value.
+    aplic: Vec<(u32, u32)>,
+    /// Registers of the IMSIC of each hart, as pairs of register number and value.
+    imsics: Vec<Vec<(u32, u64)>>,
+}
+
 struct AiaDescriptor(SafeDescriptor);
 
 impl AiaDescriptor {
@@ -196,6 +259,63 @@ impl AiaDescriptor {
     }
 }
 
+impl AiaDescriptor {
+    fn get_attr<T: Default>(&self, group: u32, attr: u64) -> Result<T> {
+        let mut value = T::default();
+        let kvm_attr = kvm_device_attr {
+            group,
+            attr,
+            addr: &mut value as *mut T as u64,
+            flags: 0,
+        };
+        // SAFETY:
+        // Safe because we allocated the struct that's being passed in, and addr is pointing to a
+        // uniquely owned local, mutable variable of the size of the attribute.
+        let ret = unsafe { ioctl_with_ref(self, KVM_GET_DEVICE_ATTR(), &kvm_attr) };
+        if ret != 0 {
+            return errno_result();
+        }
+        Ok(value)
+    }
+
+    fn set_attr<T>(&self, group: u32, attr: u64, value: &T) -> Result<()> {
+        let kvm_attr = kvm_device_attr {
+            group,
+            attr,
+            addr: value as *const T as u64,
+            flags: 0,
+        };
+        // SAFETY:
+        // Safe because we allocated the struct that's being passed in, and addr is pointing to a
+        // value of the size of the attribute, which the kernel only reads.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_DEVICE_ATTR(), &kvm_attr) };
+        if ret != 0 {
+            return errno_result();
+        }
+        Ok(())
+    }
+
+    fn get_aplic_reg(&self, offset: u32) -> Result<u32> {
+        self.get_attr(KVM_DEV_RISCV_AIA_GRP_APLIC, offset.into())
+    }
+
+    fn set_aplic_reg(&self, offset: u32, value: u32) -> Result<()> {
+        self.set_attr(KVM_DEV_RISCV_AIA_GRP_APLIC, offset.into(), &value)
+    }
+
+    fn get_imsic_reg(&self, hart: usize, isel: u32) -> Result<u64> {
+        self.get_attr(KVM_DEV_RISCV_AIA_GRP_IMSIC, aia_imsic_attr(hart, isel))
+    }
+
+    fn set_imsic_reg(&self, hart: usize, isel: u32, value: u64) -> Result<()> {
+        self.set_attr(
+            KVM_DEV_RISCV_AIA_GRP_IMSIC,
+            aia_imsic_attr(hart, isel),
+            &value,
+        )
+    }
+}
+
 impl AsRawDescriptor for AiaDescriptor {
     fn as_raw_descriptor(&self) -> RawDescriptor {
         self.0.as_raw_descriptor()
@@ -293,6 +413,60 @@ impl IrqChipRiscv64 for KvmKernelIrqChip {
     fn get_num_ids_sources(&self) -> (usize, usize) {
         (self.num_ids, self.num_sources)
     }
+
+    fn snapshot(&self, cpus_num: usize) -> anyhow::Result<serde_json::Value> {
+        if cpus_num != self.num_vcpus {
+            bail!(
+                "cannot snapshot the IMSICs of {} vcpus, the AIA has {}",
+                cpus_num,
+                self.num_vcpus
+            );
+        }
+        let aplic = aplic_state_registers(self.num_sources)
+            .into_iter()
+            .map(|offset| Ok((offset, self.aia.get_aplic_reg(offset)?)))
+            .collect::<Result<Vec<_>>>()
+            .context("failed to read APLIC state")?;
+        let imsics = (0..self.num_vcpus)
+            .map(|hart| {
+                imsic_state_registers(self.num_ids)
+                    .into_iter()
+                    .map(|isel| Ok((isel, self.aia.get_imsic_reg(hart, isel)?)))
+                    .collect::<Result<Vec<_>>>()
+                    .with_context(|| format!("failed to read the IMSIC state of hart {}", hart))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        serde_json::to_value(AiaSnapshot { aplic, imsics })
+            .context("failed to serialize KvmKernelIrqChip")
+    }
+
+    fn restore(&mut self, data: serde_json::Value, vcpus_num: usize) -> anyhow::Result<()> {
+        let deser: AiaSnapshot =
+            serde_json::from_value(data).context("failed to deserialize data")?;
+        if deser.imsics.len() != vcpus_num || vcpus_num != self.num_vcpus {
+            bail!(
+                "IrqChip has the wrong number of IMSIC state snapshots: got {}, expected {}",
+                deser.imsics.len(),
+                self.num_vcpus
+            );
+        }
+        for (offset, value) in deser.aplic {
+            self.aia
+                .set_aplic_reg(offset, value)
+                .with_context(|| format!("failed to restore APLIC register {:#x}", offset))?;
+        }
+        for (hart, imsic) in deser.imsics.into_iter().enumerate() {
+            for (isel, value) in imsic {
+                self.aia.set_imsic_reg(hart, isel, value).with_context(|| {
+                    format!(
+                        "failed to restore IMSIC register {:#x} of hart {}",
+                        isel, hart
+                    )
+                })?;
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Default RiscV routing table.
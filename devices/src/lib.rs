@@ -27,6 +27,7 @@ mod serial;
 pub mod serial_device;
 mod suspendable;
 mod sys;
+pub mod tpm;
 #[cfg(any(target_os = "android", target_os = "linux"))]
 mod virtcpufreq;
 pub mod virtio;
@@ -43,10 +44,14 @@ cfg_if::cfg_if! {
 
 use std::collections::HashMap;
 use std::collections::VecDeque;
-use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use base::debug;
 use base::error;
@@ -55,10 +60,19 @@ use base::Tube;
 use base::TubeError;
 use cros_async::AsyncTube;
 use cros_async::Executor;
+use vm_control::snapshot_format::DevicesSnapshotConfig;
+use vm_control::snapshot_format::MemoryDirtyLog;
+use vm_control::snapshot_format::SnapshotMemoryRegion;
+use vm_control::snapshot_format::SnapshotReader;
+use vm_control::snapshot_format::SnapshotWriter;
+use vm_control::snapshot_format::DEVICES_SECTION;
+use vm_control::snapshot_format::MEMORY_SECTION;
 use vm_control::DeviceControlCommand;
 use vm_control::DevicesState;
 use vm_control::VmResponse;
 use vm_memory::GuestMemory;
+use vm_memory::MemoryCompression;
+use vm_memory::MemorySnapshotParent;
 
 pub use self::acpi::ACPIPMFixedEvent;
 pub use self::acpi::ACPIPMResource;
@@ -309,8 +323,28 @@ struct SnapshotRoot {
     devices: Vec<HashMap<u32, serde_json::Value>>,
 }
 
+// Returns the parts of the VM configuration that a snapshot has to match.
+fn snapshot_config(guest_memory: &GuestMemory, buses: &[&Bus]) -> DevicesSnapshotConfig {
+    DevicesSnapshotConfig {
+        memory_regions: guest_memory
+            .regions()
+            .map(|region| SnapshotMemoryRegion {
+                guest_addr: region.guest_addr.offset(),
+                size: region.size as u64,
+            })
+            .collect(),
+        devices: buses
+            .iter()
+            .flat_map(|bus| bus.snapshot_device_list())
+            .collect(),
+    }
+}
+
 async fn snapshot_handler(
-    path: &std::path::Path,
+    snapshot_writer: &SnapshotWriter,
+    compression: MemoryCompression,
+    parent: Option<&SnapshotReader>,
+    dirty_log: Option<MemoryDirtyLog>,
     guest_memory: &GuestMemory,
     buses: &[&Bus],
 ) -> anyhow::Result<()> {
@@ -319,57 +353,81 @@ async fn snapshot_handler(
         devices: Vec::new(),
     };
 
-    // TODO(b/268093674): Better output file format.
-    // TODO(b/268094487): If the snapshot fail, this leaves an incomplete memory snapshot at the
-    // requested path.
-
-    let mut json_file =
-        File::create(path).with_context(|| format!("failed to open {}", path.display()))?;
-
-    let mem_path = path.with_extension("mem");
-    let mut mem_file = File::create(&mem_path)
-        .with_context(|| format!("failed to open {}", mem_path.display()))?;
-
+    let mut mem_file = snapshot_writer.create_section(MEMORY_SECTION)?;
+
+    let dirty_log = dirty_log.map(MemoryDirtyLog::read).transpose()?;
+    let memory_parent = match (parent, &dirty_log) {
+        (Some(parent), Some(dirty_log)) => {
+            let parent_root: SnapshotRoot = parent.read_json_section(DEVICES_SECTION)?;
+            Some(MemorySnapshotParent {
+                metadata: parent_root.guest_memory_metadata,
+                file: parent.open_section(MEMORY_SECTION)?,
+                dirty_log,
+            })
+        }
+        (Some(_), None) => bail!("incremental snapshots need the dirty page log"),
+        (None, _) => None,
+    };
     snapshot_root.guest_memory_metadata = guest_memory
-        .snapshot(&mut mem_file)
+        .snapshot(&mut mem_file, compression, memory_parent)
         .context("failed to snapshot memory")?;
 
-    for bus in buses {
-        snapshot_devices(bus, |id, snapshot| {
-            snapshot_root.devices.push([(id, snapshot)].into())
-        })
-        .context("failed to snapshot devices")?;
-    }
+    snapshot_root.devices = snapshot_all_devices(buses)?;
 
-    serde_json::to_writer(&mut json_file, &snapshot_root)?;
+    snapshot_writer.write_json_section(DEVICES_SECTION, &snapshot_root)?;
 
     Ok(())
 }
 
 async fn restore_handler(
-    path: &std::path::Path,
+    snapshot_reader: &SnapshotReader,
+    parents: &[SnapshotReader],
     guest_memory: &GuestMemory,
     buses: &[&Bus],
 ) -> anyhow::Result<()> {
-    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let snapshot_root: SnapshotRoot = snapshot_reader.read_json_section(DEVICES_SECTION)?;
+
+    // Guest memory is restored from the first full snapshot up to `snapshot_reader`.
+    let mut memory_snapshots = Vec::with_capacity(parents.len() + 1);
+    for parent in parents.iter().rev() {
+        let parent_root: SnapshotRoot = parent.read_json_section(DEVICES_SECTION)?;
+        memory_snapshots.push((
+            parent_root.guest_memory_metadata,
+            parent.open_section(MEMORY_SECTION)?,
+        ));
+    }
+    memory_snapshots.push((
+        snapshot_root.guest_memory_metadata,
+        snapshot_reader.open_section(MEMORY_SECTION)?,
+    ));
 
-    let mem_path = path.with_extension("mem");
-    let mut mem_file =
-        File::open(&mem_path).with_context(|| format!("failed to open {}", mem_path.display()))?;
+    guest_memory.restore(memory_snapshots)?;
+    restore_all_devices(buses, snapshot_root.devices)
+}
 
-    let snapshot_root: SnapshotRoot = serde_json::from_reader(file)?;
+// Returns the state of the devices on `buses`.
+fn snapshot_all_devices(buses: &[&Bus]) -> anyhow::Result<Vec<HashMap<u32, serde_json::Value>>> {
+    let mut devices = Vec::new();
+    for bus in buses {
+        snapshot_devices(bus, |id, snapshot| devices.push([(id, snapshot)].into()))
+            .context("failed to snapshot devices")?;
+    }
+    Ok(devices)
+}
 
+// Restores the state of the devices on `buses` from `devices`, as returned by
+// `snapshot_all_devices`.
+fn restore_all_devices(
+    buses: &[&Bus],
+    devices: Vec<HashMap<u32, serde_json::Value>>,
+) -> anyhow::Result<()> {
     let mut devices_map: HashMap<u32, VecDeque<serde_json::Value>> = HashMap::new();
-    for (id, device) in snapshot_root.devices.into_iter().flatten() {
+    for (id, device) in devices.into_iter().flatten() {
         devices_map.entry(id).or_default().push_back(device)
     }
 
-    {
-        guest_memory.restore(snapshot_root.guest_memory_metadata, &mut mem_file)?;
-
-        for bus in buses {
-            restore_devices(bus, &mut devices_map)?;
-        }
+    for bus in buses {
+        restore_devices(bus, &mut devices_map)?;
     }
 
     for (key, _) in devices_map.iter().filter(|(_, v)| !v.is_empty()) {
@@ -394,6 +452,10 @@ async fn handle_command_tube(
     // sleeping state, run_control will ask us to sleep devices.
     let mut devices_state = DevicesState::Wake;
 
+    // The last snapshot taken with a dirty page log, which is the only one that an incremental
+    // snapshot can be based on.
+    let mut memory_snapshot_base: Option<PathBuf> = None;
+
     loop {
         match command_tube.next().await {
             Ok(command) => {
@@ -436,17 +498,42 @@ async fn handle_command_tube(
                             .context("failed to reply to wake devices request")?;
                     }
                     DeviceControlCommand::SnapshotDevices {
-                        snapshot_path: path,
+                        snapshot_writer,
+                        compression,
+                        parent,
+                        dirty_log,
+                        incremental_base,
                     } => {
                         assert!(
                             matches!(devices_state, DevicesState::Sleep),
                             "devices must be sleeping to snapshot"
                         );
-                        if let Err(e) = snapshot_handler(path.as_path(), &guest_memory, buses).await
-                        {
+                        // The dirty log was cleared when it was fetched for this snapshot, so the
+                        // previous snapshot can't be used as a base anymore.
+                        let base = memory_snapshot_base.take();
+                        let result = match &parent {
+                            Some(parent) if parent.dir().canonicalize().ok() != base => Err(
+                                anyhow!("incremental snapshots must be based on the last snapshot"),
+                            ),
+                            _ => {
+                                snapshot_handler(
+                                    &snapshot_writer,
+                                    compression,
+                                    parent.as_ref(),
+                                    dirty_log,
+                                    &guest_memory,
+                                    buses,
+                                )
+                                .await
+                            }
+                        };
+                        if result.is_ok() && incremental_base {
+                            memory_snapshot_base = snapshot_writer.dir().canonicalize().ok();
+                        }
+                        if let Err(e) = result {
                             error!("failed to snapshot: {:#}", e);
                             command_tube
-                                .send(VmResponse::ErrString(e.to_string()))
+                                .send(VmResponse::ErrString(format!("{:#}", e)))
                                 .await
                                 .context("Failed to send response")?;
                             continue;
@@ -456,14 +543,23 @@ async fn handle_command_tube(
                             .await
                             .context("Failed to send response")?;
                     }
-                    DeviceControlCommand::RestoreDevices { restore_path: path } => {
+                    DeviceControlCommand::RestoreDevices {
+                        snapshot_reader,
+                        parents,
+                    } => {
                         assert!(
                             matches!(devices_state, DevicesState::Sleep),
                             "devices must be sleeping to restore"
                         );
-                        if let Err(e) =
-                            restore_handler(path.as_path(), &guest_memory, &[&*io_bus, &*mmio_bus])
-                                .await
+                        // Guest memory no longer matches any snapshot taken before.
+                        memory_snapshot_base = None;
+                        if let Err(e) = restore_handler(
+                            &snapshot_reader,
+                            &parents,
+                            &guest_memory,
+                            &[&*io_bus, &*mmio_bus],
+                        )
+                        .await
                         {
                             error!("failed to restore: {:#}", e);
                             command_tube
@@ -477,6 +573,69 @@ async fn handle_command_tube(
                             .await
                             .context("Failed to send response")?;
                     }
+                    DeviceControlCommand::SnapshotDevicesState { file } => {
+                        assert!(
+                            matches!(devices_state, DevicesState::Sleep),
+                            "devices must be sleeping to snapshot"
+                        );
+                        let result = snapshot_all_devices(buses).and_then(|devices| {
+                            let mut writer = BufWriter::new(file);
+                            serde_json::to_writer(&mut writer, &devices)?;
+                            writer.flush()?;
+                            Ok(())
+                        });
+                        if let Err(e) = result {
+                            error!("failed to snapshot devices: {:#}", e);
+                            command_tube
+                                .send(VmResponse::ErrString(format!("{:#}", e)))
+                                .await
+                                .context("Failed to send response")?;
+                            continue;
+                        }
+                        command_tube
+                            .send(VmResponse::Ok)
+                            .await
+                            .context("Failed to send response")?;
+                    }
+                    DeviceControlCommand::RestoreDevicesState { file } => {
+                        assert!(
+                            matches!(devices_state, DevicesState::Sleep),
+                            "devices must be sleeping to restore"
+                        );
+                        // Guest memory no longer matches any snapshot taken before.
+                        memory_snapshot_base = None;
+                        let result = serde_json::from_reader(BufReader::new(file))
+                            .context("failed to read devices state")
+                            .and_then(|devices| restore_all_devices(buses, devices));
+                        if let Err(e) = result {
+                            error!("failed to restore devices: {:#}", e);
+                            command_tube
+                                .send(VmResponse::ErrString(format!("{:#}", e)))
+                                .await
+                                .context("Failed to send response")?;
+                            continue;
+                        }
+                        command_tube
+                            .send(VmResponse::Ok)
+                            .await
+                            .context("Failed to send response")?;
+                    }
+                    DeviceControlCommand::ResetSnapshotBase => {
+                        memory_snapshot_base = None;
+                        command_tube
+                            .send(VmResponse::Ok)
+                            .await
+                            .context("Failed to send response")?;
+                    }
+                    DeviceControlCommand::GetSnapshotConfig => {
+                        command_tube
+                            .send(VmResponse::DevicesSnapshotConfig(snapshot_config(
+                                &guest_memory,
+                                buses,
+                            )))
+                            .await
+                            .context("failed to send response")?;
+                    }
                     DeviceControlCommand::GetDevicesState => {
                         command_tube
                             .send(VmResponse::DevicesState(devices_state.clone()))
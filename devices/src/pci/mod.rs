@@ -144,6 +144,8 @@ pub enum CrosvmDeviceId {
     VirtualPmc = 21,
     VirtCpufreq = 22,
     FwCfg = 23,
+    TpmCrb = 24,
+    TpmTis = 25,
 }
 
 impl TryFrom<u16> for CrosvmDeviceId {
@@ -172,6 +174,8 @@ impl TryFrom<u16> for CrosvmDeviceId {
             19 => Ok(CrosvmDeviceId::VirtioMmio),
             20 => Ok(CrosvmDeviceId::AcAdapter),
             21 => Ok(CrosvmDeviceId::VirtualPmc),
+            24 => Ok(CrosvmDeviceId::TpmCrb),
+            25 => Ok(CrosvmDeviceId::TpmTis),
             _ => Err(base::Error::new(EINVAL)),
         }
     }
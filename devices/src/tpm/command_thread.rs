@@ -0,0 +1,167 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Executes the commands of a TPM device on a thread of its own, so the VCPU that starts a command
+//! doesn't wait for the backend, which can take seconds, e.g. to generate a key.
+
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::sync::mpsc::TryRecvError;
+use std::thread;
+use std::thread::JoinHandle;
+
+use base::error;
+
+use super::TpmBackend;
+use super::TPM_RC_FAILURE_RESPONSE;
+
+/// Runs the commands of a TPM device with its backend, one at a time.
+pub struct CommandThread {
+    commands: Option<Sender<Vec<u8>>>,
+    responses: Receiver<Vec<u8>>,
+    thread: Option<JoinHandle<()>>,
+    busy: bool,
+}
+
+impl CommandThread {
+    /// Starts a thread named `name` executing commands with `backend`.
+    pub fn new(name: &str, mut backend: Box<dyn TpmBackend>) -> CommandThread {
+        let (commands, command_recv) = mpsc::channel::<Vec<u8>>();
+        let (response_send, responses) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || {
+                // Stops once the device is dropped.
+                for command in command_recv {
+                    let response = backend.execute_command(&command).to_vec();
+                    if response_send.send(response).is_err() {
+                        break;
+                    }
+                }
+            })
+            .expect("failed to spawn TPM command thread");
+        CommandThread {
+            commands: Some(commands),
+            responses,
+            thread: Some(thread),
+            busy: false,
+        }
+    }
+
+    /// Returns whether a command is being executed.
+    pub fn busy(&self) -> bool {
+        self.busy
+    }
+
+    /// Starts executing `command`. Its response is returned by `poll` or `wait`.
+    pub fn start(&mut self, command: Vec<u8>) {
+        debug_assert!(!self.busy);
+        self.busy = true;
+        if let Some(commands) = &self.commands {
+            // A thread that stopped is reported by `poll` and `wait`.
+            let _ = commands.send(command);
+        }
+    }
+
+    /// Returns the response to the command being executed, if it is complete.
+    pub fn poll(&mut self) -> Option<Vec<u8>> {
+        if !self.busy {
+            return None;
+        }
+        match self.responses.try_recv() {
+            Ok(response) => {
+                self.busy = false;
+                Some(response)
+            }
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(self.failed()),
+        }
+    }
+
+    /// Waits for the command being executed to complete, and returns its response.
+    pub fn wait(&mut self) -> Option<Vec<u8>> {
+        if !self.busy {
+            return None;
+        }
+        match self.responses.recv() {
+            Ok(response) => {
+                self.busy = false;
+                Some(response)
+            }
+            Err(_) => Some(self.failed()),
+        }
+    }
+
+    fn failed(&mut self) -> Vec<u8> {
+        error!("TPM command thread stopped");
+        self.busy = false;
+        TPM_RC_FAILURE_RESPONSE.to_vec()
+    }
+}
+
+impl Drop for CommandThread {
+    fn drop(&mut self) {
+        // Closing the channel stops the thread once the command being executed completes.
+        self.commands = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("TPM command thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::Barrier;
+
+    use super::*;
+
+    // Waits for the test to let it respond to each command, and echoes it.
+    struct BlockingBackend {
+        barrier: Arc<Barrier>,
+        response: Vec<u8>,
+    }
+
+    impl TpmBackend for BlockingBackend {
+        fn execute_command<'a>(&'a mut self, command: &[u8]) -> &'a [u8] {
+            self.barrier.wait();
+            self.response = command.to_vec();
+            &self.response
+        }
+    }
+
+    #[test]
+    fn runs_commands_in_background() {
+        let barrier = Arc::new(Barrier::new(2));
+        let mut thread = CommandThread::new(
+            "tpm_test",
+            Box::new(BlockingBackend {
+                barrier: barrier.clone(),
+                response: Vec::new(),
+            }),
+        );
+        assert_eq!(thread.poll(), None);
+
+        thread.start(vec![1, 2, 3]);
+        assert!(thread.busy());
+        // The backend is blocked, so the command can't be complete.
+        assert_eq!(thread.poll(), None);
+        barrier.wait();
+        assert_eq!(thread.wait(), Some(vec![1, 2, 3]));
+        assert!(!thread.busy());
+
+        thread.start(vec![4]);
+        barrier.wait();
+        let response = loop {
+            if let Some(response) = thread.poll() {
+                break response;
+            }
+            thread::yield_now();
+        };
+        assert_eq!(response, vec![4]);
+    }
+}
@@ -0,0 +1,415 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! TPM 2.0 Command Response Buffer (CRB) interface, as specified by the TCG PC Client Platform TPM
+//! Profile.
+//!
+//! Only locality 0 is implemented, and the device has no interrupt: the guest polls the registers.
+//! Commands are executed on a thread of their own, and CRB_CTRL_START reads as 1 until they
+//! complete.
+//!
+//! The CRB interface is described to the guest by ACPI, so this device is only used on x86_64.
+
+use acpi_tables::aml;
+use acpi_tables::aml::Aml;
+use anyhow::Context;
+use base::error;
+use base::warn;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::CommandThread;
+use super::TpmBackend;
+use super::TPM_HEADER_SIZE;
+use super::TPM_RC_FAILURE_RESPONSE;
+use crate::pci::CrosvmDeviceId;
+use crate::BusAccessInfo;
+use crate::BusDevice;
+use crate::DeviceId;
+use crate::Suspendable;
+
+/// The size of the MMIO region of the device: the registers and the buffer of locality 0.
+pub const TPM_CRB_MMIO_SIZE: u64 = 0x1000;
+
+// Registers.
+const CRB_LOC_STATE: u64 = 0x00;
+const CRB_LOC_CTRL: u64 = 0x08;
+const CRB_LOC_STS: u64 = 0x0c;
+const CRB_INTF_ID: u64 = 0x30;
+const CRB_CTRL_REQ: u64 = 0x40;
+const CRB_CTRL_STS: u64 = 0x44;
+const CRB_CTRL_CANCEL: u64 = 0x48;
+const CRB_CTRL_START: u64 = 0x4c;
+const CRB_INT_ENABLE: u64 = 0x50;
+const CRB_CTRL_CMD_SIZE: u64 = 0x58;
+const CRB_CTRL_CMD_LADDR: u64 = 0x5c;
+const CRB_CTRL_CMD_HADDR: u64 = 0x60;
+const CRB_CTRL_RSP_SIZE: u64 = 0x64;
+const CRB_CTRL_RSP_ADDR: u64 = 0x68;
+const CRB_DATA_BUFFER: u64 = 0x80;
+
+/// The size of the buffer of commands and responses.
+pub const TPM_CRB_BUFFER_SIZE: usize = (TPM_CRB_MMIO_SIZE - CRB_DATA_BUFFER) as usize;
+
+/// The offset of the control area, the registers starting at CRB_CTRL_REQ, which the ACPI TPM2
+/// table points to.
+pub const TPM_CRB_CONTROL_AREA_OFFSET: u64 = CRB_CTRL_REQ;
+
+// CRB_LOC_STATE
+const LOC_STATE_LOC_ASSIGNED: u32 = 1 << 1;
+const LOC_STATE_REG_VALID_STS: u32 = 1 << 7;
+// CRB_LOC_CTRL
+const LOC_CTRL_REQUEST_ACCESS: u32 = 1 << 0;
+const LOC_CTRL_RELINQUISH: u32 = 1 << 1;
+// CRB_LOC_STS
+const LOC_STS_GRANTED: u32 = 1 << 0;
+// CRB_CTRL_REQ
+const CTRL_REQ_CMD_READY: u32 = 1 << 0;
+const CTRL_REQ_GO_IDLE: u32 = 1 << 1;
+// CRB_CTRL_STS
+const CTRL_STS_TPM_IDLE: u32 = 1 << 1;
+// CRB_CTRL_START
+const CTRL_START: u32 = 1 << 0;
+
+// CRB_INTF_ID: an active CRB interface, with 64-byte transfers and no locality other than 0.
+const INTF_ID_TYPE_CRB_ACTIVE: u64 = 1;
+const INTF_ID_VERSION_CRB: u64 = 1 << 4;
+const INTF_ID_CAP_DATA_XFER_SIZE_64: u64 = 3 << 11;
+const INTF_ID_CAP_CRB: u64 = 1 << 14;
+const INTF_ID_SELECTOR_CRB: u64 = 1 << 17;
+const INTF_ID_VID: u64 = 0x1ae0 << 32;
+const INTF_ID_DID: u64 = 0x0001 << 48;
+
+/// TPM device with a CRB interface.
+pub struct TpmCrb {
+    commands: CommandThread,
+    base: u64,
+    // The registers and the data buffer, as read by the guest.
+    regs: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TpmCrbSnapshot {
+    regs: Vec<u8>,
+}
+
+impl TpmCrb {
+    /// Creates a device at guest physical address `base`, executing the commands with `backend`.
+    pub fn new(backend: Box<dyn TpmBackend>, base: u64) -> TpmCrb {
+        let mut crb = TpmCrb {
+            commands: CommandThread::new("tpm_crb", backend),
+            base,
+            regs: vec![0; TPM_CRB_MMIO_SIZE as usize],
+        };
+        let buffer = base + CRB_DATA_BUFFER;
+        crb.set_reg32(CRB_LOC_STATE, LOC_STATE_REG_VALID_STS);
+        crb.set_reg64(
+            CRB_INTF_ID,
+            INTF_ID_TYPE_CRB_ACTIVE
+                | INTF_ID_VERSION_CRB
+                | INTF_ID_CAP_DATA_XFER_SIZE_64
+                | INTF_ID_CAP_CRB
+                | INTF_ID_SELECTOR_CRB
+                | INTF_ID_VID
+                | INTF_ID_DID,
+        );
+        crb.set_reg32(CRB_CTRL_STS, CTRL_STS_TPM_IDLE);
+        crb.set_reg32(CRB_CTRL_CMD_SIZE, TPM_CRB_BUFFER_SIZE as u32);
+        crb.set_reg32(CRB_CTRL_CMD_LADDR, buffer as u32);
+        crb.set_reg32(CRB_CTRL_CMD_HADDR, (buffer >> 32) as u32);
+        crb.set_reg32(CRB_CTRL_RSP_SIZE, TPM_CRB_BUFFER_SIZE as u32);
+        crb.set_reg64(CRB_CTRL_RSP_ADDR, buffer);
+        crb
+    }
+
+    fn reg32(&self, offset: u64) -> u32 {
+        let offset = offset as usize;
+        u32::from_le_bytes(self.regs[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn set_reg32(&mut self, offset: u64, value: u32) {
+        let offset = offset as usize;
+        self.regs[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_reg64(&mut self, offset: u64, value: u64) {
+        let offset = offset as usize;
+        self.regs[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn locality_control(&mut self, value: u32) {
+        let state = self.reg32(CRB_LOC_STATE);
+        if value & LOC_CTRL_REQUEST_ACCESS != 0 {
+            self.set_reg32(CRB_LOC_STATE, state | LOC_STATE_LOC_ASSIGNED);
+            self.set_reg32(CRB_LOC_STS, LOC_STS_GRANTED);
+        } else if value & LOC_CTRL_RELINQUISH != 0 {
+            self.set_reg32(CRB_LOC_STATE, state & !LOC_STATE_LOC_ASSIGNED);
+            self.set_reg32(CRB_LOC_STS, 0);
+        }
+    }
+
+    fn control_request(&mut self, value: u32) {
+        let status = self.reg32(CRB_CTRL_STS);
+        // The requests complete immediately, so CRB_CTRL_REQ always reads as 0.
+        if value & CTRL_REQ_CMD_READY != 0 {
+            self.set_reg32(CRB_CTRL_STS, status & !CTRL_STS_TPM_IDLE);
+        } else if value & CTRL_REQ_GO_IDLE != 0 {
+            self.set_reg32(CRB_CTRL_STS, status | CTRL_STS_TPM_IDLE);
+        }
+    }
+
+    // Starts executing the command in the data buffer. CRB_CTRL_START reads as 1 until it
+    // completes.
+    fn start(&mut self) {
+        if self.commands.busy() {
+            return;
+        }
+        if self.reg32(CRB_LOC_STATE) & LOC_STATE_LOC_ASSIGNED == 0 {
+            warn!("tpm-crb: command started without locality 0");
+            return;
+        }
+        let buffer = CRB_DATA_BUFFER as usize..;
+        let size = u32::from_be_bytes(
+            self.regs[buffer.start + 2..buffer.start + 6]
+                .try_into()
+                .unwrap(),
+        ) as usize;
+        let command =
+            self.regs[buffer][..size.clamp(TPM_HEADER_SIZE, TPM_CRB_BUFFER_SIZE)].to_vec();
+        self.set_reg32(CRB_CTRL_START, CTRL_START);
+        self.commands.start(command);
+    }
+
+    // Replaces the command in the data buffer with its response, and clears CRB_CTRL_START.
+    fn complete(&mut self, response: Vec<u8>) {
+        let response = if response.len() > TPM_CRB_BUFFER_SIZE {
+            error!(
+                "tpm-crb: response of {} bytes is larger than the buffer",
+                response.len()
+            );
+            TPM_RC_FAILURE_RESPONSE
+        } else {
+            response.as_slice()
+        };
+        self.regs[CRB_DATA_BUFFER as usize..][..response.len()].copy_from_slice(response);
+        self.set_reg32(CRB_CTRL_START, 0);
+    }
+}
+
+impl BusDevice for TpmCrb {
+    fn device_id(&self) -> DeviceId {
+        CrosvmDeviceId::TpmCrb.into()
+    }
+
+    fn debug_label(&self) -> String {
+        "tpm-crb".to_owned()
+    }
+
+    fn read(&mut self, info: BusAccessInfo, data: &mut [u8]) {
+        if let Some(response) = self.commands.poll() {
+            self.complete(response);
+        }
+        match self
+            .regs
+            .get(info.offset as usize..info.offset as usize + data.len())
+        {
+            Some(regs) => data.copy_from_slice(regs),
+            None => warn!("tpm-crb: bad read at {:#x}", info.offset),
+        }
+    }
+
+    fn write(&mut self, info: BusAccessInfo, data: &[u8]) {
+        if let Some(response) = self.commands.poll() {
+            self.complete(response);
+        }
+        if info.offset >= CRB_DATA_BUFFER {
+            match self
+                .regs
+                .get_mut(info.offset as usize..info.offset as usize + data.len())
+            {
+                Some(buffer) => buffer.copy_from_slice(data),
+                None => warn!("tpm-crb: bad write at {:#x}", info.offset),
+            }
+            return;
+        }
+        let value = match data.try_into() {
+            Ok(value) => u32::from_le_bytes(value),
+            Err(_) => {
+                warn!(
+                    "tpm-crb: bad write of {} bytes at {:#x}",
+                    data.len(),
+                    info.offset
+                );
+                return;
+            }
+        };
+        match info.offset {
+            CRB_LOC_CTRL => self.locality_control(value),
+            CRB_CTRL_REQ => self.control_request(value),
+            // swtpm can't cancel commands, so they always complete.
+            CRB_CTRL_CANCEL => {}
+            CRB_CTRL_START => {
+                if value & CTRL_START != 0 {
+                    self.start();
+                }
+            }
+            CRB_INT_ENABLE => self.set_reg32(CRB_INT_ENABLE, value),
+            // The other registers are read-only.
+            _ => {}
+        }
+    }
+}
+
+impl Aml for TpmCrb {
+    fn to_aml_bytes(&self, bytes: &mut Vec<u8>) {
+        aml::Device::new(
+            "TPM_".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"MSFT0101"),
+                &aml::Name::new(
+                    "_CRS".into(),
+                    &aml::ResourceTemplate::new(vec![&aml::Memory32Fixed::new(
+                        true,
+                        self.base as u32,
+                        TPM_CRB_MMIO_SIZE as u32,
+                    )]),
+                ),
+            ],
+        )
+        .to_aml_bytes(bytes);
+    }
+}
+
+impl Suspendable for TpmCrb {
+    fn sleep(&mut self) -> anyhow::Result<()> {
+        // The response belongs to the snapshot of the registers.
+        if let Some(response) = self.commands.wait() {
+            self.complete(response);
+        }
+        Ok(())
+    }
+
+    fn wake(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    // The state of the TPM itself belongs to the backend, e.g. the state files of swtpm.
+    fn snapshot(&mut self) -> anyhow::Result<serde_json::Value> {
+        serde_json::to_value(TpmCrbSnapshot {
+            regs: self.regs.clone(),
+        })
+        .context("failed to snapshot tpm-crb")
+    }
+
+    fn restore(&mut self, data: serde_json::Value) -> anyhow::Result<()> {
+        let snapshot: TpmCrbSnapshot =
+            serde_json::from_value(data).context("failed to deserialize tpm-crb")?;
+        anyhow::ensure!(
+            snapshot.regs.len() == self.regs.len(),
+            "tpm-crb snapshot has {} bytes of registers, expected {}",
+            snapshot.regs.len(),
+            self.regs.len()
+        );
+        self.regs = snapshot.regs;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::suspendable_tests;
+
+    const BASE: u64 = 0xfed4_0000;
+
+    // Answers every command with a TPM_RC_SUCCESS response carrying the command code.
+    struct EchoBackend(Vec<u8>);
+
+    impl TpmBackend for EchoBackend {
+        fn execute_command<'a>(&'a mut self, command: &[u8]) -> &'a [u8] {
+            self.0 = vec![0x80, 0x01, 0, 0, 0, 14, 0, 0, 0, 0];
+            self.0.extend_from_slice(&command[6..10]);
+            &self.0
+        }
+    }
+
+    fn new() -> TpmCrb {
+        TpmCrb::new(Box::new(EchoBackend(Vec::new())), BASE)
+    }
+
+    fn info(offset: u64) -> BusAccessInfo {
+        BusAccessInfo {
+            offset,
+            address: BASE + offset,
+            id: 0,
+        }
+    }
+
+    fn read32(crb: &mut TpmCrb, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        crb.read(info(offset), &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write32(crb: &mut TpmCrb, offset: u64, value: u32) {
+        crb.write(info(offset), &value.to_le_bytes());
+    }
+
+    #[test]
+    fn registers() {
+        let mut crb = new();
+        assert_eq!(read32(&mut crb, CRB_INTF_ID) & 0xf, 1);
+        assert_eq!(read32(&mut crb, CRB_CTRL_CMD_LADDR), BASE as u32 + 0x80);
+        assert_eq!(read32(&mut crb, CRB_CTRL_RSP_SIZE), 0xf80);
+
+        write32(&mut crb, CRB_LOC_CTRL, LOC_CTRL_REQUEST_ACCESS);
+        assert_eq!(
+            read32(&mut crb, CRB_LOC_STATE),
+            LOC_STATE_REG_VALID_STS | LOC_STATE_LOC_ASSIGNED
+        );
+        assert_eq!(read32(&mut crb, CRB_LOC_STS), LOC_STS_GRANTED);
+
+        write32(&mut crb, CRB_CTRL_REQ, CTRL_REQ_CMD_READY);
+        assert_eq!(read32(&mut crb, CRB_CTRL_REQ), 0);
+        assert_eq!(read32(&mut crb, CRB_CTRL_STS), 0);
+        write32(&mut crb, CRB_CTRL_REQ, CTRL_REQ_GO_IDLE);
+        assert_eq!(read32(&mut crb, CRB_CTRL_STS), CTRL_STS_TPM_IDLE);
+
+        // Read-only.
+        write32(&mut crb, CRB_CTRL_RSP_SIZE, 0);
+        assert_eq!(read32(&mut crb, CRB_CTRL_RSP_SIZE), 0xf80);
+    }
+
+    #[test]
+    fn execute_command() {
+        let mut crb = new();
+        // TPM2_GetRandom(8)
+        let command = [0x80, 0x01, 0, 0, 0, 12, 0, 0, 0x01, 0x7b, 0, 8];
+
+        // Without locality, the command is ignored.
+        crb.write(info(CRB_DATA_BUFFER), &command);
+        write32(&mut crb, CRB_CTRL_START, CTRL_START);
+        let mut response = [0u8; 14];
+        crb.read(info(CRB_DATA_BUFFER), &mut response);
+        assert_eq!(response[..12], command);
+
+        write32(&mut crb, CRB_LOC_CTRL, LOC_CTRL_REQUEST_ACCESS);
+        write32(&mut crb, CRB_CTRL_START, CTRL_START);
+        // The guest polls CRB_CTRL_START until the command completes.
+        while read32(&mut crb, CRB_CTRL_START) != 0 {
+            std::thread::yield_now();
+        }
+        crb.read(info(CRB_DATA_BUFFER), &mut response);
+        assert_eq!(
+            response,
+            [0x80, 0x01, 0, 0, 0, 14, 0, 0, 0, 0, 0, 0, 0x01, 0x7b]
+        );
+    }
+
+    fn modify_device(crb: &mut TpmCrb) {
+        write32(crb, CRB_LOC_CTRL, LOC_CTRL_REQUEST_ACCESS);
+    }
+
+    suspendable_tests!(tpm_crb, new(), modify_device);
+}
@@ -0,0 +1,117 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! TPM 2.0 devices, and the backends that execute their commands.
+
+mod command_thread;
+mod crb;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+mod swtpm;
+mod tis;
+
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_keyvalue::FromKeyValues;
+
+use self::command_thread::CommandThread;
+pub use self::crb::TpmCrb;
+pub use self::crb::TPM_CRB_BUFFER_SIZE;
+pub use self::crb::TPM_CRB_CONTROL_AREA_OFFSET;
+pub use self::crb::TPM_CRB_MMIO_SIZE;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use self::swtpm::Swtpm;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use self::swtpm::SwtpmError;
+pub use self::tis::TpmTis;
+pub use self::tis::TPM_TIS_BUFFER_SIZE;
+pub use self::tis::TPM_TIS_MMIO_SIZE;
+
+// The size of the header of commands and responses: tag[2] size[4] code[4].
+const TPM_HEADER_SIZE: usize = 10;
+
+// The response of TPM_RC_FAILURE.
+const TPM_RC_FAILURE_RESPONSE: &[u8] = &[
+    0x80, 0x01, // TPM_ST_NO_SESSIONS
+    0x00, 0x00, 0x00, 0x0A, // Header Size = 10
+    0x00, 0x00, 0x01, 0x01, // TPM_RC_FAILURE
+];
+
+/// Executes the commands that the guest sends to a TPM device.
+pub trait TpmBackend: Send {
+    /// Executes `command`, and returns the response of the TPM.
+    fn execute_command<'a>(&'a mut self, command: &[u8]) -> &'a [u8];
+}
+
+/// How the TPM is exposed to the guest.
+///
+/// The default is the MMIO interface that the platform can describe: CRB with ACPI on x86_64, and
+/// TIS with the device tree elsewhere.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TpmInterface {
+    /// Command Response Buffer interface at a fixed MMIO address, described to the guest by the
+    /// ACPI TPM2 table, for the standard TPM drivers of firmware and kernels. Only on x86_64.
+    #[cfg_attr(target_arch = "x86_64", default)]
+    Crb,
+    /// FIFO interface at a fixed MMIO address, described to the guest by the device tree, for the
+    /// standard TPM drivers of firmware and kernels. Not on x86_64.
+    #[cfg_attr(not(target_arch = "x86_64"), default)]
+    Tis,
+    /// virtio-tpm device.
+    Virtio,
+}
+
+impl TpmInterface {
+    /// Returns whether the platform can describe this interface to the guest.
+    pub fn is_supported(self) -> bool {
+        match self {
+            TpmInterface::Crb => cfg!(target_arch = "x86_64"),
+            TpmInterface::Tis => !cfg!(target_arch = "x86_64"),
+            TpmInterface::Virtio => true,
+        }
+    }
+}
+
+/// Parameters of the TPM of the guest.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromKeyValues)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TpmParameters {
+    /// Path to the control socket of the swtpm instance emulating the TPM.
+    pub swtpm: PathBuf,
+    /// How the TPM is exposed to the guest.
+    #[serde(default)]
+    pub interface: TpmInterface,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_keyvalue::from_key_values;
+
+    use super::*;
+
+    #[test]
+    fn parse_tpm_parameters() {
+        let params: TpmParameters = from_key_values("swtpm=/run/swtpm.sock").unwrap();
+        assert_eq!(
+            params,
+            TpmParameters {
+                swtpm: PathBuf::from("/run/swtpm.sock"),
+                interface: TpmInterface::default(),
+            }
+        );
+        assert!(params.interface.is_supported());
+
+        let params: TpmParameters =
+            from_key_values("swtpm=/run/swtpm.sock,interface=virtio").unwrap();
+        assert_eq!(params.interface, TpmInterface::Virtio);
+
+        let params: TpmParameters = from_key_values("swtpm=/run/swtpm.sock,interface=tis").unwrap();
+        assert_eq!(params.interface, TpmInterface::Tis);
+
+        assert!(from_key_values::<TpmParameters>("interface=crb").is_err());
+        assert!(from_key_values::<TpmParameters>("swtpm=/a,interface=fifo").is_err());
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! TPM backend talking to a swtpm instance, started with `swtpm socket --tpm2 --ctrl
+//! type=unixio,path=PATH`.
+//!
+//! Commands are sent over the control socket, in the format of `tpm_ioctl.h` with big-endian
+//! fields. TPM commands are sent over a data channel handed to swtpm with `CMD_SET_DATAFD`.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+
+use base::error;
+use base::ScmSocket;
+use remain::sorted;
+use thiserror::Error;
+
+use super::TpmBackend;
+use super::TPM_HEADER_SIZE;
+use super::TPM_RC_FAILURE_RESPONSE;
+
+// Control commands.
+const CMD_INIT: u32 = 2;
+const CMD_SET_DATAFD: u32 = 16;
+const CMD_SET_BUFFERSIZE: u32 = 17;
+
+/// An error of the swtpm backend.
+#[sorted]
+#[derive(Error, Debug)]
+pub enum SwtpmError {
+    /// Failed to connect to the control socket.
+    #[error("failed to connect to swtpm at {0}: {1}")]
+    Connect(PathBuf, io::Error),
+    /// A control command failed to be sent or answered.
+    #[error("swtpm control command {0} failed: {1}")]
+    Control(u32, io::Error),
+    /// swtpm returned an error for a control command.
+    #[error("swtpm control command {cmd} returned TPM error {result:#x}")]
+    ControlResult { cmd: u32, result: u32 },
+    /// Failed to create the data channel.
+    #[error("failed to create the swtpm data channel: {0}")]
+    CreateDataChannel(io::Error),
+    /// Failed to exchange a TPM command over the data channel.
+    #[error("failed to exchange a command with swtpm: {0}")]
+    Data(io::Error),
+    /// The response of swtpm has an invalid size.
+    #[error("swtpm response has an invalid size of {0} bytes")]
+    ResponseSize(usize),
+}
+
+pub type Result<T> = std::result::Result<T, SwtpmError>;
+
+/// TPM backend connected to swtpm.
+pub struct Swtpm {
+    ctrl: ScmSocket<UnixStream>,
+    data: UnixStream,
+    buffer_size: usize,
+    buf: Vec<u8>,
+}
+
+impl Swtpm {
+    /// Connects to the swtpm control socket at `path` and initializes the TPM, for commands and
+    /// responses of at most `buffer_size` bytes.
+    pub fn new(path: &Path, buffer_size: usize) -> Result<Swtpm> {
+        let ctrl = UnixStream::connect(path)
+            .and_then(ScmSocket::try_from)
+            .map_err(|e| SwtpmError::Connect(path.to_owned(), e))?;
+        let (data, swtpm_data) = UnixStream::pair().map_err(SwtpmError::CreateDataChannel)?;
+        let mut swtpm = Swtpm {
+            ctrl,
+            data,
+            buffer_size,
+            buf: Vec::new(),
+        };
+
+        swtpm.ctrl_cmd(CMD_SET_DATAFD, &[], &[swtpm_data.as_raw_fd()], 4)?;
+        // swtpm adjusts the size to its limits, and reports the TPM_PT_MAX_COMMAND_SIZE and
+        // TPM_PT_MAX_RESPONSE_SIZE properties accordingly.
+        let response = swtpm.ctrl_cmd(
+            CMD_SET_BUFFERSIZE,
+            &(buffer_size as u32).to_be_bytes(),
+            &[],
+            16,
+        )?;
+        swtpm.buffer_size = u32::from_be_bytes(response[4..8].try_into().unwrap()) as usize;
+        swtpm.ctrl_cmd(CMD_INIT, &0u32.to_be_bytes(), &[], 4)?;
+        Ok(swtpm)
+    }
+
+    // Sends the control command `cmd` with `payload` and `fds`, and returns its response of
+    // `response_len` bytes, which starts with the result code.
+    fn ctrl_cmd(
+        &mut self,
+        cmd: u32,
+        payload: &[u8],
+        fds: &[RawFd],
+        response_len: usize,
+    ) -> Result<Vec<u8>> {
+        let mut request = cmd.to_be_bytes().to_vec();
+        request.extend_from_slice(payload);
+        let sent = self
+            .ctrl
+            .send_with_fds(&request, fds)
+            .map_err(|e| SwtpmError::Control(cmd, e))?;
+        if sent != request.len() {
+            return Err(SwtpmError::Control(
+                cmd,
+                io::Error::from(io::ErrorKind::WriteZero),
+            ));
+        }
+        let mut response = vec![0; response_len];
+        self.ctrl
+            .inner_mut()
+            .read_exact(&mut response)
+            .map_err(|e| SwtpmError::Control(cmd, e))?;
+        let result = u32::from_be_bytes(response[..4].try_into().unwrap());
+        if result != 0 {
+            return Err(SwtpmError::ControlResult { cmd, result });
+        }
+        Ok(response)
+    }
+
+    fn try_execute_command(&mut self, command: &[u8]) -> Result<()> {
+        self.data.write_all(command).map_err(SwtpmError::Data)?;
+        let mut header = [0u8; TPM_HEADER_SIZE];
+        self.data
+            .read_exact(&mut header)
+            .map_err(SwtpmError::Data)?;
+        let size = u32::from_be_bytes(header[2..6].try_into().unwrap()) as usize;
+        if size < TPM_HEADER_SIZE || size > self.buffer_size {
+            return Err(SwtpmError::ResponseSize(size));
+        }
+        self.buf.clear();
+        self.buf.extend_from_slice(&header);
+        self.buf.resize(size, 0);
+        self.data
+            .read_exact(&mut self.buf[TPM_HEADER_SIZE..])
+            .map_err(SwtpmError::Data)
+    }
+}
+
+impl TpmBackend for Swtpm {
+    fn execute_command<'a>(&'a mut self, command: &[u8]) -> &'a [u8] {
+        match self.try_execute_command(command) {
+            Ok(()) => &self.buf,
+            Err(e) => {
+                error!("{}", e);
+                TPM_RC_FAILURE_RESPONSE
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    // Reads the control command from `ctrl`, and returns it with the descriptor that came with it.
+    fn recv_ctrl_cmd(ctrl: &ScmSocket<UnixStream>, len: usize) -> (Vec<u8>, Option<UnixStream>) {
+        let mut buf = vec![0; len];
+        let (read, mut fds) = ctrl.recv_with_fds(&mut buf, 1).unwrap();
+        assert_eq!(read, len);
+        (buf, fds.pop().map(UnixStream::from))
+    }
+
+    #[test]
+    fn execute_command() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("swtpm.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // A fake swtpm, answering with the command it receives.
+        let server = thread::spawn(move || {
+            let ctrl = ScmSocket::try_from(listener.accept().unwrap().0).unwrap();
+            let (cmd, data) = recv_ctrl_cmd(&ctrl, 4);
+            assert_eq!(cmd, CMD_SET_DATAFD.to_be_bytes());
+            let mut data = data.expect("no data channel");
+            ctrl.inner().write_all(&0u32.to_be_bytes()).unwrap();
+
+            let (cmd, _) = recv_ctrl_cmd(&ctrl, 8);
+            assert_eq!(cmd[..4], CMD_SET_BUFFERSIZE.to_be_bytes());
+            assert_eq!(cmd[4..], 4096u32.to_be_bytes());
+            let mut response = Vec::new();
+            for value in [0u32, 3968, 1024, 3968] {
+                response.extend_from_slice(&value.to_be_bytes());
+            }
+            ctrl.inner().write_all(&response).unwrap();
+
+            let (cmd, _) = recv_ctrl_cmd(&ctrl, 8);
+            assert_eq!(cmd[..4], CMD_INIT.to_be_bytes());
+            ctrl.inner().write_all(&0u32.to_be_bytes()).unwrap();
+
+            let mut command = [0u8; 12];
+            data.read_exact(&mut command).unwrap();
+            data.write_all(&command).unwrap();
+        });
+
+        let mut swtpm = Swtpm::new(&path, 4096).unwrap();
+        assert_eq!(swtpm.buffer_size, 3968);
+        let command = [0x80, 0x01, 0, 0, 0, 12, 0, 0, 0x01, 0x7b, 0, 8];
+        assert_eq!(swtpm.execute_command(&command), command);
+        server.join().unwrap();
+
+        // The connection is gone.
+        assert_eq!(swtpm.execute_command(&command), TPM_RC_FAILURE_RESPONSE);
+    }
+}
@@ -0,0 +1,493 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! TPM 2.0 FIFO interface (TIS), as specified by the TCG PC Client Platform TPM Profile, for
+//! platforms described by a device tree (`tcg,tpm-tis-mmio`).
+//!
+//! Only locality 0 is implemented, and the device has no interrupt: the guest polls the registers.
+//! Commands are executed on a thread of their own, and TPM_STS reads as neither ready for a command
+//! nor with a response available until they complete.
+
+use anyhow::Context;
+use base::error;
+use base::warn;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::CommandThread;
+use super::TpmBackend;
+use super::TPM_HEADER_SIZE;
+use super::TPM_RC_FAILURE_RESPONSE;
+use crate::pci::CrosvmDeviceId;
+use crate::BusAccessInfo;
+use crate::BusDevice;
+use crate::DeviceId;
+use crate::Suspendable;
+
+/// The size of the MMIO region of the device: the registers of the 5 localities.
+pub const TPM_TIS_MMIO_SIZE: u64 = 0x5000;
+
+/// The size of the largest command and response.
+pub const TPM_TIS_BUFFER_SIZE: usize = 4096;
+
+// The registers of each locality take a 4k page.
+const LOCALITY_SIZE: u64 = 0x1000;
+
+// Registers.
+const TPM_ACCESS: u64 = 0x00;
+const TPM_INT_ENABLE: u64 = 0x08;
+const TPM_INT_VECTOR: u64 = 0x0c;
+const TPM_INT_STATUS: u64 = 0x10;
+const TPM_INTF_CAPABILITY: u64 = 0x14;
+const TPM_STS: u64 = 0x18;
+const TPM_DATA_FIFO: u64 = 0x24;
+const TPM_INTERFACE_ID: u64 = 0x30;
+const TPM_XDATA_FIFO: u64 = 0x80;
+const TPM_DID_VID: u64 = 0xf00;
+const TPM_RID: u64 = 0xf04;
+
+// The FIFOs accept accesses of up to 4 bytes anywhere in these ranges.
+const DATA_FIFO_SIZE: u64 = 4;
+const XDATA_FIFO_SIZE: u64 = 0x40;
+
+// TPM_ACCESS
+const ACCESS_REQUEST_USE: u8 = 1 << 1;
+const ACCESS_ACTIVE_LOCALITY: u8 = 1 << 5;
+const ACCESS_REG_VALID_STS: u8 = 1 << 7;
+
+// TPM_STS
+const STS_RESPONSE_RETRY: u32 = 1 << 1;
+const STS_EXPECT: u32 = 1 << 3;
+const STS_DATA_AVAIL: u32 = 1 << 4;
+const STS_GO: u32 = 1 << 5;
+const STS_COMMAND_READY: u32 = 1 << 6;
+const STS_VALID: u32 = 1 << 7;
+const STS_BURST_COUNT_SHIFT: u32 = 8;
+const STS_FAMILY_TPM2: u32 = 1 << 26;
+
+// TPM_INTF_CAPABILITY: a static burst count, 64-byte transfers, and the interface of TPM 2.0.
+const INTF_CAPABILITY: u32 = (1 << 8) | (3 << 9) | (3 << 28);
+
+// TPM_INTERFACE_ID: a FIFO interface for TPM 2.0, with the TIS register layout.
+const INTERFACE_ID: u32 = 1 << 13;
+
+// TPM_DID_VID
+const DID_VID: u32 = 0x0001 << 16 | 0x1ae0;
+
+// The burst count reported in TPM_STS.
+const BURST_COUNT: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum State {
+    Idle,
+    // Ready for a command.
+    Ready,
+    // Receiving a command.
+    Reception,
+    // Executing a command.
+    Execution,
+    // The response is available.
+    Completion,
+}
+
+/// TPM device with a FIFO interface.
+pub struct TpmTis {
+    commands: CommandThread,
+    state: TisState,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct TisState {
+    state: State,
+    locality_active: bool,
+    int_enable: u32,
+    int_vector: u8,
+    // The command being received, or the response being read.
+    buffer: Vec<u8>,
+    // The offset of the next byte of the response to read.
+    read_offset: usize,
+}
+
+impl TpmTis {
+    /// Creates a device executing the commands with `backend`.
+    pub fn new(backend: Box<dyn TpmBackend>) -> TpmTis {
+        TpmTis {
+            commands: CommandThread::new("tpm_tis", backend),
+            state: TisState {
+                state: State::Idle,
+                locality_active: false,
+                int_enable: 0,
+                int_vector: 0,
+                buffer: Vec::new(),
+                read_offset: 0,
+            },
+        }
+    }
+
+    // The size of the command being received, once its header was.
+    fn command_size(&self) -> Option<usize> {
+        let size = self.state.buffer.get(2..6)?;
+        Some(u32::from_be_bytes(size.try_into().unwrap()) as usize)
+    }
+
+    fn expects_data(&self) -> bool {
+        match self.command_size() {
+            Some(size) => self.state.buffer.len() < size.max(TPM_HEADER_SIZE),
+            None => true,
+        }
+    }
+
+    fn status(&self) -> u32 {
+        let (status, burst_count) = match self.state.state {
+            State::Idle | State::Execution => (STS_VALID, 0),
+            State::Ready => (STS_VALID | STS_COMMAND_READY, BURST_COUNT),
+            State::Reception => {
+                let expect = if self.expects_data() { STS_EXPECT } else { 0 };
+                (STS_VALID | expect, BURST_COUNT)
+            }
+            State::Completion => {
+                let remaining = self.state.buffer.len() - self.state.read_offset;
+                let data_avail = if remaining > 0 { STS_DATA_AVAIL } else { 0 };
+                (STS_VALID | data_avail, remaining.min(BURST_COUNT))
+            }
+        };
+        STS_FAMILY_TPM2 | (burst_count as u32) << STS_BURST_COUNT_SHIFT | status
+    }
+
+    fn access(&mut self, value: u8) {
+        if value & ACCESS_REQUEST_USE != 0 {
+            self.state.locality_active = true;
+        } else if value & ACCESS_ACTIVE_LOCALITY != 0 {
+            // Relinquishing the locality aborts the command, if any.
+            self.state.locality_active = false;
+            if self.state.state != State::Execution {
+                self.state.state = State::Idle;
+            }
+        }
+    }
+
+    fn write_status(&mut self, value: u32) {
+        if value & STS_COMMAND_READY != 0 {
+            match self.state.state {
+                // swtpm can't cancel commands, so they always complete.
+                State::Execution => {}
+                State::Idle | State::Completion | State::Reception => {
+                    self.state.buffer.clear();
+                    self.state.state = State::Ready;
+                }
+                State::Ready => {}
+            }
+        } else if value & STS_GO != 0 {
+            if self.state.state == State::Reception && !self.expects_data() {
+                self.state.state = State::Execution;
+                self.commands.start(std::mem::take(&mut self.state.buffer));
+            }
+        } else if value & STS_RESPONSE_RETRY != 0 && self.state.state == State::Completion {
+            self.state.read_offset = 0;
+        }
+    }
+
+    fn write_fifo(&mut self, data: &[u8]) {
+        if !matches!(self.state.state, State::Ready | State::Reception) {
+            warn!("tpm-tis: data written while not receiving a command");
+            return;
+        }
+        self.state.state = State::Reception;
+        if self.state.buffer.len() + data.len() > TPM_TIS_BUFFER_SIZE {
+            warn!("tpm-tis: command is larger than the buffer");
+            return;
+        }
+        self.state.buffer.extend_from_slice(data);
+    }
+
+    fn read_fifo(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            *byte = match self.state.buffer.get(self.state.read_offset) {
+                Some(&byte) if self.state.state == State::Completion => {
+                    self.state.read_offset += 1;
+                    byte
+                }
+                _ => 0xff,
+            };
+        }
+    }
+
+    fn complete(&mut self, response: Vec<u8>) {
+        self.state.buffer = if response.len() > TPM_TIS_BUFFER_SIZE {
+            error!(
+                "tpm-tis: response of {} bytes is larger than the buffer",
+                response.len()
+            );
+            TPM_RC_FAILURE_RESPONSE.to_vec()
+        } else {
+            response
+        };
+        self.state.read_offset = 0;
+        self.state.state = State::Completion;
+    }
+}
+
+// Reads the little-endian `value` of a register of `size` bytes at `offset`, `data.len()` bytes
+// at a time.
+fn read_reg(data: &mut [u8], offset: u64, base: u64, size: u64, value: u32) {
+    let value = value.to_le_bytes();
+    let start = (offset - base) as usize;
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = if ((start + i) as u64) < size {
+            value[start + i]
+        } else {
+            0
+        };
+    }
+}
+
+impl BusDevice for TpmTis {
+    fn device_id(&self) -> DeviceId {
+        CrosvmDeviceId::TpmTis.into()
+    }
+
+    fn debug_label(&self) -> String {
+        "tpm-tis".to_owned()
+    }
+
+    fn read(&mut self, info: BusAccessInfo, data: &mut [u8]) {
+        if let Some(response) = self.commands.poll() {
+            self.complete(response);
+        }
+        let locality = info.offset / LOCALITY_SIZE;
+        let offset = info.offset % LOCALITY_SIZE;
+        if locality != 0 {
+            // The other localities can't be requested, and their registers read as 0xff.
+            let value = if offset == TPM_ACCESS {
+                ACCESS_REG_VALID_STS
+            } else {
+                0xff
+            };
+            data.fill(value);
+            return;
+        }
+        match offset {
+            TPM_ACCESS => {
+                let active = if self.state.locality_active {
+                    ACCESS_ACTIVE_LOCALITY
+                } else {
+                    0
+                };
+                data.fill(0);
+                data[0] = ACCESS_REG_VALID_STS | active;
+            }
+            TPM_INT_ENABLE..=0x0b => {
+                read_reg(data, offset, TPM_INT_ENABLE, 4, self.state.int_enable)
+            }
+            TPM_INT_VECTOR => read_reg(
+                data,
+                offset,
+                TPM_INT_VECTOR,
+                1,
+                self.state.int_vector as u32,
+            ),
+            TPM_INT_STATUS..=0x13 => data.fill(0),
+            TPM_INTF_CAPABILITY..=0x17 => {
+                read_reg(data, offset, TPM_INTF_CAPABILITY, 4, INTF_CAPABILITY)
+            }
+            TPM_STS..=0x1b => read_reg(data, offset, TPM_STS, 4, self.status()),
+            o if (TPM_DATA_FIFO..TPM_DATA_FIFO + DATA_FIFO_SIZE).contains(&o)
+                || (TPM_XDATA_FIFO..TPM_XDATA_FIFO + XDATA_FIFO_SIZE).contains(&o) =>
+            {
+                self.read_fifo(data)
+            }
+            TPM_INTERFACE_ID..=0x33 => read_reg(data, offset, TPM_INTERFACE_ID, 4, INTERFACE_ID),
+            TPM_DID_VID..=0xf03 => read_reg(data, offset, TPM_DID_VID, 4, DID_VID),
+            TPM_RID => read_reg(data, offset, TPM_RID, 1, 0),
+            _ => data.fill(0),
+        }
+    }
+
+    fn write(&mut self, info: BusAccessInfo, data: &[u8]) {
+        if let Some(response) = self.commands.poll() {
+            self.complete(response);
+        }
+        let locality = info.offset / LOCALITY_SIZE;
+        let offset = info.offset % LOCALITY_SIZE;
+        if locality != 0 || data.is_empty() {
+            return;
+        }
+        match offset {
+            TPM_ACCESS => self.access(data[0]),
+            TPM_INT_ENABLE => {
+                let mut value = [0u8; 4];
+                let len = data.len().min(4);
+                value[..len].copy_from_slice(&data[..len]);
+                self.state.int_enable = u32::from_le_bytes(value);
+            }
+            TPM_INT_VECTOR => self.state.int_vector = data[0],
+            TPM_STS..=0x1b => {
+                // Only the low byte has bits the guest can set, the others are read-only.
+                if offset == TPM_STS {
+                    self.write_status(data[0] as u32);
+                }
+            }
+            o if (TPM_DATA_FIFO..TPM_DATA_FIFO + DATA_FIFO_SIZE).contains(&o)
+                || (TPM_XDATA_FIFO..TPM_XDATA_FIFO + XDATA_FIFO_SIZE).contains(&o) =>
+            {
+                self.write_fifo(data)
+            }
+            // The other registers are read-only.
+            _ => {}
+        }
+    }
+}
+
+impl Suspendable for TpmTis {
+    fn sleep(&mut self) -> anyhow::Result<()> {
+        // The response belongs to the snapshot of the registers.
+        if let Some(response) = self.commands.wait() {
+            self.complete(response);
+        }
+        Ok(())
+    }
+
+    fn wake(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    // The state of the TPM itself belongs to the backend, e.g. the state files of swtpm.
+    fn snapshot(&mut self) -> anyhow::Result<serde_json::Value> {
+        serde_json::to_value(self.state.clone()).context("failed to snapshot tpm-tis")
+    }
+
+    fn restore(&mut self, data: serde_json::Value) -> anyhow::Result<()> {
+        let state: TisState =
+            serde_json::from_value(data).context("failed to deserialize tpm-tis")?;
+        anyhow::ensure!(
+            state.state != State::Execution,
+            "tpm-tis snapshot was taken while executing a command"
+        );
+        self.state = state;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::suspendable_tests;
+
+    const BASE: u64 = 0x4000;
+
+    // Answers every command with a TPM_RC_SUCCESS response carrying the command code.
+    struct EchoBackend(Vec<u8>);
+
+    impl TpmBackend for EchoBackend {
+        fn execute_command<'a>(&'a mut self, command: &[u8]) -> &'a [u8] {
+            self.0 = vec![0x80, 0x01, 0, 0, 0, 14, 0, 0, 0, 0];
+            self.0.extend_from_slice(&command[6..10]);
+            &self.0
+        }
+    }
+
+    fn new() -> TpmTis {
+        TpmTis::new(Box::new(EchoBackend(Vec::new())))
+    }
+
+    fn info(offset: u64) -> BusAccessInfo {
+        BusAccessInfo {
+            offset,
+            address: BASE + offset,
+            id: 0,
+        }
+    }
+
+    fn read8(tis: &mut TpmTis, offset: u64) -> u8 {
+        let mut data = [0u8; 1];
+        tis.read(info(offset), &mut data);
+        data[0]
+    }
+
+    fn read32(tis: &mut TpmTis, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        tis.read(info(offset), &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write8(tis: &mut TpmTis, offset: u64, value: u8) {
+        tis.write(info(offset), &[value]);
+    }
+
+    #[test]
+    fn registers() {
+        let mut tis = new();
+        assert_eq!(read8(&mut tis, TPM_ACCESS), ACCESS_REG_VALID_STS);
+        write8(&mut tis, TPM_ACCESS, ACCESS_REQUEST_USE);
+        assert_eq!(
+            read8(&mut tis, TPM_ACCESS),
+            ACCESS_REG_VALID_STS | ACCESS_ACTIVE_LOCALITY
+        );
+        // Locality 1 can't be requested.
+        write8(&mut tis, LOCALITY_SIZE + TPM_ACCESS, ACCESS_REQUEST_USE);
+        assert_eq!(
+            read8(&mut tis, LOCALITY_SIZE + TPM_ACCESS),
+            ACCESS_REG_VALID_STS
+        );
+
+        assert_eq!(read32(&mut tis, TPM_DID_VID), 0x0001_1ae0);
+        assert_eq!(read8(&mut tis, TPM_DID_VID + 2), 0x01);
+        assert_eq!(read32(&mut tis, TPM_INTERFACE_ID) & 0xf, 0);
+        assert_eq!(read32(&mut tis, TPM_STS), STS_FAMILY_TPM2 | STS_VALID);
+
+        write8(&mut tis, TPM_STS, STS_COMMAND_READY as u8);
+        let status = read32(&mut tis, TPM_STS);
+        assert_ne!(status & STS_COMMAND_READY, 0);
+        // The burst count can be read on its own.
+        assert_eq!(read8(&mut tis, TPM_STS + 1), BURST_COUNT as u8);
+
+        // Read-only.
+        tis.write(info(TPM_DID_VID), &0u32.to_le_bytes());
+        assert_eq!(read32(&mut tis, TPM_DID_VID), 0x0001_1ae0);
+    }
+
+    #[test]
+    fn execute_command() {
+        let mut tis = new();
+        write8(&mut tis, TPM_ACCESS, ACCESS_REQUEST_USE);
+        write8(&mut tis, TPM_STS, STS_COMMAND_READY as u8);
+
+        // TPM2_GetRandom(8), written byte by byte as the Linux driver does.
+        let command = [0x80, 0x01, 0, 0, 0, 12, 0, 0, 0x01, 0x7b, 0, 8];
+        for (i, &byte) in command.iter().enumerate() {
+            write8(&mut tis, TPM_DATA_FIFO, byte);
+            let expect = read32(&mut tis, TPM_STS) & STS_EXPECT != 0;
+            assert_eq!(expect, i < command.len() - 1, "byte {}", i);
+        }
+
+        write8(&mut tis, TPM_STS, STS_GO as u8);
+        // The guest polls TPM_STS until the response is available.
+        while read32(&mut tis, TPM_STS) & STS_DATA_AVAIL == 0 {
+            std::thread::yield_now();
+        }
+        let mut response = [0u8; 14];
+        for byte in response.iter_mut() {
+            *byte = read8(&mut tis, TPM_DATA_FIFO);
+        }
+        assert_eq!(
+            response,
+            [0x80, 0x01, 0, 0, 0, 14, 0, 0, 0, 0, 0, 0, 0x01, 0x7b]
+        );
+        assert_eq!(read32(&mut tis, TPM_STS) & STS_DATA_AVAIL, 0);
+
+        // The response can be read again.
+        write8(&mut tis, TPM_STS, STS_RESPONSE_RETRY as u8);
+        assert_eq!(read8(&mut tis, TPM_DATA_FIFO), 0x80);
+
+        // Ready for the next command.
+        write8(&mut tis, TPM_STS, STS_COMMAND_READY as u8);
+        assert_ne!(read32(&mut tis, TPM_STS) & STS_COMMAND_READY, 0);
+    }
+
+    fn modify_device(tis: &mut TpmTis) {
+        write8(tis, TPM_ACCESS, ACCESS_REQUEST_USE);
+    }
+
+    suspendable_tests!(tpm_tis, new(), modify_device);
+}
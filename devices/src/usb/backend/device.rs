@@ -25,6 +25,8 @@ use usb_util::TransferStatus;
 use usb_util::UsbRequestSetup;
 use zerocopy::AsBytes;
 
+use crate::usb::backend::emulated_backend::emulated_device::EmulatedDevice;
+use crate::usb::backend::emulated_backend::emulated_device::EmulatedTransfer;
 use crate::usb::backend::endpoint::ControlEndpointState;
 use crate::usb::backend::endpoint::UsbEndpoint;
 use crate::usb::backend::error::Error;
@@ -34,6 +36,8 @@ use crate::usb::backend::transfer::BackendTransfer;
 use crate::usb::backend::transfer::BackendTransferHandle;
 use crate::usb::backend::transfer::BackendTransferType;
 use crate::usb::backend::transfer::ControlTransferState;
+use crate::usb::backend::usbip_backend::usbip_device::UsbipDevice;
+use crate::usb::backend::usbip_backend::usbip_device::UsbipTransfer;
 use crate::usb::backend::utils::multi_dispatch;
 use crate::usb::backend::utils::update_transfer_state;
 use crate::usb::xhci::scatter_gather_buffer::ScatterGatherBuffer;
@@ -50,11 +54,15 @@ use crate::utils::FailHandle;
 pub enum BackendDeviceType {
     // Real device on the host, backed by usbdevfs
     HostDevice(HostDevice),
+    // Device emulated by crosvm
+    EmulatedDevice(EmulatedDevice),
+    // Device on a remote host, imported from a USB/IP server
+    UsbipDevice(UsbipDevice),
 }
 
 impl AsRawDescriptor for BackendDeviceType {
     fn as_raw_descriptor(&self) -> RawDescriptor {
-        multi_dispatch!(self, BackendDeviceType, HostDevice, as_raw_descriptor)
+        multi_dispatch!(self, BackendDeviceType, HostDevice EmulatedDevice UsbipDevice, as_raw_descriptor)
     }
 }
 
@@ -66,7 +74,7 @@ impl BackendDevice for BackendDeviceType {
         multi_dispatch!(
             self,
             BackendDeviceType,
-            HostDevice,
+            HostDevice EmulatedDevice UsbipDevice,
             submit_backend_transfer,
             transfer
         )
@@ -76,7 +84,7 @@ impl BackendDevice for BackendDeviceType {
         multi_dispatch!(
             self,
             BackendDeviceType,
-            HostDevice,
+            HostDevice EmulatedDevice UsbipDevice,
             detach_event_handler,
             event_loop
         )
@@ -86,7 +94,7 @@ impl BackendDevice for BackendDeviceType {
         multi_dispatch!(
             self,
             BackendDeviceType,
-            HostDevice,
+            HostDevice EmulatedDevice UsbipDevice,
             request_transfer_buffer,
             size
         )
@@ -101,7 +109,7 @@ impl BackendDevice for BackendDeviceType {
         multi_dispatch!(
             self,
             BackendDeviceType,
-            HostDevice,
+            HostDevice EmulatedDevice UsbipDevice,
             build_bulk_transfer,
             ep_addr,
             transfer_buffer,
@@ -117,7 +125,7 @@ impl BackendDevice for BackendDeviceType {
         multi_dispatch!(
             self,
             BackendDeviceType,
-            HostDevice,
+            HostDevice EmulatedDevice UsbipDevice,
             build_interrupt_transfer,
             ep_addr,
             transfer_buffer
@@ -128,20 +136,20 @@ impl BackendDevice for BackendDeviceType {
         multi_dispatch!(
             self,
             BackendDeviceType,
-            HostDevice,
+            HostDevice EmulatedDevice UsbipDevice,
             get_control_transfer_state
         )
     }
 
     fn get_device_state(&mut self) -> Arc<RwLock<DeviceState>> {
-        multi_dispatch!(self, BackendDeviceType, HostDevice, get_device_state)
+        multi_dispatch!(self, BackendDeviceType, HostDevice EmulatedDevice UsbipDevice, get_device_state)
     }
 
     fn get_active_config_descriptor(&mut self) -> Result<ConfigDescriptorTree> {
         multi_dispatch!(
             self,
             BackendDeviceType,
-            HostDevice,
+            HostDevice EmulatedDevice UsbipDevice,
             get_active_config_descriptor
         )
     }
@@ -150,7 +158,7 @@ impl BackendDevice for BackendDeviceType {
         multi_dispatch!(
             self,
             BackendDeviceType,
-            HostDevice,
+            HostDevice EmulatedDevice UsbipDevice,
             get_config_descriptor,
             config
         )
@@ -160,7 +168,7 @@ impl BackendDevice for BackendDeviceType {
         multi_dispatch!(
             self,
             BackendDeviceType,
-            HostDevice,
+            HostDevice EmulatedDevice UsbipDevice,
             get_config_descriptor_by_index,
             config_index
         )
@@ -170,7 +178,7 @@ impl BackendDevice for BackendDeviceType {
         multi_dispatch!(
             self,
             BackendDeviceType,
-            HostDevice,
+            HostDevice EmulatedDevice UsbipDevice,
             get_device_descriptor_tree
         )
     }
@@ -179,7 +187,7 @@ impl BackendDevice for BackendDeviceType {
         multi_dispatch!(
             self,
             BackendDeviceType,
-            HostDevice,
+            HostDevice EmulatedDevice UsbipDevice,
             get_active_configuration
         )
     }
@@ -188,7 +196,7 @@ impl BackendDevice for BackendDeviceType {
         multi_dispatch!(
             self,
             BackendDeviceType,
-            HostDevice,
+            HostDevice EmulatedDevice UsbipDevice,
             set_active_configuration,
             config
         )
@@ -198,7 +206,7 @@ impl BackendDevice for BackendDeviceType {
         multi_dispatch!(
             self,
             BackendDeviceType,
-            HostDevice,
+            HostDevice EmulatedDevice UsbipDevice,
             clear_feature,
             value,
             index
@@ -209,7 +217,7 @@ impl BackendDevice for BackendDeviceType {
         multi_dispatch!(
             self,
             BackendDeviceType,
-            HostDevice,
+            HostDevice EmulatedDevice UsbipDevice,
             create_endpoints,
             config_descriptor
         )
@@ -218,34 +226,34 @@ impl BackendDevice for BackendDeviceType {
 
 impl XhciBackendDevice for BackendDeviceType {
     fn get_backend_type(&self) -> BackendType {
-        multi_dispatch!(self, BackendDeviceType, HostDevice, get_backend_type)
+        multi_dispatch!(self, BackendDeviceType, HostDevice EmulatedDevice UsbipDevice, get_backend_type)
     }
 
     fn get_vid(&self) -> u16 {
-        multi_dispatch!(self, BackendDeviceType, HostDevice, get_vid)
+        multi_dispatch!(self, BackendDeviceType, HostDevice EmulatedDevice UsbipDevice, get_vid)
     }
 
     fn get_pid(&self) -> u16 {
-        multi_dispatch!(self, BackendDeviceType, HostDevice, get_pid)
+        multi_dispatch!(self, BackendDeviceType, HostDevice EmulatedDevice UsbipDevice, get_pid)
     }
 
     fn set_address(&mut self, address: UsbDeviceAddress) {
-        multi_dispatch!(self, BackendDeviceType, HostDevice, set_address, address)
+        multi_dispatch!(self, BackendDeviceType, HostDevice EmulatedDevice UsbipDevice, set_address, address)
     }
 
     fn reset(&mut self) -> Result<()> {
-        multi_dispatch!(self, BackendDeviceType, HostDevice, reset)
+        multi_dispatch!(self, BackendDeviceType, HostDevice EmulatedDevice UsbipDevice, reset)
     }
 
     fn get_speed(&self) -> Option<DeviceSpeed> {
-        multi_dispatch!(self, BackendDeviceType, HostDevice, get_speed)
+        multi_dispatch!(self, BackendDeviceType, HostDevice EmulatedDevice UsbipDevice, get_speed)
     }
 
     fn alloc_streams(&self, ep: u8, num_streams: u16) -> Result<()> {
         multi_dispatch!(
             self,
             BackendDeviceType,
-            HostDevice,
+            HostDevice EmulatedDevice UsbipDevice,
             alloc_streams,
             ep,
             num_streams
@@ -253,7 +261,7 @@ impl XhciBackendDevice for BackendDeviceType {
     }
 
     fn free_streams(&self, ep: u8) -> Result<()> {
-        multi_dispatch!(self, BackendDeviceType, HostDevice, free_streams, ep)
+        multi_dispatch!(self, BackendDeviceType, HostDevice EmulatedDevice UsbipDevice, free_streams, ep)
     }
 }
 
@@ -283,6 +291,7 @@ impl Drop for BackendDeviceType {
             BackendDeviceType::HostDevice(host_device) => {
                 host_device.release_interfaces();
             }
+            BackendDeviceType::EmulatedDevice(_) | BackendDeviceType::UsbipDevice(_) => {}
         }
     }
 }
@@ -294,7 +303,7 @@ impl BackendDeviceType {
     // should be passed through.
     fn intercepted_control_transfer(
         &mut self,
-        xhci_transfer: &XhciTransfer,
+        xhci_transfer: &Arc<XhciTransfer>,
         buffer: &Option<ScatterGatherBuffer>,
         control_request_setup: &UsbRequestSetup,
     ) -> Result<bool> {
@@ -325,12 +334,24 @@ impl BackendDeviceType {
             ) => {
                 usb_trace!("handling set config");
                 let config = (control_request_setup.value & 0xff) as u8;
-                match self.set_config(config) {
-                    Ok(status) => (status, 0),
-                    Err(e) => {
-                        error!("set config error: {}", e);
-                        (TransferStatus::Stalled, 0)
+                match self {
+                    BackendDeviceType::UsbipDevice(usbip_device) => {
+                        match usbip_device.start_set_configuration(config, xhci_transfer.clone()) {
+                            // The transfer completes once the server replies.
+                            Ok(()) => return Ok(true),
+                            Err(e) => {
+                                error!("set config error: {}", e);
+                                (TransferStatus::Stalled, 0)
+                            }
+                        }
                     }
+                    _ => match self.set_config(config) {
+                        Ok(status) => (status, 0),
+                        Err(e) => {
+                            error!("set config error: {}", e);
+                            (TransferStatus::Stalled, 0)
+                        }
+                    },
                 }
             }
             (
@@ -339,10 +360,6 @@ impl BackendDeviceType {
                 ControlRequestDataPhaseTransferDirection::HostToDevice,
             ) => {
                 usb_trace!("handling set interface");
-                // Right now we only have one backend device type so the match statement is a bit
-                // dry but as we add new backend types the compiler will make sure to warn we need
-                // to take care of them in this match statement. Non-host backend device might not
-                // need to set interfaces so we'll have to add a catch-all skip case.
                 match self {
                     BackendDeviceType::HostDevice(host_device) => match host_device.set_interface(
                         control_request_setup.index as u8,
@@ -354,6 +371,24 @@ impl BackendDeviceType {
                             (TransferStatus::Stalled, 0)
                         }
                     },
+                    // Emulated interfaces have no alternate settings.
+                    BackendDeviceType::EmulatedDevice(_) => match control_request_setup.value {
+                        0 => (TransferStatus::Completed, 0),
+                        _ => (TransferStatus::Stalled, 0),
+                    },
+                    BackendDeviceType::UsbipDevice(usbip_device) => match usbip_device
+                        .start_set_interface(
+                            control_request_setup.index as u8,
+                            control_request_setup.value as u8,
+                            xhci_transfer.clone(),
+                        ) {
+                        // The transfer completes once the server replies.
+                        Ok(()) => return Ok(true),
+                        Err(e) => {
+                            error!("set interface error: {}", e);
+                            (TransferStatus::Stalled, 0)
+                        }
+                    },
                 }
             }
             (
@@ -362,12 +397,29 @@ impl BackendDeviceType {
                 ControlRequestDataPhaseTransferDirection::HostToDevice,
             ) => {
                 usb_trace!("handling clear feature");
-                match self.clear_feature(control_request_setup.value, control_request_setup.index) {
-                    Ok(status) => (status, 0),
-                    Err(e) => {
-                        error!("clear feature error: {}", e);
-                        (TransferStatus::Stalled, 0)
-                    }
+                match self {
+                    BackendDeviceType::UsbipDevice(usbip_device) => match usbip_device
+                        .start_clear_feature(
+                            control_request_setup.value,
+                            control_request_setup.index,
+                            xhci_transfer.clone(),
+                        ) {
+                        // The transfer completes once the server replies.
+                        Ok(()) => return Ok(true),
+                        Err(e) => {
+                            error!("clear feature error: {}", e);
+                            (TransferStatus::Stalled, 0)
+                        }
+                    },
+                    _ => match self
+                        .clear_feature(control_request_setup.value, control_request_setup.index)
+                    {
+                        Ok(status) => (status, 0),
+                        Err(e) => {
+                            error!("clear feature error: {}", e);
+                            (TransferStatus::Stalled, 0)
+                        }
+                    },
                 }
             }
             (
@@ -397,6 +449,24 @@ impl BackendDeviceType {
                                 }
                             }
                         }
+                        BackendDeviceType::EmulatedDevice(emulated_device) => match emulated_device
+                            .get_config_descriptor_raw(buffer, control_request_setup.value as u8)
+                        {
+                            Ok((status, b)) => (status, b),
+                            Err(e) => {
+                                error!("get descriptor error: {}", e);
+                                (TransferStatus::Stalled, 0)
+                            }
+                        },
+                        BackendDeviceType::UsbipDevice(usbip_device) => match usbip_device
+                            .get_config_descriptor_raw(buffer, control_request_setup.value as u8)
+                        {
+                            Ok((status, b)) => (status, b),
+                            Err(e) => {
+                                error!("get descriptor error: {}", e);
+                                (TransferStatus::Stalled, 0)
+                            }
+                        },
                     }
                 } else {
                     return Ok(false);
@@ -449,12 +519,17 @@ impl BackendDeviceType {
             buffer
         };
 
-        // TODO(morg): this match can be abstracted once we have more backends
         let mut control_transfer = match self {
             BackendDeviceType::HostDevice(_) => BackendTransferType::HostDevice(
                 Transfer::new_control(TransferBuffer::Vector(control_buffer))
                     .map_err(Error::CreateTransfer)?,
             ),
+            BackendDeviceType::EmulatedDevice(_) => BackendTransferType::EmulatedDevice(
+                EmulatedTransfer::new(0, TransferBuffer::Vector(control_buffer)),
+            ),
+            BackendDeviceType::UsbipDevice(_) => BackendTransferType::UsbipDevice(
+                UsbipTransfer::new(0, TransferBuffer::Vector(control_buffer)),
+            ),
         };
 
         let tmp_transfer = xhci_transfer.clone();
@@ -612,6 +687,7 @@ impl BackendDeviceType {
             BackendDeviceType::HostDevice(host_device) => {
                 host_device.release_interfaces();
             }
+            BackendDeviceType::EmulatedDevice(_) | BackendDeviceType::UsbipDevice(_) => {}
         };
 
         let cur_config = match self.get_active_configuration() {
@@ -645,6 +721,7 @@ impl BackendDeviceType {
             BackendDeviceType::HostDevice(host_device) => {
                 host_device.claim_interfaces(&config_descriptor);
             }
+            BackendDeviceType::EmulatedDevice(_) | BackendDeviceType::UsbipDevice(_) => {}
         };
 
         self.create_endpoints(&config_descriptor)?;
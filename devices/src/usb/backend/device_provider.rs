@@ -15,16 +15,20 @@ use base::EventType;
 use base::RawDescriptor;
 use base::Tube;
 use sync::Mutex;
+use vm_control::EmulatedUsbDevice;
 use vm_control::UsbControlAttachedDevice;
 use vm_control::UsbControlCommand;
 use vm_control::UsbControlResult;
 use vm_control::USB_CONTROL_MAX_PORTS;
 
 use crate::usb::backend::device::BackendDevice;
+use crate::usb::backend::device::BackendDeviceType;
 use crate::usb::backend::device::DeviceState;
+use crate::usb::backend::emulated_backend::emulated_backend_device_provider::attach_emulated_backend_device;
 use crate::usb::backend::error::Error;
 use crate::usb::backend::error::Result;
 use crate::usb::backend::host_backend::host_backend_device_provider::attach_host_backend_device;
+use crate::usb::backend::usbip_backend::usbip_backend_device_provider::attach_usbip_backend_device;
 use crate::usb::xhci::usb_hub::UsbHub;
 use crate::usb::xhci::xhci_backend_device::XhciBackendDevice;
 use crate::usb::xhci::xhci_backend_device_provider::XhciBackendDeviceProvider;
@@ -139,6 +143,9 @@ pub struct ProviderInner {
 
 struct DeviceContext {
     event_handler: Arc<dyn EventHandler>,
+    // Handler of the external events of emulated devices, e.g. the input of HID devices. It is
+    // only kept alive here, the event loop holds a weak reference to it.
+    _function_event_handler: Option<Arc<dyn EventHandler>>,
     device: Arc<Mutex<dyn BackendDevice>>,
 }
 
@@ -189,6 +196,7 @@ impl ProviderInner {
 
         let device_ctx = DeviceContext {
             event_handler,
+            _function_event_handler: None,
             device: host_device.clone(),
         };
 
@@ -205,6 +213,113 @@ impl ProviderInner {
         }
     }
 
+    fn handle_attach_emulated_device(&self, device: EmulatedUsbDevice) -> UsbControlResult {
+        let (emulated_device, event_handler, function_event_handler) =
+            match attach_emulated_backend_device(
+                device,
+                DeviceState::new(self.fail_handle.clone(), self.job_queue.clone()),
+            ) {
+                Ok(handlers) => handlers,
+                Err(e) => {
+                    error!("could not create emulated USB device: {}", e);
+                    return UsbControlResult::FailedToOpenDevice;
+                }
+            };
+
+        if let Err(e) = self.event_loop.add_event(
+            &*emulated_device.lock(),
+            EventType::Read,
+            Arc::downgrade(&event_handler),
+        ) {
+            error!("failed to add USB device to event handler: {}", e);
+            return UsbControlResult::FailedToOpenDevice;
+        }
+
+        if let Some(function_event_handler) = &function_event_handler {
+            let device = emulated_device.lock();
+            let source = match &*device {
+                BackendDeviceType::EmulatedDevice(d) => d.event_source(),
+                _ => None,
+            };
+            if let Some(source) = source {
+                if let Err(e) = self.event_loop.add_event(
+                    source,
+                    EventType::Read,
+                    Arc::downgrade(function_event_handler),
+                ) {
+                    error!("failed to add USB device events to event handler: {}", e);
+                    let _ = device.detach_event_handler(&self.event_loop);
+                    return UsbControlResult::FailedToOpenDevice;
+                }
+            }
+        }
+
+        let device_ctx = DeviceContext {
+            event_handler,
+            _function_event_handler: function_event_handler,
+            device: emulated_device.clone(),
+        };
+
+        let port = self.usb_hub.connect_backend(emulated_device);
+        match port {
+            Ok(port) => {
+                self.devices.lock().insert(port, device_ctx);
+                UsbControlResult::Ok { port }
+            }
+            Err(e) => {
+                error!("failed to connect device to hub: {}", e);
+                UsbControlResult::NoAvailablePort
+            }
+        }
+    }
+
+    fn handle_attach_usbip_device(&self, socket: File, busid: &str) -> UsbControlResult {
+        let (usbip_device, event_handler) = match attach_usbip_backend_device(
+            socket,
+            busid,
+            DeviceState::new(self.fail_handle.clone(), self.job_queue.clone()),
+        ) {
+            Ok((usbip_device, event_handler)) => (usbip_device, event_handler),
+            Err(e) => {
+                error!("could not import USB/IP device {}: {}", busid, e);
+                return UsbControlResult::FailedToOpenDevice;
+            }
+        };
+
+        if let Err(e) = self.event_loop.add_event(
+            &*usbip_device.lock(),
+            EventType::Read,
+            Arc::downgrade(&event_handler),
+        ) {
+            error!("failed to add USB device to event handler: {}", e);
+            return UsbControlResult::FailedToOpenDevice;
+        }
+
+        // Resetting the device is used to make sure it is in a known state, but it may
+        // still function if the reset fails.
+        if let Err(e) = usbip_device.lock().reset() {
+            error!("failed to reset device after attach: {:?}", e);
+        }
+
+        let device_ctx = DeviceContext {
+            event_handler,
+            _function_event_handler: None,
+            device: usbip_device.clone(),
+        };
+
+        let port = self.usb_hub.connect_backend(usbip_device);
+        match port {
+            Ok(port) => {
+                self.devices.lock().insert(port, device_ctx);
+                UsbControlResult::Ok { port }
+            }
+            Err(e) => {
+                error!("failed to connect device to hub: {}", e);
+                UsbControlResult::NoAvailablePort
+            }
+        }
+    }
+
     fn handle_detach_device(&self, port: u8) -> UsbControlResult {
         match self.usb_hub.disconnect_port(port) {
             Ok(()) => {
@@ -258,6 +373,12 @@ impl ProviderInner {
         let cmd = tube.recv().map_err(Error::ReadControlTube)?;
         let result = match cmd {
             UsbControlCommand::AttachDevice { file } => self.handle_attach_device(file),
+            UsbControlCommand::AttachEmulatedDevice { device } => {
+                self.handle_attach_emulated_device(device)
+            }
+            UsbControlCommand::AttachUsbipDevice { socket, busid } => {
+                self.handle_attach_usbip_device(socket, &busid)
+            }
             UsbControlCommand::DetachDevice { port } => self.handle_detach_device(port),
             UsbControlCommand::ListDevice { ports } => self.handle_list_devices(ports),
         };
@@ -0,0 +1,76 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use sync::Mutex;
+use vm_control::EmulatedUsbDevice;
+use vm_control::UsbHidKind;
+
+use crate::usb::backend::device::BackendDeviceType;
+use crate::usb::backend::device::DeviceState;
+use crate::usb::backend::emulated_backend::emulated_device::EmulatedDevice;
+use crate::usb::backend::emulated_backend::emulated_device::UsbFunction;
+use crate::usb::backend::emulated_backend::hid::Hid;
+use crate::usb::backend::emulated_backend::hid::HidKind;
+use crate::usb::backend::emulated_backend::mass_storage::MassStorage;
+use crate::usb::backend::error::Error;
+use crate::usb::backend::error::Result;
+use crate::usb::backend::utils::UsbFunctionEventHandler;
+use crate::usb::backend::utils::UsbUtilEventHandler;
+use crate::utils::EventHandler;
+
+// Creates the function of the emulated device described by `device`.
+fn create_usb_function(device: EmulatedUsbDevice) -> anyhow::Result<Box<dyn UsbFunction>> {
+    match device {
+        EmulatedUsbDevice::MassStorage {
+            path,
+            file,
+            read_only,
+        } => {
+            let disk = disk::create_disk_file(file, true, disk::MAX_NESTING_DEPTH, &path)
+                .with_context(|| format!("failed to open disk image {}", path.display()))?;
+            Ok(Box::new(MassStorage::new(disk, read_only)?))
+        }
+        EmulatedUsbDevice::Hid { kind, events } => {
+            let kind = match kind {
+                UsbHidKind::Keyboard => HidKind::Keyboard,
+                UsbHidKind::Tablet => HidKind::Tablet,
+            };
+            Ok(Box::new(Hid::new(kind, events)))
+        }
+    }
+}
+
+/// Attaches an emulated device to the backend. This returns the device, the event handler of its
+/// transfer completions and, if the device has one, the event handler of its external events.
+pub fn attach_emulated_backend_device(
+    device: EmulatedUsbDevice,
+    device_state: DeviceState,
+) -> Result<(
+    Arc<Mutex<BackendDeviceType>>,
+    Arc<dyn EventHandler>,
+    Option<Arc<dyn EventHandler>>,
+)> {
+    let function = create_usb_function(device).map_err(Error::CreateEmulatedFunction)?;
+    let emulated_device = EmulatedDevice::new(function, device_state)?;
+    let has_event_source = emulated_device.event_source().is_some();
+    let arc_mutex_device = Arc::new(Mutex::new(BackendDeviceType::EmulatedDevice(
+        emulated_device,
+    )));
+
+    let event_handler: Arc<dyn EventHandler> = Arc::new(UsbUtilEventHandler {
+        device: arc_mutex_device.clone(),
+    });
+    let function_event_handler: Option<Arc<dyn EventHandler>> = if has_event_source {
+        Some(Arc::new(UsbFunctionEventHandler {
+            device: arc_mutex_device.clone(),
+        }))
+    } else {
+        None
+    };
+
+    Ok((arc_mutex_device, event_handler, function_event_handler))
+}
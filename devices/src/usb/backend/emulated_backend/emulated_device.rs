@@ -0,0 +1,631 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::BTreeSet;
+use std::mem;
+use std::mem::size_of;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::sync::Weak;
+
+use base::debug;
+use base::AsRawDescriptor;
+use base::Event;
+use base::RawDescriptor;
+use sync::Mutex;
+use usb_util::parse_usbfs_descriptors;
+use usb_util::ConfigDescriptorTree;
+use usb_util::ControlRequestRecipient;
+use usb_util::ControlRequestType;
+use usb_util::DescriptorHeader;
+use usb_util::DescriptorType;
+use usb_util::DeviceDescriptor;
+use usb_util::DeviceDescriptorTree;
+use usb_util::DeviceSpeed;
+use usb_util::StandardControlRequest;
+use usb_util::TransferBuffer;
+use usb_util::TransferStatus;
+use usb_util::UsbRequestSetup;
+use usb_util::ENDPOINT_DIRECTION_OFFSET;
+use zerocopy::FromBytes;
+
+use crate::usb::backend::device::BackendDevice;
+use crate::usb::backend::device::DeviceState;
+use crate::usb::backend::endpoint::ControlEndpointState;
+use crate::usb::backend::endpoint::UsbEndpoint;
+use crate::usb::backend::error::Error;
+use crate::usb::backend::error::Result;
+use crate::usb::backend::transfer::BackendTransfer;
+use crate::usb::backend::transfer::BackendTransferHandle;
+use crate::usb::backend::transfer::BackendTransferType;
+use crate::usb::backend::transfer::ControlTransferState;
+use crate::usb::backend::transfer::GenericTransferHandle;
+use crate::usb::xhci::scatter_gather_buffer::ScatterGatherBuffer;
+use crate::usb::xhci::xhci_backend_device::BackendType;
+use crate::usb::xhci::xhci_backend_device::UsbDeviceAddress;
+use crate::usb::xhci::xhci_backend_device::XhciBackendDevice;
+use crate::utils::EventLoop;
+
+// Descriptor type of string descriptors, see USB 2.0 spec 9.6.7.
+const DESCRIPTOR_TYPE_STRING: u8 = 0x03;
+// LANGID of US English, the only language of the string descriptors.
+const LANGID_US_ENGLISH: u16 = 0x0409;
+// Feature selector of CLEAR_FEATURE requests clearing the halt of an endpoint.
+const STD_FEATURE_ENDPOINT_HALT: u16 = 0;
+
+/// The device-specific part of a USB device emulated by crosvm.
+///
+/// `EmulatedDevice` answers the standard requests from the descriptors of the function, and
+/// forwards the other control requests and the data transfers to it.
+pub trait UsbFunction: Send {
+    /// Returns the device descriptor followed by its only configuration descriptor and the
+    /// descriptors of its interfaces and endpoints, as returned by GET_DESCRIPTOR requests.
+    fn descriptors(&self) -> Vec<u8>;
+
+    /// Returns the strings referred to by the descriptors. String index `n` is `strings()[n - 1]`.
+    fn strings(&self) -> &[&str];
+
+    /// Returns the speed of the device.
+    fn speed(&self) -> DeviceSpeed;
+
+    /// Handles a control request that is not a standard device request. `data` holds the data
+    /// stage of the request. Returns the number of bytes written to `data` for device-to-host
+    /// requests, or `None` to stall the request.
+    fn control_request(&mut self, setup: &UsbRequestSetup, data: &mut [u8]) -> Option<usize>;
+
+    /// Handles an OUT transfer of `data` to endpoint `ep`. Returning `TransferStatus::Stalled`
+    /// halts the endpoint, which stalls the following transfers until the host clears the halt.
+    fn transfer_out(&mut self, ep: u8, data: &[u8]) -> TransferStatus;
+
+    /// Handles an IN transfer from endpoint `ep`, returning its status and the number of bytes
+    /// written to `data`. Returns `None` if the function has no data to send yet, in which case
+    /// the transfer is retried after `process_events()`. Endpoints halt like in `transfer_out()`.
+    fn transfer_in(&mut self, ep: u8, data: &mut [u8]) -> Option<(TransferStatus, usize)>;
+
+    /// Resets the function to its initial state.
+    fn reset(&mut self);
+
+    /// Returns the source of the external events of the function, if it has one. The event loop
+    /// calls `process_events()` when it is readable.
+    fn event_source(&self) -> Option<&dyn AsRawDescriptor> {
+        None
+    }
+
+    /// Processes the events of `event_source()`.
+    fn process_events(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// A transfer submitted to an `EmulatedDevice`.
+pub struct EmulatedTransfer {
+    // Address of the endpoint of the transfer, 0 for control transfers.
+    ep_addr: u8,
+    buffer: TransferBuffer,
+    status: TransferStatus,
+    actual_length: usize,
+    callback: Option<Box<dyn Fn(BackendTransferType) + Send + Sync>>,
+}
+
+impl EmulatedTransfer {
+    /// Creates a transfer of `buffer` to or from the endpoint `ep_addr`. A control transfer uses
+    /// endpoint 0, and its buffer holds a `UsbRequestSetup` followed by the data.
+    pub fn new(ep_addr: u8, buffer: TransferBuffer) -> EmulatedTransfer {
+        EmulatedTransfer {
+            ep_addr,
+            buffer,
+            status: TransferStatus::Error,
+            actual_length: 0,
+            callback: None,
+        }
+    }
+
+    fn run_callback(mut self) {
+        if let Some(callback) = self.callback.take() {
+            callback(BackendTransferType::EmulatedDevice(self));
+        }
+    }
+}
+
+impl BackendTransfer for EmulatedTransfer {
+    fn status(&self) -> TransferStatus {
+        self.status
+    }
+
+    fn actual_length(&self) -> usize {
+        self.actual_length
+    }
+
+    fn buffer(&self) -> &TransferBuffer {
+        &self.buffer
+    }
+
+    fn set_callback<C: 'static + Fn(BackendTransferType) + Send + Sync>(&mut self, cb: C) {
+        self.callback = Some(Box::new(cb));
+    }
+}
+
+// The transfers waiting for data from the function, and the completed ones whose callbacks have
+// yet to run on the event loop.
+struct EmulatedTransfers {
+    pending: Vec<(u64, EmulatedTransfer)>,
+    completed: Vec<EmulatedTransfer>,
+    completion_evt: Event,
+}
+
+impl EmulatedTransfers {
+    // Queues the callback of `transfer` to run on the event loop. The callbacks can't run right
+    // away because the caller may hold the lock of the xHCI transfer state.
+    fn complete(&mut self, transfer: EmulatedTransfer) -> Result<()> {
+        self.completed.push(transfer);
+        self.completion_evt
+            .signal()
+            .map_err(Error::WriteCompletionEvent)
+    }
+}
+
+struct EmulatedTransferHandle {
+    id: u64,
+    transfers: Weak<Mutex<EmulatedTransfers>>,
+}
+
+impl GenericTransferHandle for EmulatedTransferHandle {
+    fn cancel(&self) -> Result<()> {
+        let transfers = match self.transfers.upgrade() {
+            Some(transfers) => transfers,
+            None => return Ok(()),
+        };
+        let mut transfers = transfers.lock();
+        // Only the transfers waiting for data can be cancelled, the others are already complete.
+        if let Some(pos) = transfers.pending.iter().position(|(id, _)| *id == self.id) {
+            let (_, mut transfer) = transfers.pending.remove(pos);
+            transfer.status = TransferStatus::Cancelled;
+            transfers.complete(transfer)?;
+        }
+        Ok(())
+    }
+}
+
+/// A USB device emulated by crosvm. The device-specific part is implemented by a `UsbFunction`.
+pub struct EmulatedDevice {
+    function: Box<dyn UsbFunction>,
+    descriptors: DeviceDescriptorTree,
+    // bConfigurationValue of the active configuration, or 0 if the device is not configured.
+    configuration: u8,
+    // Addresses of the endpoints that stalled a transfer, and stall the others until the host
+    // clears their halt.
+    halted_endpoints: BTreeSet<u8>,
+    state: Arc<RwLock<DeviceState>>,
+    control_transfer_state: Arc<RwLock<ControlTransferState>>,
+    transfers: Arc<Mutex<EmulatedTransfers>>,
+    completion_evt: Event,
+    next_transfer_id: u64,
+}
+
+impl EmulatedDevice {
+    /// Creates a new emulated device running `function`.
+    pub fn new(function: Box<dyn UsbFunction>, state: DeviceState) -> Result<EmulatedDevice> {
+        let descriptors =
+            parse_usbfs_descriptors(&function.descriptors()).map_err(Error::ParseDescriptors)?;
+        let completion_evt = Event::new().map_err(Error::CreateCompletionEvent)?;
+        let transfers = EmulatedTransfers {
+            pending: Vec::new(),
+            completed: Vec::new(),
+            completion_evt: completion_evt
+                .try_clone()
+                .map_err(Error::CreateCompletionEvent)?,
+        };
+        let control_transfer_state = ControlTransferState {
+            ctl_ep_state: ControlEndpointState::SetupStage,
+            control_request_setup: UsbRequestSetup::new(0, 0, 0, 0, 0),
+            executed: false,
+        };
+        Ok(EmulatedDevice {
+            function,
+            descriptors,
+            configuration: 0,
+            halted_endpoints: BTreeSet::new(),
+            state: Arc::new(RwLock::new(state)),
+            control_transfer_state: Arc::new(RwLock::new(control_transfer_state)),
+            transfers: Arc::new(Mutex::new(transfers)),
+            completion_evt,
+            next_transfer_id: 0,
+        })
+    }
+
+    /// Returns the source of the external events of the function, if it has one.
+    pub fn event_source(&self) -> Option<&dyn AsRawDescriptor> {
+        self.function.event_source()
+    }
+
+    /// Processes the external events of the function, and completes the pending transfers it now
+    /// has data for.
+    pub fn process_function_events(&mut self) -> anyhow::Result<()> {
+        self.function.process_events()?;
+        self.complete_pending_transfers()?;
+        Ok(())
+    }
+
+    // Retries the transfers waiting for data from the function.
+    fn complete_pending_transfers(&mut self) -> Result<()> {
+        let transfers = self.transfers.clone();
+        let mut transfers = transfers.lock();
+        for (id, mut transfer) in mem::take(&mut transfers.pending) {
+            if self.execute_data_transfer(&mut transfer) {
+                transfers.complete(transfer)?;
+            } else {
+                transfers.pending.push((id, transfer));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the callbacks of the transfers completed since the last call.
+    pub fn poll_transfers(&self) -> Result<()> {
+        let completed = {
+            let mut transfers = self.transfers.lock();
+            if transfers.completed.is_empty() {
+                return Ok(());
+            }
+            // The event was signaled when the transfers were completed, so this doesn't block.
+            self.completion_evt
+                .wait()
+                .map_err(Error::ReadCompletionEvent)?;
+            mem::take(&mut transfers.completed)
+        };
+        for transfer in completed {
+            transfer.run_callback();
+        }
+        Ok(())
+    }
+
+    /// Writes the configuration descriptor with index `descriptor_index`, followed by the
+    /// descriptors of its interfaces and endpoints, to `buffer` for a GET_DESCRIPTOR request.
+    pub fn get_config_descriptor_raw(
+        &self,
+        buffer: &ScatterGatherBuffer,
+        descriptor_index: u8,
+    ) -> Result<(TransferStatus, u32)> {
+        let data = self
+            .config_descriptor_data(descriptor_index)
+            .ok_or(Error::UnknownConfiguration(descriptor_index))?;
+        let bytes_transferred = buffer.write(data).map_err(Error::WriteBuffer)?;
+        Ok((TransferStatus::Completed, bytes_transferred as u32))
+    }
+
+    fn config_descriptor_data(&self, descriptor_index: u8) -> Option<&[u8]> {
+        let config = self
+            .descriptors
+            .get_config_descriptor_by_index(descriptor_index)?;
+        let start = config.offset();
+        self.descriptors
+            .raw()
+            .get(start..start + config.wTotalLength as usize)
+    }
+
+    // Executes the data transfer `transfer`. Returns false if the function has no data for it yet.
+    fn execute_data_transfer(&mut self, transfer: &mut EmulatedTransfer) -> bool {
+        if self.halted_endpoints.contains(&transfer.ep_addr) {
+            transfer.status = TransferStatus::Stalled;
+            return true;
+        }
+        let data = match &mut transfer.buffer {
+            TransferBuffer::Vector(v) => v,
+            TransferBuffer::Dma(_) => {
+                // Emulated devices only hand out vector buffers.
+                transfer.status = TransferStatus::Error;
+                return true;
+            }
+        };
+        let ep = transfer.ep_addr & !(1 << ENDPOINT_DIRECTION_OFFSET);
+        if transfer.ep_addr & (1 << ENDPOINT_DIRECTION_OFFSET) != 0 {
+            match self.function.transfer_in(ep, data) {
+                Some((status, actual_length)) => {
+                    transfer.status = status;
+                    transfer.actual_length = actual_length;
+                }
+                None => return false,
+            }
+        } else {
+            transfer.status = self.function.transfer_out(ep, data);
+            transfer.actual_length = data.len();
+        }
+        if transfer.status == TransferStatus::Stalled {
+            transfer.actual_length = 0;
+            self.halted_endpoints.insert(transfer.ep_addr);
+        }
+        true
+    }
+
+    fn execute_control_transfer(&mut self, transfer: &mut EmulatedTransfer) {
+        let buffer = match &mut transfer.buffer {
+            TransferBuffer::Vector(v) if v.len() >= size_of::<UsbRequestSetup>() => v,
+            _ => {
+                transfer.status = TransferStatus::Error;
+                return;
+            }
+        };
+        let (setup, data) = buffer.split_at_mut(size_of::<UsbRequestSetup>());
+        let setup = match UsbRequestSetup::read_from(setup) {
+            Some(setup) => setup,
+            None => {
+                transfer.status = TransferStatus::Error;
+                return;
+            }
+        };
+        match self.control_request(&setup, data) {
+            Some(len) => {
+                transfer.status = TransferStatus::Completed;
+                transfer.actual_length = len;
+            }
+            None => {
+                debug!("emulated usb device stalled control request {:?}", setup);
+                transfer.status = TransferStatus::Stalled;
+            }
+        }
+    }
+
+    // Handles the standard requests that are not intercepted by `BackendDeviceType`, and forwards
+    // the others to the function.
+    fn control_request(&mut self, setup: &UsbRequestSetup, data: &mut [u8]) -> Option<usize> {
+        if setup.get_type() == ControlRequestType::Standard {
+            match (setup.get_standard_request(), setup.get_recipient()) {
+                (Some(StandardControlRequest::GetDescriptor), ControlRequestRecipient::Device) => {
+                    return self.get_descriptor(setup.value, data);
+                }
+                (Some(StandardControlRequest::GetStatus), ControlRequestRecipient::Endpoint) => {
+                    let halted = self.halted_endpoints.contains(&(setup.index as u8));
+                    return Some(copy_to(data, &[u8::from(halted), 0]));
+                }
+                (Some(StandardControlRequest::GetStatus), _) => {
+                    return Some(copy_to(data, &[0, 0]));
+                }
+                (
+                    Some(StandardControlRequest::GetConfiguration),
+                    ControlRequestRecipient::Device,
+                ) => {
+                    return Some(copy_to(data, &[self.configuration]));
+                }
+                (
+                    Some(StandardControlRequest::GetInterface),
+                    ControlRequestRecipient::Interface,
+                ) => {
+                    return Some(copy_to(data, &[0]));
+                }
+                (
+                    Some(StandardControlRequest::SetFeature)
+                    | Some(StandardControlRequest::ClearFeature),
+                    ControlRequestRecipient::Device,
+                ) => {
+                    // Remote wakeup is not supported, the feature is accepted and ignored.
+                    return Some(setup.length as usize);
+                }
+                _ => {}
+            }
+        }
+        self.function.control_request(setup, data)
+    }
+
+    fn get_descriptor(&self, value: u16, data: &mut [u8]) -> Option<usize> {
+        let descriptor_type = (value >> 8) as u8;
+        let index = value as u8;
+        if descriptor_type == DescriptorType::Device as u8 {
+            let len = size_of::<DescriptorHeader>() + size_of::<DeviceDescriptor>();
+            Some(copy_to(data, &self.descriptors.raw()[..len]))
+        } else if descriptor_type == DescriptorType::Configuration as u8 {
+            Some(copy_to(data, self.config_descriptor_data(index)?))
+        } else if descriptor_type == DESCRIPTOR_TYPE_STRING {
+            let mut descriptor = vec![0, DESCRIPTOR_TYPE_STRING];
+            if index == 0 {
+                descriptor.extend_from_slice(&LANGID_US_ENGLISH.to_le_bytes());
+            } else {
+                let string = self.function.strings().get(index as usize - 1)?;
+                descriptor.extend(string.encode_utf16().take(126).flat_map(u16::to_le_bytes));
+            }
+            descriptor[0] = descriptor.len() as u8;
+            Some(copy_to(data, &descriptor))
+        } else {
+            None
+        }
+    }
+}
+
+// Copies as much of `src` as fits in `dst`, returning the number of bytes copied.
+fn copy_to(dst: &mut [u8], src: &[u8]) -> usize {
+    let len = dst.len().min(src.len());
+    dst[..len].copy_from_slice(&src[..len]);
+    len
+}
+
+impl AsRawDescriptor for EmulatedDevice {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.completion_evt.as_raw_descriptor()
+    }
+}
+
+impl BackendDevice for EmulatedDevice {
+    fn submit_backend_transfer(
+        &mut self,
+        transfer: BackendTransferType,
+    ) -> Result<BackendTransferHandle> {
+        let mut transfer = match transfer {
+            BackendTransferType::EmulatedDevice(transfer) => transfer,
+            _ => return Err(Error::MalformedBackendTransfer),
+        };
+        let id = self.next_transfer_id;
+        self.next_transfer_id = self.next_transfer_id.wrapping_add(1);
+        let handle = BackendTransferHandle::new(EmulatedTransferHandle {
+            id,
+            transfers: Arc::downgrade(&self.transfers),
+        });
+
+        if transfer.ep_addr == 0 {
+            self.execute_control_transfer(&mut transfer);
+        } else if !self.execute_data_transfer(&mut transfer) {
+            self.transfers.lock().pending.push((id, transfer));
+            return Ok(handle);
+        }
+        self.transfers.lock().complete(transfer)?;
+        // The function may have data for the pending IN transfers once it has handled an OUT
+        // transfer, e.g. the response to a command.
+        self.complete_pending_transfers()?;
+        Ok(handle)
+    }
+
+    fn detach_event_handler(&self, event_loop: &Arc<EventLoop>) -> Result<()> {
+        if let Some(source) = self.function.event_source() {
+            // The source is already removed from the event loop if it was closed.
+            let _ = event_loop.remove_event_for_descriptor(source);
+        }
+        event_loop
+            .remove_event_for_descriptor(self)
+            .map_err(Error::RemoveFromEventLoop)
+    }
+
+    fn request_transfer_buffer(&mut self, size: usize) -> TransferBuffer {
+        TransferBuffer::Vector(vec![0u8; size])
+    }
+
+    fn build_bulk_transfer(
+        &mut self,
+        ep_addr: u8,
+        transfer_buffer: TransferBuffer,
+        _stream_id: Option<u16>,
+    ) -> Result<BackendTransferType> {
+        Ok(BackendTransferType::EmulatedDevice(EmulatedTransfer::new(
+            ep_addr,
+            transfer_buffer,
+        )))
+    }
+
+    fn build_interrupt_transfer(
+        &mut self,
+        ep_addr: u8,
+        transfer_buffer: TransferBuffer,
+    ) -> Result<BackendTransferType> {
+        Ok(BackendTransferType::EmulatedDevice(EmulatedTransfer::new(
+            ep_addr,
+            transfer_buffer,
+        )))
+    }
+
+    fn get_control_transfer_state(&mut self) -> Arc<RwLock<ControlTransferState>> {
+        self.control_transfer_state.clone()
+    }
+
+    fn get_device_state(&mut self) -> Arc<RwLock<DeviceState>> {
+        self.state.clone()
+    }
+
+    fn get_active_config_descriptor(&mut self) -> Result<ConfigDescriptorTree> {
+        self.get_config_descriptor(self.configuration)
+    }
+
+    fn get_config_descriptor(&mut self, config: u8) -> Result<ConfigDescriptorTree> {
+        self.descriptors
+            .get_config_descriptor(config)
+            .cloned()
+            .ok_or(Error::UnknownConfiguration(config))
+    }
+
+    fn get_config_descriptor_by_index(&mut self, config_index: u8) -> Result<ConfigDescriptorTree> {
+        self.descriptors
+            .get_config_descriptor_by_index(config_index)
+            .cloned()
+            .ok_or(Error::UnknownConfiguration(config_index))
+    }
+
+    fn get_device_descriptor_tree(&mut self) -> DeviceDescriptorTree {
+        self.descriptors.clone()
+    }
+
+    fn get_active_configuration(&mut self) -> Result<u8> {
+        Ok(self.configuration)
+    }
+
+    fn set_active_configuration(&mut self, config: u8) -> Result<()> {
+        self.configuration = config;
+        // Configuring the device clears the halt of its endpoints, see USB 2.0 spec 9.4.5.
+        self.halted_endpoints.clear();
+        Ok(())
+    }
+
+    fn clear_feature(&mut self, value: u16, index: u16) -> Result<TransferStatus> {
+        // It's a standard, clear_feature, endpoint request, e.g. the last step of the reset
+        // recovery of a mass storage device.
+        if value == STD_FEATURE_ENDPOINT_HALT {
+            self.halted_endpoints.remove(&(index as u8));
+        }
+        Ok(TransferStatus::Completed)
+    }
+
+    fn create_endpoints(&mut self, config_descriptor: &ConfigDescriptorTree) -> Result<()> {
+        let mut endpoints = Vec::new();
+        let device_state = self.get_device_state();
+        for i in 0..config_descriptor.num_interfaces() {
+            let interface = config_descriptor
+                .get_interface_descriptor(i, 0)
+                .ok_or(Error::GetInterfaceDescriptor(i, 0))?;
+            for ep_idx in 0..interface.bNumEndpoints {
+                let ep_dp = interface
+                    .get_endpoint_descriptor(ep_idx)
+                    .ok_or(Error::GetEndpointDescriptor(ep_idx))?;
+                let ep_num = ep_dp.get_endpoint_number();
+                if ep_num == 0 {
+                    continue;
+                }
+                let direction = ep_dp.get_direction();
+                let ty = ep_dp.get_endpoint_type().ok_or(Error::GetEndpointType)?;
+                endpoints.push(UsbEndpoint::new(
+                    device_state.read().unwrap().fail_handle.clone(),
+                    device_state.read().unwrap().job_queue.clone(),
+                    ep_num,
+                    direction,
+                    ty,
+                ));
+            }
+        }
+        device_state.write().unwrap().endpoints = endpoints;
+        Ok(())
+    }
+}
+
+impl XhciBackendDevice for EmulatedDevice {
+    fn get_backend_type(&self) -> BackendType {
+        BackendType::Usb2
+    }
+
+    fn get_vid(&self) -> u16 {
+        self.descriptors.idVendor
+    }
+
+    fn get_pid(&self) -> u16 {
+        self.descriptors.idProduct
+    }
+
+    fn set_address(&mut self, address: UsbDeviceAddress) {
+        // The address is only relevant to the xHCI controller, see the set address command.
+        debug!("emulated usb device set address: {}", address);
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.configuration = 0;
+        self.halted_endpoints.clear();
+        self.function.reset();
+        Ok(())
+    }
+
+    fn get_speed(&self) -> Option<DeviceSpeed> {
+        Some(self.function.speed())
+    }
+
+    fn alloc_streams(&self, _ep: u8, _num_streams: u16) -> Result<()> {
+        // Emulated devices have no USB 3 bulk streams.
+        Err(Error::StreamsNotSupported)
+    }
+
+    fn free_streams(&self, _ep: u8) -> Result<()> {
+        Err(Error::StreamsNotSupported)
+    }
+}
@@ -0,0 +1,586 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! USB HID keyboard and tablet devices fed by a socket of virtio input events.
+
+use std::collections::VecDeque;
+use std::fs::File;
+
+use anyhow::bail;
+use anyhow::Context;
+use base::AsRawDescriptor;
+use linux_input_sys::constants::*;
+use linux_input_sys::virtio_input_event;
+use usb_util::ControlRequestRecipient;
+use usb_util::ControlRequestType;
+use usb_util::DeviceSpeed;
+use usb_util::StandardControlRequest;
+use usb_util::TransferStatus;
+use usb_util::UsbRequestSetup;
+
+use crate::usb::backend::emulated_backend::emulated_device::UsbFunction;
+use crate::virtio::input::EventSource;
+use crate::virtio::input::SocketEventSource;
+
+const VENDOR_ID: u16 = 0x18d1;
+const KEYBOARD_PRODUCT_ID: u16 = 0x7002;
+const TABLET_PRODUCT_ID: u16 = 0x7003;
+
+const INTERRUPT_IN_EP: u8 = 1;
+
+// Descriptor types of the HID class, see the HID specification 1.11, section 7.1.
+const DESCRIPTOR_TYPE_HID: u8 = 0x21;
+const DESCRIPTOR_TYPE_REPORT: u8 = 0x22;
+
+// Class-specific requests, see the HID specification 1.11, section 7.2.
+const GET_REPORT: u8 = 0x01;
+const GET_IDLE: u8 = 0x02;
+const GET_PROTOCOL: u8 = 0x03;
+const SET_REPORT: u8 = 0x09;
+const SET_IDLE: u8 = 0x0a;
+const SET_PROTOCOL: u8 = 0x0b;
+
+// Reports queued while the guest doesn't poll the interrupt endpoint. The oldest ones are dropped
+// past this limit.
+const MAX_QUEUED_REPORTS: usize = 64;
+
+// Boot protocol keyboard, see the HID specification 1.11, appendix B.1.
+const KEYBOARD_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xa1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0xe0, //   Usage Minimum (224)
+    0x29, 0xe7, //   Usage Maximum (231)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x01, //   Input (Constant)
+    0x95, 0x05, //   Report Count (5)
+    0x75, 0x01, //   Report Size (1)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (1)
+    0x29, 0x05, //   Usage Maximum (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute)
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x03, //   Report Size (3)
+    0x91, 0x01, //   Output (Constant)
+    0x95, 0x06, //   Report Count (6)
+    0x75, 0x08, //   Report Size (8)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0xff, //   Usage Maximum (255)
+    0x81, 0x00, //   Input (Data, Array)
+    0xc0, // End Collection
+];
+
+// Absolute pointing device with three buttons and a wheel.
+const TABLET_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xa1, 0x01, // Collection (Application)
+    0x09, 0x01, //   Usage (Pointer)
+    0xa1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Button)
+    0x19, 0x01, //     Usage Minimum (1)
+    0x29, 0x03, //     Usage Maximum (3)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x95, 0x03, //     Report Count (3)
+    0x75, 0x01, //     Report Size (1)
+    0x81, 0x02, //     Input (Data, Variable, Absolute)
+    0x95, 0x01, //     Report Count (1)
+    0x75, 0x05, //     Report Size (5)
+    0x81, 0x01, //     Input (Constant)
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x26, 0xff, 0x7f, //     Logical Maximum (0x7fff)
+    0x35, 0x00, //     Physical Minimum (0)
+    0x46, 0xff, 0x7f, //     Physical Maximum (0x7fff)
+    0x75, 0x10, //     Report Size (16)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x02, //     Input (Data, Variable, Absolute)
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x38, //     Usage (Wheel)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7f, //     Logical Maximum (127)
+    0x35, 0x00, //     Physical Minimum (0)
+    0x45, 0x00, //     Physical Maximum (0)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x06, //     Input (Data, Variable, Relative)
+    0xc0, //   End Collection
+    0xc0, // End Collection
+];
+
+// Largest coordinate of the tablet.
+const TABLET_MAX_COORDINATE: i32 = 0x7fff;
+
+// Number of keys other than modifiers in a keyboard report.
+const KEYBOARD_MAX_KEYS: usize = 6;
+// Usage reported in all key slots when more keys are pressed than a report can hold.
+const KEYBOARD_ERROR_ROLL_OVER: u8 = 0x01;
+// Usage of the first modifier key, left control.
+const KEYBOARD_FIRST_MODIFIER: u8 = 0xe0;
+
+/// The kind of device emulated by a `Hid` function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HidKind {
+    Keyboard,
+    Tablet,
+}
+
+// State of the device, as reported to the guest.
+enum HidState {
+    Keyboard {
+        // Bitmap of the pressed modifier keys.
+        modifiers: u8,
+        // Usages of the other pressed keys, in the order they were pressed.
+        keys: Vec<u8>,
+    },
+    Tablet {
+        buttons: u8,
+        x: u16,
+        y: u16,
+        // Wheel movement since the last report.
+        wheel: i8,
+    },
+}
+
+impl HidState {
+    fn new(kind: HidKind) -> HidState {
+        match kind {
+            HidKind::Keyboard => HidState::Keyboard {
+                modifiers: 0,
+                keys: Vec::new(),
+            },
+            HidKind::Tablet => HidState::Tablet {
+                buttons: 0,
+                x: 0,
+                y: 0,
+                wheel: 0,
+            },
+        }
+    }
+
+    fn handle_event(&mut self, event: &virtio_input_event) {
+        let type_ = event.type_.to_native();
+        let code = event.code.to_native();
+        let value = event.value.to_native();
+        match self {
+            HidState::Keyboard { modifiers, keys } => {
+                if type_ != EV_KEY {
+                    return;
+                }
+                let usage = match keyboard_usage(code) {
+                    Some(usage) => usage,
+                    None => return,
+                };
+                // Auto-repeat events (value 2) are generated by the guest, they are ignored.
+                if usage >= KEYBOARD_FIRST_MODIFIER {
+                    let bit = 1 << (usage - KEYBOARD_FIRST_MODIFIER);
+                    match value {
+                        0 => *modifiers &= !bit,
+                        1 => *modifiers |= bit,
+                        _ => {}
+                    }
+                } else {
+                    match value {
+                        0 => keys.retain(|&k| k != usage),
+                        1 if !keys.contains(&usage) => keys.push(usage),
+                        _ => {}
+                    }
+                }
+            }
+            HidState::Tablet {
+                buttons,
+                x,
+                y,
+                wheel,
+            } => match (type_, code) {
+                (EV_ABS, ABS_X) => *x = value.clamp(0, TABLET_MAX_COORDINATE) as u16,
+                (EV_ABS, ABS_Y) => *y = value.clamp(0, TABLET_MAX_COORDINATE) as u16,
+                (EV_REL, REL_WHEEL) => {
+                    *wheel = (*wheel as i32 + value).clamp(-127, 127) as i8;
+                }
+                (EV_KEY, BTN_LEFT | BTN_TOUCH | BTN_RIGHT | BTN_MIDDLE) => {
+                    let bit = match code {
+                        BTN_RIGHT => 1 << 1,
+                        BTN_MIDDLE => 1 << 2,
+                        _ => 1 << 0,
+                    };
+                    if value != 0 {
+                        *buttons |= bit;
+                    } else {
+                        *buttons &= !bit;
+                    }
+                }
+                _ => {}
+            },
+        }
+    }
+
+    // Returns the input report of the current state.
+    fn report(&self) -> Vec<u8> {
+        match self {
+            HidState::Keyboard { modifiers, keys } => {
+                let mut report = vec![*modifiers, 0];
+                if keys.len() > KEYBOARD_MAX_KEYS {
+                    report.extend_from_slice(&[KEYBOARD_ERROR_ROLL_OVER; KEYBOARD_MAX_KEYS]);
+                } else {
+                    report.extend_from_slice(keys);
+                    report.resize(2 + KEYBOARD_MAX_KEYS, 0);
+                }
+                report
+            }
+            HidState::Tablet {
+                buttons,
+                x,
+                y,
+                wheel,
+            } => {
+                let mut report = vec![*buttons];
+                report.extend_from_slice(&x.to_le_bytes());
+                report.extend_from_slice(&y.to_le_bytes());
+                report.push(*wheel as u8);
+                report
+            }
+        }
+    }
+
+    // Clears the relative axes once they have been reported.
+    fn reported(&mut self) {
+        if let HidState::Tablet { wheel, .. } = self {
+            *wheel = 0;
+        }
+    }
+}
+
+// Returns the usage in the keyboard usage page of the evdev key `code`.
+fn keyboard_usage(code: u16) -> Option<u8> {
+    const LETTERS: [u16; 26] = [
+        KEY_A, KEY_B, KEY_C, KEY_D, KEY_E, KEY_F, KEY_G, KEY_H, KEY_I, KEY_J, KEY_K, KEY_L, KEY_M,
+        KEY_N, KEY_O, KEY_P, KEY_Q, KEY_R, KEY_S, KEY_T, KEY_U, KEY_V, KEY_W, KEY_X, KEY_Y, KEY_Z,
+    ];
+    if let Some(pos) = LETTERS.iter().position(|&key| key == code) {
+        return Some(0x04 + pos as u8);
+    }
+    let usage = match code {
+        // KEY_1 to KEY_0 are contiguous, as are their usages.
+        KEY_1..=KEY_0 => 0x1e + (code - KEY_1) as u8,
+        KEY_ENTER => 0x28,
+        KEY_ESC => 0x29,
+        KEY_BACKSPACE => 0x2a,
+        KEY_TAB => 0x2b,
+        KEY_SPACE => 0x2c,
+        KEY_MINUS => 0x2d,
+        KEY_EQUAL => 0x2e,
+        KEY_LEFTBRACE => 0x2f,
+        KEY_RIGHTBRACE => 0x30,
+        KEY_BACKSLASH => 0x31,
+        KEY_SEMICOLON => 0x33,
+        KEY_APOSTROPHE => 0x34,
+        KEY_GRAVE => 0x35,
+        KEY_COMMA => 0x36,
+        KEY_DOT => 0x37,
+        KEY_SLASH => 0x38,
+        KEY_CAPSLOCK => 0x39,
+        KEY_F1..=KEY_F10 => 0x3a + (code - KEY_F1) as u8,
+        KEY_F11 => 0x44,
+        KEY_F12 => 0x45,
+        KEY_SYSRQ => 0x46,
+        KEY_SCROLLLOCK => 0x47,
+        KEY_PAUSE => 0x48,
+        KEY_INSERT => 0x49,
+        KEY_HOME => 0x4a,
+        KEY_PAGEUP => 0x4b,
+        KEY_DELETE => 0x4c,
+        KEY_END => 0x4d,
+        KEY_PAGEDOWN => 0x4e,
+        KEY_RIGHT => 0x4f,
+        KEY_LEFT => 0x50,
+        KEY_DOWN => 0x51,
+        KEY_UP => 0x52,
+        KEY_NUMLOCK => 0x53,
+        KEY_KPSLASH => 0x54,
+        KEY_KPASTERISK => 0x55,
+        KEY_KPMINUS => 0x56,
+        KEY_KPPLUS => 0x57,
+        KEY_KPENTER => 0x58,
+        KEY_KP1 => 0x59,
+        KEY_KP2 => 0x5a,
+        KEY_KP3 => 0x5b,
+        KEY_KP4 => 0x5c,
+        KEY_KP5 => 0x5d,
+        KEY_KP6 => 0x5e,
+        KEY_KP7 => 0x5f,
+        KEY_KP8 => 0x60,
+        KEY_KP9 => 0x61,
+        KEY_KP0 => 0x62,
+        KEY_KPDOT => 0x63,
+        KEY_102ND => 0x64,
+        KEY_COMPOSE => 0x65,
+        KEY_POWER => 0x66,
+        KEY_KPEQUAL => 0x67,
+        KEY_LEFTCTRL => 0xe0,
+        KEY_LEFTSHIFT => 0xe1,
+        KEY_LEFTALT => 0xe2,
+        KEY_LEFTMETA => 0xe3,
+        KEY_RIGHTCTRL => 0xe4,
+        KEY_RIGHTSHIFT => 0xe5,
+        KEY_RIGHTALT => 0xe6,
+        KEY_RIGHTMETA => 0xe7,
+        _ => return None,
+    };
+    Some(usage)
+}
+
+/// A USB HID keyboard or tablet. The input comes from a socket of `virtio_input_event`s, like the
+/// ones of the virtio input devices.
+pub struct Hid {
+    kind: HidKind,
+    event_source: SocketEventSource<File>,
+    state: HidState,
+    // Reports waiting for the guest to poll the interrupt endpoint.
+    reports: VecDeque<Vec<u8>>,
+    idle_rate: u8,
+    protocol: u8,
+}
+
+impl Hid {
+    /// Creates a HID device of the given kind reading its input events from `events`.
+    pub fn new(kind: HidKind, events: File) -> Hid {
+        Hid {
+            kind,
+            event_source: SocketEventSource::new(events),
+            state: HidState::new(kind),
+            reports: VecDeque::new(),
+            idle_rate: 0,
+            // Devices start in the report protocol, see the HID specification 1.11, section 7.2.6.
+            protocol: 1,
+        }
+    }
+
+    fn report_descriptor(&self) -> &'static [u8] {
+        match self.kind {
+            HidKind::Keyboard => KEYBOARD_REPORT_DESCRIPTOR,
+            HidKind::Tablet => TABLET_REPORT_DESCRIPTOR,
+        }
+    }
+
+    fn hid_descriptor(&self) -> [u8; 9] {
+        let [len_lo, len_hi] = (self.report_descriptor().len() as u16).to_le_bytes();
+        [
+            9,
+            DESCRIPTOR_TYPE_HID,
+            0x11,
+            0x01,
+            0,
+            1,
+            DESCRIPTOR_TYPE_REPORT,
+            len_lo,
+            len_hi,
+        ]
+    }
+
+    fn handle_event(&mut self, event: &virtio_input_event) {
+        if event.type_.to_native() == EV_SYN {
+            if self.reports.len() == MAX_QUEUED_REPORTS {
+                self.reports.pop_front();
+            }
+            self.reports.push_back(self.state.report());
+            self.state.reported();
+        } else {
+            self.state.handle_event(event);
+        }
+    }
+}
+
+// Copies as much of `src` as fits in `dst`, returning the number of bytes copied.
+fn copy_to(dst: &mut [u8], src: &[u8]) -> usize {
+    let len = dst.len().min(src.len());
+    dst[..len].copy_from_slice(&src[..len]);
+    len
+}
+
+impl UsbFunction for Hid {
+    fn descriptors(&self) -> Vec<u8> {
+        let product_id = match self.kind {
+            HidKind::Keyboard => KEYBOARD_PRODUCT_ID,
+            HidKind::Tablet => TABLET_PRODUCT_ID,
+        };
+        // Boot keyboards use the boot interface subclass and the keyboard protocol.
+        let (subclass, protocol) = match self.kind {
+            HidKind::Keyboard => (1, 1),
+            HidKind::Tablet => (0, 0),
+        };
+        let [vid_lo, vid_hi] = VENDOR_ID.to_le_bytes();
+        let [pid_lo, pid_hi] = product_id.to_le_bytes();
+        #[rustfmt::skip]
+        let mut descriptors = vec![
+            // Device descriptor, USB 2.0.
+            18, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 64, vid_lo, vid_hi, pid_lo, pid_hi, 0x00, 0x01,
+            1, 2, 3, 1,
+            // Configuration descriptor, bus powered.
+            9, 0x02, 34, 0, 1, 1, 0, 0x80, 50,
+            // Interface descriptor, HID class.
+            9, 0x04, 0, 0, 1, 0x03, subclass, protocol, 0,
+        ];
+        descriptors.extend_from_slice(&self.hid_descriptor());
+        // Interrupt IN endpoint descriptor, polled every 10 ms.
+        descriptors.extend_from_slice(&[7, 0x05, 0x80 | INTERRUPT_IN_EP, 0x03, 8, 0, 10]);
+        descriptors
+    }
+
+    fn strings(&self) -> &[&str] {
+        match self.kind {
+            HidKind::Keyboard => &["crosvm", "USB Keyboard", "1"],
+            HidKind::Tablet => &["crosvm", "USB Tablet", "1"],
+        }
+    }
+
+    fn speed(&self) -> DeviceSpeed {
+        DeviceSpeed::Full
+    }
+
+    fn control_request(&mut self, setup: &UsbRequestSetup, data: &mut [u8]) -> Option<usize> {
+        if setup.get_recipient() != ControlRequestRecipient::Interface {
+            return None;
+        }
+        match setup.get_type() {
+            ControlRequestType::Standard => {
+                if setup.get_standard_request() != Some(StandardControlRequest::GetDescriptor) {
+                    return None;
+                }
+                match (setup.value >> 8) as u8 {
+                    DESCRIPTOR_TYPE_REPORT => Some(copy_to(data, self.report_descriptor())),
+                    DESCRIPTOR_TYPE_HID => Some(copy_to(data, &self.hid_descriptor())),
+                    _ => None,
+                }
+            }
+            ControlRequestType::Class => match setup.request {
+                GET_REPORT => Some(copy_to(data, &self.state.report())),
+                GET_IDLE => Some(copy_to(data, &[self.idle_rate])),
+                GET_PROTOCOL => Some(copy_to(data, &[self.protocol])),
+                // The keyboard LEDs are not emulated.
+                SET_REPORT => Some(data.len()),
+                SET_IDLE => {
+                    self.idle_rate = (setup.value >> 8) as u8;
+                    Some(0)
+                }
+                SET_PROTOCOL => {
+                    self.protocol = setup.value as u8;
+                    Some(0)
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn transfer_out(&mut self, _ep: u8, _data: &[u8]) -> TransferStatus {
+        // The devices have no OUT endpoint.
+        TransferStatus::Stalled
+    }
+
+    fn transfer_in(&mut self, ep: u8, data: &mut [u8]) -> Option<(TransferStatus, usize)> {
+        if ep != INTERRUPT_IN_EP {
+            return Some((TransferStatus::Stalled, 0));
+        }
+        let report = self.reports.pop_front()?;
+        Some((TransferStatus::Completed, copy_to(data, &report)))
+    }
+
+    fn reset(&mut self) {
+        self.state = HidState::new(self.kind);
+        self.reports.clear();
+        self.idle_rate = 0;
+        self.protocol = 1;
+    }
+
+    fn event_source(&self) -> Option<&dyn AsRawDescriptor> {
+        Some(&self.event_source)
+    }
+
+    fn process_events(&mut self) -> anyhow::Result<()> {
+        let count = self
+            .event_source
+            .receive_events()
+            .context("failed to receive input events")?;
+        if count == 0 {
+            bail!("input event source closed");
+        }
+        while let Some(event) = self.event_source.pop_available_event() {
+            self.handle_event(&event);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(type_: u16, code: u16, value: i32) -> virtio_input_event {
+        virtio_input_event {
+            type_: type_.into(),
+            code: code.into(),
+            value: value.into(),
+        }
+    }
+
+    #[test]
+    fn keyboard_report() {
+        let mut state = HidState::new(HidKind::Keyboard);
+        state.handle_event(&event(EV_KEY, KEY_LEFTSHIFT, 1));
+        state.handle_event(&event(EV_KEY, KEY_A, 1));
+        state.handle_event(&event(EV_KEY, KEY_1, 1));
+        assert_eq!(state.report(), [0x02, 0, 0x04, 0x1e, 0, 0, 0, 0]);
+
+        state.handle_event(&event(EV_KEY, KEY_A, 0));
+        state.handle_event(&event(EV_KEY, KEY_LEFTSHIFT, 0));
+        assert_eq!(state.report(), [0, 0, 0x1e, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn keyboard_roll_over() {
+        let mut state = HidState::new(HidKind::Keyboard);
+        for key in [KEY_A, KEY_B, KEY_C, KEY_D, KEY_E, KEY_F, KEY_G] {
+            state.handle_event(&event(EV_KEY, key, 1));
+        }
+        assert_eq!(state.report(), [0, 0, 1, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn keyboard_usages() {
+        assert_eq!(keyboard_usage(KEY_Z), Some(0x1d));
+        assert_eq!(keyboard_usage(KEY_0), Some(0x27));
+        assert_eq!(keyboard_usage(KEY_F10), Some(0x43));
+        assert_eq!(keyboard_usage(KEY_KP0), Some(0x62));
+        assert_eq!(keyboard_usage(KEY_RIGHTMETA), Some(0xe7));
+        assert_eq!(keyboard_usage(BTN_LEFT), None);
+    }
+
+    #[test]
+    fn tablet_report() {
+        let mut state = HidState::new(HidKind::Tablet);
+        state.handle_event(&event(EV_ABS, ABS_X, 0x1234));
+        state.handle_event(&event(EV_ABS, ABS_Y, 0x10000));
+        state.handle_event(&event(EV_KEY, BTN_RIGHT, 1));
+        state.handle_event(&event(EV_REL, REL_WHEEL, -1));
+        assert_eq!(state.report(), [0x02, 0x34, 0x12, 0xff, 0x7f, 0xff]);
+
+        state.reported();
+        state.handle_event(&event(EV_KEY, BTN_RIGHT, 0));
+        assert_eq!(state.report(), [0, 0x34, 0x12, 0xff, 0x7f, 0]);
+    }
+}
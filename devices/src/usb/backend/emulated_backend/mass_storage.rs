@@ -0,0 +1,688 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! USB mass storage class device using the bulk-only transport, backed by a disk image.
+
+use std::mem;
+
+use anyhow::Context;
+use base::error;
+use base::VolatileSlice;
+use cros_async::Executor;
+use disk::DiskFile;
+use usb_util::ControlRequestType;
+use usb_util::DeviceSpeed;
+use usb_util::TransferStatus;
+use usb_util::UsbRequestSetup;
+
+use crate::usb::backend::emulated_backend::emulated_device::UsbFunction;
+use crate::virtio::scsi::constants::*;
+
+const VENDOR_ID: u16 = 0x18d1;
+const PRODUCT_ID: u16 = 0x7001;
+
+const BULK_IN_EP: u8 = 1;
+const BULK_OUT_EP: u8 = 2;
+
+const BLOCK_SIZE: u64 = 512;
+
+// Command block wrapper and command status wrapper, see the USB mass storage class bulk-only
+// transport specification, section 5.
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CBW_SIZE: usize = 31;
+const CBW_FLAG_DATA_IN: u8 = 0x80;
+const CSW_SIGNATURE: u32 = 0x53425355;
+const CSW_SIZE: usize = 13;
+
+const CSW_STATUS_PASSED: u8 = 0x00;
+const CSW_STATUS_FAILED: u8 = 0x01;
+const CSW_STATUS_PHASE_ERROR: u8 = 0x02;
+
+// Class-specific requests of the bulk-only transport.
+const BULK_ONLY_MASS_STORAGE_RESET: u8 = 0xff;
+const GET_MAX_LUN: u8 = 0xfe;
+
+// READ FORMAT CAPACITIES is not part of SBC but some hosts, e.g. Windows, rely on it for USB
+// drives.
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+
+// Additional sense codes.
+const ASC_INVALID_COMMAND_OPERATION_CODE: u8 = 0x20;
+const ASC_LBA_OUT_OF_RANGE: u8 = 0x21;
+const ASC_INVALID_FIELD_IN_CDB: u8 = 0x24;
+const ASC_WRITE_PROTECTED: u8 = 0x27;
+const ASC_MEDIUM_NOT_PRESENT: u8 = 0x3a;
+const ASC_UNRECOVERED_READ_ERROR: u8 = 0x11;
+const ASC_WRITE_ERROR: u8 = 0x0c;
+
+// The command block wrapper sent by the host before each command.
+struct CommandBlockWrapper {
+    tag: u32,
+    data_transfer_length: u32,
+    data_in: bool,
+    cb: [u8; 16],
+}
+
+impl CommandBlockWrapper {
+    fn parse(data: &[u8]) -> Option<CommandBlockWrapper> {
+        if data.len() != CBW_SIZE
+            || u32::from_le_bytes(data[0..4].try_into().unwrap()) != CBW_SIGNATURE
+        {
+            return None;
+        }
+        let cb_length = data[14] as usize;
+        if !(1..=16).contains(&cb_length) {
+            return None;
+        }
+        let mut cb = [0u8; 16];
+        cb[..cb_length].copy_from_slice(&data[15..15 + cb_length]);
+        Some(CommandBlockWrapper {
+            tag: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            data_transfer_length: u32::from_le_bytes(data[8..12].try_into().unwrap()),
+            data_in: data[12] & CBW_FLAG_DATA_IN != 0,
+            cb,
+        })
+    }
+}
+
+// The phase of the bulk-only transport the device is in.
+enum Phase {
+    // Waiting for a command block wrapper.
+    Command,
+    // Sending the response of a command.
+    DataIn(Vec<u8>),
+    // Sending `remaining` bytes of the disk from `offset`.
+    ReadDisk { offset: u64, remaining: u64 },
+    // Receiving `remaining` bytes to write to the disk from `offset`, or to discard if `offset` is
+    // `None`.
+    DataOut { offset: Option<u64>, remaining: u64 },
+    // Sending the command status wrapper.
+    Status,
+    // Stalling both endpoints after an invalid command block wrapper, until the host resets the
+    // device, see the bulk-only transport specification, section 6.6.1.
+    ResetRecovery,
+}
+
+// Sense key and additional sense code of the last failed command.
+#[derive(Clone, Copy)]
+struct Sense {
+    key: u8,
+    asc: u8,
+}
+
+impl Sense {
+    const NONE: Sense = Sense {
+        key: NO_SENSE,
+        asc: 0,
+    };
+}
+
+// The result of a command, before its data phase.
+enum CommandResult {
+    // The command succeeded and has no data.
+    Done,
+    // The command succeeded and returns `data` to the host.
+    Data(Vec<u8>),
+    // The command reads `len` bytes of the disk from `offset`.
+    Read { offset: u64, len: u64 },
+    // The command writes `len` bytes to the disk from `offset`.
+    Write { offset: u64, len: u64 },
+    // The command failed.
+    Failed(Sense),
+}
+
+/// A USB mass storage device exposing a disk image as a single LUN.
+pub struct MassStorage {
+    // The disk is only `None` if it failed to be converted back after a flush.
+    disk: Option<Box<dyn DiskFile>>,
+    read_only: bool,
+    num_blocks: u64,
+    phase: Phase,
+    sense: Sense,
+    // Fields of the command status wrapper of the current command.
+    tag: u32,
+    residue: u32,
+    status: u8,
+}
+
+impl MassStorage {
+    /// Creates a mass storage device backed by `disk`. Writes fail if `read_only` is set.
+    pub fn new(disk: Box<dyn DiskFile>, read_only: bool) -> anyhow::Result<MassStorage> {
+        let size = disk.get_len().context("failed to get the disk size")?;
+        Ok(MassStorage {
+            disk: Some(disk),
+            read_only,
+            num_blocks: size / BLOCK_SIZE,
+            phase: Phase::Command,
+            sense: Sense::NONE,
+            tag: 0,
+            residue: 0,
+            status: CSW_STATUS_PASSED,
+        })
+    }
+
+    fn handle_command(&mut self, cbw: CommandBlockWrapper) {
+        self.tag = cbw.tag;
+        self.residue = cbw.data_transfer_length;
+        self.status = CSW_STATUS_PASSED;
+
+        let expected = cbw.data_transfer_length as u64;
+        let result = self.execute_command(&cbw.cb);
+        self.phase = match result {
+            CommandResult::Done => {
+                if expected == 0 {
+                    Phase::Status
+                } else if cbw.data_in {
+                    Phase::DataIn(Vec::new())
+                } else {
+                    Phase::DataOut {
+                        offset: None,
+                        remaining: expected,
+                    }
+                }
+            }
+            CommandResult::Data(mut data) if cbw.data_in || expected == 0 => {
+                data.truncate(expected as usize);
+                self.residue -= data.len() as u32;
+                if expected == 0 {
+                    Phase::Status
+                } else {
+                    Phase::DataIn(data)
+                }
+            }
+            CommandResult::Read { offset, len } if cbw.data_in || expected == 0 => {
+                let len = len.min(expected);
+                self.residue -= len as u32;
+                if expected == 0 {
+                    Phase::Status
+                } else {
+                    Phase::ReadDisk {
+                        offset,
+                        remaining: len,
+                    }
+                }
+            }
+            CommandResult::Write { offset, len } if !cbw.data_in || expected == 0 => {
+                let len = len.min(expected);
+                self.residue -= len as u32;
+                if expected == 0 {
+                    Phase::Status
+                } else {
+                    Phase::DataOut {
+                        offset: Some(offset),
+                        remaining: len,
+                    }
+                }
+            }
+            CommandResult::Failed(sense) => {
+                self.sense = sense;
+                self.status = CSW_STATUS_FAILED;
+                self.no_data_phase(&cbw)
+            }
+            // The direction of the data of the command doesn't match the one of the wrapper.
+            _ => {
+                self.status = CSW_STATUS_PHASE_ERROR;
+                self.no_data_phase(&cbw)
+            }
+        };
+    }
+
+    // Returns the phase that ends the data stage expected by the host without transferring any
+    // data.
+    fn no_data_phase(&self, cbw: &CommandBlockWrapper) -> Phase {
+        if cbw.data_transfer_length == 0 {
+            Phase::Status
+        } else if cbw.data_in {
+            Phase::DataIn(Vec::new())
+        } else {
+            Phase::DataOut {
+                offset: None,
+                remaining: cbw.data_transfer_length as u64,
+            }
+        }
+    }
+
+    fn execute_command(&mut self, cb: &[u8; 16]) -> CommandResult {
+        if self.disk.is_none() && cb[0] != REQUEST_SENSE && cb[0] != INQUIRY {
+            return CommandResult::Failed(Sense {
+                key: NOT_READY,
+                asc: ASC_MEDIUM_NOT_PRESENT,
+            });
+        }
+        match cb[0] {
+            TEST_UNIT_READY | PREVENT_ALLOW_MEDIUM_REMOVAL | START_STOP_UNIT => CommandResult::Done,
+            REQUEST_SENSE => {
+                let sense = mem::replace(&mut self.sense, Sense::NONE);
+                let mut data = vec![0u8; 18];
+                data[0] = 0x70;
+                data[2] = sense.key;
+                data[7] = 10;
+                data[12] = sense.asc;
+                data.truncate(cb[4] as usize);
+                CommandResult::Data(data)
+            }
+            INQUIRY => {
+                if cb[1] & 0x01 != 0 {
+                    // Vital product data pages are not supported.
+                    return CommandResult::Failed(Sense {
+                        key: ILLEGAL_REQUEST,
+                        asc: ASC_INVALID_FIELD_IN_CDB,
+                    });
+                }
+                // Removable direct access device, SPC-2.
+                let mut data = vec![TYPE_DISK, 0x80, 0x04, 0x02, 31, 0, 0, 0];
+                data.extend_from_slice(b"CROSVM  ");
+                data.extend_from_slice(b"USB STORAGE     ");
+                data.extend_from_slice(b"1.0 ");
+                let allocation_length = u16::from_be_bytes([cb[3], cb[4]]) as usize;
+                data.truncate(allocation_length);
+                CommandResult::Data(data)
+            }
+            MODE_SENSE_6 => {
+                // Mode parameter header without any mode page.
+                let device_specific = if self.read_only { 0x80 } else { 0 };
+                let mut data = vec![3, 0, device_specific, 0];
+                data.truncate(cb[4] as usize);
+                CommandResult::Data(data)
+            }
+            READ_CAPACITY_10 => {
+                let last_lba = self.num_blocks.saturating_sub(1).min(u32::MAX as u64) as u32;
+                let mut data = last_lba.to_be_bytes().to_vec();
+                data.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                CommandResult::Data(data)
+            }
+            READ_FORMAT_CAPACITIES => {
+                // Capacity list header followed by the current capacity descriptor of a
+                // formatted medium.
+                let num_blocks = self.num_blocks.min(u32::MAX as u64) as u32;
+                let mut data = vec![0, 0, 0, 8];
+                data.extend_from_slice(&num_blocks.to_be_bytes());
+                data.push(0x02);
+                data.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes()[1..]);
+                data.truncate(u16::from_be_bytes([cb[7], cb[8]]) as usize);
+                CommandResult::Data(data)
+            }
+            READ_10 | WRITE_10 => {
+                let lba = u32::from_be_bytes(cb[2..6].try_into().unwrap()) as u64;
+                let num_blocks = u16::from_be_bytes([cb[7], cb[8]]) as u64;
+                if lba + num_blocks > self.num_blocks {
+                    return CommandResult::Failed(Sense {
+                        key: ILLEGAL_REQUEST,
+                        asc: ASC_LBA_OUT_OF_RANGE,
+                    });
+                }
+                let offset = lba * BLOCK_SIZE;
+                let len = num_blocks * BLOCK_SIZE;
+                if cb[0] == READ_10 {
+                    CommandResult::Read { offset, len }
+                } else if self.read_only {
+                    CommandResult::Failed(Sense {
+                        key: DATA_PROTECT,
+                        asc: ASC_WRITE_PROTECTED,
+                    })
+                } else {
+                    CommandResult::Write { offset, len }
+                }
+            }
+            SYNCHRONIZE_CACHE_10 => match self.flush() {
+                Ok(()) => CommandResult::Done,
+                Err(e) => {
+                    error!("usb mass storage: failed to flush the disk: {:#}", e);
+                    CommandResult::Failed(Sense {
+                        key: MEDIUM_ERROR,
+                        asc: ASC_WRITE_ERROR,
+                    })
+                }
+            },
+            _ => CommandResult::Failed(Sense {
+                key: ILLEGAL_REQUEST,
+                asc: ASC_INVALID_COMMAND_OPERATION_CODE,
+            }),
+        }
+    }
+
+    // `DiskFile` has no synchronous flush, so the disk is converted to an `AsyncDisk` for the time
+    // of the flush. The `AsyncDisk` is not `Send` and can't be kept in the device.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        let disk = match self.disk.take() {
+            Some(disk) => disk,
+            None => return Ok(()),
+        };
+        let ex = Executor::new().context("failed to create an executor")?;
+        let async_disk = disk
+            .to_async_disk(&ex)
+            .context("failed to convert the disk")?;
+        let result = ex
+            .run_until(async_disk.fsync())
+            .context("failed to run the executor")?;
+        self.disk = Some(async_disk.into_inner());
+        result.context("failed to fsync the disk")
+    }
+
+    // Fails the current command because of an I/O error on the disk.
+    fn fail_io(&mut self, asc: u8) {
+        self.sense = Sense {
+            key: MEDIUM_ERROR,
+            asc,
+        };
+        self.status = CSW_STATUS_FAILED;
+    }
+
+    fn read_disk(&mut self, offset: u64, data: &mut [u8]) -> bool {
+        let disk = match &self.disk {
+            Some(disk) => disk,
+            None => return false,
+        };
+        match disk.read_exact_at_volatile(VolatileSlice::new(data), offset) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("usb mass storage: failed to read the disk: {}", e);
+                false
+            }
+        }
+    }
+
+    fn write_disk(&mut self, offset: u64, data: &[u8]) -> bool {
+        let disk = match &self.disk {
+            Some(disk) => disk,
+            None => return false,
+        };
+        // `VolatileSlice` needs a mutable buffer even to write it out.
+        let mut data = data.to_vec();
+        match disk.write_all_at_volatile(VolatileSlice::new(&mut data), offset) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("usb mass storage: failed to write the disk: {}", e);
+                false
+            }
+        }
+    }
+
+    fn write_status(&mut self, data: &mut [u8]) -> usize {
+        let mut csw = [0u8; CSW_SIZE];
+        csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        csw[8..12].copy_from_slice(&self.residue.to_le_bytes());
+        csw[12] = self.status;
+        let len = data.len().min(CSW_SIZE);
+        data[..len].copy_from_slice(&csw[..len]);
+        len
+    }
+}
+
+impl UsbFunction for MassStorage {
+    fn descriptors(&self) -> Vec<u8> {
+        let [vid_lo, vid_hi] = VENDOR_ID.to_le_bytes();
+        let [pid_lo, pid_hi] = PRODUCT_ID.to_le_bytes();
+        #[rustfmt::skip]
+        let descriptors = vec![
+            // Device descriptor, USB 2.0.
+            18, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 64, vid_lo, vid_hi, pid_lo, pid_hi, 0x00, 0x01,
+            1, 2, 3, 1,
+            // Configuration descriptor, self powered.
+            9, 0x02, 32, 0, 1, 1, 0, 0x80, 50,
+            // Interface descriptor, mass storage class, SCSI transparent command set, bulk-only
+            // transport.
+            9, 0x04, 0, 0, 2, 0x08, 0x06, 0x50, 0,
+            // Bulk IN endpoint descriptor.
+            7, 0x05, 0x80 | BULK_IN_EP, 0x02, 0x00, 0x02, 0,
+            // Bulk OUT endpoint descriptor.
+            7, 0x05, BULK_OUT_EP, 0x02, 0x00, 0x02, 0,
+        ];
+        descriptors
+    }
+
+    fn strings(&self) -> &[&str] {
+        &["crosvm", "USB Mass Storage", "1"]
+    }
+
+    fn speed(&self) -> DeviceSpeed {
+        DeviceSpeed::High
+    }
+
+    fn control_request(&mut self, setup: &UsbRequestSetup, data: &mut [u8]) -> Option<usize> {
+        if setup.get_type() != ControlRequestType::Class {
+            return None;
+        }
+        match setup.request {
+            BULK_ONLY_MASS_STORAGE_RESET => {
+                self.reset();
+                Some(0)
+            }
+            GET_MAX_LUN => {
+                let len = data.len().min(1);
+                data[..len].fill(0);
+                Some(len)
+            }
+            _ => None,
+        }
+    }
+
+    fn transfer_out(&mut self, ep: u8, data: &[u8]) -> TransferStatus {
+        if ep != BULK_OUT_EP {
+            return TransferStatus::Stalled;
+        }
+        match mem::replace(&mut self.phase, Phase::Command) {
+            Phase::Command => match CommandBlockWrapper::parse(data) {
+                Some(cbw) => self.handle_command(cbw),
+                None => {
+                    error!("usb mass storage: invalid command block wrapper");
+                    self.phase = Phase::ResetRecovery;
+                    return TransferStatus::Stalled;
+                }
+            },
+            Phase::DataOut { offset, remaining } => {
+                let len = (data.len() as u64).min(remaining);
+                let mut offset = offset;
+                if let Some(disk_offset) = offset {
+                    if self.write_disk(disk_offset, &data[..len as usize]) {
+                        offset = Some(disk_offset + len);
+                    } else {
+                        self.fail_io(ASC_WRITE_ERROR);
+                        offset = None;
+                    }
+                }
+                let remaining = remaining - len;
+                self.phase = if remaining == 0 {
+                    Phase::Status
+                } else {
+                    Phase::DataOut { offset, remaining }
+                };
+            }
+            phase => {
+                self.phase = phase;
+                return TransferStatus::Stalled;
+            }
+        }
+        TransferStatus::Completed
+    }
+
+    fn transfer_in(&mut self, ep: u8, data: &mut [u8]) -> Option<(TransferStatus, usize)> {
+        if ep != BULK_IN_EP {
+            return Some((TransferStatus::Stalled, 0));
+        }
+        let len = match mem::replace(&mut self.phase, Phase::Command) {
+            // The response of a command is sent once the host has sent one.
+            Phase::Command => return None,
+            Phase::DataIn(mut response) => {
+                let len = data.len().min(response.len());
+                data[..len].copy_from_slice(&response[..len]);
+                response.drain(..len);
+                self.phase = if response.is_empty() {
+                    Phase::Status
+                } else {
+                    Phase::DataIn(response)
+                };
+                len
+            }
+            Phase::ReadDisk { offset, remaining } => {
+                let len = (data.len() as u64).min(remaining);
+                if self.read_disk(offset, &mut data[..len as usize]) {
+                    self.phase = if remaining == len {
+                        Phase::Status
+                    } else {
+                        Phase::ReadDisk {
+                            offset: offset + len,
+                            remaining: remaining - len,
+                        }
+                    };
+                    len as usize
+                } else {
+                    // End the data stage early, the residue tells the host how much is missing.
+                    self.fail_io(ASC_UNRECOVERED_READ_ERROR);
+                    self.residue += remaining as u32;
+                    self.phase = Phase::Status;
+                    0
+                }
+            }
+            Phase::Status => self.write_status(data),
+            phase @ (Phase::DataOut { .. } | Phase::ResetRecovery) => {
+                self.phase = phase;
+                return Some((TransferStatus::Stalled, 0));
+            }
+        };
+        Some((TransferStatus::Completed, len))
+    }
+
+    fn reset(&mut self) {
+        self.phase = Phase::Command;
+        self.sense = Sense::NONE;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+
+    fn send(storage: &mut MassStorage, data: &[u8]) {
+        assert!(storage.transfer_out(BULK_OUT_EP, data) == TransferStatus::Completed);
+    }
+
+    fn send_command(storage: &mut MassStorage, tag: u32, len: u32, data_in: bool, cb: &[u8]) {
+        let mut cbw = vec![0u8; CBW_SIZE];
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&len.to_le_bytes());
+        cbw[12] = if data_in { CBW_FLAG_DATA_IN } else { 0 };
+        cbw[14] = cb.len() as u8;
+        cbw[15..15 + cb.len()].copy_from_slice(cb);
+        send(storage, &cbw);
+    }
+
+    fn receive(storage: &mut MassStorage, data: &mut [u8]) -> usize {
+        match storage.transfer_in(BULK_IN_EP, data) {
+            Some((TransferStatus::Completed, len)) => len,
+            _ => panic!("IN transfer did not complete"),
+        }
+    }
+
+    // Returns the tag, residue and status of the command status wrapper.
+    fn receive_status(storage: &mut MassStorage) -> (u32, u32, u8) {
+        let mut csw = [0u8; CSW_SIZE];
+        assert_eq!(receive(storage, &mut csw), CSW_SIZE);
+        assert_eq!(&csw[0..4], &CSW_SIGNATURE.to_le_bytes());
+        (
+            u32::from_le_bytes(csw[4..8].try_into().unwrap()),
+            u32::from_le_bytes(csw[8..12].try_into().unwrap()),
+            csw[12],
+        )
+    }
+
+    fn storage(blocks: u64, read_only: bool) -> MassStorage {
+        let file: File = tempfile::tempfile().unwrap();
+        file.set_len(blocks * BLOCK_SIZE).unwrap();
+        MassStorage::new(Box::new(file), read_only).unwrap()
+    }
+
+    #[test]
+    fn read_capacity() {
+        let mut storage = storage(16, false);
+        send_command(
+            &mut storage,
+            1,
+            8,
+            true,
+            &[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        );
+        let mut data = [0u8; 8];
+        assert_eq!(receive(&mut storage, &mut data), 8);
+        assert_eq!(data, [0, 0, 0, 15, 0, 0, 2, 0]);
+        assert_eq!(receive_status(&mut storage), (1, 0, CSW_STATUS_PASSED));
+    }
+
+    #[test]
+    fn write_then_read() {
+        let mut storage = storage(16, false);
+        let block = [0xa5u8; BLOCK_SIZE as usize];
+
+        send_command(
+            &mut storage,
+            2,
+            512,
+            false,
+            &[WRITE_10, 0, 0, 0, 0, 3, 0, 0, 1, 0],
+        );
+        send(&mut storage, &block);
+        assert_eq!(receive_status(&mut storage), (2, 0, CSW_STATUS_PASSED));
+
+        send_command(
+            &mut storage,
+            3,
+            512,
+            true,
+            &[READ_10, 0, 0, 0, 0, 3, 0, 0, 1, 0],
+        );
+        let mut data = [0u8; BLOCK_SIZE as usize];
+        assert_eq!(receive(&mut storage, &mut data), BLOCK_SIZE as usize);
+        assert_eq!(data, block);
+        assert_eq!(receive_status(&mut storage), (3, 0, CSW_STATUS_PASSED));
+    }
+
+    #[test]
+    fn write_read_only() {
+        let mut storage = storage(16, true);
+
+        send_command(
+            &mut storage,
+            4,
+            512,
+            false,
+            &[WRITE_10, 0, 0, 0, 0, 0, 0, 0, 1, 0],
+        );
+        send(&mut storage, &[0u8; BLOCK_SIZE as usize]);
+        assert_eq!(receive_status(&mut storage), (4, 512, CSW_STATUS_FAILED));
+
+        send_command(&mut storage, 5, 18, true, &[REQUEST_SENSE, 0, 0, 0, 18, 0]);
+        let mut sense = [0u8; 18];
+        assert_eq!(receive(&mut storage, &mut sense), 18);
+        assert_eq!(sense[2], DATA_PROTECT);
+        assert_eq!(sense[12], ASC_WRITE_PROTECTED);
+        assert_eq!(receive_status(&mut storage), (5, 0, CSW_STATUS_PASSED));
+    }
+
+    #[test]
+    fn invalid_command_needs_reset() {
+        let mut storage = storage(16, false);
+        assert!(storage.transfer_out(BULK_OUT_EP, &[0u8; CBW_SIZE]) == TransferStatus::Stalled);
+        let mut data = [0u8; CSW_SIZE];
+        assert!(matches!(
+            storage.transfer_in(BULK_IN_EP, &mut data),
+            Some((TransferStatus::Stalled, 0))
+        ));
+        assert!(storage.transfer_out(BULK_OUT_EP, &[0u8; CBW_SIZE]) == TransferStatus::Stalled);
+
+        storage.reset();
+        send_command(&mut storage, 6, 0, false, &[TEST_UNIT_READY, 0, 0, 0, 0, 0]);
+        assert_eq!(receive_status(&mut storage), (6, 0, CSW_STATUS_PASSED));
+    }
+
+    #[test]
+    fn status_waits_for_command() {
+        let mut storage = storage(16, false);
+        let mut csw = [0u8; CSW_SIZE];
+        assert!(storage.transfer_in(BULK_IN_EP, &mut csw).is_none());
+    }
+}
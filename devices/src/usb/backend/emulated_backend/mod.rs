@@ -0,0 +1,8 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+pub mod emulated_backend_device_provider;
+pub mod emulated_device;
+pub mod hid;
+pub mod mass_storage;
@@ -28,8 +28,12 @@ pub enum Error {
     ClearHalt(UsbUtilError),
     #[error("failed to create scatter gather buffer: {0}")]
     CreateBuffer(XhciTransferError),
+    #[error("failed to create the completion event of an emulated device: {0}")]
+    CreateCompletionEvent(base::Error),
     #[error("failed to create control tube: {0}")]
     CreateControlTube(TubeError),
+    #[error("failed to create the function of an emulated usb device: {0:#}")]
+    CreateEmulatedFunction(anyhow::Error),
     #[error("failed to create host backend usb device: {0}")]
     CreateHostUsbDevice(UsbUtilError),
     #[error("failed to create libusb context: {0}")]
@@ -61,10 +65,14 @@ pub enum Error {
     MalformedBackendTransfer,
     #[error("request missing required data buffer")]
     MissingRequiredBuffer,
+    #[error("failed to parse the descriptors of an emulated device: {0}")]
+    ParseDescriptors(UsbUtilError),
     #[error("failed to queue async job: {0}")]
     QueueAsyncJob(UtilsError),
     #[error("failed to read buffer: {0}")]
     ReadBuffer(BufferError),
+    #[error("failed to read the completion event of an emulated device: {0}")]
+    ReadCompletionEvent(base::Error),
     #[error("failed to read control tube: {0}")]
     ReadControlTube(TubeError),
     #[error("failed to release dma buffer")]
@@ -81,12 +89,32 @@ pub enum Error {
     SetupControlTube(TubeError),
     #[error("failed to start async job queue: {0}")]
     StartAsyncJobQueue(UtilsError),
+    #[error("the device does not support streams")]
+    StreamsNotSupported,
     #[error("xhci transfer completed: {0}")]
     TransferComplete(XhciTransferError),
     #[error("failed to cancel transfer: {0}")]
     TransferHandle(UsbUtilError),
+    #[error("the device has no configuration {0}")]
+    UnknownConfiguration(u8),
+    #[error("failed to set up the connection to the usbip server: {0}")]
+    UsbipConnection(std::io::Error),
+    #[error("the usbip server closed the connection")]
+    UsbipDisconnected,
+    #[error("failed to import the device from the usbip server: {0}")]
+    UsbipImport(std::io::Error),
+    #[error("the usbip device failed a request of the backend")]
+    UsbipRequestFailed,
+    #[error("failed to send to the usbip server: {0}")]
+    UsbipSend(std::io::Error),
+    #[error("the usbip device returned a short descriptor")]
+    UsbipShortDescriptor,
+    #[error("timed out waiting for the usbip server")]
+    UsbipTimeout,
     #[error("failed to write buffer: {0}")]
     WriteBuffer(BufferError),
+    #[error("failed to write the completion event of an emulated device: {0}")]
+    WriteCompletionEvent(base::Error),
     #[error("failed to write control tube: {0}")]
     WriteControlTube(TubeError),
 }
@@ -184,6 +184,7 @@ impl BackendDevice for HostDevice {
                 .submit_transfer(transfer)
                 .map_err(Error::CreateTransfer)
                 .map(BackendTransferHandle::new),
+            _ => Err(Error::MalformedBackendTransfer),
         }
     }
 
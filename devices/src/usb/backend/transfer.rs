@@ -7,8 +7,10 @@ use usb_util::TransferBuffer;
 use usb_util::TransferStatus;
 use usb_util::UsbRequestSetup;
 
+use crate::usb::backend::emulated_backend::emulated_device::EmulatedTransfer;
 use crate::usb::backend::endpoint::ControlEndpointState;
 use crate::usb::backend::error::Result;
+use crate::usb::backend::usbip_backend::usbip_device::UsbipTransfer;
 
 /// BackendTransferHandle is a wrapper structure around a generic transfer handle whose
 /// implementation depends on the backend type that is being used.
@@ -30,6 +32,8 @@ impl BackendTransferHandle {
 
 pub enum BackendTransferType {
     HostDevice(Transfer),
+    EmulatedDevice(EmulatedTransfer),
+    UsbipDevice(UsbipTransfer),
 }
 
 /// The backend transfer trait implemention is the interface of a generic transfer structure that
@@ -50,18 +54,26 @@ impl BackendTransfer for BackendTransferType {
     fn status(&self) -> TransferStatus {
         match self {
             BackendTransferType::HostDevice(transfer) => BackendTransfer::status(transfer),
+            BackendTransferType::EmulatedDevice(transfer) => BackendTransfer::status(transfer),
+            BackendTransferType::UsbipDevice(transfer) => BackendTransfer::status(transfer),
         }
     }
 
     fn actual_length(&self) -> usize {
         match self {
             BackendTransferType::HostDevice(transfer) => BackendTransfer::actual_length(transfer),
+            BackendTransferType::EmulatedDevice(transfer) => {
+                BackendTransfer::actual_length(transfer)
+            }
+            BackendTransferType::UsbipDevice(transfer) => BackendTransfer::actual_length(transfer),
         }
     }
 
     fn buffer(&self) -> &TransferBuffer {
         match self {
             BackendTransferType::HostDevice(transfer) => BackendTransfer::buffer(transfer),
+            BackendTransferType::EmulatedDevice(transfer) => BackendTransfer::buffer(transfer),
+            BackendTransferType::UsbipDevice(transfer) => BackendTransfer::buffer(transfer),
         }
     }
 
@@ -70,6 +82,12 @@ impl BackendTransfer for BackendTransferType {
             BackendTransferType::HostDevice(transfer) => {
                 BackendTransfer::set_callback(transfer, cb)
             }
+            BackendTransferType::EmulatedDevice(transfer) => {
+                BackendTransfer::set_callback(transfer, cb)
+            }
+            BackendTransferType::UsbipDevice(transfer) => {
+                BackendTransfer::set_callback(transfer, cb)
+            }
         }
     }
 }
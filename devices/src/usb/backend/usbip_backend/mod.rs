@@ -0,0 +1,7 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+pub mod protocol;
+pub mod usbip_backend_device_provider;
+pub mod usbip_device;
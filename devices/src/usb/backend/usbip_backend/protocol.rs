@@ -0,0 +1,260 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Messages of the USB/IP protocol, see Documentation/usb/usbip_protocol.rst in the Linux kernel.
+//! All the fields are big-endian.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use usb_util::DeviceSpeed;
+
+const USBIP_VERSION: u16 = 0x0111;
+
+const OP_REQ_IMPORT: u16 = 0x8003;
+const OP_REP_IMPORT: u16 = 0x0003;
+
+pub const USBIP_CMD_SUBMIT: u32 = 0x0001;
+pub const USBIP_CMD_UNLINK: u32 = 0x0002;
+pub const USBIP_RET_SUBMIT: u32 = 0x0003;
+pub const USBIP_RET_UNLINK: u32 = 0x0004;
+
+pub const USBIP_DIR_OUT: u32 = 0;
+pub const USBIP_DIR_IN: u32 = 1;
+
+// Size of the bus ID of a device, including the terminating NUL.
+const BUSID_SIZE: usize = 32;
+// Size of the usbip_usb_device structure describing an exported device.
+const USB_DEVICE_SIZE: usize = 312;
+
+/// Size of all the URB messages, without their data.
+pub const HEADER_SIZE: usize = 48;
+
+// URB transfer flag telling the direction of the transfer, as in include/linux/usb.h.
+const URB_DIR_IN: u32 = 0x0200;
+
+/// The exported device described by the reply to an import request.
+pub struct ImportedDevice {
+    pub busnum: u32,
+    pub devnum: u32,
+    pub speed: u32,
+    pub configuration_value: u8,
+}
+
+impl ImportedDevice {
+    /// Returns the ID of the device in the URB messages.
+    pub fn devid(&self) -> u32 {
+        (self.busnum << 16) | self.devnum
+    }
+
+    /// Returns the speed of the device, from its `enum usb_device_speed` value.
+    pub fn device_speed(&self) -> Option<DeviceSpeed> {
+        match self.speed {
+            1 => Some(DeviceSpeed::Low),
+            2 => Some(DeviceSpeed::Full),
+            3 => Some(DeviceSpeed::High),
+            5 => Some(DeviceSpeed::Super),
+            6 => Some(DeviceSpeed::SuperPlus),
+            _ => None,
+        }
+    }
+}
+
+/// Imports the device `busid` from the server at the other end of `stream`. The stream is used for
+/// the URBs of the device once this succeeds.
+pub fn import_device<S: Read + Write>(stream: &mut S, busid: &str) -> io::Result<ImportedDevice> {
+    if busid.len() >= BUSID_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "bus ID is too long",
+        ));
+    }
+    let mut request = [0u8; 8 + BUSID_SIZE];
+    request[0..2].copy_from_slice(&USBIP_VERSION.to_be_bytes());
+    request[2..4].copy_from_slice(&OP_REQ_IMPORT.to_be_bytes());
+    request[8..8 + busid.len()].copy_from_slice(busid.as_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0u8; 8];
+    stream.read_exact(&mut reply)?;
+    if u16::from_be_bytes([reply[2], reply[3]]) != OP_REP_IMPORT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected reply to the import request",
+        ));
+    }
+    if read_u32(&reply, 4) != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("the server refused to export {}", busid),
+        ));
+    }
+
+    let mut device = [0u8; USB_DEVICE_SIZE];
+    stream.read_exact(&mut device)?;
+    // The path and bus ID of the device come first, followed by its numbers and descriptor fields.
+    let fields = &device[256 + BUSID_SIZE..];
+    Ok(ImportedDevice {
+        busnum: read_u32(fields, 0),
+        devnum: read_u32(fields, 4),
+        speed: read_u32(fields, 8),
+        configuration_value: fields[21],
+    })
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_i32(data: &[u8], offset: usize) -> i32 {
+    i32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+// Writes the header common to all the URB messages.
+fn write_basic_header(header: &mut [u8], command: u32, seqnum: u32, devid: u32, dir: u32, ep: u32) {
+    header[0..4].copy_from_slice(&command.to_be_bytes());
+    header[4..8].copy_from_slice(&seqnum.to_be_bytes());
+    header[8..12].copy_from_slice(&devid.to_be_bytes());
+    header[12..16].copy_from_slice(&dir.to_be_bytes());
+    header[16..20].copy_from_slice(&ep.to_be_bytes());
+}
+
+/// Returns a USBIP_CMD_SUBMIT message without its data. `setup` is only used by control transfers.
+pub fn cmd_submit(
+    seqnum: u32,
+    devid: u32,
+    dir: u32,
+    ep: u8,
+    transfer_buffer_length: u32,
+    setup: [u8; 8],
+) -> [u8; HEADER_SIZE] {
+    let mut header = [0u8; HEADER_SIZE];
+    write_basic_header(&mut header, USBIP_CMD_SUBMIT, seqnum, devid, dir, ep as u32);
+    let transfer_flags = if dir == USBIP_DIR_IN { URB_DIR_IN } else { 0 };
+    header[20..24].copy_from_slice(&transfer_flags.to_be_bytes());
+    header[24..28].copy_from_slice(&transfer_buffer_length.to_be_bytes());
+    // Not an isochronous transfer.
+    header[32..36].copy_from_slice(&u32::MAX.to_be_bytes());
+    header[40..48].copy_from_slice(&setup);
+    header
+}
+
+/// Returns a USBIP_CMD_UNLINK message cancelling the URB `unlink_seqnum`.
+pub fn cmd_unlink(seqnum: u32, devid: u32, unlink_seqnum: u32) -> [u8; HEADER_SIZE] {
+    let mut header = [0u8; HEADER_SIZE];
+    write_basic_header(&mut header, USBIP_CMD_UNLINK, seqnum, devid, 0, 0);
+    header[20..24].copy_from_slice(&unlink_seqnum.to_be_bytes());
+    header
+}
+
+/// A message sent by the server, without its data.
+pub enum Reply {
+    /// The URB `seqnum` completed with `status`, a negated errno, and transferred `actual_length`
+    /// bytes. The data of IN URBs follows the message.
+    Submit {
+        seqnum: u32,
+        status: i32,
+        actual_length: u32,
+    },
+    /// The URB cancelled by the USBIP_CMD_UNLINK `seqnum` was unlinked if `status` is not 0.
+    Unlink { seqnum: u32, status: i32 },
+}
+
+/// Parses the header of a message sent by the server.
+pub fn parse_reply(header: &[u8; HEADER_SIZE]) -> io::Result<Reply> {
+    let seqnum = read_u32(header, 4);
+    match read_u32(header, 0) {
+        USBIP_RET_SUBMIT => Ok(Reply::Submit {
+            seqnum,
+            status: read_i32(header, 20),
+            actual_length: read_i32(header, 24).max(0) as u32,
+        }),
+        USBIP_RET_UNLINK => Ok(Reply::Unlink {
+            seqnum,
+            status: read_i32(header, 20),
+        }),
+        command => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected usbip command {:#x}", command),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    // A stream reading canned replies and recording what is written to it.
+    struct FakeStream {
+        replies: Cursor<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl Read for FakeStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for FakeStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn import() {
+        let mut replies = vec![0x01, 0x11, 0x00, 0x03, 0, 0, 0, 0];
+        let mut device = [0u8; USB_DEVICE_SIZE];
+        device[288..292].copy_from_slice(&2u32.to_be_bytes());
+        device[292..296].copy_from_slice(&5u32.to_be_bytes());
+        device[296..300].copy_from_slice(&3u32.to_be_bytes());
+        device[309] = 1;
+        replies.extend_from_slice(&device);
+        let mut stream = FakeStream {
+            replies: Cursor::new(replies),
+            written: Vec::new(),
+        };
+
+        let imported = import_device(&mut stream, "1-1.2").unwrap();
+        assert_eq!(imported.devid(), 0x0002_0005);
+        assert!(matches!(imported.device_speed(), Some(DeviceSpeed::High)));
+        assert_eq!(imported.configuration_value, 1);
+        assert_eq!(&stream.written[0..4], &[0x01, 0x11, 0x80, 0x03]);
+        assert_eq!(&stream.written[8..14], b"1-1.2\0");
+    }
+
+    #[test]
+    fn import_refused() {
+        let mut stream = FakeStream {
+            replies: Cursor::new(vec![0x01, 0x11, 0x00, 0x03, 0, 0, 0, 1]),
+            written: Vec::new(),
+        };
+        assert!(import_device(&mut stream, "1-1").is_err());
+    }
+
+    #[test]
+    fn ret_submit() {
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..4].copy_from_slice(&USBIP_RET_SUBMIT.to_be_bytes());
+        header[4..8].copy_from_slice(&7u32.to_be_bytes());
+        header[20..24].copy_from_slice(&(-32i32).to_be_bytes());
+        header[24..28].copy_from_slice(&64u32.to_be_bytes());
+        match parse_reply(&header).unwrap() {
+            Reply::Submit {
+                seqnum,
+                status,
+                actual_length,
+            } => assert_eq!((seqnum, status, actual_length), (7, -32, 64)),
+            Reply::Unlink { .. } => panic!("parsed a RET_SUBMIT as a RET_UNLINK"),
+        }
+    }
+}
@@ -0,0 +1,35 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::fs::File;
+use std::net::TcpStream;
+use std::os::fd::OwnedFd;
+use std::sync::Arc;
+
+use sync::Mutex;
+
+use crate::usb::backend::device::BackendDeviceType;
+use crate::usb::backend::device::DeviceState;
+use crate::usb::backend::error::Result;
+use crate::usb::backend::usbip_backend::usbip_device::UsbipDevice;
+use crate::usb::backend::utils::UsbUtilEventHandler;
+use crate::utils::EventHandler;
+
+/// Imports the device `busid` from the USB/IP server connected to `socket` and attaches it to the
+/// backend. This returns the device and the event handler of its transfer completions.
+pub fn attach_usbip_backend_device(
+    socket: File,
+    busid: &str,
+    device_state: DeviceState,
+) -> Result<(Arc<Mutex<BackendDeviceType>>, Arc<dyn EventHandler>)> {
+    let stream = TcpStream::from(OwnedFd::from(socket));
+    let usbip_device = UsbipDevice::new(stream, busid, device_state)?;
+    let arc_mutex_device = Arc::new(Mutex::new(BackendDeviceType::UsbipDevice(usbip_device)));
+
+    let event_handler: Arc<dyn EventHandler> = Arc::new(UsbUtilEventHandler {
+        device: arc_mutex_device.clone(),
+    });
+
+    Ok((arc_mutex_device, event_handler))
+}
@@ -0,0 +1,1211 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::mem;
+use std::mem::size_of;
+use std::net::Shutdown;
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::sync::Weak;
+use std::thread;
+use std::time::Duration;
+
+use base::debug;
+use base::error;
+use base::AsRawDescriptor;
+use base::Event;
+use base::RawDescriptor;
+use sync::Mutex;
+use usb_util::parse_usbfs_descriptors;
+use usb_util::ConfigDescriptorTree;
+use usb_util::DescriptorType;
+use usb_util::DeviceDescriptorTree;
+use usb_util::DeviceSpeed;
+use usb_util::TransferBuffer;
+use usb_util::TransferStatus;
+use usb_util::UsbRequestSetup;
+use usb_util::ENDPOINT_DIRECTION_OFFSET;
+use zerocopy::AsBytes;
+
+use crate::usb::backend::device::BackendDevice;
+use crate::usb::backend::device::DeviceState;
+use crate::usb::backend::endpoint::ControlEndpointState;
+use crate::usb::backend::endpoint::UsbEndpoint;
+use crate::usb::backend::error::Error;
+use crate::usb::backend::error::Result;
+use crate::usb::backend::transfer::BackendTransfer;
+use crate::usb::backend::transfer::BackendTransferHandle;
+use crate::usb::backend::transfer::BackendTransferType;
+use crate::usb::backend::transfer::ControlTransferState;
+use crate::usb::backend::transfer::GenericTransferHandle;
+use crate::usb::backend::usbip_backend::protocol;
+use crate::usb::backend::usbip_backend::protocol::ImportedDevice;
+use crate::usb::backend::usbip_backend::protocol::Reply;
+use crate::usb::backend::usbip_backend::protocol::HEADER_SIZE;
+use crate::usb::backend::usbip_backend::protocol::USBIP_DIR_IN;
+use crate::usb::backend::usbip_backend::protocol::USBIP_DIR_OUT;
+use crate::usb::xhci::scatter_gather_buffer::ScatterGatherBuffer;
+use crate::usb::xhci::xhci_backend_device::BackendType;
+use crate::usb::xhci::xhci_backend_device::UsbDeviceAddress;
+use crate::usb::xhci::xhci_backend_device::XhciBackendDevice;
+use crate::usb::xhci::xhci_transfer::XhciTransfer;
+use crate::utils::EventLoop;
+
+// How long to wait for the server to answer the requests made while attaching the device.
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(1000);
+
+// Negated errno values of the URB status, see Documentation/driver-api/usb/error-codes.rst in the
+// Linux kernel.
+const ENOENT: i32 = 2;
+const ENODEV: i32 = 19;
+const EPIPE: i32 = 32;
+const ECONNRESET: i32 = 104;
+const ESHUTDOWN: i32 = 108;
+
+// Standard requests sent to the device by the backend.
+const REQUEST_TYPE_DEVICE_IN: u8 = 0x80;
+const REQUEST_TYPE_DEVICE_OUT: u8 = 0x00;
+const REQUEST_TYPE_INTERFACE_OUT: u8 = 0x01;
+const REQUEST_TYPE_ENDPOINT_OUT: u8 = 0x02;
+const REQUEST_TYPE_PORT_OUT: u8 = 0x23;
+const CLEAR_FEATURE: u8 = 0x01;
+const SET_FEATURE: u8 = 0x03;
+const GET_DESCRIPTOR: u8 = 0x06;
+const SET_CONFIGURATION: u8 = 0x09;
+const SET_INTERFACE: u8 = 0x0b;
+// usbip-host resets the device when it receives a SET_FEATURE(PORT_RESET) hub class request.
+const PORT_FEAT_RESET: u16 = 4;
+
+fn transfer_status(status: i32) -> TransferStatus {
+    match -status {
+        0 => TransferStatus::Completed,
+        EPIPE => TransferStatus::Stalled,
+        ENOENT | ECONNRESET => TransferStatus::Cancelled,
+        ENODEV | ESHUTDOWN => TransferStatus::NoDevice,
+        _ => TransferStatus::Error,
+    }
+}
+
+/// A transfer submitted to a `UsbipDevice`.
+pub struct UsbipTransfer {
+    // Address of the endpoint of the transfer, 0 for control transfers.
+    ep_addr: u8,
+    buffer: TransferBuffer,
+    status: TransferStatus,
+    actual_length: usize,
+    callback: Option<Box<dyn Fn(BackendTransferType) + Send + Sync>>,
+}
+
+impl UsbipTransfer {
+    /// Creates a transfer of `buffer` to or from the endpoint `ep_addr`. A control transfer uses
+    /// endpoint 0, and its buffer holds a `UsbRequestSetup` followed by the data.
+    pub fn new(ep_addr: u8, buffer: TransferBuffer) -> UsbipTransfer {
+        UsbipTransfer {
+            ep_addr,
+            buffer,
+            status: TransferStatus::Error,
+            actual_length: 0,
+            callback: None,
+        }
+    }
+
+    // Returns the USB/IP direction of the transfer.
+    fn direction(&self) -> u32 {
+        let in_mask = if self.ep_addr == 0 {
+            // The direction of control transfers is the one of their data stage.
+            match &self.buffer {
+                TransferBuffer::Vector(v) => v.first().copied().unwrap_or(0),
+                TransferBuffer::Dma(_) => 0,
+            }
+        } else {
+            self.ep_addr
+        };
+        if in_mask & (1 << ENDPOINT_DIRECTION_OFFSET) != 0 {
+            USBIP_DIR_IN
+        } else {
+            USBIP_DIR_OUT
+        }
+    }
+
+    // Returns the data part of the buffer, after the setup packet of control transfers.
+    fn data_mut(&mut self) -> &mut [u8] {
+        let start = if self.ep_addr == 0 {
+            size_of::<UsbRequestSetup>()
+        } else {
+            0
+        };
+        match &mut self.buffer {
+            TransferBuffer::Vector(v) => v.get_mut(start..).unwrap_or_default(),
+            TransferBuffer::Dma(_) => &mut [],
+        }
+    }
+
+    fn run_callback(mut self) {
+        if let Some(callback) = self.callback.take() {
+            callback(BackendTransferType::UsbipDevice(self));
+        }
+    }
+}
+
+impl BackendTransfer for UsbipTransfer {
+    fn status(&self) -> TransferStatus {
+        self.status
+    }
+
+    fn actual_length(&self) -> usize {
+        self.actual_length
+    }
+
+    fn buffer(&self) -> &TransferBuffer {
+        &self.buffer
+    }
+
+    fn set_callback<C: 'static + Fn(BackendTransferType) + Send + Sync>(&mut self, cb: C) {
+        self.callback = Some(Box::new(cb));
+    }
+}
+
+// The result of a request made by the backend itself: its status and the data it returned.
+type RequestResult = (TransferStatus, Vec<u8>);
+
+// A standard request changing the state of the device, made by the backend on behalf of the guest.
+#[derive(Clone, Copy, Debug)]
+enum DeviceRequest {
+    SetConfiguration(u8),
+    SetInterface { interface: u8, alt_setting: u8 },
+    ClearFeature { value: u16, index: u16 },
+    Reset,
+}
+
+impl DeviceRequest {
+    fn setup(&self) -> UsbRequestSetup {
+        match *self {
+            DeviceRequest::SetConfiguration(config) => UsbRequestSetup::new(
+                REQUEST_TYPE_DEVICE_OUT,
+                SET_CONFIGURATION,
+                config as u16,
+                0,
+                0,
+            ),
+            DeviceRequest::SetInterface {
+                interface,
+                alt_setting,
+            } => UsbRequestSetup::new(
+                REQUEST_TYPE_INTERFACE_OUT,
+                SET_INTERFACE,
+                alt_setting as u16,
+                interface as u16,
+                0,
+            ),
+            DeviceRequest::ClearFeature { value, index } => {
+                UsbRequestSetup::new(REQUEST_TYPE_ENDPOINT_OUT, CLEAR_FEATURE, value, index, 0)
+            }
+            DeviceRequest::Reset => {
+                UsbRequestSetup::new(REQUEST_TYPE_PORT_OUT, SET_FEATURE, PORT_FEAT_RESET, 0, 0)
+            }
+        }
+    }
+}
+
+// What is done with the reply to a request made by the backend.
+enum RequestCompletion {
+    // The caller waits on the receiver for the result.
+    Wait(mpsc::Sender<RequestResult>),
+    // The request is applied to the device on the event loop, which then completes the control
+    // transfer of the guest, if any.
+    Apply(DeviceRequest, Option<Arc<XhciTransfer>>),
+}
+
+// A `DeviceRequest` the server replied to.
+struct CompletedRequest {
+    request: DeviceRequest,
+    xhci_transfer: Option<Arc<XhciTransfer>>,
+    status: TransferStatus,
+}
+
+// What is waiting for the reply to a URB.
+enum PendingUrb {
+    // A transfer submitted by the xHCI controller.
+    Transfer(UsbipTransfer),
+    // A request made by the backend.
+    Request {
+        direction: u32,
+        length: usize,
+        completion: RequestCompletion,
+    },
+    // The USBIP_CMD_UNLINK cancelling the URB with this sequence number.
+    Unlink(u32),
+}
+
+// The URBs waiting for a reply from the server, and the completed transfers and requests that
+// have yet to be handled on the event loop.
+struct Urbs {
+    pending: HashMap<u32, PendingUrb>,
+    completed: Vec<UsbipTransfer>,
+    completed_requests: Vec<CompletedRequest>,
+    // Whether the connection to the server is closed.
+    disconnected: bool,
+}
+
+// The state shared between the device and the thread reading the replies of the server.
+struct Connection {
+    stream: Mutex<TcpStream>,
+    devid: u32,
+    next_seqnum: Mutex<u32>,
+    urbs: Mutex<Urbs>,
+    completion_evt: Event,
+}
+
+impl Connection {
+    fn next_seqnum(&self) -> u32 {
+        let mut next_seqnum = self.next_seqnum.lock();
+        // Sequence number 0 is not used.
+        *next_seqnum = next_seqnum.wrapping_add(1).max(1);
+        *next_seqnum
+    }
+
+    // Sends a URB message to the server. A failure leaves the connection in an unknown state, so
+    // it is shut down.
+    fn send(&self, message: &[u8]) -> Result<()> {
+        let mut stream = self.stream.lock();
+        stream.write_all(message).map_err(|e| {
+            let _ = stream.shutdown(Shutdown::Both);
+            Error::UsbipSend(e)
+        })
+    }
+
+    // Queues the callback of `transfer` to run on the event loop.
+    fn complete(&self, urbs: &mut Urbs, transfer: UsbipTransfer) {
+        urbs.completed.push(transfer);
+        self.signal_completion();
+    }
+
+    // Queues `completion` to be handled on the event loop, if it isn't waited for.
+    fn complete_request(
+        &self,
+        urbs: &mut Urbs,
+        completion: RequestCompletion,
+        result: RequestResult,
+    ) {
+        match completion {
+            // The caller may have given up waiting.
+            RequestCompletion::Wait(sender) => {
+                let _ = sender.send(result);
+            }
+            RequestCompletion::Apply(request, xhci_transfer) => {
+                urbs.completed_requests.push(CompletedRequest {
+                    request,
+                    xhci_transfer,
+                    status: result.0,
+                });
+                self.signal_completion();
+            }
+        }
+    }
+
+    fn signal_completion(&self) {
+        if let Err(e) = self.completion_evt.signal() {
+            error!("failed to signal the completion of a usbip transfer: {}", e);
+        }
+    }
+
+    // Submits the control request `setup` on behalf of the backend. `data` is sent for OUT
+    // requests, or gives the length of the data to receive. The reply is handled by `completion`.
+    fn submit_request(
+        &self,
+        setup: UsbRequestSetup,
+        data: Vec<u8>,
+        completion: RequestCompletion,
+    ) -> Result<()> {
+        let direction = if setup.request_type & (1 << ENDPOINT_DIRECTION_OFFSET) != 0 {
+            USBIP_DIR_IN
+        } else {
+            USBIP_DIR_OUT
+        };
+        let seqnum = self.next_seqnum();
+        {
+            let mut urbs = self.urbs.lock();
+            if urbs.disconnected {
+                return Err(Error::UsbipDisconnected);
+            }
+            urbs.pending.insert(
+                seqnum,
+                PendingUrb::Request {
+                    direction,
+                    length: data.len(),
+                    completion,
+                },
+            );
+        }
+        let mut message = protocol::cmd_submit(
+            seqnum,
+            self.devid,
+            direction,
+            0,
+            data.len() as u32,
+            setup.as_bytes().try_into().unwrap(),
+        )
+        .to_vec();
+        if direction == USBIP_DIR_OUT {
+            message.extend_from_slice(&data);
+        }
+        self.send(&message)
+    }
+
+    // Submits the control request `setup` like `submit_request` and waits for its completion.
+    fn request(&self, setup: UsbRequestSetup, data: Vec<u8>) -> Result<RequestResult> {
+        let (sender, receiver) = mpsc::channel();
+        self.submit_request(setup, data, RequestCompletion::Wait(sender))?;
+        // A late reply is still read by the reader thread, which then drops it.
+        receiver.recv_timeout(REQUEST_TIMEOUT).map_err(|e| match e {
+            mpsc::RecvTimeoutError::Timeout => Error::UsbipTimeout,
+            mpsc::RecvTimeoutError::Disconnected => Error::UsbipDisconnected,
+        })
+    }
+
+    // Reads the device descriptor and all the configuration descriptors of the device.
+    fn read_descriptors(&self) -> Result<DeviceDescriptorTree> {
+        const DEVICE_DESCRIPTOR_SIZE: u16 = 18;
+        const CONFIG_DESCRIPTOR_SIZE: u16 = 9;
+
+        let mut raw = self.get_descriptor(DescriptorType::Device, 0, DEVICE_DESCRIPTOR_SIZE)?;
+        // bNumConfigurations is the last field of the device descriptor.
+        let num_configs = raw
+            .get(DEVICE_DESCRIPTOR_SIZE as usize - 1)
+            .copied()
+            .ok_or(Error::UsbipShortDescriptor)?;
+        for index in 0..num_configs {
+            let header =
+                self.get_descriptor(DescriptorType::Configuration, index, CONFIG_DESCRIPTOR_SIZE)?;
+            let total_length = match header.get(2..4) {
+                Some(total_length) => u16::from_le_bytes([total_length[0], total_length[1]]),
+                None => return Err(Error::UsbipShortDescriptor),
+            };
+            raw.extend(self.get_descriptor(DescriptorType::Configuration, index, total_length)?);
+        }
+        parse_usbfs_descriptors(&raw).map_err(Error::ParseDescriptors)
+    }
+
+    fn get_descriptor(&self, ty: DescriptorType, index: u8, length: u16) -> Result<Vec<u8>> {
+        let setup = UsbRequestSetup::new(
+            REQUEST_TYPE_DEVICE_IN,
+            GET_DESCRIPTOR,
+            ((ty as u16) << 8) | index as u16,
+            0,
+            length,
+        );
+        match self.request(setup, vec![0u8; length as usize])? {
+            (TransferStatus::Completed, data) if data.len() == length as usize => Ok(data),
+            (TransferStatus::Completed, _) => Err(Error::UsbipShortDescriptor),
+            _ => Err(Error::UsbipRequestFailed),
+        }
+    }
+
+    // Reads the replies of the server until the connection is closed.
+    fn run_reader(&self, mut stream: TcpStream) {
+        if let Err(e) = self.read_replies(&mut stream) {
+            if e.kind() != io::ErrorKind::UnexpectedEof {
+                error!("failed to read from the usbip server: {}", e);
+            }
+        }
+        let _ = stream.shutdown(Shutdown::Both);
+
+        // Fail everything that was waiting for the server.
+        let mut urbs = self.urbs.lock();
+        urbs.disconnected = true;
+        for (_, urb) in mem::take(&mut urbs.pending) {
+            match urb {
+                PendingUrb::Transfer(mut transfer) => {
+                    transfer.status = TransferStatus::NoDevice;
+                    self.complete(&mut urbs, transfer);
+                }
+                PendingUrb::Request { completion, .. } => {
+                    self.complete_request(
+                        &mut urbs,
+                        completion,
+                        (TransferStatus::NoDevice, Vec::new()),
+                    );
+                }
+                PendingUrb::Unlink(_) => {}
+            }
+        }
+    }
+
+    fn read_replies(&self, stream: &mut TcpStream) -> io::Result<()> {
+        loop {
+            let mut header = [0u8; HEADER_SIZE];
+            stream.read_exact(&mut header)?;
+            match protocol::parse_reply(&header)? {
+                Reply::Submit {
+                    seqnum,
+                    status,
+                    actual_length,
+                } => {
+                    let urb = self.urbs.lock().pending.remove(&seqnum);
+                    self.complete_urb(stream, urb, status, actual_length as usize)?;
+                }
+                Reply::Unlink { seqnum, status } => {
+                    let mut urbs = self.urbs.lock();
+                    let unlinked = match urbs.pending.remove(&seqnum) {
+                        Some(PendingUrb::Unlink(unlinked)) => unlinked,
+                        _ => continue,
+                    };
+                    // A status of 0 means the URB completed before it could be unlinked, its
+                    // RET_SUBMIT has already been handled. Otherwise no RET_SUBMIT will follow.
+                    if status != 0 {
+                        if let Some(PendingUrb::Transfer(mut transfer)) =
+                            urbs.pending.remove(&unlinked)
+                        {
+                            transfer.status = TransferStatus::Cancelled;
+                            self.complete(&mut urbs, transfer);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Completes `urb` with the status and the data of its RET_SUBMIT.
+    fn complete_urb(
+        &self,
+        stream: &mut TcpStream,
+        urb: Option<PendingUrb>,
+        status: i32,
+        actual_length: usize,
+    ) -> io::Result<()> {
+        match urb {
+            Some(PendingUrb::Transfer(mut transfer)) => {
+                if transfer.direction() == USBIP_DIR_IN {
+                    let data = transfer.data_mut();
+                    if actual_length > data.len() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "usbip transfer returned too much data",
+                        ));
+                    }
+                    stream.read_exact(&mut data[..actual_length])?;
+                }
+                transfer.status = transfer_status(status);
+                transfer.actual_length = actual_length;
+                let mut urbs = self.urbs.lock();
+                self.complete(&mut urbs, transfer);
+            }
+            Some(PendingUrb::Request {
+                direction,
+                length,
+                completion,
+            }) => {
+                let mut data = Vec::new();
+                if direction == USBIP_DIR_IN {
+                    if actual_length > length {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "usbip request returned too much data",
+                        ));
+                    }
+                    data.resize(actual_length, 0);
+                    stream.read_exact(&mut data)?;
+                }
+                let mut urbs = self.urbs.lock();
+                self.complete_request(&mut urbs, completion, (transfer_status(status), data));
+            }
+            Some(PendingUrb::Unlink(_)) | None => {
+                // The data of an unknown URB can't be skipped since its direction is unknown.
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "usbip server completed an unknown URB",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+struct UsbipTransferHandle {
+    seqnum: u32,
+    connection: Weak<Connection>,
+}
+
+impl GenericTransferHandle for UsbipTransferHandle {
+    fn cancel(&self) -> Result<()> {
+        let connection = match self.connection.upgrade() {
+            Some(connection) => connection,
+            None => return Ok(()),
+        };
+        let unlink_seqnum = connection.next_seqnum();
+        {
+            let mut urbs = connection.urbs.lock();
+            if !matches!(
+                urbs.pending.get(&self.seqnum),
+                Some(PendingUrb::Transfer(_))
+            ) {
+                // The transfer is already complete.
+                return Ok(());
+            }
+            urbs.pending
+                .insert(unlink_seqnum, PendingUrb::Unlink(self.seqnum));
+        }
+        connection.send(&protocol::cmd_unlink(
+            unlink_seqnum,
+            connection.devid,
+            self.seqnum,
+        ))
+    }
+}
+
+/// A device on a remote host, imported from a USB/IP server.
+pub struct UsbipDevice {
+    connection: Arc<Connection>,
+    reader_thread: Option<thread::JoinHandle<()>>,
+    imported: ImportedDevice,
+    descriptors: DeviceDescriptorTree,
+    // bConfigurationValue of the active configuration.
+    configuration: u8,
+    // Alternate settings of the interfaces of the active configuration that are not 0.
+    alt_settings: HashMap<u8, u8>,
+    state: Arc<RwLock<DeviceState>>,
+    control_transfer_state: Arc<RwLock<ControlTransferState>>,
+}
+
+impl UsbipDevice {
+    /// Imports the device `busid` from the USB/IP server connected to `stream`.
+    pub fn new(mut stream: TcpStream, busid: &str, state: DeviceState) -> Result<UsbipDevice> {
+        stream
+            .set_read_timeout(Some(REQUEST_TIMEOUT))
+            .map_err(Error::UsbipConnection)?;
+        let imported = protocol::import_device(&mut stream, busid).map_err(Error::UsbipImport)?;
+        stream
+            .set_read_timeout(None)
+            .map_err(Error::UsbipConnection)?;
+        let reader_stream = stream.try_clone().map_err(Error::UsbipConnection)?;
+
+        let connection = Arc::new(Connection {
+            stream: Mutex::new(stream),
+            devid: imported.devid(),
+            next_seqnum: Mutex::new(0),
+            urbs: Mutex::new(Urbs {
+                pending: HashMap::new(),
+                completed: Vec::new(),
+                completed_requests: Vec::new(),
+                disconnected: false,
+            }),
+            completion_evt: Event::new().map_err(Error::CreateCompletionEvent)?,
+        });
+        let reader_connection = connection.clone();
+        let reader_thread = thread::Builder::new()
+            .name("usbip_reader".to_string())
+            .spawn(move || reader_connection.run_reader(reader_stream))
+            .map_err(Error::UsbipConnection)?;
+
+        let control_transfer_state = ControlTransferState {
+            ctl_ep_state: ControlEndpointState::SetupStage,
+            control_request_setup: UsbRequestSetup::new(0, 0, 0, 0, 0),
+            executed: false,
+        };
+        let descriptors = match connection.read_descriptors() {
+            Ok(descriptors) => descriptors,
+            Err(e) => {
+                let _ = connection.stream.lock().shutdown(Shutdown::Both);
+                let _ = reader_thread.join();
+                return Err(e);
+            }
+        };
+        Ok(UsbipDevice {
+            connection,
+            reader_thread: Some(reader_thread),
+            descriptors,
+            configuration: imported.configuration_value,
+            imported,
+            alt_settings: HashMap::new(),
+            state: Arc::new(RwLock::new(state)),
+            control_transfer_state: Arc::new(RwLock::new(control_transfer_state)),
+        })
+    }
+
+    // Sends `request` to the device without waiting for the server. Once the server replies, the
+    // request is applied to the device and `xhci_transfer` completes on the event loop.
+    fn start_request(
+        &self,
+        request: DeviceRequest,
+        xhci_transfer: Option<Arc<XhciTransfer>>,
+    ) -> Result<()> {
+        self.connection.submit_request(
+            request.setup(),
+            Vec::new(),
+            RequestCompletion::Apply(request, xhci_transfer),
+        )
+    }
+
+    /// Starts a SET_CONFIGURATION request, which completes `xhci_transfer` once the server
+    /// replies.
+    pub fn start_set_configuration(
+        &mut self,
+        config: u8,
+        xhci_transfer: Arc<XhciTransfer>,
+    ) -> Result<()> {
+        self.start_request(DeviceRequest::SetConfiguration(config), Some(xhci_transfer))
+    }
+
+    /// Starts a SET_INTERFACE request, which completes `xhci_transfer` once the server replies.
+    pub fn start_set_interface(
+        &mut self,
+        interface: u8,
+        alt_setting: u8,
+        xhci_transfer: Arc<XhciTransfer>,
+    ) -> Result<()> {
+        self.start_request(
+            DeviceRequest::SetInterface {
+                interface,
+                alt_setting,
+            },
+            Some(xhci_transfer),
+        )
+    }
+
+    /// Starts a CLEAR_FEATURE endpoint request, which completes `xhci_transfer` once the server
+    /// replies.
+    pub fn start_clear_feature(
+        &mut self,
+        value: u16,
+        index: u16,
+        xhci_transfer: Arc<XhciTransfer>,
+    ) -> Result<()> {
+        self.start_request(
+            DeviceRequest::ClearFeature { value, index },
+            Some(xhci_transfer),
+        )
+    }
+
+    // Updates the device after the server completed `request`.
+    fn apply_request(&mut self, request: DeviceRequest) -> Result<()> {
+        match request {
+            DeviceRequest::SetConfiguration(config) => {
+                let config_descriptor = self.get_config_descriptor(config)?;
+                self.configuration = config;
+                self.alt_settings.clear();
+                self.create_endpoints(&config_descriptor)
+            }
+            DeviceRequest::SetInterface {
+                interface,
+                alt_setting,
+            } => {
+                self.alt_settings.insert(interface, alt_setting);
+                let config_descriptor = self.get_active_config_descriptor()?;
+                self.create_endpoints(&config_descriptor)
+            }
+            DeviceRequest::ClearFeature { .. } | DeviceRequest::Reset => Ok(()),
+        }
+    }
+
+    /// Writes the configuration descriptor with index `descriptor_index`, followed by the
+    /// descriptors of its interfaces and endpoints, to `buffer` for a GET_DESCRIPTOR request.
+    pub fn get_config_descriptor_raw(
+        &self,
+        buffer: &ScatterGatherBuffer,
+        descriptor_index: u8,
+    ) -> Result<(TransferStatus, u32)> {
+        let config = self
+            .descriptors
+            .get_config_descriptor_by_index(descriptor_index)
+            .ok_or(Error::UnknownConfiguration(descriptor_index))?;
+        let start = config.offset();
+        let data = self
+            .descriptors
+            .raw()
+            .get(start..start + config.wTotalLength as usize)
+            .ok_or(Error::UnknownConfiguration(descriptor_index))?;
+        let bytes_transferred = buffer.write(data).map_err(Error::WriteBuffer)?;
+        Ok((TransferStatus::Completed, bytes_transferred as u32))
+    }
+
+    /// Runs the callbacks of the transfers completed since the last call, and applies the
+    /// requests completed since then.
+    pub fn poll_transfers(&mut self) -> Result<()> {
+        let (completed, completed_requests) = {
+            let mut urbs = self.connection.urbs.lock();
+            if urbs.completed.is_empty() && urbs.completed_requests.is_empty() {
+                return Ok(());
+            }
+            // The event was signaled when the transfers were completed, so this doesn't block.
+            self.connection
+                .completion_evt
+                .wait()
+                .map_err(Error::ReadCompletionEvent)?;
+            (
+                mem::take(&mut urbs.completed),
+                mem::take(&mut urbs.completed_requests),
+            )
+        };
+        for transfer in completed {
+            transfer.run_callback();
+        }
+        for completed in completed_requests {
+            let status = if completed.status != TransferStatus::Completed {
+                error!(
+                    "usbip request {:?} failed: {:?}",
+                    completed.request, completed.status
+                );
+                TransferStatus::Stalled
+            } else if let Err(e) = self.apply_request(completed.request) {
+                error!(
+                    "failed to apply usbip request {:?}: {}",
+                    completed.request, e
+                );
+                TransferStatus::Stalled
+            } else {
+                TransferStatus::Completed
+            };
+            if let Some(xhci_transfer) = completed.xhci_transfer {
+                xhci_transfer
+                    .on_transfer_complete(&status, 0)
+                    .map_err(Error::TransferComplete)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for UsbipDevice {
+    fn drop(&mut self) {
+        // Closing the connection makes the reader thread exit.
+        let _ = self.connection.stream.lock().shutdown(Shutdown::Both);
+        if let Some(reader_thread) = self.reader_thread.take() {
+            if reader_thread.join().is_err() {
+                error!("usbip reader thread panicked");
+            }
+        }
+    }
+}
+
+impl AsRawDescriptor for UsbipDevice {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.connection.completion_evt.as_raw_descriptor()
+    }
+}
+
+impl BackendDevice for UsbipDevice {
+    fn submit_backend_transfer(
+        &mut self,
+        transfer: BackendTransferType,
+    ) -> Result<BackendTransferHandle> {
+        let mut transfer = match transfer {
+            BackendTransferType::UsbipDevice(transfer) => transfer,
+            _ => return Err(Error::MalformedBackendTransfer),
+        };
+        let direction = transfer.direction();
+        let buffer = match &transfer.buffer {
+            TransferBuffer::Vector(v) => v,
+            // Only vector buffers are handed out by `request_transfer_buffer()`.
+            TransferBuffer::Dma(_) => return Err(Error::MalformedBackendTransfer),
+        };
+
+        let (setup, data) = if transfer.ep_addr == 0 {
+            if buffer.len() < size_of::<UsbRequestSetup>() {
+                return Err(Error::MalformedBackendTransfer);
+            }
+            let (setup, data) = buffer.split_at(size_of::<UsbRequestSetup>());
+            (setup.try_into().unwrap(), data)
+        } else {
+            ([0u8; 8], buffer.as_slice())
+        };
+        let seqnum = self.connection.next_seqnum();
+        let mut message = protocol::cmd_submit(
+            seqnum,
+            self.connection.devid,
+            direction,
+            transfer.ep_addr & !(1 << ENDPOINT_DIRECTION_OFFSET),
+            data.len() as u32,
+            setup,
+        )
+        .to_vec();
+        if direction == USBIP_DIR_OUT {
+            message.extend_from_slice(data);
+        }
+
+        {
+            let mut urbs = self.connection.urbs.lock();
+            if urbs.disconnected {
+                transfer.status = TransferStatus::NoDevice;
+                self.connection.complete(&mut urbs, transfer);
+                return Ok(BackendTransferHandle::new(UsbipTransferHandle {
+                    seqnum,
+                    connection: Arc::downgrade(&self.connection),
+                }));
+            }
+            // The transfer is registered before it is sent, the reply may come right away.
+            urbs.pending.insert(seqnum, PendingUrb::Transfer(transfer));
+        }
+        self.connection.send(&message)?;
+        Ok(BackendTransferHandle::new(UsbipTransferHandle {
+            seqnum,
+            connection: Arc::downgrade(&self.connection),
+        }))
+    }
+
+    fn detach_event_handler(&self, event_loop: &Arc<EventLoop>) -> Result<()> {
+        event_loop
+            .remove_event_for_descriptor(self)
+            .map_err(Error::RemoveFromEventLoop)
+    }
+
+    fn request_transfer_buffer(&mut self, size: usize) -> TransferBuffer {
+        TransferBuffer::Vector(vec![0u8; size])
+    }
+
+    fn build_bulk_transfer(
+        &mut self,
+        ep_addr: u8,
+        transfer_buffer: TransferBuffer,
+        _stream_id: Option<u16>,
+    ) -> Result<BackendTransferType> {
+        Ok(BackendTransferType::UsbipDevice(UsbipTransfer::new(
+            ep_addr,
+            transfer_buffer,
+        )))
+    }
+
+    fn build_interrupt_transfer(
+        &mut self,
+        ep_addr: u8,
+        transfer_buffer: TransferBuffer,
+    ) -> Result<BackendTransferType> {
+        Ok(BackendTransferType::UsbipDevice(UsbipTransfer::new(
+            ep_addr,
+            transfer_buffer,
+        )))
+    }
+
+    fn get_control_transfer_state(&mut self) -> Arc<RwLock<ControlTransferState>> {
+        self.control_transfer_state.clone()
+    }
+
+    fn get_device_state(&mut self) -> Arc<RwLock<DeviceState>> {
+        self.state.clone()
+    }
+
+    fn get_active_config_descriptor(&mut self) -> Result<ConfigDescriptorTree> {
+        self.get_config_descriptor(self.configuration)
+    }
+
+    fn get_config_descriptor(&mut self, config: u8) -> Result<ConfigDescriptorTree> {
+        self.descriptors
+            .get_config_descriptor(config)
+            .cloned()
+            .ok_or(Error::UnknownConfiguration(config))
+    }
+
+    fn get_config_descriptor_by_index(&mut self, config_index: u8) -> Result<ConfigDescriptorTree> {
+        self.descriptors
+            .get_config_descriptor_by_index(config_index)
+            .cloned()
+            .ok_or(Error::UnknownConfiguration(config_index))
+    }
+
+    fn get_device_descriptor_tree(&mut self) -> DeviceDescriptorTree {
+        self.descriptors.clone()
+    }
+
+    fn get_active_configuration(&mut self) -> Result<u8> {
+        Ok(self.configuration)
+    }
+
+    fn set_active_configuration(&mut self, config: u8) -> Result<()> {
+        // The configuration changes once the server replies, see `poll_transfers`.
+        self.start_request(DeviceRequest::SetConfiguration(config), None)
+    }
+
+    fn clear_feature(&mut self, value: u16, index: u16) -> Result<TransferStatus> {
+        // It's a standard, clear_feature, endpoint request, e.g. clearing an endpoint halt. A
+        // failure is only logged once the server replies.
+        self.start_request(DeviceRequest::ClearFeature { value, index }, None)?;
+        Ok(TransferStatus::Completed)
+    }
+
+    fn create_endpoints(&mut self, config_descriptor: &ConfigDescriptorTree) -> Result<()> {
+        let mut endpoints = Vec::new();
+        let device_state = self.get_device_state();
+        for i in 0..config_descriptor.num_interfaces() {
+            let alt_setting = self.alt_settings.get(&i).unwrap_or(&0);
+            let interface = config_descriptor
+                .get_interface_descriptor(i, *alt_setting)
+                .ok_or(Error::GetInterfaceDescriptor(i, *alt_setting))?;
+            for ep_idx in 0..interface.bNumEndpoints {
+                let ep_dp = interface
+                    .get_endpoint_descriptor(ep_idx)
+                    .ok_or(Error::GetEndpointDescriptor(ep_idx))?;
+                let ep_num = ep_dp.get_endpoint_number();
+                if ep_num == 0 {
+                    continue;
+                }
+                let direction = ep_dp.get_direction();
+                let ty = ep_dp.get_endpoint_type().ok_or(Error::GetEndpointType)?;
+                endpoints.push(UsbEndpoint::new(
+                    device_state.read().unwrap().fail_handle.clone(),
+                    device_state.read().unwrap().job_queue.clone(),
+                    ep_num,
+                    direction,
+                    ty,
+                ));
+            }
+        }
+        device_state.write().unwrap().endpoints = endpoints;
+        Ok(())
+    }
+}
+
+impl XhciBackendDevice for UsbipDevice {
+    fn get_backend_type(&self) -> BackendType {
+        // See definition of bcdUsb.
+        const USB3_MASK: u16 = 0x0300;
+        match self.descriptors.bcdUSB & USB3_MASK {
+            USB3_MASK => BackendType::Usb3,
+            _ => BackendType::Usb2,
+        }
+    }
+
+    fn get_vid(&self) -> u16 {
+        self.descriptors.idVendor
+    }
+
+    fn get_pid(&self) -> u16 {
+        self.descriptors.idProduct
+    }
+
+    fn set_address(&mut self, address: UsbDeviceAddress) {
+        // The server keeps the address of the device on its own bus.
+        debug!("usbip device set address: {}", address);
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // A failure is only logged once the server replies.
+        self.start_request(DeviceRequest::Reset, None)
+    }
+
+    fn get_speed(&self) -> Option<DeviceSpeed> {
+        self.imported.device_speed()
+    }
+
+    fn alloc_streams(&self, _ep: u8, _num_streams: u16) -> Result<()> {
+        // USB/IP doesn't carry bulk streams.
+        Err(Error::StreamsNotSupported)
+    }
+
+    fn free_streams(&self, _ep: u8) -> Result<()> {
+        Err(Error::StreamsNotSupported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::usb::backend::usbip_backend::protocol::USBIP_CMD_SUBMIT;
+    use crate::usb::backend::usbip_backend::protocol::USBIP_CMD_UNLINK;
+    use crate::usb::backend::usbip_backend::protocol::USBIP_RET_SUBMIT;
+    use crate::usb::backend::usbip_backend::protocol::USBIP_RET_UNLINK;
+    use crate::utils::AsyncJobQueue;
+    use crate::utils::FailHandle;
+
+    // A device with a configuration whose interface has a bulk IN endpoint.
+    const DEVICE_DESCRIPTOR: [u8; 18] = [
+        18, 1, 0x00, 0x02, 0, 0, 0, 64, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0, 0, 0, 1,
+    ];
+    const CONFIG_DESCRIPTOR: [u8; 25] = [
+        9, 2, 25, 0, 1, 1, 0, 0x80, 50, // configuration 1
+        9, 4, 0, 0, 1, 0xff, 0, 0, 0, // interface 0
+        7, 5, 0x81, 2, 0x00, 0x02, 0, // bulk endpoint 1 IN
+    ];
+
+    type Completions = Arc<Mutex<Vec<(TransferStatus, Vec<u8>)>>>;
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    // Answers the import request of the device "1-1" as the server.
+    fn serve_import(stream: &mut TcpStream) {
+        let mut request = [0u8; 40];
+        stream.read_exact(&mut request).unwrap();
+        assert_eq!(&request[0..4], &[0x01, 0x11, 0x80, 0x03]);
+        assert_eq!(&request[8..12], b"1-1\0");
+
+        let mut reply = vec![0x01, 0x11, 0x00, 0x03, 0, 0, 0, 0];
+        let mut device = [0u8; 312];
+        device[288..292].copy_from_slice(&1u32.to_be_bytes());
+        device[292..296].copy_from_slice(&2u32.to_be_bytes());
+        device[296..300].copy_from_slice(&3u32.to_be_bytes());
+        device[309] = 1;
+        reply.extend_from_slice(&device);
+        stream.write_all(&reply).unwrap();
+    }
+
+    fn read_command(stream: &mut TcpStream) -> [u8; HEADER_SIZE] {
+        let mut command = [0u8; HEADER_SIZE];
+        stream.read_exact(&mut command).unwrap();
+        command
+    }
+
+    fn ret_submit(stream: &mut TcpStream, seqnum: u32, status: i32, data: &[u8]) {
+        let mut reply = [0u8; HEADER_SIZE];
+        reply[0..4].copy_from_slice(&USBIP_RET_SUBMIT.to_be_bytes());
+        reply[4..8].copy_from_slice(&seqnum.to_be_bytes());
+        reply[20..24].copy_from_slice(&status.to_be_bytes());
+        reply[24..28].copy_from_slice(&(data.len() as u32).to_be_bytes());
+        stream.write_all(&reply).unwrap();
+        stream.write_all(data).unwrap();
+    }
+
+    // Answers the GET_DESCRIPTOR requests reading the descriptors of the device.
+    fn serve_descriptors(stream: &mut TcpStream) {
+        for _ in 0..3 {
+            let command = read_command(stream);
+            assert_eq!(read_u32(&command, 0), USBIP_CMD_SUBMIT);
+            assert_eq!(read_u32(&command, 12), USBIP_DIR_IN);
+            let setup = &command[40..48];
+            assert_eq!(setup[1], GET_DESCRIPTOR);
+            let length = u16::from_le_bytes([setup[6], setup[7]]) as usize;
+            let descriptor: &[u8] = match setup[3] {
+                1 => &DEVICE_DESCRIPTOR,
+                2 => &CONFIG_DESCRIPTOR,
+                ty => panic!("unexpected descriptor type {}", ty),
+            };
+            ret_submit(
+                stream,
+                read_u32(&command, 4),
+                0,
+                &descriptor[..length.min(descriptor.len())],
+            );
+        }
+    }
+
+    // Submits a transfer from the bulk IN endpoint, recording its completion in `completions`.
+    fn submit_in(device: &mut UsbipDevice, completions: &Completions) -> BackendTransferHandle {
+        let mut transfer = UsbipTransfer::new(0x81, TransferBuffer::Vector(vec![0u8; 4]));
+        let completions = completions.clone();
+        transfer.set_callback(move |t: BackendTransferType| {
+            let data = match t.buffer() {
+                TransferBuffer::Vector(v) => v[..t.actual_length()].to_vec(),
+                TransferBuffer::Dma(_) => Vec::new(),
+            };
+            completions.lock().push((t.status(), data));
+        });
+        device
+            .submit_backend_transfer(BackendTransferType::UsbipDevice(transfer))
+            .unwrap()
+    }
+
+    // Polls the device until `done` holds.
+    fn poll_until(device: &mut UsbipDevice, mut done: impl FnMut(&mut UsbipDevice) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            device.poll_transfers().unwrap();
+            if done(device) {
+                return;
+            }
+            assert!(Instant::now() < deadline, "timed out polling the device");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn next_completion(
+        device: &mut UsbipDevice,
+        completions: &Completions,
+    ) -> (TransferStatus, Vec<u8>) {
+        poll_until(device, |_| !completions.lock().is_empty());
+        completions.lock().remove(0)
+    }
+
+    #[test]
+    fn stub_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server_thread = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            serve_import(&mut stream);
+            serve_descriptors(&mut stream);
+            stream
+        });
+        let (event_loop, event_loop_thread) =
+            EventLoop::start("usbip_test".to_string(), None).unwrap();
+        let fail_handle: Arc<dyn FailHandle> = Arc::new(None::<Arc<dyn FailHandle>>);
+        let state = DeviceState::new(fail_handle, AsyncJobQueue::init(&event_loop).unwrap());
+        let mut device =
+            UsbipDevice::new(TcpStream::connect(address).unwrap(), "1-1", state).unwrap();
+        let mut server = server_thread.join().unwrap();
+        assert_eq!((device.get_vid(), device.get_pid()), (0x1234, 0x5678));
+        assert!(matches!(device.get_speed(), Some(DeviceSpeed::High)));
+        assert_eq!(device.get_active_configuration().unwrap(), 1);
+
+        // A transfer completes with the data of its RET_SUBMIT.
+        let completions = Completions::default();
+        let _handle = submit_in(&mut device, &completions);
+        let command = read_command(&mut server);
+        assert_eq!(read_u32(&command, 0), USBIP_CMD_SUBMIT);
+        assert_eq!(read_u32(&command, 8), 0x0001_0002);
+        assert_eq!(read_u32(&command, 12), USBIP_DIR_IN);
+        assert_eq!(read_u32(&command, 16), 1);
+        assert_eq!(read_u32(&command, 24), 4);
+        ret_submit(&mut server, read_u32(&command, 4), 0, &[1, 2, 3]);
+        assert_eq!(
+            next_completion(&mut device, &completions),
+            (TransferStatus::Completed, vec![1, 2, 3])
+        );
+
+        // A cancelled transfer is unlinked.
+        let handle = submit_in(&mut device, &completions);
+        let command = read_command(&mut server);
+        handle.cancel().unwrap();
+        let unlink = read_command(&mut server);
+        assert_eq!(read_u32(&unlink, 0), USBIP_CMD_UNLINK);
+        assert_eq!(read_u32(&unlink, 20), read_u32(&command, 4));
+        let mut reply = [0u8; HEADER_SIZE];
+        reply[0..4].copy_from_slice(&USBIP_RET_UNLINK.to_be_bytes());
+        reply[4..8].copy_from_slice(&unlink[4..8]);
+        reply[20..24].copy_from_slice(&(-ECONNRESET).to_be_bytes());
+        server.write_all(&reply).unwrap();
+        assert_eq!(
+            next_completion(&mut device, &completions),
+            (TransferStatus::Cancelled, Vec::new())
+        );
+
+        // Standard requests don't wait for the server, the device is updated once it replies.
+        device.set_active_configuration(1).unwrap();
+        let command = read_command(&mut server);
+        assert_eq!(read_u32(&command, 12), USBIP_DIR_OUT);
+        assert_eq!(
+            &command[40..48],
+            &[0x00, SET_CONFIGURATION, 1, 0, 0, 0, 0, 0]
+        );
+        assert!(device.state.read().unwrap().endpoints.is_empty());
+        ret_submit(&mut server, read_u32(&command, 4), 0, &[]);
+        poll_until(&mut device, |device| {
+            device.state.read().unwrap().endpoints.len() == 1
+        });
+
+        // The transfers pending when the server disconnects fail.
+        let _handle = submit_in(&mut device, &completions);
+        read_command(&mut server);
+        drop(server);
+        assert_eq!(
+            next_completion(&mut device, &completions),
+            (TransferStatus::NoDevice, Vec::new())
+        );
+        assert!(matches!(
+            device.set_active_configuration(1),
+            Err(Error::UsbipDisconnected)
+        ));
+
+        drop(device);
+        event_loop.stop();
+        event_loop_thread.join().unwrap();
+    }
+}
@@ -72,6 +72,28 @@ impl EventHandler for UsbUtilEventHandler {
                 .lock()
                 .poll_transfers()
                 .context("UsbUtilEventHandler poll_transfers failed"),
+            BackendDeviceType::EmulatedDevice(emulated_device) => emulated_device
+                .poll_transfers()
+                .context("UsbUtilEventHandler poll_transfers failed"),
+            BackendDeviceType::UsbipDevice(usbip_device) => usbip_device
+                .poll_transfers()
+                .context("UsbUtilEventHandler poll_transfers failed"),
+        }
+    }
+}
+
+/// Handles the external events of the function of an emulated device.
+pub struct UsbFunctionEventHandler {
+    pub device: Arc<Mutex<BackendDeviceType>>,
+}
+
+impl EventHandler for UsbFunctionEventHandler {
+    fn on_event(&self) -> anyhow::Result<()> {
+        match &mut *self.device.lock() {
+            BackendDeviceType::EmulatedDevice(emulated_device) => emulated_device
+                .process_function_events()
+                .context("UsbFunctionEventHandler process_function_events failed"),
+            BackendDeviceType::HostDevice(_) | BackendDeviceType::UsbipDevice(_) => Ok(()),
         }
     }
 }
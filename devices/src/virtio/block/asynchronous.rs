@@ -15,6 +15,7 @@ use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 use std::u32;
 
 use anyhow::Context;
@@ -50,16 +51,20 @@ use futures::stream::FuturesUnordered;
 use futures::stream::StreamExt;
 use futures::FutureExt;
 use remain::sorted;
+use sync::Mutex;
 use thiserror::Error as ThisError;
 use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use vm_control::DiskControlCommand;
 use vm_control::DiskControlResult;
+use vm_control::DiskSnapshotInfo;
+use vm_control::DiskThrottleLimits;
 use vm_memory::GuestMemory;
 use zerocopy::AsBytes;
 
 use crate::virtio::async_utils;
 use crate::virtio::block::sys::*;
 use crate::virtio::block::DiskOption;
+use crate::virtio::block::Throttle;
 use crate::virtio::copy_config;
 use crate::virtio::device_constants::block::virtio_blk_config;
 use crate::virtio::device_constants::block::virtio_blk_discard_write_zeroes;
@@ -136,6 +141,8 @@ pub enum ExecuteError {
     ReceivingCommand(TubeError),
     #[error("failed to send command response: {0}")]
     SendingResponse(TubeError),
+    #[error("couldn't wait for the I/O limits of the disk: {0}")]
+    Throttle(cros_async::Error),
     #[error("couldn't reset the timer: {0}")]
     TimerReset(base::Error),
     #[error("unsupported ({0})")]
@@ -168,6 +175,7 @@ impl ExecuteError {
             ExecuteError::ReadOnly { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::ReceivingCommand(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::SendingResponse(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Throttle(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::TimerReset(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::WriteIo { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::WriteStatus(_) => VIRTIO_BLK_S_IOERR,
@@ -225,6 +233,8 @@ pub struct DiskState {
     /// A DiskState is owned by each worker's executor and cannot be shared by workers, thus
     /// `worker_shared_state` holds the state shared by workers in Arc.
     worker_shared_state: Arc<AsyncRwLock<WorkerSharedState>>,
+    /// I/O limits of the disk, shared by all the workers.
+    throttle: Arc<Mutex<Throttle>>,
 }
 
 /// Disk state which can be modified by other worker threads
@@ -240,6 +250,7 @@ impl DiskState {
         read_only: bool,
         sparse: bool,
         id: Option<BlockId>,
+        throttle: Arc<Mutex<Throttle>>,
     ) -> DiskState {
         DiskState {
             disk_image,
@@ -247,6 +258,46 @@ impl DiskState {
             sparse,
             id,
             worker_shared_state: Arc::new(AsyncRwLock::new(WorkerSharedState { disk_size })),
+            throttle,
+        }
+    }
+}
+
+// Waits until the I/O limits of the disk allow the request read by `reader` to be executed.
+// `data_len` is the size of the data region of the request. Only reads and writes are throttled.
+async fn wait_for_throttle(
+    reader: &Reader,
+    data_len: usize,
+    disk_state: &AsyncRwLock<DiskState>,
+    ex: &Executor,
+) -> result::Result<(), ExecuteError> {
+    let throttle = Arc::clone(&disk_state.read_lock().await.throttle);
+    if throttle.lock().is_unlimited() {
+        return Ok(());
+    }
+
+    // Malformed requests are reported by `execute_request`.
+    let (write, len) = match reader.peek_obj::<virtio_blk_req_header>() {
+        Ok(req_header) => match req_header.req_type.to_native() {
+            VIRTIO_BLK_T_IN => (false, data_len),
+            VIRTIO_BLK_T_OUT => (
+                true,
+                reader.available_bytes() - size_of::<virtio_blk_req_header>(),
+            ),
+            _ => return Ok(()),
+        },
+        Err(_) => return Ok(()),
+    };
+
+    loop {
+        // Don't hold the lock while sleeping, so that other requests and control commands can use
+        // the throttle.
+        let wait = throttle.lock().admit(Instant::now(), write, len as u64);
+        match wait {
+            None => return Ok(()),
+            Some(wait) => TimerAsync::sleep(ex, wait)
+                .await
+                .map_err(ExecuteError::Throttle)?,
         }
     }
 }
@@ -256,6 +307,7 @@ async fn process_one_request(
     disk_state: &AsyncRwLock<DiskState>,
     flush_timer: &RefCell<TimerAsync<Timer>>,
     flush_timer_armed: &RefCell<bool>,
+    ex: &Executor,
 ) -> result::Result<usize, ExecuteError> {
     let reader = &mut avail_desc.reader;
     let writer = &mut avail_desc.writer;
@@ -269,15 +321,14 @@ async fn process_one_request(
         .ok_or(ExecuteError::MissingStatus)?;
     let mut status_writer = writer.split_at(status_offset);
 
-    let status = match BlockAsync::execute_request(
-        reader,
-        writer,
-        disk_state,
-        flush_timer,
-        flush_timer_armed,
-    )
-    .await
-    {
+    let result = match wait_for_throttle(reader, writer.available_bytes(), disk_state, ex).await {
+        Ok(()) => {
+            BlockAsync::execute_request(reader, writer, disk_state, flush_timer, flush_timer_armed)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    let status = match result {
         Ok(()) => VIRTIO_BLK_S_OK,
         Err(e) => {
             match e.log_level() {
@@ -302,10 +353,17 @@ pub async fn process_one_chain(
     interrupt: &Interrupt,
     flush_timer: &RefCell<TimerAsync<Timer>>,
     flush_timer_armed: &RefCell<bool>,
+    ex: &Executor,
 ) {
     let _trace = cros_tracing::trace_event!(VirtioBlk, "process_one_chain");
-    let len = match process_one_request(&mut avail_desc, disk_state, flush_timer, flush_timer_armed)
-        .await
+    let len = match process_one_request(
+        &mut avail_desc,
+        disk_state,
+        flush_timer,
+        flush_timer_armed,
+        ex,
+    )
+    .await
     {
         Ok(len) => len,
         Err(e) => {
@@ -329,6 +387,7 @@ async fn handle_queue(
     interrupt: Interrupt,
     flush_timer: Rc<RefCell<TimerAsync<Timer>>>,
     flush_timer_armed: Rc<RefCell<bool>>,
+    ex: Executor,
     mut stop_rx: oneshot::Receiver<()>,
 ) -> Rc<RefCell<Queue>> {
     let mut background_tasks = FuturesUnordered::new();
@@ -365,6 +424,7 @@ async fn handle_queue(
                 &interrupt,
                 &flush_timer,
                 &flush_timer_armed,
+                &ex,
             ));
         }
     }
@@ -385,8 +445,14 @@ async fn handle_command_tube(
     loop {
         match command_tube.next().await {
             Ok(command) => {
+                let resized = matches!(command, DiskControlCommand::Resize { .. });
                 let resp = match command {
                     DiskControlCommand::Resize { new_size } => resize(&disk_state, new_size).await,
+                    DiskControlCommand::ListSnapshots => list_snapshots(&disk_state).await,
+                    DiskControlCommand::Throttle { limits } => {
+                        set_throttle(&disk_state, limits).await
+                    }
+                    command => update_snapshots(&disk_state, command).await,
                 };
 
                 let resp_clone = resp.clone();
@@ -394,7 +460,7 @@ async fn handle_command_tube(
                     .send(resp_clone)
                     .await
                     .map_err(ExecuteError::SendingResponse)?;
-                if let DiskControlResult::Ok = resp {
+                if resized && resp == DiskControlResult::Ok {
                     interrupt.signal_config_changed();
                 }
             }
@@ -439,6 +505,102 @@ async fn resize(disk_state: &AsyncRwLock<DiskState>, new_size: u64) -> DiskContr
     DiskControlResult::Ok
 }
 
+// Converts an error from an internal snapshot operation to the result sent to the host.
+fn snapshot_error_result(e: disk::Error) -> DiskControlResult {
+    match e {
+        disk::Error::UnsupportedOperation => DiskControlResult::Err(SysError::new(libc::ENOTSUP)),
+        e => {
+            error!("Disk snapshot operation failed! {:#}", e);
+            DiskControlResult::Err(SysError::new(libc::EIO))
+        }
+    }
+}
+
+async fn list_snapshots(disk_state: &AsyncRwLock<DiskState>) -> DiskControlResult {
+    let disk_state = disk_state.read_lock().await;
+    match disk_state.disk_image.list_snapshots().await {
+        Ok(snapshots) => DiskControlResult::Snapshots(
+            snapshots
+                .into_iter()
+                .map(|snapshot| DiskSnapshotInfo {
+                    id: snapshot.id,
+                    name: snapshot.name,
+                    date_sec: snapshot.date_sec,
+                    date_nsec: snapshot.date_nsec,
+                    vm_clock_nsec: snapshot.vm_clock_nsec,
+                    vm_state_size: snapshot.vm_state_size,
+                })
+                .collect(),
+        ),
+        Err(e) => snapshot_error_result(e),
+    }
+}
+
+// Creates, applies, or deletes an internal snapshot of the disk image. The guest should still be
+// suspended, since applying a snapshot changes the contents of the disk under its caches.
+async fn update_snapshots(
+    disk_state: &AsyncRwLock<DiskState>,
+    command: DiskControlCommand,
+) -> DiskControlResult {
+    // Hold exclusive access to the state and stop the other workers from doing IO while the
+    // metadata of the image changes. Every request holds a read lock until it completes, so the
+    // requests in flight are done once the locks are taken.
+    let disk_state = disk_state.lock().await;
+    let worker_shared_state = Arc::clone(&disk_state.worker_shared_state);
+    let _worker_shared_state = worker_shared_state.lock().await;
+
+    if disk_state.read_only {
+        error!("Attempted to modify snapshots of read-only block device");
+        return DiskControlResult::Err(SysError::new(libc::EROFS));
+    }
+
+    // Write the data of the completed requests to the image before its clusters are shared with,
+    // or replaced by, a snapshot.
+    if let Err(e) = disk_state.disk_image.fsync().await {
+        return snapshot_error_result(e);
+    }
+
+    info!("Block device snapshot request: {}", command);
+    let result = match &command {
+        DiskControlCommand::CreateSnapshot { name } => {
+            disk_state.disk_image.create_snapshot(name).await
+        }
+        DiskControlCommand::ApplySnapshot { name } => {
+            disk_state.disk_image.apply_snapshot(name).await
+        }
+        DiskControlCommand::DeleteSnapshot { name } => {
+            disk_state.disk_image.delete_snapshot(name).await
+        }
+        _ => unreachable!("not a snapshot update: {}", command),
+    };
+    match result {
+        Ok(()) => match disk_state.disk_image.fsync().await {
+            Ok(()) => DiskControlResult::Ok,
+            Err(e) => snapshot_error_result(e),
+        },
+        Err(e) => snapshot_error_result(e),
+    }
+}
+
+// Replaces the I/O limits of the disk.
+async fn set_throttle(
+    disk_state: &AsyncRwLock<DiskState>,
+    limits: DiskThrottleLimits,
+) -> DiskControlResult {
+    let throttle = Arc::clone(&disk_state.read_lock().await.throttle);
+    let result = throttle.lock().set_limits(limits);
+    match result {
+        Ok(()) => {
+            info!("Set disk I/O limits to {:?}", limits);
+            DiskControlResult::Ok
+        }
+        Err(e) => {
+            error!("Invalid disk I/O limits: {}", e);
+            DiskControlResult::Err(SysError::new(libc::EINVAL))
+        }
+    }
+}
+
 /// Periodically flushes the disk when the given timer fires.
 async fn flush_disk(
     disk_state: Rc<AsyncRwLock<DiskState>>,
@@ -549,6 +711,7 @@ pub async fn run_worker(
                             interrupt,
                             Rc::clone(&flush_timer),
                             Rc::clone(&flush_timer_armed),
+                            ex.clone(),
                             rx,
                         ).remote_handle();
                         let old_stop_fn = queue_handler_stop_fns.insert(index, move || {
@@ -621,6 +784,7 @@ pub struct BlockAsync {
     pub(crate) control_tube: Option<Tube>,
     pub(crate) queue_sizes: Vec<u16>,
     pub(crate) executor_kind: ExecutorKind,
+    pub(crate) throttle: Arc<Mutex<Throttle>>,
     worker_threads: Vec<(
         WorkerThread<(Box<dyn DiskFile>, Option<Tube>)>,
         mpsc::UnboundedSender<WorkerCmd>,
@@ -653,6 +817,10 @@ impl BlockAsync {
         let boot_index = disk_option.bootindex;
         #[cfg(windows)]
         let io_concurrency = disk_option.io_concurrency.get();
+        let throttle = Throttle::new(disk_option.throttle_limits()).map_err(|e| {
+            error!("Invalid disk I/O limits: {}", e);
+            SysError::new(libc::EINVAL)
+        })?;
 
         if block_size % SECTOR_SIZE as u32 != 0 {
             error!(
@@ -702,6 +870,7 @@ impl BlockAsync {
             worker_per_queue: multiple_workers,
             control_tube,
             executor_kind,
+            throttle: Arc::new(Mutex::new(throttle)),
             num_activated_queues: None,
             boot_index,
             #[cfg(windows)]
@@ -1030,6 +1199,7 @@ impl VirtioDevice for BlockAsync {
         let mut worker_threads = vec![];
         for (queues, disk_image) in queues_per_worker.into_iter() {
             let shared_state = Arc::clone(&shared_state);
+            let throttle = Arc::clone(&self.throttle);
             let interrupt = interrupt.clone();
             let control_tube = self.control_tube.take();
             let ex = self.create_executor();
@@ -1059,6 +1229,7 @@ impl VirtioDevice for BlockAsync {
                     sparse,
                     id,
                     worker_shared_state: shared_state,
+                    throttle,
                 }));
 
                 if let Err(err_string) = ex
@@ -1372,6 +1543,7 @@ mod tests {
             worker_shared_state: Arc::new(AsyncRwLock::new(WorkerSharedState {
                 disk_size: Arc::new(AtomicU64::new(disk_size)),
             })),
+            throttle: Arc::new(Mutex::new(Throttle::default())),
         }));
 
         let fut = process_one_request(
@@ -1379,6 +1551,7 @@ mod tests {
             &disk_state,
             &flush_timer,
             &flush_timer_armed,
+            &ex,
         );
 
         ex.run_until(fut)
@@ -1440,6 +1613,7 @@ mod tests {
             worker_shared_state: Arc::new(AsyncRwLock::new(WorkerSharedState {
                 disk_size: Arc::new(AtomicU64::new(disk_size)),
             })),
+            throttle: Arc::new(Mutex::new(Throttle::default())),
         }));
 
         let fut = process_one_request(
@@ -1447,6 +1621,7 @@ mod tests {
             &disk_state,
             &flush_timer,
             &flush_timer_armed,
+            &ex,
         );
 
         ex.run_until(fut)
@@ -1510,6 +1685,7 @@ mod tests {
             worker_shared_state: Arc::new(AsyncRwLock::new(WorkerSharedState {
                 disk_size: Arc::new(AtomicU64::new(disk_size)),
             })),
+            throttle: Arc::new(Mutex::new(Throttle::default())),
         }));
 
         let fut = process_one_request(
@@ -1517,6 +1693,7 @@ mod tests {
             &disk_state,
             &flush_timer,
             &flush_timer_armed,
+            &ex,
         );
 
         ex.run_until(fut)
@@ -11,12 +11,15 @@ use serde::Deserialize;
 use serde::Deserializer;
 use serde::Serialize;
 use serde::Serializer;
+use vm_control::DiskThrottleLimits;
 
 pub mod asynchronous;
 pub(crate) mod sys;
+pub mod throttle;
 
 pub use asynchronous::BlockAsync;
 pub use asynchronous::DiskState;
+pub use throttle::Throttle;
 
 fn block_option_sparse_default() -> bool {
     true
@@ -126,6 +129,47 @@ pub struct DiskOption {
     /// bootable devices. For example, if bootindex=2, then the BIOS will attempt to boot from the
     /// device right after booting from the device with bootindex=1 fails.
     pub bootindex: Option<usize>,
+
+    /// Maximum number of read and write requests per second.
+    #[serde(default)]
+    pub iops: Option<u64>,
+    /// Number of requests that can be sent at once when below the `iops` limit.
+    #[serde(default)]
+    pub iops_burst: Option<u64>,
+    /// Maximum number of bytes read and written per second.
+    #[serde(default)]
+    pub bps: Option<u64>,
+    /// Number of bytes that can be transferred at once when below the `bps` limit.
+    #[serde(default)]
+    pub bps_burst: Option<u64>,
+    /// Maximum number of bytes read per second.
+    #[serde(default)]
+    pub read_bps: Option<u64>,
+    /// Number of bytes that can be read at once when below the `read_bps` limit.
+    #[serde(default)]
+    pub read_bps_burst: Option<u64>,
+    /// Maximum number of bytes written per second.
+    #[serde(default)]
+    pub write_bps: Option<u64>,
+    /// Number of bytes that can be written at once when below the `write_bps` limit.
+    #[serde(default)]
+    pub write_bps_burst: Option<u64>,
+}
+
+impl DiskOption {
+    /// Returns the I/O limits of the disk.
+    pub fn throttle_limits(&self) -> DiskThrottleLimits {
+        DiskThrottleLimits {
+            iops: self.iops,
+            iops_burst: self.iops_burst,
+            bps: self.bps,
+            bps_burst: self.bps_burst,
+            read_bps: self.read_bps,
+            read_bps_burst: self.read_bps_burst,
+            write_bps: self.write_bps,
+            write_bps_burst: self.write_bps_burst,
+        }
+    }
 }
 
 impl Default for DiskOption {
@@ -144,6 +188,14 @@ impl Default for DiskOption {
             async_executor: None,
             packed_queue: false,
             bootindex: None,
+            iops: None,
+            iops_burst: None,
+            bps: None,
+            bps_burst: None,
+            read_bps: None,
+            read_bps_burst: None,
+            write_bps: None,
+            write_bps_burst: None,
         }
     }
 }
@@ -198,6 +250,14 @@ mod tests {
                 async_executor: None,
                 packed_queue: false,
                 bootindex: None,
+                iops: None,
+                iops_burst: None,
+                bps: None,
+                bps_burst: None,
+                read_bps: None,
+                read_bps_burst: None,
+                write_bps: None,
+                write_bps_burst: None,
             }
         );
 
@@ -219,6 +279,14 @@ mod tests {
                 async_executor: None,
                 packed_queue: false,
                 bootindex: Some(5),
+                iops: None,
+                iops_burst: None,
+                bps: None,
+                bps_burst: None,
+                read_bps: None,
+                read_bps_burst: None,
+                write_bps: None,
+                write_bps_burst: None,
             }
         );
 
@@ -240,6 +308,14 @@ mod tests {
                 async_executor: None,
                 packed_queue: false,
                 bootindex: None,
+                iops: None,
+                iops_burst: None,
+                bps: None,
+                bps_burst: None,
+                read_bps: None,
+                read_bps_burst: None,
+                write_bps: None,
+                write_bps_burst: None,
             }
         );
 
@@ -261,6 +337,14 @@ mod tests {
                 async_executor: None,
                 packed_queue: false,
                 bootindex: None,
+                iops: None,
+                iops_burst: None,
+                bps: None,
+                bps_burst: None,
+                read_bps: None,
+                read_bps_burst: None,
+                write_bps: None,
+                write_bps_burst: None,
             }
         );
 
@@ -282,6 +366,14 @@ mod tests {
                 async_executor: None,
                 packed_queue: false,
                 bootindex: None,
+                iops: None,
+                iops_burst: None,
+                bps: None,
+                bps_burst: None,
+                read_bps: None,
+                read_bps_burst: None,
+                write_bps: None,
+                write_bps_burst: None,
             }
         );
 
@@ -303,6 +395,14 @@ mod tests {
                 async_executor: None,
                 packed_queue: false,
                 bootindex: None,
+                iops: None,
+                iops_burst: None,
+                bps: None,
+                bps_burst: None,
+                read_bps: None,
+                read_bps_burst: None,
+                write_bps: None,
+                write_bps_burst: None,
             }
         );
         let params = from_block_arg("/some/path.img,sparse=false").unwrap();
@@ -322,6 +422,14 @@ mod tests {
                 async_executor: None,
                 packed_queue: false,
                 bootindex: None,
+                iops: None,
+                iops_burst: None,
+                bps: None,
+                bps_burst: None,
+                read_bps: None,
+                read_bps_burst: None,
+                write_bps: None,
+                write_bps_burst: None,
             }
         );
 
@@ -343,6 +451,14 @@ mod tests {
                 async_executor: None,
                 packed_queue: false,
                 bootindex: None,
+                iops: None,
+                iops_burst: None,
+                bps: None,
+                bps_burst: None,
+                read_bps: None,
+                read_bps_burst: None,
+                write_bps: None,
+                write_bps_burst: None,
             }
         );
 
@@ -364,6 +480,14 @@ mod tests {
                 async_executor: None,
                 packed_queue: false,
                 bootindex: None,
+                iops: None,
+                iops_burst: None,
+                bps: None,
+                bps_burst: None,
+                read_bps: None,
+                read_bps_burst: None,
+                write_bps: None,
+                write_bps_burst: None,
             }
         );
 
@@ -385,6 +509,14 @@ mod tests {
                 async_executor: None,
                 packed_queue: false,
                 bootindex: None,
+                iops: None,
+                iops_burst: None,
+                bps: None,
+                bps_burst: None,
+                read_bps: None,
+                read_bps_burst: None,
+                write_bps: None,
+                write_bps_burst: None,
             }
         );
 
@@ -406,6 +538,14 @@ mod tests {
                 multiple_workers: false,
                 packed_queue: false,
                 bootindex: None,
+                iops: None,
+                iops_burst: None,
+                bps: None,
+                bps_burst: None,
+                read_bps: None,
+                read_bps_burst: None,
+                write_bps: None,
+                write_bps_burst: None,
             }
         );
 
@@ -428,6 +568,14 @@ mod tests {
                     async_executor: None,
                     packed_queue: false,
                     bootindex: None,
+                    iops: None,
+                    iops_burst: None,
+                    bps: None,
+                    bps_burst: None,
+                    read_bps: None,
+                    read_bps_burst: None,
+                    write_bps: None,
+                    write_bps_burst: None,
                 }
             );
         }
@@ -450,6 +598,14 @@ mod tests {
                 async_executor: None,
                 packed_queue: false,
                 bootindex: None,
+                iops: None,
+                iops_burst: None,
+                bps: None,
+                bps_burst: None,
+                read_bps: None,
+                read_bps_burst: None,
+                write_bps: None,
+                write_bps_burst: None,
             }
         );
         let err = from_block_arg("/some/path.img,id=DISK_ID_IS_WAY_TOO_LONG").unwrap_err();
@@ -484,6 +640,14 @@ mod tests {
                 async_executor: Some(ex_kind),
                 packed_queue: false,
                 bootindex: None,
+                iops: None,
+                iops_burst: None,
+                bps: None,
+                bps_burst: None,
+                read_bps: None,
+                read_bps_burst: None,
+                write_bps: None,
+                write_bps_burst: None,
             }
         );
 
@@ -505,6 +669,47 @@ mod tests {
                 async_executor: None,
                 packed_queue: true,
                 bootindex: None,
+                iops: None,
+                iops_burst: None,
+                bps: None,
+                bps_burst: None,
+                read_bps: None,
+                read_bps_burst: None,
+                write_bps: None,
+                write_bps_burst: None,
+            }
+        );
+
+        // I/O limits
+        let params = from_block_arg(
+            "/path/to/disk.img,iops=100,iops-burst=200,bps=1048576,bps-burst=4194304,\
+            read-bps=524288,read-bps-burst=1048576,write-bps=262144,write-bps-burst=524288",
+        )
+        .unwrap();
+        assert_eq!(
+            params,
+            DiskOption {
+                path: "/path/to/disk.img".into(),
+                read_only: false,
+                root: false,
+                sparse: true,
+                direct: false,
+                block_size: 512,
+                id: None,
+                #[cfg(windows)]
+                io_concurrency: NonZeroU32::new(1).unwrap(),
+                multiple_workers: false,
+                async_executor: None,
+                packed_queue: false,
+                bootindex: None,
+                iops: Some(100),
+                iops_burst: Some(200),
+                bps: Some(1048576),
+                bps_burst: Some(4194304),
+                read_bps: Some(524288),
+                read_bps_burst: Some(1048576),
+                write_bps: Some(262144),
+                write_bps_burst: Some(524288),
             }
         );
 
@@ -530,6 +735,14 @@ mod tests {
                 async_executor: Some(ex_kind),
                 packed_queue: false,
                 bootindex: None,
+                iops: None,
+                iops_burst: None,
+                bps: None,
+                bps_burst: None,
+                read_bps: None,
+                read_bps_burst: None,
+                write_bps: None,
+                write_bps_burst: None,
             }
         );
     }
@@ -551,6 +764,14 @@ mod tests {
             async_executor: None,
             packed_queue: false,
             bootindex: None,
+            iops: None,
+            iops_burst: None,
+            bps: None,
+            bps_burst: None,
+            read_bps: None,
+            read_bps_burst: None,
+            write_bps: None,
+            write_bps_burst: None,
         };
         let json = serde_json::to_string(&original).unwrap();
         let deserialized = serde_json::from_str(&json).unwrap();
@@ -571,6 +792,14 @@ mod tests {
             async_executor: Some(ExecutorKind::default()),
             packed_queue: false,
             bootindex: None,
+            iops: None,
+            iops_burst: None,
+            bps: None,
+            bps_burst: None,
+            read_bps: None,
+            read_bps_burst: None,
+            write_bps: None,
+            write_bps_burst: None,
         };
         let json = serde_json::to_string(&original).unwrap();
         let deserialized = serde_json::from_str(&json).unwrap();
@@ -591,6 +820,14 @@ mod tests {
             async_executor: Some(ExecutorKind::default()),
             packed_queue: false,
             bootindex: None,
+            iops: None,
+            iops_burst: None,
+            bps: None,
+            bps_burst: None,
+            read_bps: None,
+            read_bps_burst: None,
+            write_bps: None,
+            write_bps_burst: None,
         };
         let json = serde_json::to_string(&original).unwrap();
         let deserialized = serde_json::from_str(&json).unwrap();
@@ -0,0 +1,244 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! I/O throttling of block devices.
+//!
+//! Every limit is a token bucket that is refilled at the rate of the limit and holds at most its
+//! burst. A request is admitted as soon as none of the buckets it draws from is empty, and then
+//! takes all the tokens it needs, even if this leaves a bucket in debt. This way requests larger
+//! than the burst still go through, and the guest has to wait for the debt to be repaid before
+//! sending the next one.
+
+use std::time::Duration;
+use std::time::Instant;
+
+use remain::sorted;
+use thiserror::Error as ThisError;
+use vm_control::DiskThrottleLimits;
+
+#[sorted]
+#[derive(ThisError, Debug, PartialEq, Eq)]
+pub enum ThrottleError {
+    #[error("{0}-burst is set without {0}")]
+    BurstWithoutLimit(&'static str),
+    #[error("{0} must not be 0")]
+    ZeroLimit(&'static str),
+}
+
+pub type Result<T> = std::result::Result<T, ThrottleError>;
+
+#[derive(Debug)]
+struct TokenBucket {
+    // Tokens added per second.
+    rate: u64,
+    // Maximum number of tokens.
+    capacity: u64,
+    // Tokens available. Negative if the last request took more than what was available.
+    level: f64,
+}
+
+impl TokenBucket {
+    fn new(name: &'static str, rate: Option<u64>, burst: Option<u64>) -> Result<Option<Self>> {
+        let rate = match (rate, burst) {
+            (None, None) => return Ok(None),
+            (None, Some(_)) => return Err(ThrottleError::BurstWithoutLimit(name)),
+            (Some(0), _) => return Err(ThrottleError::ZeroLimit(name)),
+            (Some(rate), _) => rate,
+        };
+        let capacity = match burst {
+            Some(0) => return Err(ThrottleError::ZeroLimit(name)),
+            Some(burst) => burst,
+            None => rate,
+        };
+        Ok(Some(TokenBucket {
+            rate,
+            capacity,
+            level: capacity as f64,
+        }))
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        self.level =
+            (self.level + elapsed.as_secs_f64() * self.rate as f64).min(self.capacity as f64);
+    }
+
+    // Time until the bucket is out of debt.
+    fn wait_time(&self) -> Duration {
+        if self.level >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.level / self.rate as f64)
+        }
+    }
+}
+
+/// The I/O limits of a disk and how much of them was used recently.
+#[derive(Debug)]
+pub struct Throttle {
+    limits: DiskThrottleLimits,
+    iops: Option<TokenBucket>,
+    bps: Option<TokenBucket>,
+    read_bps: Option<TokenBucket>,
+    write_bps: Option<TokenBucket>,
+    last_refill: Instant,
+}
+
+impl Throttle {
+    /// Creates a `Throttle` enforcing `limits`.
+    pub fn new(limits: DiskThrottleLimits) -> Result<Self> {
+        Ok(Throttle {
+            limits,
+            iops: TokenBucket::new("iops", limits.iops, limits.iops_burst)?,
+            bps: TokenBucket::new("bps", limits.bps, limits.bps_burst)?,
+            read_bps: TokenBucket::new("read-bps", limits.read_bps, limits.read_bps_burst)?,
+            write_bps: TokenBucket::new("write-bps", limits.write_bps, limits.write_bps_burst)?,
+            last_refill: Instant::now(),
+        })
+    }
+
+    /// Returns the limits being enforced.
+    pub fn limits(&self) -> DiskThrottleLimits {
+        self.limits
+    }
+
+    /// Replaces the limits being enforced. The buckets of the new limits start full.
+    pub fn set_limits(&mut self, limits: DiskThrottleLimits) -> Result<()> {
+        *self = Throttle::new(limits)?;
+        Ok(())
+    }
+
+    /// Returns whether no limit is enforced.
+    pub fn is_unlimited(&self) -> bool {
+        self.iops.is_none()
+            && self.bps.is_none()
+            && self.read_bps.is_none()
+            && self.write_bps.is_none()
+    }
+
+    /// Tries to admit a read or write request of `len` bytes at `now`.
+    ///
+    /// Returns `None` if the request can be submitted, or how long to wait before trying again.
+    pub fn admit(&mut self, now: Instant, write: bool, len: u64) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = self.last_refill.max(now);
+
+        let direction_bps = if write {
+            &mut self.write_bps
+        } else {
+            &mut self.read_bps
+        };
+        let buckets = [
+            (self.iops.as_mut(), 1),
+            (self.bps.as_mut(), len),
+            (direction_bps.as_mut(), len),
+        ];
+        let mut buckets: Vec<_> = buckets
+            .into_iter()
+            .filter_map(|(bucket, cost)| Some((bucket?, cost)))
+            .collect();
+
+        let mut wait = Duration::ZERO;
+        for (bucket, _) in buckets.iter_mut() {
+            bucket.refill(elapsed);
+            wait = wait.max(bucket.wait_time());
+        }
+        if !wait.is_zero() {
+            return Some(wait);
+        }
+        for (bucket, cost) in buckets {
+            bucket.level -= cost as f64;
+        }
+        None
+    }
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Throttle::new(DiskThrottleLimits::default()).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(iops: Option<u64>, bps: Option<u64>, bps_burst: Option<u64>) -> DiskThrottleLimits {
+        DiskThrottleLimits {
+            iops,
+            bps,
+            bps_burst,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn unlimited() {
+        let mut throttle = Throttle::default();
+        assert!(throttle.is_unlimited());
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert_eq!(throttle.admit(now, true, 1 << 20), None);
+        }
+    }
+
+    #[test]
+    fn invalid_limits() {
+        assert_eq!(
+            Throttle::new(limits(None, None, Some(10))).unwrap_err(),
+            ThrottleError::BurstWithoutLimit("bps")
+        );
+        assert_eq!(
+            Throttle::new(limits(Some(0), None, None)).unwrap_err(),
+            ThrottleError::ZeroLimit("iops")
+        );
+    }
+
+    #[test]
+    fn iops_limit() {
+        let mut throttle = Throttle::new(limits(Some(10), None, None)).unwrap();
+        let now = throttle.last_refill;
+        for _ in 0..10 {
+            assert_eq!(throttle.admit(now, false, 512), None);
+        }
+        // The bucket is empty: the next request has to wait for one token.
+        assert_eq!(
+            throttle.admit(now, false, 512),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(
+            throttle.admit(now + Duration::from_millis(100), false, 512),
+            None
+        );
+    }
+
+    #[test]
+    fn request_larger_than_burst() {
+        let mut throttle = Throttle::new(limits(None, Some(1000), Some(500))).unwrap();
+        let now = throttle.last_refill;
+        // Admitted because the bucket isn't empty, which leaves it 1500 bytes in debt.
+        assert_eq!(throttle.admit(now, true, 2000), None);
+        assert_eq!(
+            throttle.admit(now, true, 1),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            throttle.admit(now + Duration::from_millis(1500), true, 1),
+            None
+        );
+    }
+
+    #[test]
+    fn read_write_limits() {
+        let mut throttle = Throttle::new(DiskThrottleLimits {
+            write_bps: Some(100),
+            ..Default::default()
+        })
+        .unwrap();
+        let now = throttle.last_refill;
+        assert_eq!(throttle.admit(now, true, 200), None);
+        assert!(throttle.admit(now, true, 1).is_some());
+        // Reads are not limited.
+        assert_eq!(throttle.admit(now, false, 1 << 20), None);
+    }
+}
@@ -7,6 +7,7 @@ use std::io;
 use std::sync::Arc;
 
 use anyhow::anyhow;
+use anyhow::Context;
 use base::error;
 use base::warn;
 use base::AsRawDescriptor;
@@ -52,6 +53,7 @@ pub use config::CachePolicy;
 pub use config::Config;
 use fuse::Server;
 use passthrough::PassthroughFs;
+use passthrough::PassthroughFsSnapshot;
 pub use worker::process_fs_queue;
 use worker::Worker;
 
@@ -114,13 +116,15 @@ pub type Result<T> = ::std::result::Result<T, Error>;
 pub struct Fs {
     cfg: virtio_fs_config,
     tag: String,
-    fs: Option<PassthroughFs>,
+    // The server outlives the workers, so that the file system state is kept while the device is
+    // asleep.
+    server: Arc<Server<PassthroughFs>>,
     queue_sizes: Box<[u16]>,
     avail_features: u64,
     acked_features: u64,
     pci_bar: Option<Alloc>,
-    tube: Option<Tube>,
-    workers: Vec<WorkerThread<Result<()>>>,
+    tube: Arc<Mutex<Tube>>,
+    workers: Vec<WorkerThread<Result<Queue>>>,
 }
 
 impl Fs {
@@ -151,12 +155,12 @@ impl Fs {
         Ok(Fs {
             cfg,
             tag: tag.to_string(),
-            fs: Some(fs),
+            server: Arc::new(Server::new(fs)),
             queue_sizes: vec![QUEUE_SIZE; num_queues].into_boxed_slice(),
             avail_features: base_features,
             acked_features: 0,
             pci_bar: None,
-            tube: Some(tube),
+            tube: Arc::new(Mutex::new(tube)),
             workers: Vec::with_capacity(num_workers + 1),
         })
     }
@@ -164,14 +168,8 @@ impl Fs {
 
 impl VirtioDevice for Fs {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
-        let mut fds = self
-            .fs
-            .as_ref()
-            .map(PassthroughFs::keep_rds)
-            .unwrap_or_default();
-        if let Some(rd) = self.tube.as_ref().map(|s| s.as_raw_descriptor()) {
-            fds.push(rd);
-        }
+        let mut fds = self.server.fs().keep_rds();
+        fds.push(self.tube.lock().as_raw_descriptor());
 
         fds
     }
@@ -218,11 +216,7 @@ impl VirtioDevice for Fs {
             ));
         }
 
-        let fs = self.fs.take().expect("missing file system implementation");
-        let use_dax = fs.cfg().use_dax;
-
-        let server = Arc::new(Server::new(fs));
-        let socket = self.tube.take().expect("missing mapping socket");
+        let use_dax = self.server.fs().cfg().use_dax;
         let mut slot = 0;
 
         // Set up shared memory for DAX.
@@ -232,6 +226,7 @@ impl VirtioDevice for Fs {
             let request = FsMappingRequest::AllocateSharedMemoryRegion(
                 self.pci_bar.as_ref().cloned().expect("No pci_bar"),
             );
+            let socket = self.tube.lock();
             socket
                 .send(&request)
                 .expect("failed to send allocation message");
@@ -245,20 +240,20 @@ impl VirtioDevice for Fs {
             };
         }
 
-        let socket = Arc::new(Mutex::new(socket));
         let mut watch_resample_event = true;
 
         self.workers = queues
             .into_iter()
             .map(|(idx, queue)| {
-                let server = server.clone();
+                let server = self.server.clone();
                 let irq = interrupt.clone();
-                let socket = Arc::clone(&socket);
+                let socket = Arc::clone(&self.tube);
 
                 let worker =
                     WorkerThread::start(format!("v_fs:{}:{}", self.tag, idx), move |kill_evt| {
                         let mut worker = Worker::new(queue, server, irq, socket, slot);
-                        worker.run(kill_evt, watch_resample_event)
+                        worker.run(kill_evt, watch_resample_event)?;
+                        Ok(worker.into_queue())
                     });
 
                 if watch_resample_event {
@@ -272,7 +267,7 @@ impl VirtioDevice for Fs {
     }
 
     fn get_device_bars(&mut self, address: PciAddress) -> Vec<PciBarConfiguration> {
-        if self.fs.as_ref().map_or(false, |fs| !fs.cfg().use_dax) {
+        if !self.server.fs().cfg().use_dax {
             return vec![];
         }
 
@@ -292,7 +287,7 @@ impl VirtioDevice for Fs {
     }
 
     fn get_device_caps(&self) -> Vec<Box<dyn PciCapability>> {
-        if self.fs.as_ref().map_or(false, |fs| !fs.cfg().use_dax) {
+        if !self.server.fs().cfg().use_dax {
             return vec![];
         }
 
@@ -304,4 +299,50 @@ impl VirtioDevice for Fs {
             VIRTIO_FS_SHMCAP_ID_CACHE as u8,
         ))]
     }
+
+    fn virtio_sleep(&mut self) -> anyhow::Result<Option<BTreeMap<usize, Queue>>> {
+        // The mappings of the DAX window can't be recreated.
+        anyhow::ensure!(
+            !self.server.fs().cfg().use_dax,
+            "virtio-fs can't sleep with DAX enabled"
+        );
+        if self.workers.is_empty() {
+            return Ok(None);
+        }
+        let queues = self
+            .workers
+            .drain(..)
+            .map(WorkerThread::stop)
+            .collect::<Result<Vec<_>>>()
+            .context("virtio-fs worker failed")?;
+        Ok(Some(BTreeMap::from_iter(queues.into_iter().enumerate())))
+    }
+
+    fn virtio_wake(
+        &mut self,
+        queues_state: Option<(GuestMemory, Interrupt, BTreeMap<usize, Queue>)>,
+    ) -> anyhow::Result<()> {
+        if let Some((mem, interrupt, queues)) = queues_state {
+            self.activate(mem, interrupt, queues)?;
+        }
+        Ok(())
+    }
+
+    fn virtio_snapshot(&mut self) -> anyhow::Result<serde_json::Value> {
+        let snapshot = self
+            .server
+            .fs()
+            .snapshot()
+            .context("failed to snapshot the shared directory")?;
+        serde_json::to_value(snapshot).context("failed to serialize virtio-fs snapshot")
+    }
+
+    fn virtio_restore(&mut self, data: serde_json::Value) -> anyhow::Result<()> {
+        let snapshot: PassthroughFsSnapshot =
+            serde_json::from_value(data).context("failed to deserialize virtio-fs snapshot")?;
+        self.server
+            .fs()
+            .restore(snapshot)
+            .context("failed to restore the shared directory")
+    }
 }
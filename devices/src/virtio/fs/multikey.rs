@@ -107,6 +107,11 @@ where
         self.alt.clear();
         self.main.clear()
     }
+
+    /// Returns an iterator over the values of the map, sorted by main key.
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.main.values().map(|(_, v)| v)
+    }
 }
 
 #[cfg(test)]
@@ -266,4 +271,15 @@ mod test {
         assert!(m.get(&k1).is_none());
         assert!(m.get_alt(&k2).is_none());
     }
+    #[test]
+    fn values() {
+        let mut m = MultikeyBTreeMap::<u64, i64, u32>::new();
+
+        assert!(m.insert(2, -2, 20).is_none());
+        assert!(m.insert(1, -1, 10).is_none());
+        assert!(m.insert(3, -3, 30).is_none());
+        assert!(m.remove(&2).is_some());
+
+        assert_eq!(m.values().copied().collect::<Vec<_>>(), [10, 30]);
+    }
 }
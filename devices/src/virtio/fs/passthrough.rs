@@ -9,6 +9,7 @@ use std::collections::btree_map;
 use std::collections::BTreeMap;
 use std::ffi::CStr;
 use std::ffi::CString;
+use std::ffi::OsString;
 use std::fs::File;
 use std::io;
 use std::mem;
@@ -16,6 +17,10 @@ use std::mem::size_of;
 use std::mem::MaybeUninit;
 use std::os::raw::c_int;
 use std::os::raw::c_long;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
+use std::path::Path;
+use std::path::PathBuf;
 use std::ptr;
 use std::ptr::addr_of;
 use std::ptr::addr_of_mut;
@@ -59,6 +64,8 @@ use fuse::sys::WRITE_KILL_PRIV;
 use fuse::Mapper;
 #[cfg(feature = "arc_quota")]
 use protobuf::Message;
+use serde::Deserialize;
+use serde::Serialize;
 use sync::Mutex;
 #[cfg(feature = "arc_quota")]
 use system_api::client::OrgChromiumSpaced;
@@ -260,6 +267,36 @@ impl AsRawDescriptor for HandleData {
     }
 }
 
+/// The state of a `PassthroughFs` that is saved in snapshots: the inodes and handles known to the
+/// guest, and the options negotiated with it by `init`.
+#[derive(Serialize, Deserialize)]
+pub struct PassthroughFsSnapshot {
+    inodes: Vec<InodeSnapshot>,
+    next_inode: u64,
+    handles: Vec<HandleSnapshot>,
+    next_handle: u64,
+    writeback: bool,
+    zero_message_open: bool,
+    zero_message_opendir: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct InodeSnapshot {
+    inode: Inode,
+    // The path of the file on the host, which is used to re-open it.
+    path: PathBuf,
+    open_flags: libc::c_int,
+    refcount: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HandleSnapshot {
+    handle: Handle,
+    inode: Inode,
+    // The file status flags of the handle, as returned by F_GETFL.
+    flags: libc::c_int,
+}
+
 macro_rules! scoped_cred {
     ($name:ident, $ty:ty, $syscall_nr:expr) => {
         #[derive(Debug)]
@@ -512,6 +549,25 @@ fn stat<F: AsRawDescriptor + ?Sized>(f: &F) -> io::Result<libc::stat64> {
     Ok(unsafe { st.assume_init() })
 }
 
+// Opens the file at `path` on the host, reporting the files that don't exist anymore.
+fn reopen(path: &Path, flags: libc::c_int) -> io::Result<File> {
+    let pathname = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    // SAFETY: this doesn't modify any memory and we check the return value.
+    match syscall!(unsafe { libc::openat64(libc::AT_FDCWD, pathname.as_ptr(), flags) }) {
+        // SAFETY: safe because we just opened this descriptor.
+        Ok(raw_descriptor) => Ok(unsafe { File::from_raw_descriptor(raw_descriptor) }),
+        Err(e) if e.errno() == libc::ENOENT => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has disappeared from the host", path.display()),
+        )),
+        Err(e) => Err(io::Error::new(
+            io::Error::from(e).kind(),
+            format!("failed to re-open {}: {}", path.display(), e),
+        )),
+    }
+}
+
 fn statat<D: AsRawDescriptor>(dir: &D, name: &CStr) -> io::Result<libc::stat64> {
     let mut st = MaybeUninit::<libc::stat64>::zeroed();
 
@@ -857,6 +913,138 @@ impl PassthroughFs {
         Ok(unsafe { File::from_raw_descriptor(raw_descriptor) })
     }
 
+    // Returns the path on the host of the file opened as `fd`.
+    fn host_path(&self, fd: RawDescriptor) -> io::Result<PathBuf> {
+        let pathname = CString::new(format!("self/fd/{}", fd))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        // SAFETY: this only writes to `buf`, whose length is passed along, and we check the return
+        // value.
+        let len = syscall!(unsafe {
+            libc::readlinkat(
+                self.proc.as_raw_descriptor(),
+                pathname.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        })?;
+        buf.truncate(len as usize);
+        Ok(PathBuf::from(OsString::from_vec(buf)))
+    }
+
+    /// Saves the inodes and handles known to the guest, so that another `PassthroughFs` serving
+    /// the same directory can take over from this one with `restore`. Fails if one of the files
+    /// was removed from the host while the guest still uses it, since it couldn't be re-opened.
+    ///
+    /// No request must be processed while the snapshot is taken.
+    pub fn snapshot(&self) -> io::Result<PassthroughFsSnapshot> {
+        let inodes = self.inodes.lock();
+        let mut inode_snapshots = Vec::new();
+        for data in inodes.values() {
+            let file = data.file.lock();
+            let (file, open_flags) = &*file;
+            if stat(file)?.st_nlink == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "{} was removed from the host but is still used by the guest",
+                        data.path
+                    ),
+                ));
+            }
+            inode_snapshots.push(InodeSnapshot {
+                inode: data.inode,
+                path: self.host_path(file.as_raw_descriptor())?,
+                open_flags: *open_flags,
+                refcount: data.refcount.load(Ordering::Acquire),
+            });
+        }
+
+        let handles = self.handles.lock();
+        let mut handle_snapshots = Vec::new();
+        for (&handle, data) in handles.iter() {
+            // SAFETY: this doesn't modify any memory and we check the return value.
+            let flags = syscall!(unsafe {
+                libc::fcntl(data.file.lock().as_raw_descriptor(), libc::F_GETFL)
+            })?;
+            handle_snapshots.push(HandleSnapshot {
+                handle,
+                inode: data.inode,
+                flags,
+            });
+        }
+
+        Ok(PassthroughFsSnapshot {
+            inodes: inode_snapshots,
+            next_inode: self.next_inode.load(Ordering::Relaxed),
+            handles: handle_snapshots,
+            next_handle: self.next_handle.load(Ordering::Relaxed),
+            writeback: self.writeback.load(Ordering::Relaxed),
+            zero_message_open: self.zero_message_open.load(Ordering::Relaxed),
+            zero_message_opendir: self.zero_message_opendir.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Restores the inodes and handles saved by `snapshot`, re-opening their files by path. Fails
+    /// if one of the files has disappeared from the host since the snapshot was taken.
+    ///
+    /// No request must be processed while the snapshot is restored.
+    pub fn restore(&self, snapshot: PassthroughFsSnapshot) -> io::Result<()> {
+        let mut inodes = self.inodes.lock();
+        let mut handles = self.handles.lock();
+        handles.clear();
+        inodes.clear();
+
+        for inode in snapshot.inodes {
+            let file = reopen(&inode.path, inode.open_flags | libc::O_CLOEXEC)?;
+            let st = stat(&file)?;
+            // The root inode has an empty path, like the one created by `init`.
+            let path = if inode.inode == ROOT_ID {
+                "".to_string()
+            } else {
+                inode.path.to_string_lossy().into_owned()
+            };
+            inodes.insert(
+                inode.inode,
+                InodeAltKey {
+                    ino: st.st_ino,
+                    dev: st.st_dev,
+                },
+                Arc::new(InodeData {
+                    inode: inode.inode,
+                    file: Mutex::new((file, inode.open_flags)),
+                    refcount: AtomicU64::new(inode.refcount),
+                    filetype: st.st_mode.into(),
+                    path,
+                }),
+            );
+        }
+
+        for handle in snapshot.handles {
+            let inode_data = inodes.get(&handle.inode).ok_or_else(ebadf)?;
+            // Re-open the file through its inode, so that it's the file that was just checked.
+            let file = self.open_fd(inode_data.as_raw_descriptor(), handle.flags)?;
+            handles.insert(
+                handle.handle,
+                Arc::new(HandleData {
+                    inode: handle.inode,
+                    file: Mutex::new(file),
+                }),
+            );
+        }
+
+        self.next_inode
+            .store(snapshot.next_inode, Ordering::Relaxed);
+        self.next_handle
+            .store(snapshot.next_handle, Ordering::Relaxed);
+        self.writeback.store(snapshot.writeback, Ordering::Relaxed);
+        self.zero_message_open
+            .store(snapshot.zero_message_open, Ordering::Relaxed);
+        self.zero_message_opendir
+            .store(snapshot.zero_message_opendir, Ordering::Relaxed);
+        Ok(())
+    }
+
     /// Modifies the provided open flags based on the writeback caching configuration.
     /// Return the updated open flags.
     fn update_open_flags(&self, mut flags: i32) -> i32 {
@@ -3348,6 +3536,55 @@ mod tests {
         assert_eq!(&actual[..], b"security.sehash");
     }
 
+    #[test]
+    fn snapshot_restore() {
+        use std::io::Write;
+
+        let lock = NamedLock::create(UNITTEST_LOCK_NAME).expect("create named lock");
+        let _guard = lock.lock().expect("acquire named lock");
+
+        let temp_dir = TempDir::new().unwrap();
+        create_test_data(&temp_dir, &["dir"], &["a.txt"]);
+
+        let fs = PassthroughFs::new("tag", Default::default()).unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        let a_inode = lookup(&fs, &temp_dir.path().join("a.txt")).unwrap();
+        let (a_handle, _) = fs
+            .open(get_context(), a_inode, libc::O_RDWR as u32)
+            .unwrap();
+        let a_handle = a_handle.unwrap();
+        // The file is saved with its current path on the host.
+        let c_path = temp_dir.path().join("c.txt");
+        std::fs::rename(temp_dir.path().join("a.txt"), &c_path).unwrap();
+        let snapshot = serde_json::to_value(fs.snapshot().unwrap()).unwrap();
+
+        let restored = PassthroughFs::new("tag", Default::default()).unwrap();
+        restored
+            .restore(serde_json::from_value(snapshot.clone()).unwrap())
+            .unwrap();
+        assert_eq!(lookup(&restored, &c_path).unwrap(), a_inode);
+        assert!(lookup(&restored, &temp_dir.path().join("dir")).unwrap() > a_inode);
+        restored
+            .find_handle(a_handle, a_inode)
+            .unwrap()
+            .file
+            .lock()
+            .write_all(b"restored")
+            .unwrap();
+        assert_eq!(std::fs::read(&c_path).unwrap(), b"restored");
+
+        std::fs::remove_file(&c_path).unwrap();
+        let err = fs.snapshot().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("was removed from the host"));
+        let restored = PassthroughFs::new("tag", Default::default()).unwrap();
+        let err = restored
+            .restore(serde_json::from_value(snapshot).unwrap())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("has disappeared"));
+    }
+
     #[test]
     fn lookup_files() {
         // Since PassthroughFs may executes process-wide operations such as `fchdir`, acquire
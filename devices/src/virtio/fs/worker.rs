@@ -182,6 +182,11 @@ impl<F: FileSystem + Sync> Worker<F> {
         }
     }
 
+    /// Returns the queue of the worker once it has stopped running.
+    pub fn into_queue(self) -> Queue {
+        self.queue
+    }
+
     pub fn run(&mut self, kill_evt: Event, watch_resample_event: bool) -> Result<()> {
         // We need to set the no setuid fixup secure bit so that we don't drop capabilities when
         // changing the thread uid/gid. Without this, creating new entries can fail in some corner
@@ -0,0 +1,299 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Screenshots of the displays of the GPU device, written as PNG images.
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::ffi::CString;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::fs::File;
+use std::io;
+use std::io::Write;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::num::NonZeroU32;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::os::unix::io::AsRawFd;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::os::unix::io::FromRawFd;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::sync::atomic::AtomicU64;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::sync::atomic::Ordering;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::sync::mpsc;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::sync::mpsc::SyncSender;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::sync::mpsc::TrySendError;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::sync::Arc;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::thread;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::thread::JoinHandle;
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use base::error;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use base::warn;
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+const PNG_BIT_DEPTH: u8 = 8;
+const PNG_COLOR_TYPE_RGB: u8 = 2;
+const PNG_FILTER_NONE: u8 = 0;
+
+/// The pixels read back from the resource of a scanout.
+pub struct Screenshot {
+    pub width: u32,
+    pub height: u32,
+    /// Tightly packed rows of 32-bit XRGB pixels, stored little-endian like `DRM_FORMAT_XRGB8888`.
+    pub pixels: Vec<u8>,
+}
+
+impl Screenshot {
+    /// Writes the screenshot to `writer` as an 8-bit RGB PNG image.
+    pub fn write_png<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&self.width.to_be_bytes());
+        header.extend_from_slice(&self.height.to_be_bytes());
+        // Bit depth, color type, then the default compression, filter and interlace methods.
+        header.extend_from_slice(&[PNG_BIT_DEPTH, PNG_COLOR_TYPE_RGB, 0, 0, 0]);
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+        let mut row = Vec::with_capacity(1 + self.width as usize * 3);
+        for pixels in self.pixels.chunks_exact(self.width as usize * 4) {
+            row.clear();
+            row.push(PNG_FILTER_NONE);
+            for pixel in pixels.chunks_exact(4) {
+                row.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            }
+            encoder.write_all(&row)?;
+        }
+        let data = encoder.finish()?;
+
+        writer.write_all(&PNG_SIGNATURE)?;
+        write_png_chunk(&mut writer, b"IHDR", &header)?;
+        write_png_chunk(&mut writer, b"IDAT", &data)?;
+        write_png_chunk(&mut writer, b"IEND", &[])?;
+        writer.flush()
+    }
+}
+
+fn write_png_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    writer.write_all(&crc.finalize().to_be_bytes())
+}
+
+/// Most frames waiting to be written by the capture thread. Frames flushed while this many are
+/// pending are dropped rather than stalling the display.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+const MAX_PENDING_FRAMES: usize = 4;
+
+/// Records every `interval`th frame flushed to a display as PNG images in a directory.
+///
+/// The images are named after the index of the flush that produced them, counting from the flush
+/// following the start of the capture, so that frames dropped by the interval show up as gaps.
+/// The images are encoded and written on a separate thread, so that the GPU worker only pays for
+/// reading back the pixels.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub struct FrameCapture {
+    interval: u64,
+    flushes: u64,
+    sender: Option<SyncSender<(u64, Screenshot)>>,
+    thread: Option<JoinHandle<()>>,
+    frames: Arc<AtomicU64>,
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl FrameCapture {
+    pub fn new(directory: File, interval: NonZeroU32) -> io::Result<FrameCapture> {
+        let (sender, receiver) = mpsc::sync_channel::<(u64, Screenshot)>(MAX_PENDING_FRAMES);
+        let frames = Arc::new(AtomicU64::new(0));
+        let thread_frames = frames.clone();
+        let thread = thread::Builder::new()
+            .name("v_gpu_capture".to_string())
+            .spawn(move || {
+                for (index, screenshot) in receiver {
+                    match write_frame(&directory, index, &screenshot) {
+                        Ok(()) => {
+                            thread_frames.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(e) => error!("failed to write frame {} of display: {}", index, e),
+                    }
+                }
+            })?;
+        Ok(FrameCapture {
+            interval: interval.get().into(),
+            flushes: 0,
+            sender: Some(sender),
+            thread: Some(thread),
+            frames,
+        })
+    }
+
+    /// Counts a flush of the display, and returns its index if its frame should be recorded.
+    pub fn next_frame(&mut self) -> Option<u64> {
+        let index = self.flushes;
+        self.flushes += 1;
+        if index % self.interval == 0 {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// Queues `screenshot`, the frame of the flush `index`, to be written to the capture directory.
+    ///
+    /// The frame is dropped if the capture thread is still busy with earlier frames.
+    pub fn queue_frame(&mut self, index: u64, screenshot: Screenshot) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        match sender.try_send((index, screenshot)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!(
+                    "dropping frame {} of display: capture is falling behind",
+                    index
+                )
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                error!("dropping frame {} of display: capture thread exited", index)
+            }
+        }
+    }
+
+    /// Waits for the queued frames to be written, and returns the number of frames written to the
+    /// capture directory.
+    pub fn finish(mut self) -> u64 {
+        self.join();
+        self.frames.load(Ordering::Relaxed)
+    }
+
+    fn join(&mut self) {
+        // Closing the channel ends the capture thread once it has written the pending frames.
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("frame capture thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl Drop for FrameCapture {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+/// Writes `screenshot`, the frame of the flush `index`, to the capture `directory`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn write_frame(directory: &File, index: u64, screenshot: &Screenshot) -> io::Result<()> {
+    let name = CString::new(format!("frame-{:08}.png", index)).unwrap();
+    // SAFETY:
+    // Safe because `name` is a valid C string, and the result is checked.
+    let fd = unsafe {
+        libc::openat(
+            directory.as_raw_fd(),
+            name.as_ptr(),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC,
+            0o644 as libc::c_uint,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY:
+    // Safe because `fd` was just opened and nothing else owns it.
+    let file = unsafe { File::from_raw_fd(fd) };
+    screenshot.write_png(io::BufWriter::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    use super::*;
+
+    fn screenshot() -> Screenshot {
+        Screenshot {
+            width: 2,
+            height: 2,
+            pixels: vec![
+                0x00, 0x00, 0xff, 0x00, 0x00, 0xff, 0x00, 0x00, //
+                0xff, 0x00, 0x00, 0x00, 0x10, 0x20, 0x30, 0xff, //
+            ],
+        }
+    }
+
+    // Splits a PNG image into its chunks, checking their CRCs.
+    fn png_chunks(mut png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(png[..8], PNG_SIGNATURE);
+        png = &png[8..];
+        let mut chunks = Vec::new();
+        while !png.is_empty() {
+            let len = u32::from_be_bytes(png[..4].try_into().unwrap()) as usize;
+            let kind: [u8; 4] = png[4..8].try_into().unwrap();
+            let data = png[8..8 + len].to_vec();
+            let crc = u32::from_be_bytes(png[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc, crc32fast::hash(&png[4..8 + len]));
+            chunks.push((kind, data));
+            png = &png[12 + len..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn png() {
+        let mut png = Vec::new();
+        screenshot().write_png(&mut png).unwrap();
+
+        let chunks = png_chunks(&png);
+        let kinds: Vec<&[u8; 4]> = chunks.iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 2, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+
+        let mut rows = Vec::new();
+        ZlibDecoder::new(&chunks[1].1[..])
+            .read_to_end(&mut rows)
+            .unwrap();
+        assert_eq!(
+            rows,
+            [
+                0, 0xff, 0x00, 0x00, 0x00, 0xff, 0x00, //
+                0, 0x00, 0x00, 0xff, 0x30, 0x20, 0x10, //
+            ]
+        );
+        assert!(chunks[2].1.is_empty());
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn frame_capture() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut capture =
+            FrameCapture::new(File::open(dir.path()).unwrap(), NonZeroU32::new(3).unwrap())
+                .unwrap();
+
+        let frames: Vec<Option<u64>> = (0..7).map(|_| capture.next_frame()).collect();
+        assert_eq!(frames, [Some(0), None, None, Some(3), None, None, Some(6)]);
+
+        capture.queue_frame(3, screenshot());
+        assert_eq!(capture.finish(), 1);
+        let png = std::fs::read(dir.path().join("frame-00000003.png")).unwrap();
+        assert_eq!(png[..8], PNG_SIGNATURE);
+    }
+}
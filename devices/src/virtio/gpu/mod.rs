@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+mod capture;
 mod edid;
 mod parameters;
 mod protocol;
@@ -637,7 +638,7 @@ impl Frontend {
                 let resource_id = info.resource_id.to_native();
                 let virtio_gpu_format = info.format.to_native();
                 let width = info.width.to_native();
-                let height = info.width.to_native();
+                let height = info.height.to_native();
                 let mut strides: [u32; 4] = [0; 4];
                 let mut offsets: [u32; 4] = [0; 4];
 
@@ -1106,6 +1107,9 @@ pub enum DisplayBackend {
     #[cfg(any(target_os = "android", target_os = "linux"))]
     /// Open a connection to the X server at the given display if given.
     X(Option<String>),
+    #[cfg(all(feature = "vnc", any(target_os = "android", target_os = "linux")))]
+    /// Serve the displays to VNC clients, each on its own listening socket.
+    Vnc(Arc<Vec<VncListener>>),
     /// Emulate a display without actually displaying it.
     Stub,
     #[cfg(windows)]
@@ -1124,6 +1128,8 @@ impl DisplayBackend {
             DisplayBackend::Wayland(path) => GpuDisplay::open_wayland(path.as_ref()),
             #[cfg(any(target_os = "android", target_os = "linux"))]
             DisplayBackend::X(display) => GpuDisplay::open_x(display.as_deref()),
+            #[cfg(all(feature = "vnc", any(target_os = "android", target_os = "linux")))]
+            DisplayBackend::Vnc(listeners) => GpuDisplay::open_vnc(listeners),
             DisplayBackend::Stub => GpuDisplay::open_stub(),
             #[cfg(windows)]
             DisplayBackend::WinApi(display_properties) => match wndproc_thread.take() {
@@ -1652,6 +1658,13 @@ impl VirtioDevice for Gpu {
             keep_rds.push(event_device.as_raw_descriptor());
         }
 
+        #[cfg(all(feature = "vnc", any(target_os = "android", target_os = "linux")))]
+        for display_backend in &self.display_backends {
+            if let DisplayBackend::Vnc(listeners) = display_backend {
+                keep_rds.extend(listeners.iter().map(|l| l.as_raw_descriptor()));
+            }
+        }
+
         keep_rds
     }
 
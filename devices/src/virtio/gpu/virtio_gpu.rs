@@ -5,6 +5,8 @@
 use std::cell::RefCell;
 use std::collections::BTreeMap as Map;
 use std::collections::BTreeSet as Set;
+use std::fs::File;
+use std::io::BufWriter;
 use std::io::IoSliceMut;
 use std::num::NonZeroU32;
 use std::rc::Rc;
@@ -13,15 +15,18 @@ use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
+use anyhow::bail;
 use anyhow::Context;
 use base::error;
 use base::FromRawDescriptor;
 use base::IntoRawDescriptor;
+use base::MemoryMappingBuilder;
 use base::Protection;
 use base::SafeDescriptor;
 use base::VolatileSlice;
 use gpu_display::*;
 use libc::c_void;
+use rutabaga_gfx::DrmFormat;
 use rutabaga_gfx::ResourceCreate3D;
 use rutabaga_gfx::ResourceCreateBlob;
 use rutabaga_gfx::Rutabaga;
@@ -41,6 +46,7 @@ use rutabaga_gfx::RUTABAGA_MAP_ACCESS_WRITE;
 use rutabaga_gfx::RUTABAGA_MAP_CACHE_MASK;
 use rutabaga_gfx::RUTABAGA_MEM_HANDLE_TYPE_DMABUF;
 use rutabaga_gfx::RUTABAGA_MEM_HANDLE_TYPE_OPAQUE_FD;
+use rutabaga_gfx::RUTABAGA_MEM_HANDLE_TYPE_SHM;
 use serde::Deserialize;
 use serde::Serialize;
 use sync::Mutex;
@@ -53,12 +59,23 @@ use vm_memory::udmabuf::UdmabufDriverTrait;
 use vm_memory::GuestAddress;
 use vm_memory::GuestMemory;
 
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use super::capture::FrameCapture;
+use super::capture::Screenshot;
 use super::protocol::GpuResponse;
 use super::protocol::GpuResponse::*;
 use super::protocol::GpuResponsePlaneInfo;
 use super::protocol::VirtioGpuResult;
 use super::protocol::VIRTIO_GPU_BLOB_FLAG_CREATE_GUEST_HANDLE;
 use super::protocol::VIRTIO_GPU_BLOB_MEM_HOST3D;
+use super::protocol::VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM;
 use super::VirtioScanoutBlobData;
 use crate::virtio::gpu::edid::DisplayInfo;
 use crate::virtio::gpu::edid::EdidBytes;
@@ -82,10 +99,35 @@ fn to_safe_descriptor(r: RutabagaDescriptor) -> SafeDescriptor {
     unsafe { SafeDescriptor::from_raw_descriptor(r.into_raw_descriptor()) }
 }
 
+// Returns the offsets of the blue, green and red bytes in the 4-byte pixels of the virtio-gpu
+// `format`, if it is one screenshots support.
+fn pixel_layout(format: u32) -> Option<[usize; 3]> {
+    match format {
+        VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM | VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM => Some([0, 1, 2]),
+        VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM | VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM => Some([3, 2, 1]),
+        VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM | VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM => Some([2, 1, 0]),
+        VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM | VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM => Some([1, 2, 3]),
+        _ => None,
+    }
+}
+
+// Like `pixel_layout`, for the DRM format of a blob scanout.
+fn drm_pixel_layout(format: DrmFormat) -> Option<[usize; 3]> {
+    if format == DrmFormat::new(b'X', b'R', b'2', b'4')
+        || format == DrmFormat::new(b'A', b'R', b'2', b'4')
+    {
+        Some([0, 1, 2])
+    } else {
+        None
+    }
+}
+
 struct VirtioGpuResource {
     resource_id: u32,
     width: u32,
     height: u32,
+    // The virtio-gpu format of the resource, 0 for blobs, whose format comes with their scanout.
+    format: u32,
     size: u64,
     shmem_offset: Option<u64>,
     scanout_data: Option<VirtioScanoutBlobData>,
@@ -102,19 +144,27 @@ struct VirtioGpuResourceSnapshot {
     resource_id: u32,
     width: u32,
     height: u32,
+    format: u32,
     size: u64,
 
     backing_iovecs: Option<Vec<(GuestAddress, usize)>>,
 }
 
 impl VirtioGpuResource {
-    /// Creates a new VirtioGpuResource with the given metadata.  Width and height are used by the
-    /// display, while size is useful for hypervisor mapping.
-    pub fn new(resource_id: u32, width: u32, height: u32, size: u64) -> VirtioGpuResource {
+    /// Creates a new VirtioGpuResource with the given metadata.  Width, height and format are used
+    /// by the display and screenshots, while size is useful for hypervisor mapping.
+    pub fn new(
+        resource_id: u32,
+        width: u32,
+        height: u32,
+        format: u32,
+        size: u64,
+    ) -> VirtioGpuResource {
         VirtioGpuResource {
             resource_id,
             width,
             height,
+            format,
             size,
             shmem_offset: None,
             scanout_data: None,
@@ -134,13 +184,15 @@ impl VirtioGpuResource {
             resource_id: self.resource_id,
             width: self.width,
             height: self.height,
+            format: self.format,
             size: self.size,
             backing_iovecs: self.backing_iovecs.clone(),
         }
     }
 
     fn restore(s: VirtioGpuResourceSnapshot) -> Self {
-        let mut resource = VirtioGpuResource::new(s.resource_id, s.width, s.height, s.size);
+        let mut resource =
+            VirtioGpuResource::new(s.resource_id, s.width, s.height, s.format, s.size);
         resource.backing_iovecs = s.backing_iovecs;
         resource
     }
@@ -162,6 +214,10 @@ struct VirtioGpuScanout {
 
     resource_id: Option<NonZeroU32>,
     position: Option<(u32, u32)>,
+
+    // If the frames flushed to this primary scanout are being recorded, the state of the capture.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    capture: Option<FrameCapture>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -196,6 +252,8 @@ impl VirtioGpuScanout {
             parent_scanout_id: None,
             resource_id: None,
             position: None,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            capture: None,
         }
     }
 
@@ -213,6 +271,8 @@ impl VirtioGpuScanout {
             parent_scanout_id: None,
             resource_id: None,
             position: None,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            capture: None,
         }
     }
 
@@ -369,6 +429,106 @@ impl VirtioGpuScanout {
         Ok(OkNoData)
     }
 
+    // Reads back the pixels of `resource`, the resource of this scanout, through rutabaga.
+    fn read_pixels(
+        &self,
+        resource: &mut VirtioGpuResource,
+        rutabaga: &mut Rutabaga,
+    ) -> anyhow::Result<Screenshot> {
+        let (width, height) = match resource.scanout_data {
+            Some(data) => (data.width, data.height),
+            None => (
+                self.width.min(resource.width),
+                self.height.min(resource.height),
+            ),
+        };
+        if width == 0 || height == 0 {
+            bail!("scanout of size {}x{} is empty", width, height);
+        }
+        let layout = match resource.scanout_data {
+            Some(data) => drm_pixel_layout(data.drm_format),
+            None => pixel_layout(resource.format),
+        }
+        .context("screenshots don't support the format of the resource")?;
+
+        let row_size = width as usize * 4;
+        let mut pixels = vec![0u8; row_size * height as usize];
+        match resource.scanout_data {
+            // Blob resources may not support transfers, so map their memory instead.
+            Some(data) => {
+                let handle = rutabaga
+                    .export_blob(resource.resource_id)
+                    .context("failed to export blob")?;
+                if handle.handle_type != RUTABAGA_MEM_HANDLE_TYPE_DMABUF
+                    && handle.handle_type != RUTABAGA_MEM_HANDLE_TYPE_SHM
+                {
+                    bail!(
+                        "blob of handle type {:#x} can't be mapped",
+                        handle.handle_type
+                    );
+                }
+                // SAFETY:
+                // Safe because we own the exported descriptor.
+                let file = unsafe {
+                    File::from_raw_descriptor(
+                        to_safe_descriptor(handle.os_handle).into_raw_descriptor(),
+                    )
+                };
+                let mapping = MemoryMappingBuilder::new(resource.size as usize)
+                    .from_file(&file)
+                    .protection(Protection::read())
+                    .build()
+                    .context("failed to map blob")?;
+                for (y, row) in pixels.chunks_exact_mut(row_size).enumerate() {
+                    let offset = data.offsets[0] as usize + y * data.strides[0] as usize;
+                    if mapping.read_slice(row, offset).ok() != Some(row_size) {
+                        bail!("blob is too small for its scanout");
+                    }
+                }
+            }
+            None => {
+                let mut transfer = Transfer3D::new_2d(0, 0, width, height);
+                transfer.stride = row_size as u32;
+                rutabaga
+                    .transfer_read(
+                        0,
+                        resource.resource_id,
+                        transfer,
+                        Some(IoSliceMut::new(&mut pixels)),
+                    )
+                    .context("failed to transfer from resource")?;
+            }
+        }
+        for pixel in pixels.chunks_exact_mut(4) {
+            let [b, g, r] = layout.map(|offset| pixel[offset]);
+            pixel.copy_from_slice(&[b, g, r, 0]);
+        }
+
+        Ok(Screenshot {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    // Records the frame just flushed to this scanout, if it is being captured and is due.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn capture_frame(&mut self, resource: &mut VirtioGpuResource, rutabaga: &mut Rutabaga) {
+        let index = match self.capture.as_mut().and_then(|c| c.next_frame()) {
+            Some(index) => index,
+            None => return,
+        };
+
+        match self.read_pixels(resource, rutabaga) {
+            Ok(screenshot) => self
+                .capture
+                .as_mut()
+                .unwrap()
+                .queue_frame(index, screenshot),
+            Err(e) => error!("failed to capture frame {} of display: {:#}", index, e),
+        }
+    }
+
     fn import_resource_to_display(
         display: &Rc<RefCell<GpuDisplay>>,
         resource: &mut VirtioGpuResource,
@@ -633,12 +793,88 @@ impl VirtioGpu {
             })
     }
 
+    /// Writes the current contents of the specified display to `file` as a PNG image.
+    fn screenshot(&mut self, display_id: u32, file: File) -> GpuControlResult {
+        let scanout = match self.scanouts.get(&display_id) {
+            Some(scanout) => scanout,
+            None => return GpuControlResult::NoSuchDisplay { display_id },
+        };
+        let resource = match scanout
+            .resource_id
+            .and_then(|resource_id| self.resources.get_mut(&resource_id.get()))
+        {
+            Some(resource) => resource,
+            None => return GpuControlResult::NoScanoutResource { display_id },
+        };
+
+        let result = scanout
+            .read_pixels(resource, &mut self.rutabaga)
+            .and_then(|screenshot| {
+                screenshot
+                    .write_png(BufWriter::new(file))
+                    .context("failed to write image")?;
+                Ok(screenshot)
+            });
+        match result {
+            Ok(screenshot) => GpuControlResult::Screenshot {
+                width: screenshot.width,
+                height: screenshot.height,
+            },
+            Err(e) => GpuControlResult::ReadbackFailed(format!("{:#}", e)),
+        }
+    }
+
+    /// Starts recording the frames flushed to the specified display to `directory`, replacing any
+    /// capture already in progress.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn start_capture(
+        &mut self,
+        display_id: u32,
+        interval: NonZeroU32,
+        directory: File,
+    ) -> GpuControlResult {
+        match self.scanouts.get_mut(&display_id) {
+            Some(scanout) => match FrameCapture::new(directory, interval) {
+                Ok(capture) => {
+                    scanout.capture = Some(capture);
+                    GpuControlResult::CaptureStarted
+                }
+                Err(e) => GpuControlResult::CaptureFailed(e.to_string()),
+            },
+            None => GpuControlResult::NoSuchDisplay { display_id },
+        }
+    }
+
+    /// Stops recording the frames flushed to the specified display.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    fn stop_capture(&mut self, display_id: u32) -> GpuControlResult {
+        let scanout = match self.scanouts.get_mut(&display_id) {
+            Some(scanout) => scanout,
+            None => return GpuControlResult::NoSuchDisplay { display_id },
+        };
+        match scanout.capture.take() {
+            Some(capture) => GpuControlResult::CaptureStopped {
+                frames: capture.finish(),
+            },
+            None => GpuControlResult::NoCapture { display_id },
+        }
+    }
+
     /// Performs the given command to interact with or modify the device.
     pub fn process_gpu_control_command(&mut self, cmd: GpuControlCommand) -> GpuControlResult {
         match cmd {
             GpuControlCommand::AddDisplays { displays } => self.add_displays(displays),
             GpuControlCommand::ListDisplays => self.list_displays(),
             GpuControlCommand::RemoveDisplays { display_ids } => self.remove_displays(display_ids),
+            GpuControlCommand::Screenshot { display_id, file } => self.screenshot(display_id, file),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            GpuControlCommand::StartCapture {
+                display_id,
+                interval,
+                directory,
+            } => self.start_capture(display_id, interval, directory),
+            #[cfg(any(target_os = "android", target_os = "linux"))]
+            GpuControlCommand::StopCapture { display_id } => self.stop_capture(display_id),
         }
     }
 
@@ -705,6 +941,8 @@ impl VirtioGpu {
         for scanout in self.scanouts.values_mut() {
             if scanout.resource_id == resource_id {
                 scanout.flush(&self.display, resource, &mut self.rutabaga)?;
+                #[cfg(any(target_os = "android", target_os = "linux"))]
+                scanout.capture_frame(resource, &mut self.rutabaga);
             }
         }
         if self.cursor_scanout.resource_id == resource_id {
@@ -853,6 +1091,7 @@ impl VirtioGpu {
             resource_id,
             resource_create_3d.width,
             resource_create_3d.height,
+            resource_create_3d.format,
             0,
         );
 
@@ -976,7 +1215,7 @@ impl VirtioGpu {
             }),
         )?;
 
-        let resource = VirtioGpuResource::new(resource_id, 0, 0, resource_create_blob.size);
+        let resource = VirtioGpuResource::new(resource_id, 0, 0, 0, resource_create_blob.size);
 
         // Rely on rutabaga to check for duplicate resource ids.
         self.resources.insert(resource_id, resource);
@@ -1289,3 +1528,89 @@ impl VirtioGpu {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rutabaga_gfx::RutabagaBuilder;
+    use rutabaga_gfx::RutabagaComponentType;
+    use rutabaga_gfx::RutabagaHandler;
+    use rutabaga_gfx::RUTABAGA_PIPE_BIND_RENDER_TARGET;
+    use rutabaga_gfx::RUTABAGA_PIPE_TEXTURE_2D;
+
+    use super::*;
+
+    const RESOURCE_ID: u32 = 1;
+
+    fn new_2d() -> Rutabaga {
+        RutabagaBuilder::new(RutabagaComponentType::Rutabaga2D, 0)
+            .build(RutabagaHandler::new(|_| {}), None)
+            .unwrap()
+    }
+
+    // Creates a resource of 2x1 `pixels` of `format` through the 2D backend.
+    fn create_resource(
+        rutabaga: &mut Rutabaga,
+        format: u32,
+        pixels: &mut [u8; 8],
+    ) -> VirtioGpuResource {
+        let resource_create_3d = ResourceCreate3D {
+            target: RUTABAGA_PIPE_TEXTURE_2D,
+            format,
+            bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+            width: 2,
+            height: 1,
+            depth: 1,
+            array_size: 1,
+            last_level: 0,
+            nr_samples: 0,
+            flags: 0,
+        };
+        rutabaga
+            .resource_create_3d(RESOURCE_ID, resource_create_3d)
+            .unwrap();
+        rutabaga
+            .attach_backing(
+                RESOURCE_ID,
+                vec![RutabagaIovec {
+                    base: pixels.as_mut_ptr() as *mut c_void,
+                    len: pixels.len(),
+                }],
+            )
+            .unwrap();
+        rutabaga
+            .transfer_write(0, RESOURCE_ID, Transfer3D::new_2d(0, 0, 2, 1))
+            .unwrap();
+        rutabaga.detach_backing(RESOURCE_ID).unwrap();
+        VirtioGpuResource::new(RESOURCE_ID, 2, 1, format, 0)
+    }
+
+    #[test]
+    fn read_pixels_converts_to_xrgb() {
+        let mut rutabaga = new_2d();
+        // A red pixel, then a blue one.
+        let mut pixels = [0xff, 0x00, 0x00, 0xff, 0x00, 0x00, 0xff, 0xff];
+        let mut resource =
+            create_resource(&mut rutabaga, VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM, &mut pixels);
+
+        let screenshot = VirtioGpuScanout::new_cursor()
+            .read_pixels(&mut resource, &mut rutabaga)
+            .unwrap();
+        assert_eq!((screenshot.width, screenshot.height), (2, 1));
+        assert_eq!(
+            screenshot.pixels,
+            [0x00, 0x00, 0xff, 0x00, 0xff, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn read_pixels_rejects_unsupported_format() {
+        let mut rutabaga = new_2d();
+        let mut pixels = [0; 8];
+        // VIRTIO_GPU_FORMAT_R8G8B8A8_SRGB isn't supported.
+        let mut resource = create_resource(&mut rutabaga, 182, &mut pixels);
+
+        assert!(VirtioGpuScanout::new_cursor()
+            .read_pixels(&mut resource, &mut rutabaga)
+            .is_err());
+    }
+}
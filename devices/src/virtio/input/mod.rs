@@ -8,6 +8,7 @@ mod evdev;
 mod event_source;
 
 use std::collections::BTreeMap;
+use std::collections::VecDeque;
 use std::io::Read;
 use std::io::Write;
 
@@ -22,6 +23,7 @@ use base::AsRawDescriptor;
 use base::Event;
 use base::EventToken;
 use base::RawDescriptor;
+use base::Tube;
 use base::WaitContext;
 use base::WorkerThread;
 use data_model::Le16;
@@ -33,14 +35,15 @@ use remain::sorted;
 use serde::Deserialize;
 use serde::Serialize;
 use thiserror::Error;
+use vm_control::input::InputEvent;
 use vm_memory::GuestMemory;
 use zerocopy::AsBytes;
 use zerocopy::FromBytes;
 use zerocopy::FromZeroes;
 
 use self::event_source::EvdevEventSource;
-use self::event_source::EventSource;
-use self::event_source::SocketEventSource;
+pub(crate) use self::event_source::EventSource;
+pub(crate) use self::event_source::SocketEventSource;
 use super::copy_config;
 use super::DescriptorChain;
 use super::DeviceType;
@@ -51,6 +54,9 @@ use super::VirtioDevice;
 const EVENT_QUEUE_SIZE: u16 = 64;
 const STATUS_QUEUE_SIZE: u16 = 64;
 const QUEUE_SIZES: &[u16] = &[EVENT_QUEUE_SIZE, STATUS_QUEUE_SIZE];
+// The number of injected events past which the control tube isn't read until the guest takes
+// some of them. The events sent meanwhile fill the tube's socket, and then fail to be sent.
+const MAX_INJECTED_EVENTS: usize = 1024;
 
 #[sorted]
 #[derive(Error, Debug)]
@@ -342,52 +348,106 @@ impl VirtioInputConfig {
     }
 }
 
+// A stream of events sent to the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EventStream {
+    // The events of the source.
+    Source,
+    // The events received through the control tube.
+    Injected,
+}
+
 struct Worker<T: EventSource> {
     interrupt: Interrupt,
     event_source: T,
     event_queue: Queue,
     status_queue: Queue,
+    control_tube: Option<Tube>,
+    // Events received through `control_tube`, made of whole reports. Holds about
+    // `MAX_INJECTED_EVENTS` at most.
+    injected_events: VecDeque<virtio_input_event>,
+    // The stream whose report is partly sent to the guest. Until its SYN_REPORT is sent, the
+    // events of the other stream are held back so the reports of both aren't interleaved.
+    report_stream: Option<EventStream>,
 }
 
 impl<T: EventSource> Worker<T> {
-    // Fills a virtqueue with events from the source.  Returns the number of bytes written.
+    // Returns the stream the next event sent to the guest comes from, if any can be sent.
+    fn next_stream(
+        event_source: &T,
+        injected_events: &VecDeque<virtio_input_event>,
+        report_stream: Option<EventStream>,
+    ) -> Option<EventStream> {
+        let source_ready = event_source.available_events_count() > 0;
+        let injected_ready = !injected_events.is_empty();
+        match report_stream {
+            Some(EventStream::Source) => source_ready.then_some(EventStream::Source),
+            Some(EventStream::Injected) => injected_ready.then_some(EventStream::Injected),
+            None if source_ready => Some(EventStream::Source),
+            None if injected_ready => Some(EventStream::Injected),
+            None => None,
+        }
+    }
+
+    // Fills a virtqueue with events from the source and injected events, switching between them
+    // only at report boundaries.  Returns the number of bytes written.
     fn fill_event_virtqueue(
         event_source: &mut T,
+        injected_events: &mut VecDeque<virtio_input_event>,
+        report_stream: &mut Option<EventStream>,
         avail_desc: &mut DescriptorChain,
     ) -> Result<usize> {
         let writer = &mut avail_desc.writer;
 
         while writer.available_bytes() >= virtio_input_event::SIZE {
-            if let Some(evt) = event_source.pop_available_event() {
-                writer.write_obj(evt).map_err(InputError::WriteQueue)?;
-            } else {
+            let Some(stream) = Worker::next_stream(event_source, injected_events, *report_stream)
+            else {
                 break;
-            }
+            };
+            let evt = match stream {
+                EventStream::Source => event_source.pop_available_event(),
+                EventStream::Injected => injected_events.pop_front(),
+            };
+            let Some(evt) = evt else {
+                break;
+            };
+            writer.write_obj(evt).map_err(InputError::WriteQueue)?;
+            let report_end = evt.type_ == Le16::from(EV_SYN) && evt.code == Le16::from(SYN_REPORT);
+            *report_stream = if report_end { None } else { Some(stream) };
         }
 
         Ok(writer.bytes_written())
     }
 
-    // Send events from the source to the guest
+    // Send events from the source and injected events to the guest
     fn send_events(&mut self) -> bool {
         let mut needs_interrupt = false;
 
         // Only consume from the queue iterator if we know we have events to send
-        while self.event_source.available_events_count() > 0 {
+        while Worker::next_stream(
+            &self.event_source,
+            &self.injected_events,
+            self.report_stream,
+        )
+        .is_some()
+        {
             match self.event_queue.pop() {
                 None => {
                     break;
                 }
                 Some(mut avail_desc) => {
-                    let bytes_written =
-                        match Worker::fill_event_virtqueue(&mut self.event_source, &mut avail_desc)
-                        {
-                            Ok(count) => count,
-                            Err(e) => {
-                                error!("Input: failed to send events to guest: {}", e);
-                                break;
-                            }
-                        };
+                    let bytes_written = match Worker::fill_event_virtqueue(
+                        &mut self.event_source,
+                        &mut self.injected_events,
+                        &mut self.report_stream,
+                        &mut avail_desc,
+                    ) {
+                        Ok(count) => count,
+                        Err(e) => {
+                            error!("Input: failed to send events to guest: {}", e);
+                            break;
+                        }
+                    };
 
                     self.event_queue.add_used(avail_desc, bytes_written as u32);
                     needs_interrupt = true;
@@ -444,6 +504,7 @@ impl<T: EventSource> Worker<T> {
             EventQAvailable,
             StatusQAvailable,
             InputEventsAvailable,
+            Control,
             InterruptResample,
             Kill,
         }
@@ -468,6 +529,17 @@ impl<T: EventSource> Worker<T> {
                 return;
             }
         }
+        if let Some(control_tube) = &self.control_tube {
+            if let Err(e) = wait_ctx.add(control_tube, Token::Control) {
+                error!("failed adding control tube to WaitContext: {}", e);
+                return;
+            }
+        }
+
+        // Whether `control_tube` was taken out of `wait_ctx` because `injected_events` is full, or
+        // for good because it broke.
+        let mut control_paused = false;
+        let mut control_failed = false;
 
         'wait: loop {
             let wait_events = match wait_ctx.wait() {
@@ -503,6 +575,24 @@ impl<T: EventSource> Worker<T> {
                         Err(e) => error!("error receiving events: {}", e),
                         Ok(_cnt) => eventq_needs_interrupt |= self.send_events(),
                     },
+                    Token::Control => {
+                        let Some(control_tube) = &self.control_tube else {
+                            continue;
+                        };
+                        match control_tube.recv::<Vec<InputEvent>>() {
+                            Ok(events) => {
+                                self.injected_events
+                                    .extend(events.into_iter().map(virtio_input_event::from));
+                                eventq_needs_interrupt |= self.send_events();
+                            }
+                            Err(e) => {
+                                error!("failed receiving injected events: {}", e);
+                                // Stop polling a broken tube rather than spinning on it.
+                                let _ = wait_ctx.delete(control_tube);
+                                control_failed = true;
+                            }
+                        }
+                    }
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
                     }
@@ -512,6 +602,21 @@ impl<T: EventSource> Worker<T> {
                     }
                 }
             }
+            if let Some(control_tube) = self.control_tube.as_ref().filter(|_| !control_failed) {
+                let queue_full = self.injected_events.len() >= MAX_INJECTED_EVENTS;
+                if queue_full != control_paused {
+                    let result = if queue_full {
+                        wait_ctx.delete(control_tube)
+                    } else {
+                        wait_ctx.add(control_tube, Token::Control)
+                    };
+                    if let Err(e) = result {
+                        error!("failed updating the control tube in WaitContext: {}", e);
+                        break;
+                    }
+                    control_paused = queue_full;
+                }
+            }
             if eventq_needs_interrupt {
                 self.event_queue.trigger_interrupt(&self.interrupt);
             }
@@ -528,11 +633,14 @@ impl<T: EventSource> Worker<T> {
 }
 
 /// Virtio input device
-
+///
+/// Besides the events of its source, the device sends the guest the events injected through its
+/// optional control tube, which carries `Vec<InputEvent>` messages made of whole reports.
 pub struct Input<T: EventSource + Send + 'static> {
     worker_thread: Option<WorkerThread<Worker<T>>>,
     config: VirtioInputConfig,
     source: Option<T>,
+    control_tube: Option<Tube>,
     virtio_features: u64,
 }
 
@@ -548,10 +656,14 @@ where
     T: 'static + EventSource + Send,
 {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut rds = Vec::new();
         if let Some(source) = &self.source {
-            return vec![source.as_raw_descriptor()];
+            rds.push(source.as_raw_descriptor());
+        }
+        if let Some(control_tube) = &self.control_tube {
+            rds.push(control_tube.as_raw_descriptor());
         }
-        Vec::new()
+        rds
     }
 
     fn device_type(&self) -> DeviceType {
@@ -590,12 +702,16 @@ where
             .source
             .take()
             .context("tried to activate device without a source for events")?;
+        let control_tube = self.control_tube.take();
         self.worker_thread = Some(WorkerThread::start("v_input", move |kill_evt| {
             let mut worker = Worker {
                 interrupt,
                 event_source: source,
                 event_queue,
                 status_queue,
+                control_tube,
+                injected_events: VecDeque::new(),
+                report_stream: None,
             };
             worker.run(kill_evt);
             worker
@@ -608,6 +724,7 @@ where
         if let Some(worker_thread) = self.worker_thread.take() {
             let worker = worker_thread.stop();
             self.source = Some(worker.event_source);
+            self.control_tube = worker.control_tube;
             return true;
         }
         false
@@ -617,6 +734,7 @@ where
         if let Some(worker_thread) = self.worker_thread.take() {
             let worker = worker_thread.stop();
             self.source = Some(worker.event_source);
+            self.control_tube = worker.control_tube;
             let queues = BTreeMap::from([(0, worker.event_queue), (1, worker.status_queue)]);
             Ok(Some(queues))
         } else {
@@ -657,7 +775,11 @@ where
 }
 
 /// Creates a new virtio input device from an event device node
-pub fn new_evdev<T>(source: T, virtio_features: u64) -> Result<Input<EvdevEventSource<T>>>
+pub fn new_evdev<T>(
+    source: T,
+    control_tube: Option<Tube>,
+    virtio_features: u64,
+) -> Result<Input<EvdevEventSource<T>>>
 where
     T: Read + Write + AsRawDescriptor + Send + 'static,
 {
@@ -665,6 +787,7 @@ where
         worker_thread: None,
         config: VirtioInputConfig::from_evdev(&source)?,
         source: Some(EvdevEventSource::new(source)),
+        control_tube,
         virtio_features,
     })
 }
@@ -676,6 +799,7 @@ pub fn new_single_touch<T>(
     width: u32,
     height: u32,
     name: Option<&str>,
+    control_tube: Option<Tube>,
     virtio_features: u64,
 ) -> Result<Input<SocketEventSource<T>>>
 where
@@ -685,6 +809,7 @@ where
         worker_thread: None,
         config: defaults::new_single_touch_config(idx, width, height, name),
         source: Some(SocketEventSource::new(source)),
+        control_tube,
         virtio_features,
     })
 }
@@ -696,6 +821,7 @@ pub fn new_multi_touch<T>(
     width: u32,
     height: u32,
     name: Option<&str>,
+    control_tube: Option<Tube>,
     virtio_features: u64,
 ) -> Result<Input<SocketEventSource<T>>>
 where
@@ -705,6 +831,7 @@ where
         worker_thread: None,
         config: defaults::new_multi_touch_config(idx, width, height, name),
         source: Some(SocketEventSource::new(source)),
+        control_tube,
         virtio_features,
     })
 }
@@ -717,6 +844,7 @@ pub fn new_trackpad<T>(
     width: u32,
     height: u32,
     name: Option<&str>,
+    control_tube: Option<Tube>,
     virtio_features: u64,
 ) -> Result<Input<SocketEventSource<T>>>
 where
@@ -726,6 +854,7 @@ where
         worker_thread: None,
         config: defaults::new_trackpad_config(idx, width, height, name),
         source: Some(SocketEventSource::new(source)),
+        control_tube,
         virtio_features,
     })
 }
@@ -734,6 +863,7 @@ where
 pub fn new_mouse<T>(
     idx: u32,
     source: T,
+    control_tube: Option<Tube>,
     virtio_features: u64,
 ) -> Result<Input<SocketEventSource<T>>>
 where
@@ -743,6 +873,7 @@ where
         worker_thread: None,
         config: defaults::new_mouse_config(idx),
         source: Some(SocketEventSource::new(source)),
+        control_tube,
         virtio_features,
     })
 }
@@ -751,6 +882,7 @@ where
 pub fn new_keyboard<T>(
     idx: u32,
     source: T,
+    control_tube: Option<Tube>,
     virtio_features: u64,
 ) -> Result<Input<SocketEventSource<T>>>
 where
@@ -760,6 +892,7 @@ where
         worker_thread: None,
         config: defaults::new_keyboard_config(idx),
         source: Some(SocketEventSource::new(source)),
+        control_tube,
         virtio_features,
     })
 }
@@ -768,6 +901,7 @@ where
 pub fn new_switches<T>(
     idx: u32,
     source: T,
+    control_tube: Option<Tube>,
     virtio_features: u64,
 ) -> Result<Input<SocketEventSource<T>>>
 where
@@ -777,6 +911,7 @@ where
         worker_thread: None,
         config: defaults::new_switches_config(idx),
         source: Some(SocketEventSource::new(source)),
+        control_tube,
         virtio_features,
     })
 }
@@ -785,6 +920,7 @@ where
 pub fn new_rotary<T>(
     idx: u32,
     source: T,
+    control_tube: Option<Tube>,
     virtio_features: u64,
 ) -> Result<Input<SocketEventSource<T>>>
 where
@@ -794,6 +930,141 @@ where
         worker_thread: None,
         config: defaults::new_rotary_config(idx),
         source: Some(SocketEventSource::new(source)),
+        control_tube,
         virtio_features,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use vm_memory::GuestAddress;
+
+    use super::*;
+    use crate::virtio::create_descriptor_chain;
+    use crate::virtio::DescriptorType;
+
+    const BUFFER_ADDR: u64 = 0x1000;
+
+    // Hands out the events the test queues, as if they were received from a device.
+    struct FakeEventSource {
+        evt: Event,
+        events: VecDeque<virtio_input_event>,
+    }
+
+    impl AsRawDescriptor for FakeEventSource {
+        fn as_raw_descriptor(&self) -> RawDescriptor {
+            self.evt.as_raw_descriptor()
+        }
+    }
+
+    impl EventSource for FakeEventSource {
+        fn receive_events(&mut self) -> Result<usize> {
+            Ok(0)
+        }
+
+        fn available_events_count(&self) -> usize {
+            self.events.len()
+        }
+
+        fn pop_available_event(&mut self) -> Option<virtio_input_event> {
+            self.events.pop_front()
+        }
+
+        fn send_event(&mut self, _vio_evt: &virtio_input_event) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    // Sends events to the guest through a buffer with room for `count` events, and returns the
+    // events written to it.
+    fn send(
+        source: &mut FakeEventSource,
+        injected_events: &mut VecDeque<virtio_input_event>,
+        report_stream: &mut Option<EventStream>,
+        count: usize,
+    ) -> Vec<virtio_input_event> {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut avail_desc = create_descriptor_chain(
+            &mem,
+            GuestAddress(0),
+            GuestAddress(BUFFER_ADDR),
+            vec![(
+                DescriptorType::Writable,
+                (count * virtio_input_event::SIZE) as u32,
+            )],
+            0,
+        )
+        .expect("failed to create descriptor chain");
+        let written =
+            Worker::fill_event_virtqueue(source, injected_events, report_stream, &mut avail_desc)
+                .unwrap();
+        (0..written / virtio_input_event::SIZE)
+            .map(|i| {
+                mem.read_obj_from_addr(GuestAddress(
+                    BUFFER_ADDR + (i * virtio_input_event::SIZE) as u64,
+                ))
+                .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn injected_reports_wait_for_source_reports() {
+        let key = virtio_input_event::key(KEY_A, true, false);
+        let rel = virtio_input_event::relative(REL_X, 1);
+        let syn = virtio_input_event::syn();
+        let mut source = FakeEventSource {
+            evt: Event::new().unwrap(),
+            events: VecDeque::from([key]),
+        };
+        let mut injected_events = VecDeque::from([rel, syn]);
+        let mut report_stream = None;
+
+        // The source's report isn't complete, so the injected one is held back.
+        assert_eq!(
+            send(&mut source, &mut injected_events, &mut report_stream, 8),
+            vec![key]
+        );
+        assert_eq!(report_stream, Some(EventStream::Source));
+        assert_eq!(
+            Worker::next_stream(&source, &injected_events, report_stream),
+            None
+        );
+
+        // Once it is, the injected report follows.
+        source.events.push_back(syn);
+        assert_eq!(
+            send(&mut source, &mut injected_events, &mut report_stream, 8),
+            vec![syn, rel, syn]
+        );
+        assert_eq!(report_stream, None);
+    }
+
+    #[test]
+    fn source_reports_wait_for_injected_reports() {
+        let key = virtio_input_event::key(KEY_A, true, false);
+        let rel = virtio_input_event::relative(REL_X, 1);
+        let syn = virtio_input_event::syn();
+        let mut source = FakeEventSource {
+            evt: Event::new().unwrap(),
+            events: VecDeque::new(),
+        };
+        let mut injected_events = VecDeque::from([rel, syn]);
+        let mut report_stream = None;
+
+        // The buffer only has room for part of the injected report.
+        assert_eq!(
+            send(&mut source, &mut injected_events, &mut report_stream, 1),
+            vec![rel]
+        );
+        assert_eq!(report_stream, Some(EventStream::Injected));
+
+        // The source's report follows the rest of the injected one.
+        source.events.extend([key, syn]);
+        assert_eq!(
+            send(&mut source, &mut injected_events, &mut report_stream, 8),
+            vec![syn, key, syn]
+        );
+        assert_eq!(report_stream, None);
+    }
+}
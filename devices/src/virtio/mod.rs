@@ -20,7 +20,6 @@ pub mod net;
 pub mod pvclock;
 mod queue;
 mod rng;
-#[cfg(feature = "vtpm")]
 mod tpm;
 #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
 mod video;
@@ -90,10 +89,8 @@ pub use self::queue::QueueConfig;
 pub use self::rng::Rng;
 pub use self::scsi::Controller as ScsiController;
 pub use self::scsi::DiskConfig as ScsiDiskConfig;
-#[cfg(feature = "vtpm")]
 pub use self::tpm::Tpm;
-#[cfg(feature = "vtpm")]
-pub use self::tpm::TpmBackend;
+pub use self::tpm::TPM_BUFSIZE;
 #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
 pub use self::video::VideoDevice;
 pub use self::virtio_device::SharedMemoryMapper;
@@ -105,6 +102,7 @@ pub use self::virtio_pci_device::PciCapabilityType;
 pub use self::virtio_pci_device::VirtioPciCap;
 pub use self::virtio_pci_device::VirtioPciDevice;
 pub use self::virtio_pci_device::VirtioPciShmCap;
+pub use crate::tpm::TpmBackend;
 
 cfg_if::cfg_if! {
     if #[cfg(any(target_os = "android", target_os = "linux"))] {
@@ -30,6 +30,10 @@ use data_model::Le16;
 use data_model::Le64;
 use net_util::Error as TapError;
 use net_util::MacAddress;
+#[cfg(all(feature = "slirp", any(target_os = "android", target_os = "linux")))]
+use net_util::Slirp;
+#[cfg(all(feature = "slirp", any(target_os = "android", target_os = "linux")))]
+use net_util::SlirpLoop;
 use net_util::TapT;
 use remain::sorted;
 use serde::Deserialize;
@@ -169,6 +173,24 @@ pub enum NetParametersMode {
         netmask: Ipv4Addr,
         mac: MacAddress,
     },
+    #[cfg(all(feature = "slirp", any(target_os = "android", target_os = "linux")))]
+    #[serde(rename_all = "kebab-case")]
+    Slirp {
+        #[serde(deserialize_with = "deserialize_slirp")]
+        slirp: bool,
+        #[serde(default)]
+        host_fwd: Vec<net_util::slirp::HostFwd>,
+        mac: Option<MacAddress>,
+    },
+}
+
+// `slirp` is a flag selecting the user-mode network, so only accept it being set.
+#[cfg(all(feature = "slirp", any(target_os = "android", target_os = "linux")))]
+fn deserialize_slirp<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    match bool::deserialize(deserializer)? {
+        true => Ok(true),
+        false => Err(serde::de::Error::custom("slirp cannot be disabled")),
+    }
 }
 
 #[cfg(any(target_os = "android", target_os = "linux"))]
@@ -484,6 +506,8 @@ pub struct Net<T: TapT + ReadNotifier + 'static> {
     mtu: u16,
     #[cfg(windows)]
     slirp_kill_evt: Option<Event>,
+    #[cfg(all(feature = "slirp", any(target_os = "android", target_os = "linux")))]
+    slirp_loop: Option<SlirpLoop>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -504,6 +528,26 @@ where
         vq_pairs: u16,
         mac_addr: Option<MacAddress>,
         use_packed_queue: bool,
+    ) -> Result<Net<T>, NetError> {
+        Self::new_with_offloads(
+            base_features,
+            tap,
+            vq_pairs,
+            mac_addr,
+            use_packed_queue,
+            true,
+        )
+    }
+
+    // Creates a new virtio network device, offering the checksum and segmentation offloads to the
+    // guest if `offloads` is set.
+    fn new_with_offloads(
+        base_features: u64,
+        tap: T,
+        vq_pairs: u16,
+        mac_addr: Option<MacAddress>,
+        use_packed_queue: bool,
+        offloads: bool,
     ) -> Result<Net<T>, NetError> {
         let taps = tap.into_mq_taps(vq_pairs).map_err(NetError::TapOpen)?;
 
@@ -523,16 +567,19 @@ where
         // See the network device feature bits section for further details:
         //     http://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-1970003
         let mut avail_features = base_features
-            | 1 << virtio_net::VIRTIO_NET_F_GUEST_CSUM
-            | 1 << virtio_net::VIRTIO_NET_F_CSUM
             | 1 << virtio_net::VIRTIO_NET_F_CTRL_VQ
-            | 1 << virtio_net::VIRTIO_NET_F_CTRL_GUEST_OFFLOADS
-            | 1 << virtio_net::VIRTIO_NET_F_GUEST_TSO4
-            | 1 << virtio_net::VIRTIO_NET_F_GUEST_UFO
-            | 1 << virtio_net::VIRTIO_NET_F_HOST_TSO4
-            | 1 << virtio_net::VIRTIO_NET_F_HOST_UFO
             | 1 << virtio_net::VIRTIO_NET_F_MTU;
 
+        if offloads {
+            avail_features |= 1 << virtio_net::VIRTIO_NET_F_GUEST_CSUM
+                | 1 << virtio_net::VIRTIO_NET_F_CSUM
+                | 1 << virtio_net::VIRTIO_NET_F_CTRL_GUEST_OFFLOADS
+                | 1 << virtio_net::VIRTIO_NET_F_GUEST_TSO4
+                | 1 << virtio_net::VIRTIO_NET_F_GUEST_UFO
+                | 1 << virtio_net::VIRTIO_NET_F_HOST_TSO4
+                | 1 << virtio_net::VIRTIO_NET_F_HOST_UFO;
+        }
+
         if vq_pairs > 1 {
             avail_features |= 1 << virtio_net::VIRTIO_NET_F_MQ;
         }
@@ -572,6 +619,8 @@ where
             mtu,
             #[cfg(windows)]
             slirp_kill_evt: None,
+            #[cfg(all(feature = "slirp", any(target_os = "android", target_os = "linux")))]
+            slirp_loop: None,
         };
         cros_tracing::trace_simple_print!("New Net device created: {:?}", net);
         Ok(net)
@@ -584,6 +633,27 @@ where
     }
 }
 
+#[cfg(all(feature = "slirp", any(target_os = "android", target_os = "linux")))]
+impl Net<Slirp> {
+    /// Creates a new virtio network device attached to a user-mode network.
+    ///
+    /// `slirp_loop` is started once the device is sandboxed, as it opens host sockets on behalf of
+    /// the guest.
+    pub fn new_slirp(
+        base_features: u64,
+        slirp: Slirp,
+        slirp_loop: SlirpLoop,
+        mac_addr: Option<MacAddress>,
+        use_packed_queue: bool,
+    ) -> Result<Self, NetError> {
+        // libslirp neither checksums nor segments the frames it receives.
+        let mut net =
+            Self::new_with_offloads(base_features, slirp, 1, mac_addr, use_packed_queue, false)?;
+        net.slirp_loop = Some(slirp_loop);
+        Ok(net)
+    }
+}
+
 impl<T> Drop for Net<T>
 where
     T: TapT + ReadNotifier,
@@ -609,9 +679,21 @@ where
             keep_rds.push(tap.as_raw_descriptor());
         }
 
+        #[cfg(all(feature = "slirp", any(target_os = "android", target_os = "linux")))]
+        if let Some(slirp_loop) = &self.slirp_loop {
+            keep_rds.extend(slirp_loop.as_raw_descriptors());
+        }
+
         keep_rds
     }
 
+    #[cfg(all(feature = "slirp", any(target_os = "android", target_os = "linux")))]
+    fn on_device_sandboxed(&mut self) {
+        if let Some(slirp_loop) = &mut self.slirp_loop {
+            slirp_loop.start();
+        }
+    }
+
     fn device_type(&self) -> DeviceType {
         DeviceType::Net
     }
@@ -1036,4 +1118,52 @@ mod tests {
         )
         .is_err());
     }
+
+    #[cfg(all(feature = "slirp", any(target_os = "android", target_os = "linux")))]
+    #[test]
+    fn params_from_key_values_slirp() {
+        use net_util::slirp::HostFwd;
+
+        let params = from_net_arg("slirp").unwrap();
+        assert_eq!(
+            params,
+            NetParameters {
+                vhost_net: None,
+                vq_pairs: None,
+                mode: NetParametersMode::Slirp {
+                    slirp: true,
+                    host_fwd: Vec::new(),
+                    mac: None,
+                },
+                packed_queue: false
+            }
+        );
+
+        let params = from_net_arg(
+            "slirp,host-fwd=[tcp:8022:22,udp:127.0.0.1:5353:53],mac=\"3d:70:eb:61:1a:91\"",
+        )
+        .unwrap();
+        assert_eq!(
+            params,
+            NetParameters {
+                vhost_net: None,
+                vq_pairs: None,
+                mode: NetParametersMode::Slirp {
+                    slirp: true,
+                    host_fwd: vec![
+                        HostFwd::from_str("tcp:8022:22").unwrap(),
+                        HostFwd::from_str("udp:127.0.0.1:5353:53").unwrap(),
+                    ],
+                    mac: Some(MacAddress::from_str("3d:70:eb:61:1a:91").unwrap()),
+                },
+                packed_queue: false
+            }
+        );
+
+        // slirp is a flag
+        assert!(from_net_arg("slirp=false").is_err());
+
+        // invalid forwarding rule
+        assert!(from_net_arg("slirp,host-fwd=[sctp:8022:22]").is_err());
+    }
 }
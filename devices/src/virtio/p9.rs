@@ -22,12 +22,15 @@ use remain::sorted;
 use thiserror::Error;
 use vm_memory::GuestMemory;
 
+use self::fid_table::FidTable;
 use super::copy_config;
 use super::queue::Queue;
 use super::DeviceType;
 use super::Interrupt;
 use super::VirtioDevice;
 
+mod fid_table;
+
 const QUEUE_SIZE: u16 = 128;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
 
@@ -73,13 +76,18 @@ struct Worker {
     interrupt: Interrupt,
     queue: Queue,
     server: p9::Server,
+    fids: FidTable,
 }
 
 impl Worker {
     fn process_queue(&mut self) -> P9Result<()> {
         while let Some(mut avail_desc) = self.queue.pop() {
-            self.server
-                .handle_message(&mut avail_desc.reader, &mut avail_desc.writer)
+            self.fids
+                .handle_message(
+                    &mut self.server,
+                    &mut avail_desc.reader,
+                    &mut avail_desc.writer,
+                )
                 .map_err(P9Error::Internal)?;
 
             let len = avail_desc.writer.bytes_written() as u32;
@@ -135,9 +143,11 @@ impl Worker {
 pub struct P9 {
     config: Vec<u8>,
     server: Option<p9::Server>,
+    // The fids of the session, while the worker isn't running.
+    fids: FidTable,
     avail_features: u64,
     acked_features: u64,
-    worker: Option<WorkerThread<P9Result<()>>>,
+    worker: Option<WorkerThread<P9Result<Worker>>>,
 }
 
 impl P9 {
@@ -157,6 +167,7 @@ impl P9 {
         Ok(P9 {
             config: cfg,
             server: Some(server),
+            fids: FidTable::default(),
             avail_features: base_features | 1 << VIRTIO_9P_MOUNT_TAG,
             acked_features: 0,
             worker: None,
@@ -215,17 +226,59 @@ impl VirtioDevice for P9 {
         let queue = queues.remove(&0).unwrap();
 
         let server = self.server.take().context("missing server")?;
+        let fids = mem::take(&mut self.fids);
 
         self.worker = Some(WorkerThread::start("v_9p", move |kill_evt| {
             let mut worker = Worker {
                 interrupt,
                 queue,
                 server,
+                fids,
             };
 
-            worker.run(kill_evt)
+            worker.run(kill_evt)?;
+            Ok(worker)
         }));
 
         Ok(())
     }
+
+    fn virtio_sleep(&mut self) -> anyhow::Result<Option<BTreeMap<usize, Queue>>> {
+        match self.worker.take() {
+            Some(worker) => {
+                let worker = worker.stop().context("virtio-9p worker failed")?;
+                self.server = Some(worker.server);
+                self.fids = worker.fids;
+                Ok(Some(BTreeMap::from([(0, worker.queue)])))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn virtio_wake(
+        &mut self,
+        queues_state: Option<(GuestMemory, Interrupt, BTreeMap<usize, Queue>)>,
+    ) -> anyhow::Result<()> {
+        if let Some((mem, interrupt, queues)) = queues_state {
+            self.activate(mem, interrupt, queues)?;
+        }
+        Ok(())
+    }
+
+    fn virtio_snapshot(&mut self) -> anyhow::Result<serde_json::Value> {
+        serde_json::to_value(&self.fids).context("failed to serialize virtio-9p snapshot")
+    }
+
+    fn virtio_restore(&mut self, data: serde_json::Value) -> anyhow::Result<()> {
+        let fids: FidTable =
+            serde_json::from_value(data).context("failed to deserialize virtio-9p snapshot")?;
+        let server = self
+            .server
+            .as_mut()
+            .context("can't restore virtio-9p while it is active")?;
+        fids.restore(server)
+            .context("failed to restore the fids of the shared directory")?;
+        self.fids = fids;
+        Ok(())
+    }
 }
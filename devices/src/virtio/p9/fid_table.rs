@@ -0,0 +1,448 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Tracks the fids of a 9P session, so that they can be recreated in another server when the
+//! device is restored from a snapshot.
+//!
+//! The `p9` server doesn't expose its fid table, so the requests that create, change or release
+//! fids are inspected on their way to the server, and replayed on restore.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Write;
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::virtio::Reader;
+use crate::virtio::Writer;
+
+// The types of the 9P2000.L messages that matter to the fid table.
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TRENAME: u8 = 20;
+const TRENAMEAT: u8 = 74;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+// Every message starts with size[4] type[1] tag[2].
+const HEADER_SIZE: usize = 7;
+// The maximum number of names in a single Twalk.
+const MAX_WALK_NAMES: usize = 16;
+const NOFID: u32 = u32::MAX;
+const NOTAG: u16 = u16::MAX;
+// The Tlcreate flags that must not be replayed when the file is re-opened: P9_CREATE, P9_EXCL
+// and P9_TRUNC.
+const CREATE_FLAGS: u32 = 0o100 | 0o200 | 0o1000;
+
+/// The file that a fid refers to, with what is needed to recreate the fid in another server.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Fid {
+    // The parameters of the Tattach request that the fid descends from.
+    uname: String,
+    aname: String,
+    n_uname: u32,
+    // The names walked from the root of the tree to the file.
+    path: Vec<String>,
+    // The flags that the file was opened with, if it was opened.
+    open_flags: Option<u32>,
+}
+
+/// The fids of a 9P session, as left by the requests that the server completed.
+///
+/// Fids created by Txattrwalk aren't tracked: they are short-lived, and don't survive a restore.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FidTable {
+    // The maximum message size and the protocol version negotiated by Tversion.
+    version: Option<(u32, String)>,
+    fids: BTreeMap<u32, Fid>,
+}
+
+impl FidTable {
+    /// Handles the request in `reader` with `server` and writes the response to `writer`,
+    /// updating the fids with its result.
+    pub fn handle_message(
+        &mut self,
+        server: &mut p9::Server,
+        reader: &mut Reader,
+        writer: &mut Writer,
+    ) -> io::Result<()> {
+        let header = reader.peek_obj::<[u8; HEADER_SIZE]>()?;
+        if !matches!(
+            header[4],
+            TLOPEN | TLCREATE | TRENAME | TRENAMEAT | TVERSION | TATTACH | TWALK | TCLUNK | TREMOVE
+        ) {
+            // Leave the other requests, including the ones that move data, unbuffered.
+            return server.handle_message(reader, writer);
+        }
+        let size = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let mut request = vec![0; size.min(reader.available_bytes())];
+        reader.read_exact(&mut request)?;
+        let mut response = Vec::new();
+        server.handle_message(&mut Cursor::new(&request[..]), &mut response)?;
+        self.update(&request, &response);
+        writer.write_all(&response)
+    }
+
+    // Updates the fids with the result of `request`. Returns `None` if a message is malformed, in
+    // which case the server has failed the request too.
+    fn update(&mut self, request: &[u8], response: &[u8]) -> Option<()> {
+        let request_type = *request.get(4)?;
+        let succeeded = *response.get(4)? != RLERROR;
+        let mut request = Decoder(request.get(HEADER_SIZE..)?);
+        let mut response = Decoder(response.get(HEADER_SIZE..)?);
+        match request_type {
+            // The fid is released even if the request fails.
+            TCLUNK | TREMOVE => {
+                self.fids.remove(&request.u32()?);
+            }
+            _ if !succeeded => {}
+            TVERSION => {
+                // A new session starts, without any fid.
+                self.fids.clear();
+                self.version = Some((response.u32()?, response.string()?));
+            }
+            TATTACH => {
+                let fid = request.u32()?;
+                let _afid = request.u32()?;
+                let attached = Fid {
+                    uname: request.string()?,
+                    aname: request.string()?,
+                    n_uname: request.u32()?,
+                    path: Vec::new(),
+                    open_flags: None,
+                };
+                self.fids.insert(fid, attached);
+            }
+            TWALK => {
+                let fid = request.u32()?;
+                let newfid = request.u32()?;
+                let nwname = request.u16()?;
+                // The new fid is only created when all the names are walked.
+                if response.u16()? != nwname {
+                    return None;
+                }
+                let mut walked = self.fids.get(&fid)?.clone();
+                walked.open_flags = None;
+                for _ in 0..nwname {
+                    walked.path.push(request.string()?);
+                }
+                self.fids.insert(newfid, walked);
+            }
+            TLOPEN => {
+                let fid = request.u32()?;
+                self.fids.get_mut(&fid)?.open_flags = Some(request.u32()?);
+            }
+            TLCREATE => {
+                let fid = request.u32()?;
+                let name = request.string()?;
+                let flags = request.u32()?;
+                // The fid now refers to the new file.
+                let created = self.fids.get_mut(&fid)?;
+                created.path.push(name);
+                created.open_flags = Some(flags & !CREATE_FLAGS);
+            }
+            TRENAME => {
+                let fid = request.u32()?;
+                let dfid = request.u32()?;
+                let name = request.string()?;
+                let renamed = self.fids.get(&fid)?.clone();
+                let mut to = self.fids.get(&dfid)?.path.clone();
+                to.push(name);
+                self.rename(&renamed.aname, &renamed.path, &to);
+            }
+            TRENAMEAT => {
+                let olddirfid = request.u32()?;
+                let oldname = request.string()?;
+                let newdirfid = request.u32()?;
+                let newname = request.string()?;
+                let olddir = self.fids.get(&olddirfid)?;
+                let aname = olddir.aname.clone();
+                let mut from = olddir.path.clone();
+                from.push(oldname);
+                let mut to = self.fids.get(&newdirfid)?.path.clone();
+                to.push(newname);
+                self.rename(&aname, &from, &to);
+            }
+            _ => {}
+        }
+        Some(())
+    }
+
+    // Moves the fids of the files at or below `from` in the tree `aname` to `to`.
+    fn rename(&mut self, aname: &str, from: &[String], to: &[String]) {
+        for fid in self.fids.values_mut() {
+            if fid.aname == aname && fid.path.starts_with(from) {
+                fid.path.splice(..from.len(), to.iter().cloned());
+            }
+        }
+    }
+
+    /// Recreates the fids of the table in `server`, which must be fresh, by replaying the
+    /// requests that lead to them. Fails if the file of a fid has disappeared from the host.
+    pub fn restore(&self, server: &mut p9::Server) -> anyhow::Result<()> {
+        let (msize, version) = match &self.version {
+            Some(version) => version,
+            // The guest never started a session.
+            None => return Ok(()),
+        };
+        call(
+            server,
+            Encoder::default()
+                .u32(*msize)
+                .string(version)
+                .finish(TVERSION, NOTAG),
+        )
+        .map_err(|e| anyhow!("failed to negotiate 9P version {}: {}", version, e))?;
+
+        // Two fids that the guest doesn't use, to walk from the root of the trees.
+        let mut unused_fids = (0..NOFID).rev().filter(|fid| !self.fids.contains_key(fid));
+        let walk_fids = [
+            unused_fids.next().expect("no unused fid"),
+            unused_fids.next().expect("no unused fid"),
+        ];
+        for (&fid, state) in &self.fids {
+            let path = format!("/{}", state.path.join("/"));
+            Self::restore_fid(server, fid, state, walk_fids).map_err(|e| {
+                if e.kind() == io::ErrorKind::NotFound {
+                    anyhow!("{} of fid {} has disappeared from the host", path, fid)
+                } else {
+                    anyhow!("failed to re-open {} for fid {}: {}", path, fid, e)
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    fn restore_fid(
+        server: &mut p9::Server,
+        fid: u32,
+        state: &Fid,
+        [mut from, mut to]: [u32; 2],
+    ) -> io::Result<()> {
+        call(
+            server,
+            Encoder::default()
+                .u32(from)
+                .u32(NOFID)
+                .string(&state.uname)
+                .string(&state.aname)
+                .u32(state.n_uname)
+                .finish(TATTACH, 0),
+        )?;
+        // Walk the names by chunks from one temporary fid to the other, and the last chunk to
+        // `fid` itself.
+        let chunks: Vec<&[String]> = if state.path.is_empty() {
+            vec![&[]]
+        } else {
+            state.path.chunks(MAX_WALK_NAMES).collect()
+        };
+        for (i, names) in chunks.iter().enumerate() {
+            let newfid = if i + 1 == chunks.len() { fid } else { to };
+            let mut walk = Encoder::default();
+            walk.u32(from).u32(newfid).u16(names.len() as u16);
+            for name in names.iter() {
+                walk.string(name);
+            }
+            let walked = call(server, walk.finish(TWALK, 0));
+            call(server, Encoder::default().u32(from).finish(TCLUNK, 0))?;
+            // The server stops at the first name that doesn't exist.
+            if Decoder(&walked?[HEADER_SIZE..]).u16() != Some(names.len() as u16) {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT));
+            }
+            std::mem::swap(&mut from, &mut to);
+        }
+        if let Some(flags) = state.open_flags {
+            call(
+                server,
+                Encoder::default().u32(fid).u32(flags).finish(TLOPEN, 0),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Sends `request` to `server`, and returns the response if the request succeeded.
+fn call(server: &mut p9::Server, request: Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut response = Vec::new();
+    server.handle_message(&mut Cursor::new(request), &mut response)?;
+    match response.get(4) {
+        Some(&RLERROR) => {
+            let ecode = Decoder(&response[HEADER_SIZE..])
+                .u32()
+                .unwrap_or(libc::EIO as u32);
+            Err(io::Error::from_raw_os_error(ecode as i32))
+        }
+        Some(_) if response.len() >= HEADER_SIZE => Ok(response),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "truncated 9P response",
+        )),
+    }
+}
+
+// Decodes the little-endian fields of a 9P message.
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.bytes(2)?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.bytes(4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).ok()
+    }
+}
+
+// Encodes the fields of a 9P message.
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn string(&mut self, value: &str) -> &mut Self {
+        self.u16(value.len() as u16);
+        self.0.extend_from_slice(value.as_bytes());
+        self
+    }
+
+    // Returns the message of type `message_type` with the encoded fields.
+    fn finish(&self, message_type: u8, tag: u16) -> Vec<u8> {
+        let size = (HEADER_SIZE + self.0.len()) as u32;
+        let mut message = Vec::with_capacity(size as usize);
+        message.extend_from_slice(&size.to_le_bytes());
+        message.push(message_type);
+        message.extend_from_slice(&tag.to_le_bytes());
+        message.extend_from_slice(&self.0);
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RLOPEN: u8 = TLOPEN + 1;
+    const RVERSION: u8 = TVERSION + 1;
+    const RWALK: u8 = TWALK + 1;
+
+    fn update(table: &mut FidTable, request: &Encoder, request_type: u8, response: &Encoder) {
+        let response_type = if response.0.is_empty() {
+            request_type + 1
+        } else {
+            response.0[0]
+        };
+        let response_body = response.0.get(1..).unwrap_or_default();
+        table.update(
+            &request.finish(request_type, 1),
+            &Encoder(response_body.to_vec()).finish(response_type, 1),
+        );
+    }
+
+    fn fid(table: &FidTable, fid: u32) -> Option<(String, Option<u32>)> {
+        table
+            .fids
+            .get(&fid)
+            .map(|f| (f.path.join("/"), f.open_flags))
+    }
+
+    #[test]
+    fn track_fids() {
+        let mut table = FidTable::default();
+        let mut version = Encoder::default();
+        version.u32(8192).string("9P2000.L");
+        let mut rversion = Encoder(vec![RVERSION]);
+        rversion.u32(8192).string("9P2000.L");
+        update(&mut table, &version, TVERSION, &rversion);
+        assert_eq!(table.version, Some((8192, "9P2000.L".to_string())));
+
+        let mut attach = Encoder::default();
+        attach.u32(0).u32(NOFID).string("root").string("").u32(0);
+        update(&mut table, &attach, TATTACH, &Encoder::default());
+        assert_eq!(fid(&table, 0), Some((String::new(), None)));
+
+        let mut walk = Encoder::default();
+        walk.u32(0).u32(1).u16(2).string("src").string("main.rs");
+        let mut rwalk = Encoder(vec![RWALK]);
+        rwalk.u16(2);
+        update(&mut table, &walk, TWALK, &rwalk);
+        assert_eq!(fid(&table, 1), Some(("src/main.rs".to_string(), None)));
+
+        // A partial walk doesn't create the new fid.
+        let mut walk = Encoder::default();
+        walk.u32(0).u32(2).u16(2).string("src").string("missing.rs");
+        let mut rwalk = Encoder(vec![RWALK]);
+        rwalk.u16(1);
+        update(&mut table, &walk, TWALK, &rwalk);
+        assert_eq!(fid(&table, 2), None);
+
+        let mut lopen = Encoder::default();
+        lopen.u32(1).u32(2);
+        update(&mut table, &lopen, TLOPEN, &Encoder(vec![RLOPEN]));
+        assert_eq!(fid(&table, 1), Some(("src/main.rs".to_string(), Some(2))));
+
+        let mut lcreate = Encoder::default();
+        lcreate
+            .u32(0)
+            .string("new.txt")
+            .u32(0o1102)
+            .u32(0o644)
+            .u32(0);
+        update(&mut table, &lcreate, TLCREATE, &Encoder::default());
+        assert_eq!(fid(&table, 0), Some(("new.txt".to_string(), Some(2))));
+
+        // Renaming a directory moves the fids below it.
+        let mut attach = Encoder::default();
+        attach.u32(3).u32(NOFID).string("root").string("").u32(0);
+        update(&mut table, &attach, TATTACH, &Encoder::default());
+        let mut renameat = Encoder::default();
+        renameat.u32(3).string("src").u32(3).string("lib");
+        update(&mut table, &renameat, TRENAMEAT, &Encoder::default());
+        assert_eq!(fid(&table, 1), Some(("lib/main.rs".to_string(), Some(2))));
+
+        // Failed requests don't change the fids, except for the ones releasing them.
+        let mut rlerror = Encoder(vec![RLERROR]);
+        rlerror.u32(libc::EIO as u32);
+        update(&mut table, &lopen, TLOPEN, &rlerror);
+        let mut clunk = Encoder::default();
+        clunk.u32(1);
+        update(&mut table, &clunk, TCLUNK, &rlerror);
+        assert_eq!(fid(&table, 1), None);
+
+        update(&mut table, &version, TVERSION, &rversion);
+        assert!(table.fids.is_empty());
+    }
+}
@@ -5,35 +5,52 @@
 use std::cmp;
 use std::io::Read;
 use std::io::Write;
+use std::sync::Arc;
 
 use base::warn;
 use data_model::Be16;
 use data_model::Be32;
 use data_model::Be64;
+use sync::Mutex;
 use zerocopy::AsBytes;
 use zerocopy::FromBytes;
 use zerocopy::FromZeroes;
 
+use crate::virtio::scsi::constants::GET_CONFIGURATION;
+use crate::virtio::scsi::constants::GET_EVENT_STATUS_NOTIFICATION;
 use crate::virtio::scsi::constants::INQUIRY;
 use crate::virtio::scsi::constants::MAINTENANCE_IN;
 use crate::virtio::scsi::constants::MODE_SELECT_6;
 use crate::virtio::scsi::constants::MODE_SENSE_6;
+use crate::virtio::scsi::constants::PREVENT_ALLOW_MEDIUM_REMOVAL;
 use crate::virtio::scsi::constants::READ_10;
+use crate::virtio::scsi::constants::READ_12;
+use crate::virtio::scsi::constants::READ_16;
 use crate::virtio::scsi::constants::READ_6;
 use crate::virtio::scsi::constants::READ_CAPACITY_10;
 use crate::virtio::scsi::constants::READ_CAPACITY_16;
+use crate::virtio::scsi::constants::READ_TOC;
 use crate::virtio::scsi::constants::REPORT_LUNS;
 use crate::virtio::scsi::constants::REPORT_SUPPORTED_TASK_MANAGEMENT_FUNCTIONS;
 use crate::virtio::scsi::constants::SERVICE_ACTION_IN_16;
+use crate::virtio::scsi::constants::START_STOP_UNIT;
 use crate::virtio::scsi::constants::SYNCHRONIZE_CACHE_10;
+use crate::virtio::scsi::constants::SYNCHRONIZE_CACHE_16;
 use crate::virtio::scsi::constants::TEST_UNIT_READY;
 use crate::virtio::scsi::constants::TYPE_DISK;
+use crate::virtio::scsi::constants::TYPE_ROM;
 use crate::virtio::scsi::constants::UNMAP;
+use crate::virtio::scsi::constants::VERIFY_10;
+use crate::virtio::scsi::constants::VERIFY_16;
 use crate::virtio::scsi::constants::WRITE_10;
+use crate::virtio::scsi::constants::WRITE_16;
 use crate::virtio::scsi::constants::WRITE_SAME_10;
 use crate::virtio::scsi::constants::WRITE_SAME_16;
+use crate::virtio::scsi::device::single_level_lun;
 use crate::virtio::scsi::device::AsyncLogicalUnit;
+use crate::virtio::scsi::device::CdromState;
 use crate::virtio::scsi::device::ExecuteError;
+use crate::virtio::scsi::device::CDROM_BLOCK_SIZE;
 use crate::virtio::Reader;
 use crate::virtio::Writer;
 
@@ -49,11 +66,22 @@ pub enum Command {
     Read10(Read10),
     Write10(Write10),
     SynchronizeCache10(SynchronizeCache10),
+    Read16(Read16),
+    Write16(Write16),
+    Verify10(Verify10),
+    Verify16(Verify16),
+    SynchronizeCache16(SynchronizeCache16),
     WriteSame10(WriteSame10),
     Unmap(Unmap),
     WriteSame16(WriteSame16),
     ReportLuns(ReportLuns),
     ReportSupportedTMFs(ReportSupportedTMFs),
+    StartStopUnit(StartStopUnit),
+    PreventAllowMediumRemoval(PreventAllowMediumRemoval),
+    ReadToc(ReadToc),
+    GetConfiguration(GetConfiguration),
+    GetEventStatusNotification(GetEventStatusNotification),
+    Read12(Read12),
 }
 
 impl Command {
@@ -69,12 +97,27 @@ impl Command {
             READ_10 => Ok(Self::Read10(Self::parse_command(cdb)?)),
             WRITE_10 => Ok(Self::Write10(Self::parse_command(cdb)?)),
             SYNCHRONIZE_CACHE_10 => Ok(Self::SynchronizeCache10(Self::parse_command(cdb)?)),
+            READ_16 => Ok(Self::Read16(Self::parse_command(cdb)?)),
+            WRITE_16 => Ok(Self::Write16(Self::parse_command(cdb)?)),
+            VERIFY_10 => Ok(Self::Verify10(Self::parse_command(cdb)?)),
+            VERIFY_16 => Ok(Self::Verify16(Self::parse_command(cdb)?)),
+            SYNCHRONIZE_CACHE_16 => Ok(Self::SynchronizeCache16(Self::parse_command(cdb)?)),
             WRITE_SAME_10 => Ok(Self::WriteSame10(Self::parse_command(cdb)?)),
             UNMAP => Ok(Self::Unmap(Self::parse_command(cdb)?)),
             WRITE_SAME_16 => Ok(Self::WriteSame16(Self::parse_command(cdb)?)),
             SERVICE_ACTION_IN_16 => Self::parse_service_action_in_16(cdb),
             REPORT_LUNS => Ok(Self::ReportLuns(Self::parse_command(cdb)?)),
             MAINTENANCE_IN => Self::parse_maintenance_in(cdb),
+            START_STOP_UNIT => Ok(Self::StartStopUnit(Self::parse_command(cdb)?)),
+            PREVENT_ALLOW_MEDIUM_REMOVAL => {
+                Ok(Self::PreventAllowMediumRemoval(Self::parse_command(cdb)?))
+            }
+            READ_TOC => Ok(Self::ReadToc(Self::parse_command(cdb)?)),
+            GET_CONFIGURATION => Ok(Self::GetConfiguration(Self::parse_command(cdb)?)),
+            GET_EVENT_STATUS_NOTIFICATION => {
+                Ok(Self::GetEventStatusNotification(Self::parse_command(cdb)?))
+            }
+            READ_12 => Ok(Self::Read12(Self::parse_command(cdb)?)),
             _ => {
                 warn!("SCSI command {:#x?} is not implemented", op);
                 Err(ExecuteError::Unsupported(op))
@@ -131,8 +174,17 @@ impl Command {
         &self,
         reader: &mut Reader,
         writer: &mut Writer,
-        dev: &AsyncLogicalUnit,
+        dev: Option<&AsyncLogicalUnit>,
+        luns: &[u16],
     ) -> Result<(), ExecuteError> {
+        // Only INQUIRY and REPORT LUNS are valid for a LUN without a logical unit.
+        let dev = match (self, dev) {
+            (_, Some(dev)) => dev,
+            (Self::Inquiry(inquiry), None) => return inquiry.emulate_lun_not_present(writer),
+            (Self::ReportLuns(report_luns), None) => return report_luns.emulate(writer, luns),
+            (_, None) => return Err(ExecuteError::LogicalUnitNotSupported),
+        };
+        self.check_medium(dev)?;
         match self {
             Self::TestUnitReady(_) => Ok(()), // noop as the device is ready.
             Self::Read6(read6) => read6.emulate(writer, dev).await,
@@ -146,17 +198,72 @@ impl Command {
             Self::SynchronizeCache10(synchronize_cache_10) => {
                 synchronize_cache_10.emulate(dev).await
             }
+            Self::Read16(read_16) => read_16.emulate(writer, dev).await,
+            Self::Write16(write_16) => write_16.emulate(reader, dev).await,
+            Self::Verify10(verify_10) => verify_10.emulate(reader, dev).await,
+            Self::Verify16(verify_16) => verify_16.emulate(reader, dev).await,
+            Self::SynchronizeCache16(synchronize_cache_16) => {
+                synchronize_cache_16.emulate(dev).await
+            }
             Self::WriteSame10(write_same_10) => write_same_10.emulate(reader, dev).await,
             Self::Unmap(unmap) => unmap.emulate(reader, dev).await,
             Self::WriteSame16(write_same_16) => write_same_16.emulate(reader, dev).await,
-            Self::ReportLuns(report_luns) => report_luns.emulate(writer),
+            Self::ReportLuns(report_luns) => report_luns.emulate(writer, luns),
             Self::ReportSupportedTMFs(report_supported_tmfs) => {
                 report_supported_tmfs.emulate(writer)
             }
+            Self::StartStopUnit(start_stop_unit) => start_stop_unit.emulate(dev),
+            Self::PreventAllowMediumRemoval(prevent_allow_medium_removal) => {
+                prevent_allow_medium_removal.emulate(dev)
+            }
+            Self::ReadToc(read_toc) => read_toc.emulate(writer, dev),
+            Self::GetConfiguration(get_configuration) => get_configuration.emulate(writer, dev),
+            Self::GetEventStatusNotification(get_event_status_notification) => {
+                get_event_status_notification.emulate(writer, dev)
+            }
+            Self::Read12(read_12) => read_12.emulate(writer, dev).await,
+        }
+    }
+
+    // Reports the medium changes of CD-ROM drives, and fails the commands that need a medium when
+    // there is none.
+    fn check_medium(&self, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let cdrom = match &dev.cdrom {
+            Some(cdrom) => cdrom,
+            None => return Ok(()),
+        };
+        match self {
+            // These commands report the state of the drive, and don't clear the unit attention.
+            Self::Inquiry(_)
+            | Self::ReportLuns(_)
+            | Self::GetConfiguration(_)
+            | Self::GetEventStatusNotification(_) => return Ok(()),
+            _ => {}
+        }
+        if std::mem::take(&mut cdrom.lock().medium_changed) {
+            return Err(ExecuteError::MediumMayHaveChanged);
+        }
+        match self {
+            // These commands don't access the medium.
+            Self::ModeSelect6(_)
+            | Self::ModeSense6(_)
+            | Self::ReportSupportedTMFs(_)
+            | Self::StartStopUnit(_)
+            | Self::PreventAllowMediumRemoval(_) => Ok(()),
+            _ => dev.disk().map(|_| ()),
         }
     }
 }
 
+// Returns the state of the CD-ROM drive `dev`, or an error if the MMC command `opcode` is sent to
+// another kind of logical unit.
+fn cdrom_state(
+    dev: &AsyncLogicalUnit,
+    opcode: u8,
+) -> Result<&Arc<Mutex<CdromState>>, ExecuteError> {
+    dev.cdrom.as_ref().ok_or(ExecuteError::Unsupported(opcode))
+}
+
 #[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
 #[repr(C, packed)]
 pub struct TestUnitReady {
@@ -190,7 +297,7 @@ async fn read_from_disk(
     let offset = lba * block_size as u64;
     let before = writer.bytes_written();
     writer
-        .write_all_from_at_fut(&*dev.disk_image, count, offset)
+        .write_all_from_at_fut(dev.disk()?, count, offset)
         .await
         .map_err(|desc_error| {
             let resid = count - (writer.bytes_written() - before);
@@ -274,9 +381,13 @@ impl Inquiry {
         let alloc_len = self.alloc_len();
         let mut outbuf = vec![0u8; cmp::max(writer.available_bytes(), alloc_len)];
         // Peripheral
-        outbuf[0] = TYPE_DISK;
-        // Removable bit. We currently do not support removable SCSI devices.
-        outbuf[1] = 0x0;
+        outbuf[0] = if dev.cdrom.is_some() {
+            TYPE_ROM
+        } else {
+            TYPE_DISK
+        };
+        // Removable bit. Only CD-ROM drives have a removable medium.
+        outbuf[1] = if dev.cdrom.is_some() { 0x80 } else { 0x0 };
         // Version 0x5 indicates that the device complies to SPC-3.
         outbuf[2] = 0x5;
         // Hierarchical Support | Response Data Format
@@ -294,7 +405,12 @@ impl Inquiry {
         // Vendor
         Self::fill_left_aligned_ascii(&mut outbuf[8..16], "CROSVM");
         // Product ID
-        Self::fill_left_aligned_ascii(&mut outbuf[16..32], "CROSVM HARDDISK");
+        let product_id = if dev.cdrom.is_some() {
+            "CROSVM CD-ROM"
+        } else {
+            "CROSVM HARDDISK"
+        };
+        Self::fill_left_aligned_ascii(&mut outbuf[16..32], product_id);
         // Product revision level
         Self::fill_left_aligned_ascii(&mut outbuf[32..36], "0.1");
 
@@ -303,6 +419,19 @@ impl Inquiry {
             .map_err(ExecuteError::Write)
     }
 
+    // Emulates INQUIRY for a LUN of an existing target that has no logical unit attached.
+    fn emulate_lun_not_present(&self, writer: &mut Writer) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "INQUIRY");
+        let alloc_len = self.alloc_len();
+        let mut outbuf = vec![0u8; alloc_len];
+        if let Some(peripheral) = outbuf.first_mut() {
+            // Peripheral qualifier 011b: the target is not capable of supporting a device on
+            // this logical unit. Peripheral device type 0x1f: unknown or no device type.
+            *peripheral = 0x7f;
+        }
+        writer.write_all(&outbuf).map_err(ExecuteError::Write)
+    }
+
     fn emulate_vital_product_data_page(
         &self,
         writer: &mut Writer,
@@ -365,8 +494,10 @@ impl Inquiry {
                 outbuf[20..24].fill(0xff);
                 // Maximum unmap block descriptor count
                 outbuf[24..28].fill(0xff);
-                // Optimal unmap granularity
-                outbuf[28..32].copy_from_slice(&128u32.to_be_bytes());
+                // Optimal unmap granularity: the allocation unit of the disk image.
+                outbuf[28..32].copy_from_slice(&dev.unmap_granularity.to_be_bytes());
+                // Unmap granularity alignment: the allocation units start at LBA 0.
+                outbuf[32] = 0x80;
                 // Maximum WRITE SAME length
                 outbuf[36..44].copy_from_slice(&dev.max_lba.to_be_bytes());
             }
@@ -830,7 +961,7 @@ async fn write_to_disk(
     let offset = lba * block_size as u64;
     let before = reader.bytes_read();
     reader
-        .read_exact_to_at_fut(&*dev.disk_image, count, offset)
+        .read_exact_to_at_fut(dev.disk()?, count, offset)
         .await
         .map_err(|desc_error| {
             let resid = count - (reader.bytes_read() - before);
@@ -852,13 +983,232 @@ pub struct SynchronizeCache10 {
 impl SynchronizeCache10 {
     async fn emulate(&self, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
         let _trace = cros_tracing::trace_event!(VirtioScsi, "SYNCHRONIZE_CACHE(10)");
-        if dev.read_only {
-            return Err(ExecuteError::ReadOnly);
+        synchronize_cache(dev).await
+    }
+}
+
+async fn synchronize_cache(dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+    if dev.read_only {
+        return Err(ExecuteError::ReadOnly);
+    }
+    dev.disk()?.fdatasync().await.map_err(|e| {
+        warn!("failed to sync: {e}");
+        ExecuteError::SynchronizationError
+    })
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct Read16 {
+    opcode: u8,
+    rdprotect: u8,
+    lba_bytes: [u8; 8],
+    xfer_len_bytes: [u8; 4],
+    group_number: u8,
+    control: u8,
+}
+
+impl Read16 {
+    fn xfer_len(&self) -> usize {
+        u32::from_be_bytes(self.xfer_len_bytes) as usize
+    }
+
+    fn lba(&self) -> u64 {
+        u64::from_be_bytes(self.lba_bytes)
+    }
+
+    async fn emulate(
+        &self,
+        writer: &mut Writer,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let xfer_len = self.xfer_len();
+        let lba = self.lba();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "READ(16)", lba, xfer_len);
+        read_from_disk(writer, dev, xfer_len, lba).await
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct Write16 {
+    opcode: u8,
+    wrprotect: u8,
+    lba_bytes: [u8; 8],
+    xfer_len_bytes: [u8; 4],
+    group_number: u8,
+    control: u8,
+}
+
+impl Write16 {
+    fn lba(&self) -> u64 {
+        u64::from_be_bytes(self.lba_bytes)
+    }
+
+    fn xfer_len(&self) -> usize {
+        u32::from_be_bytes(self.xfer_len_bytes) as usize
+    }
+
+    async fn emulate(
+        &self,
+        reader: &mut Reader,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let xfer_len = self.xfer_len();
+        let lba = self.lba();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "WRITE(16)", lba, xfer_len);
+        write_to_disk(reader, dev, xfer_len, lba).await
+    }
+}
+
+// How VERIFY commands check the blocks, given by their BYTCHK field.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ByteCheck {
+    // Only verify the medium.
+    None,
+    // Compare each block with the next block of the Data-Out buffer.
+    Blocks,
+    // Compare each block with the single block of the Data-Out buffer.
+    SingleBlock,
+}
+
+impl ByteCheck {
+    fn from_field(vrprotect_bytchk: u8) -> Result<Self, ExecuteError> {
+        match (vrprotect_bytchk >> 1) & 0x3 {
+            0b00 => Ok(Self::None),
+            0b01 => Ok(Self::Blocks),
+            0b11 => Ok(Self::SingleBlock),
+            // 0b10 is reserved.
+            _ => Err(ExecuteError::InvalidField),
+        }
+    }
+}
+
+async fn verify(
+    reader: &mut Reader,
+    dev: &AsyncLogicalUnit,
+    nblocks: usize,
+    lba: u64,
+    byte_check: ByteCheck,
+) -> Result<(), ExecuteError> {
+    check_lba_range(dev.max_lba, lba, nblocks)?;
+    let disk = dev.disk()?;
+    // The disk image has no medium defects to look for, so there is nothing more to verify
+    // without data to compare.
+    if byte_check == ByteCheck::None {
+        return Ok(());
+    }
+    let block_size = dev.block_size as usize;
+    let mut expected = vec![0u8; block_size];
+    if byte_check == ByteCheck::SingleBlock {
+        reader
+            .read_exact(&mut expected)
+            .map_err(ExecuteError::Read)?;
+    }
+    let mut block = vec![0u8; block_size];
+    for lba in lba..lba + nblocks as u64 {
+        if byte_check == ByteCheck::Blocks {
+            reader
+                .read_exact(&mut expected)
+                .map_err(ExecuteError::Read)?;
+        }
+        disk.read_double_buffered(lba * block_size as u64, &mut block)
+            .await
+            .map_err(ExecuteError::VerifyIo)?;
+        if block != expected {
+            return Err(ExecuteError::Miscompare);
         }
-        dev.disk_image.fdatasync().await.map_err(|e| {
-            warn!("failed to sync: {e}");
-            ExecuteError::SynchronizationError
-        })
+    }
+    Ok(())
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct Verify10 {
+    opcode: u8,
+    vrprotect_bytchk: u8,
+    lba_bytes: [u8; 4],
+    group_number: u8,
+    verif_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl Verify10 {
+    fn lba(&self) -> u64 {
+        u32::from_be_bytes(self.lba_bytes) as u64
+    }
+
+    fn verif_len(&self) -> usize {
+        u16::from_be_bytes(self.verif_len_bytes) as usize
+    }
+
+    fn byte_check(&self) -> Result<ByteCheck, ExecuteError> {
+        ByteCheck::from_field(self.vrprotect_bytchk)
+    }
+
+    async fn emulate(
+        &self,
+        reader: &mut Reader,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let verif_len = self.verif_len();
+        let lba = self.lba();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "VERIFY(10)", lba, verif_len);
+        verify(reader, dev, verif_len, lba, self.byte_check()?).await
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct Verify16 {
+    opcode: u8,
+    vrprotect_bytchk: u8,
+    lba_bytes: [u8; 8],
+    verif_len_bytes: [u8; 4],
+    group_number: u8,
+    control: u8,
+}
+
+impl Verify16 {
+    fn lba(&self) -> u64 {
+        u64::from_be_bytes(self.lba_bytes)
+    }
+
+    fn verif_len(&self) -> usize {
+        u32::from_be_bytes(self.verif_len_bytes) as usize
+    }
+
+    fn byte_check(&self) -> Result<ByteCheck, ExecuteError> {
+        ByteCheck::from_field(self.vrprotect_bytchk)
+    }
+
+    async fn emulate(
+        &self,
+        reader: &mut Reader,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let verif_len = self.verif_len();
+        let lba = self.lba();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "VERIFY(16)", lba, verif_len);
+        verify(reader, dev, verif_len, lba, self.byte_check()?).await
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct SynchronizeCache16 {
+    opcode: u8,
+    immed_byte: u8,
+    lba_bytes: [u8; 8],
+    block_num_bytes: [u8; 4],
+    group_number: u8,
+    control: u8,
+}
+
+impl SynchronizeCache16 {
+    async fn emulate(&self, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "SYNCHRONIZE_CACHE(16)");
+        synchronize_cache(dev).await
     }
 }
 
@@ -867,7 +1217,7 @@ async fn unmap(dev: &AsyncLogicalUnit, lba: u64, nblocks: u64) -> Result<(), Exe
     let offset = lba * dev.block_size as u64;
     let length = nblocks * dev.block_size as u64;
     // Ignore the errors here since the device is not strictly required to unmap the LBAs.
-    let _ = dev.disk_image.punch_hole(offset, length).await;
+    let _ = dev.disk()?.punch_hole(offset, length).await;
     Ok(())
 }
 
@@ -885,7 +1235,7 @@ async fn write_same(
         let block_size = dev.block_size as u64;
         // Ignore the errors here since the device is not strictly required to unmap the LBAs.
         let _ = dev
-            .disk_image
+            .disk()?
             .write_zeroes_at(lba * block_size, nblocks * block_size)
             .await;
         Ok(())
@@ -1085,22 +1435,32 @@ impl ReportLuns {
         u32::from_be_bytes(self.alloc_len_bytes) as usize
     }
 
-    fn emulate(&self, writer: &mut Writer) -> Result<(), ExecuteError> {
+    // Builds the LUN list parameter data for the given LUNs.
+    fn lun_list(luns: &[u16]) -> Vec<u8> {
+        let lun_list_len = (luns.len() * 8) as u32;
+        let mut outbuf = Vec::with_capacity(8 + luns.len() * 8);
+        outbuf.extend_from_slice(&lun_list_len.to_be_bytes());
+        // Reserved
+        outbuf.extend_from_slice(&[0; 4]);
+        for &lun in luns {
+            outbuf.extend_from_slice(&single_level_lun(lun).to_be_bytes());
+            outbuf.extend_from_slice(&[0; 6]);
+        }
+        outbuf
+    }
+
+    fn emulate(&self, writer: &mut Writer, luns: &[u16]) -> Result<(), ExecuteError> {
         let _trace = cros_tracing::trace_event!(VirtioScsi, "REPORT_LUNS");
         // We need at least 16 bytes.
         if self.alloc_len() < 16 {
             return Err(ExecuteError::InvalidField);
         }
-        // Each LUN takes 8 bytes and we only support LUN0.
-        let lun_list_len = 8u32;
+        let outbuf = Self::lun_list(luns);
+        // Truncate the list if it doesn't fit in the allocation length. The LUN list length
+        // still tells the driver how many bytes it needs to get the whole list.
+        let len = cmp::min(outbuf.len(), self.alloc_len());
         writer
-            .write_all(&lun_list_len.to_be_bytes())
-            .map_err(ExecuteError::Write)?;
-        let reserved = [0; 4];
-        writer.write_all(&reserved).map_err(ExecuteError::Write)?;
-        let lun0 = 0u64;
-        writer
-            .write_all(&lun0.to_be_bytes())
+            .write_all(&outbuf[..len])
             .map_err(ExecuteError::Write)
     }
 }
@@ -1140,6 +1500,395 @@ impl ReportSupportedTMFs {
     }
 }
 
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct StartStopUnit {
+    opcode: u8,
+    immed_byte: u8,
+    _reserved: u8,
+    power_condition_modifier: u8,
+    start_byte: u8,
+    control: u8,
+}
+
+impl StartStopUnit {
+    fn power_condition(&self) -> u8 {
+        self.start_byte >> 4
+    }
+
+    fn load_eject(&self) -> bool {
+        self.start_byte & 0x2 != 0
+    }
+
+    fn start(&self) -> bool {
+        self.start_byte & 0x1 != 0
+    }
+
+    fn emulate(&self, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "START_STOP_UNIT");
+        // Starting and stopping the logical unit has no effect, only loading and ejecting the
+        // medium does.
+        if self.power_condition() != 0 || !self.load_eject() {
+            return Ok(());
+        }
+        let mut cdrom = cdrom_state(dev, START_STOP_UNIT)?.lock();
+        if !self.start() && cdrom.removal_prevented {
+            return Err(ExecuteError::MediumRemovalPrevented);
+        }
+        // The medium stays in the drive, but it can't be accessed while the tray is open.
+        cdrom.tray_open = !self.start();
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct PreventAllowMediumRemoval {
+    opcode: u8,
+    _reserved: [u8; 3],
+    prevent_byte: u8,
+    control: u8,
+}
+
+impl PreventAllowMediumRemoval {
+    fn prevent(&self) -> bool {
+        self.prevent_byte & 0x3 != 0
+    }
+
+    fn emulate(&self, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "PREVENT_ALLOW_MEDIUM_REMOVAL");
+        // Nothing to do for logical units without a removable medium.
+        if let Some(cdrom) = &dev.cdrom {
+            cdrom.lock().removal_prevented = self.prevent();
+        }
+        Ok(())
+    }
+}
+
+// Returns the address of the block `lba` as expected by MMC commands, either as is or in the
+// minute, second and frame format if `msf` is set.
+fn cd_address(lba: u32, msf: bool) -> [u8; 4] {
+    if !msf {
+        return lba.to_be_bytes();
+    }
+    // There are 75 frames per second, and the first track starts after two seconds.
+    let frame = lba.saturating_add(150);
+    [
+        0,
+        (frame / (75 * 60)).try_into().unwrap_or(u8::MAX),
+        ((frame / 75) % 60) as u8,
+        (frame % 75) as u8,
+    ]
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct ReadToc {
+    opcode: u8,
+    msf_byte: u8,
+    format_byte: u8,
+    _reserved: [u8; 3],
+    track_number: u8,
+    alloc_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl ReadToc {
+    fn msf(&self) -> bool {
+        self.msf_byte & 0x2 != 0
+    }
+
+    fn format(&self) -> u8 {
+        self.format_byte & 0xf
+    }
+
+    fn alloc_len(&self) -> usize {
+        u16::from_be_bytes(self.alloc_len_bytes) as usize
+    }
+
+    fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "READ_TOC");
+        cdrom_state(dev, READ_TOC)?;
+        let outbuf = match self.format() {
+            // Formatted TOC
+            0x0 => Self::toc(dev.max_lba, self.track_number, self.msf())?,
+            // Multi-session information
+            0x1 => Self::session_info(self.msf()),
+            format => {
+                warn!("READ TOC format {:#x?} is not supported", format);
+                return Err(ExecuteError::InvalidField);
+            }
+        };
+        let len = cmp::min(outbuf.len(), self.alloc_len());
+        writer
+            .write_all(&outbuf[..len])
+            .map_err(ExecuteError::Write)
+    }
+
+    // Builds the TOC of a disc of `blocks` blocks, starting at the track `track_number`. The disc
+    // has a single data track followed by the lead-out area.
+    fn toc(blocks: u64, track_number: u8, msf: bool) -> Result<Vec<u8>, ExecuteError> {
+        const LEAD_OUT_TRACK: u8 = 0xaa;
+        if track_number > 1 && track_number != LEAD_OUT_TRACK {
+            return Err(ExecuteError::InvalidField);
+        }
+        // The data length is filled at the end. The first and last tracks are track 1.
+        let mut outbuf = vec![0, 0, 1, 1];
+        // Each track descriptor has its ADR (1: the Q sub-channel encodes the current position)
+        // and CONTROL (4: data track, recorded uninterrupted) fields, its number and its start
+        // address.
+        if track_number <= 1 {
+            outbuf.extend_from_slice(&[0, 0x14, 1, 0]);
+            outbuf.extend_from_slice(&cd_address(0, msf));
+        }
+        outbuf.extend_from_slice(&[0, 0x14, LEAD_OUT_TRACK, 0]);
+        outbuf.extend_from_slice(&cd_address(blocks.try_into().unwrap_or(u32::MAX), msf));
+        // The data length doesn't include the data length field.
+        let data_len = (outbuf.len() - 2) as u16;
+        outbuf[..2].copy_from_slice(&data_len.to_be_bytes());
+        Ok(outbuf)
+    }
+
+    // Builds the multi-session information of a disc with a single session.
+    fn session_info(msf: bool) -> Vec<u8> {
+        // Data length, first and last sessions.
+        let mut outbuf = vec![0, 0x0a, 1, 1];
+        // The first track of the last session, which is track 1.
+        outbuf.extend_from_slice(&[0, 0x14, 1, 0]);
+        outbuf.extend_from_slice(&cd_address(0, msf));
+        outbuf
+    }
+}
+
+// The MMC profiles of the media read by CD-ROM drives.
+const PROFILE_CD_ROM: u16 = 0x0008;
+const PROFILE_DVD_ROM: u16 = 0x0010;
+
+// The number of blocks of an 80-minute CD. Larger media are reported as DVDs.
+const CD_MAX_BLOCKS: u64 = 80 * 60 * 75;
+
+// A feature descriptor returned by GET CONFIGURATION.
+struct Feature {
+    code: u16,
+    version: u8,
+    // Whether the feature is always current.
+    persistent: bool,
+    current: bool,
+    data: Vec<u8>,
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct GetConfiguration {
+    opcode: u8,
+    rt_byte: u8,
+    starting_feature_bytes: [u8; 2],
+    _reserved: [u8; 3],
+    alloc_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl GetConfiguration {
+    fn rt(&self) -> u8 {
+        self.rt_byte & 0x3
+    }
+
+    fn starting_feature(&self) -> u16 {
+        u16::from_be_bytes(self.starting_feature_bytes)
+    }
+
+    fn alloc_len(&self) -> usize {
+        u16::from_be_bytes(self.alloc_len_bytes) as usize
+    }
+
+    fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "GET_CONFIGURATION");
+        cdrom_state(dev, GET_CONFIGURATION)?;
+        let outbuf = self.configuration(dev.medium_present().then_some(dev.max_lba))?;
+        let len = cmp::min(outbuf.len(), self.alloc_len());
+        writer
+            .write_all(&outbuf[..len])
+            .map_err(ExecuteError::Write)
+    }
+
+    // Builds the feature header and the feature descriptors selected by the RT and STARTING
+    // FEATURE NUMBER fields, for a drive with a medium of `medium_blocks` blocks if any.
+    fn configuration(&self, medium_blocks: Option<u64>) -> Result<Vec<u8>, ExecuteError> {
+        let current_profile = match medium_blocks {
+            Some(blocks) if blocks > CD_MAX_BLOCKS => PROFILE_DVD_ROM,
+            Some(_) => PROFILE_CD_ROM,
+            None => 0,
+        };
+        let mut profile_list = Vec::new();
+        for profile in [PROFILE_DVD_ROM, PROFILE_CD_ROM] {
+            profile_list.extend_from_slice(&profile.to_be_bytes());
+            profile_list.extend_from_slice(&[(profile == current_profile) as u8, 0]);
+        }
+        // Logical block size and number of blocks per ECC block.
+        let mut random_readable = CDROM_BLOCK_SIZE.to_be_bytes().to_vec();
+        let blocking: u16 = if current_profile == PROFILE_DVD_ROM {
+            16
+        } else {
+            1
+        };
+        random_readable.extend_from_slice(&blocking.to_be_bytes());
+        random_readable.extend_from_slice(&[0, 0]);
+        let features = [
+            // Profile List
+            Feature {
+                code: 0x0000,
+                version: 0,
+                persistent: true,
+                current: true,
+                data: profile_list,
+            },
+            // Core: the physical interface is SCSI.
+            Feature {
+                code: 0x0001,
+                version: 0,
+                persistent: true,
+                current: true,
+                data: vec![0, 0, 0, 1],
+            },
+            // Removable Medium: tray loading mechanism, with an ejectable and lockable medium.
+            Feature {
+                code: 0x0003,
+                version: 0,
+                persistent: true,
+                current: true,
+                data: vec![0x29, 0, 0, 0],
+            },
+            // Random Readable
+            Feature {
+                code: 0x0010,
+                version: 0,
+                persistent: false,
+                current: current_profile != 0,
+                data: random_readable,
+            },
+        ];
+
+        // The data length is filled at the end.
+        let mut outbuf = vec![0; 8];
+        outbuf[6..8].copy_from_slice(&current_profile.to_be_bytes());
+        let starting_feature = self.starting_feature();
+        for feature in features {
+            let selected = match self.rt() {
+                // All the features from the starting feature.
+                0x0 => feature.code >= starting_feature,
+                // The current features from the starting feature.
+                0x1 => feature.code >= starting_feature && feature.current,
+                // Only the starting feature.
+                0x2 => feature.code == starting_feature,
+                _ => return Err(ExecuteError::InvalidField),
+            };
+            if !selected {
+                continue;
+            }
+            outbuf.extend_from_slice(&feature.code.to_be_bytes());
+            outbuf.push(
+                feature.version << 2 | (feature.persistent as u8) << 1 | feature.current as u8,
+            );
+            outbuf.push(feature.data.len() as u8);
+            outbuf.extend_from_slice(&feature.data);
+        }
+        // The data length doesn't include the data length field.
+        let data_len = (outbuf.len() - 4) as u32;
+        outbuf[..4].copy_from_slice(&data_len.to_be_bytes());
+        Ok(outbuf)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct GetEventStatusNotification {
+    opcode: u8,
+    polled_byte: u8,
+    _reserved: [u8; 2],
+    notification_class_request: u8,
+    _reserved2: [u8; 2],
+    alloc_len_bytes: [u8; 2],
+    control: u8,
+}
+
+impl GetEventStatusNotification {
+    fn polled(&self) -> bool {
+        self.polled_byte & 0x1 != 0
+    }
+
+    fn alloc_len(&self) -> usize {
+        u16::from_be_bytes(self.alloc_len_bytes) as usize
+    }
+
+    fn emulate(&self, writer: &mut Writer, dev: &AsyncLogicalUnit) -> Result<(), ExecuteError> {
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "GET_EVENT_STATUS_NOTIFICATION");
+        const MEDIA_CLASS: u8 = 4;
+        let cdrom = cdrom_state(dev, GET_EVENT_STATUS_NOTIFICATION)?;
+        // Asynchronous notifications are not supported.
+        if !self.polled() {
+            return Err(ExecuteError::InvalidField);
+        }
+        // The event data length is filled at the end. Only media events are supported.
+        let mut outbuf = vec![0, 0, 0, 1 << MEDIA_CLASS];
+        if self.notification_class_request & (1 << MEDIA_CLASS) != 0 {
+            outbuf[2] = MEDIA_CLASS;
+            let mut cdrom = cdrom.lock();
+            let media_event = std::mem::take(&mut cdrom.media_event);
+            // Door or Tray Open | Media Present
+            let media_status = if cdrom.tray_open {
+                0x1
+            } else if dev.disk_image.is_some() {
+                0x2
+            } else {
+                0x0
+            };
+            outbuf.extend_from_slice(&[media_event as u8, media_status, 0, 0]);
+        } else {
+            // No Event Available
+            outbuf[2] = 0x80;
+        }
+        // The event data length doesn't include the event header.
+        let data_len = (outbuf.len() - 4) as u16;
+        outbuf[..2].copy_from_slice(&data_len.to_be_bytes());
+        let len = cmp::min(outbuf.len(), self.alloc_len());
+        writer
+            .write_all(&outbuf[..len])
+            .map_err(ExecuteError::Write)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, AsBytes, FromZeroes, FromBytes, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct Read12 {
+    opcode: u8,
+    rdprotect: u8,
+    lba_bytes: [u8; 4],
+    xfer_len_bytes: [u8; 4],
+    group_number: u8,
+    control: u8,
+}
+
+impl Read12 {
+    fn xfer_len(&self) -> usize {
+        u32::from_be_bytes(self.xfer_len_bytes) as usize
+    }
+
+    fn lba(&self) -> u64 {
+        u32::from_be_bytes(self.lba_bytes) as u64
+    }
+
+    async fn emulate(
+        &self,
+        writer: &mut Writer,
+        dev: &AsyncLogicalUnit,
+    ) -> Result<(), ExecuteError> {
+        let xfer_len = self.xfer_len();
+        let lba = self.lba();
+        let _trace = cros_tracing::trace_event!(VirtioScsi, "READ(12)", lba, xfer_len);
+        read_from_disk(writer, dev, xfer_len, lba).await
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1247,6 +1996,85 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_read16() {
+        let cdb = [
+            0x88, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x01, 0x00,
+            0x00, 0x00,
+        ];
+        let command = Command::new(&cdb).unwrap();
+        let read16 = match command {
+            Command::Read16(r) => r,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        // An LBA beyond what READ(10) can address.
+        assert_eq!(read16.lba(), 0x0000_0100_0000_0010);
+        assert_eq!(read16.xfer_len(), 0x10000);
+    }
+
+    #[test]
+    fn parse_write16() {
+        let cdb = [
+            0x8a, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x08,
+            0x00, 0x00,
+        ];
+        let command = Command::new(&cdb).unwrap();
+        let write16 = match command {
+            Command::Write16(w) => w,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(write16.lba(), 0xffff_ffff);
+        assert_eq!(write16.xfer_len(), 8);
+    }
+
+    #[test]
+    fn parse_verify() {
+        let cdb = [0x2f, 0x02, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x04, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let verify10 = match command {
+            Command::Verify10(v) => v,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(verify10.lba(), 0x20);
+        assert_eq!(verify10.verif_len(), 4);
+        assert_eq!(verify10.byte_check().unwrap(), ByteCheck::Blocks);
+
+        let mut cdb = [0u8; 16];
+        cdb[0] = VERIFY_16;
+        cdb[9] = 0x20;
+        cdb[13] = 4;
+        for (bytchk, byte_check) in [
+            (0b00, Some(ByteCheck::None)),
+            (0b01, Some(ByteCheck::Blocks)),
+            (0b10, None),
+            (0b11, Some(ByteCheck::SingleBlock)),
+        ] {
+            cdb[1] = bytchk << 1;
+            let command = Command::new(&cdb).unwrap();
+            let verify16 = match command {
+                Command::Verify16(v) => v,
+                _ => panic!("unexpected command type: {:?}", command),
+            };
+            assert_eq!(verify16.lba(), 0x20);
+            assert_eq!(verify16.verif_len(), 4);
+            assert_eq!(verify16.byte_check().ok(), byte_check);
+        }
+    }
+
+    #[test]
+    fn parse_synchronize_cache_16() {
+        let mut cdb = [0u8; 16];
+        cdb[0] = SYNCHRONIZE_CACHE_16;
+        let command = Command::new(&cdb).unwrap();
+        assert_eq!(
+            command,
+            Command::SynchronizeCache16(SynchronizeCache16 {
+                opcode: SYNCHRONIZE_CACHE_16,
+                ..Default::default()
+            })
+        );
+    }
+
     #[test]
     fn parse_report_luns() {
         let cdb = [
@@ -1260,6 +2088,20 @@ mod tests {
         assert_eq!(report_luns.alloc_len(), 0xabcdef12);
     }
 
+    #[test]
+    fn report_luns_lun_list() {
+        let lun_list = ReportLuns::lun_list(&[0, 5, 300]);
+        assert_eq!(
+            lun_list,
+            [
+                0, 0, 0, 24, 0, 0, 0, 0, // header
+                0, 0, 0, 0, 0, 0, 0, 0, // LUN 0
+                0, 5, 0, 0, 0, 0, 0, 0, // LUN 5
+                0x41, 0x2c, 0, 0, 0, 0, 0, 0, // LUN 300
+            ]
+        );
+    }
+
     #[test]
     fn parse_report_supported_tmfs() {
         let cdb = [
@@ -1272,4 +2114,113 @@ mod tests {
         };
         assert_eq!(report_supported_tmfs.alloc_len(), 0xabcdef12);
     }
+
+    #[test]
+    fn parse_read12() {
+        let cdb = [
+            0xa8, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00,
+        ];
+        let command = Command::new(&cdb).unwrap();
+        let read12 = match command {
+            Command::Read12(r) => r,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(read12.lba(), 0x100);
+        assert_eq!(read12.xfer_len(), 0x10002);
+    }
+
+    #[test]
+    fn parse_start_stop_unit() {
+        let cdb = [0x1b, 0x00, 0x00, 0x00, 0x02, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let start_stop_unit = match command {
+            Command::StartStopUnit(s) => s,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(start_stop_unit.power_condition(), 0);
+        assert!(start_stop_unit.load_eject());
+        assert!(!start_stop_unit.start());
+    }
+
+    #[test]
+    fn parse_get_event_status_notification() {
+        let cdb = [0x4a, 0x01, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x08, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let gesn = match command {
+            Command::GetEventStatusNotification(g) => g,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert!(gesn.polled());
+        assert_eq!(gesn.alloc_len(), 8);
+    }
+
+    #[test]
+    fn cd_address_msf() {
+        assert_eq!(cd_address(0, false), [0, 0, 0, 0]);
+        assert_eq!(cd_address(0x12345, false), [0, 0x01, 0x23, 0x45]);
+        // The first block is at 00:02:00.
+        assert_eq!(cd_address(0, true), [0, 0, 2, 0]);
+        // 4650 + 150 frames are 1 minute, 4 seconds and 0 frames.
+        assert_eq!(cd_address(4650, true), [0, 1, 4, 0]);
+        assert_eq!(cd_address(4651, true), [0, 1, 4, 1]);
+    }
+
+    #[test]
+    fn read_toc() {
+        let cdb = [0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let read_toc = match command {
+            Command::ReadToc(r) => r,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert!(!read_toc.msf());
+        assert_eq!(read_toc.format(), 0);
+        assert_eq!(read_toc.alloc_len(), 12);
+
+        assert_eq!(
+            ReadToc::toc(0x1000, 0, false).unwrap(),
+            [
+                0, 18, 1, 1, // header
+                0, 0x14, 1, 0, 0, 0, 0, 0, // track 1
+                0, 0x14, 0xaa, 0, 0, 0, 0x10, 0, // lead-out
+            ]
+        );
+        assert_eq!(
+            ReadToc::toc(0x1000, 0xaa, false).unwrap(),
+            [0, 10, 1, 1, 0, 0x14, 0xaa, 0, 0, 0, 0x10, 0]
+        );
+        assert!(ReadToc::toc(0x1000, 2, false).is_err());
+    }
+
+    #[test]
+    fn get_configuration() {
+        // Only the current features, from the first one.
+        let cdb = [0x46, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00];
+        let command = Command::new(&cdb).unwrap();
+        let get_configuration = match command {
+            Command::GetConfiguration(g) => g,
+            _ => panic!("unexpected command type: {:?}", command),
+        };
+        assert_eq!(get_configuration.rt(), 1);
+        assert_eq!(get_configuration.starting_feature(), 0);
+        assert_eq!(get_configuration.alloc_len(), 0xffff);
+
+        let no_medium = get_configuration.configuration(None).unwrap();
+        // The current profile is none of them, and Random Readable isn't current.
+        assert_eq!(&no_medium[6..8], [0, 0]);
+        assert_eq!(no_medium.len(), 8 + 12 + 8 + 8);
+        assert_eq!(&no_medium[..4], (no_medium.len() as u32 - 4).to_be_bytes());
+
+        let cd = get_configuration.configuration(Some(1000)).unwrap();
+        assert_eq!(&cd[6..8], PROFILE_CD_ROM.to_be_bytes());
+        assert_eq!(cd.len(), 8 + 12 + 8 + 8 + 12);
+        // Random Readable with 2048-byte blocks.
+        assert_eq!(&cd[36..40], [0x00, 0x10, 0x01, 0x08]);
+        assert_eq!(&cd[40..44], 2048u32.to_be_bytes());
+
+        let dvd = get_configuration
+            .configuration(Some(CD_MAX_BLOCKS + 1))
+            .unwrap();
+        assert_eq!(&dvd[6..8], PROFILE_DVD_ROM.to_be_bytes());
+    }
 }
@@ -6,6 +6,7 @@
 //! This file contains values specified in spec.
 //! SPC-3: <https://www.t10.org/cgi-bin/ac.pl?t=f&f=spc3r23.pdf>
 //! SAM-5: <https://www.t10.org/cgi-bin/ac.pl?t=f&f=sam5r21.pdf>
+//! MMC-6 for the commands of CD/DVD devices.
 
 // SCSI opcodes
 /// Opcode for TEST UNIT READY command.
@@ -20,18 +21,38 @@ pub const INQUIRY: u8 = 0x12;
 pub const MODE_SELECT_6: u8 = 0x15;
 /// Opcode for MODE SENSE(6) command.
 pub const MODE_SENSE_6: u8 = 0x1a;
+/// Opcode for START STOP UNIT command.
+pub const START_STOP_UNIT: u8 = 0x1b;
+/// Opcode for PREVENT ALLOW MEDIUM REMOVAL command.
+pub const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
 /// Opcode for READ CAPACITY(10) command.
 pub const READ_CAPACITY_10: u8 = 0x25;
 /// Opcode for READ(10) command.
 pub const READ_10: u8 = 0x28;
 /// Opcode for WRITE(10) command.
 pub const WRITE_10: u8 = 0x2a;
+/// Opcode for VERIFY(10) command.
+pub const VERIFY_10: u8 = 0x2f;
 /// Opcode for SYNCHRONIZE CACHE(10) command.
 pub const SYNCHRONIZE_CACHE_10: u8 = 0x35;
 /// Opcode for WRITE SAME(10) command.
 pub const WRITE_SAME_10: u8 = 0x41;
 /// Opcode for UNMAP command.
 pub const UNMAP: u8 = 0x42;
+/// Opcode for READ TOC/PMA/ATIP command.
+pub const READ_TOC: u8 = 0x43;
+/// Opcode for GET CONFIGURATION command.
+pub const GET_CONFIGURATION: u8 = 0x46;
+/// Opcode for GET EVENT STATUS NOTIFICATION command.
+pub const GET_EVENT_STATUS_NOTIFICATION: u8 = 0x4a;
+/// Opcode for READ(16) command.
+pub const READ_16: u8 = 0x88;
+/// Opcode for WRITE(16) command.
+pub const WRITE_16: u8 = 0x8a;
+/// Opcode for VERIFY(16) command.
+pub const VERIFY_16: u8 = 0x8f;
+/// Opcode for SYNCHRONIZE CACHE(16) command.
+pub const SYNCHRONIZE_CACHE_16: u8 = 0x91;
 /// Opcode for WRITE SAME(16) command.
 pub const WRITE_SAME_16: u8 = 0x93;
 /// Opcode for SERVICE ACTION IN(16) command.
@@ -40,6 +61,8 @@ pub const SERVICE_ACTION_IN_16: u8 = 0x9e;
 pub const REPORT_LUNS: u8 = 0xa0;
 /// Opcode for MAINTENANCE IN command.
 pub const MAINTENANCE_IN: u8 = 0xa3;
+/// Opcode for READ(12) command.
+pub const READ_12: u8 = 0xa8;
 
 // The service actions of MAINTENANCE IN command.
 /// REPORT SUPPORTED TASK MANAGEMENT FUNCTIONS
@@ -58,10 +81,14 @@ pub const CHECK_CONDITION: u8 = 0x02;
 // Device Types
 /// Indicates the id of disk type.
 pub const TYPE_DISK: u8 = 0x00;
+/// Indicates the id of CD/DVD device type.
+pub const TYPE_ROM: u8 = 0x05;
 
 // SENSE KEYS
 /// Indicates that there is no specific sense data to be reported.
 pub const NO_SENSE: u8 = 0x00;
+/// Indicates that the logical unit is not accessible, e.g. because there is no medium.
+pub const NOT_READY: u8 = 0x02;
 /// Indicates an error that may have been caused by a flaw in the medium or an error in the
 /// recorded data.
 pub const MEDIUM_ERROR: u8 = 0x03;
@@ -69,3 +96,7 @@ pub const MEDIUM_ERROR: u8 = 0x03;
 pub const ILLEGAL_REQUEST: u8 = 0x05;
 /// Indicates that a unit attention condition has been established.
 pub const UNIT_ATTENTION: u8 = 0x06;
+/// Indicates that the command tried to write to a write-protected medium.
+pub const DATA_PROTECT: u8 = 0x07;
+/// Indicates that the source data did not match the data read from the medium.
+pub const MISCOMPARE: u8 = 0x0e;
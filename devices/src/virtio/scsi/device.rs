@@ -4,32 +4,43 @@
 
 #![deny(missing_docs)]
 //! A SCSI controller has SCSI target(s), a SCSI target has logical unit(s).
-//! crosvm currently supports only one logical unit in a target (LUN0), therefore a SCSI target is
-//! tied to a logical unit and a disk image belongs to a logical unit in crosvm.
+//! A disk image belongs to a logical unit in crosvm. Logical units can be attached to and detached
+//! from a target while the guest runs, in which case the guest is notified through the eventq.
 
 use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::collections::VecDeque;
 use std::io;
 use std::io::Read;
 use std::io::Write;
 use std::rc::Rc;
+use std::sync::Arc;
 
 use anyhow::Context;
 use base::error;
+use base::info;
 use base::warn;
+use base::AsRawDescriptor;
+use base::Error as SysError;
 use base::Event;
+use base::Tube;
 use base::WorkerThread;
+use cros_async::AsyncTube;
 use cros_async::EventAsync;
 use cros_async::Executor;
 use cros_async::ExecutorKind;
 use disk::AsyncDisk;
 use disk::DiskFile;
+use futures::channel::mpsc;
+use futures::channel::oneshot;
 use futures::pin_mut;
 use futures::stream::FuturesUnordered;
+use futures::Future;
 use futures::FutureExt;
 use futures::StreamExt;
 use remain::sorted;
+use sync::Mutex;
 use thiserror::Error as ThisError;
 use virtio_sys::virtio_scsi::virtio_scsi_config;
 use virtio_sys::virtio_scsi::virtio_scsi_ctrl_an_resp;
@@ -37,6 +48,10 @@ use virtio_sys::virtio_scsi::virtio_scsi_ctrl_tmf_req;
 use virtio_sys::virtio_scsi::virtio_scsi_ctrl_tmf_resp;
 use virtio_sys::virtio_scsi::virtio_scsi_event;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_CDB_DEFAULT_SIZE;
+use virtio_sys::virtio_scsi::VIRTIO_SCSI_EVT_RESET_REMOVED;
+use virtio_sys::virtio_scsi::VIRTIO_SCSI_EVT_RESET_RESCAN;
+use virtio_sys::virtio_scsi::VIRTIO_SCSI_F_CHANGE;
+use virtio_sys::virtio_scsi::VIRTIO_SCSI_F_HOTPLUG;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_SENSE_DEFAULT_SIZE;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_S_BAD_TARGET;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_S_FUNCTION_REJECTED;
@@ -45,9 +60,14 @@ use virtio_sys::virtio_scsi::VIRTIO_SCSI_S_INCORRECT_LUN;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_S_OK;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_AN_QUERY;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_AN_SUBSCRIBE;
+use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_EVENTS_MISSED;
+use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_PARAM_CHANGE;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_TMF;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_TMF_I_T_NEXUS_RESET;
 use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_TMF_LOGICAL_UNIT_RESET;
+use virtio_sys::virtio_scsi::VIRTIO_SCSI_T_TRANSPORT_RESET;
+use vm_control::ScsiControlCommand;
+use vm_control::ScsiControlResult;
 use vm_memory::GuestMemory;
 use zerocopy::AsBytes;
 use zerocopy::FromBytes;
@@ -61,6 +81,10 @@ use crate::virtio::scsi::constants::CHECK_CONDITION;
 use crate::virtio::scsi::constants::GOOD;
 use crate::virtio::scsi::constants::ILLEGAL_REQUEST;
 use crate::virtio::scsi::constants::MEDIUM_ERROR;
+use crate::virtio::scsi::constants::MISCOMPARE;
+use crate::virtio::scsi::constants::NOT_READY;
+use crate::virtio::scsi::constants::UNIT_ATTENTION;
+use crate::virtio::scsi::sys::create_disk;
 use crate::virtio::DescriptorChain;
 use crate::virtio::DeviceType as VirtioDeviceType;
 use crate::virtio::Interrupt;
@@ -97,6 +121,13 @@ const MAX_SECTORS: u32 = u32::MAX;
 // <https://www.t10.org/cgi-bin/ac.pl?t=f&f=spc3r23.pdf>
 const FIXED_FORMAT_SENSE_SIZE: u32 = 18;
 
+// The block size of CD-ROM drives, which is the size of the user data of a CD sector.
+pub(crate) const CDROM_BLOCK_SIZE: u32 = 2048;
+
+// The number of events kept while the driver doesn't provide buffers in the eventq. Older events
+// are dropped past this, and the driver is told that it missed some.
+const MAX_PENDING_EVENTS: usize = 64;
+
 #[repr(C, packed)]
 #[derive(Debug, Default, Copy, Clone, FromZeroes, FromBytes, AsBytes)]
 struct VirtioScsiCmdReqHeader {
@@ -143,6 +174,16 @@ pub enum ExecuteError {
         sector: u64,
         max_lba: u64,
     },
+    #[error("no logical unit at the addressed LUN")]
+    LogicalUnitNotSupported,
+    #[error("the medium may have changed")]
+    MediumMayHaveChanged,
+    #[error("no medium in the drive")]
+    MediumNotPresent { tray_open: bool },
+    #[error("the removal of the medium is prevented")]
+    MediumRemovalPrevented,
+    #[error("the data to verify doesn't match the medium")]
+    Miscompare,
     #[error("failed to read message: {0}")]
     Read(io::Error),
     #[error("failed to read command from cdb")]
@@ -160,6 +201,8 @@ pub enum ExecuteError {
     SynchronizationError,
     #[error("unsupported scsi command: {0}")]
     Unsupported(u8),
+    #[error("failed to read the blocks to verify: {0}")]
+    VerifyIo(disk::Error),
     #[error("failed to write message: {0}")]
     Write(io::Error),
     #[error("io error {resid} bytes remained to be written: {desc_error}")]
@@ -176,7 +219,7 @@ impl ExecuteError {
         // The asc and ascq assignments are taken from the t10 SPC spec.
         // cf) Table 28 of <https://www.t10.org/cgi-bin/ac.pl?t=f&f=spc3r23.pdf>
         let sense = match self {
-            Self::Read(_) | Self::ReadCommand => {
+            Self::Read(_) | Self::ReadCommand | Self::VerifyIo(_) => {
                 // UNRECOVERED READ ERROR
                 Sense {
                     key: MEDIUM_ERROR,
@@ -216,6 +259,46 @@ impl ExecuteError {
                     ascq: 0x00,
                 }
             }
+            Self::LogicalUnitNotSupported => {
+                // LOGICAL UNIT NOT SUPPORTED
+                Sense {
+                    key: ILLEGAL_REQUEST,
+                    asc: 0x25,
+                    ascq: 0x00,
+                }
+            }
+            Self::MediumMayHaveChanged => {
+                // NOT READY TO READY CHANGE, MEDIUM MAY HAVE CHANGED
+                Sense {
+                    key: UNIT_ATTENTION,
+                    asc: 0x28,
+                    ascq: 0x00,
+                }
+            }
+            Self::MediumNotPresent { tray_open } => {
+                // MEDIUM NOT PRESENT - TRAY OPEN or MEDIUM NOT PRESENT - TRAY CLOSED
+                Sense {
+                    key: NOT_READY,
+                    asc: 0x3a,
+                    ascq: if *tray_open { 0x02 } else { 0x01 },
+                }
+            }
+            Self::MediumRemovalPrevented => {
+                // MEDIUM REMOVAL PREVENTED
+                Sense {
+                    key: ILLEGAL_REQUEST,
+                    asc: 0x53,
+                    ascq: 0x02,
+                }
+            }
+            Self::Miscompare => {
+                // MISCOMPARE DURING VERIFY OPERATION
+                Sense {
+                    key: MISCOMPARE,
+                    asc: 0x1d,
+                    ascq: 0x00,
+                }
+            }
             Self::ReadOnly | Self::LbaOutOfRange { .. } => {
                 // LOGICAL BLOCK ADDRESS OUT OF RANGE
                 Sense {
@@ -302,64 +385,229 @@ struct LogicalUnit {
     max_lba: u64,
     /// Block size of the target device.
     block_size: u32,
+    /// The number of blocks in which the disk image allocates its storage.
+    unmap_granularity: u32,
     read_only: bool,
-    // Represents the image on disk.
-    disk_image: Box<dyn DiskFile>,
+    // Represents the image on disk. `None` for a CD-ROM drive without medium.
+    disk_image: Option<Box<dyn DiskFile>>,
+    // The state of the drive if the logical unit is a CD-ROM drive.
+    cdrom: Option<Arc<Mutex<CdromState>>>,
 }
 
 impl LogicalUnit {
+    fn new(
+        disk_image: Box<dyn DiskFile>,
+        block_size: u32,
+        read_only: bool,
+    ) -> anyhow::Result<Self> {
+        Ok(LogicalUnit {
+            max_lba: max_lba(&*disk_image, block_size)?,
+            block_size,
+            unmap_granularity: unmap_granularity(&*disk_image, block_size),
+            read_only,
+            disk_image: Some(disk_image),
+            cdrom: None,
+        })
+    }
+
+    // Creates a CD-ROM drive with `disk_image` as its medium.
+    fn new_cdrom(disk_image: Box<dyn DiskFile>) -> anyhow::Result<Self> {
+        Ok(LogicalUnit {
+            max_lba: max_lba(&*disk_image, CDROM_BLOCK_SIZE)?,
+            block_size: CDROM_BLOCK_SIZE,
+            unmap_granularity: 1,
+            read_only: true,
+            disk_image: Some(disk_image),
+            cdrom: Some(Arc::new(Mutex::new(CdromState::default()))),
+        })
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(LogicalUnit {
+            max_lba: self.max_lba,
+            block_size: self.block_size,
+            unmap_granularity: self.unmap_granularity,
+            read_only: self.read_only,
+            disk_image: self
+                .disk_image
+                .as_ref()
+                .map(|disk_image| disk_image.try_clone())
+                .transpose()?,
+            cdrom: self.cdrom.clone(),
+        })
+    }
+
     fn make_async(self, ex: &Executor) -> anyhow::Result<AsyncLogicalUnit> {
         let disk_image = self
             .disk_image
-            .to_async_disk(ex)
+            .map(|disk_image| disk_image.to_async_disk(ex))
+            .transpose()
             .context("Failed to create async disk")?;
         Ok(AsyncLogicalUnit {
             max_lba: self.max_lba,
             block_size: self.block_size,
+            unmap_granularity: self.unmap_granularity,
             read_only: self.read_only,
             disk_image,
+            cdrom: self.cdrom,
         })
     }
 }
 
+// Returns the number of blocks of `disk_image`.
+fn max_lba(disk_image: &dyn DiskFile, block_size: u32) -> anyhow::Result<u64> {
+    Ok(disk_image
+        .get_len()
+        .context("Failed to get the length of the disk image")?
+        / block_size as u64)
+}
+
+// Returns the number of blocks in which `disk_image` allocates its storage, so that the driver
+// can align the ranges it unmaps on them.
+fn unmap_granularity(disk_image: &dyn DiskFile, block_size: u32) -> u32 {
+    (disk_image.discard_granularity() / block_size as u64).clamp(1, u32::MAX as u64) as u32
+}
+
 /// A logical unit with an AsyncDisk as the disk.
 pub struct AsyncLogicalUnit {
     pub max_lba: u64,
     pub block_size: u32,
+    // The optimal granularity of the ranges to unmap, in blocks.
+    pub unmap_granularity: u32,
     pub read_only: bool,
-    // Represents the async image on disk.
-    pub disk_image: Box<dyn AsyncDisk>,
+    // Represents the async image on disk. `None` for a CD-ROM drive without medium.
+    pub disk_image: Option<Box<dyn AsyncDisk>>,
+    // The state of the drive if the logical unit is a CD-ROM drive.
+    pub cdrom: Option<Arc<Mutex<CdromState>>>,
+}
+
+impl AsyncLogicalUnit {
+    /// Returns the disk image, or an error if the logical unit is a CD-ROM drive without medium.
+    pub fn disk(&self) -> Result<&dyn AsyncDisk, ExecuteError> {
+        let tray_open = self
+            .cdrom
+            .as_ref()
+            .map_or(false, |cdrom| cdrom.lock().tray_open);
+        match &self.disk_image {
+            Some(disk_image) if !tray_open => Ok(&**disk_image),
+            _ => Err(ExecuteError::MediumNotPresent { tray_open }),
+        }
+    }
+
+    /// Indicates whether there is a medium that can be accessed.
+    pub fn medium_present(&self) -> bool {
+        self.disk().is_ok()
+    }
+}
+
+/// The state of a CD-ROM drive, shared by the request queues.
+#[derive(Debug, Default)]
+pub struct CdromState {
+    /// Whether the driver opened the tray. The medium can't be accessed until it's closed.
+    pub tray_open: bool,
+    /// Whether the driver prevents the medium from being removed.
+    pub removal_prevented: bool,
+    /// Whether the medium changed since the driver was last told with a unit attention.
+    pub medium_changed: bool,
+    /// The event to report to the next GET EVENT STATUS NOTIFICATION command.
+    pub media_event: MediaEvent,
+}
+
+/// The media events reported by GET EVENT STATUS NOTIFICATION.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum MediaEvent {
+    #[default]
+    NoChange = 0,
+    EjectRequest = 1,
+    NewMedia = 2,
+    MediaRemoval = 3,
 }
 
 type TargetId = u8;
-struct Targets(BTreeMap<TargetId, LogicalUnit>);
+type Lun = u16;
+
+// Stores the logical units by target id and LUN.
+struct Targets(BTreeMap<TargetId, BTreeMap<Lun, LogicalUnit>>);
 
 impl Targets {
     fn try_clone(&self) -> io::Result<Self> {
-        let logical_units = self
+        let targets = self
             .0
             .iter()
-            .map(|(id, logical_unit)| {
-                let disk_image = logical_unit.disk_image.try_clone()?;
-                Ok((
-                    *id,
-                    LogicalUnit {
-                        disk_image,
-                        max_lba: logical_unit.max_lba,
-                        block_size: logical_unit.block_size,
-                        read_only: logical_unit.read_only,
-                    },
-                ))
+            .map(|(target_id, logical_units)| {
+                let logical_units = logical_units
+                    .iter()
+                    .map(|(lun, logical_unit)| Ok((*lun, logical_unit.try_clone()?)))
+                    .collect::<io::Result<_>>()?;
+                Ok((*target_id, logical_units))
             })
             .collect::<io::Result<_>>()?;
-        Ok(Self(logical_units))
+        Ok(Self(targets))
+    }
+
+    fn cdroms(&self) -> BTreeMap<(TargetId, Lun), Arc<Mutex<CdromState>>> {
+        self.0
+            .iter()
+            .flat_map(|(target_id, logical_units)| {
+                logical_units.iter().filter_map(|(lun, logical_unit)| {
+                    Some(((*target_id, *lun), logical_unit.cdrom.clone()?))
+                })
+            })
+            .collect()
+    }
+
+    fn luns(&self) -> BTreeMap<TargetId, BTreeSet<Lun>> {
+        self.0
+            .iter()
+            .map(|(target_id, logical_units)| (*target_id, logical_units.keys().cloned().collect()))
+            .collect()
+    }
+
+    fn make_async(self, ex: &Executor) -> anyhow::Result<AsyncTargets> {
+        self.0
+            .into_iter()
+            .map(|(target_id, logical_units)| {
+                let logical_units = logical_units
+                    .into_iter()
+                    .map(|(lun, logical_unit)| Ok((lun, Rc::new(logical_unit.make_async(ex)?))))
+                    .collect::<anyhow::Result<_>>()?;
+                Ok((target_id, logical_units))
+            })
+            .collect()
+    }
+}
+
+// The logical units as seen by a request queue worker.
+type AsyncTargets = BTreeMap<TargetId, BTreeMap<Lun, Rc<AsyncLogicalUnit>>>;
+
+// Returns the target id and the LUN addressed by the `lun` field of a virtio-scsi request.
+fn parse_lun(lun: [u8; 8]) -> Option<(TargetId, Lun)> {
+    // First byte should be 1.
+    if lun[0] != 1 {
+        return None;
     }
+    // The LUN is in the single level format, where the top two bits are the addressing method.
+    Some((lun[1], u16::from_be_bytes([lun[2], lun[3]]) & 0x3fff))
+}
 
-    fn target_ids(&self) -> BTreeSet<TargetId> {
-        self.0.keys().cloned().collect()
+// Returns the first level of the single level LUN structure addressing `lun`. It uses the
+// peripheral device addressing method when the LUN fits in a byte, as it's what guests use to
+// address those LUNs, and the flat space addressing method otherwise.
+pub(crate) fn single_level_lun(lun: Lun) -> u16 {
+    if lun < 256 {
+        lun
+    } else {
+        0x4000 | lun
     }
 }
 
+// Builds the `lun` field of a virtio-scsi event for the given logical unit, with the same encoding
+// as the LUNs reported by REPORT LUNS so that the guest finds the logical unit it scanned.
+fn lun_bytes(target_id: TargetId, lun: Lun) -> [u8; 8] {
+    let [lun_hi, lun_lo] = single_level_lun(lun).to_be_bytes();
+    [1, target_id, lun_hi, lun_lo, 0, 0, 0, 0]
+}
+
 /// Configuration of each SCSI device.
 pub struct DiskConfig {
     /// The disk file of the device.
@@ -368,12 +616,21 @@ pub struct DiskConfig {
     pub block_size: u32,
     /// Indicates whether the SCSI disk is read only.
     pub read_only: bool,
+    /// The id of the target the SCSI disk belongs to.
+    pub target: u8,
+    /// The logical unit number of the SCSI disk in its target.
+    pub lun: u16,
+    /// Indicates whether the SCSI disk is a CD-ROM drive with `file` as its medium. CD-ROM drives
+    /// are read only and have 2048-byte blocks regardless of `block_size` and `read_only`.
+    pub cdrom: bool,
 }
 
 /// Vitio device for exposing SCSI command operations on a host file.
 pub struct Controller {
     // Bitmap of virtio-scsi feature bits.
     avail_features: u64,
+    // Bitmap of the feature bits acked by the driver.
+    acked_features: u64,
     // Sizes for the virtqueue.
     queue_sizes: Vec<u16>,
     // The maximum number of segments that can be in a command.
@@ -389,46 +646,56 @@ pub struct Controller {
     // Whether the devices handles requests in multiple request queues.
     // If true, each virtqueue will be handled in a separate worker thread.
     multi_queue: bool,
+    // Receives the commands that attach and detach logical units.
+    control_tube: Option<Tube>,
 }
 
 impl Controller {
     /// Creates a virtio-scsi device.
-    pub fn new(base_features: u64, disks: Vec<DiskConfig>) -> anyhow::Result<Self> {
+    pub fn new(
+        base_features: u64,
+        disks: Vec<DiskConfig>,
+        control_tube: Option<Tube>,
+    ) -> anyhow::Result<Self> {
         let multi_queue = disks.iter().all(|disk| disk.file.try_clone().is_ok());
         let num_queues = if multi_queue {
             MAX_NUM_QUEUES
         } else {
             MIN_NUM_QUEUES
         };
-        let logical_units = disks
-            .into_iter()
-            .enumerate()
-            .map(|(i, disk)| {
-                let max_lba = disk
-                    .file
-                    .get_len()
-                    .context("Failed to get the length of the disk image")?
-                    / disk.block_size as u64;
-                let target = LogicalUnit {
-                    max_lba,
-                    block_size: disk.block_size,
-                    read_only: disk.read_only,
-                    disk_image: disk.file,
-                };
-                Ok((i as TargetId, target))
-            })
-            .collect::<anyhow::Result<_>>()?;
+        let mut targets = BTreeMap::new();
+        for disk in disks {
+            if disk.lun as u32 > DEFAULT_MAX_LUN {
+                anyhow::bail!("SCSI LUN {} is larger than {}", disk.lun, DEFAULT_MAX_LUN);
+            }
+            let logical_unit = if disk.cdrom {
+                LogicalUnit::new_cdrom(disk.file)?
+            } else {
+                LogicalUnit::new(disk.file, disk.block_size, disk.read_only)?
+            };
+            let logical_units: &mut BTreeMap<Lun, LogicalUnit> =
+                targets.entry(disk.target).or_default();
+            if logical_units.insert(disk.lun, logical_unit).is_some() {
+                anyhow::bail!(
+                    "SCSI target {} LUN {} is used by several disks",
+                    disk.target,
+                    disk.lun
+                );
+            }
+        }
         // b/300560198: Support feature bits in virtio-scsi.
         Ok(Self {
-            avail_features: base_features,
+            avail_features: base_features | 1 << VIRTIO_SCSI_F_HOTPLUG | 1 << VIRTIO_SCSI_F_CHANGE,
+            acked_features: 0,
             queue_sizes: vec![DEFAULT_QUEUE_SIZE; num_queues],
             seg_max: get_seg_max(DEFAULT_QUEUE_SIZE),
             sense_size: VIRTIO_SCSI_SENSE_DEFAULT_SIZE,
             cdb_size: VIRTIO_SCSI_CDB_DEFAULT_SIZE,
             executor_kind: ExecutorKind::default(),
             worker_threads: vec![],
-            targets: Some(Targets(logical_units)),
+            targets: Some(Targets(targets)),
             multi_queue,
+            control_tube,
         })
     }
 
@@ -453,7 +720,7 @@ impl Controller {
     fn execute_control(
         reader: &mut Reader,
         writer: &mut Writer,
-        target_ids: &BTreeSet<TargetId>,
+        target_luns: &BTreeMap<TargetId, BTreeSet<Lun>>,
     ) -> Result<(), ExecuteError> {
         let typ = reader.peek_obj::<u32>().map_err(ExecuteError::Read)?;
         match typ {
@@ -461,7 +728,7 @@ impl Controller {
                 let tmf = reader
                     .read_obj::<virtio_scsi_ctrl_tmf_req>()
                     .map_err(ExecuteError::Read)?;
-                let resp = Self::execute_tmf(tmf, target_ids);
+                let resp = Self::execute_tmf(tmf, target_luns);
                 writer.write_obj(resp).map_err(ExecuteError::Write)?;
                 Ok(())
             }
@@ -485,22 +752,22 @@ impl Controller {
     // Executes a TMF (task management function) request.
     fn execute_tmf(
         tmf: virtio_scsi_ctrl_tmf_req,
-        target_ids: &BTreeSet<TargetId>,
+        target_luns: &BTreeMap<TargetId, BTreeSet<Lun>>,
     ) -> virtio_scsi_ctrl_tmf_resp {
         match tmf.subtype {
             VIRTIO_SCSI_T_TMF_LOGICAL_UNIT_RESET | VIRTIO_SCSI_T_TMF_I_T_NEXUS_RESET => {
-                // We only have LUN0.
-                let lun = tmf.lun;
-                let target_id = lun[1];
-                let response = if target_ids.contains(&target_id) {
-                    let is_lun0 = u16::from_be_bytes([lun[2], lun[3]]) & 0x3fff == 0;
-                    if is_lun0 {
+                let luns = parse_lun(tmf.lun)
+                    .and_then(|(target_id, lun)| Some((target_luns.get(&target_id)?, lun)));
+                let response = match luns {
+                    // An I_T nexus reset applies to the whole target.
+                    Some((luns, lun))
+                        if tmf.subtype == VIRTIO_SCSI_T_TMF_I_T_NEXUS_RESET
+                            || luns.contains(&lun) =>
+                    {
                         VIRTIO_SCSI_S_FUNCTION_SUCCEEDED as u8
-                    } else {
-                        VIRTIO_SCSI_S_INCORRECT_LUN as u8
                     }
-                } else {
-                    VIRTIO_SCSI_S_BAD_TARGET as u8
+                    Some(_) => VIRTIO_SCSI_S_INCORRECT_LUN as u8,
+                    None => VIRTIO_SCSI_S_BAD_TARGET as u8,
                 };
                 virtio_scsi_ctrl_tmf_resp { response }
             }
@@ -517,19 +784,25 @@ impl Controller {
         reader: &mut Reader,
         resp_writer: &mut Writer,
         data_writer: &mut Writer,
-        targets: &BTreeMap<TargetId, AsyncLogicalUnit>,
+        targets: &RefCell<AsyncTargets>,
         sense_size: u32,
         cdb_size: u32,
     ) -> Result<(), ExecuteError> {
         let req_header = reader
             .read_obj::<VirtioScsiCmdReqHeader>()
             .map_err(ExecuteError::Read)?;
-        match Self::get_logical_unit(req_header.lun, targets) {
-            Some(target) => {
+        // Don't keep the targets borrowed while the command runs, as logical units can be
+        // attached or detached in the meantime.
+        let logical_unit = Self::get_logical_unit(req_header.lun, &targets.borrow());
+        match logical_unit {
+            Some((logical_unit, target_luns)) => {
                 let mut cdb = vec![0; cdb_size as usize];
                 reader.read_exact(&mut cdb).map_err(ExecuteError::Read)?;
                 let command = Command::new(&cdb)?;
-                match command.execute(reader, data_writer, target).await {
+                match command
+                    .execute(reader, data_writer, logical_unit.as_deref(), &target_luns)
+                    .await
+                {
                     Ok(()) => {
                         let hdr = VirtioScsiCmdRespHeader {
                             sense_len: 0,
@@ -562,41 +835,50 @@ impl Controller {
         }
     }
 
+    // Returns the logical unit addressed by `lun` if there is one, and the LUNs of its target.
+    // Returns `None` if the target doesn't exist.
     fn get_logical_unit(
         lun: [u8; 8],
-        targets: &BTreeMap<TargetId, AsyncLogicalUnit>,
-    ) -> Option<&AsyncLogicalUnit> {
-        // First byte should be 1.
-        if lun[0] != 1 {
-            return None;
-        }
-        // General search strategy for scsi devices is as follows:
-        // 1) Look for a device which has the same bus id and lun indicated by the given lun. If
-        //    there is one, that is the target device.
-        // 2) If we cannot find such device, then we return the first device that has the same bus
-        //    id.
-        // Since we only support one LUN per target, we only need to use the target id.
-        let target_id = lun[1];
-        targets.get(&target_id)
+        targets: &AsyncTargets,
+    ) -> Option<(Option<Rc<AsyncLogicalUnit>>, Vec<Lun>)> {
+        let (target_id, lun) = parse_lun(lun)?;
+        let logical_units = targets.get(&target_id)?;
+        Some((
+            logical_units.get(&lun).cloned(),
+            logical_units.keys().cloned().collect(),
+        ))
     }
 }
 
 impl VirtioDevice for Controller {
     fn keep_rds(&self) -> Vec<base::RawDescriptor> {
-        match &self.targets {
+        let mut keep_rds = match &self.targets {
             Some(targets) => targets
                 .0
                 .values()
-                .flat_map(|t| t.disk_image.as_raw_descriptors())
+                .flat_map(|t| t.values())
+                .flat_map(|t| t.disk_image.iter().flat_map(|d| d.as_raw_descriptors()))
                 .collect(),
             None => vec![],
+        };
+        if let Some(control_tube) = &self.control_tube {
+            keep_rds.push(control_tube.as_raw_descriptor());
         }
+        keep_rds
     }
 
     fn features(&self) -> u64 {
         self.avail_features
     }
 
+    fn ack_features(&mut self, value: u64) {
+        let unrequested_features = value & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("virtio-scsi got unknown feature ack: {:x}", value);
+        }
+        self.acked_features |= value & self.avail_features;
+    }
+
     fn device_type(&self) -> VirtioDeviceType {
         VirtioDeviceType::Scsi
     }
@@ -649,14 +931,19 @@ impl VirtioDevice for Controller {
         // 0th virtqueue is the controlq.
         let controlq = queues.remove(&0).context("controlq should be present")?;
         // 1st virtqueue is the eventq.
-        // We do not send any events through eventq.
-        let _eventq = queues.remove(&1).context("eventq should be present")?;
+        let eventq = queues.remove(&1).context("eventq should be present")?;
         let targets = self.targets.take().context("failed to take SCSI targets")?;
-        let target_ids = targets.target_ids();
+        let target_luns = targets.luns();
+        let cdroms = targets.cdroms();
         let sense_size = self.sense_size;
         let cdb_size = self.cdb_size;
+        let control_tube = self.control_tube.take();
+        let events = EventTypes {
+            hotplug: self.acked_features & (1 << VIRTIO_SCSI_F_HOTPLUG) != 0,
+            change: self.acked_features & (1 << VIRTIO_SCSI_F_CHANGE) != 0,
+        };
         // The rest of the queues are request queues.
-        let request_queues = if self.multi_queue {
+        let request_queues: Vec<(Queue, Targets)> = if self.multi_queue {
             queues
                 .into_values()
                 .map(|queue| {
@@ -675,20 +962,55 @@ impl VirtioDevice for Controller {
                 targets,
             )]
         };
+        // Each request queue worker is told about the logical units attached and detached at
+        // runtime through its own channel.
+        let (update_senders, update_receivers): (Vec<_>, Vec<_>) = request_queues
+            .iter()
+            .map(|_| mpsc::unbounded::<LogicalUnitUpdate>())
+            .unzip();
 
         let intr = interrupt.clone();
         let worker_thread = WorkerThread::start("v_scsi_ctrlq", move |kill_evt| {
             let ex =
                 Executor::with_executor_kind(executor_kind).expect("Failed to create an executor");
+            let control_tube =
+                control_tube.map(|t| AsyncTube::new(&ex, t).expect("failed to create async tube"));
+            let eventq_kick_evt = eventq
+                .event()
+                .try_clone()
+                .expect("Failed to clone queue event");
+            let eventq_kick_evt = EventAsync::new(eventq_kick_evt, &ex)
+                .expect("Failed to create async event for queue");
+            let event_queue = Rc::new(RefCell::new(EventQueue::new(eventq)));
+            let target_luns = Rc::new(RefCell::new(target_luns));
+            let hotplug = Hotplug {
+                target_luns: target_luns.clone(),
+                cdroms: RefCell::new(cdroms),
+                update_senders,
+                event_queue: event_queue.clone(),
+                interrupt: intr.clone(),
+                events,
+            };
+            let background = async {
+                let control = handle_control_tube(&control_tube, &hotplug).fuse();
+                pin_mut!(control);
+                let eventq = handle_event_queue(eventq_kick_evt, &event_queue, &intr).fuse();
+                pin_mut!(eventq);
+                futures::select! {
+                    r = control => r,
+                    r = eventq => r,
+                }
+            };
             if let Err(err) = ex
                 .run_until(run_worker(
                     &ex,
-                    intr,
+                    intr.clone(),
                     controlq,
                     kill_evt,
-                    QueueType::Control { target_ids },
+                    QueueType::Control { target_luns },
                     sense_size,
                     cdb_size,
+                    background,
                 ))
                 .expect("run_until failed")
             {
@@ -697,29 +1019,29 @@ impl VirtioDevice for Controller {
         });
         self.worker_threads.push(worker_thread);
 
-        for (i, (queue, targets)) in request_queues.into_iter().enumerate() {
+        for (i, ((queue, targets), updates)) in
+            request_queues.into_iter().zip(update_receivers).enumerate()
+        {
             let interrupt = interrupt.clone();
             let worker_thread =
                 WorkerThread::start(format!("v_scsi_req_{}", i + 2), move |kill_evt| {
                     let ex = Executor::with_executor_kind(executor_kind)
                         .expect("Failed to create an executor");
-                    let async_logical_unit = targets
-                        .0
-                        .into_iter()
-                        .map(|(idx, unit)| match unit.make_async(&ex) {
-                            Ok(async_unit) => (idx, async_unit),
-                            Err(err) => panic!("{err}"),
-                        })
-                        .collect();
+                    let async_targets = match targets.make_async(&ex) {
+                        Ok(async_targets) => Rc::new(RefCell::new(async_targets)),
+                        Err(err) => panic!("{err}"),
+                    };
+                    let background = handle_logical_unit_updates(&ex, updates, &async_targets);
                     if let Err(err) = ex
                         .run_until(run_worker(
                             &ex,
                             interrupt,
                             queue,
                             kill_evt,
-                            QueueType::Request(async_logical_unit),
+                            QueueType::Request(async_targets.clone()),
                             sense_size,
                             cdb_size,
+                            background,
                         ))
                         .expect("run_until failed")
                     {
@@ -733,10 +1055,532 @@ impl VirtioDevice for Controller {
 }
 
 enum QueueType {
-    Control { target_ids: BTreeSet<TargetId> },
-    Request(BTreeMap<TargetId, AsyncLogicalUnit>),
+    Control {
+        target_luns: Rc<RefCell<BTreeMap<TargetId, BTreeSet<Lun>>>>,
+    },
+    Request(Rc<RefCell<AsyncTargets>>),
+}
+
+// The kinds of events that the driver accepts in the eventq.
+#[derive(Copy, Clone)]
+struct EventTypes {
+    // Logical units being attached and detached.
+    hotplug: bool,
+    // Unit attention conditions reported by logical units.
+    change: bool,
+}
+
+// The eventq, along with the events waiting for the driver to provide buffers.
+struct EventQueue {
+    queue: Queue,
+    pending: VecDeque<virtio_scsi_event>,
+    // Whether events were dropped since the last one sent to the driver.
+    events_missed: bool,
+}
+
+impl EventQueue {
+    fn new(queue: Queue) -> Self {
+        EventQueue {
+            queue,
+            pending: VecDeque::new(),
+            events_missed: false,
+        }
+    }
+
+    // Queues `event` and sends as many pending events as the driver provided buffers for.
+    fn push(&mut self, event: virtio_scsi_event, interrupt: &Interrupt) {
+        if self.pending.len() == MAX_PENDING_EVENTS {
+            self.pending.pop_front();
+            self.events_missed = true;
+        }
+        self.pending.push_back(event);
+        self.flush(interrupt);
+    }
+
+    // Sends the pending events to the driver.
+    fn flush(&mut self, interrupt: &Interrupt) {
+        let mut sent = false;
+        while !self.pending.is_empty() {
+            let mut chain = match self.queue.pop() {
+                Some(chain) => chain,
+                None => break,
+            };
+            let mut event = self.pending.pop_front().unwrap();
+            if self.events_missed {
+                event.event |= VIRTIO_SCSI_T_EVENTS_MISSED;
+                self.events_missed = false;
+            }
+            if let Err(e) = chain.writer.write_obj(event) {
+                error!("failed to write a virtio-scsi event: {e}");
+            }
+            let len = chain.writer.bytes_written();
+            self.queue.add_used(chain, len as u32);
+            sent = true;
+        }
+        if sent {
+            self.queue.trigger_interrupt(interrupt);
+        }
+    }
+}
+
+// Sends the pending events whenever the driver provides new buffers in the eventq.
+async fn handle_event_queue(
+    evt: EventAsync,
+    event_queue: &RefCell<EventQueue>,
+    interrupt: &Interrupt,
+) -> anyhow::Result<()> {
+    loop {
+        evt.next_val()
+            .await
+            .context("Failed to read the next queue event")?;
+        event_queue.borrow_mut().flush(interrupt);
+    }
+}
+
+// Messages sent to the request queue workers when logical units are attached or detached, or when
+// the medium of a CD-ROM drive changes. `done` is signaled once the worker uses the new set of
+// logical units.
+enum LogicalUnitUpdate {
+    Attach {
+        target_id: TargetId,
+        lun: Lun,
+        logical_unit: LogicalUnit,
+        done: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Detach {
+        target_id: TargetId,
+        lun: Lun,
+        done: oneshot::Sender<anyhow::Result<()>>,
+    },
+    ChangeMedium {
+        target_id: TargetId,
+        lun: Lun,
+        disk_image: Option<Box<dyn DiskFile>>,
+        max_lba: u64,
+        done: oneshot::Sender<anyhow::Result<()>>,
+    },
+}
+
+// Applies the updates of the logical units to the targets of a request queue worker.
+async fn handle_logical_unit_updates(
+    ex: &Executor,
+    mut updates: mpsc::UnboundedReceiver<LogicalUnitUpdate>,
+    targets: &RefCell<AsyncTargets>,
+) -> anyhow::Result<()> {
+    while let Some(update) = updates.next().await {
+        match update {
+            LogicalUnitUpdate::Attach {
+                target_id,
+                lun,
+                logical_unit,
+                done,
+            } => {
+                let result = logical_unit.make_async(ex).map(|logical_unit| {
+                    targets
+                        .borrow_mut()
+                        .entry(target_id)
+                        .or_default()
+                        .insert(lun, Rc::new(logical_unit));
+                });
+                let _ = done.send(result);
+            }
+            LogicalUnitUpdate::Detach {
+                target_id,
+                lun,
+                done,
+            } => {
+                let mut targets = targets.borrow_mut();
+                if let Some(logical_units) = targets.get_mut(&target_id) {
+                    logical_units.remove(&lun);
+                    if logical_units.is_empty() {
+                        targets.remove(&target_id);
+                    }
+                }
+                let _ = done.send(Ok(()));
+            }
+            LogicalUnitUpdate::ChangeMedium {
+                target_id,
+                lun,
+                disk_image,
+                max_lba,
+                done,
+            } => {
+                let result = change_medium(ex, targets, target_id, lun, disk_image, max_lba);
+                let _ = done.send(result);
+            }
+        }
+    }
+    // The controlq worker is gone, so there won't be any more updates.
+    futures::future::pending().await
+}
+
+// Replaces the medium of a CD-ROM drive. The commands in flight keep using the previous one.
+fn change_medium(
+    ex: &Executor,
+    targets: &RefCell<AsyncTargets>,
+    target_id: TargetId,
+    lun: Lun,
+    disk_image: Option<Box<dyn DiskFile>>,
+    max_lba: u64,
+) -> anyhow::Result<()> {
+    let disk_image = disk_image
+        .map(|disk_image| disk_image.to_async_disk(ex))
+        .transpose()
+        .context("Failed to create async disk")?;
+    let mut targets = targets.borrow_mut();
+    let logical_unit = targets
+        .get_mut(&target_id)
+        .and_then(|logical_units| logical_units.get_mut(&lun))
+        .context("no logical unit at the LUN")?;
+    *logical_unit = Rc::new(AsyncLogicalUnit {
+        max_lba,
+        block_size: logical_unit.block_size,
+        unmap_granularity: logical_unit.unmap_granularity,
+        read_only: logical_unit.read_only,
+        disk_image,
+        cdrom: logical_unit.cdrom.clone(),
+    });
+    Ok(())
+}
+
+// The state used by the controlq worker to attach and detach logical units, and to change the
+// medium of CD-ROM drives.
+struct Hotplug {
+    target_luns: Rc<RefCell<BTreeMap<TargetId, BTreeSet<Lun>>>>,
+    cdroms: RefCell<BTreeMap<(TargetId, Lun), Arc<Mutex<CdromState>>>>,
+    update_senders: Vec<mpsc::UnboundedSender<LogicalUnitUpdate>>,
+    event_queue: Rc<RefCell<EventQueue>>,
+    interrupt: Interrupt,
+    events: EventTypes,
+}
+
+impl Hotplug {
+    async fn execute(&self, command: ScsiControlCommand) -> ScsiControlResult {
+        match command {
+            ScsiControlCommand::AttachLun {
+                target,
+                lun,
+                path,
+                file,
+                block_size,
+                read_only,
+                cdrom,
+            } => {
+                info!(
+                    "Attaching {} to SCSI target {} LUN {}",
+                    path.display(),
+                    target,
+                    lun
+                );
+                if lun as u32 > DEFAULT_MAX_LUN {
+                    error!("SCSI LUN {} is larger than {}", lun, DEFAULT_MAX_LUN);
+                    return ScsiControlResult::Err(SysError::new(libc::EINVAL));
+                }
+                if self.is_attached(target, lun) {
+                    error!("SCSI target {} LUN {} is already in use", target, lun);
+                    return ScsiControlResult::Err(SysError::new(libc::EEXIST));
+                }
+                let logical_unit = create_disk(file, read_only || cdrom, &path).and_then(|disk| {
+                    if cdrom {
+                        LogicalUnit::new_cdrom(disk)
+                    } else {
+                        LogicalUnit::new(disk, block_size, read_only)
+                    }
+                });
+                let logical_unit = match logical_unit {
+                    Ok(logical_unit) => logical_unit,
+                    Err(e) => {
+                        error!("failed to open {}: {:#}", path.display(), e);
+                        return ScsiControlResult::Err(SysError::new(libc::EINVAL));
+                    }
+                };
+                match self.attach(target, lun, logical_unit).await {
+                    Ok(()) => ScsiControlResult::Ok,
+                    Err(e) => {
+                        error!("failed to attach {}: {:#}", path.display(), e);
+                        ScsiControlResult::Err(SysError::new(libc::EIO))
+                    }
+                }
+            }
+            ScsiControlCommand::DetachLun { target, lun } => {
+                info!("Detaching SCSI target {} LUN {}", target, lun);
+                if !self.is_attached(target, lun) {
+                    error!("SCSI target {} LUN {} isn't in use", target, lun);
+                    return ScsiControlResult::Err(SysError::new(libc::ENOENT));
+                }
+                match self.detach(target, lun).await {
+                    Ok(()) => ScsiControlResult::Ok,
+                    Err(e) => {
+                        error!(
+                            "failed to detach SCSI target {} LUN {}: {:#}",
+                            target, lun, e
+                        );
+                        ScsiControlResult::Err(SysError::new(libc::EIO))
+                    }
+                }
+            }
+            ScsiControlCommand::InsertMedium {
+                target,
+                lun,
+                path,
+                file,
+            } => {
+                info!(
+                    "Inserting {} in SCSI target {} LUN {}",
+                    path.display(),
+                    target,
+                    lun
+                );
+                let disk_image = match create_disk(file, true, &path) {
+                    Ok(disk_image) => disk_image,
+                    Err(e) => {
+                        error!("failed to open {}: {:#}", path.display(), e);
+                        return ScsiControlResult::Err(SysError::new(libc::EINVAL));
+                    }
+                };
+                self.change_medium(target, lun, Some(disk_image)).await
+            }
+            ScsiControlCommand::EjectMedium { target, lun } => {
+                info!("Ejecting the medium of SCSI target {} LUN {}", target, lun);
+                self.change_medium(target, lun, None).await
+            }
+        }
+    }
+
+    async fn change_medium(
+        &self,
+        target_id: TargetId,
+        lun: Lun,
+        disk_image: Option<Box<dyn DiskFile>>,
+    ) -> ScsiControlResult {
+        let cdrom = match self.cdroms.borrow().get(&(target_id, lun)) {
+            Some(cdrom) => cdrom.clone(),
+            None => {
+                error!("SCSI target {} LUN {} isn't a CD-ROM drive", target_id, lun);
+                return ScsiControlResult::Err(SysError::new(libc::ENODEV));
+            }
+        };
+        {
+            let mut cdrom = cdrom.lock();
+            if cdrom.removal_prevented {
+                // Ask the driver to allow the removal of the medium, e.g. by unmounting it.
+                cdrom.media_event = MediaEvent::EjectRequest;
+                error!(
+                    "the driver prevents the medium of SCSI target {} LUN {} from being removed",
+                    target_id, lun
+                );
+                return ScsiControlResult::Err(SysError::new(libc::EBUSY));
+            }
+        }
+        let inserted = disk_image.is_some();
+        if let Err(e) = self.set_medium(target_id, lun, disk_image).await {
+            error!(
+                "failed to change the medium of SCSI target {} LUN {}: {:#}",
+                target_id, lun, e
+            );
+            return ScsiControlResult::Err(SysError::new(libc::EIO));
+        }
+
+        let mut cdrom = cdrom.lock();
+        cdrom.tray_open = false;
+        cdrom.medium_changed = true;
+        cdrom.media_event = if inserted {
+            MediaEvent::NewMedia
+        } else {
+            MediaEvent::MediaRemoval
+        };
+        ScsiControlResult::Ok
+    }
+
+    async fn set_medium(
+        &self,
+        target_id: TargetId,
+        lun: Lun,
+        disk_image: Option<Box<dyn DiskFile>>,
+    ) -> anyhow::Result<()> {
+        let max_lba = match &disk_image {
+            Some(disk_image) => max_lba(&**disk_image, CDROM_BLOCK_SIZE)?,
+            None => 0,
+        };
+        // Each request queue worker gets its own instance of the disk.
+        let mut disk_images = self.clone_for_request_queues(disk_image, |disk_image| {
+            disk_image
+                .as_ref()
+                .map(|disk_image| disk_image.try_clone())
+                .transpose()
+        })?;
+        self.update_request_queues(|done| LogicalUnitUpdate::ChangeMedium {
+            target_id,
+            lun,
+            disk_image: disk_images
+                .next()
+                .expect("one disk image per request queue"),
+            max_lba,
+            done,
+        })
+        .await
+    }
+
+    fn is_attached(&self, target_id: TargetId, lun: Lun) -> bool {
+        self.target_luns
+            .borrow()
+            .get(&target_id)
+            .map_or(false, |luns| luns.contains(&lun))
+    }
+
+    async fn attach(
+        &self,
+        target_id: TargetId,
+        lun: Lun,
+        logical_unit: LogicalUnit,
+    ) -> anyhow::Result<()> {
+        let cdrom = logical_unit.cdrom.clone();
+        // Each request queue worker gets its own instance of the disk.
+        let mut logical_units =
+            self.clone_for_request_queues(logical_unit, LogicalUnit::try_clone)?;
+        let result = self
+            .update_request_queues(|done| LogicalUnitUpdate::Attach {
+                target_id,
+                lun,
+                logical_unit: logical_units
+                    .next()
+                    .expect("one logical unit per request queue"),
+                done,
+            })
+            .await;
+        if let Err(e) = result {
+            // Don't leave the logical unit attached to some of the request queues only.
+            self.update_request_queues(|done| LogicalUnitUpdate::Detach {
+                target_id,
+                lun,
+                done,
+            })
+            .await?;
+            return Err(e);
+        }
+
+        if let Some(cdrom) = cdrom {
+            self.cdroms.borrow_mut().insert((target_id, lun), cdrom);
+        }
+        let mut target_luns = self.target_luns.borrow_mut();
+        let luns = target_luns.entry(target_id).or_default();
+        luns.insert(lun);
+        self.notify(target_id, lun, luns, VIRTIO_SCSI_EVT_RESET_RESCAN);
+        Ok(())
+    }
+
+    async fn detach(&self, target_id: TargetId, lun: Lun) -> anyhow::Result<()> {
+        self.update_request_queues(|done| LogicalUnitUpdate::Detach {
+            target_id,
+            lun,
+            done,
+        })
+        .await?;
+
+        self.cdroms.borrow_mut().remove(&(target_id, lun));
+        let mut target_luns = self.target_luns.borrow_mut();
+        let luns = target_luns.entry(target_id).or_default();
+        luns.remove(&lun);
+        self.notify(target_id, lun, luns, VIRTIO_SCSI_EVT_RESET_REMOVED);
+        if luns.is_empty() {
+            target_luns.remove(&target_id);
+        }
+        Ok(())
+    }
+
+    // Returns one instance of `value` per request queue worker, the first ones being cloned with
+    // `try_clone`.
+    fn clone_for_request_queues<T>(
+        &self,
+        value: T,
+        try_clone: impl Fn(&T) -> io::Result<T>,
+    ) -> anyhow::Result<std::vec::IntoIter<T>> {
+        let mut values = Vec::with_capacity(self.update_senders.len());
+        for _ in 1..self.update_senders.len() {
+            values.push(
+                try_clone(&value)
+                    .context("the disk image can't be shared between request queues")?,
+            );
+        }
+        values.push(value);
+        Ok(values.into_iter())
+    }
+
+    // Sends the updates built by `update` to all the request queue workers, one per worker, and
+    // waits for them to apply them.
+    async fn update_request_queues(
+        &self,
+        mut update: impl FnMut(oneshot::Sender<anyhow::Result<()>>) -> LogicalUnitUpdate,
+    ) -> anyhow::Result<()> {
+        let mut results = Vec::with_capacity(self.update_senders.len());
+        for sender in &self.update_senders {
+            let (done, result) = oneshot::channel();
+            sender
+                .unbounded_send(update(done))
+                .context("request queue worker is gone")?;
+            results.push(result);
+        }
+        for result in results {
+            result.await.context("request queue worker is gone")??;
+        }
+        Ok(())
+    }
+
+    // Tells the driver that the logical unit `lun` of `target_id` was attached or detached, and
+    // that the list of LUNs changed for the other logical units of the target.
+    fn notify(&self, target_id: TargetId, lun: Lun, target_luns: &BTreeSet<Lun>, reason: u32) {
+        let mut event_queue = self.event_queue.borrow_mut();
+        if self.events.hotplug {
+            event_queue.push(
+                virtio_scsi_event {
+                    event: VIRTIO_SCSI_T_TRANSPORT_RESET,
+                    lun: lun_bytes(target_id, lun),
+                    reason,
+                },
+                &self.interrupt,
+            );
+        }
+        if self.events.change {
+            // The reason holds the ASC and ASCQ of the unit attention, here REPORTED LUNS DATA HAS
+            // CHANGED.
+            const REPORTED_LUNS_DATA_HAS_CHANGED: u32 = 0x3f | 0x0e << 8;
+            for other_lun in target_luns.iter().filter(|l| **l != lun) {
+                event_queue.push(
+                    virtio_scsi_event {
+                        event: VIRTIO_SCSI_T_PARAM_CHANGE,
+                        lun: lun_bytes(target_id, *other_lun),
+                        reason: REPORTED_LUNS_DATA_HAS_CHANGED,
+                    },
+                    &self.interrupt,
+                );
+            }
+        }
+    }
+}
+
+// Executes the commands that attach and detach logical units.
+async fn handle_control_tube(
+    control_tube: &Option<AsyncTube>,
+    hotplug: &Hotplug,
+) -> anyhow::Result<()> {
+    let control_tube = match control_tube {
+        Some(c) => c,
+        None => futures::future::pending().await,
+    };
+    loop {
+        let command = control_tube
+            .next::<ScsiControlCommand>()
+            .await
+            .context("failed to receive a SCSI control command")?;
+        let result = hotplug.execute(command).await;
+        control_tube
+            .send(result)
+            .await
+            .context("failed to send the result of a SCSI control command")?;
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn run_worker(
     ex: &Executor,
     interrupt: Interrupt,
@@ -745,6 +1589,7 @@ async fn run_worker(
     queue_type: QueueType,
     sense_size: u32,
     cdb_size: u32,
+    background: impl Future<Output = anyhow::Result<()>>,
 ) -> anyhow::Result<()> {
     let kill = async_utils::await_and_exit(ex, kill_evt).fuse();
     pin_mut!(kill);
@@ -752,6 +1597,9 @@ async fn run_worker(
     let resample = async_utils::handle_irq_resample(ex, interrupt.clone()).fuse();
     pin_mut!(resample);
 
+    let background = background.fuse();
+    pin_mut!(background);
+
     let kick_evt = queue
         .event()
         .try_clone()
@@ -769,6 +1617,7 @@ async fn run_worker(
 
     futures::select! {
         _ = queue_handler => anyhow::bail!("queue handler exited unexpectedly"),
+        r = background => r.context("background task failed"),
         r = resample => r.context("failed to resample an irq value"),
         r = kill => r.context("failed to wait on the kill event"),
     }
@@ -833,8 +1682,10 @@ async fn process_one_request(
     let reader = &mut avail_desc.reader;
     let resp_writer = &mut avail_desc.writer;
     match queue_type {
-        QueueType::Control { target_ids } => {
-            if let Err(err) = Controller::execute_control(reader, resp_writer, target_ids) {
+        QueueType::Control { target_luns } => {
+            if let Err(err) =
+                Controller::execute_control(reader, resp_writer, &target_luns.borrow())
+            {
                 error!("failed to execute control request: {err}");
             }
             resp_writer.bytes_written()
@@ -908,7 +1759,7 @@ mod tests {
         cdb[5] = start_lba;
         cdb[8] = xfer_blocks;
         virtio_scsi_cmd_req {
-            lun: [1, 0, 0, target_id, 0, 0, 0, 0],
+            lun: [1, target_id, 0, 0, 0, 0, 0, 0],
             cdb,
             ..Default::default()
         }
@@ -970,15 +1821,17 @@ mod tests {
                 let logical_unit = AsyncLogicalUnit {
                     max_lba: 0x1000,
                     block_size,
+                    unmap_granularity: 1,
                     read_only: false,
-                    disk_image,
+                    disk_image: Some(disk_image),
+                    cdrom: None,
                 };
-                (i as TargetId, logical_unit)
+                (i as TargetId, BTreeMap::from([(0, Rc::new(logical_unit))]))
             })
             .collect();
         ex.run_until(process_one_request(
             &mut avail_desc,
-            &QueueType::Request(targets),
+            &QueueType::Request(Rc::new(RefCell::new(targets))),
             VIRTIO_SCSI_SENSE_DEFAULT_SIZE,
             VIRTIO_SCSI_CDB_DEFAULT_SIZE,
         ))
@@ -1078,4 +1931,52 @@ mod tests {
         test_read_blocks(3, blocks, start_lba, xfer_blocks, 128u32);
         test_read_blocks(3, blocks, start_lba, xfer_blocks, 512u32);
     }
+
+    // The LUN number Linux gives to the logical unit addressed by the LUN structure `lun`, like
+    // its `scsilun_to_int`.
+    fn scsilun_to_int(lun: &[u8]) -> u64 {
+        lun.chunks(2)
+            .enumerate()
+            .map(|(i, level)| u64::from(u16::from_be_bytes([level[0], level[1]])) << (i * 16))
+            .sum()
+    }
+
+    #[test]
+    fn lun_bytes_round_trip() {
+        for (target_id, lun) in [(0, 0), (1, 255), (7, 256), (255, DEFAULT_MAX_LUN as Lun)] {
+            assert_eq!(parse_lun(lun_bytes(target_id, lun)), Some((target_id, lun)));
+        }
+        // virtio_scsi in Linux looks up the logical unit of an event with the LUN number given by
+        // bytes 2 and 3 of its `lun` field, which must be the number of the logical unit scanned
+        // from the REPORT LUNS list.
+        for (lun, linux_lun) in [(0, 0), (5, 5), (255, 255), (256, 0x4100), (16383, 0x7fff)] {
+            let event_lun = lun_bytes(3, lun);
+            assert_eq!(event_lun[1], 3);
+            assert_eq!(scsilun_to_int(&event_lun[2..]), linux_lun);
+        }
+        // The peripheral device addressing method used by Linux for LUNs below 256.
+        assert_eq!(parse_lun([1, 2, 0, 5, 0, 0, 0, 0]), Some((2, 5)));
+        assert_eq!(parse_lun([0, 2, 0, 5, 0, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn tmf_with_multiple_luns() {
+        let target_luns = BTreeMap::from([(1, BTreeSet::from([0, 3]))]);
+        let tmf = |subtype, target_id, lun| {
+            let tmf = virtio_scsi_ctrl_tmf_req {
+                type_: VIRTIO_SCSI_T_TMF,
+                subtype,
+                lun: lun_bytes(target_id, lun),
+                tag: 0,
+            };
+            Controller::execute_tmf(tmf, &target_luns).response
+        };
+        let reset = VIRTIO_SCSI_T_TMF_LOGICAL_UNIT_RESET;
+        assert_eq!(tmf(reset, 1, 3), VIRTIO_SCSI_S_FUNCTION_SUCCEEDED as u8);
+        assert_eq!(tmf(reset, 1, 2), VIRTIO_SCSI_S_INCORRECT_LUN as u8);
+        assert_eq!(tmf(reset, 2, 0), VIRTIO_SCSI_S_BAD_TARGET as u8);
+        let reset = VIRTIO_SCSI_T_TMF_I_T_NEXUS_RESET;
+        assert_eq!(tmf(reset, 1, 2), VIRTIO_SCSI_S_FUNCTION_SUCCEEDED as u8);
+        assert_eq!(tmf(reset, 2, 0), VIRTIO_SCSI_S_BAD_TARGET as u8);
+    }
 }
@@ -36,6 +36,16 @@ pub struct ScsiOption {
     /// adding specific command-line options.
     #[serde(default)]
     pub root: bool,
+    /// The id of the target the device belongs to. Defaults to the index of the device.
+    #[serde(default)]
+    pub target: Option<u8>,
+    /// The logical unit number of the device in its target.
+    #[serde(default)]
+    pub lun: u16,
+    /// Whether the device is a CD-ROM drive with the image as its medium. CD-ROM drives are read
+    /// only and have 2048-byte blocks.
+    #[serde(default)]
+    pub cdrom: bool,
 }
 
 #[cfg(test)]
@@ -56,6 +66,9 @@ mod tests {
                 read_only: false,
                 block_size: 512,
                 root: false,
+                target: None,
+                lun: 0,
+                cdrom: false,
             }
         );
 
@@ -67,6 +80,9 @@ mod tests {
                 read_only: true,
                 block_size: 512,
                 root: false,
+                target: None,
+                lun: 0,
+                cdrom: false,
             }
         );
 
@@ -78,6 +94,9 @@ mod tests {
                 read_only: false,
                 block_size: 1024,
                 root: false,
+                target: None,
+                lun: 0,
+                cdrom: false,
             }
         );
 
@@ -90,6 +109,37 @@ mod tests {
                 read_only: false,
                 block_size: 1024,
                 root: true,
+                target: None,
+                lun: 0,
+                cdrom: false,
+            }
+        );
+
+        let scsi_option = from_key_values::<ScsiOption>("/path/to/image,target=2,lun=300").unwrap();
+        assert_eq!(
+            scsi_option,
+            ScsiOption {
+                path: Path::new("/path/to/image").to_path_buf(),
+                read_only: false,
+                block_size: 512,
+                root: false,
+                target: Some(2),
+                lun: 300,
+                cdrom: false,
+            }
+        );
+
+        let scsi_option = from_key_values::<ScsiOption>("/path/to/image.iso,cdrom").unwrap();
+        assert_eq!(
+            scsi_option,
+            ScsiOption {
+                path: Path::new("/path/to/image.iso").to_path_buf(),
+                read_only: false,
+                block_size: 512,
+                root: false,
+                target: None,
+                lun: 0,
+                cdrom: true,
             }
         );
     }
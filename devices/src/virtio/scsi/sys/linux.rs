@@ -4,6 +4,7 @@
 
 use std::fs::File;
 use std::fs::OpenOptions;
+use std::path::Path;
 
 use anyhow::Context;
 use base::flock;
@@ -15,22 +16,33 @@ use crate::virtio::scsi::ScsiOption;
 
 impl ScsiOption {
     pub fn open(&self) -> anyhow::Result<Box<dyn DiskFile>> {
+        // The medium of CD-ROM drives is read only.
+        let read_only = self.read_only || self.cdrom;
         let mut options = OpenOptions::new();
-        options.read(true).write(!self.read_only);
+        options.read(true).write(!read_only);
 
         let raw_image: File = open_file_or_duplicate(&self.path, &options)
             .with_context(|| format!("failed to load disk image {}", self.path.display()))?;
-        // Lock the disk image to prevent other crosvm instances from using it.
-        let lock_op = if self.read_only {
-            FlockOperation::LockShared
-        } else {
-            FlockOperation::LockExclusive
-        };
-        flock(&raw_image, lock_op, true)
-            .with_context(|| format!("failed to lock disk image {}", self.path.display()))?;
-
-        // We only support sparse disks for now.
-        disk::create_disk_file(raw_image, true, disk::MAX_NESTING_DEPTH, &self.path)
-            .context("create_disk_file failed")
+        create_disk(raw_image, read_only, &self.path)
     }
 }
+
+/// Creates the disk of a SCSI logical unit from the already opened image at `path`.
+pub fn create_disk(
+    raw_image: File,
+    read_only: bool,
+    path: &Path,
+) -> anyhow::Result<Box<dyn DiskFile>> {
+    // Lock the disk image to prevent other crosvm instances from using it.
+    let lock_op = if read_only {
+        FlockOperation::LockShared
+    } else {
+        FlockOperation::LockExclusive
+    };
+    flock(&raw_image, lock_op, true)
+        .with_context(|| format!("failed to lock disk image {}", path.display()))?;
+
+    // We only support sparse disks for now.
+    disk::create_disk_file(raw_image, true, disk::MAX_NESTING_DEPTH, path)
+        .context("create_disk_file failed")
+}
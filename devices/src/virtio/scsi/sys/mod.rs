@@ -5,7 +5,9 @@
 cfg_if::cfg_if! {
     if #[cfg(any(target_os = "android", target_os = "linux"))] {
         mod linux;
+        pub(crate) use linux::create_disk;
     } else if #[cfg(windows)] {
         mod windows;
+        pub(crate) use windows::create_disk;
     }
 }
@@ -2,6 +2,9 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fs::File;
+use std::path::Path;
+
 use anyhow::bail;
 use anyhow::Context;
 use disk::DiskFile;
@@ -13,3 +16,12 @@ impl ScsiOption {
         bail!("ScsiOption::open() is yet to be implemented for windows.")
     }
 }
+
+/// Creates the disk of a SCSI logical unit from the already opened image at `path`.
+pub fn create_disk(
+    _raw_image: File,
+    _read_only: bool,
+    _path: &Path,
+) -> anyhow::Result<Box<dyn DiskFile>> {
+    bail!("create_disk() is yet to be implemented for windows.")
+}
@@ -11,7 +11,10 @@ use std::io::Write;
 use std::path::Path;
 
 use audio_streams::NoopStreamSourceGenerator;
+use audio_util::Error as AudioUtilError;
+use audio_util::FileCaptureStreamSourceGenerator;
 use audio_util::FileStreamSourceGenerator;
+use audio_util::PcmFormat;
 use base::error;
 use base::open_file_or_duplicate;
 use base::AsRawDescriptor;
@@ -27,6 +30,8 @@ use crate::virtio::snd::sys::SysAudioStreamSourceGenerator;
 pub enum Error {
     #[error("Failed to allocate space: {0}")]
     AllocateSpace(IOError),
+    #[error("Failed to load capture file: {0}")]
+    LoadCaptureFile(AudioUtilError),
     #[error("Failed to open file: {0}")]
     OpenFile(base::Error),
 }
@@ -51,6 +56,31 @@ fn open_playback_file(dir_path: &String, stream_id: usize) -> Result<File, Error
     Ok(file)
 }
 
+// Opens the file captured by `stream_id`, a WAV file if there is one, or a raw PCM file otherwise.
+fn create_capture_generator(
+    params: &Parameters,
+    stream_id: usize,
+) -> Result<FileCaptureStreamSourceGenerator, Error> {
+    let wav_path = Path::new(&params.capture_path).join(format!("stream-{}.wav", stream_id));
+    if wav_path.exists() {
+        let file = open_file_or_duplicate(wav_path, OpenOptions::new().read(true))
+            .map_err(Error::OpenFile)?;
+        return FileCaptureStreamSourceGenerator::from_wav(file, params.capture_loop)
+            .map_err(Error::LoadCaptureFile);
+    }
+
+    let raw_path = Path::new(&params.capture_path).join(format!("stream-{}.raw", stream_id));
+    let file =
+        open_file_or_duplicate(raw_path, OpenOptions::new().read(true)).map_err(Error::OpenFile)?;
+    let format = PcmFormat {
+        format: params.capture_raw_format,
+        num_channels: params.capture_raw_channels,
+        frame_rate: params.capture_raw_rate,
+    };
+    FileCaptureStreamSourceGenerator::from_raw(file, format, params.capture_loop)
+        .map_err(Error::LoadCaptureFile)
+}
+
 pub(crate) fn create_file_stream_source_generators(
     params: &Parameters,
     snd_data: &SndData,
@@ -66,8 +96,10 @@ pub(crate) fn create_file_stream_source_generators(
             keep_rds.push(file.as_raw_descriptor());
 
             Box::new(FileStreamSourceGenerator::new(file, params.playback_size))
+        } else if params.capture && !params.capture_path.is_empty() {
+            // The capture files are read entirely here, so they don't need to be kept open.
+            Box::new(create_capture_generator(params, stream)?)
         } else {
-            // Capture is not supported yet
             Box::new(NoopStreamSourceGenerator::new())
         };
 
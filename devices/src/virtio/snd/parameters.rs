@@ -5,6 +5,7 @@
 use std::num::ParseIntError;
 use std::str::ParseBoolError;
 
+use audio_streams::SampleFormat;
 use audio_streams::StreamEffect;
 #[cfg(all(unix, feature = "audio_cras"))]
 use libcras::CrasClientType;
@@ -85,6 +86,9 @@ pub struct PCMDeviceParameters {
     pub client_type: Option<CrasClientType>,
     #[cfg(all(unix, feature = "audio_cras"))]
     pub stream_type: Option<CrasStreamType>,
+    /// Name of the ALSA PCM used by the ALSA, PipeWire and PulseAudio backends, e.g. `hw:0,0`.
+    #[cfg(all(unix, feature = "audio_alsa"))]
+    pub device: Option<String>,
     pub effects: Option<Vec<StreamEffect>>,
 }
 
@@ -100,6 +104,11 @@ pub struct Parameters {
     pub num_input_streams: u32,
     pub playback_path: String,
     pub playback_size: usize,
+    pub capture_path: String,
+    pub capture_loop: bool,
+    pub capture_raw_format: SampleFormat,
+    pub capture_raw_channels: usize,
+    pub capture_raw_rate: u32,
     #[cfg(all(unix, feature = "audio_cras"))]
     #[serde(deserialize_with = "libcras::deserialize_cras_client_type")]
     pub client_type: CrasClientType,
@@ -120,6 +129,11 @@ impl Default for Parameters {
             num_input_streams: 1,
             playback_path: "".to_string(),
             playback_size: 0,
+            capture_path: "".to_string(),
+            capture_loop: false,
+            capture_raw_format: SampleFormat::S16LE,
+            capture_raw_channels: 2,
+            capture_raw_rate: 48000,
             #[cfg(all(unix, feature = "audio_cras"))]
             client_type: CrasClientType::CRAS_CLIENT_TYPE_CROSVM,
             #[cfg(all(unix, feature = "audio_cras"))]
@@ -292,6 +306,35 @@ mod tests {
         check_failure("output_device_config=[[effects=[none]]]");
     }
 
+    #[test]
+    fn file_parameters_fromstr() {
+        let params: Parameters = serde_keyvalue::from_key_values(
+            "backend=file,capture=true,playback_path=/tmp/out,playback_size=1024,\
+            capture_path=/tmp/in,capture_loop=true,capture_raw_format=U8,\
+            capture_raw_channels=1,capture_raw_rate=8000",
+        )
+        .expect("parse should have succeded");
+        assert_eq!(params.backend, StreamSourceBackend::FILE);
+        assert_eq!(params.playback_path, "/tmp/out");
+        assert_eq!(params.playback_size, 1024);
+        assert_eq!(params.capture_path, "/tmp/in");
+        assert!(params.capture_loop);
+        assert_eq!(params.capture_raw_format, SampleFormat::U8);
+        assert_eq!(params.capture_raw_channels, 1);
+        assert_eq!(params.capture_raw_rate, 8000);
+
+        let params: Parameters =
+            serde_keyvalue::from_key_values("backend=file,capture_path=/tmp/in")
+                .expect("parse should have succeded");
+        assert!(!params.capture_loop);
+        assert_eq!(params.capture_raw_format, SampleFormat::S16LE);
+        assert_eq!(params.capture_raw_channels, 2);
+        assert_eq!(params.capture_raw_rate, 48000);
+
+        // Invalid sample format of raw capture files
+        check_failure("capture_raw_format=S16_LE");
+    }
+
     #[test]
     #[cfg(all(unix, feature = "audio_cras"))]
     fn cras_parameters_fromstr() {
@@ -363,11 +406,13 @@ mod tests {
                     client_type: Some(CrasClientType::CRAS_CLIENT_TYPE_CROSVM),
                     stream_type: None,
                     effects: None,
+                    ..Default::default()
                 },
                 PCMDeviceParameters{
                     client_type: Some(CrasClientType::CRAS_CLIENT_TYPE_ARCVM),
                     stream_type: Some(CrasStreamType::CRAS_STREAM_TYPE_PRO_AUDIO),
                     effects: None,
+                    ..Default::default()
                 },
                 Default::default(),
                 ],
@@ -384,16 +429,19 @@ mod tests {
                     client_type: Some(CrasClientType::CRAS_CLIENT_TYPE_CROSVM),
                     stream_type: None,
                     effects: None,
+                    ..Default::default()
                 },
                 PCMDeviceParameters{
                     client_type: Some(CrasClientType::CRAS_CLIENT_TYPE_ARCVM),
                     stream_type: Some(CrasStreamType::CRAS_STREAM_TYPE_PRO_AUDIO),
                     effects: Some(vec![StreamEffect::EchoCancellation]),
+                    ..Default::default()
                 },
                 PCMDeviceParameters{
                     client_type: None,
                     stream_type: None,
                     effects: Some(vec![StreamEffect::EchoCancellation]),
+                    ..Default::default()
                 },
                 Default::default(),
                 ],
@@ -406,6 +454,58 @@ mod tests {
         check_failure("output_device_config=[[stream_type=none]]");
     }
 
+    #[test]
+    #[cfg(all(unix, feature = "audio_alsa"))]
+    fn alsa_parameters_fromstr() {
+        fn alsa_check_success(
+            s: &str,
+            backend: StreamSourceBackend,
+            output_device_config: Vec<PCMDeviceParameters>,
+            input_device_config: Vec<PCMDeviceParameters>,
+        ) {
+            let params: Parameters =
+                serde_keyvalue::from_key_values(s).expect("parse should have succeded");
+            assert_eq!(params.backend, backend);
+            assert_eq!(params.output_device_config, output_device_config);
+            assert_eq!(params.input_device_config, input_device_config);
+        }
+
+        alsa_check_success(
+            "backend=alsa",
+            StreamSourceBackend::Sys(SysStreamSourceBackend::ALSA),
+            vec![],
+            vec![],
+        );
+        alsa_check_success(
+            "backend=pipewire",
+            StreamSourceBackend::Sys(SysStreamSourceBackend::PIPEWIRE),
+            vec![],
+            vec![],
+        );
+        alsa_check_success(
+            "backend=pulseaudio",
+            StreamSourceBackend::Sys(SysStreamSourceBackend::PULSEAUDIO),
+            vec![],
+            vec![],
+        );
+        alsa_check_success(
+            "backend=alsa,output_device_config=[[device=\"hw:Loopback,0,0\"],[]],input_device_config=[[device=plughw:1,effects=[aec]]]",
+            StreamSourceBackend::Sys(SysStreamSourceBackend::ALSA),
+            vec![
+                PCMDeviceParameters {
+                    device: Some("hw:Loopback,0,0".to_owned()),
+                    ..Default::default()
+                },
+                Default::default(),
+            ],
+            vec![PCMDeviceParameters {
+                device: Some("plughw:1".to_owned()),
+                effects: Some(vec![StreamEffect::EchoCancellation]),
+                ..Default::default()
+            }],
+        );
+    }
+
     #[test]
     fn get_device_params_output() {
         let params = Parameters {
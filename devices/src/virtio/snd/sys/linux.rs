@@ -7,9 +7,13 @@ use audio_streams::capture::AsyncCaptureBuffer;
 use audio_streams::capture::AsyncCaptureBufferStream;
 use audio_streams::AsyncPlaybackBufferStream;
 use audio_streams::BoxError;
+#[cfg(feature = "audio_alsa")]
+use audio_streams::NoopStreamSourceGenerator;
 use audio_streams::StreamSource;
 use audio_streams::StreamSourceGenerator;
-#[cfg(feature = "audio_cras")]
+#[cfg(feature = "audio_alsa")]
+use audio_util::AlsaStreamSourceGenerator;
+#[cfg(any(feature = "audio_alsa", feature = "audio_cras"))]
 use base::error;
 use base::set_rt_prio_limit;
 use base::set_rt_round_robin;
@@ -29,6 +33,8 @@ use crate::virtio::snd::common_backend::DirectionalStream;
 use crate::virtio::snd::common_backend::Error;
 use crate::virtio::snd::common_backend::PcmResponse;
 use crate::virtio::snd::common_backend::SndData;
+#[cfg(feature = "audio_alsa")]
+use crate::virtio::snd::constants::VIRTIO_SND_D_INPUT;
 use crate::virtio::snd::parameters::Error as ParametersError;
 use crate::virtio::snd::parameters::Parameters;
 
@@ -50,6 +56,15 @@ pub(crate) struct SysAsyncStreamObjects {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub enum StreamSourceBackend {
+    /// ALSA PCMs, `default` unless the PCM devices name another one.
+    #[cfg(feature = "audio_alsa")]
+    ALSA,
+    /// The ALSA backend with the `pipewire` PCM of the ALSA plugin of PipeWire as default PCM.
+    #[cfg(feature = "audio_alsa")]
+    PIPEWIRE,
+    /// The ALSA backend with the `pulse` PCM of the ALSA plugin of PulseAudio as default PCM.
+    #[cfg(feature = "audio_alsa")]
+    PULSEAUDIO,
     #[cfg(feature = "audio_cras")]
     CRAS,
 }
@@ -58,6 +73,12 @@ pub enum StreamSourceBackend {
 impl From<StreamSourceBackend> for String {
     fn from(backend: StreamSourceBackend) -> Self {
         match backend {
+            #[cfg(feature = "audio_alsa")]
+            StreamSourceBackend::ALSA => "alsa".to_owned(),
+            #[cfg(feature = "audio_alsa")]
+            StreamSourceBackend::PIPEWIRE => "pipewire".to_owned(),
+            #[cfg(feature = "audio_alsa")]
+            StreamSourceBackend::PULSEAUDIO => "pulseaudio".to_owned(),
             #[cfg(feature = "audio_cras")]
             StreamSourceBackend::CRAS => "cras".to_owned(),
         }
@@ -69,6 +90,12 @@ impl TryFrom<&str> for StreamSourceBackend {
 
     fn try_from(s: &str) -> Result<Self, Self::Error> {
         match s {
+            #[cfg(feature = "audio_alsa")]
+            "alsa" => Ok(StreamSourceBackend::ALSA),
+            #[cfg(feature = "audio_alsa")]
+            "pipewire" => Ok(StreamSourceBackend::PIPEWIRE),
+            #[cfg(feature = "audio_alsa")]
+            "pulseaudio" => Ok(StreamSourceBackend::PULSEAUDIO),
             #[cfg(feature = "audio_cras")]
             "cras" => Ok(StreamSourceBackend::CRAS),
             _ => Err(ParametersError::InvalidBackend),
@@ -76,6 +103,34 @@ impl TryFrom<&str> for StreamSourceBackend {
     }
 }
 
+// Creates the generators of the ALSA PCMs used by the PCM devices of the guest. Each PCM device can
+// be given its own ALSA PCM with the `device` parameter, and otherwise uses `default_device`.
+#[cfg(feature = "audio_alsa")]
+pub(crate) fn create_alsa_stream_source_generators(
+    params: &Parameters,
+    snd_data: &SndData,
+    default_device: &str,
+) -> Vec<Box<dyn StreamSourceGenerator>> {
+    let mut generators: Vec<Box<dyn StreamSourceGenerator>> =
+        Vec::with_capacity(snd_data.pcm_info_len());
+    for pcm_info in snd_data.pcm_info_iter() {
+        if pcm_info.direction == VIRTIO_SND_D_INPUT && !params.capture {
+            generators.push(Box::new(NoopStreamSourceGenerator::new()));
+            continue;
+        }
+        let device_params = params.get_device_params(pcm_info).unwrap_or_else(|err| {
+            error!("Create alsa stream source generator error: {}", err);
+            Default::default()
+        });
+        generators.push(Box::new(AlsaStreamSourceGenerator::new(
+            device_params
+                .device
+                .unwrap_or_else(|| default_device.to_owned()),
+        )));
+    }
+    generators
+}
+
 #[cfg(feature = "audio_cras")]
 pub(crate) fn create_cras_stream_source_generators(
     params: &Parameters,
@@ -107,6 +162,20 @@ pub(crate) fn create_stream_source_generators(
     snd_data: &SndData,
 ) -> Vec<Box<dyn StreamSourceGenerator>> {
     match backend {
+        #[cfg(feature = "audio_alsa")]
+        StreamSourceBackend::ALSA => {
+            create_alsa_stream_source_generators(params, snd_data, "default")
+        }
+        // The PCMs of the ALSA plugins of PipeWire and PulseAudio, which are clients of their
+        // servers.
+        #[cfg(feature = "audio_alsa")]
+        StreamSourceBackend::PIPEWIRE => {
+            create_alsa_stream_source_generators(params, snd_data, "pipewire")
+        }
+        #[cfg(feature = "audio_alsa")]
+        StreamSourceBackend::PULSEAUDIO => {
+            create_alsa_stream_source_generators(params, snd_data, "pulse")
+        }
         #[cfg(feature = "audio_cras")]
         StreamSourceBackend::CRAS => create_cras_stream_source_generators(params, snd_data),
     }
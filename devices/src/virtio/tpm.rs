@@ -25,6 +25,7 @@ use super::DeviceType;
 use super::Interrupt;
 use super::Queue;
 use super::VirtioDevice;
+use crate::tpm::TpmBackend;
 
 // A single queue of size 2. The guest kernel driver will enqueue a single
 // descriptor chain containing one command buffer and one response buffer at a
@@ -32,10 +33,10 @@ use super::VirtioDevice;
 const QUEUE_SIZE: u16 = 2;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
 
-// Maximum command or response message size permitted by this device
-// implementation. Named to match the equivalent constant in Linux's tpm.h.
-// There is no hard requirement that the value is the same but it makes sense.
-const TPM_BUFSIZE: usize = 4096;
+/// Maximum command or response message size permitted by this device
+/// implementation. Named to match the equivalent constant in Linux's tpm.h.
+/// There is no hard requirement that the value is the same but it makes sense.
+pub const TPM_BUFSIZE: usize = 4096;
 
 struct Worker {
     interrupt: Interrupt,
@@ -43,10 +44,6 @@ struct Worker {
     backend: Box<dyn TpmBackend>,
 }
 
-pub trait TpmBackend: Send {
-    fn execute_command<'a>(&'a mut self, command: &[u8]) -> &'a [u8];
-}
-
 impl Worker {
     fn perform_work(&mut self, desc: &mut DescriptorChain) -> Result<u32> {
         let available_bytes = desc.reader.available_bytes();
@@ -139,6 +139,7 @@ impl VhostUserDevice for BlockAsync {
             self.read_only,
             self.sparse,
             self.id,
+            Arc::clone(&self.throttle),
         )));
 
         let backend = BlockBackend {
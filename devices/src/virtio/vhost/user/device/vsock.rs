@@ -9,8 +9,11 @@ use std::mem::size_of;
 use std::num::Wrapping;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
+use std::path::PathBuf;
 use std::str;
 
+use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use argh::FromArgs;
 use base::AsRawDescriptor;
@@ -18,6 +21,7 @@ use base::Event;
 use base::SafeDescriptor;
 use cros_async::Executor;
 use data_model::Le64;
+use hypervisor::ProtectionType;
 use vhost::Vhost;
 use vhost::Vsock;
 use vm_memory::GuestMemory;
@@ -37,15 +41,24 @@ use vmm_vhost::VhostUserSlaveReqHandler;
 use vmm_vhost::VHOST_USER_F_PROTOCOL_FEATURES;
 use zerocopy::AsBytes;
 
+use crate::virtio::base_features;
 use crate::virtio::device_constants::vsock::NUM_QUEUES;
 use crate::virtio::vhost::user::device::handler::vmm_va_to_gpa;
+use crate::virtio::vhost::user::device::handler::DeviceRequestHandler;
+use crate::virtio::vhost::user::device::handler::Error as DeviceError;
 use crate::virtio::vhost::user::device::handler::MappingInfo;
+use crate::virtio::vhost::user::device::handler::VhostUserBackend;
 use crate::virtio::vhost::user::device::handler::VhostUserRegularOps;
 use crate::virtio::vhost::user::VhostUserDevice;
 use crate::virtio::vhost::user::VhostUserListener;
 use crate::virtio::vhost::user::VhostUserListenerTrait;
+use crate::virtio::vsock::HybridVsock;
+use crate::virtio::vsock::VsockQueue;
+use crate::virtio::vsock::VsockQueues;
+use crate::virtio::Interrupt;
 use crate::virtio::Queue;
 use crate::virtio::QueueConfig;
+use crate::virtio::VirtioDevice;
 
 const EVENT_QUEUE: usize = NUM_QUEUES - 1;
 
@@ -453,6 +466,152 @@ impl VhostUserSlaveReqHandler for VsockBackend {
     }
 }
 
+/// Vhost-user backend of the hybrid vsock device. The device worker needs all the queues, so it
+/// only runs once they are all started.
+struct HybridVsockBackend {
+    device: HybridVsock,
+    acked_features: u64,
+    acked_protocol_features: VhostUserProtocolFeatures,
+    // Queues started by the frontend while the worker isn't running.
+    started_queues: [Option<VsockQueue>; NUM_QUEUES],
+    // Queues given back by the worker which haven't been stopped by the frontend yet.
+    stopped_queues: [Option<Queue>; NUM_QUEUES],
+}
+
+impl VhostUserDevice for HybridVsock {
+    fn max_queue_num(&self) -> usize {
+        NUM_QUEUES
+    }
+
+    fn into_req_handler(
+        self: Box<Self>,
+        _ex: &Executor,
+    ) -> anyhow::Result<Box<dyn vmm_vhost::VhostUserSlaveReqHandler>> {
+        let backend = HybridVsockBackend {
+            device: *self,
+            acked_features: 0,
+            acked_protocol_features: VhostUserProtocolFeatures::empty(),
+            started_queues: Default::default(),
+            stopped_queues: Default::default(),
+        };
+
+        Ok(Box::new(DeviceRequestHandler::new(Box::new(backend))))
+    }
+}
+
+impl VhostUserBackend for HybridVsockBackend {
+    fn max_queue_num(&self) -> usize {
+        NUM_QUEUES
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | 1 << VHOST_USER_F_PROTOCOL_FEATURES
+    }
+
+    fn ack_features(&mut self, value: u64) -> anyhow::Result<()> {
+        let unrequested_features = value & !self.features();
+        if unrequested_features != 0 {
+            bail!("invalid features are given: {:#x}", unrequested_features);
+        }
+
+        self.acked_features |= value;
+        self.device.ack_features(value);
+
+        Ok(())
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::CONFIG
+    }
+
+    fn ack_protocol_features(&mut self, features: u64) -> anyhow::Result<()> {
+        let features = VhostUserProtocolFeatures::from_bits(features)
+            .ok_or_else(|| anyhow!("invalid protocol features are given: {:#x}", features))?;
+        let supported = self.protocol_features();
+        self.acked_protocol_features = features & supported;
+        Ok(())
+    }
+
+    fn acked_protocol_features(&self) -> u64 {
+        self.acked_protocol_features.bits()
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.device.read_config(offset, data)
+    }
+
+    fn start_queue(
+        &mut self,
+        idx: usize,
+        queue: Queue,
+        _mem: GuestMemory,
+        doorbell: Interrupt,
+    ) -> anyhow::Result<()> {
+        if idx >= NUM_QUEUES {
+            bail!("invalid queue index {}", idx);
+        }
+        self.started_queues[idx] = Some(VsockQueue {
+            queue,
+            interrupt: doorbell,
+        });
+
+        if self.started_queues.iter().all(Option::is_some) {
+            let [rx, tx, event] = std::mem::take(&mut self.started_queues);
+            self.device.start_worker(VsockQueues {
+                rx: rx.unwrap(),
+                tx: tx.unwrap(),
+                event: event.unwrap(),
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn stop_queue(&mut self, idx: usize) -> anyhow::Result<Queue> {
+        if let Some(queues) = self.device.stop_worker() {
+            self.stopped_queues = [
+                Some(queues.rx.queue),
+                Some(queues.tx.queue),
+                Some(queues.event.queue),
+            ];
+        }
+
+        if let Some(queue) = self.started_queues.get_mut(idx).and_then(Option::take) {
+            return Ok(queue.queue);
+        }
+        self.stopped_queues
+            .get_mut(idx)
+            .and_then(Option::take)
+            .ok_or_else(|| anyhow::Error::new(DeviceError::WorkerNotFound))
+    }
+
+    fn reset(&mut self) {
+        for queue_num in 0..NUM_QUEUES {
+            // Queues which were never started can't be stopped.
+            let _ = self.stop_queue(queue_num);
+        }
+        VirtioDevice::reset(&mut self.device);
+    }
+
+    fn stop_non_queue_workers(&mut self) -> anyhow::Result<()> {
+        // The device has no worker besides the one handling the queues.
+        Ok(())
+    }
+
+    fn snapshot(&self) -> anyhow::Result<Vec<u8>> {
+        serde_json::to_vec(&self.device.snapshot()?).context("failed to serialize vsock snapshot")
+    }
+
+    fn restore(&mut self, data: Vec<u8>) -> anyhow::Result<()> {
+        let data = serde_json::from_slice(&data).context("failed to deserialize vsock snapshot")?;
+        self.device.virtio_restore(data)
+    }
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "vsock")]
 /// Vsock device
@@ -470,6 +629,9 @@ pub struct Options {
     )]
     /// path to the vhost-vsock control socket
     vhost_socket: String,
+    #[argh(option, arg_name = "PATH")]
+    /// path of the host unix socket to map the guest ports to, instead of using vhost-vsock
+    uds_path: Option<PathBuf>,
 }
 
 /// Returns an error if the given `args` is invalid or the device fails to run.
@@ -478,6 +640,15 @@ pub fn run_vsock_device(opts: Options) -> anyhow::Result<()> {
 
     let listener = VhostUserListener::new_socket(&opts.socket, None)?;
 
+    if let Some(uds_path) = opts.uds_path {
+        let vsock_device = Box::new(HybridVsock::new(
+            opts.cid,
+            &uds_path,
+            base_features(ProtectionType::Unprotected),
+        )?);
+        return listener.run_device(ex, vsock_device);
+    }
+
     let vsock_device = Box::new(VhostUserVsockDevice::new(opts.cid, opts.vhost_socket)?);
 
     listener.run_device(ex, vsock_device)
@@ -4,10 +4,21 @@
 
 //! This module implements the virtio vsock device.
 //!
-//! Currently, this is only implemented for Windows.
-//! For Linux, please use the vhost-vsock device, which delegates the vsock
-//! implementation to the kernel.
+//! On Windows, guest ports are mapped to named pipes.
+//! On Linux, the vhost-vsock device, which delegates the vsock implementation to the kernel, is
+//! used by default. The hybrid vsock device maps guest ports to host unix sockets instead, for
+//! hosts without vhost-vsock.
 
+pub mod protocol;
 mod sys;
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use sys::HybridVsock;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use sys::HybridVsockError;
 pub use sys::Vsock;
 pub use sys::VsockConfig;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(crate) use sys::VsockQueue;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub(crate) use sys::VsockQueues;
@@ -6,6 +6,10 @@ cfg_if::cfg_if! {
     if #[cfg(any(target_os = "android", target_os = "linux"))] {
         mod linux;
         use linux as platform;
+        pub use linux::HybridVsock;
+        pub use linux::HybridVsockError;
+        pub(crate) use linux::VsockQueue;
+        pub(crate) use linux::VsockQueues;
         pub use crate::virtio::vhost::Vsock;
     } else if #[cfg(windows)] {
         mod windows;
@@ -2,9 +2,15 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+mod hybrid;
+
 use std::path::Path;
 use std::path::PathBuf;
 
+pub use hybrid::HybridVsock;
+pub use hybrid::HybridVsockError;
+pub(crate) use hybrid::VsockQueue;
+pub(crate) use hybrid::VsockQueues;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
@@ -25,6 +31,10 @@ pub struct VsockConfig {
     /// Path to the vhost-vsock device.
     #[serde(default = "default_vsock_path", rename = "device")]
     pub vhost_device: PathBuf,
+    /// Path of the host unix socket to use instead of vhost-vsock. If set, the device is
+    /// implemented in userspace and guest ports are mapped to host unix sockets.
+    #[serde(default, rename = "uds-path")]
+    pub uds_path: Option<PathBuf>,
 }
 
 impl VsockConfig {
@@ -37,6 +47,7 @@ impl VsockConfig {
             vhost_device: vhost_device
                 .map(|p| PathBuf::from(p.as_ref()))
                 .unwrap_or_else(|| PathBuf::from(VHOST_VSOCK_DEFAULT_PATH)),
+            uds_path: None,
         }
     }
 }
@@ -61,6 +72,7 @@ mod tests {
             VsockConfig {
                 vhost_device: VHOST_VSOCK_DEFAULT_PATH.into(),
                 cid: 56,
+                uds_path: None,
             }
         );
 
@@ -80,6 +92,7 @@ mod tests {
                 #[cfg(any(target_os = "android", target_os = "linux"))]
                 vhost_device: VHOST_VSOCK_DEFAULT_PATH.into(),
                 cid: 78,
+                uds_path: None,
             }
         );
 
@@ -97,7 +110,7 @@ mod tests {
             from_vsock_arg("invalid=foo").unwrap_err(),
             ParseError {
                 kind: ErrorKind::SerdeError(
-                    "unknown field `invalid`, expected `cid` or `device`".into()
+                    "unknown field `invalid`, expected one of `cid`, `device`, `uds-path`".into()
                 ),
                 pos: 0,
             }
@@ -109,6 +122,7 @@ mod tests {
             VsockConfig {
                 vhost_device: "/some/path".into(),
                 cid: 56,
+                uds_path: None,
             }
         );
 
@@ -118,6 +132,7 @@ mod tests {
             VsockConfig {
                 vhost_device: "/some/path".into(),
                 cid: 56,
+                uds_path: None,
             }
         );
 
@@ -130,6 +145,16 @@ mod tests {
             }
         );
 
+        // Unix socket path
+        assert_eq!(
+            from_vsock_arg("cid=3,uds-path=/run/vm.vsock").unwrap(),
+            VsockConfig {
+                vhost_device: VHOST_VSOCK_DEFAULT_PATH.into(),
+                cid: 3,
+                uds_path: Some("/run/vm.vsock".into()),
+            }
+        );
+
         // Device passed twice
         assert_eq!(
             from_vsock_arg("cid=56,device=42,device=/some/path").unwrap_err(),
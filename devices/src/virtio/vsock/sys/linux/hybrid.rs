@@ -0,0 +1,1170 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Userspace virtio-vsock device which maps the guest ports to host unix sockets.
+//!
+//! This follows the "hybrid vsock" model of Firecracker, for hosts without vhost-vsock. The device
+//! listens on a unix socket at `uds_path`:
+//!
+//! - To connect to port `PORT` of the guest, a host process connects to `uds_path` and writes
+//!   `CONNECT PORT\n`. Once the guest accepts the connection, the device replies `OK HOST_PORT\n`,
+//!   where `HOST_PORT` is the port of the host side of the connection, as seen by the guest. If the
+//!   guest refuses the connection, the socket is closed.
+//! - When the guest connects to port `PORT` of the host (CID 2), the device connects to the unix
+//!   socket `<uds_path>_PORT`, on which a host process is expected to listen.
+//!
+//! Once connected, the data is forwarded as is between the guest and the host socket.
+
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::mem::size_of;
+use std::net::Shutdown;
+use std::num::Wrapping;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::result;
+use std::str;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use base::error;
+use base::info;
+use base::warn;
+use base::AsRawDescriptor;
+use base::Event;
+use base::EventToken;
+use base::EventType;
+use base::RawDescriptor;
+use base::WaitContext;
+use base::WorkerThread;
+use data_model::Le32;
+use data_model::Le64;
+use remain::sorted;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+use virtio_sys::virtio_vsock::virtio_vsock_event_id_VIRTIO_VSOCK_EVENT_TRANSPORT_RESET;
+use virtio_sys::virtio_vsock::virtio_vsock_shutdown_VIRTIO_VSOCK_SHUTDOWN_RCV;
+use virtio_sys::virtio_vsock::virtio_vsock_shutdown_VIRTIO_VSOCK_SHUTDOWN_SEND;
+use vm_memory::GuestMemory;
+use zerocopy::AsBytes;
+
+use crate::virtio::copy_config;
+use crate::virtio::device_constants::vsock::NUM_QUEUES;
+use crate::virtio::vsock::protocol::virtio_vsock_config;
+use crate::virtio::vsock::protocol::virtio_vsock_event;
+use crate::virtio::vsock::protocol::virtio_vsock_hdr;
+use crate::virtio::vsock::protocol::vsock_op;
+use crate::virtio::vsock::protocol::TYPE_STREAM_SOCKET;
+use crate::virtio::DeviceType;
+use crate::virtio::Interrupt;
+use crate::virtio::Queue;
+use crate::virtio::VirtioDevice;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum HybridVsockError {
+    #[error("failed to bind the vsock unix socket {0}: {1}")]
+    BindListener(PathBuf, io::Error),
+    #[error("failed to set the vsock unix socket as non-blocking: {0}")]
+    SetNonBlocking(io::Error),
+}
+pub type Result<T> = result::Result<T, HybridVsockError>;
+
+const QUEUE_SIZE: u16 = 256;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+const EVENT_QUEUE: usize = 2;
+
+/// CID of the host, as seen by the guest.
+const VMADDR_CID_HOST: u64 = 2;
+
+/// Size of the buffer holding the data sent by the guest to a connection until it is written to
+/// the host socket. This is the credit given to the guest for each connection.
+const CONN_TX_BUF_SIZE: u32 = 256 * 1024;
+
+/// Maximum size of the data of a packet sent to the guest.
+const MAX_PKT_BUF_SIZE: usize = 64 * 1024;
+
+/// Ports allocated to the host side of host-initiated connections start from here, to avoid
+/// conflicts with the well-known ports the guest may connect to.
+const FIRST_LOCAL_PORT: u32 = 1 << 30;
+
+/// Maximum length of the `CONNECT PORT\n` line sent by host processes.
+const MAX_CONNECT_LINE_LEN: usize = 32;
+
+/// Maximum number of control packets waiting for an rx descriptor. Once reached, packets from the
+/// guest are not processed anymore until the guest gives more rx descriptors, as processing them
+/// could queue more replies.
+const MAX_PENDING_RX: usize = 256;
+
+/// Port numbers of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct PortPair {
+    host: u32,
+    guest: u32,
+}
+
+impl PortPair {
+    fn from_tx_header(header: &virtio_vsock_hdr) -> PortPair {
+        PortPair {
+            host: header.dst_port.to_native(),
+            guest: header.src_port.to_native(),
+        }
+    }
+
+    // Returns the header of a packet sent to the guest for this connection, without data nor
+    // credit information.
+    fn rx_header(&self, guest_cid: u64, op: u16) -> virtio_vsock_hdr {
+        virtio_vsock_hdr {
+            src_cid: Le64::from(VMADDR_CID_HOST),
+            dst_cid: Le64::from(guest_cid),
+            src_port: Le32::from(self.host),
+            dst_port: Le32::from(self.guest),
+            r#type: TYPE_STREAM_SOCKET.into(),
+            op: op.into(),
+            ..Default::default()
+        }
+    }
+}
+
+enum ConnectionState {
+    /// Host-initiated connection, waiting for the `CONNECT PORT\n` line. Holds the bytes of the
+    /// line read so far.
+    ReadingConnectLine(Vec<u8>),
+    /// A connection request was sent to the guest, which hasn't answered yet.
+    WaitingResponse,
+    /// Data can be exchanged.
+    Connected,
+}
+
+struct Connection {
+    stream: UnixStream,
+    state: ConnectionState,
+    ports: PortPair,
+    // Data sent by the guest which hasn't been written to `stream` yet.
+    tx_buf: VecDeque<u8>,
+    // Number of bytes sent by the guest which were written to `stream`.
+    fwd_cnt: Wrapping<u32>,
+    // Value of `fwd_cnt` last sent to the guest.
+    last_fwd_cnt_sent: Wrapping<u32>,
+    // Number of bytes sent to the guest.
+    rx_cnt: Wrapping<u32>,
+    // Size of the receive buffer of the guest.
+    peer_buf_alloc: u32,
+    // Number of bytes sent to the guest which were consumed by the guest.
+    peer_fwd_cnt: Wrapping<u32>,
+    // The host closed its side of the connection, or the guest said it won't read anymore.
+    rx_shutdown: bool,
+    // The guest said it won't send anymore.
+    tx_shutdown: bool,
+    // Events the stream is registered for in the `WaitContext`.
+    events: EventType,
+}
+
+impl Connection {
+    fn new(stream: UnixStream, state: ConnectionState, ports: PortPair) -> Connection {
+        Connection {
+            stream,
+            state,
+            ports,
+            tx_buf: VecDeque::new(),
+            fwd_cnt: Wrapping(0),
+            last_fwd_cnt_sent: Wrapping(0),
+            rx_cnt: Wrapping(0),
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: Wrapping(0),
+            rx_shutdown: false,
+            tx_shutdown: false,
+            events: EventType::None,
+        }
+    }
+
+    // Returns the header of a packet sent to the guest for this connection, with our current
+    // credit.
+    fn rx_header(&mut self, guest_cid: u64, op: u16) -> virtio_vsock_hdr {
+        let mut header = self.ports.rx_header(guest_cid, op);
+        header.buf_alloc = Le32::from(CONN_TX_BUF_SIZE);
+        header.fwd_cnt = Le32::from(self.fwd_cnt.0);
+        self.last_fwd_cnt_sent = self.fwd_cnt;
+        header
+    }
+
+    fn update_peer_credit(&mut self, header: &virtio_vsock_hdr) {
+        self.peer_buf_alloc = header.buf_alloc.to_native();
+        self.peer_fwd_cnt = Wrapping(header.fwd_cnt.to_native());
+    }
+
+    // Number of bytes that can be sent to the guest without overflowing its receive buffer.
+    fn peer_credit(&self) -> usize {
+        let in_flight = (self.rx_cnt - self.peer_fwd_cnt).0;
+        self.peer_buf_alloc.saturating_sub(in_flight) as usize
+    }
+
+    fn wanted_events(&self, rx_available: bool) -> EventType {
+        let (read, write) = match self.state {
+            ConnectionState::ReadingConnectLine(_) => (true, false),
+            ConnectionState::WaitingResponse => (false, false),
+            ConnectionState::Connected => (
+                rx_available && !self.rx_shutdown && self.peer_credit() > 0,
+                !self.tx_buf.is_empty(),
+            ),
+        };
+        match (read, write) {
+            (false, false) => EventType::None,
+            (true, false) => EventType::Read,
+            (false, true) => EventType::Write,
+            (true, true) => EventType::ReadWrite,
+        }
+    }
+
+    // Writes as much of `tx_buf` as possible to the stream.
+    fn flush_tx_buf(&mut self) -> io::Result<()> {
+        while !self.tx_buf.is_empty() {
+            let (data, _) = self.tx_buf.as_slices();
+            match self.stream.write(data) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(len) => {
+                    self.tx_buf.drain(..len);
+                    self.fwd_cnt += Wrapping(len as u32);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if self.tx_shutdown {
+            self.stream.shutdown(Shutdown::Write)?;
+        }
+        Ok(())
+    }
+
+    // Whether enough space was freed in `tx_buf` since the guest was last told about it to be
+    // worth a credit update.
+    fn needs_credit_update(&self) -> bool {
+        (self.fwd_cnt - self.last_fwd_cnt_sent).0 >= CONN_TX_BUF_SIZE / 4
+    }
+}
+
+/// Parses the `CONNECT PORT\n` line sent by host processes. Returns the port, or `None` if the
+/// line is invalid.
+fn parse_connect_line(line: &[u8]) -> Option<u32> {
+    let line = str::from_utf8(line).ok()?;
+    let port = line.strip_prefix("CONNECT ")?.strip_suffix('\n')?;
+    port.trim_end_matches('\r').parse().ok()
+}
+
+/// Returns the path of the unix socket on which host processes listen for guest connections to
+/// `port`.
+fn host_listener_path(uds_path: &Path, port: u32) -> PathBuf {
+    let mut path = OsString::from(uds_path.as_os_str());
+    path.push(format!("_{}", port));
+    PathBuf::from(path)
+}
+
+/// State of the device kept between the runs of its worker.
+struct VsockState {
+    guest_cid: u64,
+    uds_path: PathBuf,
+    listener: UnixListener,
+    connections: BTreeMap<u32, Connection>,
+    // Connections known to the guest, by port numbers.
+    connection_ids: BTreeMap<PortPair, u32>,
+    next_connection_id: u32,
+    next_local_port: u32,
+    // Control packets waiting for an rx descriptor.
+    pending_rx: VecDeque<virtio_vsock_hdr>,
+    // If true, a TRANSPORT_RESET event is sent to the guest at the next opportunity. This tells
+    // the guest that all its connections are broken when a snapshot is restored.
+    needs_transport_reset: bool,
+}
+
+impl VsockState {
+    // Closes all the connections, without telling the guest.
+    fn clear_connections(&mut self) {
+        self.connections.clear();
+        self.connection_ids.clear();
+        self.pending_rx.clear();
+    }
+
+    fn add_connection(&mut self, connection: Connection) -> u32 {
+        let id = self.next_connection_id;
+        self.next_connection_id = self.next_connection_id.wrapping_add(1);
+        if matches!(connection.state, ConnectionState::Connected) {
+            self.connection_ids.insert(connection.ports, id);
+        }
+        self.connections.insert(id, connection);
+        id
+    }
+
+    // Returns a host port which isn't used by any connection.
+    fn allocate_local_port(&mut self) -> u32 {
+        loop {
+            let port = self.next_local_port;
+            self.next_local_port = match self.next_local_port.checked_add(1) {
+                Some(port) => port,
+                None => FIRST_LOCAL_PORT,
+            };
+            if !self.connections.values().any(|c| c.ports.host == port) {
+                return port;
+            }
+        }
+    }
+}
+
+/// A queue of the device and the interrupt used to signal its used descriptors.
+pub(crate) struct VsockQueue {
+    pub(crate) queue: Queue,
+    pub(crate) interrupt: Interrupt,
+}
+
+pub(crate) struct VsockQueues {
+    pub(crate) rx: VsockQueue,
+    pub(crate) tx: VsockQueue,
+    pub(crate) event: VsockQueue,
+}
+
+#[derive(EventToken)]
+enum Token {
+    RxQueue,
+    TxQueue,
+    EventQueue,
+    Listener,
+    Connection { id: u32 },
+    InterruptResample,
+    Kill,
+}
+
+struct Worker {
+    state: VsockState,
+    queues: VsockQueues,
+    // Whether the rx queue may have available descriptors.
+    rx_available: bool,
+    rx_needs_interrupt: bool,
+    tx_needs_interrupt: bool,
+    event_needs_interrupt: bool,
+}
+
+impl Worker {
+    fn run(mut self, kill_evt: Event) -> (VsockState, VsockQueues) {
+        if let Err(e) = self.run_loop(&kill_evt) {
+            error!("hybrid vsock worker failed: {:#}", e);
+        }
+        // Forget the registrations of the connections, which belong to the `WaitContext` of this
+        // run.
+        for connection in self.state.connections.values_mut() {
+            connection.events = EventType::None;
+        }
+        (self.state, self.queues)
+    }
+
+    fn run_loop(&mut self, kill_evt: &Event) -> anyhow::Result<()> {
+        let wait_ctx: WaitContext<Token> = WaitContext::build_with(&[
+            (self.queues.rx.queue.event(), Token::RxQueue),
+            (self.queues.tx.queue.event(), Token::TxQueue),
+            (self.queues.event.queue.event(), Token::EventQueue),
+            (&self.state.listener, Token::Listener),
+            (kill_evt, Token::Kill),
+        ])
+        .context("failed to create WaitContext")?;
+        if let Some(resample_evt) = self.queues.rx.interrupt.get_resample_evt() {
+            wait_ctx
+                .add(resample_evt, Token::InterruptResample)
+                .context("failed to add resample event to WaitContext")?;
+        }
+
+        // Packets may have been queued before the device went to sleep.
+        self.process_queues(&wait_ctx)?;
+
+        loop {
+            let events = wait_ctx.wait().context("failed to wait for events")?;
+            for event in events.iter() {
+                match event.token {
+                    Token::RxQueue => {
+                        self.queues
+                            .rx
+                            .queue
+                            .event()
+                            .wait()
+                            .context("failed to read rx queue event")?;
+                        self.rx_available = true;
+                    }
+                    Token::TxQueue => {
+                        self.queues
+                            .tx
+                            .queue
+                            .event()
+                            .wait()
+                            .context("failed to read tx queue event")?;
+                    }
+                    Token::EventQueue => {
+                        self.queues
+                            .event
+                            .queue
+                            .event()
+                            .wait()
+                            .context("failed to read event queue event")?;
+                    }
+                    Token::Listener => self.accept_connections(),
+                    Token::Connection { id } => {
+                        if event.is_writable || event.is_hungup {
+                            self.write_to_host(id);
+                        }
+                        if event.is_readable || event.is_hungup {
+                            self.read_from_host(id);
+                        }
+                    }
+                    Token::InterruptResample => {
+                        self.queues.rx.interrupt.interrupt_resample();
+                    }
+                    Token::Kill => return Ok(()),
+                }
+            }
+            self.process_queues(&wait_ctx)?;
+        }
+    }
+
+    fn process_queues(&mut self, wait_ctx: &WaitContext<Token>) -> anyhow::Result<()> {
+        self.send_transport_reset();
+        self.flush_pending_rx();
+        self.process_tx_queue();
+        self.flush_pending_rx();
+        self.update_events(wait_ctx)?;
+
+        if std::mem::take(&mut self.rx_needs_interrupt) {
+            self.queues
+                .rx
+                .queue
+                .trigger_interrupt(&self.queues.rx.interrupt);
+        }
+        if std::mem::take(&mut self.tx_needs_interrupt) {
+            self.queues
+                .tx
+                .queue
+                .trigger_interrupt(&self.queues.tx.interrupt);
+        }
+        if std::mem::take(&mut self.event_needs_interrupt) {
+            self.queues
+                .event
+                .queue
+                .trigger_interrupt(&self.queues.event.interrupt);
+        }
+        Ok(())
+    }
+
+    // Registers the connections for the events they can handle now.
+    fn update_events(&mut self, wait_ctx: &WaitContext<Token>) -> anyhow::Result<()> {
+        for (id, connection) in self.state.connections.iter_mut() {
+            let events = connection.wanted_events(self.rx_available);
+            if events == connection.events {
+                continue;
+            }
+            // Unregister the connections which can't do anything, so that a socket which was
+            // closed by the host doesn't wake us up continuously.
+            if events == EventType::None {
+                wait_ctx
+                    .delete(&connection.stream)
+                    .context("failed to remove vsock connection from WaitContext")?;
+            } else if connection.events == EventType::None {
+                wait_ctx
+                    .add_for_event(&connection.stream, events, Token::Connection { id: *id })
+                    .context("failed to add vsock connection to WaitContext")?;
+            } else {
+                wait_ctx
+                    .modify(&connection.stream, events, Token::Connection { id: *id })
+                    .context("failed to modify vsock connection in WaitContext")?;
+            }
+            connection.events = events;
+        }
+        Ok(())
+    }
+
+    fn send_transport_reset(&mut self) {
+        if !self.state.needs_transport_reset {
+            return;
+        }
+        let event_queue = &mut self.queues.event.queue;
+        if let Some(mut desc) = event_queue.pop() {
+            let event = virtio_vsock_event {
+                id: virtio_vsock_event_id_VIRTIO_VSOCK_EVENT_TRANSPORT_RESET.into(),
+            };
+            match desc.writer.write_obj(event) {
+                Ok(()) => self.state.needs_transport_reset = false,
+                Err(e) => error!("failed to write vsock transport reset event: {}", e),
+            }
+            let len = desc.writer.bytes_written() as u32;
+            event_queue.add_used(desc, len);
+            self.event_needs_interrupt = true;
+        }
+    }
+
+    // Writes the pending control packets to the rx queue.
+    fn flush_pending_rx(&mut self) {
+        while let Some(header) = self.state.pending_rx.front() {
+            let mut desc = match self.queues.rx.queue.pop() {
+                Some(desc) => desc,
+                None => {
+                    self.rx_available = false;
+                    return;
+                }
+            };
+            if let Err(e) = desc.writer.write_obj(*header) {
+                error!("failed to write vsock packet to rx queue: {}", e);
+            }
+            let len = desc.writer.bytes_written() as u32;
+            self.queues.rx.queue.add_used(desc, len);
+            self.rx_needs_interrupt = true;
+            self.state.pending_rx.pop_front();
+        }
+    }
+
+    fn queue_rx(&mut self, header: virtio_vsock_hdr) {
+        self.state.pending_rx.push_back(header);
+    }
+
+    // Tells the guest that a connection doesn't exist.
+    fn queue_reset(&mut self, ports: PortPair) {
+        let header = ports.rx_header(self.state.guest_cid, vsock_op::VIRTIO_VSOCK_OP_RST);
+        self.queue_rx(header);
+    }
+
+    // Closes a connection. The guest is told about it if it knows the connection.
+    fn close_connection(&mut self, id: u32) {
+        if let Some(connection) = self.state.connections.remove(&id) {
+            if self
+                .state
+                .connection_ids
+                .remove(&connection.ports)
+                .is_some()
+            {
+                self.queue_reset(connection.ports);
+            }
+        }
+    }
+
+    fn process_tx_queue(&mut self) {
+        while self.state.pending_rx.len() < MAX_PENDING_RX {
+            let mut desc = match self.queues.tx.queue.pop() {
+                Some(desc) => desc,
+                None => break,
+            };
+            match desc.reader.read_obj::<virtio_vsock_hdr>() {
+                Ok(header) => {
+                    let len = (header.len.to_native() as usize).min(desc.reader.available_bytes());
+                    let mut data = vec![0u8; len];
+                    match desc.reader.read_exact(&mut data) {
+                        Ok(()) => self.handle_tx_packet(header, &data),
+                        Err(e) => error!("failed to read vsock packet data: {}", e),
+                    }
+                }
+                Err(e) => error!("failed to read vsock packet header: {}", e),
+            }
+            self.queues.tx.queue.add_used(desc, 0);
+            self.tx_needs_interrupt = true;
+        }
+    }
+
+    fn handle_tx_packet(&mut self, header: virtio_vsock_hdr, data: &[u8]) {
+        if header.src_cid.to_native() != self.state.guest_cid
+            || header.dst_cid.to_native() != VMADDR_CID_HOST
+        {
+            warn!(
+                "vsock: dropping packet from CID {} to CID {}",
+                header.src_cid.to_native(),
+                header.dst_cid.to_native()
+            );
+            return;
+        }
+        let ports = PortPair::from_tx_header(&header);
+        let op = header.op.to_native();
+        if header.r#type.to_native() != TYPE_STREAM_SOCKET {
+            if op != vsock_op::VIRTIO_VSOCK_OP_RST {
+                self.queue_reset(ports);
+            }
+            return;
+        }
+
+        let id = match self.state.connection_ids.get(&ports) {
+            Some(id) => *id,
+            None => {
+                match op {
+                    vsock_op::VIRTIO_VSOCK_OP_REQUEST => self.connect_to_host(&header),
+                    vsock_op::VIRTIO_VSOCK_OP_RST => {}
+                    _ => self.queue_reset(ports),
+                }
+                return;
+            }
+        };
+        let guest_cid = self.state.guest_cid;
+        let connection = self
+            .state
+            .connections
+            .get_mut(&id)
+            .expect("vsock connection ids out of sync");
+        connection.update_peer_credit(&header);
+
+        match (op, &connection.state) {
+            (vsock_op::VIRTIO_VSOCK_OP_RESPONSE, ConnectionState::WaitingResponse) => {
+                let reply = format!("OK {}\n", ports.host);
+                // The reply is much smaller than the socket buffer, which is empty at this point.
+                match connection.stream.write_all(reply.as_bytes()) {
+                    Ok(()) => {
+                        info!("vsock: port {:?}: connected", ports);
+                        connection.state = ConnectionState::Connected;
+                    }
+                    Err(e) => {
+                        warn!("vsock: port {:?}: failed to reply to host: {}", ports, e);
+                        self.close_connection(id);
+                    }
+                }
+            }
+            (vsock_op::VIRTIO_VSOCK_OP_RST, _) => {
+                info!("vsock: port {:?}: reset by guest", ports);
+                self.state.connection_ids.remove(&ports);
+                self.state.connections.remove(&id);
+            }
+            (vsock_op::VIRTIO_VSOCK_OP_RW, ConnectionState::Connected) => {
+                if connection.tx_shutdown
+                    || connection.tx_buf.len() + data.len() > CONN_TX_BUF_SIZE as usize
+                {
+                    warn!("vsock: port {:?}: guest exceeded its credit", ports);
+                    self.close_connection(id);
+                    return;
+                }
+                connection.tx_buf.extend(data);
+                self.write_to_host(id);
+            }
+            (vsock_op::VIRTIO_VSOCK_OP_SHUTDOWN, ConnectionState::Connected) => {
+                let flags = header.flags.to_native();
+                let rcv = flags & virtio_vsock_shutdown_VIRTIO_VSOCK_SHUTDOWN_RCV != 0;
+                let send = flags & virtio_vsock_shutdown_VIRTIO_VSOCK_SHUTDOWN_SEND != 0;
+                connection.rx_shutdown |= rcv;
+                connection.tx_shutdown |= send;
+                if rcv && send {
+                    // The guest closed the connection, and expects a reset once we are done.
+                    if let Err(e) = connection.flush_tx_buf() {
+                        warn!("vsock: port {:?}: failed to write to host: {}", ports, e);
+                    }
+                    self.close_connection(id);
+                } else if send {
+                    self.write_to_host(id);
+                }
+            }
+            (vsock_op::VIRTIO_VSOCK_OP_CREDIT_UPDATE, ConnectionState::Connected) => {}
+            (vsock_op::VIRTIO_VSOCK_OP_CREDIT_REQUEST, ConnectionState::Connected) => {
+                let header =
+                    connection.rx_header(guest_cid, vsock_op::VIRTIO_VSOCK_OP_CREDIT_UPDATE);
+                self.queue_rx(header);
+            }
+            _ => {
+                warn!(
+                    "vsock: port {:?}: unexpected packet with op {} from guest",
+                    ports, op
+                );
+                self.close_connection(id);
+            }
+        }
+    }
+
+    // Handles a connection request from the guest.
+    fn connect_to_host(&mut self, header: &virtio_vsock_hdr) {
+        let ports = PortPair::from_tx_header(header);
+        let path = host_listener_path(&self.state.uds_path, ports.host);
+        let stream = match UnixStream::connect(&path).and_then(|stream| {
+            stream.set_nonblocking(true)?;
+            Ok(stream)
+        }) {
+            Ok(stream) => stream,
+            Err(e) => {
+                info!(
+                    "vsock: port {:?}: failed to connect to {}: {}",
+                    ports,
+                    path.display(),
+                    e
+                );
+                self.queue_reset(ports);
+                return;
+            }
+        };
+
+        info!("vsock: port {:?}: connected to {}", ports, path.display());
+        let mut connection = Connection::new(stream, ConnectionState::Connected, ports);
+        connection.update_peer_credit(header);
+        let response =
+            connection.rx_header(self.state.guest_cid, vsock_op::VIRTIO_VSOCK_OP_RESPONSE);
+        self.state.add_connection(connection);
+        self.queue_rx(response);
+    }
+
+    fn accept_connections(&mut self) {
+        loop {
+            let stream = match self.state.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("vsock: failed to accept connection: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = stream.set_nonblocking(true) {
+                error!("vsock: failed to set connection as non-blocking: {}", e);
+                continue;
+            }
+            let ports = PortPair {
+                host: self.state.allocate_local_port(),
+                guest: 0,
+            };
+            self.state.add_connection(Connection::new(
+                stream,
+                ConnectionState::ReadingConnectLine(Vec::new()),
+                ports,
+            ));
+        }
+    }
+
+    fn write_to_host(&mut self, id: u32) {
+        let guest_cid = self.state.guest_cid;
+        let connection = match self.state.connections.get_mut(&id) {
+            Some(connection) => connection,
+            None => return,
+        };
+        if let Err(e) = connection.flush_tx_buf() {
+            warn!(
+                "vsock: port {:?}: failed to write to host: {}",
+                connection.ports, e
+            );
+            self.close_connection(id);
+            return;
+        }
+        if connection.needs_credit_update() {
+            let header = connection.rx_header(guest_cid, vsock_op::VIRTIO_VSOCK_OP_CREDIT_UPDATE);
+            self.queue_rx(header);
+        }
+    }
+
+    fn read_from_host(&mut self, id: u32) {
+        let connection = match self.state.connections.get(&id) {
+            Some(connection) => connection,
+            None => return,
+        };
+        match connection.state {
+            ConnectionState::ReadingConnectLine(_) => self.read_connect_line(id),
+            ConnectionState::WaitingResponse => {}
+            ConnectionState::Connected => {
+                // Control packets go first, in particular the response to a connection request.
+                self.flush_pending_rx();
+                if self.state.pending_rx.is_empty() {
+                    self.forward_host_data(id);
+                }
+            }
+        }
+    }
+
+    fn read_connect_line(&mut self, id: u32) {
+        let guest_cid = self.state.guest_cid;
+        let connection = match self.state.connections.get_mut(&id) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let line = match &mut connection.state {
+            ConnectionState::ReadingConnectLine(line) => line,
+            _ => return,
+        };
+        // Read one byte at a time, so that no data following the line is consumed.
+        let mut byte = [0u8];
+        loop {
+            match connection.stream.read(&mut byte) {
+                Ok(0) => {
+                    self.state.connections.remove(&id);
+                    return;
+                }
+                Ok(_) => {
+                    line.push(byte[0]);
+                    if byte[0] == b'\n' {
+                        break;
+                    }
+                    if line.len() >= MAX_CONNECT_LINE_LEN {
+                        warn!("vsock: connection line from host is too long");
+                        self.state.connections.remove(&id);
+                        return;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    warn!("vsock: failed to read connection line from host: {}", e);
+                    self.state.connections.remove(&id);
+                    return;
+                }
+            }
+        }
+
+        let port = match parse_connect_line(line) {
+            Some(port) => port,
+            None => {
+                warn!(
+                    "vsock: invalid connection line from host: {:?}",
+                    String::from_utf8_lossy(line)
+                );
+                self.state.connections.remove(&id);
+                return;
+            }
+        };
+        connection.ports.guest = port;
+        connection.state = ConnectionState::WaitingResponse;
+        info!(
+            "vsock: port {:?}: requesting connection to guest",
+            connection.ports
+        );
+        let request = connection.rx_header(guest_cid, vsock_op::VIRTIO_VSOCK_OP_REQUEST);
+        self.state.connection_ids.insert(connection.ports, id);
+        self.queue_rx(request);
+    }
+
+    // Sends the data available on the host socket of a connection to the guest.
+    fn forward_host_data(&mut self, id: u32) {
+        let guest_cid = self.state.guest_cid;
+        let connection = match self.state.connections.get_mut(&id) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let credit = connection.peer_credit();
+        if connection.rx_shutdown || credit == 0 {
+            return;
+        }
+        let rx_queue = &mut self.queues.rx.queue;
+        let desc = match rx_queue.peek() {
+            Some(desc) => desc,
+            None => {
+                self.rx_available = false;
+                return;
+            }
+        };
+        let available = desc.writer.available_bytes();
+        if available < size_of::<virtio_vsock_hdr>() {
+            error!("vsock: rx descriptor is too small");
+            let desc = desc.pop();
+            rx_queue.add_used(desc, 0);
+            self.rx_needs_interrupt = true;
+            return;
+        }
+
+        let mut data = vec![
+            0u8;
+            (available - size_of::<virtio_vsock_hdr>())
+                .min(credit)
+                .min(MAX_PKT_BUF_SIZE)
+        ];
+        let len = match connection.stream.read(&mut data) {
+            Ok(0) => {
+                // The host won't send anything anymore.
+                connection.rx_shutdown = true;
+                let mut header =
+                    connection.rx_header(guest_cid, vsock_op::VIRTIO_VSOCK_OP_SHUTDOWN);
+                header.flags = Le32::from(virtio_vsock_shutdown_VIRTIO_VSOCK_SHUTDOWN_SEND);
+                drop(desc);
+                self.queue_rx(header);
+                self.flush_pending_rx();
+                return;
+            }
+            Ok(len) => len,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::Interrupted =>
+            {
+                return
+            }
+            Err(e) => {
+                warn!(
+                    "vsock: port {:?}: failed to read from host: {}",
+                    connection.ports, e
+                );
+                drop(desc);
+                self.close_connection(id);
+                return;
+            }
+        };
+
+        let mut header = connection.rx_header(guest_cid, vsock_op::VIRTIO_VSOCK_OP_RW);
+        header.len = Le32::from(len as u32);
+        connection.rx_cnt += Wrapping(len as u32);
+        let mut desc = desc.pop();
+        if let Err(e) = desc
+            .writer
+            .write_obj(header)
+            .and_then(|()| desc.writer.write_all(&data[..len]))
+        {
+            error!("failed to write vsock packet to rx queue: {}", e);
+        }
+        let written = desc.writer.bytes_written() as u32;
+        rx_queue.add_used(desc, written);
+        self.rx_needs_interrupt = true;
+    }
+}
+
+/// Userspace virtio-vsock device which maps the guest ports to host unix sockets.
+pub struct HybridVsock {
+    guest_cid: u64,
+    features: u64,
+    acked_features: u64,
+    // `None` while the worker is running.
+    state: Option<VsockState>,
+    worker_thread: Option<WorkerThread<(VsockState, VsockQueues)>>,
+}
+
+/// Snapshotted state of HybridVsock. These fields are serialized in order to validate they
+/// haven't changed when this device is restored.
+#[derive(Serialize, Deserialize)]
+struct HybridVsockSnapshot {
+    guest_cid: u64,
+    features: u64,
+    acked_features: u64,
+}
+
+impl HybridVsock {
+    /// Creates a new hybrid vsock device for a guest with CID `guest_cid`, listening for host
+    /// connections on the unix socket `uds_path`.
+    pub fn new(guest_cid: u64, uds_path: &Path, base_features: u64) -> Result<HybridVsock> {
+        let listener = UnixListener::bind(uds_path)
+            .map_err(|e| HybridVsockError::BindListener(uds_path.to_path_buf(), e))?;
+        listener
+            .set_nonblocking(true)
+            .map_err(HybridVsockError::SetNonBlocking)?;
+
+        Ok(HybridVsock {
+            guest_cid,
+            features: base_features,
+            acked_features: 0,
+            state: Some(VsockState {
+                guest_cid,
+                uds_path: uds_path.to_path_buf(),
+                listener,
+                connections: BTreeMap::new(),
+                connection_ids: BTreeMap::new(),
+                next_connection_id: 0,
+                next_local_port: FIRST_LOCAL_PORT,
+                pending_rx: VecDeque::new(),
+                needs_transport_reset: false,
+            }),
+            worker_thread: None,
+        })
+    }
+
+    fn get_config(&self) -> virtio_vsock_config {
+        virtio_vsock_config {
+            guest_cid: Le64::from(self.guest_cid),
+        }
+    }
+
+    pub(crate) fn start_worker(&mut self, queues: VsockQueues) -> anyhow::Result<()> {
+        let state = self
+            .state
+            .take()
+            .ok_or_else(|| anyhow!("hybrid vsock worker is already running"))?;
+        self.worker_thread = Some(WorkerThread::start("v_hybrid_vsock", move |kill_evt| {
+            let worker = Worker {
+                state,
+                queues,
+                rx_available: true,
+                rx_needs_interrupt: false,
+                tx_needs_interrupt: false,
+                event_needs_interrupt: false,
+            };
+            worker.run(kill_evt)
+        }));
+        Ok(())
+    }
+
+    /// Stops the worker, if it is running, and returns its queues.
+    pub(crate) fn stop_worker(&mut self) -> Option<VsockQueues> {
+        let worker_thread = self.worker_thread.take()?;
+        let (state, queues) = worker_thread.stop();
+        self.state = Some(state);
+        Some(queues)
+    }
+
+    pub(crate) fn snapshot(&self) -> anyhow::Result<serde_json::Value> {
+        serde_json::to_value(HybridVsockSnapshot {
+            guest_cid: self.guest_cid,
+            features: self.features,
+            acked_features: self.acked_features,
+        })
+        .context("failed to serialize vsock snapshot")
+    }
+}
+
+impl VirtioDevice for HybridVsock {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        self.state
+            .iter()
+            .map(|state| state.listener.as_raw_descriptor())
+            .collect()
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        copy_config(data, 0, self.get_config().as_bytes(), offset);
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Vsock
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.acked_features |= value & self.features;
+    }
+
+    fn activate(
+        &mut self,
+        _mem: GuestMemory,
+        interrupt: Interrupt,
+        mut queues: BTreeMap<usize, Queue>,
+    ) -> anyhow::Result<()> {
+        if queues.len() != NUM_QUEUES {
+            return Err(anyhow!(
+                "expected {} queues, got {}",
+                NUM_QUEUES,
+                queues.len()
+            ));
+        }
+        let mut take_queue = |idx| VsockQueue {
+            queue: queues.remove(&idx).unwrap(),
+            interrupt: interrupt.clone(),
+        };
+        let queues = VsockQueues {
+            rx: take_queue(RX_QUEUE),
+            tx: take_queue(TX_QUEUE),
+            event: take_queue(EVENT_QUEUE),
+        };
+        self.start_worker(queues)
+    }
+
+    fn reset(&mut self) -> bool {
+        if self.stop_worker().is_none() {
+            return false;
+        }
+        if let Some(state) = &mut self.state {
+            state.clear_connections();
+        }
+        true
+    }
+
+    fn virtio_sleep(&mut self) -> anyhow::Result<Option<BTreeMap<usize, Queue>>> {
+        Ok(self.stop_worker().map(|queues| {
+            BTreeMap::from([
+                (RX_QUEUE, queues.rx.queue),
+                (TX_QUEUE, queues.tx.queue),
+                (EVENT_QUEUE, queues.event.queue),
+            ])
+        }))
+    }
+
+    fn virtio_wake(
+        &mut self,
+        queues_state: Option<(GuestMemory, Interrupt, BTreeMap<usize, Queue>)>,
+    ) -> anyhow::Result<()> {
+        if let Some((mem, interrupt, queues)) = queues_state {
+            self.activate(mem, interrupt, queues)?;
+        }
+        Ok(())
+    }
+
+    fn virtio_snapshot(&mut self) -> anyhow::Result<serde_json::Value> {
+        self.snapshot()
+    }
+
+    fn virtio_restore(&mut self, data: serde_json::Value) -> anyhow::Result<()> {
+        let snapshot: HybridVsockSnapshot =
+            serde_json::from_value(data).context("error deserializing vsock snapshot")?;
+        anyhow::ensure!(
+            self.guest_cid == snapshot.guest_cid,
+            "expected guest_cid to match, but they did not. Live: {}, snapshot: {}",
+            self.guest_cid,
+            snapshot.guest_cid
+        );
+        anyhow::ensure!(
+            self.features == snapshot.features,
+            "vsock: expected features to match, but they did not. Live: {}, snapshot: {}",
+            self.features,
+            snapshot.features
+        );
+        let state = self
+            .state
+            .as_mut()
+            .ok_or_else(|| anyhow!("cannot restore vsock while it is running"))?;
+        // The host sockets of the connections can't be restored.
+        state.clear_connections();
+        state.needs_transport_reset = true;
+        self.acked_features = snapshot.acked_features;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connect_line() {
+        assert_eq!(parse_connect_line(b"CONNECT 1234\n"), Some(1234));
+        assert_eq!(parse_connect_line(b"CONNECT 52\r\n"), Some(52));
+        assert_eq!(parse_connect_line(b"CONNECT 1234"), None);
+        assert_eq!(parse_connect_line(b"CONNECT\n"), None);
+        assert_eq!(parse_connect_line(b"CONNECT -1\n"), None);
+        assert_eq!(parse_connect_line(b"CONNECT 4294967296\n"), None);
+        assert_eq!(parse_connect_line(b"connect 1234\n"), None);
+    }
+
+    #[test]
+    fn listener_path() {
+        assert_eq!(
+            host_listener_path(Path::new("/run/vm.vsock"), 52),
+            PathBuf::from("/run/vm.vsock_52")
+        );
+    }
+
+    #[test]
+    fn peer_credit() {
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let mut connection = Connection::new(
+            stream,
+            ConnectionState::Connected,
+            PortPair { host: 1, guest: 2 },
+        );
+        assert_eq!(connection.peer_credit(), 0);
+
+        let header = virtio_vsock_hdr {
+            buf_alloc: Le32::from(4096),
+            fwd_cnt: Le32::from(u32::MAX - 99),
+            ..Default::default()
+        };
+        connection.update_peer_credit(&header);
+        // The counters wrap around.
+        connection.rx_cnt = Wrapping(u32::MAX - 99) + Wrapping(1000);
+        assert_eq!(connection.peer_credit(), 3096);
+        assert_eq!(connection.wanted_events(true), EventType::Read);
+        assert_eq!(connection.wanted_events(false), EventType::None);
+
+        connection.tx_buf.extend([1, 2, 3]);
+        assert_eq!(connection.wanted_events(true), EventType::ReadWrite);
+        connection.flush_tx_buf().unwrap();
+        assert_eq!(connection.fwd_cnt, Wrapping(3));
+        assert!(!connection.needs_credit_update());
+    }
+}
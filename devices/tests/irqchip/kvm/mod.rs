@@ -4,6 +4,7 @@
 
 #![cfg(any(target_os = "android", target_os = "linux"))]
 
+mod riscv64;
 mod x86_64;
 
 use devices::irqchip::IrqChip;
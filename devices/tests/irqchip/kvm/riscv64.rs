@@ -0,0 +1,47 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+#![cfg(target_arch = "riscv64")]
+
+use devices::IrqChip;
+use devices::IrqChipRiscv64;
+use devices::KvmKernelIrqChip;
+use hypervisor::kvm::Kvm;
+use hypervisor::kvm::KvmVm;
+use hypervisor::Vm;
+use hypervisor::VmRiscv64;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+#[test]
+fn snapshot_restore() {
+    let kvm = Kvm::new().expect("failed to instantiate Kvm");
+    let mem = GuestMemory::new(&[(GuestAddress(0x8000_0000), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, mem, Default::default()).expect("failed to instantiate vm");
+
+    let mut chip = KvmKernelIrqChip::new(vm.try_clone().expect("failed to clone vm"), 2)
+        .expect("failed to instantiate KvmKernelIrqChip");
+    for i in 0..2 {
+        let vcpu = vm.create_vcpu(i).expect("failed to instantiate vcpu");
+        chip.add_vcpu(i, vcpu.as_vcpu())
+            .expect("failed to add vcpu");
+    }
+    chip.finalize()
+        .expect("failed to finalize KvmKernelIrqChip");
+
+    let snapshot = chip
+        .snapshot(2)
+        .expect("failed to snapshot KvmKernelIrqChip");
+    chip.restore(snapshot.clone(), 2)
+        .expect("failed to restore KvmKernelIrqChip");
+    assert_eq!(
+        chip.snapshot(2)
+            .expect("failed to snapshot KvmKernelIrqChip"),
+        snapshot
+    );
+
+    // Snapshots only restore to a chip with as many harts.
+    chip.restore(snapshot, 1)
+        .expect_err("restored the snapshot of 2 harts to 1");
+}
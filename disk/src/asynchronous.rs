@@ -26,6 +26,7 @@ use crate::DiskFile;
 use crate::DiskGetLen;
 use crate::Error;
 use crate::Result;
+use crate::SnapshotInfo;
 
 /// Async wrapper around a non-async `DiskFile` using a `BlockingPool`.
 ///
@@ -75,11 +76,27 @@ pub trait DiskFlush {
     fn flush(&mut self) -> io::Result<()>;
 }
 
+/// Internal snapshots of a disk image.
+pub trait DiskSnapshots {
+    /// Lists the internal snapshots stored in the disk image.
+    fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>>;
+
+    /// Creates an internal snapshot named `name` of the current contents of the disk.
+    fn create_snapshot(&mut self, name: &str) -> Result<()>;
+
+    /// Reverts the contents of the disk to the internal snapshot with the given name or ID.
+    fn apply_snapshot(&mut self, name: &str) -> Result<()>;
+
+    /// Deletes the internal snapshot with the given name or ID.
+    fn delete_snapshot(&mut self, name: &str) -> Result<()>;
+}
+
 #[async_trait(?Send)]
 impl<
         T: 'static
             + DiskFile
             + DiskFlush
+            + DiskSnapshots
             + Send
             + FileAllocate
             + FileSetLen
@@ -205,4 +222,41 @@ impl<
             })
             .await
     }
+
+    async fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>> {
+        self.inner.lock().list_snapshots()
+    }
+
+    async fn create_snapshot(&self, name: &str) -> Result<()> {
+        let inner_clone = self.inner.clone();
+        let name = name.to_owned();
+        self.blocking_pool
+            .spawn(move || {
+                let mut disk_file = inner_clone.lock();
+                disk_file.create_snapshot(&name)
+            })
+            .await
+    }
+
+    async fn apply_snapshot(&self, name: &str) -> Result<()> {
+        let inner_clone = self.inner.clone();
+        let name = name.to_owned();
+        self.blocking_pool
+            .spawn(move || {
+                let mut disk_file = inner_clone.lock();
+                disk_file.apply_snapshot(&name)
+            })
+            .await
+    }
+
+    async fn delete_snapshot(&self, name: &str) -> Result<()> {
+        let inner_clone = self.inner.clone();
+        let name = name.to_owned();
+        self.blocking_pool
+            .spawn(move || {
+                let mut disk_file = inner_clone.lock();
+                disk_file.delete_snapshot(&name)
+            })
+            .await
+    }
 }
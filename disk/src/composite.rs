@@ -143,7 +143,16 @@ pub struct CompositeDiskFile {
 }
 
 // TODO(b/271381851): implement `try_clone`. It allows virtio-blk to run multiple workers.
-impl DiskFile for CompositeDiskFile {}
+impl DiskFile for CompositeDiskFile {
+    fn discard_granularity(&self) -> u64 {
+        // A range aligned on the coarsest granularity is aligned for every component.
+        self.component_disks
+            .iter()
+            .map(|c| c.file.discard_granularity())
+            .max()
+            .unwrap_or(crate::DEFAULT_DISCARD_GRANULARITY)
+    }
+}
 
 fn ranges_overlap(a: &Range<u64>, b: &Range<u64>) -> bool {
     range_intersection(a, b).is_some()
@@ -155,6 +155,10 @@ impl DiskGetLen for File {
     }
 }
 
+// The discard granularity of disk images that don't allocate their storage in units of their own.
+// Most host filesystems allocate 4 KiB blocks.
+const DEFAULT_DISCARD_GRANULARITY: u64 = 4096;
+
 /// The prerequisites necessary to support a block device.
 pub trait DiskFile:
     FileSetLen + DiskGetLen + FileReadWriteAtVolatile + ToAsyncDisk + Send + AsRawDescriptors + Debug
@@ -171,6 +175,30 @@ pub trait DiskFile:
             "unsupported operation",
         ))
     }
+
+    /// Returns the size in bytes of the units in which the disk image allocates its storage, e.g.
+    /// the cluster size of a qcow2 image. Discarding ranges that aren't aligned on it may not free
+    /// any storage.
+    fn discard_granularity(&self) -> u64 {
+        DEFAULT_DISCARD_GRANULARITY
+    }
+}
+
+/// Describes an internal snapshot stored in a disk image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    /// Unique ID of the snapshot within the image.
+    pub id: String,
+    /// Name of the snapshot.
+    pub name: String,
+    /// Seconds since the Unix epoch when the snapshot was taken.
+    pub date_sec: u32,
+    /// Nanoseconds part of the time the snapshot was taken.
+    pub date_nsec: u32,
+    /// Guest time in nanoseconds when the snapshot was taken.
+    pub vm_clock_nsec: u64,
+    /// Size of the VM state saved with the snapshot, 0 if there is none.
+    pub vm_state_size: u64,
 }
 
 /// A `DiskFile` that can be converted for asychronous access.
@@ -368,6 +396,28 @@ pub trait AsyncDisk: DiskGetLen + FileSetLen + FileAllocate {
     /// Writes up to `length` bytes of zeroes to the stream, returning how many bytes were written.
     async fn write_zeroes_at(&self, file_offset: u64, length: u64) -> Result<()>;
 
+    /// Lists the internal snapshots stored in the disk image.
+    ///
+    /// Returns [`Error::UnsupportedOperation`] if the image format has no internal snapshots.
+    async fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>> {
+        Err(Error::UnsupportedOperation)
+    }
+
+    /// Creates an internal snapshot named `name` of the current contents of the disk.
+    async fn create_snapshot(&self, _name: &str) -> Result<()> {
+        Err(Error::UnsupportedOperation)
+    }
+
+    /// Reverts the contents of the disk to the internal snapshot with the given name or ID.
+    async fn apply_snapshot(&self, _name: &str) -> Result<()> {
+        Err(Error::UnsupportedOperation)
+    }
+
+    /// Deletes the internal snapshot with the given name or ID.
+    async fn delete_snapshot(&self, _name: &str) -> Result<()> {
+        Err(Error::UnsupportedOperation)
+    }
+
     /// Reads from the file at 'file_offset' into `buf`.
     ///
     /// Less efficient than `read_to_mem` because of extra copies and allocations.
@@ -0,0 +1,164 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Compression and decompression of qcow2 compressed clusters.
+
+use std::io;
+#[cfg(feature = "zstd")]
+use std::io::Read;
+
+use flate2::Compress;
+use flate2::Compression;
+use flate2::Decompress;
+use flate2::FlushCompress;
+use flate2::FlushDecompress;
+use flate2::Status;
+
+/// The algorithm used for the compressed clusters of an image, as given by the `compression_type`
+/// header field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompressionType {
+    /// Raw deflate streams without a zlib header. This is the default when the header doesn't
+    /// specify a compression type.
+    Deflate,
+    /// zstd frames.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl CompressionType {
+    /// Returns the compression type for the `compression_type` header field value, or `None` if it
+    /// is unknown or not supported by this build.
+    pub fn from_header_value(value: u8) -> Option<CompressionType> {
+        match value {
+            0 => Some(CompressionType::Deflate),
+            #[cfg(feature = "zstd")]
+            1 => Some(CompressionType::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Decompresses the data of a compressed cluster. `input` may extend past the end of the
+/// compressed data, since the image only records the number of sectors the data touches.
+pub fn decompress_cluster(
+    compression_type: CompressionType,
+    input: &[u8],
+    cluster_size: usize,
+) -> io::Result<Vec<u8>> {
+    let mut output = vec![0u8; cluster_size];
+    match compression_type {
+        CompressionType::Deflate => {
+            let mut decompress = Decompress::new(false);
+            decompress
+                .decompress(input, &mut output, FlushDecompress::Finish)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if decompress.total_out() != cluster_size as u64 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "compressed cluster is too short",
+                ));
+            }
+        }
+        #[cfg(feature = "zstd")]
+        CompressionType::Zstd => {
+            zstd::stream::read::Decoder::with_buffer(input)?
+                .single_frame()
+                .read_exact(&mut output)?;
+        }
+    }
+    Ok(output)
+}
+
+/// Compresses the data of a cluster. Returns `None` if the data doesn't compress to less than
+/// `input.len()` bytes, in which case it should be stored as a normal cluster.
+pub fn compress_cluster(
+    compression_type: CompressionType,
+    input: &[u8],
+) -> io::Result<Option<Vec<u8>>> {
+    match compression_type {
+        CompressionType::Deflate => {
+            let mut compress = Compress::new(Compression::default(), false);
+            let mut output = vec![0u8; input.len() - 1];
+            let status = compress
+                .compress(input, &mut output, FlushCompress::Finish)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            if status != Status::StreamEnd {
+                // The output buffer filled up before the whole cluster was compressed.
+                return Ok(None);
+            }
+            output.truncate(compress.total_out() as usize);
+            Ok(Some(output))
+        }
+        #[cfg(feature = "zstd")]
+        CompressionType::Zstd => {
+            let output = zstd::bulk::compress(input, 0)?;
+            if output.len() >= input.len() {
+                return Ok(None);
+            }
+            Ok(Some(output))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(compression_type: CompressionType) {
+        let mut data = vec![0u8; 0x1_0000];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = (i % 251) as u8;
+        }
+        let compressed = compress_cluster(compression_type, &data)
+            .expect("failed to compress")
+            .expect("data didn't compress");
+        assert!(compressed.len() < data.len());
+
+        // Trailing bytes after the compressed stream must be ignored.
+        let mut input = compressed.clone();
+        input.extend_from_slice(&[0xa5; 511]);
+        let decompressed =
+            decompress_cluster(compression_type, &input, data.len()).expect("failed to decompress");
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        round_trip(CompressionType::Deflate);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trip() {
+        round_trip(CompressionType::Zstd);
+    }
+
+    #[test]
+    fn incompressible_data() {
+        // A xorshift sequence doesn't compress.
+        let mut state = 0x1234_5678u32;
+        let data: Vec<u8> = (0..0x1000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        assert!(compress_cluster(CompressionType::Deflate, &data)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn truncated_deflate_stream() {
+        let data = vec![0x55u8; 0x1000];
+        let compressed = compress_cluster(CompressionType::Deflate, &data)
+            .unwrap()
+            .unwrap();
+        decompress_cluster(CompressionType::Deflate, &compressed, data.len() * 2)
+            .expect_err("decompressed a short cluster");
+    }
+}
@@ -2,8 +2,10 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+mod compressed;
 mod qcow_raw_file;
 mod refcount;
+mod snapshot;
 mod vec_cache;
 
 use std::cmp::max;
@@ -18,6 +20,8 @@ use std::io::Write;
 use std::mem::size_of;
 use std::path::Path;
 use std::str;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use base::error;
 use base::open_file_or_duplicate;
@@ -35,14 +39,16 @@ use base::WriteZeroesAt;
 use cros_async::Executor;
 use libc::EINVAL;
 use libc::ENOSPC;
-use libc::ENOTSUP;
 use remain::sorted;
 use thiserror::Error;
 
 use crate::asynchronous::DiskFlush;
+use crate::asynchronous::DiskSnapshots;
 use crate::create_disk_file;
+use crate::qcow::compressed::CompressionType;
 use crate::qcow::qcow_raw_file::QcowRawFile;
 use crate::qcow::refcount::RefCount;
+use crate::qcow::snapshot::SnapshotEntry;
 use crate::qcow::vec_cache::CacheMap;
 use crate::qcow::vec_cache::Cacheable;
 use crate::qcow::vec_cache::VecCache;
@@ -50,6 +56,7 @@ use crate::AsyncDisk;
 use crate::AsyncDiskFileWrapper;
 use crate::DiskFile;
 use crate::DiskGetLen;
+use crate::SnapshotInfo;
 use crate::ToAsyncDisk;
 
 #[sorted]
@@ -61,8 +68,6 @@ pub enum Error {
     BackingFileOpen(Box<crate::Error>),
     #[error("backing file name is too long: {0} bytes over")]
     BackingFileTooLong(usize),
-    #[error("compressed blocks not supported")]
-    CompressedBlocksNotSupported,
     #[error("failed to evict cache: {0}")]
     EvictingCache(io::Error),
     #[error("file larger than max of {}: {0}", MAX_QCOW_FILE_SIZE)]
@@ -91,6 +96,8 @@ pub enum Error {
     InvalidRefcountTableOffset,
     #[error("invalid refcount table size: {0}")]
     InvalidRefcountTableSize(u64),
+    #[error("invalid snapshot name")]
+    InvalidSnapshotName,
     #[error("no free clusters")]
     NoFreeClusters,
     #[error("no refcount clusters")]
@@ -107,6 +114,10 @@ pub enum Error {
     ReadingRefCountBlock(refcount::Error),
     #[error("failed to read ref counts: {0}")]
     ReadingRefCounts(io::Error),
+    #[error("failed to read snapshot table: {0}")]
+    ReadingSnapshots(io::Error),
+    #[error("failed to read source image: {0}")]
+    ReadingSourceImage(io::Error),
     #[error("failed to rebuild ref counts: {0}")]
     RebuildingRefCounts(io::Error),
     #[error("refcount table offset past file end")]
@@ -119,14 +130,28 @@ pub enum Error {
     SettingRefcountRefcount(io::Error),
     #[error("size too small for number of clusters")]
     SizeTooSmallForNumberOfClusters,
+    #[error("a snapshot named {0} already exists")]
+    SnapshotExists(String),
+    #[error("no snapshot named {0}")]
+    SnapshotNotFound(String),
+    #[error("snapshot was taken of a disk of size {0}")]
+    SnapshotSizeMismatch(u64),
     #[error("l1 entry table too large: {0}")]
     TooManyL1Entries(u64),
     #[error("ref count table too large: {0}")]
     TooManyRefcounts(u64),
+    #[error("too many snapshots")]
+    TooManySnapshots,
+    #[error("unsupported compression type: {0}")]
+    UnsupportedCompressionType(u8),
     #[error("unsupported refcount order")]
     UnsupportedRefcountOrder,
     #[error("unsupported version: {0}")]
     UnsupportedVersion(u32),
+    #[error("failed to update snapshots: {0}")]
+    UpdatingSnapshots(io::Error),
+    #[error("failed to write compressed cluster: {0}")]
+    WritingCompressedCluster(io::Error),
     #[error("failed to write header: {0}")]
     WritingHeader(io::Error),
 }
@@ -157,9 +182,13 @@ const V3_BARE_HEADER_SIZE: u32 = 104;
 const L1_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
 const L2_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
 // Flags
-const COMPRESSED_FLAG: u64 = 1 << 62;
+pub(super) const COMPRESSED_FLAG: u64 = 1 << 62;
 const CLUSTER_USED_FLAG: u64 = 1 << 63;
 const COMPATIBLE_FEATURES_LAZY_REFCOUNTS: u64 = 1 << 0;
+const INCOMPATIBLE_FEATURES_COMPRESSION_TYPE: u64 = 1 << 3;
+
+// Compressed cluster data is located in units of 512 byte sectors.
+const COMPRESSED_SECTOR_SIZE: u64 = 512;
 
 // The format supports a "header extension area", that crosvm does not use.
 const QCOW_EMPTY_HEADER_EXTENSION_SIZE: u32 = 8;
@@ -167,6 +196,9 @@ const QCOW_EMPTY_HEADER_EXTENSION_SIZE: u32 = 8;
 // Defined by the specification
 const MAX_BACKING_FILE_SIZE: u32 = 1023;
 
+// Offset of the `nb_snapshots` header field, which is directly followed by `snapshots_offset`.
+const NB_SNAPSHOTS_HEADER_OFFSET: u64 = 60;
+
 /// Contains the information from the header of a qcow file.
 #[derive(Clone, Debug)]
 pub struct QcowHeader {
@@ -195,11 +227,22 @@ pub struct QcowHeader {
     pub autoclear_features: u64,
     pub refcount_order: u32,
     pub header_size: u32,
+    // Only present if header_size is larger than V3_BARE_HEADER_SIZE.
+    pub compression_type: u8,
 
     // Post-header entries
     pub backing_file_path: Option<String>,
 }
 
+// Reads the next u8 from the file.
+fn read_u8_from_file(mut f: &File) -> Result<u8> {
+    let mut value = [0u8; 1];
+    (&mut f)
+        .read_exact(&mut value)
+        .map_err(Error::ReadingHeader)?;
+    Ok(value[0])
+}
+
 // Reads the next u16 from the file.
 fn read_u16_from_file(mut f: &File) -> Result<u16> {
     let mut value = [0u8; 2];
@@ -256,8 +299,12 @@ impl QcowHeader {
             autoclear_features: read_u64_from_file(f)?,
             refcount_order: read_u32_from_file(f)?,
             header_size: read_u32_from_file(f)?,
+            compression_type: 0,
             backing_file_path: None,
         };
+        if header.header_size > V3_BARE_HEADER_SIZE {
+            header.compression_type = read_u8_from_file(f)?;
+        }
         if header.backing_file_size > MAX_BACKING_FILE_SIZE {
             return Err(Error::BackingFileTooLong(header.backing_file_size as usize));
         }
@@ -329,6 +376,7 @@ impl QcowHeader {
             autoclear_features: 0,
             refcount_order: DEFAULT_REFCOUNT_ORDER,
             header_size: V3_BARE_HEADER_SIZE,
+            compression_type: 0,
             backing_file_path: backing_file.map(String::from),
         })
     }
@@ -365,9 +413,19 @@ impl QcowHeader {
         write_u64_to_file(file, self.autoclear_features)?;
         write_u32_to_file(file, self.refcount_order)?;
         write_u32_to_file(file, self.header_size)?;
+        if self.header_size > V3_BARE_HEADER_SIZE {
+            // The compression type is followed by padding up to the header size.
+            let mut additional_fields =
+                vec![0u8; (self.header_size - V3_BARE_HEADER_SIZE) as usize];
+            additional_fields[0] = self.compression_type;
+            file.write_all(&additional_fields)
+                .map_err(Error::WritingHeader)?;
+        }
         write_u32_to_file(file, 0)?; // header extension type: end of header extension area
         write_u32_to_file(file, 0)?; // length of header extension data: 0
         if let Some(backing_file_path) = self.backing_file_path.as_ref() {
+            file.seek(SeekFrom::Start(self.backing_file_offset))
+                .map_err(Error::WritingHeader)?;
             write!(file, "{}", backing_file_path).map_err(Error::WritingHeader)?;
         }
 
@@ -425,9 +483,49 @@ pub struct QcowFile {
     // removal of references to them have been synced to disk.
     avail_clusters: Vec<u64>,
     backing_file: Option<Box<dyn DiskFile>>,
+    compression_type: CompressionType,
+    // The most recently decompressed cluster along with the L2 entry that describes it.
+    decompressed_cluster: Option<(u64, Vec<u8>)>,
+    // Offset of the first free byte after the last compressed cluster written, if the host cluster
+    // it is in still has room.
+    compressed_tail: Option<u64>,
+    // Internal snapshots of the image and the size of the table that stores them.
+    snapshots: Vec<SnapshotEntry>,
+    snapshot_table_size: u64,
 }
 
-impl DiskFile for QcowFile {}
+// Where the data for part of a read from a `QcowFile` comes from.
+enum ReadSource<'a> {
+    // The raw qcow file or the backing file, at the given offset.
+    File(&'a mut dyn DiskFile, u64),
+    // The data of a decompressed cluster.
+    Memory(&'a [u8]),
+    // Unallocated clusters without a backing file read as zeroes.
+    Zeroes,
+}
+
+impl ReadSource<'_> {
+    // Fills `slice` with data from this source.
+    fn read_into(self, slice: VolatileSlice) -> io::Result<()> {
+        match self {
+            ReadSource::File(file, offset) => file.read_exact_at_volatile(slice, offset),
+            ReadSource::Memory(data) => {
+                slice.copy_from(data);
+                Ok(())
+            }
+            ReadSource::Zeroes => {
+                slice.write_bytes(0);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl DiskFile for QcowFile {
+    fn discard_granularity(&self) -> u64 {
+        self.raw_file.cluster_size()
+    }
+}
 
 impl DiskFlush for QcowFile {
     fn flush(&mut self) -> io::Result<()> {
@@ -437,6 +535,24 @@ impl DiskFlush for QcowFile {
     }
 }
 
+impl DiskSnapshots for QcowFile {
+    fn list_snapshots(&self) -> crate::Result<Vec<SnapshotInfo>> {
+        Ok(self.snapshots())
+    }
+
+    fn create_snapshot(&mut self, name: &str) -> crate::Result<()> {
+        QcowFile::create_snapshot(self, name).map_err(crate::Error::QcowError)
+    }
+
+    fn apply_snapshot(&mut self, name: &str) -> crate::Result<()> {
+        QcowFile::apply_snapshot(self, name).map_err(crate::Error::QcowError)
+    }
+
+    fn delete_snapshot(&mut self, name: &str) -> crate::Result<()> {
+        QcowFile::delete_snapshot(self, name).map_err(crate::Error::QcowError)
+    }
+}
+
 impl QcowFile {
     /// Creates a QcowFile from `file`. File must be a valid qcow2 image.
     pub fn from(mut file: File, max_nesting_depth: u32) -> Result<QcowFile> {
@@ -463,6 +579,15 @@ impl QcowFile {
             return Err(Error::FileTooBig(header.size));
         }
 
+        // Compressed clusters use deflate unless the header says otherwise.
+        let compression_type =
+            if header.incompatible_features & INCOMPATIBLE_FEATURES_COMPRESSION_TYPE != 0 {
+                CompressionType::from_header_value(header.compression_type)
+                    .ok_or(Error::UnsupportedCompressionType(header.compression_type))?
+            } else {
+                CompressionType::Deflate
+            };
+
         let backing_file = if let Some(backing_file_path) = header.backing_file_path.as_ref() {
             let path = backing_file_path.clone();
             let backing_raw_file = open_file_or_duplicate(
@@ -531,6 +656,18 @@ impl QcowFile {
             QcowFile::rebuild_refcounts(&mut raw_file, header.clone())?;
         }
 
+        let (snapshots, snapshot_table_size) = if header.nb_snapshots > 0 {
+            snapshot::read_snapshot_table(
+                raw_file.file_mut(),
+                header.snapshots_offset,
+                header.nb_snapshots,
+                header.size,
+            )
+            .map_err(Error::ReadingSnapshots)?
+        } else {
+            (Vec::new(), 0)
+        };
+
         let l2_size = cluster_size / size_of::<u64>() as u64;
         let num_clusters = div_round_up_u64(header.size, cluster_size);
         let num_l2_clusters = div_round_up_u64(num_clusters, l2_size);
@@ -585,6 +722,11 @@ impl QcowFile {
             unref_clusters: Vec::new(),
             avail_clusters: Vec::new(),
             backing_file,
+            compression_type,
+            decompressed_cluster: None,
+            compressed_tail: None,
+            snapshots,
+            snapshot_table_size,
         };
 
         // Check that the L1 and refcount tables fit in a 64bit address space.
@@ -664,10 +806,230 @@ impl QcowFile {
         Ok(qcow)
     }
 
+    /// Creates a new QcowFile with the contents of the raw image `source`, storing each cluster
+    /// that isn't all zeroes as a compressed cluster.
+    pub fn new_compressed_from(file: File, source: &mut File) -> Result<QcowFile> {
+        let size = source.metadata().map_err(Error::ReadingSourceImage)?.len();
+        let mut qcow = QcowFile::new(file, size)?;
+        let cluster_size = qcow.raw_file.cluster_size();
+        let mut cluster_data = vec![0u8; cluster_size as usize];
+        let mut address = 0;
+        while address < size {
+            let count = min(cluster_size, size - address) as usize;
+            // The last cluster is padded with zeroes if the image size isn't cluster aligned.
+            cluster_data[count..].fill(0);
+            source
+                .read_exact(&mut cluster_data[..count])
+                .map_err(Error::ReadingSourceImage)?;
+            if cluster_data.iter().any(|b| *b != 0) {
+                qcow.write_compressed_cluster(address, &cluster_data)
+                    .map_err(Error::WritingCompressedCluster)?;
+            }
+            address += cluster_size;
+        }
+        qcow.fsync().map_err(Error::WritingCompressedCluster)?;
+        Ok(qcow)
+    }
+
     pub fn set_backing_file(&mut self, backing: Option<Box<dyn DiskFile>>) {
         self.backing_file = backing;
     }
 
+    /// Stores `data`, the full contents of the cluster starting at the cluster aligned guest
+    /// `address`, as a compressed cluster. If `data` doesn't compress it is written as a normal
+    /// cluster.
+    pub fn write_compressed_cluster(&mut self, address: u64, data: &[u8]) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        if address >= self.virtual_size()
+            || self.raw_file.cluster_offset(address) != 0
+            || data.len() as u64 != cluster_size
+        {
+            return Err(std::io::Error::from_raw_os_error(EINVAL));
+        }
+
+        let mut compressed_data = match compressed::compress_cluster(self.compression_type, data)? {
+            Some(compressed_data) => compressed_data,
+            None => {
+                self.write_cb(address, data.len(), |file, offset, raw_offset, count| {
+                    file.seek(SeekFrom::Start(raw_offset))?;
+                    file.write_all(&data[offset..(offset + count)])
+                })?;
+                return Ok(());
+            }
+        };
+        let len = compressed_data.len() as u64;
+
+        // Drop whatever the cluster pointed to before.
+        self.deallocate_cluster(address)?;
+
+        // Append the data to the host cluster holding the previous compressed cluster if it fits,
+        // otherwise start a new host cluster. The data never crosses host cluster boundaries.
+        let mut tail_cluster = None;
+        if let Some(tail) = self.compressed_tail {
+            let tail_cluster_addr = tail - self.raw_file.cluster_offset(tail);
+            let refcount = self
+                .refcounts
+                .get_cluster_refcount(&mut self.raw_file, tail_cluster_addr)
+                .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+            if self.raw_file.cluster_offset(tail) + len <= cluster_size && refcount < u16::MAX {
+                tail_cluster = Some((tail, tail_cluster_addr, refcount));
+            }
+        }
+        let offset = match tail_cluster {
+            Some((tail, tail_cluster_addr, refcount)) => {
+                let mut newly_unref = self.set_cluster_refcount(tail_cluster_addr, refcount + 1)?;
+                self.unref_clusters.append(&mut newly_unref);
+                tail
+            }
+            None => self.append_data_cluster(None)?,
+        };
+        self.raw_file
+            .file_mut()
+            .write_all_at_volatile(VolatileSlice::new(&mut compressed_data), offset)?;
+        self.compressed_tail = Some(offset + len).filter(|tail| tail % cluster_size != 0);
+
+        let l1_index = self.l1_table_index(address) as usize;
+        let l2_index = self.l2_table_index(address) as usize;
+        let mut set_refcounts = Vec::new();
+        self.cache_l2_table_for_write(l1_index, &mut set_refcounts)?;
+        let entry = compressed_cluster_entry(offset, len, self.header.cluster_bits);
+        self.update_cluster_addr(l1_index, l2_index, entry, &mut set_refcounts)?;
+        for (addr, count) in set_refcounts {
+            let mut newly_unref = self.set_cluster_refcount(addr, count)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+        Ok(())
+    }
+
+    /// Returns the internal snapshots stored in the image.
+    pub fn snapshots(&self) -> Vec<SnapshotInfo> {
+        self.snapshots
+            .iter()
+            .map(|snapshot| SnapshotInfo {
+                id: snapshot.id.clone(),
+                name: snapshot.name.clone(),
+                date_sec: snapshot.date_sec,
+                date_nsec: snapshot.date_nsec,
+                vm_clock_nsec: snapshot.vm_clock_nsec,
+                vm_state_size: snapshot.vm_state_size,
+            })
+            .collect()
+    }
+
+    /// Creates an internal snapshot named `name` of the current contents of the image. The
+    /// snapshot shares all clusters with the image until they are modified.
+    pub fn create_snapshot(&mut self, name: &str) -> Result<()> {
+        if name.is_empty() || name.len() > u16::MAX as usize {
+            return Err(Error::InvalidSnapshotName);
+        }
+        if self.snapshots.iter().any(|snapshot| snapshot.name == name) {
+            return Err(Error::SnapshotExists(name.to_string()));
+        }
+        if self.snapshots.len() >= snapshot::MAX_SNAPSHOTS {
+            return Err(Error::TooManySnapshots);
+        }
+        self.prepare_snapshot_update()
+            .map_err(Error::UpdatingSnapshots)?;
+
+        // The snapshot gets its own copy of the L1 table. Everything it points to is now
+        // referenced once more.
+        let l1_table = self.l1_table.get_values().to_vec();
+        let l1_table_offset = self
+            .append_table_clusters(l1_table.len() * size_of::<u64>())
+            .map_err(Error::UpdatingSnapshots)?;
+        self.raw_file
+            .write_pointer_table(l1_table_offset, &l1_table, 0)
+            .map_err(Error::UpdatingSnapshots)?;
+        self.update_l1_refcounts(&l1_table, true)
+            .map_err(Error::UpdatingSnapshots)?;
+
+        let date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let id = self
+            .snapshots
+            .iter()
+            .filter_map(|snapshot| snapshot.id.parse::<u64>().ok())
+            .max()
+            .map_or(1, |id| id + 1)
+            .to_string();
+        let mut snapshots = self.snapshots.clone();
+        snapshots.push(SnapshotEntry {
+            l1_table_offset,
+            l1_size: l1_table.len() as u32,
+            id,
+            name: name.to_string(),
+            date_sec: date.as_secs() as u32,
+            date_nsec: date.subsec_nanos(),
+            vm_clock_nsec: 0,
+            vm_state_size: 0,
+            disk_size: self.virtual_size(),
+            unknown_extra_data: Vec::new(),
+        });
+        self.write_snapshot_table(snapshots)
+            .map_err(Error::UpdatingSnapshots)?;
+        self.fsync().map_err(Error::UpdatingSnapshots)
+    }
+
+    /// Reverts the contents of the image to the internal snapshot with the given name or ID. The
+    /// snapshot itself is kept.
+    pub fn apply_snapshot(&mut self, name: &str) -> Result<()> {
+        let snapshot = self.snapshots[self.find_snapshot(name)?].clone();
+        if snapshot.disk_size != self.virtual_size() {
+            return Err(Error::SnapshotSizeMismatch(snapshot.disk_size));
+        }
+        self.prepare_snapshot_update()
+            .map_err(Error::UpdatingSnapshots)?;
+
+        let mut l1_table = self.read_snapshot_l1_table(&snapshot)?;
+        l1_table.resize(self.l1_table.len(), 0);
+        // Reference the clusters of the snapshot before dropping the current ones, so clusters
+        // shared by both never reach a refcount of zero.
+        self.update_l1_refcounts(&l1_table, true)
+            .map_err(Error::UpdatingSnapshots)?;
+        self.sync_caches().map_err(Error::UpdatingSnapshots)?;
+        self.raw_file
+            .write_pointer_table(self.header.l1_table_offset, &l1_table, 0)
+            .map_err(Error::UpdatingSnapshots)?;
+        self.raw_file
+            .file_mut()
+            .sync_data()
+            .map_err(Error::UpdatingSnapshots)?;
+
+        let old_l1_table = self.l1_table.get_values().to_vec();
+        self.l1_table = VecCache::from_vec(l1_table);
+        // The cached L2 tables belong to the old L1 table and were written by
+        // `prepare_snapshot_update()`.
+        self.l2_cache.clear();
+        self.update_l1_refcounts(&old_l1_table, false)
+            .map_err(Error::UpdatingSnapshots)?;
+        self.fsync().map_err(Error::UpdatingSnapshots)
+    }
+
+    /// Deletes the internal snapshot with the given name or ID, freeing the clusters that only it
+    /// used.
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<()> {
+        let index = self.find_snapshot(name)?;
+        self.prepare_snapshot_update()
+            .map_err(Error::UpdatingSnapshots)?;
+
+        // Remove the snapshot from the table first so an interrupted deletion only leaks clusters.
+        let mut snapshots = self.snapshots.clone();
+        let snapshot = snapshots.remove(index);
+        let l1_table = self.read_snapshot_l1_table(&snapshot)?;
+        self.write_snapshot_table(snapshots)
+            .map_err(Error::UpdatingSnapshots)?;
+
+        self.update_l1_refcounts(&l1_table, false)
+            .map_err(Error::UpdatingSnapshots)?;
+        self.unref_table_clusters(
+            snapshot.l1_table_offset,
+            u64::from(snapshot.l1_size) * size_of::<u64>() as u64,
+        )
+        .map_err(Error::UpdatingSnapshots)?;
+        self.fsync().map_err(Error::UpdatingSnapshots)
+    }
+
     /// Returns the first cluster in the file with a 0 refcount. Used for testing.
     pub fn first_zero_refcount(&mut self) -> Result<Option<u64>> {
         let file_size = self
@@ -770,12 +1132,23 @@ impl QcowFile {
                         .read_pointer_table(
                             l2_addr_disk,
                             cluster_size / size_of::<u64>() as u64,
-                            Some(L2_TABLE_OFFSET_MASK),
+                            None,
                         )
                         .map_err(Error::ReadingPointers)?;
-                    for data_cluster_addr in l2_table {
-                        if data_cluster_addr != 0 {
-                            add_ref(refcounts, cluster_size, data_cluster_addr)?;
+                    for l2_entry in l2_table {
+                        if l2_entry & COMPRESSED_FLAG != 0 {
+                            // Each host cluster holding part of the compressed data is referenced
+                            // once by every compressed cluster stored in it.
+                            for host_cluster_addr in
+                                compressed_host_clusters(l2_entry, header.cluster_bits)
+                            {
+                                add_ref(refcounts, cluster_size, host_cluster_addr)?;
+                            }
+                        } else {
+                            let data_cluster_addr = l2_entry & L2_TABLE_OFFSET_MASK;
+                            if data_cluster_addr != 0 {
+                                add_ref(refcounts, cluster_size, data_cluster_addr)?;
+                            }
                         }
                     }
                 }
@@ -784,6 +1157,57 @@ impl QcowFile {
             Ok(())
         }
 
+        // Add references to the snapshot table, the L1 tables of the snapshots, and the clusters
+        // reachable from them.
+        fn set_snapshot_refcounts(
+            refcounts: &mut [u16],
+            header: QcowHeader,
+            cluster_size: u64,
+            raw_file: &mut QcowRawFile,
+        ) -> Result<()> {
+            if header.nb_snapshots == 0 {
+                return Ok(());
+            }
+            let (snapshots, table_size) = snapshot::read_snapshot_table(
+                raw_file.file_mut(),
+                header.snapshots_offset,
+                header.nb_snapshots,
+                header.size,
+            )
+            .map_err(Error::ReadingSnapshots)?;
+            for i in 0..div_round_up_u64(table_size, cluster_size) {
+                add_ref(
+                    refcounts,
+                    cluster_size,
+                    header.snapshots_offset + i * cluster_size,
+                )?;
+            }
+            for snapshot in snapshots {
+                if u64::from(snapshot.l1_size) > MAX_RAM_POINTER_TABLE_SIZE {
+                    return Err(Error::InvalidL1TableSize(snapshot.l1_size));
+                }
+                let l1_clusters = div_round_up_u64(
+                    u64::from(snapshot.l1_size) * size_of::<u64>() as u64,
+                    cluster_size,
+                );
+                for i in 0..l1_clusters {
+                    add_ref(
+                        refcounts,
+                        cluster_size,
+                        snapshot.l1_table_offset + i * cluster_size,
+                    )?;
+                }
+                // Snapshot L1 tables reference clusters the same way as the active one.
+                let snapshot_header = QcowHeader {
+                    l1_table_offset: snapshot.l1_table_offset,
+                    l1_size: snapshot.l1_size,
+                    ..header.clone()
+                };
+                set_data_refcounts(refcounts, snapshot_header, cluster_size, raw_file)?;
+            }
+            Ok(())
+        }
+
         // Add references to the top-level refcount table clusters.
         fn set_refcount_table_refcounts(
             refcounts: &mut [u16],
@@ -935,6 +1359,7 @@ impl QcowFile {
         set_header_refcount(&mut refcounts, cluster_size)?;
         set_l1_refcounts(&mut refcounts, header.clone(), cluster_size)?;
         set_data_refcounts(&mut refcounts, header.clone(), cluster_size, raw_file)?;
+        set_snapshot_refcounts(&mut refcounts, header.clone(), cluster_size, raw_file)?;
         set_refcount_table_refcounts(&mut refcounts, header.clone(), cluster_size)?;
 
         // Allocate clusters to store the new reference count blocks.
@@ -991,9 +1416,9 @@ impl QcowFile {
         (address / self.raw_file.cluster_size()) % self.l2_entries
     }
 
-    // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters have
-    // yet to be allocated, return None.
-    fn file_offset_read(&mut self, address: u64) -> std::io::Result<Option<u64>> {
+    // Gets the L2 entry for the given guest address. If the L2 table or data cluster have yet to
+    // be allocated, returns 0.
+    fn l2_entry(&mut self, address: u64) -> std::io::Result<u64> {
         if address >= self.virtual_size() {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -1006,7 +1431,7 @@ impl QcowFile {
 
         if l2_addr_disk == 0 {
             // Reading from an unallocated cluster will return zeros.
-            return Ok(None);
+            return Ok(0);
         }
 
         let l2_index = self.l2_table_index(address) as usize;
@@ -1016,22 +1441,238 @@ impl QcowFile {
             let table =
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
 
+            let has_snapshots = !self.snapshots.is_empty();
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
+            let refcounts = &mut self.refcounts;
             self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
+                write_l2_table(
+                    raw_file,
+                    refcounts,
+                    has_snapshots,
                     l1_table[index],
                     evicted.get_values(),
-                    CLUSTER_USED_FLAG,
                 )
             })?;
         };
 
-        let cluster_addr = self.l2_cache.get(&l1_index).unwrap()[l2_index];
-        if cluster_addr == 0 {
-            return Ok(None);
+        Ok(self.l2_cache.get(&l1_index).unwrap()[l2_index])
+    }
+
+    // Reads and decompresses the compressed cluster described by the L2 entry `entry`. The most
+    // recently decompressed cluster is kept so that a series of small reads from the same cluster
+    // only decompresses it once.
+    fn decompress_cluster(&mut self, entry: u64) -> std::io::Result<&[u8]> {
+        if !matches!(&self.decompressed_cluster, Some((cached, _)) if *cached == entry) {
+            let (offset, len) = compressed_cluster_extent(entry, self.header.cluster_bits);
+            // The sectors of the last compressed cluster in the file can extend past its end.
+            let file_size = self.raw_file.file().metadata()?.len();
+            let len = min(len, file_size.saturating_sub(offset));
+            let mut compressed_data = vec![0u8; len as usize];
+            self.raw_file
+                .file_mut()
+                .read_exact_at_volatile(VolatileSlice::new(&mut compressed_data), offset)?;
+            let data = compressed::decompress_cluster(
+                self.compression_type,
+                &compressed_data,
+                self.raw_file.cluster_size() as usize,
+            )?;
+            self.decompressed_cluster = Some((entry, data));
+        }
+        // unwrap is safe because the cluster was just decompressed if it wasn't cached.
+        Ok(&self.decompressed_cluster.as_ref().unwrap().1)
+    }
+
+    // Drops the references that the compressed cluster described by the L2 entry `entry` holds on
+    // the host clusters storing its data.
+    fn unref_compressed_cluster(&mut self, entry: u64) -> std::io::Result<()> {
+        self.decompressed_cluster = None;
+        for host_cluster_addr in compressed_host_clusters(entry, self.header.cluster_bits) {
+            let refcount = self
+                .refcounts
+                .get_cluster_refcount(&mut self.raw_file, host_cluster_addr)
+                .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+            if refcount == 0 {
+                return Err(std::io::Error::from_raw_os_error(EINVAL));
+            }
+            let mut newly_unref = self.set_cluster_refcount(host_cluster_addr, refcount - 1)?;
+            self.unref_clusters.append(&mut newly_unref);
+            if refcount == 1 {
+                self.unref_clusters.push(host_cluster_addr);
+                // Don't append more compressed data to a cluster that is about to be reused.
+                if let Some(tail) = self.compressed_tail {
+                    if tail - self.raw_file.cluster_offset(tail) == host_cluster_addr {
+                        self.compressed_tail = None;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the index of the snapshot with the given name, or with the given ID if no snapshot
+    // has that name.
+    fn find_snapshot(&self, name: &str) -> Result<usize> {
+        self.snapshots
+            .iter()
+            .position(|snapshot| snapshot.name == name)
+            .or_else(|| {
+                self.snapshots
+                    .iter()
+                    .position(|snapshot| snapshot.id == name)
+            })
+            .ok_or_else(|| Error::SnapshotNotFound(name.to_string()))
+    }
+
+    // Reads the L1 table of `snapshot`.
+    fn read_snapshot_l1_table(&mut self, snapshot: &SnapshotEntry) -> Result<Vec<u64>> {
+        if u64::from(snapshot.l1_size) > MAX_RAM_POINTER_TABLE_SIZE {
+            return Err(Error::InvalidL1TableSize(snapshot.l1_size));
+        }
+        offset_is_cluster_boundary(snapshot.l1_table_offset, self.header.cluster_bits)?;
+        self.raw_file
+            .read_pointer_table(
+                snapshot.l1_table_offset,
+                u64::from(snapshot.l1_size),
+                Some(L1_TABLE_OFFSET_MASK),
+            )
+            .map_err(Error::ReadingSnapshots)
+    }
+
+    // Writes all cached metadata to the file, so that the tables can be walked on disk, and stops
+    // reusing cached compressed data whose refcounts are about to change.
+    fn prepare_snapshot_update(&mut self) -> std::io::Result<()> {
+        self.fsync()?;
+        self.decompressed_cluster = None;
+        self.compressed_tail = None;
+        Ok(())
+    }
+
+    // Adds a reference to, or drops a reference from, every L2 table and cluster reachable from
+    // `l1_table`, as happens when a snapshot of it is created or deleted. The L2 tables that are
+    // still in use are rewritten so their flags match the new refcounts.
+    fn update_l1_refcounts(&mut self, l1_table: &[u64], increment: bool) -> std::io::Result<()> {
+        for &l2_addr in l1_table.iter().filter(|addr| **addr != 0) {
+            let l2_table = Self::read_l2_cluster(&mut self.raw_file, l2_addr)?;
+            for &entry in l2_table.iter().filter(|entry| **entry != 0) {
+                if entry & COMPRESSED_FLAG != 0 {
+                    for host_cluster_addr in
+                        compressed_host_clusters(entry, self.header.cluster_bits)
+                    {
+                        self.update_cluster_refcount(host_cluster_addr, increment)?;
+                    }
+                } else {
+                    self.update_cluster_refcount(entry, increment)?;
+                }
+            }
+            if self.update_cluster_refcount(l2_addr, increment)? > 0 {
+                write_l2_table(
+                    &mut self.raw_file,
+                    &mut self.refcounts,
+                    true,
+                    l2_addr,
+                    &l2_table,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Adds or drops one reference to the cluster at `address` and returns the new refcount.
+    // Clusters that are no longer referenced are freed.
+    fn update_cluster_refcount(&mut self, address: u64, increment: bool) -> std::io::Result<u16> {
+        let refcount = self
+            .refcounts
+            .get_cluster_refcount(&mut self.raw_file, address)
+            .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+        let new_refcount = if increment {
+            refcount.checked_add(1)
+        } else {
+            refcount.checked_sub(1)
+        }
+        .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
+        let mut newly_unref = self.set_cluster_refcount(address, new_refcount)?;
+        self.unref_clusters.append(&mut newly_unref);
+        if new_refcount == 0 {
+            // The underlying FS may not support FALLOC_FL_PUNCH_HOLE, so don't treat an error as
+            // fatal.
+            let _ = self
+                .raw_file
+                .file_mut()
+                .punch_hole_mut(address, self.raw_file.cluster_size());
+            self.unref_clusters.push(address);
+        }
+        Ok(new_refcount)
+    }
+
+    // Allocates enough contiguous clusters at the end of the file to hold a table of `size` bytes
+    // and returns the offset of the first one.
+    fn append_table_clusters(&mut self, size: usize) -> std::io::Result<u64> {
+        let cluster_size = self.raw_file.cluster_size();
+        let count = div_round_up_u64(size as u64, cluster_size);
+        if count <= 1 {
+            return self.append_data_cluster(None);
         }
-        Ok(Some(cluster_addr + self.raw_file.cluster_offset(address)))
+        // Allocate all the clusters before setting refcounts, which can allocate refcount blocks.
+        let max_valid_cluster_offset = self.refcounts.max_valid_cluster_offset();
+        let mut clusters = Vec::new();
+        for _ in 0..count {
+            match self.raw_file.add_cluster_end(max_valid_cluster_offset)? {
+                Some(cluster) => clusters.push(cluster),
+                None => {
+                    error!("No free clusters in append_table_clusters()");
+                    return Err(std::io::Error::from_raw_os_error(ENOSPC));
+                }
+            }
+        }
+        for &cluster in &clusters {
+            let mut newly_unref = self.set_cluster_refcount(cluster, 1)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+        Ok(clusters[0])
+    }
+
+    // Drops the reference to each cluster of the table of `size` bytes at `offset`.
+    fn unref_table_clusters(&mut self, offset: u64, size: u64) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        for i in 0..div_round_up_u64(size, cluster_size) {
+            self.update_cluster_refcount(offset + i * cluster_size, false)?;
+        }
+        Ok(())
+    }
+
+    // Writes a snapshot table holding `snapshots` to new clusters, points the header at it, and
+    // frees the previous table.
+    fn write_snapshot_table(&mut self, snapshots: Vec<SnapshotEntry>) -> std::io::Result<()> {
+        let mut table = snapshot::snapshot_table_bytes(&snapshots);
+        let table_offset = if table.is_empty() {
+            0
+        } else {
+            let offset = self.append_table_clusters(table.len())?;
+            self.raw_file
+                .file_mut()
+                .write_all_at_volatile(VolatileSlice::new(&mut table), offset)?;
+            offset
+        };
+        // The table and the refcounts of its clusters have to be on disk before the header
+        // references it.
+        self.sync_caches()?;
+        let mut header_fields = Vec::new();
+        header_fields.extend_from_slice(&(snapshots.len() as u32).to_be_bytes());
+        header_fields.extend_from_slice(&table_offset.to_be_bytes());
+        self.raw_file.file_mut().write_all_at_volatile(
+            VolatileSlice::new(&mut header_fields),
+            NB_SNAPSHOTS_HEADER_OFFSET,
+        )?;
+        self.raw_file.file_mut().sync_data()?;
+
+        let old_table_offset = self.header.snapshots_offset;
+        let old_table_size = self.snapshot_table_size;
+        self.header.nb_snapshots = snapshots.len() as u32;
+        self.header.snapshots_offset = table_offset;
+        self.snapshots = snapshots;
+        self.snapshot_table_size = table.len() as u64;
+        self.unref_table_clusters(old_table_offset, old_table_size)
     }
 
     // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters need
@@ -1042,37 +1683,11 @@ impl QcowFile {
         }
 
         let l1_index = self.l1_table_index(address) as usize;
-        let l2_addr_disk = *self
-            .l1_table
-            .get(l1_index)
-            .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
         let l2_index = self.l2_table_index(address) as usize;
 
         let mut set_refcounts = Vec::new();
 
-        if !self.l2_cache.contains_key(&l1_index) {
-            // Not in the cache.
-            let l2_table = if l2_addr_disk == 0 {
-                // Allocate a new cluster to store the L2 table and update the L1 table to point
-                // to the new table.
-                let new_addr: u64 = self.get_new_cluster(None)?;
-                // The cluster refcount starts at one meaning it is used but doesn't need COW.
-                set_refcounts.push((new_addr, 1));
-                self.l1_table[l1_index] = new_addr;
-                VecCache::new(self.l2_entries as usize)
-            } else {
-                VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?)
-            };
-            let l1_table = &self.l1_table;
-            let raw_file = &mut self.raw_file;
-            self.l2_cache.insert(l1_index, l2_table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
-            })?;
-        }
+        self.cache_l2_table_for_write(l1_index, &mut set_refcounts)?;
 
         let cluster_addr = match self.l2_cache.get(&l1_index).unwrap()[l2_index] {
             0 => {
@@ -1091,6 +1706,34 @@ impl QcowFile {
                 self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
                 cluster_addr
             }
+            entry if entry & COMPRESSED_FLAG != 0 => {
+                // Compressed clusters can't be modified in place. Move the decompressed data to a
+                // newly allocated data cluster.
+                let initial_data = self.decompress_cluster(entry)?.to_vec();
+                let cluster_addr = self.append_data_cluster(Some(initial_data))?;
+                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                self.unref_compressed_cluster(entry)?;
+                cluster_addr
+            }
+            a if !self.snapshots.is_empty() => {
+                let refcount = self
+                    .refcounts
+                    .get_cluster_refcount(&mut self.raw_file, a)
+                    .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+                if refcount > 1 {
+                    // The cluster is shared with a snapshot. Copy it before it is modified.
+                    let mut cluster_data = vec![0u8; self.raw_file.cluster_size() as usize];
+                    self.raw_file
+                        .file_mut()
+                        .read_exact_at_volatile(VolatileSlice::new(&mut cluster_data), a)?;
+                    let cluster_addr = self.append_data_cluster(Some(cluster_data))?;
+                    self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                    set_refcounts.push((a, refcount - 1));
+                    cluster_addr
+                } else {
+                    a
+                }
+            }
             a => a,
         };
 
@@ -1102,6 +1745,49 @@ impl QcowFile {
         Ok(cluster_addr + self.raw_file.cluster_offset(address))
     }
 
+    // Reads the L2 table for `l1_index` into the cache if it isn't already there, allocating a new
+    // table if none exists yet. Refcounts for newly allocated clusters are added to
+    // `set_refcounts`.
+    fn cache_l2_table_for_write(
+        &mut self,
+        l1_index: usize,
+        set_refcounts: &mut Vec<(u64, u16)>,
+    ) -> std::io::Result<()> {
+        let l2_addr_disk = *self
+            .l1_table
+            .get(l1_index)
+            .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
+
+        if !self.l2_cache.contains_key(&l1_index) {
+            // Not in the cache.
+            let l2_table = if l2_addr_disk == 0 {
+                // Allocate a new cluster to store the L2 table and update the L1 table to point
+                // to the new table.
+                let new_addr: u64 = self.get_new_cluster(None)?;
+                // The cluster refcount starts at one meaning it is used but doesn't need COW.
+                set_refcounts.push((new_addr, 1));
+                self.l1_table[l1_index] = new_addr;
+                VecCache::new(self.l2_entries as usize)
+            } else {
+                VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?)
+            };
+            let has_snapshots = !self.snapshots.is_empty();
+            let l1_table = &self.l1_table;
+            let raw_file = &mut self.raw_file;
+            let refcounts = &mut self.refcounts;
+            self.l2_cache.insert(l1_index, l2_table, |index, evicted| {
+                write_l2_table(
+                    raw_file,
+                    refcounts,
+                    has_snapshots,
+                    l1_table[index],
+                    evicted.get_values(),
+                )
+            })?;
+        }
+        Ok(())
+    }
+
     // Updates the l1 and l2 tables to point to the new `cluster_addr`.
     fn update_cluster_addr(
         &mut self,
@@ -1117,8 +1803,17 @@ impl QcowFile {
             // The index must be valid from when it was insterted.
             let addr = self.l1_table[l1_index];
             if addr != 0 {
-                self.unref_clusters.push(addr);
-                set_refcounts.push((addr, 0));
+                let refcount = self
+                    .refcounts
+                    .get_cluster_refcount(&mut self.raw_file, addr)
+                    .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+                if refcount > 1 {
+                    // The table is shared with a snapshot, which keeps using it.
+                    set_refcounts.push((addr, refcount - 1));
+                } else {
+                    self.unref_clusters.push(addr);
+                    set_refcounts.push((addr, 0));
+                }
             }
 
             // Allocate a new cluster to store the L2 table and update the L1 table to point
@@ -1193,13 +1888,17 @@ impl QcowFile {
             // Not in the cache.
             let table =
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
+            let has_snapshots = !self.snapshots.is_empty();
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
+            let refcounts = &mut self.refcounts;
             self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
+                write_l2_table(
+                    raw_file,
+                    refcounts,
+                    has_snapshots,
                     l1_table[index],
                     evicted.get_values(),
-                    CLUSTER_USED_FLAG,
                 )
             })?;
         }
@@ -1210,6 +1909,18 @@ impl QcowFile {
             return Ok(());
         }
 
+        // Rewrite the L2 entry to remove the cluster mapping.
+        let mut set_refcounts = Vec::new();
+        self.update_cluster_addr(l1_index, l2_index, 0, &mut set_refcounts)?;
+        for (addr, count) in set_refcounts {
+            let mut newly_unref = self.set_cluster_refcount(addr, count)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+
+        if cluster_addr & COMPRESSED_FLAG != 0 {
+            return self.unref_compressed_cluster(cluster_addr);
+        }
+
         // Decrement the refcount.
         let refcount = self
             .refcounts
@@ -1223,10 +1934,6 @@ impl QcowFile {
         let mut newly_unref = self.set_cluster_refcount(cluster_addr, new_refcount)?;
         self.unref_clusters.append(&mut newly_unref);
 
-        // Rewrite the L2 entry to remove the cluster mapping.
-        // unwrap is safe as we just checked/inserted this entry.
-        self.l2_cache.get_mut(&l1_index).unwrap()[l2_index] = 0;
-
         if new_refcount == 0 {
             let cluster_size = self.raw_file.cluster_size();
             // This cluster is no longer in use; deallocate the storage.
@@ -1263,9 +1970,14 @@ impl QcowFile {
                     // show through.
                     Some(self.file_offset_write(curr_addr)?)
                 } else {
-                    // Any space in unallocated clusters can be left alone, since
-                    // unallocated clusters already read back as zeroes.
-                    self.file_offset_read(curr_addr)?
+                    match self.l2_entry(curr_addr)? {
+                        // Any space in unallocated clusters can be left alone, since
+                        // unallocated clusters already read back as zeroes.
+                        0 => None,
+                        // Compressed clusters have to be decompressed and clusters shared with
+                        // a snapshot copied before they can be partially zeroed.
+                        _ => Some(self.file_offset_write(curr_addr)?),
+                    }
                 };
                 if let Some(offset) = offset {
                     // Partial cluster - zero it out.
@@ -1280,16 +1992,19 @@ impl QcowFile {
         Ok(())
     }
 
-    // Reads an L2 cluster from the disk, returning an error if the file can't be read or if any
-    // cluster is compressed.
+    // Reads an L2 cluster from the disk. Entries of normal clusters are reduced to the cluster
+    // address, while compressed cluster descriptors are kept along with their `COMPRESSED_FLAG`.
     fn read_l2_cluster(raw_file: &mut QcowRawFile, cluster_addr: u64) -> std::io::Result<Vec<u64>> {
         let file_values = raw_file.read_pointer_cluster(cluster_addr, None)?;
-        if file_values.iter().any(|entry| entry & COMPRESSED_FLAG != 0) {
-            return Err(std::io::Error::from_raw_os_error(ENOTSUP));
-        }
         Ok(file_values
             .iter()
-            .map(|entry| *entry & L2_TABLE_OFFSET_MASK)
+            .map(|entry| {
+                if entry & COMPRESSED_FLAG != 0 {
+                    *entry & !CLUSTER_USED_FLAG
+                } else {
+                    *entry & L2_TABLE_OFFSET_MASK
+                }
+            })
             .collect())
     }
 
@@ -1352,14 +2067,17 @@ impl QcowFile {
 
     fn sync_caches(&mut self) -> std::io::Result<()> {
         // Write out all dirty L2 tables.
+        let has_snapshots = !self.snapshots.is_empty();
         for (l1_index, l2_table) in self.l2_cache.iter_mut().filter(|(_k, v)| v.dirty()) {
             // The index must be valid from when we insterted it.
             let addr = self.l1_table[*l1_index];
             if addr != 0 {
-                self.raw_file.write_pointer_table(
+                write_l2_table(
+                    &mut self.raw_file,
+                    &mut self.refcounts,
+                    has_snapshots,
                     addr,
                     l2_table.get_values(),
-                    CLUSTER_USED_FLAG,
                 )?;
             } else {
                 return Err(std::io::Error::from_raw_os_error(EINVAL));
@@ -1391,27 +2109,39 @@ impl QcowFile {
     }
 
     // Reads `count` bytes starting at `address`, calling `cb` repeatedly with the data source,
-    // number of bytes read so far, offset to read from, and number of bytes to read from the file
-    // in that invocation. If None is given to `cb` in place of the backing file, the `cb` should
-    // infer zeros would have been read.
+    // number of bytes read so far, and number of bytes to read from the source in that invocation.
     fn read_cb<F>(&mut self, address: u64, count: usize, mut cb: F) -> std::io::Result<usize>
     where
-        F: FnMut(Option<&mut dyn DiskFile>, usize, u64, usize) -> std::io::Result<()>,
+        F: FnMut(ReadSource, usize, usize) -> std::io::Result<()>,
     {
         let read_count: usize = self.limit_range_file(address, count);
 
         let mut nread: usize = 0;
         while nread < read_count {
             let curr_addr = address + nread as u64;
-            let file_offset = self.file_offset_read(curr_addr)?;
+            let entry = self.l2_entry(curr_addr)?;
             let count = self.limit_range_cluster(curr_addr, read_count - nread);
-
-            if let Some(offset) = file_offset {
-                cb(Some(self.raw_file.file_mut()), nread, offset, count)?;
+            let cluster_offset = self.raw_file.cluster_offset(curr_addr);
+
+            if entry & COMPRESSED_FLAG != 0 {
+                let data = self.decompress_cluster(entry)?;
+                let start = cluster_offset as usize;
+                cb(
+                    ReadSource::Memory(&data[start..start + count]),
+                    nread,
+                    count,
+                )?;
+            } else if entry != 0 {
+                let offset = entry + cluster_offset;
+                cb(
+                    ReadSource::File(self.raw_file.file_mut(), offset),
+                    nread,
+                    count,
+                )?;
             } else if let Some(backing) = self.backing_file.as_mut() {
-                cb(Some(backing.as_mut()), nread, curr_addr, count)?;
+                cb(ReadSource::File(backing.as_mut(), curr_addr), nread, count)?;
             } else {
-                cb(None, nread, 0, count)?;
+                cb(ReadSource::Zeroes, nread, count)?;
             }
 
             nread += count;
@@ -1462,20 +2192,11 @@ impl Read for QcowFile {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let len = buf.len();
         let slice = VolatileSlice::new(buf);
-        let read_count = self.read_cb(
-            self.current_offset,
-            len,
-            |file, already_read, offset, count| {
+        let read_count =
+            self.read_cb(self.current_offset, len, |source, already_read, count| {
                 let sub_slice = slice.get_slice(already_read, count).unwrap();
-                match file {
-                    Some(f) => f.read_exact_at_volatile(sub_slice, offset),
-                    None => {
-                        sub_slice.write_bytes(0);
-                        Ok(())
-                    }
-                }
-            },
-        )?;
+                source.read_into(sub_slice)
+            })?;
         self.current_offset += read_count as u64;
         Ok(read_count)
     }
@@ -1534,15 +2255,9 @@ impl Write for QcowFile {
 
 impl FileReadWriteAtVolatile for QcowFile {
     fn read_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
-        self.read_cb(offset, slice.size(), |file, read, offset, count| {
+        self.read_cb(offset, slice.size(), |source, read, count| {
             let sub_slice = slice.get_slice(read, count).unwrap();
-            match file {
-                Some(f) => f.read_exact_at_volatile(sub_slice, offset),
-                None => {
-                    sub_slice.write_bytes(0);
-                    Ok(())
-                }
-            }
+            source.read_into(sub_slice)
         })
     }
 
@@ -1622,6 +2337,65 @@ impl ToAsyncDisk for QcowFile {
     }
 }
 
+// Writes the L2 table `table` to the cluster at `addr`. When the image has snapshots, entries of
+// clusters that are shared with a snapshot are written without `CLUSTER_USED_FLAG`, which tells
+// other implementations that the cluster has to be copied before it is modified.
+fn write_l2_table(
+    raw_file: &mut QcowRawFile,
+    refcounts: &mut RefCount,
+    has_snapshots: bool,
+    addr: u64,
+    table: &[u64],
+) -> std::io::Result<()> {
+    if !has_snapshots {
+        return raw_file.write_pointer_table(addr, table, CLUSTER_USED_FLAG);
+    }
+    let mut entries = Vec::with_capacity(table.len());
+    for &entry in table {
+        if entry == 0 || entry & COMPRESSED_FLAG != 0 {
+            entries.push(entry);
+            continue;
+        }
+        let refcount = refcounts
+            .get_cluster_refcount(raw_file, entry)
+            .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+        entries.push(if refcount == 1 {
+            entry | CLUSTER_USED_FLAG
+        } else {
+            entry
+        });
+    }
+    raw_file.write_pointer_table(addr, &entries, 0)
+}
+
+// Returns the host file offset and the maximum length of the data of the compressed cluster
+// described by the L2 entry `entry`.
+fn compressed_cluster_extent(entry: u64, cluster_bits: u32) -> (u64, u64) {
+    // The host offset takes up the low `offset_bits` bits, followed by the number of sectors the
+    // data extends past the sector containing the offset.
+    let offset_bits = 62 - (cluster_bits - 8);
+    let offset = entry & ((1 << offset_bits) - 1);
+    let additional_sectors = (entry >> offset_bits) & ((1 << (cluster_bits - 8)) - 1);
+    let len = (additional_sectors + 1) * COMPRESSED_SECTOR_SIZE - offset % COMPRESSED_SECTOR_SIZE;
+    (offset, len)
+}
+
+// Returns the L2 entry for a compressed cluster whose data is `len` bytes at host file `offset`.
+fn compressed_cluster_entry(offset: u64, len: u64, cluster_bits: u32) -> u64 {
+    let offset_bits = 62 - (cluster_bits - 8);
+    let additional_sectors =
+        (offset + len - 1) / COMPRESSED_SECTOR_SIZE - offset / COMPRESSED_SECTOR_SIZE;
+    COMPRESSED_FLAG | (additional_sectors << offset_bits) | offset
+}
+
+// Returns the addresses of the host clusters that hold the data of the compressed cluster
+// described by the L2 entry `entry`.
+fn compressed_host_clusters(entry: u64, cluster_bits: u32) -> impl Iterator<Item = u64> {
+    let (offset, len) = compressed_cluster_extent(entry, cluster_bits);
+    let cluster_mask = (0x01u64 << cluster_bits) - 1;
+    ((offset & !cluster_mask)..=((offset + len - 1) & !cluster_mask)).step_by(1 << cluster_bits)
+}
+
 // Returns an Error if the given offset doesn't align to a cluster boundary.
 fn offset_is_cluster_boundary(offset: u64, cluster_bits: u32) -> Result<()> {
     if offset & ((0x01 << cluster_bits) - 1) != 0 {
@@ -1895,6 +2669,16 @@ mod tests {
         });
     }
 
+    #[cfg_attr(windows, ignore = "TODO(b/257958782): Enable large test on windows")]
+    #[test]
+    fn discard_granularity_is_cluster_size() {
+        with_basic_file(&valid_header(), |disk_file: File| {
+            let q = QcowFile::from(disk_file, MAX_NESTING_DEPTH).unwrap();
+            // The header has 64 KiB clusters.
+            assert_eq!(q.discard_granularity(), 64 * 1024);
+        });
+    }
+
     #[cfg_attr(windows, ignore = "TODO(b/257958782): Enable large test on windows")]
     #[test]
     fn write_read_start_backing() {
@@ -2568,4 +3352,304 @@ mod tests {
             }
         });
     }
+
+    // Returns a cluster of easily compressible data that differs for each `seed`.
+    fn compressible_cluster(qcow: &QcowFile, seed: u8) -> Vec<u8> {
+        (0..qcow.raw_file.cluster_size())
+            .map(|i| (i % 13) as u8 ^ seed)
+            .collect()
+    }
+
+    // Returns the refcount of the single host cluster storing the compressed cluster at `address`.
+    fn compressed_host_cluster_refcount(qcow: &mut QcowFile, address: u64) -> u16 {
+        let entry = qcow.l2_entry(address).unwrap();
+        assert_ne!(entry & COMPRESSED_FLAG, 0);
+        let host_clusters: Vec<u64> =
+            compressed_host_clusters(entry, qcow.header.cluster_bits).collect();
+        assert_eq!(host_clusters.len(), 1);
+        qcow.refcounts
+            .get_cluster_refcount(&mut qcow.raw_file, host_clusters[0])
+            .unwrap()
+    }
+
+    #[test]
+    fn compressed_cluster_entry_round_trip() {
+        let entry = compressed_cluster_entry(0x1_fe45, 0x1_0000, 16);
+        assert_ne!(entry & COMPRESSED_FLAG, 0);
+        let (offset, len) = compressed_cluster_extent(entry, 16);
+        assert_eq!(offset, 0x1_fe45);
+        // The length is rounded up to the end of the last sector touched.
+        assert_eq!(len, 0x3_0000 - 0x1_fe45);
+        assert_eq!(
+            compressed_host_clusters(entry, 16).collect::<Vec<u64>>(),
+            vec![0x1_0000, 0x2_0000]
+        );
+    }
+
+    #[test]
+    fn write_read_compressed() {
+        with_default_file(1024 * 1024 * 10, |mut qcow_file| {
+            let cluster_size = qcow_file.raw_file.cluster_size();
+            let data_a = compressible_cluster(&qcow_file, 0);
+            let data_b = compressible_cluster(&qcow_file, 0x80);
+            qcow_file
+                .write_compressed_cluster(cluster_size, &data_a)
+                .expect("Failed to write compressed cluster.");
+            qcow_file
+                .write_compressed_cluster(cluster_size * 3, &data_b)
+                .expect("Failed to write compressed cluster.");
+
+            let mut readback = vec![0u8; cluster_size as usize];
+            read_exact_at(&mut qcow_file, &mut readback, cluster_size).expect("Failed to read.");
+            assert_eq!(readback, data_a);
+            read_exact_at(&mut qcow_file, &mut readback, cluster_size * 3)
+                .expect("Failed to read.");
+            assert_eq!(readback, data_b);
+
+            // Both compressed clusters are small enough to share one host cluster.
+            assert_eq!(
+                compressed_host_cluster_refcount(&mut qcow_file, cluster_size),
+                2
+            );
+
+            // Reads spanning compressed and unallocated clusters.
+            let mut readback = vec![0u8; cluster_size as usize * 2];
+            read_exact_at(&mut qcow_file, &mut readback, cluster_size / 2)
+                .expect("Failed to read.");
+            assert!(readback[..cluster_size as usize / 2]
+                .iter()
+                .all(|b| *b == 0));
+            assert_eq!(
+                &readback[cluster_size as usize / 2..cluster_size as usize * 3 / 2],
+                &data_a[..]
+            );
+        });
+    }
+
+    #[test]
+    fn write_to_compressed_cluster() {
+        with_default_file(1024 * 1024 * 10, |mut qcow_file| {
+            let cluster_size = qcow_file.raw_file.cluster_size();
+            let data_a = compressible_cluster(&qcow_file, 0);
+            let data_b = compressible_cluster(&qcow_file, 0x80);
+            qcow_file.write_compressed_cluster(0, &data_a).unwrap();
+            qcow_file
+                .write_compressed_cluster(cluster_size, &data_b)
+                .unwrap();
+
+            // A partial write turns the cluster into a normal one and keeps the rest of its data.
+            write_all_at(&mut qcow_file, b"test", 0x10).expect("Failed to write.");
+            assert_eq!(qcow_file.l2_entry(0).unwrap() & COMPRESSED_FLAG, 0);
+            let mut expected = data_a.clone();
+            expected[0x10..0x14].copy_from_slice(b"test");
+            let mut readback = vec![0u8; cluster_size as usize];
+            read_exact_at(&mut qcow_file, &mut readback, 0).expect("Failed to read.");
+            assert_eq!(readback, expected);
+
+            // The other compressed cluster is untouched and holds the only reference left.
+            read_exact_at(&mut qcow_file, &mut readback, cluster_size).expect("Failed to read.");
+            assert_eq!(readback, data_b);
+            assert_eq!(
+                compressed_host_cluster_refcount(&mut qcow_file, cluster_size),
+                1
+            );
+
+            // Zeroing part of a compressed cluster.
+            qcow_file
+                .write_zeroes_all_at(cluster_size + 0x20, 0x20)
+                .expect("Failed to write zeroes.");
+            let mut expected = data_b.clone();
+            expected[0x20..0x40].fill(0);
+            read_exact_at(&mut qcow_file, &mut readback, cluster_size).expect("Failed to read.");
+            assert_eq!(readback, expected);
+        });
+    }
+
+    #[test]
+    fn compressed_clusters_persist() {
+        let file = tempfile().expect("failed to create temp file");
+        let data;
+        let cluster_size;
+        {
+            let mut qcow_file = QcowFile::new(file.try_clone().unwrap(), 1024 * 1024).unwrap();
+            cluster_size = qcow_file.raw_file.cluster_size();
+            data = compressible_cluster(&qcow_file, 0x42);
+            qcow_file
+                .write_compressed_cluster(cluster_size * 2, &data)
+                .unwrap();
+            qcow_file
+                .write_compressed_cluster(cluster_size * 4, &data)
+                .unwrap();
+        }
+
+        // Force a refcount rebuild on open to check compressed clusters are accounted for.
+        let mut file = file;
+        let mut header = QcowHeader::new(&mut file).unwrap();
+        header.compatible_features |= COMPATIBLE_FEATURES_LAZY_REFCOUNTS;
+        file.rewind().unwrap();
+        header.write_to(&mut file).unwrap();
+
+        let mut qcow_file = QcowFile::from(file, MAX_NESTING_DEPTH).unwrap();
+        let mut readback = vec![0u8; cluster_size as usize];
+        for address in [cluster_size * 2, cluster_size * 4] {
+            read_exact_at(&mut qcow_file, &mut readback, address).expect("Failed to read.");
+            assert_eq!(readback, data);
+        }
+        assert_eq!(
+            compressed_host_cluster_refcount(&mut qcow_file, cluster_size * 2),
+            2
+        );
+    }
+
+    #[test]
+    fn create_compressed_from_raw() {
+        const SOURCE_SIZE: usize = 0x1_0000 * 3 + 0x1234;
+        let mut source_data = vec![0u8; SOURCE_SIZE];
+        // Leave the second cluster empty and put some data in the partial last cluster.
+        source_data[..0x1_0000].fill(0x11);
+        source_data[0x2_0000..0x2_1000].copy_from_slice(&[0x22; 0x1000]);
+        source_data[SOURCE_SIZE - 4..].copy_from_slice(b"tail");
+        let mut source = tempfile().expect("failed to create temp file");
+        source.write_all(&source_data).unwrap();
+        source.rewind().unwrap();
+
+        let file = tempfile().expect("failed to create temp file");
+        let mut qcow_file = QcowFile::new_compressed_from(file, &mut source)
+            .expect("Failed to create compressed image.");
+        assert_eq!(qcow_file.virtual_size(), SOURCE_SIZE as u64);
+        assert_eq!(qcow_file.l2_entry(0x1_0000).unwrap(), 0);
+        assert_ne!(qcow_file.l2_entry(0x3_0000).unwrap() & COMPRESSED_FLAG, 0);
+
+        let mut readback = vec![0u8; SOURCE_SIZE];
+        read_exact_at(&mut qcow_file, &mut readback, 0).expect("Failed to read.");
+        assert_eq!(readback, source_data);
+    }
+
+    // Returns the refcount of the host cluster holding the data at guest `address`.
+    fn data_cluster_refcount(qcow: &mut QcowFile, address: u64) -> u16 {
+        let entry = qcow.l2_entry(address).unwrap();
+        assert_ne!(entry, 0);
+        qcow.refcounts
+            .get_cluster_refcount(&mut qcow.raw_file, entry)
+            .unwrap()
+    }
+
+    #[test]
+    fn snapshot_apply() {
+        with_default_file(1024 * 1024 * 10, |mut qcow_file| {
+            write_all_at(&mut qcow_file, &[0x55u8; 0x1000], 0x1000).expect("Failed to write.");
+            qcow_file
+                .create_snapshot("before")
+                .expect("Failed to create snapshot.");
+            assert_eq!(data_cluster_refcount(&mut qcow_file, 0x1000), 2);
+
+            // Writing to a shared cluster copies it and leaves the snapshot's data alone.
+            write_all_at(&mut qcow_file, &[0xaau8; 0x100], 0x1000).expect("Failed to write.");
+            write_all_at(&mut qcow_file, &[0xaau8; 0x100], 0x20_0000).expect("Failed to write.");
+            assert_eq!(data_cluster_refcount(&mut qcow_file, 0x1000), 1);
+            let mut readback = [0u8; 0x200];
+            read_exact_at(&mut qcow_file, &mut readback, 0x1000).expect("Failed to read.");
+            assert!(readback[..0x100].iter().all(|b| *b == 0xaa));
+            assert!(readback[0x100..].iter().all(|b| *b == 0x55));
+
+            qcow_file
+                .apply_snapshot("before")
+                .expect("Failed to apply snapshot.");
+            read_exact_at(&mut qcow_file, &mut readback, 0x1000).expect("Failed to read.");
+            assert!(readback.iter().all(|b| *b == 0x55));
+            read_exact_at(&mut qcow_file, &mut readback, 0x20_0000).expect("Failed to read.");
+            assert!(readback.iter().all(|b| *b == 0));
+            assert_eq!(data_cluster_refcount(&mut qcow_file, 0x1000), 2);
+        });
+    }
+
+    #[test]
+    fn snapshot_delete() {
+        with_default_file(1024 * 1024 * 10, |mut qcow_file| {
+            write_all_at(&mut qcow_file, &[0x55u8; 0x1000], 0).expect("Failed to write.");
+            qcow_file
+                .create_snapshot("first")
+                .expect("Failed to create snapshot.");
+            write_all_at(&mut qcow_file, &[0xaau8; 0x1000], 0).expect("Failed to write.");
+            let snapshot_cluster = {
+                let snapshot = qcow_file.snapshots[0].clone();
+                let mut snapshot_l1 = qcow_file.read_snapshot_l1_table(&snapshot).unwrap();
+                let l2_table =
+                    QcowFile::read_l2_cluster(&mut qcow_file.raw_file, snapshot_l1.remove(0))
+                        .unwrap();
+                l2_table[0]
+            };
+            assert_ne!(snapshot_cluster, qcow_file.l2_entry(0).unwrap());
+
+            qcow_file
+                .delete_snapshot("1")
+                .expect("Failed to delete snapshot.");
+            assert!(qcow_file.snapshots().is_empty());
+            assert_eq!(qcow_file.header.nb_snapshots, 0);
+            assert_eq!(
+                qcow_file
+                    .refcounts
+                    .get_cluster_refcount(&mut qcow_file.raw_file, snapshot_cluster)
+                    .unwrap(),
+                0
+            );
+            assert_eq!(data_cluster_refcount(&mut qcow_file, 0), 1);
+            let mut readback = [0u8; 0x1000];
+            read_exact_at(&mut qcow_file, &mut readback, 0).expect("Failed to read.");
+            assert!(readback.iter().all(|b| *b == 0xaa));
+        });
+    }
+
+    #[test]
+    fn snapshot_names() {
+        with_default_file(1024 * 1024, |mut qcow_file| {
+            qcow_file.create_snapshot("a").unwrap();
+            qcow_file.create_snapshot("b").unwrap();
+            assert!(matches!(
+                qcow_file.create_snapshot("a"),
+                Err(Error::SnapshotExists(_))
+            ));
+            assert!(matches!(
+                qcow_file.create_snapshot(""),
+                Err(Error::InvalidSnapshotName)
+            ));
+            assert!(matches!(
+                qcow_file.apply_snapshot("c"),
+                Err(Error::SnapshotNotFound(_))
+            ));
+            let snapshots = qcow_file.snapshots();
+            assert_eq!(snapshots.len(), 2);
+            assert_eq!(snapshots[0].id, "1");
+            assert_eq!(snapshots[1].id, "2");
+            assert_eq!(snapshots[1].name, "b");
+        });
+    }
+
+    #[test]
+    fn snapshots_persist() {
+        let file = tempfile().expect("failed to create temp file");
+        {
+            let mut qcow_file = QcowFile::new(file.try_clone().unwrap(), 1024 * 1024).unwrap();
+            write_all_at(&mut qcow_file, &[0x55u8; 0x1000], 0).expect("Failed to write.");
+            qcow_file.create_snapshot("saved").unwrap();
+            write_all_at(&mut qcow_file, &[0xaau8; 0x1000], 0).expect("Failed to write.");
+        }
+
+        // Force a refcount rebuild on open to check the snapshot's clusters are accounted for.
+        let mut file = file;
+        let mut header = QcowHeader::new(&mut file).unwrap();
+        assert_eq!(header.nb_snapshots, 1);
+        header.compatible_features |= COMPATIBLE_FEATURES_LAZY_REFCOUNTS;
+        file.rewind().unwrap();
+        header.write_to(&mut file).unwrap();
+
+        let mut qcow_file = QcowFile::from(file, MAX_NESTING_DEPTH).unwrap();
+        assert_eq!(qcow_file.snapshots()[0].name, "saved");
+        assert_eq!(data_cluster_refcount(&mut qcow_file, 0), 1);
+        qcow_file.apply_snapshot("saved").unwrap();
+        let mut readback = [0u8; 0x1000];
+        read_exact_at(&mut qcow_file, &mut readback, 0).expect("Failed to read.");
+        assert!(readback.iter().all(|b| *b == 0x55));
+        assert_eq!(data_cluster_refcount(&mut qcow_file, 0), 2);
+    }
 }
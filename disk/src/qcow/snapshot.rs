@@ -0,0 +1,250 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The table of internal snapshots stored in a qcow2 image.
+
+use std::cmp::min;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+
+/// The specification limits images to this many snapshots.
+pub const MAX_SNAPSHOTS: usize = 65536;
+
+// Size of the fixed part of a snapshot table entry.
+const ENTRY_HEADER_SIZE: usize = 40;
+// Size of the extra data fields that are understood: the 64-bit VM state size and the virtual disk
+// size.
+const KNOWN_EXTRA_DATA_SIZE: usize = 16;
+// Same limit on the extra data of an entry as qemu.
+const MAX_EXTRA_DATA_SIZE: usize = 1024;
+// Each table entry is padded to a multiple of 8 bytes.
+const ENTRY_ALIGNMENT: usize = 8;
+
+/// An entry of the snapshot table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    /// Offset of the snapshot's copy of the L1 table in the image.
+    pub l1_table_offset: u64,
+    /// Number of entries in the snapshot's L1 table.
+    pub l1_size: u32,
+    /// Unique ID of the snapshot.
+    pub id: String,
+    /// Name of the snapshot.
+    pub name: String,
+    /// Seconds since the Unix epoch when the snapshot was taken.
+    pub date_sec: u32,
+    /// Nanoseconds part of the time the snapshot was taken.
+    pub date_nsec: u32,
+    /// Guest time in nanoseconds when the snapshot was taken.
+    pub vm_clock_nsec: u64,
+    /// Size of the VM state saved with the snapshot, 0 if there is none.
+    pub vm_state_size: u64,
+    /// Virtual size of the disk when the snapshot was taken.
+    pub disk_size: u64,
+    /// Extra data after the known fields, kept as is when the table is rewritten.
+    pub unknown_extra_data: Vec<u8>,
+}
+
+impl SnapshotEntry {
+    // Appends the on-disk representation of the entry to `table`.
+    fn write_to(&self, table: &mut Vec<u8>) {
+        let start = table.len();
+        let extra_data_size = KNOWN_EXTRA_DATA_SIZE + self.unknown_extra_data.len();
+        table.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        table.extend_from_slice(&self.l1_size.to_be_bytes());
+        table.extend_from_slice(&(self.id.len() as u16).to_be_bytes());
+        table.extend_from_slice(&(self.name.len() as u16).to_be_bytes());
+        table.extend_from_slice(&self.date_sec.to_be_bytes());
+        table.extend_from_slice(&self.date_nsec.to_be_bytes());
+        table.extend_from_slice(&self.vm_clock_nsec.to_be_bytes());
+        // The 32-bit VM state size is superseded by the 64-bit one in the extra data.
+        let vm_state_size_32 = min(self.vm_state_size, u64::from(u32::MAX)) as u32;
+        table.extend_from_slice(&vm_state_size_32.to_be_bytes());
+        table.extend_from_slice(&(extra_data_size as u32).to_be_bytes());
+        table.extend_from_slice(&self.vm_state_size.to_be_bytes());
+        table.extend_from_slice(&self.disk_size.to_be_bytes());
+        table.extend_from_slice(&self.unknown_extra_data);
+        table.extend_from_slice(self.id.as_bytes());
+        table.extend_from_slice(self.name.as_bytes());
+        let len = table.len() - start;
+        table.resize(start + padded_entry_size(len), 0);
+    }
+}
+
+// Returns `len` rounded up to the alignment of table entries.
+fn padded_entry_size(len: usize) -> usize {
+    (len + ENTRY_ALIGNMENT - 1) / ENTRY_ALIGNMENT * ENTRY_ALIGNMENT
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u16<R: Read>(r: &mut R) -> io::Result<u16> {
+    let mut value = [0u8; 2];
+    r.read_exact(&mut value)?;
+    Ok(u16::from_be_bytes(value))
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut value = [0u8; 4];
+    r.read_exact(&mut value)?;
+    Ok(u32::from_be_bytes(value))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut value = [0u8; 8];
+    r.read_exact(&mut value)?;
+    Ok(u64::from_be_bytes(value))
+}
+
+fn read_string<R: Read>(r: &mut R, len: usize) -> io::Result<String> {
+    let mut bytes = vec![0u8; len];
+    r.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| invalid_data("snapshot name is not valid UTF-8"))
+}
+
+/// Reads the `count` entries of the snapshot table at `offset` in `file`. Returns the entries and
+/// the size of the table in bytes. `disk_size` is used for entries that don't record the virtual
+/// size of the disk.
+pub fn read_snapshot_table(
+    file: &mut File,
+    offset: u64,
+    count: u32,
+    disk_size: u64,
+) -> io::Result<(Vec<SnapshotEntry>, u64)> {
+    if count as usize > MAX_SNAPSHOTS {
+        return Err(invalid_data("too many snapshots"));
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let mut reader = BufReader::new(file);
+    let mut snapshots = Vec::with_capacity(count as usize);
+    let mut table_size = 0;
+    for _ in 0..count {
+        let l1_table_offset = read_u64(&mut reader)?;
+        let l1_size = read_u32(&mut reader)?;
+        let id_size = read_u16(&mut reader)? as usize;
+        let name_size = read_u16(&mut reader)? as usize;
+        let date_sec = read_u32(&mut reader)?;
+        let date_nsec = read_u32(&mut reader)?;
+        let vm_clock_nsec = read_u64(&mut reader)?;
+        let mut vm_state_size = u64::from(read_u32(&mut reader)?);
+        let extra_data_size = read_u32(&mut reader)? as usize;
+        if extra_data_size > MAX_EXTRA_DATA_SIZE {
+            return Err(invalid_data("snapshot extra data is too large"));
+        }
+        let mut extra_data = vec![0u8; extra_data_size];
+        reader.read_exact(&mut extra_data)?;
+        let mut extra_data_reader = &extra_data[..];
+        if extra_data_size >= 8 {
+            vm_state_size = read_u64(&mut extra_data_reader)?;
+        }
+        let disk_size = if extra_data_size >= KNOWN_EXTRA_DATA_SIZE {
+            read_u64(&mut extra_data_reader)?
+        } else {
+            disk_size
+        };
+        let unknown_extra_data = extra_data_reader.to_vec();
+        let id = read_string(&mut reader, id_size)?;
+        let name = read_string(&mut reader, name_size)?;
+
+        let len = ENTRY_HEADER_SIZE + extra_data_size + id_size + name_size;
+        let mut padding = vec![0u8; padded_entry_size(len) - len];
+        reader.read_exact(&mut padding)?;
+        table_size += padded_entry_size(len) as u64;
+
+        snapshots.push(SnapshotEntry {
+            l1_table_offset,
+            l1_size,
+            id,
+            name,
+            date_sec,
+            date_nsec,
+            vm_clock_nsec,
+            vm_state_size,
+            disk_size,
+            unknown_extra_data,
+        });
+    }
+    Ok((snapshots, table_size))
+}
+
+/// Returns the on-disk representation of a snapshot table holding `snapshots`.
+pub fn snapshot_table_bytes(snapshots: &[SnapshotEntry]) -> Vec<u8> {
+    let mut table = Vec::new();
+    for snapshot in snapshots {
+        snapshot.write_to(&mut table);
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::tempfile;
+
+    use super::*;
+
+    fn test_entry(id: &str, name: &str) -> SnapshotEntry {
+        SnapshotEntry {
+            l1_table_offset: 0x3_0000,
+            l1_size: 2,
+            id: id.to_string(),
+            name: name.to_string(),
+            date_sec: 1_700_000_000,
+            date_nsec: 1234,
+            vm_clock_nsec: 5678,
+            vm_state_size: 0,
+            disk_size: 0x10_0000,
+            unknown_extra_data: vec![0, 0, 0, 0, 0, 0, 0, 7],
+        }
+    }
+
+    #[test]
+    fn table_round_trip() {
+        let snapshots = vec![
+            test_entry("1", "first"),
+            test_entry("22", "second snapshot"),
+        ];
+        let table = snapshot_table_bytes(&snapshots);
+        assert_eq!(table.len() % ENTRY_ALIGNMENT, 0);
+
+        let mut file = tempfile().unwrap();
+        file.write_all(&[0xffu8; 0x200]).unwrap();
+        file.write_all(&table).unwrap();
+        let (read_snapshots, size) = read_snapshot_table(&mut file, 0x200, 2, 0).unwrap();
+        assert_eq!(read_snapshots, snapshots);
+        assert_eq!(size, table.len() as u64);
+    }
+
+    #[test]
+    fn missing_extra_data() {
+        // An entry without extra data, as written by old versions of qemu.
+        let mut table = Vec::new();
+        table.extend_from_slice(&0x3_0000u64.to_be_bytes());
+        table.extend_from_slice(&1u32.to_be_bytes());
+        table.extend_from_slice(&1u16.to_be_bytes());
+        table.extend_from_slice(&3u16.to_be_bytes());
+        table.extend_from_slice(&[0u8; 16]);
+        table.extend_from_slice(&0x1000u32.to_be_bytes());
+        table.extend_from_slice(&0u32.to_be_bytes());
+        table.extend_from_slice(b"1old");
+        table.resize(48, 0);
+
+        let mut file = tempfile().unwrap();
+        file.write_all(&table).unwrap();
+        let (snapshots, size) = read_snapshot_table(&mut file, 0, 1, 0x4000).unwrap();
+        assert_eq!(size, 48);
+        assert_eq!(snapshots[0].id, "1");
+        assert_eq!(snapshots[0].name, "old");
+        assert_eq!(snapshots[0].vm_state_size, 0x1000);
+        assert_eq!(snapshots[0].disk_size, 0x4000);
+        assert!(snapshots[0].unknown_extra_data.is_empty());
+    }
+}
@@ -114,6 +114,11 @@ impl<T: Cacheable> CacheMap<T> {
         self.map.iter_mut()
     }
 
+    /// Drops all cached items without writing them back. Dirty items have to be written first.
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
     // Check if the refblock cache is full and we need to evict.
     pub fn insert<F>(&mut self, index: usize, block: T, write_callback: F) -> io::Result<()>
     where
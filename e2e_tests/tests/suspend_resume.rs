@@ -176,7 +176,7 @@ fn snapshot_vhost_user() {
         vm.suspend_full().unwrap();
         vm.snapshot(&snap_path).unwrap();
 
-        let snapshot_json = std::fs::read_to_string(&snap_path).unwrap();
+        let snapshot_json = std::fs::read_to_string(snap_path.join("devices")).unwrap();
 
         assert!(snapshot_json.contains("\"device_name\":\"virtio-block\""));
     }
@@ -146,6 +146,11 @@ impl<F: FileSystem + Sync> Server<F> {
         Server { fs }
     }
 
+    /// Returns the file system served by this server.
+    pub fn fs(&self) -> &F {
+        &self.fs
+    }
+
     pub fn handle_message<R: Reader + ZeroCopyReader, W: Writer + ZeroCopyWriter, M: Mapper>(
         &self,
         mut r: R,
@@ -0,0 +1,428 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Encodings of the rectangles of the framebuffer updates sent to the clients.
+
+use std::cmp::min;
+use std::io;
+
+use flate2::Compress;
+use flate2::Compression;
+use flate2::FlushCompress;
+
+use super::rfb::write_rect_header;
+use super::rfb::PixelFormat;
+use super::rfb::ENCODING_RAW;
+use super::rfb::ENCODING_TIGHT;
+use super::rfb::ENCODING_ZRLE;
+
+const ZRLE_TILE_SIZE: u32 = 64;
+const ZRLE_RAW: u8 = 0;
+const ZRLE_SOLID: u8 = 1;
+const ZRLE_MAX_PALETTE_SIZE: usize = 16;
+
+// Limits of the size of the rectangles sent with the Tight encoding.
+const TIGHT_MAX_WIDTH: u32 = 2048;
+const TIGHT_MAX_PIXELS: u32 = 65536;
+// Tight data shorter than this is sent without being compressed.
+const TIGHT_MIN_TO_COMPRESS: usize = 12;
+const TIGHT_FILL: u8 = 0x80;
+// Basic compression with zlib stream 0 and no filter.
+const TIGHT_BASIC_STREAM_0: u8 = 0x00;
+
+/// The XRGB8888 pixels of a frame.
+pub struct Frame<'a> {
+    pub pixels: &'a [u8],
+    pub width: u32,
+}
+
+impl<'a> Frame<'a> {
+    fn pixel(&self, x: u32, y: u32) -> u32 {
+        let offset = ((y * self.width + x) * 4) as usize;
+        u32::from_le_bytes(self.pixels[offset..offset + 4].try_into().unwrap())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Raw,
+    Tight,
+    Zrle,
+}
+
+/// Encodes the framebuffer updates of a client. The zlib streams of ZRLE and Tight last as long as
+/// the connection, so each client has its own encoder.
+pub struct Encoder {
+    encoding: Encoding,
+    format: PixelFormat,
+    zrle_stream: Compress,
+    tight_stream: Compress,
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder {
+            encoding: Encoding::Raw,
+            format: PixelFormat::XRGB8888,
+            zrle_stream: Compress::new(Compression::default(), true),
+            tight_stream: Compress::new(Compression::default(), true),
+        }
+    }
+
+    /// Uses the first supported encoding of the client's list of preferences, raw if none is.
+    pub fn set_encodings(&mut self, encodings: &[i32]) {
+        self.encoding = encodings
+            .iter()
+            .find_map(|encoding| match *encoding {
+                ENCODING_RAW => Some(Encoding::Raw),
+                ENCODING_TIGHT => Some(Encoding::Tight),
+                ENCODING_ZRLE => Some(Encoding::Zrle),
+                _ => None,
+            })
+            .unwrap_or(Encoding::Raw);
+    }
+
+    pub fn set_pixel_format(&mut self, format: PixelFormat) {
+        self.format = format;
+    }
+
+    /// Appends the rectangles encoding the given area of `frame` to `out` and returns their
+    /// number, which is 0 for an empty area.
+    pub fn encode(
+        &mut self,
+        frame: &Frame,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        out: &mut Vec<u8>,
+    ) -> io::Result<usize> {
+        // The Tight encoding splits the area by its width, which can't be 0.
+        if width == 0 || height == 0 {
+            return Ok(0);
+        }
+        match self.encoding {
+            Encoding::Raw => {
+                self.encode_raw(frame, x, y, width, height, out);
+                Ok(1)
+            }
+            Encoding::Tight => self.encode_tight(frame, x, y, width, height, out),
+            Encoding::Zrle => {
+                self.encode_zrle(frame, x, y, width, height, out)?;
+                Ok(1)
+            }
+        }
+    }
+
+    fn encode_raw(
+        &self,
+        frame: &Frame,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        out: &mut Vec<u8>,
+    ) {
+        write_rect_header(out, x, y, width, height, ENCODING_RAW);
+        out.reserve((width * height) as usize * self.format.bytes_per_pixel());
+        for row in y..y + height {
+            for column in x..x + width {
+                self.format
+                    .write_pixel(self.format.convert(frame.pixel(column, row)), out);
+            }
+        }
+    }
+
+    fn encode_zrle(
+        &mut self,
+        frame: &Frame,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        out: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        let mut data = Vec::new();
+        let mut tile = Vec::with_capacity((ZRLE_TILE_SIZE * ZRLE_TILE_SIZE) as usize);
+        for tile_y in (y..y + height).step_by(ZRLE_TILE_SIZE as usize) {
+            let tile_height = min(ZRLE_TILE_SIZE, y + height - tile_y);
+            for tile_x in (x..x + width).step_by(ZRLE_TILE_SIZE as usize) {
+                let tile_width = min(ZRLE_TILE_SIZE, x + width - tile_x);
+                tile.clear();
+                for row in tile_y..tile_y + tile_height {
+                    for column in tile_x..tile_x + tile_width {
+                        tile.push(self.format.convert(frame.pixel(column, row)));
+                    }
+                }
+                write_zrle_tile(&self.format, &tile, tile_width as usize, &mut data);
+            }
+        }
+
+        let compressed = deflate(&mut self.zrle_stream, &data)?;
+        write_rect_header(out, x, y, width, height, ENCODING_ZRLE);
+        out.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+        out.extend_from_slice(&compressed);
+        Ok(())
+    }
+
+    fn encode_tight(
+        &mut self,
+        frame: &Frame,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        out: &mut Vec<u8>,
+    ) -> io::Result<usize> {
+        let max_height = (TIGHT_MAX_PIXELS / min(width, TIGHT_MAX_WIDTH)).max(1);
+        let mut count = 0;
+        for rect_y in (y..y + height).step_by(max_height as usize) {
+            let rect_height = min(max_height, y + height - rect_y);
+            for rect_x in (x..x + width).step_by(TIGHT_MAX_WIDTH as usize) {
+                let rect_width = min(TIGHT_MAX_WIDTH, x + width - rect_x);
+                self.encode_tight_rect(frame, rect_x, rect_y, rect_width, rect_height, out)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn encode_tight_rect(
+        &mut self,
+        frame: &Frame,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        out: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        write_rect_header(out, x, y, width, height, ENCODING_TIGHT);
+
+        let first = frame.pixel(x, y);
+        let solid = (y..y + height)
+            .all(|row| (x..x + width).all(|column| frame.pixel(column, row) == first));
+        if solid {
+            out.push(TIGHT_FILL);
+            self.format.write_tight_pixel(first, out);
+            return Ok(());
+        }
+
+        let mut data = Vec::with_capacity((width * height * 4) as usize);
+        for row in y..y + height {
+            for column in x..x + width {
+                self.format
+                    .write_tight_pixel(frame.pixel(column, row), &mut data);
+            }
+        }
+        out.push(TIGHT_BASIC_STREAM_0);
+        if data.len() < TIGHT_MIN_TO_COMPRESS {
+            out.extend_from_slice(&data);
+        } else {
+            let compressed = deflate(&mut self.tight_stream, &data)?;
+            write_tight_length(compressed.len(), out);
+            out.extend_from_slice(&compressed);
+        }
+        Ok(())
+    }
+}
+
+// Appends a ZRLE tile made of `pixels`, already converted to the client format, to `out`.
+fn write_zrle_tile(format: &PixelFormat, pixels: &[u32], width: usize, out: &mut Vec<u8>) {
+    let pixel_size = format.compact_bytes_per_pixel();
+    let mut palette = Vec::with_capacity(ZRLE_MAX_PALETTE_SIZE);
+    for pixel in pixels {
+        if !palette.contains(pixel) {
+            if palette.len() == ZRLE_MAX_PALETTE_SIZE {
+                palette.clear();
+                break;
+            }
+            palette.push(*pixel);
+        }
+    }
+
+    if palette.len() == 1 {
+        out.push(ZRLE_SOLID);
+        format.write_compact_pixel(palette[0], out);
+        return;
+    }
+
+    if !palette.is_empty() {
+        let bits = match palette.len() {
+            2 => 1,
+            3..=4 => 2,
+            _ => 4,
+        };
+        let rows = pixels.len() / width;
+        let packed_size = palette.len() * pixel_size + rows * (width * bits + 7) / 8;
+        if packed_size < pixels.len() * pixel_size {
+            out.push(palette.len() as u8);
+            for color in &palette {
+                format.write_compact_pixel(*color, out);
+            }
+            // The indices are packed from the most significant bits, each row starting on a byte.
+            for row in pixels.chunks(width) {
+                let mut byte = 0u8;
+                let mut used_bits = 0;
+                for pixel in row {
+                    let index = palette.iter().position(|color| color == pixel).unwrap();
+                    byte = (byte << bits) | index as u8;
+                    used_bits += bits;
+                    if used_bits == 8 {
+                        out.push(byte);
+                        byte = 0;
+                        used_bits = 0;
+                    }
+                }
+                if used_bits > 0 {
+                    out.push(byte << (8 - used_bits));
+                }
+            }
+            return;
+        }
+    }
+
+    out.push(ZRLE_RAW);
+    for pixel in pixels {
+        format.write_compact_pixel(*pixel, out);
+    }
+}
+
+// Appends the length of compressed Tight data to `out`, 7 bits per byte with the last byte holding
+// up to 8 bits.
+fn write_tight_length(length: usize, out: &mut Vec<u8>) {
+    if length < 1 << 7 {
+        out.push(length as u8);
+    } else if length < 1 << 14 {
+        out.extend_from_slice(&[(length as u8) | 0x80, (length >> 7) as u8]);
+    } else {
+        out.extend_from_slice(&[
+            (length as u8) | 0x80,
+            ((length >> 7) as u8) | 0x80,
+            (length >> 14) as u8,
+        ]);
+    }
+}
+
+// Compresses `input` with `stream`, flushing it so that the client can decompress all the data
+// without waiting for the next update.
+fn deflate(stream: &mut Compress, input: &[u8]) -> io::Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len() / 2 + 64);
+    let start = stream.total_in();
+    loop {
+        let consumed = (stream.total_in() - start) as usize;
+        stream
+            .compress_vec(&input[consumed..], &mut output, FlushCompress::Sync)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        // The flush is done once all the input is consumed without filling the output.
+        if (stream.total_in() - start) as usize == input.len() && output.len() < output.capacity() {
+            return Ok(output);
+        }
+        output.reserve(output.capacity());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::Decompress;
+    use flate2::FlushDecompress;
+
+    use super::*;
+
+    fn frame_pixels(pixels: &[u32]) -> Vec<u8> {
+        pixels
+            .iter()
+            .flat_map(|pixel| pixel.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn raw() {
+        let pixels = frame_pixels(&[0x00ff_0000, 0x0000_ff00, 0x0000_00ff, 0]);
+        let frame = Frame {
+            pixels: &pixels,
+            width: 2,
+        };
+        let mut encoder = Encoder::new();
+        let mut out = Vec::new();
+        assert_eq!(encoder.encode(&frame, 1, 0, 1, 2, &mut out).unwrap(), 1);
+        assert_eq!(&out[0..12], &[0, 1, 0, 0, 0, 1, 0, 2, 0, 0, 0, 0]);
+        assert_eq!(
+            &out[12..],
+            &[0x00, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn zrle_tiles() {
+        // A solid tile followed by a two colour tile.
+        let mut pixels = Vec::new();
+        for _ in 0..2 {
+            pixels.extend([0x0012_3456; 64]);
+            pixels.extend([0x00ff_ffff, 0x0000_0000, 0x00ff_ffff, 0x0000_0000]);
+        }
+        let pixels = frame_pixels(&pixels);
+        let frame = Frame {
+            pixels: &pixels,
+            width: 68,
+        };
+        let mut encoder = Encoder::new();
+        encoder.set_encodings(&[ENCODING_ZRLE, ENCODING_RAW]);
+        let mut out = Vec::new();
+        encoder.encode(&frame, 0, 0, 68, 2, &mut out).unwrap();
+        assert_eq!(&out[8..12], &ENCODING_ZRLE.to_be_bytes());
+        let length = u32::from_be_bytes(out[12..16].try_into().unwrap()) as usize;
+        assert_eq!(out.len(), 16 + length);
+
+        let mut data = Vec::with_capacity(64);
+        Decompress::new(true)
+            .decompress_vec(&out[16..], &mut data, FlushDecompress::Sync)
+            .unwrap();
+        let mut expected = vec![ZRLE_SOLID, 0x56, 0x34, 0x12];
+        // A palette of two colours, then a bit per pixel.
+        expected.extend_from_slice(&[2, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00]);
+        expected.extend_from_slice(&[0b0101_0000, 0b0101_0000]);
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn tight_fill_and_split() {
+        let pixels = 0x0080_4020u32.to_le_bytes().repeat(4096 * 32);
+        let frame = Frame {
+            pixels: &pixels,
+            width: 4096,
+        };
+        let mut encoder = Encoder::new();
+        encoder.set_encodings(&[-239, ENCODING_TIGHT, ENCODING_ZRLE]);
+        let mut out = Vec::new();
+        assert_eq!(encoder.encode(&frame, 0, 0, 4096, 32, &mut out).unwrap(), 2);
+        assert_eq!(&out[12..16], &[TIGHT_FILL, 0x80, 0x40, 0x20]);
+        assert_eq!(out.len(), 2 * 16);
+    }
+
+    #[test]
+    fn empty_area() {
+        let pixels = [0u8; 16];
+        let frame = Frame {
+            pixels: &pixels,
+            width: 2,
+        };
+        let mut encoder = Encoder::new();
+        for encoding in [ENCODING_RAW, ENCODING_TIGHT, ENCODING_ZRLE] {
+            encoder.set_encodings(&[encoding]);
+            let mut out = Vec::new();
+            assert_eq!(encoder.encode(&frame, 0, 0, 0, 2, &mut out).unwrap(), 0);
+            assert_eq!(encoder.encode(&frame, 0, 0, 2, 0, &mut out).unwrap(), 0);
+            assert!(out.is_empty());
+        }
+    }
+
+    #[test]
+    fn tight_length() {
+        let mut out = Vec::new();
+        write_tight_length(10, &mut out);
+        write_tight_length(10000, &mut out);
+        write_tight_length(100000, &mut out);
+        assert_eq!(out, [10, 0x90, 0x4e, 0xa0, 0x8d, 0x06]);
+    }
+}
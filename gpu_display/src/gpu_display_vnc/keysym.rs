@@ -0,0 +1,117 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Translation of the X keysyms sent by VNC clients to Linux key codes.
+//!
+//! Clients send the symbol a key produces rather than the key itself, so the keys are found
+//! assuming the guest uses a US layout. The shifted symbols map to the same key as their unshifted
+//! counterpart since clients also send the presses of the shift keys.
+
+use linux_input_sys::constants::*;
+
+const LETTERS: [u16; 26] = [
+    KEY_A, KEY_B, KEY_C, KEY_D, KEY_E, KEY_F, KEY_G, KEY_H, KEY_I, KEY_J, KEY_K, KEY_L, KEY_M,
+    KEY_N, KEY_O, KEY_P, KEY_Q, KEY_R, KEY_S, KEY_T, KEY_U, KEY_V, KEY_W, KEY_X, KEY_Y, KEY_Z,
+];
+
+const DIGITS: [u16; 10] = [
+    KEY_0, KEY_1, KEY_2, KEY_3, KEY_4, KEY_5, KEY_6, KEY_7, KEY_8, KEY_9,
+];
+
+const KEYPAD_DIGITS: [u16; 10] = [
+    KEY_KP0, KEY_KP1, KEY_KP2, KEY_KP3, KEY_KP4, KEY_KP5, KEY_KP6, KEY_KP7, KEY_KP8, KEY_KP9,
+];
+
+const FUNCTION_KEYS: [u16; 12] = [
+    KEY_F1, KEY_F2, KEY_F3, KEY_F4, KEY_F5, KEY_F6, KEY_F7, KEY_F8, KEY_F9, KEY_F10, KEY_F11,
+    KEY_F12,
+];
+
+/// Returns the Linux key code of the key producing `keysym`, or `None` if there is no such key.
+pub fn keysym_to_linux(keysym: u32) -> Option<u16> {
+    let key = match keysym {
+        0x61..=0x7a => LETTERS[(keysym - 0x61) as usize],
+        0x41..=0x5a => LETTERS[(keysym - 0x41) as usize],
+        0x30..=0x39 => DIGITS[(keysym - 0x30) as usize],
+        0x20 => KEY_SPACE,
+        0x21 => KEY_1,
+        0x40 => KEY_2,
+        0x23 => KEY_3,
+        0x24 => KEY_4,
+        0x25 => KEY_5,
+        0x5e => KEY_6,
+        0x26 => KEY_7,
+        0x2a => KEY_8,
+        0x28 => KEY_9,
+        0x29 => KEY_0,
+        0x2d | 0x5f => KEY_MINUS,
+        0x3d | 0x2b => KEY_EQUAL,
+        0x5b | 0x7b => KEY_LEFTBRACE,
+        0x5d | 0x7d => KEY_RIGHTBRACE,
+        0x3b | 0x3a => KEY_SEMICOLON,
+        0x27 | 0x22 => KEY_APOSTROPHE,
+        0x60 | 0x7e => KEY_GRAVE,
+        0x5c | 0x7c => KEY_BACKSLASH,
+        0x2c | 0x3c => KEY_COMMA,
+        0x2e | 0x3e => KEY_DOT,
+        0x2f | 0x3f => KEY_SLASH,
+        0xff08 => KEY_BACKSPACE,
+        0xff09 | 0xfe20 => KEY_TAB,
+        0xff0d => KEY_ENTER,
+        0xff13 => KEY_PAUSE,
+        0xff14 => KEY_SCROLLLOCK,
+        0xff15 | 0xff61 => KEY_SYSRQ,
+        0xff1b => KEY_ESC,
+        0xff50 => KEY_HOME,
+        0xff51 => KEY_LEFT,
+        0xff52 => KEY_UP,
+        0xff53 => KEY_RIGHT,
+        0xff54 => KEY_DOWN,
+        0xff55 => KEY_PAGEUP,
+        0xff56 => KEY_PAGEDOWN,
+        0xff57 => KEY_END,
+        0xff63 => KEY_INSERT,
+        0xff67 => KEY_COMPOSE,
+        0xff7f => KEY_NUMLOCK,
+        0xff8d => KEY_KPENTER,
+        0xffaa => KEY_KPASTERISK,
+        0xffab => KEY_KPPLUS,
+        0xffad => KEY_KPMINUS,
+        0xffae => KEY_KPDOT,
+        0xffaf => KEY_KPSLASH,
+        0xffb0..=0xffb9 => KEYPAD_DIGITS[(keysym - 0xffb0) as usize],
+        0xffbe..=0xffc9 => FUNCTION_KEYS[(keysym - 0xffbe) as usize],
+        0xffe1 => KEY_LEFTSHIFT,
+        0xffe2 => KEY_RIGHTSHIFT,
+        0xffe3 => KEY_LEFTCTRL,
+        0xffe4 => KEY_RIGHTCTRL,
+        0xffe5 => KEY_CAPSLOCK,
+        0xffe7 | 0xffeb => KEY_LEFTMETA,
+        0xffe8 | 0xffec => KEY_RIGHTMETA,
+        0xffe9 => KEY_LEFTALT,
+        0xffea | 0xfe03 => KEY_RIGHTALT,
+        0xffff => KEY_DELETE,
+        _ => return None,
+    };
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translate() {
+        assert_eq!(keysym_to_linux(0x61), Some(KEY_A));
+        assert_eq!(keysym_to_linux(0x5a), Some(KEY_Z));
+        assert_eq!(keysym_to_linux(0x30), Some(KEY_0));
+        assert_eq!(keysym_to_linux(0x40), Some(KEY_2));
+        assert_eq!(keysym_to_linux(0x3f), Some(KEY_SLASH));
+        assert_eq!(keysym_to_linux(0xff0d), Some(KEY_ENTER));
+        assert_eq!(keysym_to_linux(0xffb7), Some(KEY_KP7));
+        assert_eq!(keysym_to_linux(0xffc9), Some(KEY_F12));
+        assert_eq!(keysym_to_linux(0xffe9), Some(KEY_LEFTALT));
+        assert_eq!(keysym_to_linux(0x20ac), None);
+    }
+}
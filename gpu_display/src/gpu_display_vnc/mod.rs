@@ -0,0 +1,702 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A display served to VNC clients with the RFB protocol.
+//!
+//! Each listening socket shows one scanout surface, the first surface created getting the first
+//! socket and so on. The keyboard and pointer events of the clients are sent to the event devices
+//! of the display like the events of the other backends.
+
+mod encoding;
+mod keysym;
+mod rfb;
+
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::bail;
+use anyhow::Context;
+use base::error;
+use base::info;
+use base::warn;
+use base::AsRawDescriptor;
+use base::Event;
+use base::EventToken;
+use base::RawDescriptor;
+use base::VolatileSlice;
+use base::WaitContext;
+use linux_input_sys::virtio_input_event;
+use sync::Mutex;
+
+use self::encoding::Encoder;
+use self::encoding::Frame;
+use self::keysym::keysym_to_linux;
+use self::rfb::ClientMessage;
+use self::rfb::ENCODING_DESKTOP_SIZE;
+use crate::DisplayT;
+use crate::EventDeviceKind;
+use crate::GpuDisplayError;
+use crate::GpuDisplayEvents;
+use crate::GpuDisplayFramebuffer;
+use crate::GpuDisplayResult;
+use crate::GpuDisplaySurface;
+use crate::SurfaceType;
+use crate::SysDisplayT;
+
+// Size of the framebuffer shown to the clients until a surface is created.
+const DEFAULT_WIDTH: u32 = 640;
+const DEFAULT_HEIGHT: u32 = 480;
+
+const DESKTOP_NAME: &str = "crosvm";
+
+// Clients that don't read their updates or send partial messages for this long are disconnected.
+const CLIENT_IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Size of the tiles compared to find the changed areas of the framebuffer.
+const UPDATE_TILE_SIZE: u32 = 64;
+
+const BUTTON_LEFT: u8 = 1;
+
+/// A listening socket of a VNC display.
+pub enum VncListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl VncListener {
+    pub fn try_clone(&self) -> io::Result<VncListener> {
+        match self {
+            VncListener::Tcp(listener) => listener.try_clone().map(VncListener::Tcp),
+            VncListener::Unix(listener) => listener.try_clone().map(VncListener::Unix),
+        }
+    }
+}
+
+impl AsRawDescriptor for VncListener {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        match self {
+            VncListener::Tcp(listener) => listener.as_raw_descriptor(),
+            VncListener::Unix(listener) => listener.as_raw_descriptor(),
+        }
+    }
+}
+
+// The framebuffer shown to the clients of a listening socket.
+struct Scanout {
+    width: u32,
+    height: u32,
+    // XRGB8888 pixels.
+    pixels: Vec<u8>,
+    // Incremented each time the size or the pixels change.
+    frame: u64,
+    // The descriptor of the surface shown, which receives the input events of the clients.
+    surface: Option<u64>,
+    // Events signaled when the framebuffer changes, by client.
+    clients: BTreeMap<u64, Event>,
+}
+
+impl Scanout {
+    fn new() -> Scanout {
+        Scanout {
+            width: DEFAULT_WIDTH,
+            height: DEFAULT_HEIGHT,
+            pixels: vec![0; (DEFAULT_WIDTH * DEFAULT_HEIGHT * 4) as usize],
+            frame: 0,
+            surface: None,
+            clients: BTreeMap::new(),
+        }
+    }
+
+    fn notify_clients(&mut self) {
+        self.frame += 1;
+        for event in self.clients.values() {
+            if let Err(e) = event.signal() {
+                error!("failed to signal a vnc client: {}", e);
+            }
+        }
+    }
+}
+
+// Input events of the clients waiting to be dispatched to the event devices.
+struct InputQueue {
+    events: Mutex<VecDeque<(u64, GpuDisplayEvents)>>,
+    // Signaled while `events` isn't empty.
+    event: Event,
+    next_tracking_id: AtomicI32,
+}
+
+impl InputQueue {
+    fn push(&self, surface: u64, events: GpuDisplayEvents) {
+        let mut queue = self.events.lock();
+        queue.push_back((surface, events));
+        if let Err(e) = self.event.signal() {
+            error!("failed to signal vnc input events: {}", e);
+        }
+    }
+}
+
+struct VncSurface {
+    surface_id: u32,
+    width: u32,
+    buffer: Vec<u8>,
+    scanout: Arc<Mutex<Scanout>>,
+}
+
+impl GpuDisplaySurface for VncSurface {
+    fn surface_descriptor(&self) -> u64 {
+        self.surface_id as u64
+    }
+
+    fn framebuffer(&mut self) -> Option<GpuDisplayFramebuffer> {
+        // XRGB8888
+        let stride = self.width * 4;
+        Some(GpuDisplayFramebuffer::new(
+            VolatileSlice::new(&mut self.buffer),
+            stride,
+            4,
+        ))
+    }
+
+    fn flip(&mut self) {
+        let mut scanout = self.scanout.lock();
+        scanout.pixels.copy_from_slice(&self.buffer);
+        scanout.notify_clients();
+    }
+}
+
+impl Drop for VncSurface {
+    fn drop(&mut self) {
+        // The clients keep showing the last frame until another surface is created.
+        let mut scanout = self.scanout.lock();
+        if scanout.surface == Some(self.surface_id as u64) {
+            scanout.surface = None;
+        }
+    }
+}
+
+pub struct DisplayVnc {
+    scanouts: Vec<Arc<Mutex<Scanout>>>,
+    input: Arc<InputQueue>,
+    current_event: Option<GpuDisplayEvents>,
+    kill_evt: Event,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl DisplayVnc {
+    /// Starts serving clients on `listeners`, one per scanout surface.
+    pub fn new(listeners: &[VncListener]) -> GpuDisplayResult<DisplayVnc> {
+        let kill_evt = Event::new().map_err(|_| GpuDisplayError::CreateEvent)?;
+        let input = Arc::new(InputQueue {
+            events: Mutex::new(VecDeque::new()),
+            event: Event::new().map_err(|_| GpuDisplayError::CreateEvent)?,
+            next_tracking_id: AtomicI32::new(0),
+        });
+
+        let mut display = DisplayVnc {
+            scanouts: Vec::new(),
+            input,
+            current_event: None,
+            kill_evt,
+            threads: Vec::new(),
+        };
+        for (index, listener) in listeners.iter().enumerate() {
+            let server = Server {
+                index,
+                listener: listener.try_clone()?,
+                scanout: Arc::new(Mutex::new(Scanout::new())),
+                input: display.input.clone(),
+                kill_evt: display
+                    .kill_evt
+                    .try_clone()
+                    .map_err(|_| GpuDisplayError::CreateEvent)?,
+            };
+            display.scanouts.push(server.scanout.clone());
+            // Dropping `display` on failure stops the servers already started.
+            display.threads.push(
+                thread::Builder::new()
+                    .name(format!("vnc_server_{}", index))
+                    .spawn(move || server.run())?,
+            );
+        }
+        Ok(display)
+    }
+}
+
+impl Drop for DisplayVnc {
+    fn drop(&mut self) {
+        if let Err(e) = self.kill_evt.signal() {
+            error!("failed to stop the vnc servers: {}", e);
+            return;
+        }
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                error!("vnc server thread panicked");
+            }
+        }
+    }
+}
+
+impl DisplayT for DisplayVnc {
+    fn pending_events(&self) -> bool {
+        let queue = self.input.events.lock();
+        if queue.is_empty() {
+            // The clients signal the event with the lock held, so no event can be missed.
+            if let Err(e) = self.input.event.reset() {
+                error!("failed to reset vnc input event: {}", e);
+            }
+            return false;
+        }
+        true
+    }
+
+    fn next_event(&mut self) -> GpuDisplayResult<u64> {
+        let (surface, events) = self
+            .input
+            .events
+            .lock()
+            .pop_front()
+            .ok_or(GpuDisplayError::Unsupported)?;
+        self.current_event = Some(events);
+        Ok(surface)
+    }
+
+    fn handle_next_event(
+        &mut self,
+        _surface: &mut Box<dyn GpuDisplaySurface>,
+    ) -> Option<GpuDisplayEvents> {
+        self.current_event.take()
+    }
+
+    fn create_surface(
+        &mut self,
+        parent_surface_id: Option<u32>,
+        surface_id: u32,
+        width: u32,
+        height: u32,
+        _surf_type: SurfaceType,
+    ) -> GpuDisplayResult<Box<dyn GpuDisplaySurface>> {
+        if parent_surface_id.is_some() {
+            return Err(GpuDisplayError::Unsupported);
+        }
+
+        for scanout in &self.scanouts {
+            let mut state = scanout.lock();
+            if state.surface.is_some() {
+                continue;
+            }
+            let size = (width as usize) * (height as usize) * 4;
+            state.width = width;
+            state.height = height;
+            state.pixels = vec![0; size];
+            state.surface = Some(surface_id as u64);
+            state.notify_clients();
+            return Ok(Box::new(VncSurface {
+                surface_id,
+                width,
+                buffer: vec![0; size],
+                scanout: scanout.clone(),
+            }));
+        }
+        error!("no vnc listener left for a new display");
+        Err(GpuDisplayError::CreateSurface)
+    }
+}
+
+impl SysDisplayT for DisplayVnc {}
+
+impl AsRawDescriptor for DisplayVnc {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.input.event.as_raw_descriptor()
+    }
+}
+
+#[derive(EventToken)]
+enum ServerToken {
+    Listener,
+    Kill,
+}
+
+// Accepts the clients of a listening socket.
+struct Server {
+    index: usize,
+    listener: VncListener,
+    scanout: Arc<Mutex<Scanout>>,
+    input: Arc<InputQueue>,
+    kill_evt: Event,
+}
+
+impl Server {
+    fn run(self) {
+        let mut clients = Vec::new();
+        if let Err(e) = self.serve(&mut clients) {
+            error!("vnc server of display {} failed: {:#}", self.index, e);
+        }
+        for client in clients {
+            if client.join().is_err() {
+                error!("vnc client thread panicked");
+            }
+        }
+    }
+
+    fn serve(&self, clients: &mut Vec<thread::JoinHandle<()>>) -> anyhow::Result<()> {
+        let wait_ctx = WaitContext::build_with(&[
+            (&self.listener, ServerToken::Listener),
+            (&self.kill_evt, ServerToken::Kill),
+        ])
+        .context("failed to create wait context")?;
+
+        let mut next_client_id = 0;
+        loop {
+            let events = wait_ctx.wait().context("failed to wait for events")?;
+            for event in events.iter().filter(|e| e.is_readable) {
+                match event.token {
+                    ServerToken::Listener => {
+                        next_client_id += 1;
+                        match self.accept(next_client_id) {
+                            Ok(client) => clients.push(client),
+                            Err(e) => warn!("failed to accept a vnc client: {}", e),
+                        }
+                    }
+                    ServerToken::Kill => return Ok(()),
+                }
+            }
+            clients.retain(|client| !client.is_finished());
+        }
+    }
+
+    fn accept(&self, id: u64) -> io::Result<thread::JoinHandle<()>> {
+        match &self.listener {
+            VncListener::Tcp(listener) => {
+                let (stream, address) = listener.accept()?;
+                info!("vnc client {} connected to display {}", address, self.index);
+                stream.set_nodelay(true)?;
+                stream.set_read_timeout(Some(CLIENT_IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(CLIENT_IO_TIMEOUT))?;
+                self.spawn_client(stream, id)
+            }
+            VncListener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                info!("vnc client connected to display {}", self.index);
+                stream.set_read_timeout(Some(CLIENT_IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(CLIENT_IO_TIMEOUT))?;
+                self.spawn_client(stream, id)
+            }
+        }
+    }
+
+    fn spawn_client<S>(&self, stream: S, id: u64) -> io::Result<thread::JoinHandle<()>>
+    where
+        S: Read + Write + AsRawDescriptor + Send + 'static,
+    {
+        let event_error = |_| io::Error::new(io::ErrorKind::Other, "failed to create event");
+        let client = Client {
+            stream,
+            id,
+            scanout: self.scanout.clone(),
+            input: self.input.clone(),
+            frame_evt: Event::new().map_err(event_error)?,
+            kill_evt: self.kill_evt.try_clone().map_err(event_error)?,
+            encoder: Encoder::new(),
+            desktop_size: false,
+            width: 0,
+            height: 0,
+            pixels: Vec::new(),
+            frame: 0,
+            update_requested: false,
+            full_update: true,
+            buttons: 0,
+            tracking_id: -1,
+        };
+        thread::Builder::new()
+            .name(format!("vnc_client_{}_{}", self.index, id))
+            .spawn(move || client.run())
+    }
+}
+
+#[derive(EventToken)]
+enum ClientToken {
+    Socket,
+    Frame,
+    Kill,
+}
+
+struct Client<S> {
+    stream: S,
+    id: u64,
+    scanout: Arc<Mutex<Scanout>>,
+    input: Arc<InputQueue>,
+    frame_evt: Event,
+    kill_evt: Event,
+    encoder: Encoder,
+    // Whether the client accepts changes of the framebuffer size.
+    desktop_size: bool,
+    // The framebuffer as last sent to the client.
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+    frame: u64,
+    // Set when the client asked for an update that wasn't sent yet.
+    update_requested: bool,
+    // Set when the whole framebuffer must be sent rather than the areas that changed.
+    full_update: bool,
+    buttons: u8,
+    tracking_id: i32,
+}
+
+impl<S: Read + Write + AsRawDescriptor> Client<S> {
+    fn run(mut self) {
+        match self.serve() {
+            Ok(()) => info!("vnc client {} disconnected", self.id),
+            Err(e) => warn!("vnc client {} disconnected: {:#}", self.id, e),
+        }
+        self.scanout.lock().clients.remove(&self.id);
+    }
+
+    fn serve(&mut self) -> anyhow::Result<()> {
+        let (width, height) = {
+            let scanout = self.scanout.lock();
+            (scanout.width, scanout.height)
+        };
+        rfb::handshake(&mut self.stream, width as u16, height as u16, DESKTOP_NAME)
+            .context("handshake failed")?;
+        self.width = width;
+        self.height = height;
+        self.scanout.lock().clients.insert(
+            self.id,
+            self.frame_evt
+                .try_clone()
+                .context("failed to clone frame event")?,
+        );
+
+        let wait_ctx = WaitContext::build_with(&[
+            (&self.stream, ClientToken::Socket),
+            (&self.frame_evt, ClientToken::Frame),
+            (&self.kill_evt, ClientToken::Kill),
+        ])
+        .context("failed to create wait context")?;
+
+        loop {
+            let events = wait_ctx.wait().context("failed to wait for events")?;
+            for event in events.iter() {
+                match event.token {
+                    ClientToken::Socket => {
+                        if !event.is_readable {
+                            return Ok(());
+                        }
+                        match rfb::read_client_message(&mut self.stream) {
+                            Ok(message) => self.handle_message(message),
+                            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                            Err(e) => return Err(e).context("failed to read message"),
+                        }
+                    }
+                    ClientToken::Frame => {
+                        self.frame_evt
+                            .reset()
+                            .context("failed to reset frame event")?;
+                    }
+                    ClientToken::Kill => return Ok(()),
+                }
+            }
+            if self.update_requested {
+                self.send_update()?;
+            }
+        }
+    }
+
+    fn handle_message(&mut self, message: ClientMessage) {
+        match message {
+            ClientMessage::SetPixelFormat(format) => {
+                self.encoder.set_pixel_format(format);
+                self.full_update = true;
+            }
+            ClientMessage::SetEncodings(encodings) => {
+                self.encoder.set_encodings(&encodings);
+                self.desktop_size = encodings.contains(&ENCODING_DESKTOP_SIZE);
+            }
+            // The area requested is ignored, all the areas that changed are sent.
+            ClientMessage::FramebufferUpdateRequest { incremental, .. } => {
+                self.update_requested = true;
+                self.full_update |= !incremental;
+            }
+            ClientMessage::KeyEvent { down, keysym } => {
+                if let Some(code) = keysym_to_linux(keysym) {
+                    self.send_input(GpuDisplayEvents {
+                        events: vec![virtio_input_event::key(code, down, false)],
+                        device_type: EventDeviceKind::Keyboard,
+                    });
+                }
+            }
+            ClientMessage::PointerEvent { buttons, x, y } => self.handle_pointer(buttons, x, y),
+            ClientMessage::ClientCutText => {}
+        }
+    }
+
+    // Sends the presses of the left button and the motion while it is pressed as a single touch,
+    // like the other display backends.
+    fn handle_pointer(&mut self, buttons: u8, x: u16, y: u16) {
+        let was_pressed = self.buttons & BUTTON_LEFT != 0;
+        let pressed = buttons & BUTTON_LEFT != 0;
+        self.buttons = buttons;
+
+        // The touch event *must* be first per the Linux input subsystem's guidance.
+        let mut events = vec![virtio_input_event::multitouch_slot(0)];
+        match (was_pressed, pressed) {
+            (false, false) => return,
+            (true, false) => {
+                self.tracking_id = -1;
+                events.push(virtio_input_event::multitouch_tracking_id(-1));
+            }
+            (_, true) => {
+                if !was_pressed {
+                    self.tracking_id = self.input.next_tracking_id.fetch_add(1, Ordering::Relaxed);
+                }
+                events.push(virtio_input_event::multitouch_tracking_id(self.tracking_id));
+                events.push(virtio_input_event::multitouch_absolute_x(x as i32));
+                events.push(virtio_input_event::multitouch_absolute_y(y as i32));
+            }
+        }
+        self.send_input(GpuDisplayEvents {
+            events,
+            device_type: EventDeviceKind::Touchscreen,
+        });
+    }
+
+    fn send_input(&self, events: GpuDisplayEvents) {
+        // Events are dropped while no surface is shown.
+        let surface = self.scanout.lock().surface;
+        if let Some(surface) = surface {
+            self.input.push(surface, events);
+        }
+    }
+
+    fn send_update(&mut self) -> anyhow::Result<()> {
+        let (width, height, pixels, frame) = {
+            let scanout = self.scanout.lock();
+            if scanout.frame == self.frame && !self.full_update {
+                return Ok(());
+            }
+            (
+                scanout.width,
+                scanout.height,
+                scanout.pixels.clone(),
+                scanout.frame,
+            )
+        };
+
+        let mut rects = Vec::new();
+        let mut num_rects = 0;
+        if width != self.width || height != self.height {
+            if !self.desktop_size {
+                bail!("the client doesn't support changes of the display size");
+            }
+            rfb::write_rect_header(&mut rects, 0, 0, width, height, ENCODING_DESKTOP_SIZE);
+            num_rects += 1;
+            self.width = width;
+            self.height = height;
+            self.full_update = true;
+        }
+
+        let areas = if self.full_update {
+            vec![(0, 0, width, height)]
+        } else {
+            changed_areas(&self.pixels, &pixels, width, height)
+        };
+        let frame_pixels = Frame {
+            pixels: &pixels,
+            width,
+        };
+        for (x, y, area_width, area_height) in areas {
+            num_rects += self
+                .encoder
+                .encode(&frame_pixels, x, y, area_width, area_height, &mut rects)
+                .context("failed to encode update")?;
+        }
+        self.pixels = pixels;
+        self.frame = frame;
+        if num_rects == 0 {
+            // Nothing changed, the request is answered with the next frame.
+            return Ok(());
+        }
+
+        let mut update = Vec::with_capacity(4 + rects.len());
+        rfb::write_update_header(
+            &mut update,
+            num_rects
+                .try_into()
+                .context("too many rectangles in update")?,
+        );
+        update.extend_from_slice(&rects);
+        self.stream
+            .write_all(&update)
+            .context("failed to send update")?;
+        self.update_requested = false;
+        self.full_update = false;
+        Ok(())
+    }
+}
+
+// Returns the areas of `new` that differ from `old`, as the runs of changed tiles of each row of
+// tiles.
+fn changed_areas(old: &[u8], new: &[u8], width: u32, height: u32) -> Vec<(u32, u32, u32, u32)> {
+    let mut areas = Vec::new();
+    for y in (0..height).step_by(UPDATE_TILE_SIZE as usize) {
+        let tile_height = min(UPDATE_TILE_SIZE, height - y);
+        let mut run_start = None;
+        for x in (0..width).step_by(UPDATE_TILE_SIZE as usize) {
+            let tile_width = min(UPDATE_TILE_SIZE, width - x);
+            let changed = (y..y + tile_height).any(|row| {
+                let start = ((row * width + x) * 4) as usize;
+                let end = start + (tile_width * 4) as usize;
+                old[start..end] != new[start..end]
+            });
+            match (changed, run_start) {
+                (true, None) => run_start = Some(x),
+                (false, Some(start)) => {
+                    areas.push((start, y, x - start, tile_height));
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = run_start {
+            areas.push((start, y, width - start, tile_height));
+        }
+    }
+    areas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed_tiles() {
+        let width = 200;
+        let height = 100;
+        let old = vec![0u8; (width * height * 4) as usize];
+        let mut new = old.clone();
+        // Changes the first and second tiles of the first row of tiles and the last tile of the
+        // second row.
+        new[(10 * width + 10) as usize * 4] = 1;
+        new[(63 * width + 64) as usize * 4] = 1;
+        new[(99 * width + 199) as usize * 4] = 1;
+
+        assert_eq!(
+            changed_areas(&old, &new, width, height),
+            vec![(0, 0, 128, 64), (192, 64, 8, 36)]
+        );
+        assert!(changed_areas(&old, &old, width, height).is_empty());
+    }
+}
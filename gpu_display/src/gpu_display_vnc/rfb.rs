@@ -0,0 +1,467 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Messages of the RFB protocol spoken by VNC clients, see https://github.com/rfbproto/rfbproto.
+//! All the fields are big-endian.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+const SERVER_VERSION: &[u8; 12] = b"RFB 003.008\n";
+
+const SECURITY_TYPE_NONE: u8 = 1;
+
+const CLIENT_SET_PIXEL_FORMAT: u8 = 0;
+const CLIENT_SET_ENCODINGS: u8 = 2;
+const CLIENT_FRAMEBUFFER_UPDATE_REQUEST: u8 = 3;
+const CLIENT_KEY_EVENT: u8 = 4;
+const CLIENT_POINTER_EVENT: u8 = 5;
+const CLIENT_CUT_TEXT: u8 = 6;
+
+const SERVER_FRAMEBUFFER_UPDATE: u8 = 0;
+
+pub const ENCODING_RAW: i32 = 0;
+pub const ENCODING_TIGHT: i32 = 7;
+pub const ENCODING_ZRLE: i32 = 16;
+/// Pseudo-encoding announcing that the client accepts changes of the framebuffer size.
+pub const ENCODING_DESKTOP_SIZE: i32 = -223;
+
+/// How a client wants the pixels it receives to be laid out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelFormat {
+    pub bits_per_pixel: u8,
+    pub depth: u8,
+    pub big_endian: bool,
+    pub red_max: u16,
+    pub green_max: u16,
+    pub blue_max: u16,
+    pub red_shift: u8,
+    pub green_shift: u8,
+    pub blue_shift: u8,
+}
+
+impl PixelFormat {
+    /// The format of the surfaces, XRGB8888 stored little-endian. It is the one advertised to the
+    /// clients until they ask for another.
+    pub const XRGB8888: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+
+    /// Parses the PIXEL_FORMAT structure of a SetPixelFormat message. Only true-colour formats of
+    /// 8, 16 or 32 bits per pixel are supported.
+    pub fn from_bytes(bytes: &[u8; 16]) -> io::Result<PixelFormat> {
+        let format = PixelFormat {
+            bits_per_pixel: bytes[0],
+            depth: bytes[1],
+            big_endian: bytes[2] != 0,
+            red_max: u16::from_be_bytes([bytes[4], bytes[5]]),
+            green_max: u16::from_be_bytes([bytes[6], bytes[7]]),
+            blue_max: u16::from_be_bytes([bytes[8], bytes[9]]),
+            red_shift: bytes[10],
+            green_shift: bytes[11],
+            blue_shift: bytes[12],
+        };
+        if bytes[3] == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "colour map pixel formats are not supported",
+            ));
+        }
+        if !matches!(format.bits_per_pixel, 8 | 16 | 32)
+            || format.red_shift >= 32
+            || format.green_shift >= 32
+            || format.blue_shift >= 32
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid pixel format",
+            ));
+        }
+        Ok(format)
+    }
+
+    pub fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[0] = self.bits_per_pixel;
+        bytes[1] = self.depth;
+        bytes[2] = self.big_endian as u8;
+        bytes[3] = 1;
+        bytes[4..6].copy_from_slice(&self.red_max.to_be_bytes());
+        bytes[6..8].copy_from_slice(&self.green_max.to_be_bytes());
+        bytes[8..10].copy_from_slice(&self.blue_max.to_be_bytes());
+        bytes[10] = self.red_shift;
+        bytes[11] = self.green_shift;
+        bytes[12] = self.blue_shift;
+        bytes
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.bits_per_pixel as usize / 8
+    }
+
+    /// Converts an XRGB8888 pixel to this format.
+    pub fn convert(&self, xrgb: u32) -> u32 {
+        let scale = |value: u32, max: u16| (value * max as u32 + 127) / 255;
+        let red = scale((xrgb >> 16) & 0xff, self.red_max);
+        let green = scale((xrgb >> 8) & 0xff, self.green_max);
+        let blue = scale(xrgb & 0xff, self.blue_max);
+        (red << self.red_shift) | (green << self.green_shift) | (blue << self.blue_shift)
+    }
+
+    /// Appends `pixel`, already converted to this format, to `out`.
+    pub fn write_pixel(&self, pixel: u32, out: &mut Vec<u8>) {
+        match (self.bits_per_pixel, self.big_endian) {
+            (8, _) => out.push(pixel as u8),
+            (16, false) => out.extend_from_slice(&(pixel as u16).to_le_bytes()),
+            (16, true) => out.extend_from_slice(&(pixel as u16).to_be_bytes()),
+            (_, false) => out.extend_from_slice(&pixel.to_le_bytes()),
+            (_, true) => out.extend_from_slice(&pixel.to_be_bytes()),
+        }
+    }
+
+    // Returns whether the colour bits of the 32 bits pixels fit in their least significant bytes,
+    // or `None` if they don't fit in 3 bytes at all.
+    fn compact_in_low_bytes(&self) -> Option<bool> {
+        if self.bits_per_pixel != 32 || self.depth > 24 {
+            return None;
+        }
+        let mask = ((self.red_max as u64) << self.red_shift)
+            | ((self.green_max as u64) << self.green_shift)
+            | ((self.blue_max as u64) << self.blue_shift);
+        if mask < 1 << 24 {
+            Some(true)
+        } else if mask & 0xff == 0 && mask < 1 << 32 {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the size of the compact pixels (CPIXEL) of this format used by ZRLE.
+    pub fn compact_bytes_per_pixel(&self) -> usize {
+        match self.compact_in_low_bytes() {
+            Some(_) => 3,
+            None => self.bytes_per_pixel(),
+        }
+    }
+
+    /// Appends `pixel`, already converted to this format, to `out` as a compact pixel: 32 bits
+    /// pixels whose colour fits in 3 bytes are sent without their unused byte.
+    pub fn write_compact_pixel(&self, pixel: u32, out: &mut Vec<u8>) {
+        match (self.compact_in_low_bytes(), self.big_endian) {
+            (Some(true), false) => out.extend_from_slice(&pixel.to_le_bytes()[0..3]),
+            (Some(true), true) => out.extend_from_slice(&pixel.to_be_bytes()[1..4]),
+            (Some(false), false) => out.extend_from_slice(&pixel.to_le_bytes()[1..4]),
+            (Some(false), true) => out.extend_from_slice(&pixel.to_be_bytes()[0..3]),
+            (None, _) => self.write_pixel(pixel, out),
+        }
+    }
+
+    /// Appends the XRGB8888 `xrgb` pixel to `out` as a pixel of the Tight encoding (TPIXEL). They
+    /// are sent as red, green and blue bytes when the client asked for 24 bits of colour in 32 bits
+    /// pixels.
+    pub fn write_tight_pixel(&self, xrgb: u32, out: &mut Vec<u8>) {
+        if self.bits_per_pixel == 32
+            && self.depth == 24
+            && self.red_max == 255
+            && self.green_max == 255
+            && self.blue_max == 255
+        {
+            out.extend_from_slice(&[(xrgb >> 16) as u8, (xrgb >> 8) as u8, xrgb as u8]);
+        } else {
+            self.write_pixel(self.convert(xrgb), out);
+        }
+    }
+}
+
+/// A message sent by a client.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientMessage {
+    SetPixelFormat(PixelFormat),
+    SetEncodings(Vec<i32>),
+    FramebufferUpdateRequest {
+        incremental: bool,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    },
+    KeyEvent {
+        down: bool,
+        keysym: u32,
+    },
+    PointerEvent {
+        buttons: u8,
+        x: u16,
+        y: u16,
+    },
+    /// The client clipboard changed. Its text is discarded.
+    ClientCutText,
+}
+
+/// Exchanges the protocol version, security type and initialization messages with a client that
+/// just connected. No authentication is done, the listening socket must be protected instead.
+pub fn handshake<S: Read + Write>(
+    stream: &mut S,
+    width: u16,
+    height: u16,
+    name: &str,
+) -> io::Result<()> {
+    stream.write_all(SERVER_VERSION)?;
+    let mut version = [0u8; 12];
+    stream.read_exact(&mut version)?;
+    if &version[0..4] != b"RFB " || version[7] != b'.' || version[11] != b'\n' {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid protocol version",
+        ));
+    }
+    let minor = std::str::from_utf8(&version[8..11])
+        .ok()
+        .and_then(|minor| minor.parse::<u32>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid protocol version"))?;
+
+    if minor < 7 {
+        // Version 3.3 lets the server pick the security type.
+        stream.write_all(&(SECURITY_TYPE_NONE as u32).to_be_bytes())?;
+    } else {
+        stream.write_all(&[1, SECURITY_TYPE_NONE])?;
+        let mut security_type = [0u8; 1];
+        stream.read_exact(&mut security_type)?;
+        if security_type[0] != SECURITY_TYPE_NONE {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported security type {}", security_type[0]),
+            ));
+        }
+        // Version 3.7 has no SecurityResult message when there is no authentication.
+        if minor >= 8 {
+            stream.write_all(&0u32.to_be_bytes())?;
+        }
+    }
+
+    // The shared flag of ClientInit is ignored, all the clients share the display.
+    let mut client_init = [0u8; 1];
+    stream.read_exact(&mut client_init)?;
+
+    let mut server_init = Vec::with_capacity(24 + name.len());
+    server_init.extend_from_slice(&width.to_be_bytes());
+    server_init.extend_from_slice(&height.to_be_bytes());
+    server_init.extend_from_slice(&PixelFormat::XRGB8888.to_bytes());
+    server_init.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    server_init.extend_from_slice(name.as_bytes());
+    stream.write_all(&server_init)
+}
+
+/// Reads the next message of a client.
+pub fn read_client_message<R: Read>(stream: &mut R) -> io::Result<ClientMessage> {
+    let mut message_type = [0u8; 1];
+    stream.read_exact(&mut message_type)?;
+    match message_type[0] {
+        CLIENT_SET_PIXEL_FORMAT => {
+            let mut message = [0u8; 19];
+            stream.read_exact(&mut message)?;
+            Ok(ClientMessage::SetPixelFormat(PixelFormat::from_bytes(
+                message[3..19].try_into().unwrap(),
+            )?))
+        }
+        CLIENT_SET_ENCODINGS => {
+            let mut header = [0u8; 3];
+            stream.read_exact(&mut header)?;
+            let count = u16::from_be_bytes([header[1], header[2]]) as usize;
+            let mut encodings = vec![0u8; count * 4];
+            stream.read_exact(&mut encodings)?;
+            Ok(ClientMessage::SetEncodings(
+                encodings
+                    .chunks_exact(4)
+                    .map(|encoding| i32::from_be_bytes(encoding.try_into().unwrap()))
+                    .collect(),
+            ))
+        }
+        CLIENT_FRAMEBUFFER_UPDATE_REQUEST => {
+            let mut message = [0u8; 9];
+            stream.read_exact(&mut message)?;
+            Ok(ClientMessage::FramebufferUpdateRequest {
+                incremental: message[0] != 0,
+                x: u16::from_be_bytes([message[1], message[2]]),
+                y: u16::from_be_bytes([message[3], message[4]]),
+                width: u16::from_be_bytes([message[5], message[6]]),
+                height: u16::from_be_bytes([message[7], message[8]]),
+            })
+        }
+        CLIENT_KEY_EVENT => {
+            let mut message = [0u8; 7];
+            stream.read_exact(&mut message)?;
+            Ok(ClientMessage::KeyEvent {
+                down: message[0] != 0,
+                keysym: u32::from_be_bytes(message[3..7].try_into().unwrap()),
+            })
+        }
+        CLIENT_POINTER_EVENT => {
+            let mut message = [0u8; 5];
+            stream.read_exact(&mut message)?;
+            Ok(ClientMessage::PointerEvent {
+                buttons: message[0],
+                x: u16::from_be_bytes([message[1], message[2]]),
+                y: u16::from_be_bytes([message[3], message[4]]),
+            })
+        }
+        CLIENT_CUT_TEXT => {
+            let mut message = [0u8; 7];
+            stream.read_exact(&mut message)?;
+            // A negative length is used by the extended clipboard pseudo-encoding.
+            let length = i32::from_be_bytes(message[3..7].try_into().unwrap()).unsigned_abs();
+            io::copy(&mut stream.take(length as u64), &mut io::sink())?;
+            Ok(ClientMessage::ClientCutText)
+        }
+        message_type => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown client message type {}", message_type),
+        )),
+    }
+}
+
+/// Appends the header of a FramebufferUpdate message made of `num_rects` rectangles to `out`.
+pub fn write_update_header(out: &mut Vec<u8>, num_rects: u16) {
+    out.extend_from_slice(&[SERVER_FRAMEBUFFER_UPDATE, 0]);
+    out.extend_from_slice(&num_rects.to_be_bytes());
+}
+
+/// Appends the header of a rectangle of a FramebufferUpdate message to `out`.
+pub fn write_rect_header(
+    out: &mut Vec<u8>,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    encoding: i32,
+) {
+    out.extend_from_slice(&(x as u16).to_be_bytes());
+    out.extend_from_slice(&(y as u16).to_be_bytes());
+    out.extend_from_slice(&(width as u16).to_be_bytes());
+    out.extend_from_slice(&(height as u16).to_be_bytes());
+    out.extend_from_slice(&encoding.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    // A stream reading canned client messages and recording what is written to it.
+    struct FakeStream {
+        input: Cursor<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl Read for FakeStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for FakeStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn handshake_3_8() {
+        let mut input = b"RFB 003.008\n".to_vec();
+        input.extend_from_slice(&[SECURITY_TYPE_NONE, 1]);
+        let mut stream = FakeStream {
+            input: Cursor::new(input),
+            written: Vec::new(),
+        };
+
+        handshake(&mut stream, 1024, 768, "crosvm").unwrap();
+        let mut expected = b"RFB 003.008\n".to_vec();
+        expected.extend_from_slice(&[1, SECURITY_TYPE_NONE, 0, 0, 0, 0, 0x04, 0x00, 0x03, 0x00]);
+        expected.extend_from_slice(&PixelFormat::XRGB8888.to_bytes());
+        expected.extend_from_slice(&[0, 0, 0, 6]);
+        expected.extend_from_slice(b"crosvm");
+        assert_eq!(stream.written, expected);
+    }
+
+    #[test]
+    fn handshake_3_3() {
+        let mut stream = FakeStream {
+            input: Cursor::new(b"RFB 003.003\n\x00".to_vec()),
+            written: Vec::new(),
+        };
+
+        handshake(&mut stream, 640, 480, "").unwrap();
+        assert_eq!(&stream.written[12..16], &[0, 0, 0, SECURITY_TYPE_NONE]);
+        assert_eq!(&stream.written[16..20], &[0x02, 0x80, 0x01, 0xe0]);
+    }
+
+    #[test]
+    fn client_messages() {
+        let mut input = vec![CLIENT_SET_ENCODINGS, 0, 0, 2];
+        input.extend_from_slice(&ENCODING_ZRLE.to_be_bytes());
+        input.extend_from_slice(&ENCODING_DESKTOP_SIZE.to_be_bytes());
+        input.extend_from_slice(&[CLIENT_KEY_EVENT, 1, 0, 0, 0x00, 0x00, 0xff, 0x0d]);
+        input.extend_from_slice(&[CLIENT_CUT_TEXT, 0, 0, 0, 0, 0, 0, 3, b'a', b'b', b'c']);
+        input.extend_from_slice(&[CLIENT_POINTER_EVENT, 1, 0, 10, 0, 20]);
+        let mut input = Cursor::new(input);
+
+        assert_eq!(
+            read_client_message(&mut input).unwrap(),
+            ClientMessage::SetEncodings(vec![ENCODING_ZRLE, ENCODING_DESKTOP_SIZE])
+        );
+        assert_eq!(
+            read_client_message(&mut input).unwrap(),
+            ClientMessage::KeyEvent {
+                down: true,
+                keysym: 0xff0d
+            }
+        );
+        assert_eq!(
+            read_client_message(&mut input).unwrap(),
+            ClientMessage::ClientCutText
+        );
+        assert_eq!(
+            read_client_message(&mut input).unwrap(),
+            ClientMessage::PointerEvent {
+                buttons: 1,
+                x: 10,
+                y: 20
+            }
+        );
+    }
+
+    #[test]
+    fn convert_rgb565() {
+        let format = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian: false,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+        };
+        assert_eq!(format.convert(0x00ff_ffff), 0xffff);
+        assert_eq!(format.convert(0x00ff_0000), 0xf800);
+        assert_eq!(format.compact_bytes_per_pixel(), 2);
+        assert_eq!(PixelFormat::XRGB8888.compact_bytes_per_pixel(), 3);
+    }
+}
@@ -22,6 +22,8 @@ use thiserror::Error;
 
 mod event_device;
 mod gpu_display_stub;
+#[cfg(all(feature = "vnc", any(target_os = "android", target_os = "linux")))]
+mod gpu_display_vnc;
 #[cfg(windows)]
 mod gpu_display_win;
 #[cfg(any(target_os = "android", target_os = "linux"))]
@@ -34,6 +36,8 @@ mod sys;
 
 pub use event_device::EventDevice;
 pub use event_device::EventDeviceKind;
+#[cfg(all(feature = "vnc", any(target_os = "android", target_os = "linux")))]
+pub use gpu_display_vnc::VncListener;
 #[cfg(windows)]
 pub use gpu_display_win::DisplayProperties as WinDisplayProperties;
 #[cfg(windows)]
@@ -359,6 +363,24 @@ impl GpuDisplay {
         Err(GpuDisplayError::Unsupported)
     }
 
+    /// Serves the display to VNC clients, each of `listeners` showing one scanout.
+    #[cfg(all(feature = "vnc", any(target_os = "android", target_os = "linux")))]
+    pub fn open_vnc(listeners: &[VncListener]) -> GpuDisplayResult<GpuDisplay> {
+        let display = gpu_display_vnc::DisplayVnc::new(listeners)?;
+
+        let wait_ctx = WaitContext::new()?;
+        wait_ctx.add(&display, DisplayEventToken::Display)?;
+
+        Ok(GpuDisplay {
+            inner: Box::new(display),
+            next_id: 1,
+            event_devices: Default::default(),
+            surfaces: Default::default(),
+            imports: Default::default(),
+            wait_ctx,
+        })
+    }
+
     pub fn open_stub() -> GpuDisplayResult<GpuDisplay> {
         let display = gpu_display_stub::DisplayStub::new()?;
         let wait_ctx = WaitContext::new()?;
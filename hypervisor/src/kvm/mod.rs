@@ -225,6 +225,8 @@ pub struct KvmVm {
     mem_regions: Arc<Mutex<BTreeMap<MemSlot, Box<dyn MappedRegion>>>>,
     /// A min heap of MemSlot numbers that were used and then removed and can now be re-used
     mem_slot_gaps: Arc<Mutex<BinaryHeap<Reverse<MemSlot>>>>,
+    /// Whether the slots of `guest_mem` log dirty pages.
+    guest_mem_dirty_log: Arc<Mutex<bool>>,
 }
 
 impl KvmVm {
@@ -268,11 +270,56 @@ impl KvmVm {
             guest_mem,
             mem_regions: Arc::new(Mutex::new(BTreeMap::new())),
             mem_slot_gaps: Arc::new(Mutex::new(BinaryHeap::new())),
+            guest_mem_dirty_log: Arc::new(Mutex::new(false)),
         };
         vm.init_arch(&cfg)?;
         Ok(vm)
     }
 
+    // Sets whether the slots of `guest_mem` log the pages written by the guest.
+    fn set_guest_memory_dirty_log(&self, log_dirty_pages: bool) -> Result<()> {
+        let mut enabled = self.guest_mem_dirty_log.lock();
+        if *enabled == log_dirty_pages {
+            return Ok(());
+        }
+        for region in self.guest_mem.regions() {
+            // SAFETY:
+            // Safe because only the flags of the slot created in `KvmVm::new` are changed.
+            unsafe {
+                set_user_memory_region(
+                    &self.vm,
+                    region.index as MemSlot,
+                    false,
+                    log_dirty_pages,
+                    region.guest_addr.offset(),
+                    region.size as u64,
+                    region.host_addr as *mut u8,
+                )
+            }?;
+        }
+        *enabled = log_dirty_pages;
+        Ok(())
+    }
+
+    // Fetches and clears the dirty log of `slot` into `dirty_log`, which must be large enough for
+    // the slot.
+    fn get_slot_dirty_log(&self, slot: MemSlot, dirty_log: &mut [u8]) -> Result<()> {
+        let mut dirty_log_kvm = kvm_dirty_log {
+            slot,
+            ..Default::default()
+        };
+        dirty_log_kvm.__bindgen_anon_1.dirty_bitmap = dirty_log.as_ptr() as *mut c_void;
+        // SAFETY:
+        // Safe because the `dirty_bitmap` pointer assigned above is guaranteed to be valid (because
+        // it's from a slice) and we checked that it will be large enough to hold the entire log.
+        let ret = unsafe { ioctl_with_ref(self, KVM_GET_DIRTY_LOG(), &dirty_log_kvm) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
+
     pub fn create_kvm_vcpu(&self, id: usize) -> Result<KvmVcpu> {
         let run_mmap_size = self.kvm.get_vcpu_mmap_size()?;
 
@@ -548,6 +595,7 @@ impl Vm for KvmVm {
             guest_mem: self.guest_mem.clone(),
             mem_regions: self.mem_regions.clone(),
             mem_slot_gaps: self.mem_slot_gaps.clone(),
+            guest_mem_dirty_log: self.guest_mem_dirty_log.clone(),
         })
     }
 
@@ -706,21 +754,29 @@ impl Vm for KvmVm {
         if dirty_log_bitmap_size(mmap.size()) > dirty_log.len() {
             return Err(Error::new(EINVAL));
         }
+        self.get_slot_dirty_log(slot, dirty_log)
+    }
 
-        let mut dirty_log_kvm = kvm_dirty_log {
-            slot,
-            ..Default::default()
-        };
-        dirty_log_kvm.__bindgen_anon_1.dirty_bitmap = dirty_log.as_ptr() as *mut c_void;
-        // SAFETY:
-        // Safe because the `dirty_bitmap` pointer assigned above is guaranteed to be valid (because
-        // it's from a slice) and we checked that it will be large enough to hold the entire log.
-        let ret = unsafe { ioctl_with_ref(self, KVM_GET_DIRTY_LOG(), &dirty_log_kvm) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            errno_result()
+    fn enable_guest_memory_dirty_log(&self) -> Result<()> {
+        self.set_guest_memory_dirty_log(true)
+    }
+
+    fn disable_guest_memory_dirty_log(&self) -> Result<()> {
+        self.set_guest_memory_dirty_log(false)
+    }
+
+    fn get_guest_memory_dirty_log(&self) -> Result<Vec<Vec<u8>>> {
+        if !*self.guest_mem_dirty_log.lock() {
+            return Err(Error::new(ENOENT));
         }
+        self.guest_mem
+            .regions()
+            .map(|region| {
+                let mut dirty_log = vec![0u8; dirty_log_bitmap_size(region.size)];
+                self.get_slot_dirty_log(region.index as MemSlot, &mut dirty_log)?;
+                Ok(dirty_log)
+            })
+            .collect()
     }
 
     fn register_ioevent(
@@ -6,6 +6,7 @@ use base::errno_result;
 use base::error;
 use base::ioctl_with_ref;
 use base::Error;
+use base::MappedRegion;
 use base::Result;
 use kvm_sys::*;
 use libc::ENXIO;
@@ -18,6 +19,7 @@ use crate::ClockState;
 use crate::DeviceKind;
 use crate::Hypervisor;
 use crate::IrqSourceChip;
+use crate::IsaExtension;
 use crate::ProtectionType;
 use crate::VcpuExit;
 use crate::VcpuRegister;
@@ -135,6 +137,38 @@ impl VcpuRiscv64 for KvmVcpu {
             errno_result()
         }
     }
+
+    fn handle_sbi(&self, error: i64, value: u64) -> Result<()> {
+        // SAFETY:
+        // Safe because we know we mapped enough memory to hold the kvm_run struct because the
+        // kernel told us how large it was.
+        let run = unsafe { &mut *(self.run_mmap.as_ptr() as *mut kvm_run) };
+        // Verify that the handler is called in the right context.
+        assert!(run.exit_reason == KVM_EXIT_RISCV_SBI);
+        // SAFETY:
+        // Safe because the exit_reason (which comes from the kernel) told us which
+        // union field to use.
+        let sbi = unsafe { &mut run.__bindgen_anon_1.riscv_sbi };
+        sbi.ret = [error as u64, value];
+        Ok(())
+    }
+
+    #[cfg(feature = "gdb")]
+    fn set_guest_debug(&self) -> Result<()> {
+        let dbg = kvm_guest_debug {
+            control: KVM_GUESTDBG_ENABLE,
+            ..Default::default()
+        };
+
+        // SAFETY:
+        // Safe because the kernel won't read past the end of the kvm_guest_debug struct.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_GUEST_DEBUG(), &dbg) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
 }
 
 // Returns the id used for call to `KVM_[GET|SET]_ONE_REG`.
@@ -144,12 +178,45 @@ fn vcpu_reg_id(reg: VcpuRegister) -> u64 {
     }
 
     match reg {
+        VcpuRegister::AiaCsr(r) => id_from_reg(KVM_REG_RISCV_CSR | KVM_REG_RISCV_CSR_AIA, r as u64),
         VcpuRegister::Config(r) => id_from_reg(KVM_REG_RISCV_CONFIG, r as u64),
         VcpuRegister::Core(r) => id_from_reg(KVM_REG_RISCV_CORE, r as u64),
+        VcpuRegister::Csr(r) => {
+            id_from_reg(KVM_REG_RISCV_CSR | KVM_REG_RISCV_CSR_GENERAL, r as u64)
+        }
+        VcpuRegister::FpD(n) => id_from_reg(KVM_REG_RISCV_FP_D, n as u64),
+        // fcsr follows the 32 registers and is only 32 bits wide. The kernel accesses the low half
+        // of the 64 bits value used by `[get|set]_one_reg`, since riscv64 is little-endian.
+        VcpuRegister::Fcsr => {
+            KVM_REG_RISCV_FP_D as u64 | 32 | KVM_REG_RISCV as u64 | KVM_REG_SIZE_U32
+        }
+        VcpuRegister::IsaExt(ext) => id_from_reg(KVM_REG_RISCV_ISA_EXT, isa_ext_id(ext)),
         VcpuRegister::Timer(r) => id_from_reg(KVM_REG_RISCV_TIMER, r as u64),
     }
 }
 
+// Returns the id of `ext` in the ISA extension registers of kvm.
+fn isa_ext_id(ext: IsaExtension) -> u64 {
+    let id = match ext {
+        IsaExtension::A => KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_A,
+        IsaExtension::C => KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_C,
+        IsaExtension::D => KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_D,
+        IsaExtension::F => KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_F,
+        IsaExtension::H => KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_H,
+        IsaExtension::I => KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_I,
+        IsaExtension::M => KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_M,
+        IsaExtension::V => KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_V,
+        IsaExtension::Svpbmt => KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_SVPBMT,
+        IsaExtension::Sstc => KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_SSTC,
+        IsaExtension::Svinval => KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_SVINVAL,
+        IsaExtension::Zihintpause => KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_ZIHINTPAUSE,
+        IsaExtension::Zicbom => KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_ZICBOM,
+        IsaExtension::Zbb => KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_ZBB,
+        IsaExtension::Ssaia => KVM_RISCV_ISA_EXT_ID_KVM_RISCV_ISA_EXT_SSAIA,
+    };
+    id.into()
+}
+
 // This function translates an IrqSrouceChip to the kvm u32 equivalent. It has a different
 // implementation between the architectures because the irqchip KVM constants are not defined on all
 // of them.
@@ -168,7 +235,10 @@ pub(super) fn chip_to_kvm_chip(chip: IrqSourceChip) -> u32 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::AiaCsrRegister;
     use crate::CoreRegister;
+    use crate::CsrRegister;
+    use crate::TimerRegister;
 
     #[test]
     fn reg_id() {
@@ -305,4 +375,51 @@ mod tests {
             0x8030_0000_0200_0020
         );
     }
+
+    #[test]
+    fn fp_reg_id() {
+        assert_eq!(vcpu_reg_id(VcpuRegister::FpD(0)), 0x8030_0000_0600_0000);
+        assert_eq!(vcpu_reg_id(VcpuRegister::FpD(31)), 0x8030_0000_0600_001f);
+        assert_eq!(vcpu_reg_id(VcpuRegister::Fcsr), 0x8020_0000_0600_0020);
+    }
+
+    #[test]
+    fn csr_reg_id() {
+        assert_eq!(
+            vcpu_reg_id(VcpuRegister::Csr(CsrRegister::Sstatus)),
+            0x8030_0000_0300_0000
+        );
+        assert_eq!(
+            vcpu_reg_id(VcpuRegister::Csr(CsrRegister::Scounteren)),
+            0x8030_0000_0300_0009
+        );
+        assert_eq!(
+            vcpu_reg_id(VcpuRegister::AiaCsr(AiaCsrRegister::Siselect)),
+            0x8030_0000_0301_0000
+        );
+        assert_eq!(
+            vcpu_reg_id(VcpuRegister::Timer(TimerRegister::State)),
+            0x8030_0000_0400_0003
+        );
+    }
+
+    #[test]
+    fn isa_ext_reg_id() {
+        assert_eq!(
+            vcpu_reg_id(VcpuRegister::IsaExt(IsaExtension::Sstc)),
+            0x8030_0000_0700_0008
+        );
+        assert_eq!(
+            vcpu_reg_id(VcpuRegister::IsaExt(IsaExtension::Zicbom)),
+            0x8030_0000_0700_000b
+        );
+        assert_eq!(
+            vcpu_reg_id(VcpuRegister::IsaExt(IsaExtension::Zbb)),
+            0x8030_0000_0700_000d
+        );
+        assert_eq!(
+            vcpu_reg_id(VcpuRegister::IsaExt(IsaExtension::V)),
+            0x8030_0000_0700_000f
+        );
+    }
 }
@@ -147,6 +147,26 @@ pub trait Vm: Send {
     /// be 2 bytes or greater.
     fn get_dirty_log(&self, slot: MemSlot, dirty_log: &mut [u8]) -> Result<()>;
 
+    /// Starts logging the pages of guest memory, as given to the Vm when it was created, that are
+    /// written by the guest. Does nothing if logging has already started.
+    fn enable_guest_memory_dirty_log(&self) -> Result<()> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+    }
+
+    /// Stops logging the pages of guest memory written by the guest, which slows down its writes.
+    /// Does nothing if logging isn't enabled.
+    fn disable_guest_memory_dirty_log(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Gets the bitmap of the pages written by the guest since the last call, or since logging
+    /// started, for each region of guest memory in the order of `GuestMemory::regions`.
+    ///
+    /// Only works after `enable_guest_memory_dirty_log` succeeded.
+    fn get_guest_memory_dirty_log(&self) -> Result<Vec<Vec<u8>>> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+    }
+
     /// Registers an event to be signaled whenever a certain address is written to.
     ///
     /// The `datamatch` parameter can be used to limit signaling `evt` to only the cases where the
@@ -33,23 +33,185 @@ pub trait VcpuRiscv64: Vcpu {
     /// Gets the value of a register on this VCPU.
     fn get_one_reg(&self, reg_id: VcpuRegister) -> Result<u64>;
 
+    /// Handles a SBI call from the guest, by setting the values returned in its `a0` and `a1`
+    /// registers.
+    ///
+    /// This function should be called after `Vcpu::run` returns `VcpuExit::Sbi`, and in the same
+    /// thread as run().
+    fn handle_sbi(&self, error: i64, value: u64) -> Result<()>;
+
+    #[cfg(feature = "gdb")]
+    /// Configures the VCPU for handling guest debug events: `ebreak` instructions, which GDB uses
+    /// as software breakpoints, exit to userspace. KVM can't single-step riscv64 VCPUs.
+    fn set_guest_debug(&self) -> Result<()>;
+
     /// Snapshot VCPU
     fn snapshot(&self) -> anyhow::Result<VcpuSnapshot> {
-        Err(anyhow!("not yet implemented"))
+        let isa = self.get_one_reg(VcpuRegister::Config(ConfigRegister::Isa))?;
+        check_snapshot_isa(isa)?;
+        let get_regs = |regs: &[VcpuRegister]| -> Result<Vec<u64>> {
+            regs.iter().map(|&reg| self.get_one_reg(reg)).collect()
+        };
+        let fp = if isa & ISA_EXT_D != 0 {
+            Some(FpSnapshot {
+                f: get_regs(&(0..32).map(VcpuRegister::FpD).collect::<Vec<_>>())?,
+                fcsr: self.get_one_reg(VcpuRegister::Fcsr)?,
+            })
+        } else {
+            None
+        };
+        let aia_csrs = match get_regs(&AIA_CSR_REGISTERS.map(VcpuRegister::AiaCsr)) {
+            Ok(values) => Some(values),
+            // The AIA CSRs are only available if the VCPU has the Ssaia extension.
+            Err(e) if e.errno() == libc::ENOENT => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(VcpuSnapshot {
+            vcpu_id: self.id(),
+            isa,
+            core: get_regs(&CORE_REGISTERS.map(VcpuRegister::Core))?,
+            csrs: get_regs(&CSR_REGISTERS.map(VcpuRegister::Csr))?,
+            aia_csrs,
+            fp,
+            timer: get_regs(&TIMER_REGISTERS.map(VcpuRegister::Timer))?,
+        })
     }
 
     /// Restore VCPU
-    fn restore(&self, _snapshot: &VcpuSnapshot) -> anyhow::Result<()> {
-        Err(anyhow!("not yet implemented"))
+    fn restore(&self, snapshot: &VcpuSnapshot) -> anyhow::Result<()> {
+        assert_eq!(snapshot.vcpu_id, self.id());
+        // The extensions of the VCPU can't change once it has run, so they are only checked.
+        let isa = self.get_one_reg(VcpuRegister::Config(ConfigRegister::Isa))?;
+        if isa != snapshot.isa {
+            return Err(anyhow!(
+                "VCPU ISA extensions {:#x} don't match the snapshot's {:#x}",
+                isa,
+                snapshot.isa
+            ));
+        }
+        let set_regs = |regs: &[VcpuRegister], values: &[u64]| -> anyhow::Result<()> {
+            if regs.len() != values.len() {
+                return Err(anyhow!(
+                    "snapshot has {} register values, expected {}",
+                    values.len(),
+                    regs.len()
+                ));
+            }
+            for (&reg, &value) in regs.iter().zip(values) {
+                self.set_one_reg(reg, value)?;
+            }
+            Ok(())
+        };
+
+        set_regs(&CSR_REGISTERS.map(VcpuRegister::Csr), &snapshot.csrs)?;
+        if let Some(aia_csrs) = &snapshot.aia_csrs {
+            set_regs(&AIA_CSR_REGISTERS.map(VcpuRegister::AiaCsr), aia_csrs)?;
+        }
+        if let Some(fp) = &snapshot.fp {
+            set_regs(&(0..32).map(VcpuRegister::FpD).collect::<Vec<_>>(), &fp.f)?;
+            self.set_one_reg(VcpuRegister::Fcsr, fp.fcsr)?;
+        }
+        set_regs(&CORE_REGISTERS.map(VcpuRegister::Core), &snapshot.core)?;
+        // The comparator is armed last, once the time it compares to is restored.
+        set_regs(&TIMER_REGISTERS.map(VcpuRegister::Timer), &snapshot.timer)?;
+        Ok(())
     }
 }
 
+// Bits of the D and V extensions in the ISA config register.
+const ISA_EXT_D: u64 = 1 << (b'd' - b'a');
+const ISA_EXT_V: u64 = 1 << (b'v' - b'a');
+
+// Fails if the state of a VCPU with the extensions `isa` can't be saved in a `VcpuSnapshot`.
+fn check_snapshot_isa(isa: u64) -> anyhow::Result<()> {
+    if isa & ISA_EXT_V != 0 {
+        return Err(anyhow!(
+            "VCPUs with the V extension can't be snapshotted, their vector registers aren't saved"
+        ));
+    }
+    Ok(())
+}
+
+// The registers saved in a `VcpuSnapshot`, in the order they are restored.
+const CORE_REGISTERS: [CoreRegister; 33] = [
+    CoreRegister::Pc,
+    CoreRegister::Ra,
+    CoreRegister::Sp,
+    CoreRegister::Gp,
+    CoreRegister::Tp,
+    CoreRegister::T0,
+    CoreRegister::T1,
+    CoreRegister::T2,
+    CoreRegister::S0,
+    CoreRegister::S1,
+    CoreRegister::A0,
+    CoreRegister::A1,
+    CoreRegister::A2,
+    CoreRegister::A3,
+    CoreRegister::A4,
+    CoreRegister::A5,
+    CoreRegister::A6,
+    CoreRegister::A7,
+    CoreRegister::S2,
+    CoreRegister::S3,
+    CoreRegister::S4,
+    CoreRegister::S5,
+    CoreRegister::S6,
+    CoreRegister::S7,
+    CoreRegister::S8,
+    CoreRegister::S9,
+    CoreRegister::S10,
+    CoreRegister::S11,
+    CoreRegister::T3,
+    CoreRegister::T4,
+    CoreRegister::T5,
+    CoreRegister::T6,
+    CoreRegister::Mode,
+];
+const CSR_REGISTERS: [CsrRegister; 10] = [
+    CsrRegister::Sstatus,
+    CsrRegister::Sie,
+    CsrRegister::Stvec,
+    CsrRegister::Sscratch,
+    CsrRegister::Sepc,
+    CsrRegister::Scause,
+    CsrRegister::Stval,
+    CsrRegister::Sip,
+    CsrRegister::Satp,
+    CsrRegister::Scounteren,
+];
+const AIA_CSR_REGISTERS: [AiaCsrRegister; 3] = [
+    AiaCsrRegister::Siselect,
+    AiaCsrRegister::Iprio1,
+    AiaCsrRegister::Iprio2,
+];
+const TIMER_REGISTERS: [TimerRegister; 3] = [
+    TimerRegister::Time,
+    TimerRegister::Compare,
+    TimerRegister::State,
+];
+
 /// Riscv64 specific vCPU snapshot.
 ///
-/// Not implemented yet.
+/// The vector registers aren't saved, so `VcpuRiscv64::snapshot` refuses VCPUs with the V
+/// extension.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct VcpuSnapshot {
     pub vcpu_id: usize,
+    isa: u64,
+    core: Vec<u64>,
+    csrs: Vec<u64>,
+    aia_csrs: Option<Vec<u64>>,
+    fp: Option<FpSnapshot>,
+    timer: Vec<u64>,
+}
+
+/// Floating point state of a riscv64 vCPU with the D extension.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct FpSnapshot {
+    f: Vec<u64>,
+    fcsr: u64,
 }
 
 impl_downcast!(VcpuRiscv64);
@@ -57,13 +219,18 @@ impl_downcast!(VcpuRiscv64);
 /// Initial state for Riscv64 VCPUs.
 #[derive(Clone)]
 pub struct VcpuInitRiscv64 {
+    /// The address of the first instruction to execute, either the kernel or the firmware.
+    pub entry_address: GuestAddress,
     /// The address of the FDT
     pub fdt_address: GuestAddress,
 }
 
 impl VcpuInitRiscv64 {
-    pub fn new(fdt_address: GuestAddress) -> Self {
-        Self { fdt_address }
+    pub fn new(entry_address: GuestAddress, fdt_address: GuestAddress) -> Self {
+        Self {
+            entry_address,
+            fdt_address,
+        }
     }
 }
 
@@ -85,6 +252,70 @@ impl CpuConfigRiscv64 {
 #[derive(Copy, Clone)]
 pub enum ConfigRegister {
     Isa = 0,
+    ZicbomBlockSize = 1,
+}
+
+/// ISA extensions that kvm can enable or disable in the VCPUs.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IsaExtension {
+    A,
+    C,
+    D,
+    F,
+    H,
+    I,
+    M,
+    V,
+    Svpbmt,
+    Sstc,
+    Svinval,
+    Zihintpause,
+    Zicbom,
+    Zbb,
+    Ssaia,
+}
+
+impl IsaExtension {
+    /// All the extensions, with the single letter ones in their canonical order, followed by the
+    /// multi-letter ones in the order they appear in ISA strings.
+    pub const ALL: [IsaExtension; 15] = [
+        IsaExtension::I,
+        IsaExtension::M,
+        IsaExtension::A,
+        IsaExtension::F,
+        IsaExtension::D,
+        IsaExtension::C,
+        IsaExtension::V,
+        IsaExtension::H,
+        IsaExtension::Zicbom,
+        IsaExtension::Zihintpause,
+        IsaExtension::Zbb,
+        IsaExtension::Ssaia,
+        IsaExtension::Sstc,
+        IsaExtension::Svinval,
+        IsaExtension::Svpbmt,
+    ];
+
+    /// Returns the name of the extension, as used in ISA strings.
+    pub fn name(&self) -> &'static str {
+        match self {
+            IsaExtension::A => "a",
+            IsaExtension::C => "c",
+            IsaExtension::D => "d",
+            IsaExtension::F => "f",
+            IsaExtension::H => "h",
+            IsaExtension::I => "i",
+            IsaExtension::M => "m",
+            IsaExtension::V => "v",
+            IsaExtension::Svpbmt => "svpbmt",
+            IsaExtension::Sstc => "sstc",
+            IsaExtension::Svinval => "svinval",
+            IsaExtension::Zihintpause => "zihintpause",
+            IsaExtension::Zicbom => "zicbom",
+            IsaExtension::Zbb => "zbb",
+            IsaExtension::Ssaia => "ssaia",
+        }
+    }
 }
 
 /// Timer registers exposed by kvm.
@@ -92,6 +323,34 @@ pub enum ConfigRegister {
 #[derive(Copy, Clone)]
 pub enum TimerRegister {
     TimebaseFrequency = 0,
+    Time = 1,    // Current time, in timebase ticks
+    Compare = 2, // Time of the next timer interrupt
+    State = 3,   // Whether the timer interrupt is armed
+}
+
+/// Supervisor CSRs exposed by kvm.
+#[repr(u64)]
+#[derive(Copy, Clone)]
+pub enum CsrRegister {
+    Sstatus = 0,
+    Sie = 1,
+    Stvec = 2,
+    Sscratch = 3,
+    Sepc = 4,
+    Scause = 5,
+    Stval = 6,
+    Sip = 7,
+    Satp = 8,
+    Scounteren = 9,
+}
+
+/// AIA CSRs exposed by kvm, if the VCPU has the Ssaia extension.
+#[repr(u64)]
+#[derive(Copy, Clone)]
+pub enum AiaCsrRegister {
+    Siselect = 0,
+    Iprio1 = 1,
+    Iprio2 = 2,
 }
 
 /// Core registers exposed by kvm.
@@ -136,8 +395,19 @@ pub enum CoreRegister {
 /// Registers exposed through `KVM_[GET|SET]_ONE_REG` API.
 #[derive(Copy, Clone)]
 pub enum VcpuRegister {
+    AiaCsr(AiaCsrRegister),
     Config(ConfigRegister),
     Core(CoreRegister),
+    Csr(CsrRegister),
+    /// Double precision floating point register `fN`, for N from 0 to 31. Only available if the
+    /// VCPU has the D extension.
+    FpD(u8),
+    /// Floating point control and status register. Only available if the VCPU has the D
+    /// extension.
+    Fcsr,
+    /// Whether an ISA extension is enabled (1) or not (0). Only available if the host supports the
+    /// extension, and only writable before the VCPU first runs.
+    IsaExt(IsaExtension),
     Timer(TimerRegister),
 }
 
@@ -153,3 +423,16 @@ impl IrqRoute {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_isa() {
+        // rv64imafdc
+        let isa = 0x112d;
+        assert!(check_snapshot_isa(isa).is_ok());
+        assert!(check_snapshot_isa(isa | ISA_EXT_V).is_err());
+    }
+}
@@ -7,6 +7,9 @@
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
 mod aarch64;
 
+#[cfg(target_arch = "riscv64")]
+mod riscv64;
+
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
 
@@ -0,0 +1,61 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use hypervisor::kvm::*;
+use hypervisor::*;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+#[test]
+fn vcpu_snapshot_restore() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0x8000_0000), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let vcpu = vm.create_vcpu(0).unwrap();
+
+    vcpu.set_one_reg(VcpuRegister::Core(CoreRegister::Pc), 0x8000_1000)
+        .unwrap();
+    vcpu.set_one_reg(VcpuRegister::Core(CoreRegister::A0), 0x1234)
+        .unwrap();
+    vcpu.set_one_reg(VcpuRegister::Csr(CsrRegister::Sscratch), 0x5678)
+        .unwrap();
+    vcpu.set_one_reg(VcpuRegister::Timer(TimerRegister::Compare), 0x9abc)
+        .unwrap();
+    let snapshot = vcpu.snapshot().unwrap();
+
+    // The snapshot goes through the same serialization as the ones written to files.
+    let snapshot: VcpuSnapshot =
+        serde_json::from_value(serde_json::to_value(&snapshot).unwrap()).unwrap();
+
+    vcpu.set_one_reg(VcpuRegister::Core(CoreRegister::Pc), 0x8000_2000)
+        .unwrap();
+    vcpu.set_one_reg(VcpuRegister::Core(CoreRegister::A0), 0)
+        .unwrap();
+    vcpu.set_one_reg(VcpuRegister::Csr(CsrRegister::Sscratch), 0)
+        .unwrap();
+    vcpu.set_one_reg(VcpuRegister::Timer(TimerRegister::Compare), 0)
+        .unwrap();
+    vcpu.restore(&snapshot).unwrap();
+
+    assert_eq!(
+        vcpu.get_one_reg(VcpuRegister::Core(CoreRegister::Pc))
+            .unwrap(),
+        0x8000_1000
+    );
+    assert_eq!(
+        vcpu.get_one_reg(VcpuRegister::Core(CoreRegister::A0))
+            .unwrap(),
+        0x1234
+    );
+    assert_eq!(
+        vcpu.get_one_reg(VcpuRegister::Csr(CsrRegister::Sscratch))
+            .unwrap(),
+        0x5678
+    );
+    assert_eq!(
+        vcpu.get_one_reg(VcpuRegister::Timer(TimerRegister::Compare))
+            .unwrap(),
+        0x9abc
+    );
+}
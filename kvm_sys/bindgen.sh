@@ -38,6 +38,11 @@ pub struct kvm_vfio_iommu_config {
 }
 pub const KVM_PVIOMMU_SET_CONFIG: i32 = 1;"
 
+RISCV64_EXTRAS="// Added by kvm_sys/bindgen.sh
+pub const KVM_CAP_ARM_PROTECTED_VM: u32 = 0xffbadab1;
+pub const KVM_CAP_ARM_PROTECTED_VM_FLAGS_ENABLE: u32 = 0;
+pub const KVM_CAP_ARM_PROTECTED_VM_FLAGS_INFO: u32 = 1;"
+
 X86_64_EXTRAS="
 // This is how zerocopy's author deal with bindings for __BindgenBitfieldUnit<Storage>, see:
 // https://fuchsia-review.googlesource.com/c/859278/8/src/starnix/lib/linux_uapi/generate.py
@@ -117,3 +122,15 @@ bindgen_generate \
     -isystem "${BINDGEN_LINUX_ARM64_HEADERS}/include" \
     | replace_linux_int_types \
     > kvm_sys/src/aarch64/bindings.rs
+
+bindgen_generate \
+    --raw-line "${RISCV64_EXTRAS}" \
+    --blocklist-item='__kernel.*' \
+    --blocklist-item='__BITS_PER_LONG' \
+    --blocklist-item='__FD_SETSIZE' \
+    --blocklist-item='_?IOC.*' \
+    "${BINDGEN_LINUX_RISCV64_HEADERS}/include/linux/kvm.h" \
+    -- \
+    -isystem "${BINDGEN_LINUX_RISCV64_HEADERS}/include" \
+    | replace_linux_int_types \
+    > kvm_sys/src/riscv64/bindings.rs
@@ -37,8 +37,13 @@ use thiserror::Error as ThisError;
 
 #[cfg(feature = "slirp")]
 pub mod slirp;
-#[cfg(all(feature = "slirp", windows))]
+#[cfg(all(
+    feature = "slirp",
+    any(target_os = "android", target_os = "linux", windows)
+))]
 pub use slirp::Slirp;
+#[cfg(all(feature = "slirp", any(target_os = "android", target_os = "linux")))]
+pub use slirp::SlirpLoop;
 
 #[sorted]
 #[derive(ThisError, Debug)]
@@ -58,7 +63,10 @@ pub enum Error {
     /// Couldn't open /dev/net/tun.
     #[error("failed to open /dev/net/tun: {0}")]
     OpenTun(SysError),
-    #[cfg(all(feature = "slirp", windows))]
+    #[cfg(all(
+        feature = "slirp",
+        any(target_os = "android", target_os = "linux", windows)
+    ))]
     #[error("slirp related error")]
     Slirp(slirp::SlirpError),
 }
@@ -73,7 +81,10 @@ impl Error {
             Error::CreateTap(e) => *e,
             Error::CloneTap(e) => *e,
             Error::IoctlError(e) => *e,
-            #[cfg(all(feature = "slirp", windows))]
+            #[cfg(all(
+                feature = "slirp",
+                any(target_os = "android", target_os = "linux", windows)
+            ))]
             Error::Slirp(e) => e.sys_error(),
         }
     }
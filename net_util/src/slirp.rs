@@ -6,7 +6,13 @@
 //! level interfaces to libslirp that are used to implement that loop, and
 //! diagnostic tools.
 
-#![cfg(windows)]
+#![cfg(any(target_os = "android", target_os = "linux", windows))]
+
+use std::fmt;
+use std::fmt::Display;
+use std::net::Ipv4Addr;
+use std::num::ParseIntError;
+use std::str::FromStr;
 
 #[path = "../../third_party/libslirp-rs/src/context.rs"]
 pub mod context;
@@ -17,14 +23,19 @@ pub mod packet_ring_buffer;
 pub mod sys;
 use base::Error as SysError;
 use remain::sorted;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
 pub use sys::Slirp;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use sys::SlirpLoop;
 use thiserror::Error as ThisError;
 
 /// Length includes space for an ethernet frame & the vnet header. See the virtio spec for details:
 /// <http://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-2050006>
 pub const ETHERNET_FRAME_SIZE: usize = 1526;
 
-#[cfg(windows)]
 #[sorted]
 #[derive(ThisError, Debug)]
 pub enum SlirpError {
@@ -32,6 +43,8 @@ pub enum SlirpError {
     BrokenPipe(std::io::Error),
     #[error("failed to clone object: {0}")]
     CloneFailed(std::io::Error),
+    #[error("failed to forward host port: {0}")]
+    HostFwd(std::io::Error),
     #[error("overlapped operation failed: {0}")]
     OverlappedError(std::io::Error),
     /// Error encountered while in a Slirp related poll operation.
@@ -40,20 +53,174 @@ pub enum SlirpError {
     /// Error encountered while in a Slirp related poll operation.
     #[error("slirp poll failed: {0}")]
     SlirpPollError(SysError),
+    #[cfg(windows)]
     #[error("WSAStartup failed with code: {0}")]
     WSAStartupError(SysError),
 }
 
-#[cfg(windows)]
 impl SlirpError {
     pub fn sys_error(&self) -> SysError {
         match self {
             SlirpError::BrokenPipe(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             SlirpError::CloneFailed(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
+            SlirpError::HostFwd(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             SlirpError::OverlappedError(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             SlirpError::SlirpIOPollError(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             SlirpError::SlirpPollError(e) => *e,
+            #[cfg(windows)]
             SlirpError::WSAStartupError(e) => *e,
         }
     }
 }
+
+#[sorted]
+#[derive(ThisError, Debug, PartialEq, Eq)]
+pub enum HostFwdError {
+    /// Invalid host address.
+    #[error("invalid host address: {0}")]
+    InvalidAddress(std::net::AddrParseError),
+    /// Invalid number of fields.
+    #[error("expected PROTOCOL:[HOST_ADDRESS:]HOST_PORT:GUEST_PORT, got {0} fields")]
+    InvalidNumFields(usize),
+    /// Invalid port.
+    #[error("invalid port: {0}")]
+    InvalidPort(ParseIntError),
+    /// Unknown protocol.
+    #[error("unknown protocol {0}, expected tcp or udp")]
+    UnknownProtocol(String),
+}
+
+/// Transport protocol of a forwarded port.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HostFwdProtocol {
+    Tcp,
+    Udp,
+}
+
+/// A port of the host forwarded to a port of the guest.
+///
+/// Written as `PROTOCOL:[HOST_ADDRESS:]HOST_PORT:GUEST_PORT`, for example `tcp:8022:22`. The host
+/// address defaults to `127.0.0.1`, which only accepts connections from the host itself.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HostFwd {
+    pub protocol: HostFwdProtocol,
+    pub host_addr: Ipv4Addr,
+    pub host_port: u16,
+    pub guest_port: u16,
+}
+
+impl FromStr for HostFwd {
+    type Err = HostFwdError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split(':').collect();
+        let (protocol, host_addr, host_port, guest_port) = match fields[..] {
+            [protocol, host_port, guest_port] => {
+                (protocol, Ipv4Addr::LOCALHOST, host_port, guest_port)
+            }
+            [protocol, host_addr, host_port, guest_port] => (
+                protocol,
+                host_addr.parse().map_err(HostFwdError::InvalidAddress)?,
+                host_port,
+                guest_port,
+            ),
+            _ => return Err(HostFwdError::InvalidNumFields(fields.len())),
+        };
+        let protocol = match protocol {
+            "tcp" => HostFwdProtocol::Tcp,
+            "udp" => HostFwdProtocol::Udp,
+            _ => return Err(HostFwdError::UnknownProtocol(protocol.to_owned())),
+        };
+
+        Ok(HostFwd {
+            protocol,
+            host_addr,
+            host_port: host_port.parse().map_err(HostFwdError::InvalidPort)?,
+            guest_port: guest_port.parse().map_err(HostFwdError::InvalidPort)?,
+        })
+    }
+}
+
+impl Display for HostFwd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let protocol = match self.protocol {
+            HostFwdProtocol::Tcp => "tcp",
+            HostFwdProtocol::Udp => "udp",
+        };
+        write!(
+            f,
+            "{}:{}:{}:{}",
+            protocol, self.host_addr, self.host_port, self.guest_port
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for HostFwd {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for HostFwd {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(&self)
+    }
+}
+
+/// Configuration of a user-mode network.
+#[derive(Clone, Debug, Default)]
+pub struct SlirpConfig {
+    /// Ports of the host forwarded to the guest.
+    pub host_fwds: Vec<HostFwd>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_fwd_from_str() {
+        assert_eq!(
+            "tcp:8022:22".parse::<HostFwd>().unwrap(),
+            HostFwd {
+                protocol: HostFwdProtocol::Tcp,
+                host_addr: Ipv4Addr::LOCALHOST,
+                host_port: 8022,
+                guest_port: 22,
+            }
+        );
+        assert_eq!(
+            "udp:0.0.0.0:5353:53".parse::<HostFwd>().unwrap(),
+            HostFwd {
+                protocol: HostFwdProtocol::Udp,
+                host_addr: Ipv4Addr::UNSPECIFIED,
+                host_port: 5353,
+                guest_port: 53,
+            }
+        );
+        assert_eq!(
+            "tcp:22".parse::<HostFwd>().unwrap_err(),
+            HostFwdError::InvalidNumFields(2)
+        );
+        assert_eq!(
+            "sctp:8022:22".parse::<HostFwd>().unwrap_err(),
+            HostFwdError::UnknownProtocol("sctp".to_owned())
+        );
+        assert!("tcp:8022:65536".parse::<HostFwd>().is_err());
+        assert!("tcp:localhost:8022:22".parse::<HostFwd>().is_err());
+    }
+
+    #[test]
+    fn host_fwd_display() {
+        let host_fwd: HostFwd = "tcp:8022:22".parse().unwrap();
+        assert_eq!(host_fwd.to_string(), "tcp:127.0.0.1:8022:22");
+        assert_eq!(host_fwd.to_string().parse::<HostFwd>().unwrap(), host_fwd);
+    }
+}
@@ -0,0 +1,280 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+pub mod handler;
+
+use std::io::Read;
+use std::io::Result as IoResult;
+use std::io::Write;
+use std::mem::size_of;
+use std::net;
+use std::os::raw::*;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+
+use base::error;
+use base::volatile_impl;
+use base::warn;
+use base::AsRawDescriptor;
+use base::Error as SysError;
+use base::Event;
+use base::RawDescriptor;
+use base::ReadNotifier;
+use base::UnixSeqpacket;
+use base::WorkerThread;
+
+use crate::slirp::SlirpConfig;
+use crate::slirp::SlirpError;
+use crate::slirp::ETHERNET_FRAME_SIZE;
+use crate::sys::linux::TapTLinux;
+use crate::Error;
+use crate::MacAddress;
+use crate::Result;
+use crate::TapT;
+use crate::TapTCommon;
+
+// Size of the socket buffers for packets in transit between the virtio-net device & Slirp.
+pub const SLIRP_BUFFER_SIZE: usize = 1000 * ETHERNET_FRAME_SIZE;
+
+// libslirp's default MTU, used since the context doesn't set one.
+const SLIRP_MTU: u16 = 1500;
+
+// Size of the virtio-net header preceding every frame, which is the only size the handler
+// supports.
+const VNET_HDR_SIZE: usize = 12;
+
+/// Handle for a pseudo-tap interface backed by libslirp.
+///
+/// Frames are exchanged with the libslirp loop over a `SOCK_SEQPACKET` socket pair, one frame per
+/// packet, preceded by a virtio-net header like on a tap with `IFF_VNET_HDR`.
+pub struct Slirp {
+    guest_socket: UnixSeqpacket,
+}
+
+impl Slirp {
+    /// Creates a user-mode network configured by `config`.
+    ///
+    /// Returns the pseudo-tap to give to the virtio-net device, and the libslirp loop connected to
+    /// it, which doesn't run until `SlirpLoop::start` is called.
+    pub fn new(config: SlirpConfig) -> Result<(Slirp, SlirpLoop)> {
+        let (guest_socket, host_socket) = UnixSeqpacket::pair()
+            .map_err(SysError::from)
+            .map_err(Error::CreateSocket)?;
+        for socket in [&guest_socket, &host_socket] {
+            socket
+                .set_nonblocking(true)
+                .map_err(SysError::from)
+                .map_err(Error::CreateSocket)?;
+            set_buffer_size(socket, SLIRP_BUFFER_SIZE).map_err(Error::CreateSocket)?;
+        }
+
+        Ok((
+            Slirp { guest_socket },
+            SlirpLoop {
+                host_socket: Some(host_socket),
+                config,
+                worker_thread: None,
+            },
+        ))
+    }
+}
+
+fn set_buffer_size(socket: &UnixSeqpacket, size: usize) -> base::Result<()> {
+    let size = size as c_int;
+    for option in [libc::SO_SNDBUF, libc::SO_RCVBUF] {
+        // SAFETY:
+        // Safe because the socket is valid, we pass a valid pointer and length for the option
+        // value, and we check the return value.
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_descriptor(),
+                libc::SOL_SOCKET,
+                option,
+                &size as *const c_int as *const c_void,
+                size_of::<c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(SysError::last());
+        }
+    }
+    Ok(())
+}
+
+impl TapT for Slirp {}
+
+impl TapTCommon for Slirp {
+    fn new_with_name(_name: &[u8], _vnet_hdr: bool, _multi_vq: bool) -> Result<Self> {
+        unimplemented!("not implemented for Slirp");
+    }
+
+    fn new(_vnet_hdr: bool, _multi_vq: bool) -> Result<Slirp> {
+        unimplemented!("not implemented for Slirp");
+    }
+
+    fn into_mq_taps(self, vq_pairs: u16) -> Result<Vec<Self>> {
+        // libslirp is single threaded; only one vq pair is supported.
+        if vq_pairs != 1 {
+            return Err(Error::CloneTap(SysError::new(libc::EINVAL)));
+        }
+
+        Ok(vec![self])
+    }
+
+    fn ip_addr(&self) -> Result<net::Ipv4Addr> {
+        // Only used by the plugin system.
+        unimplemented!("need to fetch the client's IP address from Slirp");
+    }
+
+    fn set_ip_addr(&self, _ip_addr: net::Ipv4Addr) -> Result<()> {
+        // Only used by the plugin system.
+        unimplemented!("need to fetch the client's IP address from Slirp");
+    }
+
+    fn netmask(&self) -> Result<net::Ipv4Addr> {
+        // Only used by the plugin system.
+        unimplemented!("need to fetch the client's IP address from Slirp");
+    }
+
+    fn set_netmask(&self, _netmask: net::Ipv4Addr) -> Result<()> {
+        // Only used by the plugin system.
+        unimplemented!("need to fetch the client's IP address from Slirp");
+    }
+
+    fn mtu(&self) -> Result<u16> {
+        Ok(SLIRP_MTU)
+    }
+
+    fn set_mtu(&self, _mtu: u16) -> Result<()> {
+        unimplemented!("Set MTU unsupported by Slirp");
+    }
+
+    fn mac_address(&self) -> Result<MacAddress> {
+        // Only used by the plugin system.
+        unimplemented!("need to fetch the client's IP address from Slirp");
+    }
+
+    fn set_mac_address(&self, _mac_addr: MacAddress) -> Result<()> {
+        // Only used by the plugin system.
+        unimplemented!("need to fetch the client's IP address from Slirp");
+    }
+
+    fn set_offload(&self, flags: c_uint) -> Result<()> {
+        // Slirp does not support offload. The device doesn't offer it to the guest, but a guest
+        // could still try to enable it through the control queue.
+        if flags != 0 {
+            return Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)));
+        }
+        Ok(())
+    }
+
+    fn enable(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(Slirp {
+            guest_socket: self
+                .guest_socket
+                .try_clone()
+                .map_err(|e| Error::Slirp(SlirpError::CloneFailed(e)))?,
+        })
+    }
+
+    unsafe fn from_raw_descriptor(_descriptor: RawDescriptor) -> Result<Self> {
+        unimplemented!("not used by Slirp");
+    }
+}
+
+impl TapTLinux for Slirp {
+    fn set_vnet_hdr_size(&self, size: usize) -> Result<()> {
+        if size != VNET_HDR_SIZE {
+            return Err(Error::IoctlError(SysError::new(libc::EINVAL)));
+        }
+        Ok(())
+    }
+
+    fn if_flags(&self) -> u32 {
+        net_sys::IFF_TAP | net_sys::IFF_NO_PI | net_sys::IFF_VNET_HDR
+    }
+}
+
+impl Read for Slirp {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.guest_socket.read(buf)
+    }
+}
+
+impl Write for Slirp {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.guest_socket.write(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for Slirp {
+    fn as_raw_fd(&self) -> RawFd {
+        self.guest_socket.as_raw_descriptor()
+    }
+}
+
+impl AsRawDescriptor for Slirp {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.guest_socket.as_raw_descriptor()
+    }
+}
+
+impl ReadNotifier for Slirp {
+    fn get_read_notifier(&self) -> &dyn AsRawDescriptor {
+        self
+    }
+}
+
+volatile_impl!(Slirp);
+
+/// The libslirp loop of a user-mode network, which exchanges the frames of the guest with the
+/// host's network stack.
+///
+/// The loop opens host sockets on behalf of the guest, so it should be started in the sandbox of
+/// the device using the matching `Slirp`.
+pub struct SlirpLoop {
+    host_socket: Option<UnixSeqpacket>,
+    config: SlirpConfig,
+    worker_thread: Option<WorkerThread<()>>,
+}
+
+impl SlirpLoop {
+    /// Starts running the loop in a new thread. Does nothing if it was already started.
+    pub fn start(&mut self) {
+        let host_socket = match self.host_socket.take() {
+            Some(host_socket) => host_socket,
+            None => return,
+        };
+        let config = self.config.clone();
+        self.worker_thread = Some(WorkerThread::start("v_slirp", move |kill_evt| {
+            run_slirp_loop(host_socket, kill_evt, config)
+        }));
+    }
+
+    /// Returns the descriptors that the loop needs before it is started.
+    pub fn as_raw_descriptors(&self) -> Vec<RawDescriptor> {
+        self.host_socket
+            .iter()
+            .map(|socket| socket.as_raw_descriptor())
+            .collect()
+    }
+}
+
+fn run_slirp_loop(host_socket: UnixSeqpacket, kill_evt: Event, config: SlirpConfig) {
+    match handler::start_slirp(host_socket, kill_evt, config) {
+        Err(Error::Slirp(SlirpError::BrokenPipe(e))) => {
+            warn!("exited slirp listening loop: {}", e)
+        }
+        Err(e) => error!("error while running slirp listening loop: {}", e),
+        Ok(()) => {}
+    }
+}
@@ -0,0 +1,400 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::time::Duration;
+use std::time::Instant;
+
+use base::error;
+use base::warn;
+use base::AsRawDescriptor;
+use base::Error as SysError;
+use base::Event;
+use base::RawDescriptor;
+use base::Timer;
+use base::TimerTrait;
+use base::UnixSeqpacket;
+use smallvec::SmallVec;
+use virtio_sys::virtio_net::virtio_net_hdr;
+use virtio_sys::virtio_net::virtio_net_hdr_mrg_rxbuf;
+use zerocopy::AsBytes;
+
+use crate::slirp::context::CallbackHandler;
+use crate::slirp::context::Context;
+use crate::slirp::context::PollEvents;
+use crate::slirp::HostFwdProtocol;
+use crate::slirp::SlirpConfig;
+use crate::slirp::SlirpError;
+use crate::slirp::ETHERNET_FRAME_SIZE;
+use crate::Error;
+use crate::Result;
+
+const VETH_HEADER_LENGTH: usize = 12;
+
+struct Handler {
+    start: Instant,
+    socket: UnixSeqpacket,
+    buf: [u8; ETHERNET_FRAME_SIZE],
+    // Stores a handle to each timer with its callback. Note that the timer itself is owned by
+    // libslirp, and created/released via `timer_new` and `timer_free`.
+    timer_callbacks: HashMap<RawDescriptor, (Timer, Box<dyn FnMut()>)>,
+}
+
+impl CallbackHandler for Handler {
+    type Timer = base::Timer;
+
+    fn clock_get_ns(&mut self) -> i64 {
+        self.start.elapsed().as_nanos() as i64
+    }
+
+    /// Sends a packet to the guest.
+    fn send_packet(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let vnet_hdr = virtio_net_hdr_mrg_rxbuf {
+            hdr: virtio_net_hdr {
+                flags: 0,
+                gso_size: 0,
+                hdr_len: 0,
+                csum_start: 0,
+                csum_offset: 0,
+                gso_type: virtio_sys::virtio_net::VIRTIO_NET_HDR_GSO_NONE as u8,
+            },
+            num_buffers: 1,
+        };
+        let send_buf = [vnet_hdr.as_bytes(), buf].concat();
+
+        match self.socket.send(&send_buf) {
+            // The guest doesn't take its frames as fast as they arrive: drop this one, like a NIC
+            // with a full receive queue.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            res => res,
+        }
+    }
+
+    fn register_poll_fd(&mut self, _fd: i32) {}
+    fn unregister_poll_fd(&mut self, _fd: i32) {}
+
+    fn guest_error(&mut self, msg: &str) {
+        warn!("guest error: {}", msg);
+    }
+
+    fn notify(&mut self) {}
+
+    fn timer_new(&mut self, callback: Box<dyn FnMut()>) -> Box<Self::Timer> {
+        let timer = Timer::new().expect("failed to create network timer");
+        let handle = timer.try_clone().expect("failed to clone network timer");
+        self.timer_callbacks
+            .insert(timer.as_raw_descriptor(), (handle, callback));
+        Box::new(timer)
+    }
+
+    fn timer_mod(&mut self, timer: &mut Self::Timer, expire_time: i64) {
+        // expire_time is a clock_get_ns relative deadline in milliseconds. A zero duration would
+        // disarm the timer, so a deadline in the past expires as soon as possible instead.
+        let timer_duration = Duration::from_millis(expire_time as u64)
+            .saturating_sub(Duration::from_nanos(self.clock_get_ns() as u64))
+            .max(Duration::from_nanos(1));
+
+        timer
+            .reset(timer_duration, None)
+            .expect("failed to modify network timer");
+    }
+
+    fn timer_free(&mut self, timer: Box<Self::Timer>) {
+        self.timer_callbacks.remove(&timer.as_raw_descriptor());
+        // The actual Timer is freed implicitly by the Box drop.
+    }
+
+    fn get_timers<'a>(&'a self) -> Box<dyn Iterator<Item = &RawDescriptor> + 'a> {
+        Box::new(self.timer_callbacks.keys())
+    }
+
+    fn execute_timer(&mut self, timer: RawDescriptor) {
+        // The timer may have been freed by a callback that ran since the loop polled it.
+        if let Some((handle, timer_callback)) = self.timer_callbacks.get_mut(&timer) {
+            if let Err(e) = handle.mark_waited() {
+                error!("failed to read network timer: {}", e);
+            }
+            timer_callback()
+        }
+    }
+
+    fn begin_read_from_guest(&mut self) -> io::Result<()> {
+        // Reads complete synchronously in `end_read_from_guest`.
+        Ok(())
+    }
+
+    fn end_read_from_guest(&mut self) -> io::Result<&[u8]> {
+        match self.socket.recv(&mut self.buf) {
+            // Frames always carry a header, so this is the end of the stream.
+            Ok(0) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the virtio-net device closed its socket",
+            )),
+            Ok(len) if len >= VETH_HEADER_LENGTH => {
+                // Skip over the veth header (12 bytes, created by the frontend per the
+                // virtio spec).
+                Ok(&self.buf[VETH_HEADER_LENGTH..len])
+            }
+            Ok(len) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Too few bytes ({}) read from the guest's virtio-net frontend.",
+                    len
+                ),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn poll_events_to_slirp_events(events: i16) -> PollEvents {
+    let mut poll_events = PollEvents::empty();
+    if events & libc::POLLIN != 0 {
+        poll_events |= PollEvents::poll_in();
+    }
+    if events & libc::POLLOUT != 0 {
+        poll_events |= PollEvents::poll_out();
+    }
+    if events & libc::POLLPRI != 0 {
+        poll_events |= PollEvents::poll_pri();
+    }
+    if events & libc::POLLERR != 0 {
+        poll_events |= PollEvents::poll_err();
+    }
+    if events & libc::POLLHUP != 0 {
+        poll_events |= PollEvents::poll_hup();
+    }
+    poll_events
+}
+
+fn slirp_events_to_poll_events(events: PollEvents) -> i16 {
+    // POLLERR and POLLHUP are always reported by poll(2), so they don't need to be requested.
+    let mut poll_events = 0;
+    if events.has_in() {
+        poll_events |= libc::POLLIN;
+    }
+    if events.has_out() {
+        poll_events |= libc::POLLOUT;
+    }
+    if events.has_pri() {
+        poll_events |= libc::POLLPRI;
+    }
+    poll_events
+}
+
+fn pollfd(fd: RawDescriptor, events: i16) -> libc::pollfd {
+    libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    }
+}
+
+// Waits for events on `fds` for at most `timeout_ms` milliseconds.
+fn poll(fds: &mut [libc::pollfd], timeout_ms: u32) -> io::Result<()> {
+    let timeout_ms = timeout_ms.min(i32::MAX as u32) as i32;
+    // SAFETY:
+    // Safe because fds is a valid slice of pollfd structs, and we check the return value.
+    let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Starts libslirp's main loop attached to host_socket. Packets are exchanged between host_socket
+/// and the host's network stack, until `shutdown_event` is signaled or the other end of
+/// host_socket is closed.
+///
+/// host_socket must be a non blocking `SOCK_SEQPACKET` socket.
+pub fn start_slirp(
+    host_socket: UnixSeqpacket,
+    shutdown_event: Event,
+    config: SlirpConfig,
+) -> Result<()> {
+    let disable_access_to_host = !cfg!(feature = "guest-to-host-net-loopback");
+    let host_socket_fd = host_socket.as_raw_descriptor();
+    let mut context = create_slirp_context(host_socket, disable_access_to_host)?;
+
+    for host_fwd in &config.host_fwds {
+        // The unspecified guest address stands for the first address given out by DHCP.
+        if let Err(e) = context.add_hostfwd(
+            host_fwd.protocol == HostFwdProtocol::Udp,
+            host_fwd.host_addr,
+            host_fwd.host_port,
+            Ipv4Addr::UNSPECIFIED,
+            host_fwd.guest_port,
+        ) {
+            error!("failed to forward {}: {}", host_fwd, e);
+        }
+    }
+
+    loop {
+        // The descriptors polled on behalf of crosvm come first, followed by the ones requested by
+        // Slirp. Slirp refers to its descriptors by the index we return from the pollfds_fill
+        // callback, and asks for their events with that index in the pollfds_poll callback.
+        let mut poll_fds = vec![
+            pollfd(shutdown_event.as_raw_descriptor(), libc::POLLIN),
+            pollfd(host_socket_fd, libc::POLLIN),
+        ];
+        // There are relatively few concurrent timers used by libslirp, so we set the small vector
+        // size low.
+        let timers = context
+            .get_timers()
+            .copied()
+            .collect::<SmallVec<[RawDescriptor; 8]>>();
+        poll_fds.extend(timers.iter().map(|timer| pollfd(*timer, libc::POLLIN)));
+        let slirp_fds_start = poll_fds.len();
+
+        // We'd like to sleep as long as possible (assuming no actionable notifications arrive).
+        let mut timeout_ms: u32 = u32::MAX;
+        context.pollfds_fill(&mut timeout_ms, |fd: i32, events: PollEvents| {
+            poll_fds.push(pollfd(fd, slirp_events_to_poll_events(events)));
+            (poll_fds.len() - 1 - slirp_fds_start) as i32
+        });
+
+        match poll(&mut poll_fds, timeout_ms) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                // Slirp doesn't look at its descriptors when told that polling failed.
+                context.pollfds_poll(true, |_| PollEvents::empty());
+                continue;
+            }
+            Err(e) => {
+                return Err(Error::Slirp(SlirpError::SlirpPollError(SysError::from(e))));
+            }
+        }
+
+        if poll_fds[0].revents != 0 {
+            break;
+        }
+        if poll_fds[1].revents != 0 {
+            // Collect input from the guest & inject into Slirp. It seems that this input step
+            // should be between pollfds_fill & pollfds_poll.
+            context.handle_guest_input()?;
+        }
+        for (timer, poll_fd) in timers.iter().zip(&poll_fds[2..slirp_fds_start]) {
+            if poll_fd.revents != 0 {
+                context.execute_timer(*timer);
+            }
+        }
+
+        // It's possible no descriptor is ready and we got here from a timeout. This is fine,
+        // because libslirp wants to be woken up if timeout has expired.
+        let slirp_fds = &poll_fds[slirp_fds_start..];
+        context.pollfds_poll(false, |fd_index: i32| {
+            poll_events_to_slirp_events(slirp_fds[fd_index as usize].revents)
+        });
+    }
+
+    Ok(())
+}
+
+fn create_slirp_context(
+    host_socket: UnixSeqpacket,
+    disable_access_to_host: bool,
+) -> Result<Box<Context<Handler>>> {
+    let handler = Handler {
+        start: Instant::now(),
+        socket: host_socket,
+        buf: [0; ETHERNET_FRAME_SIZE],
+        timer_callbacks: HashMap::new(),
+    };
+
+    // Address & mask of the virtual network.
+    let v4_network_addr = Ipv4Addr::new(10, 0, 2, 0);
+    let v4_network_mask = Ipv4Addr::new(255, 255, 255, 0);
+
+    // Address of the host machine on the virtual network (if the feature is enabled).
+    let host_v4_addr = Ipv4Addr::new(10, 0, 2, 2);
+
+    // Address of the libslirp provided DNS proxy (packets to this address are intercepted by
+    // libslirp & routed to the first nameserver configured in the host's /etc/resolv.conf).
+    let dns_addr = Ipv4Addr::new(10, 0, 2, 3);
+
+    // DHCP range should start *after* the statically assigned addresses.
+    let dhcp_start_addr = Ipv4Addr::new(10, 0, 2, 4);
+
+    // IPv6 network address. This is a ULA (unique local address) network, with a randomly generated
+    // ID (0x13624603218). The "prefix" or network address is 64 bits, incorporating both the
+    // network ID, and the subnet (0x0001).
+    let v6_network_addr = Ipv6Addr::new(0xfd13, 0x6246, 0x3218, 0x0001, 0, 0, 0, 0);
+
+    let v6_host_addr = Ipv6Addr::new(0xfd13, 0x6246, 0x3218, 0x0001, 0, 0, 0, 2);
+    let v6_dns_addr = Ipv6Addr::new(0xfd13, 0x6246, 0x3218, 0x0001, 0, 0, 0, 3);
+    Context::new(
+        disable_access_to_host,
+        /* IPv4 enabled */
+        true,
+        v4_network_addr,
+        v4_network_mask,
+        host_v4_addr,
+        /* IPv6 enabled */ true,
+        v6_network_addr,
+        /* virtual_network_v6_prefix_len */ 64,
+        /* host_v6_address */ v6_host_addr,
+        /* host_hostname */ None,
+        dhcp_start_addr,
+        dns_addr,
+        /* dns_server_v6_addr */ v6_dns_addr,
+        /* virtual_network_dns_search_domains */ Vec::new(),
+        /* dns_server_domain_name */ None,
+        handler,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poll_events_round_trip() {
+        let events = PollEvents::poll_in() | PollEvents::poll_out();
+        assert_eq!(
+            poll_events_to_slirp_events(slirp_events_to_poll_events(events)),
+            events
+        );
+        assert_eq!(
+            poll_events_to_slirp_events(libc::POLLHUP | libc::POLLERR),
+            PollEvents::poll_hup() | PollEvents::poll_err()
+        );
+    }
+
+    #[test]
+    fn guest_frames_lose_their_header() {
+        let (guest_socket, host_socket) = UnixSeqpacket::pair().unwrap();
+        host_socket.set_nonblocking(true).unwrap();
+        let mut handler = Handler {
+            start: Instant::now(),
+            socket: host_socket,
+            buf: [0; ETHERNET_FRAME_SIZE],
+            timer_callbacks: HashMap::new(),
+        };
+
+        assert_eq!(
+            handler.end_read_from_guest().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        let mut frame = vec![0u8; VETH_HEADER_LENGTH];
+        frame.extend_from_slice(b"frame");
+        guest_socket.send(&frame).unwrap();
+        assert_eq!(handler.end_read_from_guest().unwrap(), b"frame");
+
+        handler.send_packet(b"reply").unwrap();
+        let mut buf = [0u8; ETHERNET_FRAME_SIZE];
+        let len = guest_socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[VETH_HEADER_LENGTH..len], b"reply");
+
+        drop(guest_socket);
+        assert_eq!(
+            handler.end_read_from_guest().unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+}
@@ -17,7 +17,6 @@ pub trait TapTLinux {
     fn if_flags(&self) -> u32;
 }
 
-// TODO(b/159159958) implement FileReadWriteVolatile for slirp
 pub trait TapT: FileReadWriteVolatile + TapTCommon + TapTLinux {}
 
 pub mod fakes {
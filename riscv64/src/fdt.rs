@@ -16,8 +16,10 @@ use devices::irqchip::aia_aplic_addr;
 use devices::irqchip::aia_imsic_size;
 use devices::irqchip::AIA_APLIC_SIZE;
 use devices::irqchip::AIA_IMSIC_BASE;
+use devices::tpm::TPM_TIS_MMIO_SIZE;
 use devices::PciAddress;
 use devices::PciInterruptPin;
+use hypervisor::IsaExtension;
 use rand::rngs::OsRng;
 use rand::RngCore;
 use vm_memory::GuestAddress;
@@ -61,7 +63,43 @@ fn create_memory_node(fdt: &mut Fdt, guest_mem: &GuestMemory) -> Result<()> {
     Ok(())
 }
 
-fn create_cpu_nodes(fdt: &mut Fdt, num_cpus: u32, timebase_frequency: u32) -> Result<()> {
+// Returns the names of the ISA extensions exposed in the device tree.
+fn isa_extension_names(isa_extensions: &[IsaExtension]) -> Vec<&'static str> {
+    let mut names = Vec::new();
+    for ext in isa_extensions {
+        // The machine-level part of AIA is provided by the host, but guests expect it to be
+        // listed with the supervisor-level part.
+        if *ext == IsaExtension::Ssaia {
+            names.push("smaia");
+        }
+        names.push(ext.name());
+    }
+    names
+}
+
+// Builds the ISA string of the CPUs, for instance "rv64imafdc_zicbom_smaia_ssaia".
+fn isa_string(extension_names: &[&str]) -> String {
+    let mut isa = String::from("rv64");
+    for name in extension_names.iter().filter(|name| name.len() == 1) {
+        isa.push_str(name);
+    }
+    for name in extension_names.iter().filter(|name| name.len() > 1) {
+        isa.push('_');
+        isa.push_str(name);
+    }
+    isa
+}
+
+fn create_cpu_nodes(
+    fdt: &mut Fdt,
+    num_cpus: u32,
+    timebase_frequency: u32,
+    isa_extensions: &[IsaExtension],
+    cbom_block_size: Option<u32>,
+) -> Result<()> {
+    let extension_names = isa_extension_names(isa_extensions);
+    let isa = isa_string(&extension_names);
+
     let cpus_node = fdt.root_mut().subnode_mut("cpus")?;
     cpus_node.set_prop("#address-cells", 0x1u32)?;
     cpus_node.set_prop("#size-cells", 0x0u32)?;
@@ -73,7 +111,12 @@ fn create_cpu_nodes(fdt: &mut Fdt, num_cpus: u32, timebase_frequency: u32) -> Re
         cpu_node.set_prop("device_type", "cpu")?;
         cpu_node.set_prop("compatible", "riscv")?;
         cpu_node.set_prop("mmu-type", "sv48")?;
-        cpu_node.set_prop("riscv,isa", "rv64iafdcsu_smaia_ssaia")?;
+        cpu_node.set_prop("riscv,isa", isa.as_str())?;
+        cpu_node.set_prop("riscv,isa-base", "rv64i")?;
+        cpu_node.set_prop("riscv,isa-extensions", extension_names.as_slice())?;
+        if let Some(block_size) = cbom_block_size {
+            cpu_node.set_prop("riscv,cbom-block-size", block_size)?;
+        }
         cpu_node.set_prop("status", "okay")?;
         cpu_node.set_prop("reg", cpu_id)?;
         cpu_node.set_prop("phandle", PHANDLE_CPU0 + cpu_id)?;
@@ -281,6 +324,14 @@ fn create_pci_nodes(
     Ok(())
 }
 
+fn create_tpm_tis_node(fdt: &mut Fdt, base: u64) -> Result<()> {
+    let reg = [base, TPM_TIS_MMIO_SIZE];
+    let tpm_node = fdt.root_mut().subnode_mut(&format!("tpm@{:x}", base))?;
+    tpm_node.set_prop("compatible", "tcg,tpm-tis-mmio")?;
+    tpm_node.set_prop("reg", &reg)?;
+    Ok(())
+}
+
 /// Creates a flattened device tree containing all of the parameters for the
 /// kernel and loads it into the guest memory at the specified offset.
 ///
@@ -296,6 +347,9 @@ fn create_pci_nodes(
 /// * `cmdline` - The kernel commandline
 /// * `initrd` - An optional tuple of initrd guest physical address and size
 /// * `timebase_frequency` - The time base frequency for the VM.
+/// * `isa_extensions` - The ISA extensions enabled in the VCPUs.
+/// * `cbom_block_size` - The cache block size of the Zicbom extension, if it is enabled.
+/// * `tpm_tis_base` - The base address of the TPM TIS device, if any.
 pub fn create_fdt(
     fdt_max_size: usize,
     guest_mem: &GuestMemory,
@@ -312,6 +366,9 @@ pub fn create_fdt(
     cmdline: &str,
     initrd: Option<(GuestAddress, usize)>,
     timebase_frequency: u32,
+    isa_extensions: &[IsaExtension],
+    cbom_block_size: Option<u32>,
+    tpm_tis_base: Option<u64>,
     device_tree_overlays: Vec<DtbOverlay>,
 ) -> Result<()> {
     let mut fdt = Fdt::new(&[]);
@@ -323,9 +380,18 @@ pub fn create_fdt(
     root_node.set_prop("#size-cells", 0x2u32)?;
     create_chosen_node(&mut fdt, cmdline, initrd)?;
     create_memory_node(&mut fdt, guest_mem)?;
-    create_cpu_nodes(&mut fdt, num_cpus, timebase_frequency)?;
+    create_cpu_nodes(
+        &mut fdt,
+        num_cpus,
+        timebase_frequency,
+        isa_extensions,
+        cbom_block_size,
+    )?;
     create_aia_node(&mut fdt, num_cpus as usize, aia_num_ids, aia_num_sources)?;
     create_pci_nodes(&mut fdt, pci_irqs, pci_cfg, pci_ranges)?;
+    if let Some(base) = tpm_tis_base {
+        create_tpm_tis_node(&mut fdt, base)?;
+    }
 
     // Done writing base FDT, now apply DT overlays
     apply_device_tree_overlays(
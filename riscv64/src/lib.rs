@@ -22,6 +22,8 @@ use base::Event;
 use base::SendTube;
 use devices::serial_device::SerialHardware;
 use devices::serial_device::SerialParameters;
+use devices::tpm::TpmTis;
+use devices::tpm::TPM_TIS_MMIO_SIZE;
 use devices::Bus;
 use devices::BusDeviceObj;
 use devices::BusError;
@@ -34,10 +36,14 @@ use devices::PciRootCommand;
 #[cfg(feature = "gdb")]
 use gdbstub::arch::Arch;
 #[cfg(feature = "gdb")]
+use gdbstub_arch::riscv::reg::id::RiscvRegId;
+#[cfg(feature = "gdb")]
 use gdbstub_arch::riscv::Riscv64 as GdbArch;
+use hypervisor::ConfigRegister;
 use hypervisor::CoreRegister;
 use hypervisor::CpuConfigRiscv64;
 use hypervisor::Hypervisor;
+use hypervisor::IsaExtension;
 use hypervisor::ProtectionType;
 use hypervisor::TimerRegister;
 use hypervisor::VcpuInitRiscv64;
@@ -64,8 +70,14 @@ use vm_memory::GuestMemory;
 use vm_memory::MemoryRegionOptions;
 
 mod fdt;
+pub mod sbi;
+
+// The firmware is placed at the start of DRAM, which is the reset vector, and can use the memory
+// up to the kernel. KVM implements the SBI itself, so the firmware runs in S-mode, like U-Boot's
+// S-mode build or EDK2.
+const RISCV64_BIOS_MAX_LEN: u64 = RISCV64_KERNEL_OFFSET;
 
-// We place the kernel at offset 8MB
+// We place the kernel at offset 2MB, which is also where OpenSBI's fw_jump expects its payload.
 const RISCV64_KERNEL_OFFSET: u64 = 0x20_0000;
 const RISCV64_INITRD_ALIGN: u64 = 8;
 const RISCV64_FDT_ALIGN: u64 = 0x40_0000;
@@ -84,6 +96,13 @@ const RISCV64_MMIO_SIZE: u64 = 0x10_0000;
 
 const RISCV64_FDT_MAX_SIZE: u64 = 0x1_0000;
 
+// Place the TPM TIS device at pages 4 to 8, below the PCI configuration region.
+const RISCV64_TPM_TIS_ADDR: u64 = 0x4000;
+
+fn get_bios_addr() -> GuestAddress {
+    GuestAddress(RISCV64_PHYS_MEM_START)
+}
+
 fn get_kernel_addr() -> GuestAddress {
     GuestAddress(RISCV64_PHYS_MEM_START + RISCV64_KERNEL_OFFSET)
 }
@@ -93,6 +112,8 @@ const RISCV64_IRQ_BASE: u32 = 1;
 #[sorted]
 #[derive(Error, Debug)]
 pub enum Error {
+    #[error("bios could not be loaded: {0}")]
+    BiosLoadFailure(arch::LoadImageError),
     #[error("unable to clone an Event: {0}")]
     CloneEvent(base::Error),
     #[error("failed to clone IRQ chip: {0}")]
@@ -113,18 +134,28 @@ pub enum Error {
     CreateSocket(io::Error),
     #[error("failed to create VCPU: {0}")]
     CreateVcpu(base::Error),
+    #[error("the host can't disable the {0} ISA extension: {1}")]
+    DisableIsaExtension(&'static str, base::Error),
     #[error("vm created wrong kind of vcpu")]
     DowncastVcpu,
+    #[error("the host can't enable the {0} ISA extension: {1}")]
+    EnableIsaExtension(&'static str, base::Error),
+    #[error("failed to enable guest debugging: {0}")]
+    EnableGuestDebug(base::Error),
+    #[error("failed to enable singlestep execution: {0}")]
+    EnableSinglestep(base::Error),
     #[error("failed to finalize devices: {0}")]
     FinalizeDevices(base::Error),
     #[error("failed to finalize IRQ chip: {0}")]
     FinalizeIrqChip(base::Error),
+    #[error("failed to get the state of the {0} ISA extension: {1}")]
+    GetIsaExtension(&'static str, base::Error),
     #[error("failed to get serial cmdline: {0}")]
     GetSerialCmdline(GetSerialCmdlineError),
     #[error("Failed to get the timer base frequency: {0}")]
     GetTimebase(base::Error),
-    #[error("Image type not supported on riscv")]
-    ImageTypeUnsupported,
+    #[error("failed to get the Zicbom block size: {0}")]
+    GetZicbomBlockSize(base::Error),
     #[error("initrd could not be loaded: {0}")]
     InitrdLoadFailure(arch::LoadImageError),
     #[error("kernel could not be loaded: {0}")]
@@ -133,14 +164,24 @@ pub enum Error {
     ProtectedVmUnsupported,
     #[error("ramoops address is different from high_mmio_base: {0} vs {1}")]
     RamoopsAddress(u64, u64),
+    #[error("error reading guest memory: {0}")]
+    ReadGuestMemory(vm_memory::GuestMemoryError),
+    #[error("error reading CPU register: {0}")]
+    ReadReg(base::Error),
+    #[error("error reading CPU registers: {0}")]
+    ReadRegs(base::Error),
     #[error("failed to register irq fd: {0}")]
     RegisterIrqfd(base::Error),
     #[error("error registering PCI bus: {0}")]
     RegisterPci(BusError),
+    #[error("error registering TPM TIS device: {0}")]
+    RegisterTpmTis(BusError),
     #[error("error registering virtual socket device: {0}")]
     RegisterVsock(arch::DeviceRegistrationError),
     #[error("failed to set device attr: {0}")]
     SetDeviceAttr(base::Error),
+    #[error("failed to set a hardware breakpoint: {0}")]
+    SetHwBreakpoint(base::Error),
     #[error("failed to set register: {0}")]
     SetReg(base::Error),
     #[error("Timebase frequency too large")]
@@ -149,6 +190,12 @@ pub enum Error {
     Unsupported,
     #[error("failed to initialize VCPU: {0}")]
     VcpuInit(base::Error),
+    #[error("error writing guest memory: {0}")]
+    WriteGuestMemory(vm_memory::GuestMemoryError),
+    #[error("error writing CPU register: {0}")]
+    WriteReg(base::Error),
+    #[error("error writing CPU registers: {0}")]
+    WriteRegs(base::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -224,6 +271,20 @@ impl arch::LinuxArch for Riscv64 {
         )
         .map_err(Error::CreateSerialDevices)?;
 
+        let tpm_tis_base = match components.tpm.take() {
+            Some(backend) => {
+                mmio_bus
+                    .insert(
+                        Arc::new(Mutex::new(TpmTis::new(backend))),
+                        RISCV64_TPM_TIS_ADDR,
+                        TPM_TIS_MMIO_SIZE,
+                    )
+                    .map_err(Error::RegisterTpmTis)?;
+                Some(RISCV64_TPM_TIS_ADDR)
+            }
+            None => None,
+        };
+
         let (pci_devices, others): (Vec<_>, Vec<_>) = devices
             .into_iter()
             .partition(|(dev, _)| dev.as_pci_device().is_some());
@@ -294,38 +355,52 @@ impl arch::LinuxArch for Riscv64 {
 
         // separate out image loading from other setup to get a specific error for
         // image loading
-        let initrd;
-        let kernel_initrd_end = match components.vm_image {
-            VmImage::Bios(ref _bios) => {
-                return Err(Error::ImageTypeUnsupported);
+        let (entry_address, kernel_end) = match components.vm_image {
+            VmImage::Bios(ref mut bios) => {
+                arch::load_image(&mem, bios, get_bios_addr(), RISCV64_BIOS_MAX_LEN)
+                    .map_err(Error::BiosLoadFailure)?;
+                // The kernel, if any, is the payload that the firmware jumps to once it is done.
+                let payload_end = match components.bios_payload {
+                    Some(ref mut payload) => {
+                        let payload_size =
+                            arch::load_image(&mem, payload, get_kernel_addr(), u64::max_value())
+                                .map_err(Error::KernelLoadFailure)?;
+                        get_kernel_addr().offset() + payload_size as u64
+                    }
+                    None => get_kernel_addr().offset(),
+                };
+                (get_bios_addr(), payload_end)
             }
             VmImage::Kernel(ref mut kernel_image) => {
                 let kernel_size =
                     arch::load_image(&mem, kernel_image, get_kernel_addr(), u64::max_value())
                         .map_err(Error::KernelLoadFailure)?;
-                let kernel_end = get_kernel_addr().offset() + kernel_size as u64;
-                initrd = match components.initrd_image {
-                    Some(initrd_file) => {
-                        let mut initrd_file = initrd_file;
-                        let initrd_addr =
-                            (kernel_end + (RISCV64_INITRD_ALIGN - 1)) & !(RISCV64_INITRD_ALIGN - 1);
-                        let initrd_max_size =
-                            components.memory_size - (initrd_addr - RISCV64_PHYS_MEM_START);
-                        let initrd_addr = GuestAddress(initrd_addr);
-                        let initrd_size =
-                            arch::load_image(&mem, &mut initrd_file, initrd_addr, initrd_max_size)
-                                .map_err(Error::InitrdLoadFailure)?;
-                        Some((initrd_addr, initrd_size))
-                    }
-                    None => None,
-                };
-                if let Some((initrd_addr, initrd_size)) = initrd {
-                    initrd_addr.offset() + initrd_size as u64 - RISCV64_PHYS_MEM_START
-                } else {
-                    kernel_end - RISCV64_PHYS_MEM_START
-                }
+                (
+                    get_kernel_addr(),
+                    get_kernel_addr().offset() + kernel_size as u64,
+                )
             }
         };
+        let initrd = match components.initrd_image {
+            Some(initrd_file) => {
+                let mut initrd_file = initrd_file;
+                let initrd_addr =
+                    (kernel_end + (RISCV64_INITRD_ALIGN - 1)) & !(RISCV64_INITRD_ALIGN - 1);
+                let initrd_max_size =
+                    components.memory_size - (initrd_addr - RISCV64_PHYS_MEM_START);
+                let initrd_addr = GuestAddress(initrd_addr);
+                let initrd_size =
+                    arch::load_image(&mem, &mut initrd_file, initrd_addr, initrd_max_size)
+                        .map_err(Error::InitrdLoadFailure)?;
+                Some((initrd_addr, initrd_size))
+            }
+            None => None,
+        };
+        let kernel_initrd_end = if let Some((initrd_addr, initrd_size)) = initrd {
+            initrd_addr.offset() + initrd_size as u64 - RISCV64_PHYS_MEM_START
+        } else {
+            kernel_end - RISCV64_PHYS_MEM_START
+        };
 
         // Creates vcpus early as the irqchip needs them created to attach interrupts.
         let vcpu_count = components.vcpu_count;
@@ -336,10 +411,37 @@ impl arch::LinuxArch for Riscv64 {
                 .map_err(Error::CreateVcpu)?
                 .downcast::<Vcpu>()
                 .map_err(|_| Error::DowncastVcpu)?;
+            // Software breakpoints are `ebreak` instructions, which only exit to the debugger once
+            // guest debugging is enabled.
+            #[cfg(feature = "gdb")]
+            if components.gdb.is_some() {
+                vcpu.set_guest_debug().map_err(Error::EnableGuestDebug)?;
+            }
+            // The ISA extensions can only be changed before the VCPU first runs.
+            for (ext, enable) in components.cpu_isa.extensions() {
+                vcpu.set_one_reg(VcpuRegister::IsaExt(ext), enable as u64)
+                    .map_err(|e| {
+                        if enable {
+                            Error::EnableIsaExtension(ext.name(), e)
+                        } else {
+                            Error::DisableIsaExtension(ext.name(), e)
+                        }
+                    })?;
+            }
             vcpus.push(vcpu);
             vcpu_ids.push(vcpu_id);
         }
 
+        let isa_extensions = get_isa_extensions(&vcpus[0])?;
+        let cbom_block_size = if isa_extensions.contains(&IsaExtension::Zicbom) {
+            let block_size = vcpus[0]
+                .get_one_reg(VcpuRegister::Config(ConfigRegister::ZicbomBlockSize))
+                .map_err(Error::GetZicbomBlockSize)?;
+            Some(block_size as u32)
+        } else {
+            None
+        };
+
         irq_chip.finalize().map_err(Error::FinalizeIrqChip)?;
 
         irq_chip
@@ -386,14 +488,15 @@ impl arch::LinuxArch for Riscv64 {
             cmdline.as_str(),
             initrd,
             timebase_freq,
+            &isa_extensions,
+            cbom_block_size,
+            tpm_tis_base,
             device_tree_overlays,
         )
         .map_err(Error::CreateFdt)?;
 
-        let vcpu_init = vec![
-            VcpuInitRiscv64::new(GuestAddress(fdt_offset + RISCV64_PHYS_MEM_START));
-            vcpu_count
-        ];
+        let fdt_address = GuestAddress(fdt_offset + RISCV64_PHYS_MEM_START);
+        let vcpu_init = vec![VcpuInitRiscv64::new(entry_address, fdt_address); vcpu_count];
 
         Ok(RunnableLinuxVm {
             vm,
@@ -427,13 +530,16 @@ impl arch::LinuxArch for Riscv64 {
         _hypervisor: &dyn Hypervisor,
         _irq_chip: &mut dyn IrqChipRiscv64,
         vcpu: &mut dyn VcpuRiscv64,
-        _vcpu_init: VcpuInitRiscv64,
+        vcpu_init: VcpuInitRiscv64,
         vcpu_id: usize,
         _num_cpus: usize,
         cpu_config: Option<CpuConfigRiscv64>,
     ) -> std::result::Result<(), Self::Error> {
-        vcpu.set_one_reg(VcpuRegister::Core(CoreRegister::Pc), get_kernel_addr().0)
-            .map_err(Self::Error::SetReg)?;
+        vcpu.set_one_reg(
+            VcpuRegister::Core(CoreRegister::Pc),
+            vcpu_init.entry_address.offset(),
+        )
+        .map_err(Self::Error::SetReg)?;
         vcpu.set_one_reg(VcpuRegister::Core(CoreRegister::A0), vcpu_id as u64)
             .map_err(Self::Error::SetReg)?;
         vcpu.set_one_reg(
@@ -462,54 +568,171 @@ impl arch::LinuxArch for Riscv64 {
     }
 }
 
+// The core registers holding x1 to x31, in order. x0 is hardwired to zero.
+#[cfg(feature = "gdb")]
+const GDB_GPRS: [CoreRegister; 31] = [
+    CoreRegister::Ra,
+    CoreRegister::Sp,
+    CoreRegister::Gp,
+    CoreRegister::Tp,
+    CoreRegister::T0,
+    CoreRegister::T1,
+    CoreRegister::T2,
+    CoreRegister::S0,
+    CoreRegister::S1,
+    CoreRegister::A0,
+    CoreRegister::A1,
+    CoreRegister::A2,
+    CoreRegister::A3,
+    CoreRegister::A4,
+    CoreRegister::A5,
+    CoreRegister::A6,
+    CoreRegister::A7,
+    CoreRegister::S2,
+    CoreRegister::S3,
+    CoreRegister::S4,
+    CoreRegister::S5,
+    CoreRegister::S6,
+    CoreRegister::S7,
+    CoreRegister::S8,
+    CoreRegister::S9,
+    CoreRegister::S10,
+    CoreRegister::S11,
+    CoreRegister::T3,
+    CoreRegister::T4,
+    CoreRegister::T5,
+    CoreRegister::T6,
+];
+
+// Numbers of the floating point CSRs, which are fields of the fcsr register.
+#[cfg(feature = "gdb")]
+const CSR_FFLAGS: u16 = 0x001;
+#[cfg(feature = "gdb")]
+const CSR_FRM: u16 = 0x002;
+#[cfg(feature = "gdb")]
+const CSR_FCSR: u16 = 0x003;
+
+// Locates a GDB register as a field of a VCPU register, given by its mask and shift. Returns `None`
+// for the registers that aren't exposed by the hypervisor, and for x0.
+#[cfg(feature = "gdb")]
+fn gdb_register_field(reg_id: <GdbArch as Arch>::RegId) -> Option<(VcpuRegister, u64, u32)> {
+    match reg_id {
+        RiscvRegId::Gpr(n) => GDB_GPRS
+            .get(usize::from(n).checked_sub(1)?)
+            .map(|&reg| (VcpuRegister::Core(reg), u64::MAX, 0)),
+        RiscvRegId::Pc => Some((VcpuRegister::Core(CoreRegister::Pc), u64::MAX, 0)),
+        RiscvRegId::Fpr(n) if n < 32 => Some((VcpuRegister::FpD(n), u64::MAX, 0)),
+        RiscvRegId::Csr(CSR_FFLAGS) => Some((VcpuRegister::Fcsr, 0x1f, 0)),
+        RiscvRegId::Csr(CSR_FRM) => Some((VcpuRegister::Fcsr, 0x7, 5)),
+        RiscvRegId::Csr(CSR_FCSR) => Some((VcpuRegister::Fcsr, 0xff, 0)),
+        RiscvRegId::Priv => Some((VcpuRegister::Core(CoreRegister::Mode), u64::MAX, 0)),
+        _ => None,
+    }
+}
+
 #[cfg(feature = "gdb")]
 impl<T: VcpuRiscv64> arch::GdbOps<T> for Riscv64 {
     type Error = Error;
 
     fn read_memory(
         _vcpu: &T,
-        _guest_mem: &GuestMemory,
-        _vaddr: GuestAddress,
-        _len: usize,
+        guest_mem: &GuestMemory,
+        vaddr: GuestAddress,
+        len: usize,
     ) -> Result<Vec<u8>> {
-        unimplemented!();
+        let mut buf = vec![0; len];
+
+        guest_mem
+            .read_exact_at_addr(&mut buf, vaddr)
+            .map_err(Error::ReadGuestMemory)?;
+
+        Ok(buf)
     }
 
     fn write_memory(
         _vcpu: &T,
-        _guest_mem: &GuestMemory,
-        _vaddr: GuestAddress,
-        _buf: &[u8],
+        guest_mem: &GuestMemory,
+        vaddr: GuestAddress,
+        buf: &[u8],
     ) -> Result<()> {
-        unimplemented!();
+        guest_mem
+            .write_all_at_addr(buf, vaddr)
+            .map_err(Error::WriteGuestMemory)
     }
 
-    fn read_registers(_vcpu: &T) -> Result<<GdbArch as Arch>::Registers> {
-        unimplemented!();
+    fn read_registers(vcpu: &T) -> Result<<GdbArch as Arch>::Registers> {
+        let mut regs: <GdbArch as Arch>::Registers = Default::default();
+
+        for (x, reg) in regs.x.iter_mut().skip(1).zip(GDB_GPRS) {
+            *x = vcpu
+                .get_one_reg(VcpuRegister::Core(reg))
+                .map_err(Error::ReadRegs)?;
+        }
+        regs.pc = vcpu
+            .get_one_reg(VcpuRegister::Core(CoreRegister::Pc))
+            .map_err(Error::ReadRegs)?;
+
+        Ok(regs)
     }
 
-    fn write_registers(_vcpu: &T, _regs: &<GdbArch as Arch>::Registers) -> Result<()> {
-        unimplemented!();
+    fn write_registers(vcpu: &T, regs: &<GdbArch as Arch>::Registers) -> Result<()> {
+        for (x, reg) in regs.x.iter().skip(1).zip(GDB_GPRS) {
+            vcpu.set_one_reg(VcpuRegister::Core(reg), *x)
+                .map_err(Error::WriteRegs)?;
+        }
+        vcpu.set_one_reg(VcpuRegister::Core(CoreRegister::Pc), regs.pc)
+            .map_err(Error::WriteRegs)
     }
 
-    fn read_register(_vcpu: &T, _reg_id: <GdbArch as Arch>::RegId) -> Result<Vec<u8>> {
-        unimplemented!();
+    fn read_register(vcpu: &T, reg_id: <GdbArch as Arch>::RegId) -> Result<Vec<u8>> {
+        let value = match gdb_register_field(reg_id) {
+            Some((reg, mask, shift)) => {
+                (vcpu.get_one_reg(reg).map_err(Error::ReadReg)? >> shift) & mask
+            }
+            None if matches!(reg_id, RiscvRegId::Gpr(0)) => 0,
+            // Unavailable register
+            None => return Ok(Vec::new()),
+        };
+        Ok(value.to_le_bytes().to_vec())
     }
 
-    fn write_register(_vcpu: &T, _reg_id: <GdbArch as Arch>::RegId, _data: &[u8]) -> Result<()> {
-        unimplemented!();
+    fn write_register(vcpu: &T, reg_id: <GdbArch as Arch>::RegId, data: &[u8]) -> Result<()> {
+        let data: [u8; 8] = data
+            .get(..8)
+            .and_then(|d| d.try_into().ok())
+            .ok_or(Error::WriteReg(base::Error::new(libc::ENOBUFS)))?;
+        let value = u64::from_le_bytes(data);
+        let (reg, mask, shift) = match gdb_register_field(reg_id) {
+            Some(field) => field,
+            // Writes to x0 are ignored, like by the hardware.
+            None if matches!(reg_id, RiscvRegId::Gpr(0)) => return Ok(()),
+            None => return Err(Error::WriteReg(base::Error::new(libc::ENOENT))),
+        };
+        let value = if mask == u64::MAX {
+            value
+        } else {
+            let current = vcpu.get_one_reg(reg).map_err(Error::ReadReg)?;
+            (current & !(mask << shift)) | ((value & mask) << shift)
+        };
+        vcpu.set_one_reg(reg, value).map_err(Error::WriteReg)
     }
 
     fn enable_singlestep(_vcpu: &T) -> Result<()> {
-        unimplemented!();
+        // KVM ignores `KVM_GUESTDBG_SINGLESTEP` on riscv64, so the VCPU would simply resume.
+        Err(Error::EnableSinglestep(base::Error::new(libc::ENOTSUP)))
     }
 
     fn get_max_hw_breakpoints(_vcpu: &T) -> Result<usize> {
-        unimplemented!();
+        // KVM doesn't give the host access to the trigger module, so only software breakpoints,
+        // which GDB writes as `ebreak` instructions in guest memory, are supported.
+        Ok(0)
     }
 
-    fn set_hw_breakpoints(_vcpu: &T, _breakpoints: &[GuestAddress]) -> Result<()> {
-        unimplemented!();
+    fn set_hw_breakpoints(vcpu: &T, breakpoints: &[GuestAddress]) -> Result<()> {
+        if !breakpoints.is_empty() {
+            return Err(Error::SetHwBreakpoint(base::Error::new(libc::EINVAL)));
+        }
+        vcpu.set_guest_debug().map_err(Error::SetHwBreakpoint)
     }
 }
 
@@ -527,6 +750,21 @@ fn get_high_mmio_base_size(mem_size: u64, guest_phys_addr_bits: u8) -> (u64, u64
     (high_mmio_base, size)
 }
 
+// Returns the ISA extensions that are enabled in `vcpu`, in the order of `IsaExtension::ALL`.
+fn get_isa_extensions(vcpu: &dyn VcpuRiscv64) -> Result<Vec<IsaExtension>> {
+    let mut extensions = Vec::new();
+    for ext in IsaExtension::ALL {
+        match vcpu.get_one_reg(VcpuRegister::IsaExt(ext)) {
+            Ok(0) => {}
+            Ok(_) => extensions.push(ext),
+            // Extensions that the host kernel doesn't know about are never enabled.
+            Err(e) if e.errno() == libc::ENOENT => {}
+            Err(e) => return Err(Error::GetIsaExtension(ext.name(), e)),
+        }
+    }
+    Ok(extensions)
+}
+
 fn get_base_linux_cmdline() -> kernel_cmdline::Cmdline {
     let mut cmdline = kernel_cmdline::Cmdline::new(base::pagesize());
     cmdline.insert_str("panic=-1").unwrap();
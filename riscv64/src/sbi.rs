@@ -0,0 +1,441 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Handling of the Supervisor Binary Interface (SBI) calls that KVM forwards to userspace.
+//!
+//! KVM implements most SBI extensions in the kernel, and forwards the others to crosvm. The
+//! `SbiDispatcher` implements the legacy console, Debug Console (DBCN) and System Reset (SRST)
+//! extensions, routes experimental and vendor extensions to the handlers registered for them, and
+//! fails the calls to any other extension with `SBI_ERR_NOT_SUPPORTED`.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use arch::SERIAL_ADDR;
+use base::info;
+use base::warn;
+use devices::serial_device::SerialHardware;
+use devices::serial_device::SerialParameters;
+use devices::Bus;
+use sync::Mutex;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+/// Legacy Console Putchar extension.
+pub const SBI_EXT_0_1_CONSOLE_PUTCHAR: u64 = 0x01;
+/// Legacy Console Getchar extension.
+pub const SBI_EXT_0_1_CONSOLE_GETCHAR: u64 = 0x02;
+/// System Reset extension.
+pub const SBI_EXT_SRST: u64 = 0x5352_5354;
+/// Debug Console extension.
+pub const SBI_EXT_DBCN: u64 = 0x4442_434E;
+/// Extension IDs reserved for experimental extensions.
+pub const SBI_EXT_EXPERIMENTAL_START: u64 = 0x0800_0000;
+pub const SBI_EXT_EXPERIMENTAL_END: u64 = 0x08FF_FFFF;
+/// Extension IDs reserved for vendor specific extensions.
+pub const SBI_EXT_VENDOR_START: u64 = 0x0900_0000;
+pub const SBI_EXT_VENDOR_END: u64 = 0x09FF_FFFF;
+
+const SBI_EXT_SRST_RESET: u64 = 0;
+const SBI_SRST_RESET_TYPE_SHUTDOWN: u64 = 0;
+const SBI_SRST_RESET_TYPE_COLD_REBOOT: u64 = 1;
+const SBI_SRST_RESET_TYPE_WARM_REBOOT: u64 = 2;
+const SBI_SRST_RESET_REASON_NONE: u64 = 0;
+const SBI_SRST_RESET_REASON_SYSFAIL: u64 = 1;
+
+const SBI_EXT_DBCN_CONSOLE_WRITE: u64 = 0;
+const SBI_EXT_DBCN_CONSOLE_READ: u64 = 1;
+const SBI_EXT_DBCN_CONSOLE_WRITE_BYTE: u64 = 2;
+
+/// Standard SBI error codes.
+pub const SBI_SUCCESS: i64 = 0;
+pub const SBI_ERR_FAILED: i64 = -1;
+pub const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+pub const SBI_ERR_INVALID_PARAM: i64 = -3;
+pub const SBI_ERR_DENIED: i64 = -4;
+pub const SBI_ERR_INVALID_ADDRESS: i64 = -5;
+
+// Largest number of bytes read or written by a single Debug Console call. The guest retries with
+// the remaining bytes.
+const DBCN_MAX_TRANSFER: usize = 4096;
+
+// Registers of the 8250 UART backing the console.
+const UART_DATA: u64 = 0;
+const UART_LSR: u64 = 5;
+const UART_LSR_DATA_READY: u8 = 0x01;
+
+/// Values returned to the guest by a SBI call, in its `a0` and `a1` registers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SbiRet {
+    pub error: i64,
+    pub value: u64,
+}
+
+impl SbiRet {
+    /// Returns `value` to the guest with `SBI_SUCCESS`.
+    pub fn success(value: u64) -> Self {
+        SbiRet {
+            error: SBI_SUCCESS,
+            value,
+        }
+    }
+
+    /// Returns the `error` SBI error code to the guest.
+    pub fn error(error: i64) -> Self {
+        SbiRet { error, value: 0 }
+    }
+}
+
+/// What the vCPU should do after a SBI call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SbiAction {
+    /// Return to the guest with the given values.
+    Return(SbiRet),
+    /// Shut the VM down.
+    Shutdown,
+    /// Reset the VM.
+    Reset,
+}
+
+/// A SBI call, as forwarded by the hypervisor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SbiCall {
+    pub extension_id: u64,
+    pub function_id: u64,
+    pub args: [u64; 6],
+}
+
+/// A handler for the calls to an experimental or vendor SBI extension.
+pub trait SbiExtensionHandler: Send + Sync {
+    /// Handles `call` from the vCPU `cpu_id`.
+    fn handle(&self, cpu_id: usize, call: &SbiCall) -> SbiAction;
+}
+
+/// Dispatches the SBI calls forwarded by the hypervisor to their extension.
+///
+/// The dispatcher is shared by all the vCPUs of a VM.
+pub struct SbiDispatcher {
+    mem: GuestMemory,
+    mmio_bus: Bus,
+    // Address of the UART used by the console extensions, if any.
+    console: Option<u64>,
+    // Serializes the accesses to the console, so that the output of vCPUs isn't interleaved.
+    console_lock: Mutex<()>,
+    extension_handlers: BTreeMap<u64, Arc<dyn SbiExtensionHandler>>,
+}
+
+impl SbiDispatcher {
+    /// Creates a dispatcher of the SBI calls of a VM.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - The memory of the VM, where console buffers are read and written.
+    /// * `mmio_bus` - The MMIO bus of the VM, which has the serial devices.
+    /// * `serial_parameters` - The parameters of the serial devices, used to find the console.
+    pub fn new(
+        mem: GuestMemory,
+        mmio_bus: Bus,
+        serial_parameters: &BTreeMap<(SerialHardware, u8), SerialParameters>,
+    ) -> Self {
+        SbiDispatcher {
+            mem,
+            mmio_bus,
+            console: console_address(serial_parameters),
+            console_lock: Mutex::new(()),
+            extension_handlers: BTreeMap::new(),
+        }
+    }
+
+    /// Routes the calls to the experimental or vendor extension `extension_id` to `handler`,
+    /// replacing any handler previously registered for it.
+    ///
+    /// Returns `false` if `extension_id` isn't in the experimental or vendor ranges, whose
+    /// handlers can't be replaced.
+    pub fn register_extension_handler(
+        &mut self,
+        extension_id: u64,
+        handler: Arc<dyn SbiExtensionHandler>,
+    ) -> bool {
+        if !is_custom_extension(extension_id) {
+            return false;
+        }
+        self.extension_handlers.insert(extension_id, handler);
+        true
+    }
+
+    /// Handles `call` from the vCPU `cpu_id`.
+    pub fn handle(&self, cpu_id: usize, call: &SbiCall) -> SbiAction {
+        let ret = match call.extension_id {
+            SBI_EXT_0_1_CONSOLE_PUTCHAR => self.legacy_console_putchar(call.args[0] as u8),
+            SBI_EXT_0_1_CONSOLE_GETCHAR => self.legacy_console_getchar(),
+            SBI_EXT_DBCN => self.debug_console(call),
+            SBI_EXT_SRST => return self.system_reset(cpu_id, call),
+            id if is_custom_extension(id) => match self.extension_handlers.get(&id) {
+                Some(handler) => return handler.handle(cpu_id, call),
+                None => SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+            },
+            _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+        };
+        SbiAction::Return(ret)
+    }
+
+    fn system_reset(&self, cpu_id: usize, call: &SbiCall) -> SbiAction {
+        if call.function_id != SBI_EXT_SRST_RESET {
+            return SbiAction::Return(SbiRet::error(SBI_ERR_NOT_SUPPORTED));
+        }
+        let (reset_type, reset_reason) = (call.args[0] as u32 as u64, call.args[1] as u32 as u64);
+        let reason = match reset_reason {
+            SBI_SRST_RESET_REASON_NONE => "no reason",
+            SBI_SRST_RESET_REASON_SYSFAIL => "system failure",
+            _ => "unknown reason",
+        };
+        match reset_type {
+            SBI_SRST_RESET_TYPE_SHUTDOWN => {
+                info!("system shutdown on vcpu {}: {}", cpu_id, reason);
+                SbiAction::Shutdown
+            }
+            SBI_SRST_RESET_TYPE_COLD_REBOOT | SBI_SRST_RESET_TYPE_WARM_REBOOT => {
+                info!("system reset on vcpu {}: {}", cpu_id, reason);
+                SbiAction::Reset
+            }
+            _ => SbiAction::Return(SbiRet::error(SBI_ERR_INVALID_PARAM)),
+        }
+    }
+
+    fn debug_console(&self, call: &SbiCall) -> SbiRet {
+        let console = match self.console {
+            Some(console) => console,
+            None => return SbiRet::error(SBI_ERR_FAILED),
+        };
+        match call.function_id {
+            SBI_EXT_DBCN_CONSOLE_WRITE | SBI_EXT_DBCN_CONSOLE_READ => {
+                let num_bytes = (call.args[0] as usize).min(DBCN_MAX_TRANSFER);
+                // On RV64, the upper XLEN bits of the address must be zero.
+                if call.args[2] != 0 {
+                    return SbiRet::error(SBI_ERR_INVALID_PARAM);
+                }
+                let addr = GuestAddress(call.args[1]);
+                let _console_lock = self.console_lock.lock();
+                if call.function_id == SBI_EXT_DBCN_CONSOLE_WRITE {
+                    let mut buf = vec![0; num_bytes];
+                    if self.mem.read_exact_at_addr(&mut buf, addr).is_err() {
+                        return SbiRet::error(SBI_ERR_INVALID_PARAM);
+                    }
+                    for byte in buf {
+                        self.write_console(console, byte);
+                    }
+                    SbiRet::success(num_bytes as u64)
+                } else {
+                    let mut buf = Vec::with_capacity(num_bytes);
+                    while buf.len() < num_bytes {
+                        match self.read_console(console) {
+                            Some(byte) => buf.push(byte),
+                            None => break,
+                        }
+                    }
+                    if self.mem.write_all_at_addr(&buf, addr).is_err() {
+                        return SbiRet::error(SBI_ERR_INVALID_PARAM);
+                    }
+                    SbiRet::success(buf.len() as u64)
+                }
+            }
+            SBI_EXT_DBCN_CONSOLE_WRITE_BYTE => {
+                let _console_lock = self.console_lock.lock();
+                self.write_console(console, call.args[0] as u8);
+                SbiRet::success(0)
+            }
+            _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+
+    // The legacy extensions return their value in `a0`, where other extensions return the error.
+    fn legacy_console_putchar(&self, byte: u8) -> SbiRet {
+        if let Some(console) = self.console {
+            let _console_lock = self.console_lock.lock();
+            self.write_console(console, byte);
+        }
+        SbiRet::error(0)
+    }
+
+    fn legacy_console_getchar(&self) -> SbiRet {
+        let byte = self.console.and_then(|console| {
+            let _console_lock = self.console_lock.lock();
+            self.read_console(console)
+        });
+        SbiRet::error(byte.map_or(-1, i64::from))
+    }
+
+    fn write_console(&self, console: u64, byte: u8) {
+        if !self.mmio_bus.write(console + UART_DATA, &[byte]) {
+            warn!("failed to write to the SBI console at {:#x}", console);
+        }
+    }
+
+    // Returns the next byte received by the console, if there is one.
+    fn read_console(&self, console: u64) -> Option<u8> {
+        let mut lsr = [0u8];
+        if !self.mmio_bus.read(console + UART_LSR, &mut lsr) || lsr[0] & UART_LSR_DATA_READY == 0 {
+            return None;
+        }
+        let mut data = [0u8];
+        self.mmio_bus.read(console + UART_DATA, &mut data);
+        Some(data[0])
+    }
+}
+
+fn is_custom_extension(extension_id: u64) -> bool {
+    (SBI_EXT_EXPERIMENTAL_START..=SBI_EXT_EXPERIMENTAL_END).contains(&extension_id)
+        || (SBI_EXT_VENDOR_START..=SBI_EXT_VENDOR_END).contains(&extension_id)
+}
+
+// Returns the address of the serial device used as console by the guest, or of the first serial
+// device if none is.
+fn console_address(
+    serial_parameters: &BTreeMap<(SerialHardware, u8), SerialParameters>,
+) -> Option<u64> {
+    let num = serial_parameters
+        .iter()
+        .find(|((hardware, _), param)| *hardware == SerialHardware::Serial && param.console)
+        .or_else(|| {
+            serial_parameters.iter().find(|((hardware, _), param)| {
+                *hardware == SerialHardware::Serial && param.earlycon
+            })
+        })
+        .map_or(1, |((_, num), _)| *num);
+    SERIAL_ADDR.get(usize::from(num).checked_sub(1)?).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use devices::BusType;
+
+    use super::*;
+
+    const CONSOLE_WRITE_BUFFER: u64 = 0x1000;
+
+    struct TestHandler;
+
+    impl SbiExtensionHandler for TestHandler {
+        fn handle(&self, cpu_id: usize, call: &SbiCall) -> SbiAction {
+            SbiAction::Return(SbiRet::success(cpu_id as u64 + call.args[0]))
+        }
+    }
+
+    fn dispatcher() -> SbiDispatcher {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        SbiDispatcher::new(mem, Bus::new(BusType::Mmio), &BTreeMap::new())
+    }
+
+    fn call(extension_id: u64, function_id: u64, args: &[u64]) -> SbiCall {
+        let mut call = SbiCall {
+            extension_id,
+            function_id,
+            args: [0; 6],
+        };
+        call.args[..args.len()].copy_from_slice(args);
+        call
+    }
+
+    #[test]
+    fn unknown_extension() {
+        let dispatcher = dispatcher();
+        for extension_id in [0x10, 0x4853_4D, SBI_EXT_VENDOR_START, 0xFFFF_FFFF] {
+            assert_eq!(
+                dispatcher.handle(0, &call(extension_id, 0, &[])),
+                SbiAction::Return(SbiRet::error(SBI_ERR_NOT_SUPPORTED))
+            );
+        }
+    }
+
+    #[test]
+    fn system_reset() {
+        let dispatcher = dispatcher();
+        assert_eq!(
+            dispatcher.handle(0, &call(SBI_EXT_SRST, 0, &[0, 0])),
+            SbiAction::Shutdown
+        );
+        assert_eq!(
+            dispatcher.handle(1, &call(SBI_EXT_SRST, 0, &[1, 1])),
+            SbiAction::Reset
+        );
+        assert_eq!(
+            dispatcher.handle(0, &call(SBI_EXT_SRST, 0, &[2, 0])),
+            SbiAction::Reset
+        );
+        assert_eq!(
+            dispatcher.handle(0, &call(SBI_EXT_SRST, 0, &[0xF000_0000, 0])),
+            SbiAction::Return(SbiRet::error(SBI_ERR_INVALID_PARAM))
+        );
+        assert_eq!(
+            dispatcher.handle(0, &call(SBI_EXT_SRST, 1, &[0, 0])),
+            SbiAction::Return(SbiRet::error(SBI_ERR_NOT_SUPPORTED))
+        );
+    }
+
+    #[test]
+    fn debug_console_invalid_buffer() {
+        let dispatcher = dispatcher();
+        // Out of the guest memory.
+        assert_eq!(
+            dispatcher.handle(
+                0,
+                &call(SBI_EXT_DBCN, SBI_EXT_DBCN_CONSOLE_WRITE, &[16, 0x20000, 0])
+            ),
+            SbiAction::Return(SbiRet::error(SBI_ERR_INVALID_PARAM))
+        );
+        // Above 64 bits.
+        assert_eq!(
+            dispatcher.handle(
+                0,
+                &call(
+                    SBI_EXT_DBCN,
+                    SBI_EXT_DBCN_CONSOLE_WRITE,
+                    &[16, CONSOLE_WRITE_BUFFER, 1]
+                )
+            ),
+            SbiAction::Return(SbiRet::error(SBI_ERR_INVALID_PARAM))
+        );
+        // Written to a missing UART, which doesn't stop the call.
+        assert_eq!(
+            dispatcher.handle(
+                0,
+                &call(
+                    SBI_EXT_DBCN,
+                    SBI_EXT_DBCN_CONSOLE_WRITE,
+                    &[16, CONSOLE_WRITE_BUFFER, 0]
+                )
+            ),
+            SbiAction::Return(SbiRet::success(16))
+        );
+        // Nothing to read from a missing UART.
+        assert_eq!(
+            dispatcher.handle(
+                0,
+                &call(
+                    SBI_EXT_DBCN,
+                    SBI_EXT_DBCN_CONSOLE_READ,
+                    &[16, CONSOLE_WRITE_BUFFER, 0]
+                )
+            ),
+            SbiAction::Return(SbiRet::success(0))
+        );
+    }
+
+    #[test]
+    fn extension_handler() {
+        let mut dispatcher = dispatcher();
+        assert!(!dispatcher.register_extension_handler(SBI_EXT_SRST, Arc::new(TestHandler)));
+        assert!(
+            dispatcher.register_extension_handler(SBI_EXT_VENDOR_START + 1, Arc::new(TestHandler))
+        );
+        assert_eq!(
+            dispatcher.handle(2, &call(SBI_EXT_VENDOR_START + 1, 0, &[40])),
+            SbiAction::Return(SbiRet::success(42))
+        );
+        assert_eq!(
+            dispatcher.handle(2, &call(SBI_EXT_VENDOR_START + 2, 0, &[40])),
+            SbiAction::Return(SbiRet::error(SBI_ERR_NOT_SUPPORTED))
+        );
+    }
+}
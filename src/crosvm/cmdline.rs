@@ -13,6 +13,8 @@ cfg_if::cfg_if! {
 }
 
 use std::collections::BTreeMap;
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+use std::num::NonZeroU32;
 #[cfg(feature = "config-file")]
 use std::path::Path;
 use std::path::PathBuf;
@@ -22,12 +24,18 @@ use std::sync::atomic::Ordering;
 
 use arch::CpuSet;
 use arch::Pstore;
+#[cfg(target_arch = "riscv64")]
+use arch::RiscvIsaOptions;
 #[cfg(target_arch = "x86_64")]
 use arch::SmbiosOptions;
 use arch::VcpuAffinity;
 use argh::FromArgs;
 use base::getpid;
 use cros_async::ExecutorKind;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use devices::tpm::TpmInterface;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use devices::tpm::TpmParameters;
 use devices::virtio::block::DiskOption;
 #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
 use devices::virtio::device_constants::video::VideoDeviceConfig;
@@ -63,6 +71,9 @@ use serde::Deserializer;
 use serde::Serialize;
 #[cfg(feature = "gpu")]
 use serde_keyvalue::FromKeyValues;
+use vm_control::input::TouchStroke;
+use vm_control::migration::MigrationAddress;
+use vm_memory::MemoryCompression;
 
 #[cfg(feature = "gpu")]
 use super::gpu_config::fixup_gpu_display_options;
@@ -71,7 +82,6 @@ use super::gpu_config::fixup_gpu_options;
 #[cfg(all(feature = "gpu", feature = "virgl_renderer"))]
 use super::sys::GpuRenderServerParameters;
 use crate::crosvm::config::from_key_values;
-use crate::crosvm::config::parse_bus_id_addr;
 use crate::crosvm::config::parse_cpu_affinity;
 use crate::crosvm::config::parse_cpu_capacity;
 use crate::crosvm::config::parse_dynamic_power_coefficient;
@@ -80,7 +90,11 @@ use crate::crosvm::config::parse_memory_region;
 use crate::crosvm::config::parse_mmio_address_range;
 use crate::crosvm::config::parse_pflash_parameters;
 use crate::crosvm::config::parse_serial_options;
+use crate::crosvm::config::parse_touch_stroke;
+use crate::crosvm::config::parse_usbip_address;
 use crate::crosvm::config::parse_vhost_user_fs_option;
+#[cfg(all(feature = "vnc", any(target_os = "android", target_os = "linux")))]
+use crate::crosvm::config::parse_vnc_address;
 use crate::crosvm::config::BatteryConfig;
 use crate::crosvm::config::CpuOptions;
 use crate::crosvm::config::DtboOption;
@@ -93,6 +107,8 @@ use crate::crosvm::config::TouchDeviceOption;
 use crate::crosvm::config::VhostUserFrontendOption;
 use crate::crosvm::config::VhostUserFsOption;
 use crate::crosvm::config::VhostUserOption;
+#[cfg(all(feature = "vnc", any(target_os = "android", target_os = "linux")))]
+use crate::crosvm::config::VncAddress;
 #[cfg(feature = "plugin")]
 use crate::crosvm::plugin::parse_plugin_mount_option;
 #[cfg(feature = "plugin")]
@@ -141,9 +157,12 @@ pub enum CrossPlatformCommands {
     Disk(DiskCommand),
     #[cfg(feature = "gpu")]
     Gpu(GpuCommand),
+    Input(InputCommand),
     MakeRT(MakeRTCommand),
+    Migrate(MigrateCommand),
     Resume(ResumeCommand),
     Run(RunCommand),
+    Scsi(ScsiCommand),
     Stop(StopCommand),
     Suspend(SuspendCommand),
     Swap(SwapCommand),
@@ -237,18 +256,25 @@ pub struct CreateQcow2Command {
     /// path to the new qcow2 file to create
     pub file_path: String,
     #[argh(positional, arg_name = "SIZE")]
-    /// desired size of the image in bytes; required if not using --backing-file
+    /// desired size of the image in bytes; required if not using --backing-file or
+    /// --compress-from
     pub size: Option<u64>,
     #[argh(option)]
     /// path to backing file; if specified, the image will be the same size as the backing file, and
     /// SIZE may not be specified
     pub backing_file: Option<String>,
+    #[argh(option, arg_name = "PATH")]
+    /// path to a raw image to copy into the new image as compressed clusters; if specified, the
+    /// image will be the same size as the raw image, and SIZE may not be specified
+    pub compress_from: Option<String>,
 }
 
 #[derive(FromArgs)]
 #[argh(subcommand)]
 pub enum DiskSubcommand {
     Resize(ResizeDiskSubcommand),
+    Snapshot(SnapshotDiskSubcommand),
+    Throttle(ThrottleDiskSubcommand),
 }
 
 #[derive(FromArgs)]
@@ -266,6 +292,118 @@ pub struct ResizeDiskSubcommand {
     pub socket_path: String,
 }
 
+#[derive(FromArgs)]
+/// manage internal snapshots of a qcow2 disk (suspend the VM before applying one)
+#[argh(subcommand, name = "snapshot")]
+pub struct SnapshotDiskSubcommand {
+    #[argh(subcommand)]
+    pub nested: SnapshotDiskSubcommands,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum SnapshotDiskSubcommands {
+    List(ListDiskSnapshotsCommand),
+    Create(CreateDiskSnapshotCommand),
+    Apply(ApplyDiskSnapshotCommand),
+    Delete(DeleteDiskSnapshotCommand),
+}
+
+#[derive(FromArgs)]
+/// list the internal snapshots of a disk
+#[argh(subcommand, name = "list")]
+pub struct ListDiskSnapshotsCommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// create an internal snapshot of a disk
+#[argh(subcommand, name = "create")]
+pub struct CreateDiskSnapshotCommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "NAME")]
+    /// snapshot name
+    pub name: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// revert a disk to an internal snapshot
+#[argh(subcommand, name = "apply")]
+pub struct ApplyDiskSnapshotCommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "NAME")]
+    /// snapshot name or ID
+    pub name: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// delete an internal snapshot of a disk
+#[argh(subcommand, name = "delete")]
+pub struct DeleteDiskSnapshotCommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "NAME")]
+    /// snapshot name or ID
+    pub name: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// set the I/O limits of a disk, replacing the current ones (limits that aren't given are removed)
+#[argh(subcommand, name = "throttle")]
+pub struct ThrottleDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(option, arg_name = "N")]
+    /// maximum number of read and write requests per second
+    pub iops: Option<u64>,
+    #[argh(option, arg_name = "N")]
+    /// number of requests that can be sent at once when below the iops limit (default: iops)
+    pub iops_burst: Option<u64>,
+    #[argh(option, arg_name = "BYTES")]
+    /// maximum number of bytes read and written per second
+    pub bps: Option<u64>,
+    #[argh(option, arg_name = "BYTES")]
+    /// number of bytes that can be transferred at once when below the bps limit (default: bps)
+    pub bps_burst: Option<u64>,
+    #[argh(option, arg_name = "BYTES")]
+    /// maximum number of bytes read per second
+    pub read_bps: Option<u64>,
+    #[argh(option, arg_name = "BYTES")]
+    /// number of bytes that can be read at once when below the read-bps limit
+    /// (default: read-bps)
+    pub read_bps_burst: Option<u64>,
+    #[argh(option, arg_name = "BYTES")]
+    /// maximum number of bytes written per second
+    pub write_bps: Option<u64>,
+    #[argh(option, arg_name = "BYTES")]
+    /// number of bytes that can be written at once when below the write-bps limit
+    /// (default: write-bps)
+    pub write_bps_burst: Option<u64>,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "disk")]
 /// Manage attached virtual disk devices
@@ -412,6 +550,238 @@ pub struct GpeCommand {
     pub socket_path: String,
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand, name = "scsi")]
+/// Manage the logical units of the virtio-scsi controller and the media of its CD-ROM drives.
+pub struct ScsiCommand {
+    #[argh(subcommand)]
+    pub command: ScsiSubCommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum ScsiSubCommand {
+    Attach(ScsiAttachCommand),
+    Detach(ScsiDetachCommand),
+    Insert(ScsiInsertCommand),
+    Eject(ScsiEjectCommand),
+}
+
+#[derive(FromArgs)]
+/// Attach a disk image to a SCSI logical unit
+#[argh(subcommand, name = "attach")]
+pub struct ScsiAttachCommand {
+    #[argh(option, default = "0")]
+    /// target of the logical unit (default: 0)
+    pub target: u8,
+    #[argh(option, default = "0")]
+    /// LUN of the logical unit in its target (default: 0)
+    pub lun: u16,
+    #[argh(option, default = "512")]
+    /// block size of the logical unit (default: 512)
+    pub block_size: u32,
+    #[argh(switch)]
+    /// attach the disk image read only
+    pub ro: bool,
+    #[argh(switch)]
+    /// attach a CD-ROM drive with the disk image as its medium
+    pub cdrom: bool,
+    #[argh(positional, arg_name = "PATH")]
+    /// disk image path
+    pub path: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Detach a SCSI logical unit
+#[argh(subcommand, name = "detach")]
+pub struct ScsiDetachCommand {
+    #[argh(option, default = "0")]
+    /// target of the logical unit (default: 0)
+    pub target: u8,
+    #[argh(option, default = "0")]
+    /// LUN of the logical unit in its target (default: 0)
+    pub lun: u16,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Insert a disk image in a SCSI CD-ROM drive, replacing its medium
+#[argh(subcommand, name = "insert")]
+pub struct ScsiInsertCommand {
+    #[argh(option, default = "0")]
+    /// target of the CD-ROM drive (default: 0)
+    pub target: u8,
+    #[argh(option, default = "0")]
+    /// LUN of the CD-ROM drive in its target (default: 0)
+    pub lun: u16,
+    #[argh(positional, arg_name = "PATH")]
+    /// disk image path
+    pub path: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Eject the medium of a SCSI CD-ROM drive
+#[argh(subcommand, name = "eject")]
+pub struct ScsiEjectCommand {
+    #[argh(option, default = "0")]
+    /// target of the CD-ROM drive (default: 0)
+    pub target: u8,
+    #[argh(option, default = "0")]
+    /// LUN of the CD-ROM drive in its target (default: 0)
+    pub lun: u16,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "input")]
+/// Inject keyboard, pointer and touch events through the virtio-input devices.
+pub struct InputCommand {
+    #[argh(subcommand)]
+    pub command: InputSubCommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum InputSubCommand {
+    List(InputListCommand),
+    Key(InputKeyCommand),
+    Text(InputTextCommand),
+    Move(InputMoveCommand),
+    Click(InputClickCommand),
+    Touch(InputTouchCommand),
+    Replay(InputReplayCommand),
+}
+
+#[derive(FromArgs)]
+/// List the virtio-input devices and their indices
+#[argh(subcommand, name = "list")]
+pub struct InputListCommand {
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Press a key or a combination of keys, then release them
+#[argh(subcommand, name = "key")]
+pub struct InputKeyCommand {
+    #[argh(option, default = "0")]
+    /// index of the input device, as listed by `crosvm input list` (default: 0)
+    pub device: usize,
+    #[argh(option, default = "20")]
+    /// milliseconds between the presses and releases of the keys (default: 20)
+    pub interval_ms: u64,
+    #[argh(positional, arg_name = "KEYS")]
+    /// keys joined by `+`, named after their Linux key code like `leftctrl+c` or `KEY_F1`
+    pub keys: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Type a string with a US keyboard layout
+#[argh(subcommand, name = "text")]
+pub struct InputTextCommand {
+    #[argh(option, default = "0")]
+    /// index of the input device, as listed by `crosvm input list` (default: 0)
+    pub device: usize,
+    #[argh(option, default = "20")]
+    /// milliseconds between the presses and releases of the keys (default: 20)
+    pub interval_ms: u64,
+    #[argh(positional, arg_name = "TEXT")]
+    /// text to type
+    pub text: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Move a pointer by a relative amount, or to an absolute position
+#[argh(subcommand, name = "move")]
+pub struct InputMoveCommand {
+    #[argh(option, default = "0")]
+    /// index of the input device, as listed by `crosvm input list` (default: 0)
+    pub device: usize,
+    #[argh(option, default = "0")]
+    /// horizontal motion, or position with --absolute (default: 0)
+    pub x: i32,
+    #[argh(option, default = "0")]
+    /// vertical motion, or position with --absolute (default: 0)
+    pub y: i32,
+    #[argh(switch)]
+    /// move an absolute pointer to the position X, Y
+    pub absolute: bool,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Press a mouse button, then release it
+#[argh(subcommand, name = "click")]
+pub struct InputClickCommand {
+    #[argh(option, default = "0")]
+    /// index of the input device, as listed by `crosvm input list` (default: 0)
+    pub device: usize,
+    #[argh(option, default = "String::from(\"left\")")]
+    /// left, right, middle, side, extra or the name of a key (default: left)
+    pub button: String,
+    #[argh(option, default = "20")]
+    /// milliseconds to hold the button down (default: 20)
+    pub hold_ms: u64,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Perform a touch gesture, moving fingers along straight strokes at the same time
+#[argh(subcommand, name = "touch")]
+pub struct InputTouchCommand {
+    #[argh(option, default = "0")]
+    /// index of the input device, as listed by `crosvm input list` (default: 0)
+    pub device: usize,
+    #[argh(option, arg_name = "X,Y[:X,Y]", from_str_fn(parse_touch_stroke))]
+    /// stroke of a finger from its start to its optional end, repeated for each finger (at least one)
+    pub finger: Vec<TouchStroke>,
+    #[argh(option, default = "200")]
+    /// duration of the gesture in milliseconds (default: 200)
+    pub duration_ms: u64,
+    #[argh(option, default = "10")]
+    /// number of motion steps of the fingers (default: 10)
+    pub steps: u32,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Replay the events recorded by evemu-record, with their original timing
+#[argh(subcommand, name = "replay")]
+pub struct InputReplayCommand {
+    #[argh(option, default = "0")]
+    /// index of the input device, as listed by `crosvm input list` (default: 0)
+    pub device: usize,
+    #[argh(positional, arg_name = "PATH")]
+    /// path of the event file
+    pub path: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "usb")]
 /// Manage attached virtual USB devices.
@@ -555,6 +925,11 @@ pub enum GpuSubCommand {
     AddDisplays(GpuAddDisplaysCommand),
     ListDisplays(GpuListDisplaysCommand),
     RemoveDisplays(GpuRemoveDisplaysCommand),
+    Screenshot(GpuScreenshotCommand),
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    StartCapture(GpuStartCaptureCommand),
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    StopCapture(GpuStopCaptureCommand),
 }
 
 #[cfg(feature = "gpu")]
@@ -594,6 +969,54 @@ pub struct GpuRemoveDisplaysCommand {
     pub socket_path: String,
 }
 
+#[cfg(feature = "gpu")]
+#[derive(FromArgs)]
+/// Save the current contents of a display of the GPU device as a PNG image.
+#[argh(subcommand, name = "screenshot")]
+pub struct GpuScreenshotCommand {
+    #[argh(option, arg_name = "N", default = "0")]
+    /// display id (default: 0)
+    pub display: u32,
+    #[argh(option, arg_name = "PATH")]
+    /// path of the PNG image to write
+    pub out: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+#[derive(FromArgs)]
+/// Record every Nth frame flushed to a display of the GPU device as PNG images in a directory.
+#[argh(subcommand, name = "start-capture")]
+pub struct GpuStartCaptureCommand {
+    #[argh(option, arg_name = "N", default = "0")]
+    /// display id (default: 0)
+    pub display: u32,
+    #[argh(option, arg_name = "N", default = "NonZeroU32::new(1).unwrap()")]
+    /// record one of every N flushed frames (default: 1)
+    pub every: NonZeroU32,
+    #[argh(option, arg_name = "DIR")]
+    /// directory to write the frames to, created if missing
+    pub out_dir: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+#[derive(FromArgs)]
+/// Stop recording the frames of a display of the GPU device.
+#[argh(subcommand, name = "stop-capture")]
+pub struct GpuStopCaptureCommand {
+    #[argh(option, arg_name = "N", default = "0")]
+    /// display id (default: 0)
+    pub display: u32,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand)]
 pub enum UsbSubCommand {
@@ -606,18 +1029,32 @@ pub enum UsbSubCommand {
 /// Attach usb device
 #[argh(subcommand, name = "attach")]
 pub struct UsbAttachCommand {
+    #[argh(option, arg_name = "HOST:PORT/BUSID", from_str_fn(parse_usbip_address))]
+    /// attach the device BUSID exported by the USB/IP server at HOST:PORT instead of a device of
+    /// the host
+    pub usbip: Option<(String, String)>,
+    #[argh(option, arg_name = "IMAGE")]
+    /// attach an emulated mass storage device exposing the disk image IMAGE instead of a device
+    /// of the host
+    pub storage: Option<String>,
+    #[argh(switch, long = "ro")]
+    /// expose the disk image of --storage read-only
+    pub read_only: bool,
+    #[argh(option, arg_name = "EVENTS_SOCKET")]
+    /// attach an emulated keyboard reading virtio input events from the unix socket
+    /// EVENTS_SOCKET instead of a device of the host
+    pub keyboard: Option<String>,
+    #[argh(option, arg_name = "EVENTS_SOCKET")]
+    /// attach an emulated tablet reading virtio input events from the unix socket EVENTS_SOCKET
+    /// instead of a device of the host
+    pub tablet: Option<String>,
     #[argh(
         positional,
-        arg_name = "BUS_ID:ADDR:BUS_NUM:DEV_NUM",
-        from_str_fn(parse_bus_id_addr)
+        arg_name = "[BUS_ID:ADDR:BUS_NUM:DEV_NUM DEV_PATH] VM_SOCKET"
     )]
-    pub addr: (u8, u8, u16, u16),
-    #[argh(positional)]
-    /// usb device path
-    pub dev_path: String,
-    #[argh(positional, arg_name = "VM_SOCKET")]
-    /// VM Socket path
-    pub socket_path: String,
+    /// usb device address and path, omitted with --usbip, --storage, --keyboard and --tablet,
+    /// followed by the VM Socket path
+    pub args: Vec<String>,
 }
 
 #[derive(FromArgs)]
@@ -694,11 +1131,23 @@ pub struct SnapshotCommand {
 /// Take a snapshot of the VM
 pub struct SnapshotTakeCommand {
     #[argh(positional, arg_name = "snapshot_path")]
-    /// VM Image path
+    /// path of the snapshot directory to create
     pub snapshot_path: PathBuf,
     #[argh(positional, arg_name = "VM_SOCKET")]
     /// VM Socket path
     pub socket_path: String,
+    #[argh(option, arg_name = "TYPE", default = "MemoryCompression::None")]
+    /// compression of the guest memory saved in the snapshot: none (default), zstd or lz4
+    pub compression: MemoryCompression,
+    #[argh(option, arg_name = "PARENT")]
+    /// only save the guest memory that changed since the snapshot at PARENT, which must be the
+    /// last snapshot taken of the VM. Restoring the snapshot needs PARENT and the snapshots it is
+    /// based on.
+    pub incremental: Option<PathBuf>,
+    #[argh(switch)]
+    /// keep logging the guest memory written after the snapshot, so that the next snapshot can be
+    /// taken with --incremental based on this one. Logging slows down the guest's memory writes.
+    pub incremental_base: bool,
 }
 
 #[derive(FromArgs)]
@@ -706,7 +1155,7 @@ pub struct SnapshotTakeCommand {
 /// Restore VM state from a snapshot created by take
 pub struct SnapshotRestoreCommand {
     #[argh(positional)]
-    /// path to snapshot to restore
+    /// path to snapshot directory to restore
     pub snapshot_path: PathBuf,
     #[argh(positional, arg_name = "VM_SOCKET")]
     /// VM Socket path
@@ -721,6 +1170,50 @@ pub enum SnapshotSubCommands {
     Restore(SnapshotRestoreCommand),
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand, name = "migrate", description = "Live migration commands")]
+/// Live migration commands
+pub struct MigrateCommand {
+    #[argh(subcommand)]
+    pub migrate_command: MigrateSubCommands,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "send")]
+/// Move the running VM to a crosvm process waiting with `crosvm migrate receive`, then exit
+pub struct MigrateSendCommand {
+    #[argh(positional, arg_name = "ADDRESS")]
+    /// address of the destination: unix:PATH or tcp:HOST:PORT
+    pub address: MigrationAddress,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "receive")]
+/// Wait for a VM sent with `crosvm migrate send` and replace this VM with it
+pub struct MigrateReceiveCommand {
+    #[argh(positional, arg_name = "ADDRESS")]
+    /// address to listen on: unix:PATH or tcp:HOST:PORT
+    pub address: MigrationAddress,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+    #[argh(switch)]
+    /// accept VMs on a TCP address that isn't a loopback address, which any host that can reach
+    /// it can send a VM to
+    pub allow_remote: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+/// Live migration commands
+pub enum MigrateSubCommands {
+    Send(MigrateSendCommand),
+    Receive(MigrateReceiveCommand),
+}
+
 /// Container for GpuParameters that have been fixed after parsing using serde.
 ///
 /// This deserializes as a regular `GpuParameters` and applies validation.
@@ -959,7 +1452,9 @@ pub struct RunCommand {
     #[argh(option)]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = overwrite_option)]
-    /// path to BIOS/firmware ROM
+    /// path to BIOS/firmware ROM. On riscv64, the firmware is
+    /// loaded at the reset vector, and a kernel given along
+    /// with it is loaded as the payload of the firmware
     pub bios: Option<PathBuf>,
 
     #[argh(option, short = 'b', arg_name = "PATH[,key=value[,key=value[,...]]]")]
@@ -999,6 +1494,18 @@ pub struct RunCommand {
     ///         will attempt to boot from the current device
     ///         after failing to boot from the device with
     ///         bootindex=1.
+    ///     iops=NUM - Maximum number of read and write requests
+    ///         per second. (default: no limit)
+    ///     bps=BYTES - Maximum number of bytes read and written
+    ///         per second. (default: no limit)
+    ///     read-bps=BYTES - Maximum number of bytes read per
+    ///         second. (default: no limit)
+    ///     write-bps=BYTES - Maximum number of bytes written per
+    ///         second. (default: no limit)
+    ///     iops-burst=NUM, bps-burst=BYTES, read-bps-burst=BYTES,
+    ///     write-bps-burst=BYTES - Amount that can be used at
+    ///         once when the guest stayed below the matching
+    ///         limit. (default: one second of the limit)
     block: Vec<DiskOptionWithId>,
 
     #[cfg(target_arch = "x86_64")]
@@ -1080,6 +1587,22 @@ pub struct RunCommand {
     /// group the given CPUs into a cluster (default: no clusters)
     pub cpu_cluster: Vec<CpuSet>,
 
+    #[cfg(target_arch = "riscv64")]
+    #[argh(option, arg_name = "EXT[=BOOL],...")]
+    #[serde(default)]
+    #[merge(strategy = overwrite_option)]
+    /// ISA extensions to enable or disable in the VCPUs. Each
+    /// extension is enabled if given alone or with `=true`, and
+    /// disabled with `=false`. The other extensions keep the
+    /// default of the host, and the extensions that the host
+    /// can't provide are refused.
+    ///     Valid extensions:
+    ///       a, c, d, f, v, svpbmt, sstc, svinval, zihintpause,
+    ///       zicbom, zbb
+    ///     Example:
+    ///       --cpu-isa zicbom,sstc=false
+    pub cpu_isa: Option<RiscvIsaOptions>,
+
     #[argh(option, short = 'c')]
     #[merge(strategy = overwrite_option)]
     /// cpu parameters.
@@ -1513,7 +2036,7 @@ pub struct RunCommand {
     #[cfg(all(unix, feature = "net"))]
     #[argh(
         option,
-        arg_name = "(tap-name=TAP_NAME,mac=MAC_ADDRESS|tap-fd=TAP_FD,mac=MAC_ADDRESS|host-ip=IP,netmask=NETMASK,mac=MAC_ADDRESS),vhost-net=VHOST_NET,vq-pairs=N"
+        arg_name = "(tap-name=TAP_NAME,mac=MAC_ADDRESS|tap-fd=TAP_FD,mac=MAC_ADDRESS|host-ip=IP,netmask=NETMASK,mac=MAC_ADDRESS|slirp,host-fwd=[RULE],mac=MAC_ADDRESS),vhost-net=VHOST_NET,vq-pairs=N"
     )]
     #[serde(default)]
     #[merge(strategy = append)]
@@ -1537,6 +2060,17 @@ pub struct RunCommand {
     ///       AND
     ///         mac=STRING      - MAC address for VM.
     ///      )
+    ///    OR
+    ///      slirp           - use a user-mode network, which
+    ///                          needs neither a TAP interface nor
+    ///                          privileges. Only supports one
+    ///                          queue pair and no vhost-net.
+    ///      host-fwd=[PROTO:[ADDR:]HPORT:GPORT,...]
+    ///                      - forward the tcp or udp HPORT of the
+    ///                          host's ADDR (default 127.0.0.1)
+    ///                          to the GPORT of the guest.
+    ///                          [Optional]
+    ///      mac=STRING      - MAC address for VM. [Optional]
     ///   )
     /// AND
     ///   vhost-net
@@ -1553,8 +2087,8 @@ pub struct RunCommand {
     ///                       use split virtqueue.
     ///                       Default: false.  [Optional]
     ///
-    /// Either one tap_name, one tap_fd or a triplet of host_ip,
-    /// netmask and mac must be specified.
+    /// Either one tap_name, one tap_fd, a triplet of host_ip,
+    /// netmask and mac, or slirp must be specified.
     pub net: Vec<NetParameters>,
 
     #[cfg(all(unix, feature = "net"))]
@@ -1880,6 +2414,14 @@ pub struct RunCommand {
     ///         as the root filesystem. This will add the required
     ///         parameters to the kernel command-line. Can only be
     ///         specified once. (default: false)
+    ///     target=NUM - The target of the disk. (default: the
+    ///         index of the disk in the scsi-block options)
+    ///     lun=NUM - The LUN of the disk in its target.
+    ///         (default: 0)
+    ///     cdrom=BOOL - Whether the disk is a CD-ROM drive with
+    ///         the image as its medium, e.g. an ISO image. CD-ROM
+    ///         drives are read-only and have 2048-byte blocks.
+    ///         (default: false)
     // TODO(b/300580119): Add O_DIRECT and sparse file support.
     scsi_block: Vec<ScsiOption>,
 
@@ -2138,6 +2680,22 @@ pub struct RunCommand {
     /// comma-separated names of the task profiles to apply to all threads in crosvm including the vCPU threads
     pub task_profiles: Vec<String>,
 
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[argh(option, arg_name = "swtpm=PATH[,interface=crb|tis|virtio]")]
+    #[serde(default)]
+    #[merge(strategy = overwrite_option)]
+    /// add a TPM 2.0 emulated by swtpm.
+    ///     swtpm=PATH - path to the control socket of swtpm,
+    ///         started with
+    ///         `swtpm socket --tpm2 --ctrl type=unixio,path=PATH`.
+    ///     interface=crb|tis|virtio - how the TPM is exposed to
+    ///         the guest (default: crb on x86_64, tis elsewhere).
+    ///         crb is the Command Response Buffer interface,
+    ///         described by the ACPI TPM2 table, only on x86_64.
+    ///         tis is the FIFO interface, described by the device
+    ///         tree, on the other architectures.
+    pub tpm: Option<TpmParameters>,
+
     #[argh(option, arg_name = "PATH:WIDTH:HEIGHT:NAME")]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = append)]
@@ -2356,8 +2914,11 @@ pub struct RunCommand {
     /// Possible key values:
     ///     capture=(false,true) - Disable/enable audio capture.
     ///         Default is false.
-    ///     backend=(null,file,[cras]) - Which backend to use for
-    ///         virtio-snd.
+    ///     backend=(null,file,[alsa,pipewire,pulseaudio,cras]) -
+    ///         Which backend to use for virtio-snd.
+    ///         pipewire and pulseaudio are the alsa backend
+    ///         playing on the pipewire and pulse PCMs of the
+    ///         ALSA plugins of PipeWire and PulseAudio.
     ///     client_type=(crosvm,arcvm,borealis) - Set specific
     ///         client type for cras backend. Default is crosvm.
     ///     socket_type=(legacy,unified) Set specific socket type
@@ -2366,6 +2927,18 @@ pub struct RunCommand {
     ///         for file backend.
     ///     playback_size=INT - Set size of the output streams
     ///         from file backend.
+    ///     capture_path=STR - Set directory of the input streams
+    ///         for file backend, read from stream-ID.wav, or
+    ///         from stream-ID.raw if there is no WAV file.
+    ///     capture_loop=BOOL - Loop the input streams of file
+    ///         backend instead of capturing silence after their
+    ///         end. Default is false.
+    ///     capture_raw_format=(U8,S16LE,S24LE,S32LE) - Set sample
+    ///         format of raw input streams. Default is S16LE.
+    ///     capture_raw_channels=INT - Set number of channels of
+    ///         raw input streams. Default is 2.
+    ///     capture_raw_rate=INT - Set frame rate of raw input
+    ///         streams. Default is 48000.
     ///     num_output_devices=INT - Set number of output PCM
     ///         devices.
     ///     num_input_devices=INT - Set number of input PCM devices.
@@ -2373,9 +2946,28 @@ pub struct RunCommand {
     ///         streams per device.
     ///     num_input_streams=INT - Set number of input PCM streams
     ///         per device.
+    ///     output_device_config=[[device=STR],...] - Set the ALSA
+    ///         PCM used by each output PCM device for alsa,
+    ///         pipewire and pulseaudio backends.
+    ///     input_device_config=[[device=STR],...] - Set the ALSA
+    ///         PCM used by each input PCM device for alsa,
+    ///         pipewire and pulseaudio backends.
     pub virtio_snd: Vec<SndParameters>,
 
-    #[argh(option, arg_name = "cid=CID[,device=VHOST_DEVICE]")]
+    #[cfg(all(feature = "vnc", any(target_os = "android", target_os = "linux")))]
+    #[argh(
+        option,
+        arg_name = "HOST:PORT|unix:PATH",
+        from_str_fn(parse_vnc_address)
+    )]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// serve the gpu displays to VNC clients on the given TCP
+    ///     address or unix socket. Each additional display uses
+    ///     the next port, or the path suffixed with .N.
+    pub vnc: Option<VncAddress>,
+
+    #[argh(option, arg_name = "cid=CID[,device=VHOST_DEVICE,uds-path=PATH]")]
     #[serde(default)]
     #[merge(strategy = overwrite_option)]
     /// add a vsock device. Since a guest can only have one CID,
@@ -2383,6 +2975,12 @@ pub struct RunCommand {
     ///     cid=CID - CID to use for the device.
     ///     device=VHOST_DEVICE - path to the vhost-vsock device to
     ///         use (Linux only). Defaults to /dev/vhost-vsock.
+    ///     uds-path=PATH - implement the device in userspace
+    ///         instead of using vhost-vsock, and map guest ports to
+    ///         host unix sockets (Linux only). Host processes
+    ///         connect to port P of the guest by connecting to
+    ///         PATH and writing "CONNECT P\n". Guest connections to
+    ///         port P of the host go to the socket PATH_P.
     pub vsock: Option<VsockConfig>,
 
     #[cfg(feature = "vtpm")]
@@ -2531,6 +3129,11 @@ impl TryFrom<RunCommand> for super::config::Config {
             cfg.cpu_capacity = capacity;
         }
 
+        #[cfg(target_arch = "riscv64")]
+        {
+            cfg.cpu_isa = cmd.cpu_isa.unwrap_or_default();
+        }
+
         #[cfg(all(
             any(target_arch = "arm", target_arch = "aarch64"),
             any(target_os = "android", target_os = "linux")
@@ -2681,6 +3284,9 @@ impl TryFrom<RunCommand> for super::config::Config {
         {
             return Err("only one root disk can be specified".to_string());
         }
+        if cmd.scsi_block.iter().any(|s| s.root && s.cdrom) {
+            return Err("a scsi CD-ROM drive can't be the root disk".to_string());
+        }
 
         // If we have a root disk, add the corresponding command-line parameters.
         if let Some(d) = disks.iter().find(|d| d.disk_option.root) {
@@ -2699,9 +3305,19 @@ impl TryFrom<RunCommand> for super::config::Config {
 
         // If we have a root scsi disk, add the corresponding command-line parameters.
         if let Some((i, s)) = cmd.scsi_block.iter().enumerate().find(|(_, s)| s.root) {
+            // The guest names the SCSI disks in the order of their target and LUN. CD-ROM drives
+            // are named separately.
+            let address = |i: usize, s: &ScsiOption| (s.target.unwrap_or(i as u8), s.lun);
+            let root_address = address(i, s);
+            let rank = cmd
+                .scsi_block
+                .iter()
+                .enumerate()
+                .filter(|(j, s)| !s.cdrom && address(*j, s) < root_address)
+                .count();
             cfg.params.push(format!(
                 "root=/dev/sd{} {}",
-                char::from(b'a' + i as u8),
+                char::from(b'a' + rank as u8),
                 if s.read_only { "ro" } else { "rw" }
             ));
         }
@@ -2771,6 +3387,11 @@ impl TryFrom<RunCommand> for super::config::Config {
             cfg.x_display = cmd.x_display;
         }
 
+        #[cfg(all(feature = "vnc", any(target_os = "android", target_os = "linux")))]
+        {
+            cfg.vnc = cmd.vnc;
+        }
+
         cfg.display_window_keyboard = cmd.display_window_keyboard.unwrap_or_default();
         cfg.display_window_mouse = cmd.display_window_mouse.unwrap_or_default();
 
@@ -2900,6 +3521,12 @@ impl TryFrom<RunCommand> for super::config::Config {
         cfg.initrd_path = cmd.initrd;
 
         if let Some(p) = cmd.bios {
+            // On riscv64, the kernel is loaded along the firmware as its payload.
+            #[cfg(target_arch = "riscv64")]
+            if let Some(Executable::Kernel(kernel)) = &cfg.executable_path {
+                cfg.bios_payload_path = Some(kernel.clone());
+                cfg.executable_path = None;
+            }
             if cfg.executable_path.is_some() {
                 return Err(format!(
                     "A VM executable was already specified: {:?}",
@@ -3254,6 +3881,38 @@ impl TryFrom<RunCommand> for super::config::Config {
             cfg.task_profiles = cmd.task_profiles;
         }
 
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        {
+            #[cfg(feature = "vtpm")]
+            if cfg.vtpm_proxy
+                && cmd
+                    .tpm
+                    .as_ref()
+                    .map_or(false, |tpm| tpm.interface == TpmInterface::Virtio)
+            {
+                return Err(
+                    "--vtpm-proxy cannot be specified together with --tpm interface=virtio"
+                        .to_string(),
+                );
+            }
+            if let Some(tpm) = &cmd.tpm {
+                if !tpm.interface.is_supported() {
+                    return Err(match tpm.interface {
+                        TpmInterface::Crb => {
+                            "--tpm interface=crb is only described by ACPI, which is only \
+                             available on x86_64, use interface=tis"
+                        }
+                        _ => {
+                            "--tpm interface=tis is only described by the device tree, use \
+                             interface=crb on x86_64"
+                        }
+                    }
+                    .to_string());
+                }
+            }
+            cfg.tpm = cmd.tpm;
+        }
+
         #[cfg(any(target_os = "android", target_os = "linux"))]
         {
             if cmd.unmap_guest_memory_on_fork.unwrap_or_default()
@@ -7,6 +7,8 @@ use std::arch::x86_64::__cpuid;
 #[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::__cpuid_count;
 use std::collections::BTreeMap;
+#[cfg(all(feature = "vnc", any(target_os = "android", target_os = "linux")))]
+use std::net::SocketAddr;
 use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
@@ -14,6 +16,8 @@ use std::str::FromStr;
 use arch::set_default_serial_parameters;
 use arch::CpuSet;
 use arch::Pstore;
+#[cfg(target_arch = "riscv64")]
+use arch::RiscvIsaOptions;
 #[cfg(target_arch = "x86_64")]
 use arch::SmbiosOptions;
 use arch::VcpuAffinity;
@@ -22,6 +26,8 @@ use base::pagesize;
 use cros_async::ExecutorKind;
 use devices::serial_device::SerialHardware;
 use devices::serial_device::SerialParameters;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use devices::tpm::TpmParameters;
 use devices::virtio::block::DiskOption;
 #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
 use devices::virtio::device_constants::video::VideoDeviceConfig;
@@ -56,6 +62,7 @@ use resources::AddressRange;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
+use vm_control::input::TouchStroke;
 use vm_control::BatteryType;
 #[cfg(target_arch = "x86_64")]
 use x86_64::check_host_hybrid_support;
@@ -470,6 +477,55 @@ pub fn parse_bus_id_addr(v: &str) -> Result<(u8, u8, u16, u16), String> {
     }
 }
 
+/// Parses a `HOST:PORT/BUSID` USB/IP device address into the server address and the bus ID.
+pub fn parse_usbip_address(v: &str) -> Result<(String, String), String> {
+    match v.split_once('/') {
+        Some((server, busid)) if server.contains(':') && !busid.is_empty() => {
+            Ok((server.to_string(), busid.to_string()))
+        }
+        _ => Err(invalid_value_err(v, "expected HOST:PORT/BUSID")),
+    }
+}
+
+/// Where the VNC clients of the displays of the gpu device connect.
+#[cfg(all(feature = "vnc", any(target_os = "android", target_os = "linux")))]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VncAddress {
+    /// A TCP address. The displays after the first one use the following ports.
+    Tcp(SocketAddr),
+    /// A unix socket path. The displays after the first one use the path suffixed with their
+    /// index.
+    Unix(PathBuf),
+}
+
+/// Parses a `HOST:PORT` or `unix:PATH` VNC address.
+#[cfg(all(feature = "vnc", any(target_os = "android", target_os = "linux")))]
+pub fn parse_vnc_address(v: &str) -> Result<VncAddress, String> {
+    if let Some(path) = v.strip_prefix("unix:") {
+        return Ok(VncAddress::Unix(PathBuf::from(path)));
+    }
+    v.parse()
+        .map(VncAddress::Tcp)
+        .map_err(|_| invalid_value_err(v, "expected HOST:PORT or unix:PATH"))
+}
+
+/// Parses the `X,Y` start and the optional `:X,Y` end of the stroke of a finger in a touch gesture.
+/// A stroke without an end is a tap.
+pub fn parse_touch_stroke(v: &str) -> Result<TouchStroke, String> {
+    let parse_point = |point: &str| -> Option<(i32, i32)> {
+        let (x, y) = point.split_once(',')?;
+        Some((x.parse().ok()?, y.parse().ok()?))
+    };
+    let (start, end) = match v.split_once(':') {
+        Some((start, end)) => (parse_point(start), parse_point(end)),
+        None => (parse_point(v), parse_point(v)),
+    };
+    match (start, end) {
+        (Some(start), Some(end)) => Ok(TouchStroke { start, end }),
+        _ => Err(invalid_value_err(v, "expected X,Y or X,Y:X,Y")),
+    }
+}
+
 pub fn invalid_value_err<T: AsRef<str>, S: ToString>(value: T, expected: S) -> String {
     format!("invalid value {}: {}", value.as_ref(), expected.to_string())
 }
@@ -624,6 +680,8 @@ pub struct Config {
     pub balloon_ws_num_bins: u8,
     pub balloon_ws_reporting: bool,
     pub battery_config: Option<BatteryConfig>,
+    #[cfg(target_arch = "riscv64")]
+    pub bios_payload_path: Option<PathBuf>,
     #[cfg(windows)]
     pub block_control_tube: Vec<Tube>,
     #[cfg(windows)]
@@ -639,6 +697,8 @@ pub struct Config {
     pub core_scheduling: bool,
     pub cpu_capacity: BTreeMap<usize, u32>, // CPU index -> capacity
     pub cpu_clusters: Vec<CpuSet>,
+    #[cfg(target_arch = "riscv64")]
+    pub cpu_isa: RiscvIsaOptions,
     #[cfg(feature = "crash-report")]
     pub crash_pipe_name: Option<String>,
     #[cfg(feature = "crash-report")]
@@ -765,6 +825,8 @@ pub struct Config {
     #[cfg(target_os = "android")]
     pub task_profiles: Vec<String>,
     #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub tpm: Option<TpmParameters>,
+    #[cfg(any(target_os = "android", target_os = "linux"))]
     pub unmap_guest_memory_on_fork: bool,
     pub usb: bool,
     pub vcpu_affinity: Option<VcpuAffinity>,
@@ -804,6 +866,8 @@ pub struct Config {
     pub virtio_snds: Vec<SndParameters>,
     pub virtio_switches: Vec<PathBuf>,
     pub virtio_trackpad: Vec<TouchDeviceOption>,
+    #[cfg(all(feature = "vnc", any(target_os = "android", target_os = "linux")))]
+    pub vnc: Option<VncAddress>,
     pub vsock: Option<VsockConfig>,
     #[cfg(feature = "vtpm")]
     pub vtpm_proxy: bool,
@@ -828,6 +892,8 @@ impl Default for Config {
             balloon_ws_num_bins: VIRTIO_BALLOON_WS_DEFAULT_NUM_BINS,
             balloon_ws_reporting: false,
             battery_config: None,
+            #[cfg(target_arch = "riscv64")]
+            bios_payload_path: None,
             #[cfg(windows)]
             block_control_tube: Vec::new(),
             #[cfg(windows)]
@@ -847,6 +913,8 @@ impl Default for Config {
             crash_report_uuid: None,
             cpu_capacity: BTreeMap::new(),
             cpu_clusters: Vec::new(),
+            #[cfg(target_arch = "riscv64")]
+            cpu_isa: RiscvIsaOptions::default(),
             delay_rt: false,
             device_tree_overlay: Vec::new(),
             disks: Vec::new(),
@@ -970,6 +1038,8 @@ impl Default for Config {
             #[cfg(target_os = "android")]
             task_profiles: Vec::new(),
             #[cfg(any(target_os = "android", target_os = "linux"))]
+            tpm: None,
+            #[cfg(any(target_os = "android", target_os = "linux"))]
             unmap_guest_memory_on_fork: false,
             usb: true,
             vcpu_affinity: None,
@@ -1009,6 +1079,8 @@ impl Default for Config {
             virtio_snds: Vec::new(),
             virtio_switches: Vec::new(),
             virtio_trackpad: Vec::new(),
+            #[cfg(all(feature = "vnc", any(target_os = "android", target_os = "linux")))]
+            vnc: None,
             #[cfg(feature = "vtpm")]
             vtpm_proxy: false,
             wayland_socket_paths: BTreeMap::new(),
@@ -1912,4 +1984,37 @@ mod tests {
 
         from_key_values::<SmbiosOptions>("uuid=zzzz").expect_err("expected error parsing uuid");
     }
+
+    #[cfg(all(feature = "vnc", any(target_os = "android", target_os = "linux")))]
+    #[test]
+    fn parse_vnc() {
+        assert_eq!(
+            parse_vnc_address("127.0.0.1:5900").unwrap(),
+            VncAddress::Tcp("127.0.0.1:5900".parse().unwrap())
+        );
+        assert_eq!(
+            parse_vnc_address("unix:/run/crosvm/vnc.sock").unwrap(),
+            VncAddress::Unix(PathBuf::from("/run/crosvm/vnc.sock"))
+        );
+        parse_vnc_address("5900").expect_err("expected error parsing address without host");
+    }
+
+    #[test]
+    fn parse_touch() {
+        assert_eq!(
+            parse_touch_stroke("10,20:-30,40").unwrap(),
+            TouchStroke {
+                start: (10, 20),
+                end: (-30, 40),
+            }
+        );
+        assert_eq!(
+            parse_touch_stroke("10,20").unwrap(),
+            TouchStroke {
+                start: (10, 20),
+                end: (10, 20),
+            }
+        );
+        parse_touch_stroke("10:20").expect_err("expected error parsing point without y");
+    }
 }
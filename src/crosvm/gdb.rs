@@ -23,7 +23,7 @@ use gdbstub::stub::run_blocking;
 use gdbstub::stub::run_blocking::BlockingEventLoop;
 use gdbstub::stub::SingleThreadStopReason;
 use gdbstub::target::ext::base::single_register_access::SingleRegisterAccess;
-#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+#[cfg(any(target_arch = "arm", target_arch = "aarch64", target_arch = "riscv64"))]
 use gdbstub::target::ext::base::single_register_access::SingleRegisterAccessOps;
 use gdbstub::target::ext::base::singlethread::SingleThreadBase;
 use gdbstub::target::ext::base::singlethread::SingleThreadResume;
@@ -292,7 +292,7 @@ impl SingleThreadBase for GdbStub {
         Some(self)
     }
 
-    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64", target_arch = "riscv64"))]
     #[inline(always)]
     fn support_single_register_access(&mut self) -> Option<SingleRegisterAccessOps<(), Self>> {
         Some(self)
@@ -311,7 +311,12 @@ impl SingleThreadResume for GdbStub {
 
     #[inline(always)]
     fn support_single_step(&mut self) -> Option<SingleThreadSingleStepOps<'_, Self>> {
-        Some(self)
+        // KVM can't single-step riscv64 VCPUs, so GDB steps them itself with temporary breakpoints.
+        if cfg!(target_arch = "riscv64") {
+            None
+        } else {
+            Some(self)
+        }
     }
 }
 
@@ -605,6 +605,10 @@ pub fn run_config(cfg: Config) -> Result<()> {
                 }
                 tap_interfaces.push(tap);
             }
+            #[cfg(feature = "slirp")]
+            NetParametersMode::Slirp { .. } => {
+                bail!("slirp networking not supported with plugin");
+            }
         }
     }
 
@@ -45,6 +45,7 @@ use std::rc::Rc;
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Barrier;
+use std::time::Duration;
 
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
 use aarch64::AArch64 as Arch;
@@ -74,6 +75,11 @@ use cros_async::Executor;
 use device_helpers::*;
 use devices::create_devices_worker_thread;
 use devices::serial_device::SerialHardware;
+use devices::tpm::Swtpm;
+use devices::tpm::TpmBackend;
+use devices::tpm::TpmInterface;
+use devices::tpm::TPM_CRB_BUFFER_SIZE;
+use devices::tpm::TPM_TIS_BUFFER_SIZE;
 use devices::vfio::VfioCommonSetup;
 use devices::vfio::VfioCommonTrait;
 #[cfg(feature = "gpu")]
@@ -167,6 +173,8 @@ use resources::AddressRange;
 use resources::Alloc;
 use resources::SystemAllocator;
 #[cfg(target_arch = "riscv64")]
+use riscv64::sbi::SbiDispatcher;
+#[cfg(target_arch = "riscv64")]
 use riscv64::Riscv64 as Arch;
 use rutabaga_gfx::RutabagaGralloc;
 use smallvec::SmallVec;
@@ -175,6 +183,9 @@ use swap::SwapController;
 use sync::Condvar;
 use sync::Mutex;
 use vm_control::api::VmMemoryClient;
+use vm_control::input::InputDeviceInfo;
+use vm_control::input::InputDeviceKind;
+use vm_control::migration::Migration;
 use vm_control::*;
 use vm_memory::GuestAddress;
 use vm_memory::GuestMemory;
@@ -205,6 +216,30 @@ const GENIEZONE_PATH: &str = "/dev/gzvm";
 #[cfg(all(any(target_arch = "arm", target_arch = "aarch64"), feature = "gunyah"))]
 static GUNYAH_PATH: &str = "/dev/gunyah";
 
+// How long the run loop waits for room in the control socket of an input device, whose worker
+// only drains it while the guest driver is active.
+const INPUT_CONTROL_SEND_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Creates the control tube of an input device, keeping its host end in `input_host_tubes`.
+fn create_input_control_tube(
+    input_host_tubes: &mut Vec<(InputDeviceInfo, Tube)>,
+    kind: InputDeviceKind,
+    path: Option<&Path>,
+) -> DeviceResult<Tube> {
+    let (host_tube, device_tube) = Tube::pair().context("failed to create tube")?;
+    host_tube
+        .set_send_timeout(Some(INPUT_CONTROL_SEND_TIMEOUT))
+        .context("failed to set the send timeout of the input control tube")?;
+    input_host_tubes.push((
+        InputDeviceInfo {
+            kind,
+            path: path.map(Path::to_path_buf),
+        },
+        host_tube,
+    ));
+    Ok(device_tube)
+}
+
 fn create_virtio_devices(
     cfg: &Config,
     vm: &mut impl Vm,
@@ -214,8 +249,10 @@ fn create_virtio_devices(
     #[cfg(feature = "balloon")] balloon_inflate_tube: Option<Tube>,
     #[cfg(feature = "balloon")] init_balloon_size: u64,
     disk_device_tubes: &mut Vec<Tube>,
+    scsi_device_tube: Option<Tube>,
     pmem_device_tubes: &mut Vec<Tube>,
     fs_device_tubes: &mut Vec<Tube>,
+    input_host_tubes: &mut Vec<(InputDeviceInfo, Tube)>,
     #[cfg(feature = "gpu")] gpu_control_tube: Tube,
     #[cfg(feature = "gpu")] render_server_fd: Option<SafeDescriptor>,
     #[cfg(feature = "registered_events")] registered_evt_q: &SendTube,
@@ -301,6 +338,11 @@ fn create_virtio_devices(
                     multi_touch_width,
                     multi_touch_height,
                     multi_touch_name,
+                    Some(create_input_control_tube(
+                        input_host_tubes,
+                        InputDeviceKind::DisplayWindowMouse,
+                        None,
+                    )?),
                     virtio::base_features(cfg.protection_type),
                 )
                 .context("failed to set up mouse device")?;
@@ -319,6 +361,11 @@ fn create_virtio_devices(
                     // the multi_touch options, which begin at 0.
                     u32::MAX,
                     virtio_dev_socket,
+                    Some(create_input_control_tube(
+                        input_host_tubes,
+                        InputDeviceKind::DisplayWindowKeyboard,
+                        None,
+                    )?),
                     virtio::base_features(cfg.protection_type),
                 )
                 .context("failed to set up keyboard device")?;
@@ -356,7 +403,7 @@ fn create_virtio_devices(
     }
 
     if !cfg.scsis.is_empty() {
-        let scsi_config = ScsiConfig(&cfg.scsis);
+        let scsi_config = ScsiConfig::new(&cfg.scsis, scsi_device_tube);
         devs.push(
             scsi_config.create_virtio_device_and_jail(cfg.protection_type, &cfg.jail_config)?,
         );
@@ -389,74 +436,132 @@ fn create_virtio_devices(
         }
     }
 
+    if let Some(params) = &cfg.tpm {
+        if params.interface == TpmInterface::Virtio {
+            devs.push(create_swtpm_device(
+                cfg.protection_type,
+                &cfg.jail_config,
+                params,
+            )?);
+        }
+    }
+
     for (idx, single_touch_spec) in cfg.virtio_single_touch.iter().enumerate() {
+        let control_tube = create_input_control_tube(
+            input_host_tubes,
+            InputDeviceKind::SingleTouch,
+            Some(single_touch_spec.get_path()),
+        )?;
         devs.push(create_single_touch_device(
             cfg.protection_type,
             &cfg.jail_config,
             single_touch_spec,
             idx as u32,
+            control_tube,
         )?);
     }
 
     for (idx, multi_touch_spec) in cfg.virtio_multi_touch.iter().enumerate() {
+        let control_tube = create_input_control_tube(
+            input_host_tubes,
+            InputDeviceKind::MultiTouch,
+            Some(multi_touch_spec.get_path()),
+        )?;
         devs.push(create_multi_touch_device(
             cfg.protection_type,
             &cfg.jail_config,
             multi_touch_spec,
             idx as u32,
+            control_tube,
         )?);
     }
 
     for (idx, trackpad_spec) in cfg.virtio_trackpad.iter().enumerate() {
+        let control_tube = create_input_control_tube(
+            input_host_tubes,
+            InputDeviceKind::Trackpad,
+            Some(trackpad_spec.get_path()),
+        )?;
         devs.push(create_trackpad_device(
             cfg.protection_type,
             &cfg.jail_config,
             trackpad_spec,
             idx as u32,
+            control_tube,
         )?);
     }
 
     for (idx, mouse_socket) in cfg.virtio_mice.iter().enumerate() {
+        let control_tube = create_input_control_tube(
+            input_host_tubes,
+            InputDeviceKind::Mouse,
+            Some(mouse_socket.as_path()),
+        )?;
         devs.push(create_mouse_device(
             cfg.protection_type,
             &cfg.jail_config,
             mouse_socket,
             idx as u32,
+            control_tube,
         )?);
     }
 
     for (idx, keyboard_socket) in cfg.virtio_keyboard.iter().enumerate() {
+        let control_tube = create_input_control_tube(
+            input_host_tubes,
+            InputDeviceKind::Keyboard,
+            Some(keyboard_socket.as_path()),
+        )?;
         devs.push(create_keyboard_device(
             cfg.protection_type,
             &cfg.jail_config,
             keyboard_socket,
             idx as u32,
+            control_tube,
         )?);
     }
 
     for (idx, switches_socket) in cfg.virtio_switches.iter().enumerate() {
+        let control_tube = create_input_control_tube(
+            input_host_tubes,
+            InputDeviceKind::Switches,
+            Some(switches_socket.as_path()),
+        )?;
         devs.push(create_switches_device(
             cfg.protection_type,
             &cfg.jail_config,
             switches_socket,
             idx as u32,
+            control_tube,
         )?);
     }
 
     for (idx, rotary_socket) in cfg.virtio_rotary.iter().enumerate() {
+        let control_tube = create_input_control_tube(
+            input_host_tubes,
+            InputDeviceKind::Rotary,
+            Some(rotary_socket.as_path()),
+        )?;
         devs.push(create_rotary_device(
             cfg.protection_type,
             &cfg.jail_config,
             rotary_socket,
             idx as u32,
+            control_tube,
         )?);
     }
 
     for dev_path in &cfg.virtio_input_evdevs {
+        let control_tube = create_input_control_tube(
+            input_host_tubes,
+            InputDeviceKind::Evdev,
+            Some(dev_path.as_path()),
+        )?;
         devs.push(create_vinput_device(
             cfg.protection_type,
             &cfg.jail_config,
             dev_path,
+            control_tube,
         )?);
     }
 
@@ -624,8 +729,10 @@ fn create_devices(
     #[cfg(feature = "balloon")] balloon_device_tube: Option<Tube>,
     #[cfg(feature = "balloon")] init_balloon_size: u64,
     disk_device_tubes: &mut Vec<Tube>,
+    scsi_device_tube: Option<Tube>,
     pmem_device_tubes: &mut Vec<Tube>,
     fs_device_tubes: &mut Vec<Tube>,
+    input_host_tubes: &mut Vec<(InputDeviceInfo, Tube)>,
     #[cfg(feature = "usb")] usb_provider: DeviceProvider,
     #[cfg(feature = "gpu")] gpu_control_tube: Tube,
     #[cfg(feature = "gpu")] render_server_fd: Option<SafeDescriptor>,
@@ -756,8 +863,10 @@ fn create_devices(
         #[cfg(feature = "balloon")]
         init_balloon_size,
         disk_device_tubes,
+        scsi_device_tube,
         pmem_device_tubes,
         fs_device_tubes,
+        input_host_tubes,
         #[cfg(feature = "gpu")]
         gpu_control_tube,
         #[cfg(feature = "gpu")]
@@ -1025,6 +1134,15 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         ),
         _ => panic!("Did not receive a bios or kernel, should be impossible."),
     };
+    #[cfg(target_arch = "riscv64")]
+    let bios_payload = if let Some(payload_path) = &cfg.bios_payload_path {
+        Some(
+            open_file_or_duplicate(payload_path, OpenOptions::new().read(true))
+                .with_context(|| format!("failed to open kernel {}", payload_path.display()))?,
+        )
+    } else {
+        None
+    };
 
     let swiotlb = if let Some(size) = cfg.swiotlb {
         Some(
@@ -1088,6 +1206,20 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         }
     }
 
+    let tpm = match &cfg.tpm {
+        Some(params) if params.interface != TpmInterface::Virtio => {
+            let buffer_size = match params.interface {
+                TpmInterface::Crb => TPM_CRB_BUFFER_SIZE,
+                _ => TPM_TIS_BUFFER_SIZE,
+            };
+            let backend = Swtpm::new(&params.swtpm, buffer_size).with_context(|| {
+                format!("failed to connect to swtpm at {}", params.swtpm.display())
+            })?;
+            Some(Box::new(backend) as Box<dyn TpmBackend>)
+        }
+        _ => None,
+    };
+
     // if --enable-fw-cfg or --fw-cfg was given, we want to enable fw_cfg
     let fw_cfg_enable = cfg.enable_fw_cfg || !cfg.fw_cfg_parameters.is_empty();
 
@@ -1102,6 +1234,7 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
             .checked_mul(1024 * 1024)
             .ok_or_else(|| anyhow!("requested memory size too large"))?,
         swiotlb,
+        tpm,
         fw_cfg_enable,
         bootorder_fw_cfg_blob: Vec::new(),
         vcpu_count: cfg.vcpu_count.unwrap_or(1),
@@ -1110,6 +1243,8 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         cpu_capacity: cfg.cpu_capacity.clone(),
         #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
         cpu_frequencies,
+        #[cfg(target_arch = "riscv64")]
+        cpu_isa: cfg.cpu_isa.clone(),
         fw_cfg_parameters: cfg.fw_cfg_parameters.clone(),
         no_smt: cfg.no_smt,
         hugepages: cfg.hugepages,
@@ -1119,6 +1254,8 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
             protection_type: cfg.protection_type,
         },
         vm_image,
+        #[cfg(target_arch = "riscv64")]
+        bios_payload,
         android_fstab: cfg
             .android_fstab
             .as_ref()
@@ -1622,6 +1759,14 @@ where
         disk_device_tubes.push(disk_device_tube);
     }
 
+    // Create a control socket for the SCSI controller to attach and detach logical units.
+    let (scsi_host_tube, scsi_device_tube) = if cfg.scsis.is_empty() {
+        (None, None)
+    } else {
+        let (host, device) = Tube::pair().context("failed to create tube")?;
+        (Some(host), Some(device))
+    };
+
     let mut pmem_device_tubes = Vec::new();
     let pmem_count = cfg.pmem_devices.len();
     for _ in 0..pmem_count {
@@ -1675,6 +1820,9 @@ where
         fs_device_tubes.push(fs_device_tube);
     }
 
+    // The host ends of the control sockets of the input devices, created along with the devices.
+    let mut input_host_tubes = Vec::new();
+
     let (vm_evt_wrtube, vm_evt_rdtube) =
         Tube::directional_pair().context("failed to create vm event tube")?;
 
@@ -1740,8 +1888,10 @@ where
         #[cfg(feature = "balloon")]
         init_balloon_size,
         &mut disk_device_tubes,
+        scsi_device_tube,
         &mut pmem_device_tubes,
         &mut fs_device_tubes,
+        &mut input_host_tubes,
         #[cfg(feature = "usb")]
         usb_provider,
         #[cfg(feature = "gpu")]
@@ -1995,6 +2145,8 @@ where
         #[cfg(feature = "balloon")]
         balloon_host_tube,
         &disk_host_tubes,
+        scsi_host_tube,
+        &input_host_tubes,
         #[cfg(feature = "gpu")]
         gpu_control_host_tube,
         #[cfg(feature = "usb")]
@@ -2589,6 +2741,40 @@ fn handle_hotplug_command<V: VmArch, Vcpu: VcpuArch>(
     }
 }
 
+// Starts the live migration requested by `cmd` on a worker thread.
+fn start_migration<V: VmArch + 'static>(
+    cmd: &MigrateCommand,
+    vm: &V,
+    device_ctrl_tube: &Tube,
+    vcpu_size: usize,
+) -> anyhow::Result<Migration> {
+    match cmd {
+        MigrateCommand::Send { address } => {
+            let dirty_log_vm = vm.try_clone().context("failed to clone vm")?;
+            Migration::send(
+                address,
+                vm.get_memory(),
+                device_ctrl_tube,
+                vcpu_size,
+                move || {
+                    dirty_log_vm.enable_guest_memory_dirty_log()?;
+                    Ok(dirty_log_vm.get_guest_memory_dirty_log()?)
+                },
+            )
+        }
+        MigrateCommand::Receive {
+            address,
+            allow_remote,
+        } => Migration::receive(
+            address,
+            *allow_remote,
+            vm.get_memory(),
+            device_ctrl_tube,
+            vcpu_size,
+        ),
+    }
+}
+
 fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     mut linux: RunnableLinuxVm<V, Vcpu>,
     sys_allocator: SystemAllocator,
@@ -2599,6 +2785,8 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     control_tubes: Vec<TaggedControlTube>,
     #[cfg(feature = "balloon")] balloon_host_tube: Option<Tube>,
     disk_host_tubes: &[Tube],
+    scsi_host_tube: Option<Tube>,
+    input_host_tubes: &[(InputDeviceInfo, Tube)],
     #[cfg(feature = "gpu")] gpu_control_tube: Tube,
     #[cfg(feature = "usb")] usb_control_tube: Tube,
     vm_evt_rdtube: RecvTube,
@@ -2629,6 +2817,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
         RegisteredEvent,
         #[cfg(feature = "balloon")]
         BalloonTube,
+        Migration,
     }
 
     #[cfg(feature = "registered_events")]
@@ -2840,6 +3029,16 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
         }
     }
 
+    // The SBI calls that the hypervisor doesn't handle itself, shared by all the vcpus.
+    // Handlers of experimental and vendor extensions must be registered with
+    // `SbiDispatcher::register_extension_handler` before the dispatcher is shared.
+    #[cfg(target_arch = "riscv64")]
+    let sbi_dispatcher = Arc::new(SbiDispatcher::new(
+        linux.vm.get_memory().clone(),
+        (*linux.mmio_bus).clone(),
+        &cfg.serial_parameters,
+    ));
+
     #[cfg(target_os = "android")]
     android::set_process_profiles(&cfg.task_profiles)?;
 
@@ -2944,6 +3143,8 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
             },
             #[cfg(target_arch = "x86_64")]
             bus_lock_ratelimit_ctrl,
+            #[cfg(target_arch = "riscv64")]
+            sbi_dispatcher.clone(),
             run_mode,
         )?;
         vcpu_handles.push((handle, to_vcpu_channel));
@@ -3045,6 +3246,8 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     #[cfg(feature = "registered_events")]
     let mut registered_evt_tubes: HashMap<RegisteredEvent, HashSet<AddressedProtoTube>> =
         HashMap::new();
+    // The live migration in progress, and the control tube to reply to once it is over.
+    let mut migration: Option<(Migration, usize)> = None;
 
     'wait: loop {
         let events = {
@@ -3214,6 +3417,21 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                                     let mut suspend_requested = false;
                                     let mut run_mode_opt = None;
                                     let response = match request {
+                                        // The migration thread drives the vcpus and devices
+                                        // through `device_ctrl_tube` until it is over.
+                                        VmRequest::SuspendVcpus
+                                        | VmRequest::ResumeVcpus
+                                        | VmRequest::SuspendVm
+                                        | VmRequest::ResumeVm
+                                        | VmRequest::Snapshot(_)
+                                        | VmRequest::Restore(_)
+                                            if migration.is_some() =>
+                                        {
+                                            VmResponse::ErrString(
+                                                "not allowed while a migration is running"
+                                                    .to_owned(),
+                                            )
+                                        }
                                         VmRequest::HotPlugVfioCommand { device, add } => {
                                             #[cfg(target_arch = "x86_64")]
                                             {
@@ -3332,6 +3550,43 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                                                 VmResponse::Err(base::Error::new(libc::ENOTSUP))
                                             }
                                         }
+                                        VmRequest::ScsiCommand(ref cmd) => match &scsi_host_tube {
+                                            Some(tube) => handle_scsi_command(cmd, tube),
+                                            None => VmResponse::Err(base::Error::new(libc::ENODEV)),
+                                        },
+                                        VmRequest::InputCommand(ref cmd) => {
+                                            handle_input_command(cmd, input_host_tubes)
+                                        }
+                                        VmRequest::Migrate(ref cmd) => {
+                                            if migration.is_some() {
+                                                VmResponse::ErrString(
+                                                    "a migration is already running".to_owned(),
+                                                )
+                                            } else {
+                                                match start_migration(
+                                                    cmd,
+                                                    &linux.vm,
+                                                    &device_ctrl_tube,
+                                                    vcpu_handles.len(),
+                                                ) {
+                                                    Ok(m) => {
+                                                        wait_ctx
+                                                            .add(m.tube(), Token::Migration)
+                                                            .context(
+                                                                "failed to add descriptor to wait \
+                                                                 context",
+                                                            )?;
+                                                        // Replied to once the migration is over.
+                                                        migration = Some((m, id));
+                                                        continue;
+                                                    }
+                                                    Err(e) => VmResponse::ErrString(format!(
+                                                        "failed to migrate: {:#}",
+                                                        e
+                                                    )),
+                                                }
+                                            }
+                                        }
                                         _ => {
                                             let response = request.execute(
                                                 &mut run_mode_opt,
@@ -3371,6 +3626,17 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                                                         .try_box_clone()?
                                                         .restore(image, linux.vcpu_count)
                                                 },
+                                                |enable| {
+                                                    if enable {
+                                                        linux.vm.enable_guest_memory_dirty_log()?;
+                                                    } else {
+                                                        linux
+                                                            .vm
+                                                            .disable_guest_memory_dirty_log()?;
+                                                    }
+                                                    Ok(())
+                                                },
+                                                || Ok(linux.vm.get_guest_memory_dirty_log()?),
                                             );
 
                                             // For non s2idle guest suspension we are done
@@ -3527,6 +3793,61 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                         }
                     }
                 }
+                Token::Migration => {
+                    let Some((m, &mut client_id)) = migration.as_mut() else {
+                        continue;
+                    };
+                    let Some(result) = m.handle_worker_request(
+                        |msg| {
+                            vcpu::kick_all_vcpus(
+                                &vcpu_handles,
+                                linux.irq_chip.as_irq_chip(),
+                                msg,
+                            )
+                        },
+                        |msg, index| {
+                            vcpu::kick_vcpu(
+                                &vcpu_handles.get(index),
+                                linux.irq_chip.as_irq_chip(),
+                                msg,
+                            )
+                        },
+                        &irq_handler_control,
+                        &device_ctrl_tube,
+                        vcpu_handles.len(),
+                        || linux.irq_chip.snapshot(linux.vcpu_count),
+                        |image| {
+                            linux
+                                .irq_chip
+                                .try_box_clone()?
+                                .restore(image, linux.vcpu_count)
+                        },
+                    ) else {
+                        continue;
+                    };
+                    let sent = m.is_sending() && result.is_ok();
+                    let response = match result {
+                        Ok(()) => VmResponse::Ok,
+                        Err(e) => {
+                            error!("live migration failed: {:#}", e);
+                            VmResponse::ErrString(format!("failed to migrate: {:#}", e))
+                        }
+                    };
+                    if let Some(TaggedControlTube::Vm(tube)) = control_tubes.get(&client_id) {
+                        if let Err(e) = tube.send(&response) {
+                            error!("failed to send VmResponse: {}", e);
+                        }
+                    }
+                    if let Err(e) = wait_ctx.delete(m.tube()) {
+                        warn!("failed to remove migration tube from wait context: {}", e);
+                    }
+                    migration = None;
+                    if sent {
+                        info!("VM migrated, exiting");
+                        exit_state = ExitState::Stop;
+                        break 'wait;
+                    }
+                }
             }
         }
 
@@ -22,6 +22,8 @@ use base::*;
 use devices::serial_device::SerialHardware;
 use devices::serial_device::SerialParameters;
 use devices::serial_device::SerialType;
+use devices::tpm::Swtpm;
+use devices::tpm::TpmParameters;
 use devices::vfio::VfioCommonSetup;
 use devices::vfio::VfioCommonTrait;
 use devices::virtio;
@@ -70,10 +72,14 @@ use hypervisor::ProtectionType;
 use hypervisor::Vm;
 use jail::*;
 use minijail::Minijail;
+#[cfg(all(feature = "net", feature = "slirp"))]
+use net_util::slirp::SlirpConfig;
 #[cfg(feature = "net")]
 use net_util::sys::linux::Tap;
 #[cfg(feature = "net")]
 use net_util::MacAddress;
+#[cfg(all(feature = "net", feature = "slirp"))]
+use net_util::Slirp;
 #[cfg(feature = "net")]
 use net_util::TapTCommon;
 use resources::Alloc;
@@ -289,9 +295,25 @@ impl<'a> VirtioDeviceBuilder for DiskConfig<'a> {
     }
 }
 
-pub struct ScsiConfig<'a>(pub &'a [ScsiOption]);
+/// A one-shot configuration structure for implementing `VirtioDeviceBuilder` for the SCSI
+/// controller, which can be passed a control tube to attach and detach logical units.
+pub struct ScsiConfig<'a> {
+    /// Options of the SCSI devices attached at boot.
+    scsis: &'a [ScsiOption],
+    /// Optional control tube for the controller.
+    control_tube: Option<Tube>,
+}
+
+impl<'a> ScsiConfig<'a> {
+    pub fn new(scsis: &'a [ScsiOption], control_tube: Option<Tube>) -> Self {
+        Self {
+            scsis,
+            control_tube,
+        }
+    }
+}
 
-impl<'a> VirtioDeviceBuilder for &'a ScsiConfig<'a> {
+impl<'a> VirtioDeviceBuilder for ScsiConfig<'a> {
     const NAME: &'static str = "scsi";
 
     fn create_virtio_device(
@@ -300,19 +322,27 @@ impl<'a> VirtioDeviceBuilder for &'a ScsiConfig<'a> {
     ) -> anyhow::Result<Box<dyn VirtioDevice>> {
         let base_features = virtio::base_features(protection_type);
         let disks = self
-            .0
+            .scsis
             .iter()
-            .map(|op| {
+            .enumerate()
+            .map(|(i, op)| {
                 info!("Trying to attach a scsi device: {}", op.path.display());
                 let file = op.open()?;
+                let target = match op.target {
+                    Some(target) => target,
+                    None => u8::try_from(i).context("too many scsi devices")?,
+                };
                 Ok(virtio::ScsiDiskConfig {
                     file,
                     block_size: op.block_size,
                     read_only: op.read_only,
+                    target,
+                    lun: op.lun,
+                    cdrom: op.cdrom,
                 })
             })
             .collect::<anyhow::Result<_>>()?;
-        let controller = virtio::ScsiController::new(base_features, disks)
+        let controller = virtio::ScsiController::new(base_features, disks, self.control_tube)
             .context("failed to create a scsi controller")?;
         Ok(Box::new(controller))
     }
@@ -396,11 +426,26 @@ pub fn create_virtio_snd_device(
 
     let policy = match backend {
         Backend::NULL | Backend::FILE => "snd_null_device",
+        #[cfg(feature = "audio_alsa")]
+        Backend::Sys(
+            virtio::snd::sys::StreamSourceBackend::ALSA
+            | virtio::snd::sys::StreamSourceBackend::PIPEWIRE
+            | virtio::snd::sys::StreamSourceBackend::PULSEAUDIO,
+        ) => "snd_alsa_device",
         #[cfg(feature = "audio_cras")]
         Backend::Sys(virtio::snd::sys::StreamSourceBackend::CRAS) => "snd_cras_device",
-        #[cfg(not(feature = "audio_cras"))]
+        #[cfg(not(any(feature = "audio_alsa", feature = "audio_cras")))]
         _ => unreachable!(),
     };
+    #[cfg(feature = "audio_alsa")]
+    let is_alsa_backend = matches!(
+        backend,
+        Backend::Sys(
+            virtio::snd::sys::StreamSourceBackend::ALSA
+                | virtio::snd::sys::StreamSourceBackend::PIPEWIRE
+                | virtio::snd::sys::StreamSourceBackend::PULSEAUDIO
+        )
+    );
 
     let jail = if let Some(jail_config) = jail_config {
         let mut config = SandboxConfig::new(jail_config, policy);
@@ -408,6 +453,10 @@ pub fn create_virtio_snd_device(
         if backend == Backend::Sys(virtio::snd::sys::StreamSourceBackend::CRAS) {
             config.bind_mounts = true;
         }
+        #[cfg(feature = "audio_alsa")]
+        if is_alsa_backend {
+            config.bind_mounts = true;
+        }
         // TODO(b/267574679): running as current_user may not be required for snd device.
         config.run_as = RunAsUser::CurrentUser;
         #[allow(unused_mut)]
@@ -418,6 +467,50 @@ pub fn create_virtio_snd_device(
             let run_cras_path = Path::new("/run/cras");
             jail.mount_bind(run_cras_path, run_cras_path, true)?;
         }
+        #[cfg(feature = "audio_alsa")]
+        if is_alsa_backend {
+            let dev_snd_path = Path::new("/dev/snd");
+            if dev_snd_path.exists() {
+                jail.mount_bind(dev_snd_path, dev_snd_path, true)?;
+            }
+            // The configuration of alsa-lib, and the directories it loads its plugins from.
+            let lib_dirs = [
+                PathBuf::from("/usr/lib"),
+                PathBuf::from("/usr/lib64"),
+                PathBuf::from(format!("/usr/lib/{}-linux-gnu", std::env::consts::ARCH)),
+            ];
+            let mut paths = vec![
+                PathBuf::from("/etc/alsa"),
+                PathBuf::from("/etc/asound.conf"),
+                PathBuf::from("/usr/share/alsa"),
+            ];
+            paths.extend(lib_dirs.iter().map(|dir| dir.join("alsa-lib")));
+            // The PipeWire and PulseAudio plugins are clients of a server of the user, which
+            // need their client configuration and modules, and the socket of the server.
+            let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from);
+            let mut server_socket = None;
+            match backend {
+                Backend::Sys(virtio::snd::sys::StreamSourceBackend::PIPEWIRE) => {
+                    paths.push(PathBuf::from("/etc/pipewire"));
+                    paths.push(PathBuf::from("/usr/share/pipewire"));
+                    for dir in &lib_dirs {
+                        paths.push(dir.join("pipewire-0.3"));
+                        paths.push(dir.join("spa-0.2"));
+                    }
+                    server_socket = runtime_dir.map(|dir| dir.join("pipewire-0"));
+                }
+                Backend::Sys(virtio::snd::sys::StreamSourceBackend::PULSEAUDIO) => {
+                    paths.push(PathBuf::from("/etc/pulse"));
+                    paths.extend(lib_dirs.iter().map(|dir| dir.join("pulseaudio")));
+                    server_socket = runtime_dir.map(|dir| dir.join("pulse/native"));
+                }
+                _ => {}
+            }
+            jail_mount_bind_if_exists(&mut jail, &paths)?;
+            if let Some(socket_path) = server_socket.filter(|path| path.exists()) {
+                jail.mount_bind(&socket_path, &socket_path, true)?;
+            }
+        }
         Some(jail)
     } else {
         None
@@ -455,11 +548,27 @@ pub fn create_vtpm_proxy_device(
     })
 }
 
+pub fn create_swtpm_device(
+    protection_type: ProtectionType,
+    jail_config: &Option<JailConfig>,
+    params: &TpmParameters,
+) -> DeviceResult {
+    let backend = Swtpm::new(&params.swtpm, virtio::TPM_BUFSIZE)
+        .with_context(|| format!("failed to connect to swtpm at {}", params.swtpm.display()))?;
+    let dev = virtio::Tpm::new(Box::new(backend), virtio::base_features(protection_type));
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(jail_config, "swtpm_device")?,
+    })
+}
+
 pub fn create_single_touch_device(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
     single_touch_spec: &TouchDeviceOption,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = single_touch_spec
         .get_path()
@@ -474,6 +583,7 @@ pub fn create_single_touch_device(
         width,
         height,
         name,
+        Some(control_tube),
         virtio::base_features(protection_type),
     )
     .context("failed to set up input device")?;
@@ -488,6 +598,7 @@ pub fn create_multi_touch_device(
     jail_config: &Option<JailConfig>,
     multi_touch_spec: &TouchDeviceOption,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = multi_touch_spec
         .get_path()
@@ -502,6 +613,7 @@ pub fn create_multi_touch_device(
         width,
         height,
         name,
+        Some(control_tube),
         virtio::base_features(protection_type),
     )
     .context("failed to set up input device")?;
@@ -517,6 +629,7 @@ pub fn create_trackpad_device(
     jail_config: &Option<JailConfig>,
     trackpad_spec: &TouchDeviceOption,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = trackpad_spec
         .get_path()
@@ -531,6 +644,7 @@ pub fn create_trackpad_device(
         width,
         height,
         name,
+        Some(control_tube),
         virtio::base_features(protection_type),
     )
     .context("failed to set up input device")?;
@@ -546,13 +660,19 @@ pub fn create_mouse_device<T: IntoUnixStream>(
     jail_config: &Option<JailConfig>,
     mouse_socket: T,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = mouse_socket
         .into_unix_stream()
         .context("failed configuring virtio mouse")?;
 
-    let dev = virtio::input::new_mouse(idx, socket, virtio::base_features(protection_type))
-        .context("failed to set up input device")?;
+    let dev = virtio::input::new_mouse(
+        idx,
+        socket,
+        Some(control_tube),
+        virtio::base_features(protection_type),
+    )
+    .context("failed to set up input device")?;
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -565,13 +685,19 @@ pub fn create_keyboard_device<T: IntoUnixStream>(
     jail_config: &Option<JailConfig>,
     keyboard_socket: T,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = keyboard_socket
         .into_unix_stream()
         .context("failed configuring virtio keyboard")?;
 
-    let dev = virtio::input::new_keyboard(idx, socket, virtio::base_features(protection_type))
-        .context("failed to set up input device")?;
+    let dev = virtio::input::new_keyboard(
+        idx,
+        socket,
+        Some(control_tube),
+        virtio::base_features(protection_type),
+    )
+    .context("failed to set up input device")?;
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -584,13 +710,19 @@ pub fn create_switches_device<T: IntoUnixStream>(
     jail_config: &Option<JailConfig>,
     switches_socket: T,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = switches_socket
         .into_unix_stream()
         .context("failed configuring virtio switches")?;
 
-    let dev = virtio::input::new_switches(idx, socket, virtio::base_features(protection_type))
-        .context("failed to set up input device")?;
+    let dev = virtio::input::new_switches(
+        idx,
+        socket,
+        Some(control_tube),
+        virtio::base_features(protection_type),
+    )
+    .context("failed to set up input device")?;
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -603,13 +735,19 @@ pub fn create_rotary_device<T: IntoUnixStream>(
     jail_config: &Option<JailConfig>,
     rotary_socket: T,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = rotary_socket
         .into_unix_stream()
         .context("failed configuring virtio rotary")?;
 
-    let dev = virtio::input::new_rotary(idx, socket, virtio::base_features(protection_type))
-        .context("failed to set up input device")?;
+    let dev = virtio::input::new_rotary(
+        idx,
+        socket,
+        Some(control_tube),
+        virtio::base_features(protection_type),
+    )
+    .context("failed to set up input device")?;
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -621,6 +759,7 @@ pub fn create_vinput_device(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
     dev_path: &Path,
+    control_tube: Tube,
 ) -> DeviceResult {
     let dev_file = OpenOptions::new()
         .read(true)
@@ -628,8 +767,12 @@ pub fn create_vinput_device(
         .open(dev_path)
         .with_context(|| format!("failed to open vinput device {}", dev_path.display()))?;
 
-    let dev = virtio::input::new_evdev(dev_file, virtio::base_features(protection_type))
-        .context("failed to set up input device")?;
+    let dev = virtio::input::new_evdev(
+        dev_file,
+        Some(control_tube),
+        virtio::base_features(protection_type),
+    )
+    .context("failed to set up input device")?;
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -680,6 +823,25 @@ impl VirtioDeviceBuilder for &NetParameters {
         let multi_vq = vq_pairs > 1 && self.vhost_net.is_none();
 
         let features = virtio::base_features(protection_type);
+
+        #[cfg(feature = "slirp")]
+        if let NetParametersMode::Slirp { host_fwd, mac, .. } = &self.mode {
+            if self.vhost_net.is_some() {
+                bail!("slirp networking is not supported with vhost-net");
+            }
+            if vq_pairs > 1 {
+                bail!("slirp networking only supports one queue pair");
+            }
+            let (slirp, slirp_loop) = Slirp::new(SlirpConfig {
+                host_fwds: host_fwd.clone(),
+            })
+            .context("failed to set up slirp networking")?;
+            return Ok(Box::new(
+                virtio::Net::new_slirp(features, slirp, slirp_loop, *mac, self.packed_queue)
+                    .context("failed to set up virtio networking")?,
+            ));
+        }
+
         let (tap, mac) = create_tap_for_net_device(&self.mode, multi_vq)?;
 
         Ok(if let Some(vhost_net) = &self.vhost_net {
@@ -706,6 +868,33 @@ impl VirtioDeviceBuilder for &NetParameters {
         jail_config: &Option<JailConfig>,
         virtio_transport: VirtioDeviceType,
     ) -> anyhow::Result<Option<Minijail>> {
+        // Slirp isn't supported by the vhost-user device, which fails when it is created instead.
+        #[cfg(feature = "slirp")]
+        if let (NetParametersMode::Slirp { .. }, VirtioDeviceType::Regular) =
+            (&self.mode, &virtio_transport)
+        {
+            // libslirp connects to the host's network on behalf of the guest, and forwards DNS
+            // requests to the host's resolvers.
+            return if let Some(jail_config) = jail_config {
+                let policy = virtio_transport.seccomp_policy_file("slirp_net");
+                let mut config = SandboxConfig::new(jail_config, &policy);
+                config.namespace_net = false;
+                config.bind_mounts = true;
+                let mut jail = create_sandbox_minijail(
+                    &jail_config.pivot_root,
+                    MAX_OPEN_FILES_DEFAULT,
+                    &config,
+                )?;
+                let resolv_conf_path = Path::new("/etc/resolv.conf");
+                if resolv_conf_path.exists() {
+                    jail.mount_bind(resolv_conf_path, resolv_conf_path, false)?;
+                }
+                Ok(Some(jail))
+            } else {
+                Ok(None)
+            };
+        }
+
         let policy = if self.vhost_net.is_some() {
             "vhost_net"
         } else {
@@ -768,6 +957,10 @@ fn create_tap_for_net_device(
             tap.enable().map_err(NetError::TapEnable)?;
             Ok((tap, None))
         }
+        #[cfg(feature = "slirp")]
+        NetParametersMode::Slirp { .. } => {
+            bail!("slirp networking is only supported by the virtio-net device")
+        }
     }
 }
 
@@ -914,6 +1107,12 @@ impl VirtioDeviceBuilder for &VsockConfig {
     ) -> anyhow::Result<Box<dyn VirtioDevice>> {
         let features = virtio::base_features(protection_type);
 
+        if let Some(uds_path) = &self.uds_path {
+            let dev = virtio::vsock::HybridVsock::new(self.cid, uds_path, features)
+                .context("failed to set up hybrid virtual socket device")?;
+            return Ok(Box::new(dev));
+        }
+
         let dev = virtio::vhost::Vsock::new(features, self)
             .context("failed to set up virtual socket device")?;
 
@@ -924,12 +1123,59 @@ impl VirtioDeviceBuilder for &VsockConfig {
         self,
         keep_rds: &mut Vec<RawDescriptor>,
     ) -> anyhow::Result<Box<dyn VhostUserDevice>> {
+        if let Some(uds_path) = &self.uds_path {
+            let vsock_device = virtio::vsock::HybridVsock::new(
+                self.cid,
+                uds_path,
+                virtio::base_features(ProtectionType::Unprotected),
+            )
+            .context("failed to set up hybrid virtual socket device")?;
+
+            keep_rds.extend(vsock_device.keep_rds());
+
+            return Ok(Box::new(vsock_device));
+        }
+
         let vsock_device = VhostUserVsockDevice::new(self.cid, &self.vhost_device)?;
 
         keep_rds.push(vsock_device.as_raw_descriptor());
 
         Ok(Box::new(vsock_device))
     }
+
+    fn create_jail(
+        &self,
+        jail_config: &Option<JailConfig>,
+        virtio_transport: VirtioDeviceType,
+    ) -> anyhow::Result<Option<Minijail>> {
+        let uds_path = match &self.uds_path {
+            Some(uds_path) => uds_path,
+            None => {
+                return simple_jail(
+                    jail_config,
+                    &virtio_transport.seccomp_policy_file(Self::NAME),
+                )
+            }
+        };
+
+        if let Some(jail_config) = jail_config {
+            let policy = virtio_transport.seccomp_policy_file("vsock");
+            let mut config = SandboxConfig::new(jail_config, &policy);
+            config.bind_mounts = true;
+            let mut jail =
+                create_sandbox_minijail(&jail_config.pivot_root, MAX_OPEN_FILES_DEFAULT, &config)?;
+            // Guest-initiated connections are made to sockets next to `uds_path`.
+            if let Some(parent) = uds_path.parent() {
+                if parent.exists() {
+                    info!("Bind mounting dir {}", parent.display());
+                    jail.mount_bind(parent, parent, true)?;
+                }
+            }
+            Ok(Some(jail))
+        } else {
+            Ok(None)
+        }
+    }
 }
 
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
@@ -8,9 +8,15 @@ static_assertions::assert_cfg!(feature = "gpu");
 
 use std::collections::HashMap;
 use std::env;
+#[cfg(feature = "vnc")]
+use std::net::TcpListener;
+#[cfg(feature = "vnc")]
+use std::os::unix::net::UnixListener;
 use std::path::PathBuf;
 
 use base::linux::move_proc_to_cgroup;
+#[cfg(feature = "vnc")]
+use gpu_display::VncListener;
 use jail::*;
 use serde::Deserialize;
 use serde::Serialize;
@@ -18,6 +24,8 @@ use serde_keyvalue::FromKeyValues;
 
 use super::*;
 use crate::crosvm::config::Config;
+#[cfg(feature = "vnc")]
+use crate::crosvm::config::VncAddress;
 
 pub struct GpuCacheInfo<'a> {
     directory: Option<&'a str>,
@@ -82,6 +90,42 @@ pub fn get_gpu_cache_info<'a>(
     }
 }
 
+// Binds a listening socket for the VNC clients of each of the `count` displays.
+#[cfg(feature = "vnc")]
+fn bind_vnc_listeners(address: &VncAddress, count: usize) -> Result<Vec<VncListener>> {
+    let mut listeners = Vec::with_capacity(count);
+    for index in 0..count {
+        let listener = match address {
+            VncAddress::Tcp(address) => {
+                let mut address = *address;
+                let port = u16::try_from(index)
+                    .ok()
+                    .and_then(|index| address.port().checked_add(index))
+                    .context("no vnc port left for the display")?;
+                address.set_port(port);
+                let listener = TcpListener::bind(address)
+                    .with_context(|| format!("failed to listen for vnc clients on {}", address))?;
+                VncListener::Tcp(listener)
+            }
+            VncAddress::Unix(path) => {
+                let path = if index == 0 {
+                    path.clone()
+                } else {
+                    let mut path = path.clone().into_os_string();
+                    path.push(format!(".{}", index));
+                    PathBuf::from(path)
+                };
+                let listener = UnixListener::bind(&path).with_context(|| {
+                    format!("failed to listen for vnc clients on {}", path.display())
+                })?;
+                VncListener::Unix(listener)
+            }
+        };
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
 pub fn create_gpu_device(
     cfg: &Config,
     exit_evt_wrtube: &SendTube,
@@ -112,6 +156,13 @@ pub fn create_gpu_device(
         );
     }
 
+    // The listening sockets are bound before the device is jailed, without network access.
+    #[cfg(feature = "vnc")]
+    if let Some(address) = &cfg.vnc {
+        let listeners = bind_vnc_listeners(address, gpu_params.display_params.len())?;
+        display_backends.insert(0, virtio::DisplayBackend::Vnc(Arc::new(listeners)));
+    }
+
     let dev = virtio::Gpu::new(
         exit_evt_wrtube
             .try_clone()
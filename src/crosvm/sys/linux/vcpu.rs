@@ -36,6 +36,12 @@ use hypervisor::VcpuExit;
 use hypervisor::VcpuSignalHandle;
 use libc::c_int;
 #[cfg(target_arch = "riscv64")]
+use riscv64::sbi::SbiAction;
+#[cfg(target_arch = "riscv64")]
+use riscv64::sbi::SbiCall;
+#[cfg(target_arch = "riscv64")]
+use riscv64::sbi::SbiDispatcher;
+#[cfg(target_arch = "riscv64")]
 use riscv64::Riscv64 as Arch;
 #[cfg(target_arch = "x86_64")]
 use sync::Mutex;
@@ -226,6 +232,7 @@ fn vcpu_loop<V>(
     #[cfg(feature = "gdb")] to_gdb_tube: Option<mpsc::Sender<VcpuDebugStatusMessage>>,
     #[cfg(feature = "gdb")] guest_mem: GuestMemory,
     #[cfg(target_arch = "x86_64")] bus_lock_ratelimit_ctrl: Arc<Mutex<Ratelimit>>,
+    #[cfg(target_arch = "riscv64")] sbi_dispatcher: Arc<SbiDispatcher>,
 ) -> ExitState
 where
     V: VcpuArch,
@@ -416,12 +423,26 @@ where
                     let delay_ns: u64 = bus_lock_ratelimit_ctrl.lock().ratelimit_calculate_delay(1);
                     thread::sleep(Duration::from_nanos(delay_ns));
                 }
+                #[cfg(target_arch = "riscv64")]
                 Ok(VcpuExit::Sbi {
-                    extension_id: _,
-                    function_id: _,
-                    args: _,
+                    extension_id,
+                    function_id,
+                    args,
                 }) => {
-                    unimplemented!("Sbi exits not yet supported");
+                    let call = SbiCall {
+                        extension_id,
+                        function_id,
+                        args,
+                    };
+                    match sbi_dispatcher.handle(cpu_id, &call) {
+                        SbiAction::Return(ret) => {
+                            if let Err(e) = vcpu.handle_sbi(ret.error, ret.value) {
+                                error!("failed to handle sbi call {:?}: {}", call, e);
+                            }
+                        }
+                        SbiAction::Shutdown => return ExitState::Stop,
+                        SbiAction::Reset => return ExitState::Reset,
+                    }
                 }
                 Ok(VcpuExit::RiscvCsr {
                     csr_num,
@@ -481,6 +502,7 @@ pub fn run_vcpu<V>(
     cpu_config: Option<CpuConfigArch>,
     vcpu_cgroup_tasks_file: Option<File>,
     #[cfg(target_arch = "x86_64")] bus_lock_ratelimit_ctrl: Arc<Mutex<Ratelimit>>,
+    #[cfg(target_arch = "riscv64")] sbi_dispatcher: Arc<SbiDispatcher>,
     run_mode: VmRunMode,
 ) -> Result<JoinHandle<()>>
 where
@@ -549,6 +571,8 @@ where
                     guest_mem,
                     #[cfg(target_arch = "x86_64")]
                     bus_lock_ratelimit_ctrl,
+                    #[cfg(target_arch = "riscv64")]
+                    sbi_dispatcher,
                 );
 
                 // We don't want any more VCPU signals from now until the thread exits.
@@ -7,9 +7,12 @@
 //! ## Feature flags
 #![cfg_attr(feature = "document-features", doc = document_features::document_features!())]
 
+use std::fs::File;
 #[cfg(any(feature = "composite-disk", feature = "qcow"))]
 use std::fs::OpenOptions;
+use std::io::BufReader;
 use std::path::Path;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use anyhow::Context;
@@ -27,6 +30,7 @@ mod crosvm;
 use crosvm::cmdline;
 #[cfg(feature = "plugin")]
 use crosvm::config::executable_is_plugin;
+use crosvm::config::parse_bus_id_addr;
 use crosvm::config::Config;
 use devices::virtio::vhost::user::device::run_block_device;
 #[cfg(feature = "gpu")]
@@ -53,19 +57,35 @@ use crosvm::cmdline::CrossPlatformCommands;
 use crosvm::cmdline::CrossPlatformDevicesCommands;
 #[cfg(windows)]
 use sys::windows::setup_metrics_reporting;
+use vm_control::client::do_disk_snapshot_list;
 #[cfg(feature = "gpu")]
 use vm_control::client::do_gpu_display_add;
 #[cfg(feature = "gpu")]
 use vm_control::client::do_gpu_display_list;
 #[cfg(feature = "gpu")]
 use vm_control::client::do_gpu_display_remove;
+#[cfg(feature = "gpu")]
+use vm_control::client::do_gpu_screenshot;
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+use vm_control::client::do_gpu_start_capture;
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+use vm_control::client::do_gpu_stop_capture;
+use vm_control::client::do_input_list;
+use vm_control::client::do_input_send;
 use vm_control::client::do_modify_battery;
 #[cfg(feature = "pci-hotplug")]
 use vm_control::client::do_net_add;
 #[cfg(feature = "pci-hotplug")]
 use vm_control::client::do_net_remove;
+use vm_control::client::do_scsi_attach;
+use vm_control::client::do_scsi_insert_medium;
 use vm_control::client::do_swap_status;
 use vm_control::client::do_usb_attach;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use vm_control::client::do_usb_attach_hid;
+use vm_control::client::do_usb_attach_storage;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use vm_control::client::do_usb_attach_usbip;
 use vm_control::client::do_usb_detach;
 use vm_control::client::do_usb_list;
 #[cfg(feature = "balloon")]
@@ -73,16 +93,25 @@ use vm_control::client::handle_request;
 use vm_control::client::vms_request;
 #[cfg(feature = "gpu")]
 use vm_control::client::ModifyGpuResult;
+use vm_control::client::ModifyInputError;
+use vm_control::client::ModifyInputResult;
+use vm_control::client::ModifyUsbError;
 use vm_control::client::ModifyUsbResult;
+use vm_control::input;
 #[cfg(feature = "balloon")]
 use vm_control::BalloonControlCommand;
 use vm_control::DiskControlCommand;
+use vm_control::DiskThrottleLimits;
 use vm_control::HotPlugDeviceInfo;
 use vm_control::HotPlugDeviceType;
+use vm_control::MigrateCommand;
 use vm_control::RestoreCommand;
+use vm_control::ScsiControlCommand;
 use vm_control::SnapshotCommand;
 use vm_control::SwapCommand;
 use vm_control::UsbControlResult;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use vm_control::UsbHidKind;
 use vm_control::VmRequest;
 #[cfg(feature = "balloon")]
 use vm_control::VmResponse;
@@ -444,10 +473,18 @@ fn create_composite(cmd: cmdline::CreateCompositeCommand) -> std::result::Result
 
 #[cfg(feature = "qcow")]
 fn create_qcow2(cmd: cmdline::CreateQcow2Command) -> std::result::Result<(), ()> {
-    if !(cmd.size.is_some() ^ cmd.backing_file.is_some()) {
+    let num_sources = [
+        cmd.size.is_some(),
+        cmd.backing_file.is_some(),
+        cmd.compress_from.is_some(),
+    ]
+    .iter()
+    .filter(|source| **source)
+    .count();
+    if num_sources != 1 {
         println!(
-            "Create a new QCOW2 image at `PATH` of either the specified `SIZE` in bytes or
-    with a '--backing_file'."
+            "Create a new QCOW2 image at `PATH` of either the specified `SIZE` in bytes, with a
+    '--backing_file', or with the compressed contents of a '--compress-from' raw image."
         );
         return Err(());
     }
@@ -462,17 +499,25 @@ fn create_qcow2(cmd: cmdline::CreateQcow2Command) -> std::result::Result<(), ()>
             error!("Failed opening qcow file at '{}': {}", cmd.file_path, e);
         })?;
 
-    match (cmd.size, cmd.backing_file) {
-        (Some(size), None) => QcowFile::new(file, size).map_err(|e| {
+    match (cmd.size, cmd.backing_file, cmd.compress_from) {
+        (Some(size), None, None) => QcowFile::new(file, size).map_err(|e| {
             error!("Failed to create qcow file at '{}': {}", cmd.file_path, e);
         })?,
-        (None, Some(backing_file)) => {
+        (None, Some(backing_file), None) => {
             QcowFile::new_from_backing(file, &backing_file, disk::MAX_NESTING_DEPTH).map_err(
                 |e| {
                     error!("Failed to create qcow file at '{}': {}", cmd.file_path, e);
                 },
             )?
         }
+        (None, None, Some(raw_path)) => {
+            let mut raw_file = OpenOptions::new().read(true).open(&raw_path).map_err(|e| {
+                error!("Failed opening raw image at '{}': {}", raw_path, e);
+            })?;
+            QcowFile::new_compressed_from(file, &mut raw_file).map_err(|e| {
+                error!("Failed to create qcow file at '{}': {}", cmd.file_path, e);
+            })?
+        }
         _ => unreachable!(),
     };
     Ok(())
@@ -502,6 +547,95 @@ fn start_device(opts: cmdline::DeviceCommand) -> std::result::Result<(), ()> {
     })
 }
 
+fn scsi_cmd(cmd: cmdline::ScsiCommand) -> std::result::Result<(), ()> {
+    match cmd.command {
+        cmdline::ScsiSubCommand::Attach(cmd) => do_scsi_attach(
+            cmd.socket_path,
+            &cmd.path,
+            cmd.target,
+            cmd.lun,
+            cmd.block_size,
+            cmd.ro,
+            cmd.cdrom,
+        ),
+        cmdline::ScsiSubCommand::Detach(cmd) => {
+            let request = VmRequest::ScsiCommand(ScsiControlCommand::DetachLun {
+                target: cmd.target,
+                lun: cmd.lun,
+            });
+            vms_request(&request, cmd.socket_path)
+        }
+        cmdline::ScsiSubCommand::Insert(cmd) => {
+            do_scsi_insert_medium(cmd.socket_path, &cmd.path, cmd.target, cmd.lun)
+        }
+        cmdline::ScsiSubCommand::Eject(cmd) => {
+            let request = VmRequest::ScsiCommand(ScsiControlCommand::EjectMedium {
+                target: cmd.target,
+                lun: cmd.lun,
+            });
+            vms_request(&request, cmd.socket_path)
+        }
+    }
+}
+
+fn input_key(cmd: cmdline::InputKeyCommand) -> ModifyInputResult<()> {
+    let keys = cmd
+        .keys
+        .split('+')
+        .map(input::parse_key)
+        .collect::<ModifyInputResult<Vec<u16>>>()?;
+    let reports = input::key_reports(&keys, Duration::from_millis(cmd.interval_ms));
+    do_input_send(cmd.socket_path, cmd.device, &reports)
+}
+
+fn input_text(cmd: cmdline::InputTextCommand) -> ModifyInputResult<()> {
+    let reports = input::text_reports(&cmd.text, Duration::from_millis(cmd.interval_ms))?;
+    do_input_send(cmd.socket_path, cmd.device, &reports)
+}
+
+fn input_move(cmd: cmdline::InputMoveCommand) -> ModifyInputResult<()> {
+    let reports = if cmd.absolute {
+        input::absolute_motion_reports(cmd.x, cmd.y)
+    } else {
+        input::relative_motion_reports(cmd.x, cmd.y)
+    };
+    do_input_send(cmd.socket_path, cmd.device, &reports)
+}
+
+fn input_click(cmd: cmdline::InputClickCommand) -> ModifyInputResult<()> {
+    let button = input::parse_button(&cmd.button)?;
+    let reports = input::button_reports(button, Duration::from_millis(cmd.hold_ms));
+    do_input_send(cmd.socket_path, cmd.device, &reports)
+}
+
+fn input_touch(cmd: cmdline::InputTouchCommand) -> ModifyInputResult<()> {
+    let reports = input::touch_reports(
+        &cmd.finger,
+        cmd.steps,
+        Duration::from_millis(cmd.duration_ms),
+    )?;
+    do_input_send(cmd.socket_path, cmd.device, &reports)
+}
+
+fn input_replay(cmd: cmdline::InputReplayCommand) -> ModifyInputResult<()> {
+    let file = File::open(&cmd.path).map_err(ModifyInputError::ReadEvents)?;
+    let reports = input::parse_evemu_events(BufReader::new(file))?;
+    do_input_send(cmd.socket_path, cmd.device, &reports)
+}
+
+fn input_cmd(cmd: cmdline::InputCommand) -> std::result::Result<(), ()> {
+    let result = match cmd.command {
+        cmdline::InputSubCommand::List(cmd) => do_input_list(cmd.socket_path),
+        cmdline::InputSubCommand::Key(cmd) => input_key(cmd),
+        cmdline::InputSubCommand::Text(cmd) => input_text(cmd),
+        cmdline::InputSubCommand::Move(cmd) => input_move(cmd),
+        cmdline::InputSubCommand::Click(cmd) => input_click(cmd),
+        cmdline::InputSubCommand::Touch(cmd) => input_touch(cmd),
+        cmdline::InputSubCommand::Replay(cmd) => input_replay(cmd),
+    };
+    result.map_err(|e| println!("error {}", e))
+}
+
 fn disk_cmd(cmd: cmdline::DiskCommand) -> std::result::Result<(), ()> {
     match cmd.command {
         cmdline::DiskSubcommand::Resize(cmd) => {
@@ -513,6 +647,51 @@ fn disk_cmd(cmd: cmdline::DiskCommand) -> std::result::Result<(), ()> {
             };
             vms_request(&request, cmd.socket_path)
         }
+        cmdline::DiskSubcommand::Snapshot(cmd) => {
+            let (disk_index, command, socket_path) = match cmd.nested {
+                cmdline::SnapshotDiskSubcommands::List(cmd) => {
+                    return do_disk_snapshot_list(cmd.disk_index, cmd.socket_path);
+                }
+                cmdline::SnapshotDiskSubcommands::Create(cmd) => (
+                    cmd.disk_index,
+                    DiskControlCommand::CreateSnapshot { name: cmd.name },
+                    cmd.socket_path,
+                ),
+                cmdline::SnapshotDiskSubcommands::Apply(cmd) => (
+                    cmd.disk_index,
+                    DiskControlCommand::ApplySnapshot { name: cmd.name },
+                    cmd.socket_path,
+                ),
+                cmdline::SnapshotDiskSubcommands::Delete(cmd) => (
+                    cmd.disk_index,
+                    DiskControlCommand::DeleteSnapshot { name: cmd.name },
+                    cmd.socket_path,
+                ),
+            };
+            let request = VmRequest::DiskCommand {
+                disk_index,
+                command,
+            };
+            vms_request(&request, socket_path)
+        }
+        cmdline::DiskSubcommand::Throttle(cmd) => {
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::Throttle {
+                    limits: DiskThrottleLimits {
+                        iops: cmd.iops,
+                        iops_burst: cmd.iops_burst,
+                        bps: cmd.bps,
+                        bps_burst: cmd.bps_burst,
+                        read_bps: cmd.read_bps,
+                        read_bps_burst: cmd.read_bps_burst,
+                        write_bps: cmd.write_bps,
+                        write_bps_burst: cmd.write_bps_burst,
+                    },
+                },
+            };
+            vms_request(&request, cmd.socket_path)
+        }
     }
 }
 
@@ -535,12 +714,32 @@ fn gpu_display_remove(cmd: cmdline::GpuRemoveDisplaysCommand) -> ModifyGpuResult
     do_gpu_display_remove(cmd.socket_path, cmd.display_id)
 }
 
+#[cfg(feature = "gpu")]
+fn gpu_screenshot(cmd: cmdline::GpuScreenshotCommand) -> ModifyGpuResult {
+    do_gpu_screenshot(cmd.socket_path, cmd.display, &cmd.out)
+}
+
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+fn gpu_start_capture(cmd: cmdline::GpuStartCaptureCommand) -> ModifyGpuResult {
+    do_gpu_start_capture(cmd.socket_path, cmd.display, cmd.every, &cmd.out_dir)
+}
+
+#[cfg(all(feature = "gpu", any(target_os = "android", target_os = "linux")))]
+fn gpu_stop_capture(cmd: cmdline::GpuStopCaptureCommand) -> ModifyGpuResult {
+    do_gpu_stop_capture(cmd.socket_path, cmd.display)
+}
+
 #[cfg(feature = "gpu")]
 fn modify_gpu(cmd: cmdline::GpuCommand) -> std::result::Result<(), ()> {
     let result = match cmd.command {
         cmdline::GpuSubCommand::AddDisplays(cmd) => gpu_display_add(cmd),
         cmdline::GpuSubCommand::ListDisplays(cmd) => gpu_display_list(cmd),
         cmdline::GpuSubCommand::RemoveDisplays(cmd) => gpu_display_remove(cmd),
+        cmdline::GpuSubCommand::Screenshot(cmd) => gpu_screenshot(cmd),
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        cmdline::GpuSubCommand::StartCapture(cmd) => gpu_start_capture(cmd),
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        cmdline::GpuSubCommand::StopCapture(cmd) => gpu_stop_capture(cmd),
     };
     match result {
         Ok(response) => {
@@ -555,9 +754,45 @@ fn modify_gpu(cmd: cmdline::GpuCommand) -> std::result::Result<(), ()> {
 }
 
 fn usb_attach(cmd: UsbAttachCommand) -> ModifyUsbResult<UsbControlResult> {
-    let dev_path = Path::new(&cmd.dev_path);
-
-    do_usb_attach(cmd.socket_path, dev_path)
+    if cmd.read_only && cmd.storage.is_none() {
+        return Err(ModifyUsbError::InvalidArguments(
+            "--ro is only valid with --storage".to_string(),
+        ));
+    }
+    match (
+        cmd.usbip,
+        cmd.storage,
+        cmd.keyboard,
+        cmd.tablet,
+        cmd.args.as_slice(),
+    ) {
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        (Some((server, busid)), None, None, None, [socket_path]) => {
+            do_usb_attach_usbip(socket_path, &server, &busid)
+        }
+        (None, Some(image), None, None, [socket_path]) => {
+            do_usb_attach_storage(socket_path, Path::new(&image), cmd.read_only)
+        }
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        (None, None, Some(events_path), None, [socket_path]) => {
+            do_usb_attach_hid(socket_path, UsbHidKind::Keyboard, Path::new(&events_path))
+        }
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        (None, None, None, Some(events_path), [socket_path]) => {
+            do_usb_attach_hid(socket_path, UsbHidKind::Tablet, Path::new(&events_path))
+        }
+        (None, None, None, None, [addr, dev_path, socket_path]) => {
+            parse_bus_id_addr(addr).map_err(ModifyUsbError::InvalidArguments)?;
+            do_usb_attach(socket_path, Path::new(dev_path))
+        }
+        (None, None, None, None, _) => Err(ModifyUsbError::InvalidArguments(
+            "expected BUS_ID:ADDR:BUS_NUM:DEV_NUM DEV_PATH VM_SOCKET".to_string(),
+        )),
+        _ => Err(ModifyUsbError::InvalidArguments(
+            "expected one of --usbip, --storage, --keyboard or --tablet, followed by VM_SOCKET"
+                .to_string(),
+        )),
+    }
 }
 
 fn usb_detach(cmd: cmdline::UsbDetachCommand) -> ModifyUsbResult<UsbControlResult> {
@@ -592,6 +827,9 @@ fn snapshot_vm(cmd: cmdline::SnapshotCommand) -> std::result::Result<(), ()> {
         Take(path) => {
             let req = VmRequest::Snapshot(SnapshotCommand::Take {
                 snapshot_path: path.snapshot_path,
+                compression: path.compression,
+                parent: path.incremental,
+                incremental_base: path.incremental_base,
             });
             (path.socket_path, req)
         }
@@ -606,6 +844,26 @@ fn snapshot_vm(cmd: cmdline::SnapshotCommand) -> std::result::Result<(), ()> {
     vms_request(&request, socket_path)
 }
 
+fn migrate_vm(cmd: cmdline::MigrateCommand) -> std::result::Result<(), ()> {
+    use cmdline::MigrateSubCommands::*;
+    let (socket_path, request) = match cmd.migrate_command {
+        Send(cmd) => (
+            cmd.socket_path,
+            VmRequest::Migrate(MigrateCommand::Send {
+                address: cmd.address,
+            }),
+        ),
+        Receive(cmd) => (
+            cmd.socket_path,
+            VmRequest::Migrate(MigrateCommand::Receive {
+                address: cmd.address,
+                allow_remote: cmd.allow_remote,
+            }),
+        ),
+    };
+    vms_request(&request, socket_path)
+}
+
 #[allow(clippy::unnecessary_wraps)]
 fn pkg_version() -> std::result::Result<(), ()> {
     const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
@@ -765,13 +1023,22 @@ fn crosvm_main<I: IntoIterator<Item = String>>(args: I) -> Result<CommandStatus>
                     CrossPlatformCommands::Gpu(cmd) => {
                         modify_gpu(cmd).map_err(|_| anyhow!("gpu subcommand failed"))
                     }
+                    CrossPlatformCommands::Input(cmd) => {
+                        input_cmd(cmd).map_err(|_| anyhow!("input subcommand failed"))
+                    }
                     CrossPlatformCommands::MakeRT(cmd) => {
                         make_rt(cmd).map_err(|_| anyhow!("make_rt subcommand failed"))
                     }
+                    CrossPlatformCommands::Migrate(cmd) => {
+                        migrate_vm(cmd).map_err(|_| anyhow!("migrate subcommand failed"))
+                    }
                     CrossPlatformCommands::Resume(cmd) => {
                         resume_vms(cmd).map_err(|_| anyhow!("resume subcommand failed"))
                     }
                     CrossPlatformCommands::Run(_) => unreachable!(),
+                    CrossPlatformCommands::Scsi(cmd) => {
+                        scsi_cmd(cmd).map_err(|_| anyhow!("scsi subcommand failed"))
+                    }
                     CrossPlatformCommands::Stop(cmd) => {
                         stop_vms(cmd).map_err(|_| anyhow!("stop subcommand failed"))
                     }
@@ -420,6 +420,7 @@ fn create_multi_touch_device(
         width,
         height,
         name,
+        /* control_tube= */ None,
         virtio::base_features(cfg.protection_type),
     )
     .exit_context(Exit::InputDeviceNew, "failed to set up input device")?;
@@ -431,8 +432,13 @@ fn create_multi_touch_device(
 
 #[cfg(feature = "gpu")]
 fn create_mouse_device(cfg: &Config, event_pipe: StreamChannel, idx: u32) -> DeviceResult {
-    let dev = virtio::input::new_mouse(idx, event_pipe, virtio::base_features(cfg.protection_type))
-        .exit_context(Exit::InputDeviceNew, "failed to set up input device")?;
+    let dev = virtio::input::new_mouse(
+        idx,
+        event_pipe,
+        /* control_tube= */ None,
+        virtio::base_features(cfg.protection_type),
+    )
+    .exit_context(Exit::InputDeviceNew, "failed to set up input device")?;
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
         jail: None,
@@ -727,6 +733,7 @@ fn create_virtio_input_event_devices(
     let dev = virtio::input::new_keyboard(
         /* idx= */ 0,
         keyboard_pipe,
+        /* control_tube= */ None,
         virtio::base_features(cfg.protection_type),
     )
     .exit_context(Exit::InputDeviceNew, "failed to set up input device")?;
@@ -938,6 +945,15 @@ fn handle_readable_event<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                     .try_box_clone()?
                     .restore(snapshot, vcpu_size)
             },
+            |enable| {
+                if enable {
+                    guest_os.vm.enable_guest_memory_dirty_log()?;
+                } else {
+                    guest_os.vm.disable_guest_memory_dirty_log()?;
+                }
+                Ok(())
+            },
+            || Ok(guest_os.vm.get_guest_memory_dirty_log()?),
         );
         (resp, run_mode_opt)
     };
@@ -2056,6 +2072,7 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
             .checked_mul(1024 * 1024)
             .ok_or_else(|| anyhow!("requested memory size too large"))?,
         swiotlb,
+        tpm: None,
         vcpu_count: cfg.vcpu_count.unwrap_or(1),
         fw_cfg_enable: false,
         bootorder_fw_cfg_blob: Vec::new(),
@@ -597,6 +597,37 @@ impl<H: CallbackHandler> Context<H> {
         Ok(())
     }
 
+    /// Forwards `host_port` of `host_addr` to `guest_port` of `guest_addr`, for UDP datagrams if
+    /// `is_udp` or TCP connections otherwise. An unspecified `guest_addr` (0.0.0.0) stands for the
+    /// first address given out by the DHCP server.
+    pub fn add_hostfwd(
+        &mut self,
+        is_udp: bool,
+        host_addr: Ipv4Addr,
+        host_port: u16,
+        guest_addr: Ipv4Addr,
+        guest_port: u16,
+    ) -> Result<()> {
+        // SAFETY:
+        // Safe because self.slirp is guaranteed to be valid, and we check the return value.
+        let ret = unsafe {
+            slirp_add_hostfwd(
+                self.slirp,
+                is_udp as c_int,
+                host_addr.into(),
+                host_port as c_int,
+                guest_addr.into(),
+                guest_port as c_int,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::Slirp(
+                SlirpError::HostFwd(io::Error::last_os_error()),
+            ));
+        }
+        Ok(())
+    }
+
     pub fn connection_info(&mut self) -> &str {
         str::from_utf8(
             // TODO(b/315998194): Add safety comment
@@ -6,6 +6,9 @@
 
 export BINDGEN_LINUX="${PWD}/../../third_party/kernel/v6.1"
 
+# The RISC-V KVM API gained the V extension and the vector registers in 6.5.
+export BINDGEN_LINUX_RISCV64="${PWD}/../../third_party/kernel/v6.6"
+
 export BINDGEN_PLATFORM2="${PWD}/../../platform2"
 
 export BINDGEN_OPTS=(
@@ -50,19 +53,23 @@ bindgen_generate() {
 }
 
 bindgen_cleanup() {
-    rm -rf "${BINDGEN_LINUX_X86_HEADERS}" "${BINDGEN_LINUX_ARM64_HEADERS}"
+    rm -rf "${BINDGEN_LINUX_X86_HEADERS}" "${BINDGEN_LINUX_ARM64_HEADERS}" \
+        "${BINDGEN_LINUX_RISCV64_HEADERS}"
 }
 
-# Install Linux kernel headers for x86 and arm into temporary locations. These are used for KVM bindings.
+# Install Linux kernel headers for x86, arm and riscv into temporary locations. These are used for
+# KVM bindings.
 
 if [[ -z "${BINDGEN_LINUX_X86_HEADERS+x}" || ! -d "${BINDGEN_LINUX_X86_HEADERS}" ||
-    -z "${BINDGEN_LINUX_ARM64_HEADERS+x}" || ! -d "${BINDGEN_LINUX_ARM64_HEADERS}" ]]; then
+    -z "${BINDGEN_LINUX_ARM64_HEADERS+x}" || ! -d "${BINDGEN_LINUX_ARM64_HEADERS}" ||
+    -z "${BINDGEN_LINUX_RISCV64_HEADERS+x}" || ! -d "${BINDGEN_LINUX_RISCV64_HEADERS}" ]]; then
     export BINDGEN_LINUX_X86_HEADERS='/tmp/bindgen_linux_x86_headers'
     export BINDGEN_LINUX_ARM64_HEADERS='/tmp/bindgen_linux_arm64_headers'
+    export BINDGEN_LINUX_RISCV64_HEADERS='/tmp/bindgen_linux_riscv64_headers'
 
     trap bindgen_cleanup EXIT
 
-    echo -n "Installing Linux headers for x86 and arm64..."
+    echo -n "Installing Linux headers for x86, arm64 and riscv64..."
     (
         cd "${BINDGEN_LINUX}"
         nproc=$(nproc)
@@ -70,5 +77,10 @@ if [[ -z "${BINDGEN_LINUX_X86_HEADERS+x}" || ! -d "${BINDGEN_LINUX_X86_HEADERS}"
         make -s headers_install ARCH=arm64 INSTALL_HDR_PATH="${BINDGEN_LINUX_ARM64_HEADERS}" -j "${nproc}"
         make -s mrproper
     )
+    (
+        cd "${BINDGEN_LINUX_RISCV64}"
+        make -s headers_install ARCH=riscv INSTALL_HDR_PATH="${BINDGEN_LINUX_RISCV64_HEADERS}" -j "$(nproc)"
+        make -s mrproper
+    )
     echo " done."
 fi
@@ -2,7 +2,14 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fs::File;
 use std::fs::OpenOptions;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::net::TcpStream;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::os::fd::OwnedFd;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::os::unix::net::UnixStream;
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -15,6 +22,7 @@ use thiserror::Error;
 
 #[cfg(feature = "gpu")]
 pub use crate::gpu::*;
+pub use crate::input::*;
 pub use crate::sys::handle_request;
 #[cfg(any(target_os = "android", target_os = "linux"))]
 pub use crate::sys::handle_request_with_timeout;
@@ -30,8 +38,14 @@ enum ModifyBatError {
 #[sorted]
 #[derive(Error, Debug)]
 pub enum ModifyUsbError {
+    #[error("failed to connect to {0}: {1}")]
+    FailedToConnect(PathBuf, std::io::Error),
+    #[error("failed to connect to the usbip server {0}: {1}")]
+    FailedToConnectServer(String, std::io::Error),
     #[error("failed to open device {0}: {1}")]
     FailedToOpenDevice(PathBuf, base::Error),
+    #[error("invalid arguments: {0}")]
+    InvalidArguments(String),
     #[error("socket failed")]
     SocketFailed,
     #[error("unexpected response: {0}")]
@@ -104,6 +118,54 @@ pub fn do_net_remove<T: AsRef<Path> + std::fmt::Debug>(
     bail!("Unsupported: pci-hotplug feature disabled");
 }
 
+/// Send a `VmRequest` attaching the disk image at `path` to a SCSI logical unit, that expects
+/// `VmResponse::Ok`.
+pub fn do_scsi_attach<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    path: &Path,
+    target: u8,
+    lun: u16,
+    block_size: u32,
+    read_only: bool,
+    cdrom: bool,
+) -> VmsRequestResult {
+    let file = open_scsi_image(path, read_only || cdrom)?;
+    let request = VmRequest::ScsiCommand(ScsiControlCommand::AttachLun {
+        target,
+        lun,
+        path: path.to_path_buf(),
+        file,
+        block_size,
+        read_only,
+        cdrom,
+    });
+    vms_request(&request, socket_path)
+}
+
+/// Send a `VmRequest` inserting the disk image at `path` in a SCSI CD-ROM drive, that expects
+/// `VmResponse::Ok`.
+pub fn do_scsi_insert_medium<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    path: &Path,
+    target: u8,
+    lun: u16,
+) -> VmsRequestResult {
+    let file = open_scsi_image(path, true)?;
+    let request = VmRequest::ScsiCommand(ScsiControlCommand::InsertMedium {
+        target,
+        lun,
+        path: path.to_path_buf(),
+        file,
+    });
+    vms_request(&request, socket_path)
+}
+
+fn open_scsi_image(path: &Path, read_only: bool) -> std::result::Result<File, ()> {
+    open_file_or_duplicate(path, OpenOptions::new().read(true).write(!read_only)).map_err(|e| {
+        println!("failed to open disk image {}: {}", path.display(), e);
+    })
+}
+
 pub fn do_usb_attach<T: AsRef<Path> + std::fmt::Debug>(
     socket_path: T,
     dev_path: &Path,
@@ -120,6 +182,80 @@ pub fn do_usb_attach<T: AsRef<Path> + std::fmt::Debug>(
     }
 }
 
+/// Send a `VmRequest` attaching an emulated USB mass storage device exposing the disk image at
+/// `path`.
+pub fn do_usb_attach_storage<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    path: &Path,
+    read_only: bool,
+) -> ModifyUsbResult<UsbControlResult> {
+    let file = open_file_or_duplicate(path, OpenOptions::new().read(true).write(!read_only))
+        .map_err(|e| ModifyUsbError::FailedToOpenDevice(path.into(), e))?;
+
+    let request = VmRequest::UsbCommand(UsbControlCommand::AttachEmulatedDevice {
+        device: EmulatedUsbDevice::MassStorage {
+            path: path.to_path_buf(),
+            file,
+            read_only,
+        },
+    });
+    let response =
+        handle_request(&request, socket_path).map_err(|_| ModifyUsbError::SocketFailed)?;
+    match response {
+        VmResponse::UsbResponse(usb_resp) => Ok(usb_resp),
+        r => Err(ModifyUsbError::UnexpectedResponse(r)),
+    }
+}
+
+/// Send a `VmRequest` attaching an emulated USB HID device that reads its input events from the
+/// unix socket at `events_path`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn do_usb_attach_hid<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    kind: UsbHidKind,
+    events_path: &Path,
+) -> ModifyUsbResult<UsbControlResult> {
+    let events = UnixStream::connect(events_path)
+        .map_err(|e| ModifyUsbError::FailedToConnect(events_path.into(), e))?;
+
+    let request = VmRequest::UsbCommand(UsbControlCommand::AttachEmulatedDevice {
+        device: EmulatedUsbDevice::Hid {
+            kind,
+            events: File::from(OwnedFd::from(events)),
+        },
+    });
+    let response =
+        handle_request(&request, socket_path).map_err(|_| ModifyUsbError::SocketFailed)?;
+    match response {
+        VmResponse::UsbResponse(usb_resp) => Ok(usb_resp),
+        r => Err(ModifyUsbError::UnexpectedResponse(r)),
+    }
+}
+
+/// Send a `VmRequest` attaching the device `busid` exported by the USB/IP server at `server`, given
+/// as `host:port`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn do_usb_attach_usbip<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    server: &str,
+    busid: &str,
+) -> ModifyUsbResult<UsbControlResult> {
+    // The xhci device is sandboxed, so the connection to the server is made here.
+    let stream = TcpStream::connect(server)
+        .map_err(|e| ModifyUsbError::FailedToConnectServer(server.to_string(), e))?;
+
+    let request = VmRequest::UsbCommand(UsbControlCommand::AttachUsbipDevice {
+        socket: File::from(OwnedFd::from(stream)),
+        busid: busid.to_string(),
+    });
+    let response =
+        handle_request(&request, socket_path).map_err(|_| ModifyUsbError::SocketFailed)?;
+    match response {
+        VmResponse::UsbResponse(usb_resp) => Ok(usb_resp),
+        r => Err(ModifyUsbError::UnexpectedResponse(r)),
+    }
+}
+
 pub fn do_usb_detach<T: AsRef<Path> + std::fmt::Debug>(
     socket_path: T,
     port: u8,
@@ -194,4 +330,26 @@ pub fn do_swap_status<T: AsRef<Path> + std::fmt::Debug>(socket_path: T) -> VmsRe
     }
 }
 
+/// Send a `VmRequest` that lists the internal snapshots of the disk at `disk_index` and print them.
+pub fn do_disk_snapshot_list<T: AsRef<Path> + std::fmt::Debug>(
+    disk_index: usize,
+    socket_path: T,
+) -> VmsRequestResult {
+    let request = VmRequest::DiskCommand {
+        disk_index,
+        command: DiskControlCommand::ListSnapshots,
+    };
+    let response = handle_request(&request, socket_path)?;
+    match &response {
+        VmResponse::DiskSnapshots(_) => {
+            println!("{}", response);
+            Ok(())
+        }
+        r => {
+            println!("unexpected response: {r}");
+            Err(())
+        }
+    }
+}
+
 pub type HandleRequestResult = std::result::Result<VmResponse, ()>;
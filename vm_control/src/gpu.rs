@@ -5,8 +5,15 @@
 use std::collections::BTreeMap as Map;
 use std::fmt;
 use std::fmt::Display;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+#[cfg(any(target_os = "android", target_os = "linux"))]
+use std::num::NonZeroU32;
 use std::path::Path;
+use std::path::PathBuf;
 
+use base::with_as_descriptor;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
@@ -122,9 +129,33 @@ impl Default for DisplayParameters {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum GpuControlCommand {
-    AddDisplays { displays: Vec<DisplayParameters> },
+    AddDisplays {
+        displays: Vec<DisplayParameters>,
+    },
     ListDisplays,
-    RemoveDisplays { display_ids: Vec<u32> },
+    RemoveDisplays {
+        display_ids: Vec<u32>,
+    },
+    /// Write the current contents of the display `display_id` to `file` as a PNG image.
+    Screenshot {
+        display_id: u32,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    /// Write every `interval`th frame flushed to the display `display_id` to `directory` as a PNG
+    /// image, until the capture is stopped.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    StartCapture {
+        display_id: u32,
+        interval: NonZeroU32,
+        #[serde(with = "with_as_descriptor")]
+        directory: File,
+    },
+    /// Stop the capture of the frames of the display `display_id`.
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    StopCapture {
+        display_id: u32,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -137,6 +168,22 @@ pub enum GpuControlResult {
     NoSuchDisplay {
         display_id: u32,
     },
+    Screenshot {
+        width: u32,
+        height: u32,
+    },
+    NoScanoutResource {
+        display_id: u32,
+    },
+    ReadbackFailed(String),
+    CaptureStarted,
+    CaptureFailed(String),
+    CaptureStopped {
+        frames: u64,
+    },
+    NoCapture {
+        display_id: u32,
+    },
 }
 
 impl Display for GpuControlResult {
@@ -155,6 +202,15 @@ impl Display for GpuControlResult {
             }
             TooManyDisplays(n) => write!(f, "too_many_displays {}", n),
             NoSuchDisplay { display_id } => write!(f, "no_such_display {}", display_id),
+            Screenshot { width, height } => write!(f, "screenshot {}x{}", width, height),
+            NoScanoutResource { display_id } => {
+                write!(f, "no_scanout_resource {}", display_id)
+            }
+            ReadbackFailed(e) => write!(f, "readback_failed {}", e),
+            CaptureStarted => write!(f, "capture started"),
+            CaptureFailed(e) => write!(f, "capture_failed {}", e),
+            CaptureStopped { frames } => write!(f, "capture stopped after {} frames", frames),
+            NoCapture { display_id } => write!(f, "no_capture {}", display_id),
         }
     }
 }
@@ -164,6 +220,7 @@ pub enum ModifyGpuError {
     UnexpectedResponse(VmResponse),
     UnknownCommand(String),
     GpuControl(GpuControlResult),
+    OpenFailed(PathBuf, io::Error),
 }
 
 impl fmt::Display for ModifyGpuError {
@@ -175,6 +232,7 @@ impl fmt::Display for ModifyGpuError {
             UnexpectedResponse(r) => write!(f, "unexpected response: {}", r),
             UnknownCommand(c) => write!(f, "unknown display command: `{}`", c),
             GpuControl(e) => write!(f, "{}", e),
+            OpenFailed(path, e) => write!(f, "failed to open {}: {}", path.display(), e),
         }
     }
 }
@@ -218,3 +276,56 @@ pub fn do_gpu_display_remove<T: AsRef<Path> + std::fmt::Debug>(
         .map_err(|_| ModifyGpuError::SocketFailed)?
         .into()
 }
+
+pub fn do_gpu_screenshot<T: AsRef<Path> + std::fmt::Debug>(
+    control_socket_path: T,
+    display_id: u32,
+    out: &Path,
+) -> ModifyGpuResult {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out)
+        .map_err(|e| ModifyGpuError::OpenFailed(out.to_path_buf(), e))?;
+    let request = VmRequest::GpuCommand(GpuControlCommand::Screenshot { display_id, file });
+    let result = handle_request(&request, control_socket_path)
+        .map_err(|_| ModifyGpuError::SocketFailed)
+        .and_then(ModifyGpuResult::from);
+    if !matches!(result, Ok(GpuControlResult::Screenshot { .. })) {
+        // Don't leave an empty image behind.
+        let _ = std::fs::remove_file(out);
+    }
+    result
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn do_gpu_start_capture<T: AsRef<Path> + std::fmt::Debug>(
+    control_socket_path: T,
+    display_id: u32,
+    interval: NonZeroU32,
+    out_dir: &Path,
+) -> ModifyGpuResult {
+    let directory = std::fs::create_dir_all(out_dir)
+        .and_then(|_| File::open(out_dir))
+        .map_err(|e| ModifyGpuError::OpenFailed(out_dir.to_path_buf(), e))?;
+    let request = VmRequest::GpuCommand(GpuControlCommand::StartCapture {
+        display_id,
+        interval,
+        directory,
+    });
+    handle_request(&request, control_socket_path)
+        .map_err(|_| ModifyGpuError::SocketFailed)?
+        .into()
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn do_gpu_stop_capture<T: AsRef<Path> + std::fmt::Debug>(
+    control_socket_path: T,
+    display_id: u32,
+) -> ModifyGpuResult {
+    let request = VmRequest::GpuCommand(GpuControlCommand::StopCapture { display_id });
+    handle_request(&request, control_socket_path)
+        .map_err(|_| ModifyGpuError::SocketFailed)?
+        .into()
+}
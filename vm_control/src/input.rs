@@ -0,0 +1,787 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Injection of keyboard, pointer and touch events into the virtio-input devices of a VM.
+//!
+//! Events are grouped in reports, each terminated by a `SYN_REPORT` event like the reports of a
+//! real input device, and carry the time at which they should reach the guest relative to the
+//! start of the injection. virtio-input events have no timestamps of their own: the guest stamps
+//! them when they arrive, so the client paces the reports it sends instead.
+
+use std::fmt;
+use std::fmt::Display;
+use std::io;
+use std::io::BufRead;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use data_model::Le16;
+use data_model::SLe32;
+use linux_input_sys::constants::*;
+use linux_input_sys::virtio_input_event;
+use remain::sorted;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::sys::handle_request;
+use crate::VmRequest;
+use crate::VmResponse;
+
+/// An event of the Linux input subsystem, as sent to the guest by a virtio-input device.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub type_: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl InputEvent {
+    pub fn new(type_: u16, code: u16, value: i32) -> InputEvent {
+        InputEvent { type_, code, value }
+    }
+
+    /// Returns the `SYN_REPORT` event terminating a report.
+    pub fn syn() -> InputEvent {
+        InputEvent::new(EV_SYN, SYN_REPORT, 0)
+    }
+
+    pub fn is_syn_report(&self) -> bool {
+        self.type_ == EV_SYN && self.code == SYN_REPORT
+    }
+
+    fn key(code: u16, down: bool) -> InputEvent {
+        InputEvent::new(EV_KEY, code, down as i32)
+    }
+}
+
+impl From<InputEvent> for virtio_input_event {
+    fn from(event: InputEvent) -> Self {
+        virtio_input_event {
+            type_: Le16::from(event.type_),
+            code: Le16::from(event.code),
+            value: SLe32::from(event.value),
+        }
+    }
+}
+
+/// The kind of a virtio-input device, named after the command-line option that created it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputDeviceKind {
+    DisplayWindowMouse,
+    DisplayWindowKeyboard,
+    SingleTouch,
+    MultiTouch,
+    Trackpad,
+    Mouse,
+    Keyboard,
+    Switches,
+    Rotary,
+    Evdev,
+}
+
+impl Display for InputDeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::InputDeviceKind::*;
+
+        match self {
+            DisplayWindowMouse => write!(f, "display-window-mouse"),
+            DisplayWindowKeyboard => write!(f, "display-window-keyboard"),
+            SingleTouch => write!(f, "single-touch"),
+            MultiTouch => write!(f, "multi-touch"),
+            Trackpad => write!(f, "trackpad"),
+            Mouse => write!(f, "mouse"),
+            Keyboard => write!(f, "keyboard"),
+            Switches => write!(f, "switches"),
+            Rotary => write!(f, "rotary"),
+            Evdev => write!(f, "evdev"),
+        }
+    }
+}
+
+/// A virtio-input device accepting injected events.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InputDeviceInfo {
+    pub kind: InputDeviceKind,
+    /// The socket or event device the device also forwards events from, if any.
+    pub path: Option<PathBuf>,
+}
+
+/// Commands to list the virtio-input devices and inject events through them.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum InputControlCommand {
+    /// List the virtio-input devices, in the order of their indices.
+    ListDevices,
+    /// Send `events`, made of whole reports, to the guest through the device `device_index`.
+    SendEvents {
+        device_index: usize,
+        events: Vec<InputEvent>,
+    },
+}
+
+/// The events of a report, and the time they should be sent at after the start of an injection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputReport {
+    pub time: Duration,
+    /// The events of the report, the last being `SYN_REPORT`.
+    pub events: Vec<InputEvent>,
+}
+
+// Builds reports `interval` apart, terminating each of them with `SYN_REPORT`.
+struct ReportBuilder {
+    reports: Vec<InputReport>,
+    time: Duration,
+    interval: Duration,
+}
+
+impl ReportBuilder {
+    fn new(interval: Duration) -> ReportBuilder {
+        ReportBuilder {
+            reports: Vec::new(),
+            time: Duration::ZERO,
+            interval,
+        }
+    }
+
+    fn push<I: IntoIterator<Item = InputEvent>>(&mut self, events: I) {
+        let mut events: Vec<InputEvent> = events.into_iter().collect();
+        events.push(InputEvent::syn());
+        self.reports.push(InputReport {
+            time: self.time,
+            events,
+        });
+        self.time += self.interval;
+    }
+
+    fn finish(self) -> Vec<InputReport> {
+        self.reports
+    }
+}
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum ModifyInputError {
+    #[error("invalid event on line {0}: `{1}`")]
+    InvalidEvent(usize, String),
+    #[error("a touch gesture needs at least one finger")]
+    NoTouchStrokes,
+    #[error("the event queue of input device {0} is full; is the guest driver reading it?")]
+    QueueFull(usize),
+    #[error("failed to read the events: {0}")]
+    ReadEvents(io::Error),
+    #[error("socket failed")]
+    SocketFailed,
+    #[error("unexpected response: {0}")]
+    UnexpectedResponse(VmResponse),
+    #[error("unknown key `{0}`")]
+    UnknownKey(String),
+    #[error("no key of a US keyboard types {0:?}")]
+    UnsupportedCharacter(char),
+}
+
+pub type ModifyInputResult<T> = std::result::Result<T, ModifyInputError>;
+
+const LETTERS: [u16; 26] = [
+    KEY_A, KEY_B, KEY_C, KEY_D, KEY_E, KEY_F, KEY_G, KEY_H, KEY_I, KEY_J, KEY_K, KEY_L, KEY_M,
+    KEY_N, KEY_O, KEY_P, KEY_Q, KEY_R, KEY_S, KEY_T, KEY_U, KEY_V, KEY_W, KEY_X, KEY_Y, KEY_Z,
+];
+
+const DIGITS: [u16; 10] = [
+    KEY_0, KEY_1, KEY_2, KEY_3, KEY_4, KEY_5, KEY_6, KEY_7, KEY_8, KEY_9,
+];
+
+const KEYPAD_DIGITS: [u16; 10] = [
+    KEY_KP0, KEY_KP1, KEY_KP2, KEY_KP3, KEY_KP4, KEY_KP5, KEY_KP6, KEY_KP7, KEY_KP8, KEY_KP9,
+];
+
+const FUNCTION_KEYS: [u16; 12] = [
+    KEY_F1, KEY_F2, KEY_F3, KEY_F4, KEY_F5, KEY_F6, KEY_F7, KEY_F8, KEY_F9, KEY_F10, KEY_F11,
+    KEY_F12,
+];
+
+// The keys named after their Linux constant, without the `KEY_` prefix, and a few aliases.
+const KEY_NAMES: &[(&str, u16)] = &[
+    ("esc", KEY_ESC),
+    ("minus", KEY_MINUS),
+    ("equal", KEY_EQUAL),
+    ("backspace", KEY_BACKSPACE),
+    ("tab", KEY_TAB),
+    ("leftbrace", KEY_LEFTBRACE),
+    ("rightbrace", KEY_RIGHTBRACE),
+    ("enter", KEY_ENTER),
+    ("semicolon", KEY_SEMICOLON),
+    ("apostrophe", KEY_APOSTROPHE),
+    ("grave", KEY_GRAVE),
+    ("backslash", KEY_BACKSLASH),
+    ("comma", KEY_COMMA),
+    ("dot", KEY_DOT),
+    ("slash", KEY_SLASH),
+    ("space", KEY_SPACE),
+    ("leftctrl", KEY_LEFTCTRL),
+    ("ctrl", KEY_LEFTCTRL),
+    ("rightctrl", KEY_RIGHTCTRL),
+    ("leftshift", KEY_LEFTSHIFT),
+    ("shift", KEY_LEFTSHIFT),
+    ("rightshift", KEY_RIGHTSHIFT),
+    ("leftalt", KEY_LEFTALT),
+    ("alt", KEY_LEFTALT),
+    ("rightalt", KEY_RIGHTALT),
+    ("leftmeta", KEY_LEFTMETA),
+    ("meta", KEY_LEFTMETA),
+    ("rightmeta", KEY_RIGHTMETA),
+    ("compose", KEY_COMPOSE),
+    ("capslock", KEY_CAPSLOCK),
+    ("numlock", KEY_NUMLOCK),
+    ("scrolllock", KEY_SCROLLLOCK),
+    ("sysrq", KEY_SYSRQ),
+    ("pause", KEY_PAUSE),
+    ("insert", KEY_INSERT),
+    ("delete", KEY_DELETE),
+    ("home", KEY_HOME),
+    ("end", KEY_END),
+    ("pageup", KEY_PAGEUP),
+    ("pagedown", KEY_PAGEDOWN),
+    ("up", KEY_UP),
+    ("down", KEY_DOWN),
+    ("left", KEY_LEFT),
+    ("right", KEY_RIGHT),
+    ("kpenter", KEY_KPENTER),
+    ("kpplus", KEY_KPPLUS),
+    ("kpminus", KEY_KPMINUS),
+    ("kpasterisk", KEY_KPASTERISK),
+    ("kpslash", KEY_KPSLASH),
+    ("kpdot", KEY_KPDOT),
+    ("menu", KEY_MENU),
+    ("back", KEY_BACK),
+    ("forward", KEY_FORWARD),
+    ("mute", KEY_MUTE),
+    ("volumedown", KEY_VOLUMEDOWN),
+    ("volumeup", KEY_VOLUMEUP),
+    ("power", KEY_POWER),
+    ("sleep", KEY_SLEEP),
+    ("wakeup", KEY_WAKEUP),
+    ("btn_left", BTN_LEFT),
+    ("btn_right", BTN_RIGHT),
+    ("btn_middle", BTN_MIDDLE),
+    ("btn_side", BTN_SIDE),
+    ("btn_extra", BTN_EXTRA),
+    ("btn_touch", BTN_TOUCH),
+];
+
+// Parses a decimal number, or a hexadecimal one prefixed with `0x`.
+fn parse_code(code: &str) -> Option<u16> {
+    match code.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => code.parse().ok(),
+    }
+}
+
+/// Returns the Linux code of the key `name`.
+///
+/// Keys are named after their Linux constant, case-insensitively and with or without the `KEY_`
+/// prefix, e.g. `KEY_LEFTCTRL`, `leftctrl` or `a`. Buttons keep their `BTN_` prefix. Codes without
+/// a name here may be given as a number instead.
+pub fn parse_key(name: &str) -> ModifyInputResult<u16> {
+    let lower = name.to_ascii_lowercase();
+    let key = lower.strip_prefix("key_").unwrap_or(&lower);
+    let code = match *key.as_bytes() {
+        [c @ b'a'..=b'z'] => Some(LETTERS[(c - b'a') as usize]),
+        [c @ b'0'..=b'9'] => Some(DIGITS[(c - b'0') as usize]),
+        _ => None,
+    }
+    .or_else(|| {
+        let number: usize = key.strip_prefix('f')?.parse().ok()?;
+        FUNCTION_KEYS.get(number.checked_sub(1)?).copied()
+    })
+    .or_else(|| {
+        let number: usize = key.strip_prefix("kp")?.parse().ok()?;
+        KEYPAD_DIGITS.get(number).copied()
+    })
+    .or_else(|| {
+        KEY_NAMES
+            .iter()
+            .find(|(key_name, _)| *key_name == key)
+            .map(|(_, code)| *code)
+    })
+    .or_else(|| parse_code(key));
+    code.ok_or_else(|| ModifyInputError::UnknownKey(name.to_owned()))
+}
+
+/// Returns the Linux code of the mouse button `name`, either `left`, `right`, `middle`, `side`,
+/// `extra`, or any key accepted by `parse_key`.
+pub fn parse_button(name: &str) -> ModifyInputResult<u16> {
+    match name {
+        "left" => Ok(BTN_LEFT),
+        "right" => Ok(BTN_RIGHT),
+        "middle" => Ok(BTN_MIDDLE),
+        "side" => Ok(BTN_SIDE),
+        "extra" => Ok(BTN_EXTRA),
+        _ => parse_key(name),
+    }
+}
+
+// Returns the key typing `c` on a US keyboard, and whether shift must be held while pressing it.
+fn char_to_key(c: char) -> Option<(u16, bool)> {
+    let key = match c {
+        'a'..='z' => (LETTERS[c as usize - 'a' as usize], false),
+        'A'..='Z' => (LETTERS[c as usize - 'A' as usize], true),
+        '0'..='9' => (DIGITS[c as usize - '0' as usize], false),
+        ' ' => (KEY_SPACE, false),
+        '\n' => (KEY_ENTER, false),
+        '\t' => (KEY_TAB, false),
+        '!' => (KEY_1, true),
+        '@' => (KEY_2, true),
+        '#' => (KEY_3, true),
+        '$' => (KEY_4, true),
+        '%' => (KEY_5, true),
+        '^' => (KEY_6, true),
+        '&' => (KEY_7, true),
+        '*' => (KEY_8, true),
+        '(' => (KEY_9, true),
+        ')' => (KEY_0, true),
+        '-' => (KEY_MINUS, false),
+        '_' => (KEY_MINUS, true),
+        '=' => (KEY_EQUAL, false),
+        '+' => (KEY_EQUAL, true),
+        '[' => (KEY_LEFTBRACE, false),
+        '{' => (KEY_LEFTBRACE, true),
+        ']' => (KEY_RIGHTBRACE, false),
+        '}' => (KEY_RIGHTBRACE, true),
+        ';' => (KEY_SEMICOLON, false),
+        ':' => (KEY_SEMICOLON, true),
+        '\'' => (KEY_APOSTROPHE, false),
+        '"' => (KEY_APOSTROPHE, true),
+        '`' => (KEY_GRAVE, false),
+        '~' => (KEY_GRAVE, true),
+        '\\' => (KEY_BACKSLASH, false),
+        '|' => (KEY_BACKSLASH, true),
+        ',' => (KEY_COMMA, false),
+        '<' => (KEY_COMMA, true),
+        '.' => (KEY_DOT, false),
+        '>' => (KEY_DOT, true),
+        '/' => (KEY_SLASH, false),
+        '?' => (KEY_SLASH, true),
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// Returns the reports pressing `keys` in order, then releasing them in the reverse order, one
+/// key per report and `interval` apart.
+pub fn key_reports(keys: &[u16], interval: Duration) -> Vec<InputReport> {
+    let mut builder = ReportBuilder::new(interval);
+    for &key in keys {
+        builder.push([InputEvent::key(key, true)]);
+    }
+    for &key in keys.iter().rev() {
+        builder.push([InputEvent::key(key, false)]);
+    }
+    builder.finish()
+}
+
+/// Returns the reports typing `text` on a US keyboard, `interval` apart.
+pub fn text_reports(text: &str, interval: Duration) -> ModifyInputResult<Vec<InputReport>> {
+    let mut builder = ReportBuilder::new(interval);
+    for c in text.chars() {
+        let (key, shift) = char_to_key(c).ok_or(ModifyInputError::UnsupportedCharacter(c))?;
+        if shift {
+            builder.push([
+                InputEvent::key(KEY_LEFTSHIFT, true),
+                InputEvent::key(key, true),
+            ]);
+            builder.push([
+                InputEvent::key(key, false),
+                InputEvent::key(KEY_LEFTSHIFT, false),
+            ]);
+        } else {
+            builder.push([InputEvent::key(key, true)]);
+            builder.push([InputEvent::key(key, false)]);
+        }
+    }
+    Ok(builder.finish())
+}
+
+/// Returns the reports pressing then releasing `button`, `interval` apart.
+pub fn button_reports(button: u16, interval: Duration) -> Vec<InputReport> {
+    key_reports(&[button], interval)
+}
+
+/// Returns the report moving a relative pointer by `dx` and `dy`.
+pub fn relative_motion_reports(dx: i32, dy: i32) -> Vec<InputReport> {
+    let mut builder = ReportBuilder::new(Duration::ZERO);
+    builder.push(
+        [(REL_X, dx), (REL_Y, dy)]
+            .into_iter()
+            .filter(|&(_, delta)| delta != 0)
+            .map(|(code, delta)| InputEvent::new(EV_REL, code, delta)),
+    );
+    builder.finish()
+}
+
+/// Returns the report moving an absolute pointer to `x` and `y`.
+pub fn absolute_motion_reports(x: i32, y: i32) -> Vec<InputReport> {
+    let mut builder = ReportBuilder::new(Duration::ZERO);
+    builder.push([
+        InputEvent::new(EV_ABS, ABS_X, x),
+        InputEvent::new(EV_ABS, ABS_Y, y),
+    ]);
+    builder.finish()
+}
+
+/// The straight path of a finger on a touch device during a gesture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TouchStroke {
+    pub start: (i32, i32),
+    pub end: (i32, i32),
+}
+
+impl TouchStroke {
+    // Returns the position of the finger after `step` of `steps` equal steps.
+    fn position(&self, step: u32, steps: u32) -> (i32, i32) {
+        let interpolate = |start: i32, end: i32| {
+            start + ((end as i64 - start as i64) * step as i64 / steps as i64) as i32
+        };
+        (
+            interpolate(self.start.0, self.end.0),
+            interpolate(self.start.1, self.end.1),
+        )
+    }
+}
+
+/// Returns the reports of a gesture moving a finger along each of `strokes` at the same time,
+/// in `steps` steps lasting `duration` overall, before lifting them.
+///
+/// Fingers are reported in the slots of the multi-touch protocol B, and the first finger is also
+/// reported with the single-touch events so that the gesture works on single-touch devices.
+pub fn touch_reports(
+    strokes: &[TouchStroke],
+    steps: u32,
+    duration: Duration,
+) -> ModifyInputResult<Vec<InputReport>> {
+    if strokes.is_empty() {
+        return Err(ModifyInputError::NoTouchStrokes);
+    }
+    let steps = steps.max(1);
+    let mut builder = ReportBuilder::new(duration / steps);
+    for step in 0..=steps {
+        let mut events = Vec::new();
+        for (slot, stroke) in strokes.iter().enumerate() {
+            let (x, y) = stroke.position(step, steps);
+            events.push(InputEvent::new(EV_ABS, ABS_MT_SLOT, slot as i32));
+            if step == 0 {
+                events.push(InputEvent::new(EV_ABS, ABS_MT_TRACKING_ID, slot as i32));
+            }
+            events.push(InputEvent::new(EV_ABS, ABS_MT_POSITION_X, x));
+            events.push(InputEvent::new(EV_ABS, ABS_MT_POSITION_Y, y));
+        }
+        if let Some(stroke) = strokes.first() {
+            let (x, y) = stroke.position(step, steps);
+            if step == 0 {
+                events.push(InputEvent::key(BTN_TOUCH, true));
+            }
+            events.push(InputEvent::new(EV_ABS, ABS_X, x));
+            events.push(InputEvent::new(EV_ABS, ABS_Y, y));
+        }
+        builder.push(events);
+    }
+    // Lift the fingers at the time of the last motion.
+    builder.time = duration;
+    let mut events = Vec::new();
+    for slot in 0..strokes.len() {
+        events.push(InputEvent::new(EV_ABS, ABS_MT_SLOT, slot as i32));
+        events.push(InputEvent::new(EV_ABS, ABS_MT_TRACKING_ID, -1));
+    }
+    events.push(InputEvent::key(BTN_TOUCH, false));
+    builder.push(events);
+    Ok(builder.finish())
+}
+
+// Parses the `<seconds>.<microseconds>` timestamp of an event recorded by evemu.
+fn parse_timestamp(timestamp: &str) -> Option<Duration> {
+    let (seconds, micros) = timestamp.split_once('.')?;
+    Some(Duration::from_secs(seconds.parse().ok()?) + Duration::from_micros(micros.parse().ok()?))
+}
+
+/// Parses the events recorded by `evemu-record` into reports timed relative to the first event.
+///
+/// Each `E: <seconds>.<microseconds> <type> <code> <value>` line is an event, with its type and
+/// code in hexadecimal. The other lines, which describe the recorded device, and the comments are
+/// ignored. Events following the last `SYN_REPORT` are terminated by one.
+pub fn parse_evemu_events<R: BufRead>(reader: R) -> ModifyInputResult<Vec<InputReport>> {
+    let mut reports = Vec::new();
+    let mut events = Vec::new();
+    let mut start = None;
+    let mut time = Duration::ZERO;
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(ModifyInputError::ReadEvents)?;
+        let Some(event) = line.strip_prefix("E:") else {
+            continue;
+        };
+        let invalid = || ModifyInputError::InvalidEvent(index + 1, line.clone());
+        // evemu names the event in a trailing comment.
+        let fields: Vec<&str> = event
+            .split('#')
+            .next()
+            .unwrap()
+            .split_whitespace()
+            .collect();
+        let [timestamp, type_, code, value] = fields[..] else {
+            return Err(invalid());
+        };
+        let timestamp = parse_timestamp(timestamp).ok_or_else(invalid)?;
+        let event = InputEvent::new(
+            u16::from_str_radix(type_, 16).map_err(|_| invalid())?,
+            u16::from_str_radix(code, 16).map_err(|_| invalid())?,
+            value.parse().map_err(|_| invalid())?,
+        );
+        time = timestamp.saturating_sub(*start.get_or_insert(timestamp));
+        events.push(event);
+        if event.is_syn_report() {
+            reports.push(InputReport {
+                time,
+                events: std::mem::take(&mut events),
+            });
+        }
+    }
+    if !events.is_empty() {
+        events.push(InputEvent::syn());
+        reports.push(InputReport { time, events });
+    }
+    Ok(reports)
+}
+
+/// Prints the virtio-input devices of the VM, in the order of their indices.
+pub fn do_input_list<T: AsRef<Path> + std::fmt::Debug>(
+    control_socket_path: T,
+) -> ModifyInputResult<()> {
+    let request = VmRequest::InputCommand(InputControlCommand::ListDevices);
+    match handle_request(&request, control_socket_path) {
+        Ok(response @ VmResponse::InputDevices(_)) => {
+            println!("{}", response);
+            Ok(())
+        }
+        Ok(r) => Err(ModifyInputError::UnexpectedResponse(r)),
+        Err(()) => Err(ModifyInputError::SocketFailed),
+    }
+}
+
+/// Sends `reports` to the guest through the virtio-input device `device_index`, waiting for the
+/// time of each report to come. Reports due at the same time are sent together.
+pub fn do_input_send<T: AsRef<Path> + std::fmt::Debug>(
+    control_socket_path: T,
+    device_index: usize,
+    reports: &[InputReport],
+) -> ModifyInputResult<()> {
+    let start = Instant::now();
+    let mut remaining = reports;
+    while let Some(first) = remaining.first() {
+        let count = remaining
+            .iter()
+            .take_while(|report| report.time == first.time)
+            .count();
+        let (batch, rest) = remaining.split_at(count);
+        remaining = rest;
+
+        if let Some(delay) = (start + first.time).checked_duration_since(Instant::now()) {
+            thread::sleep(delay);
+        }
+        let request = VmRequest::InputCommand(InputControlCommand::SendEvents {
+            device_index,
+            events: batch
+                .iter()
+                .flat_map(|report| report.events.iter().copied())
+                .collect(),
+        });
+        match handle_request(&request, &control_socket_path) {
+            Ok(VmResponse::Ok) => {}
+            Ok(VmResponse::Err(e)) if e.errno() == libc::EAGAIN => {
+                return Err(ModifyInputError::QueueFull(device_index))
+            }
+            Ok(r) => return Err(ModifyInputError::UnexpectedResponse(r)),
+            Err(()) => return Err(ModifyInputError::SocketFailed),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: u16, value: i32) -> InputEvent {
+        InputEvent::new(EV_KEY, code, value)
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(parse_key("a").unwrap(), KEY_A);
+        assert_eq!(parse_key("1").unwrap(), KEY_1);
+        assert_eq!(parse_key("30").unwrap(), KEY_A);
+        assert_eq!(parse_key("KEY_LEFTCTRL").unwrap(), KEY_LEFTCTRL);
+        assert_eq!(parse_key("F12").unwrap(), KEY_F12);
+        assert_eq!(parse_key("kp7").unwrap(), KEY_KP7);
+        assert_eq!(parse_key("0x1c").unwrap(), KEY_ENTER);
+        assert_eq!(parse_key("btn_left").unwrap(), BTN_LEFT);
+        assert_eq!(parse_button("right").unwrap(), BTN_RIGHT);
+        assert!(parse_key("f13").is_err());
+        assert!(parse_key("hyper").is_err());
+    }
+
+    #[test]
+    fn key_combination() {
+        let ms = Duration::from_millis;
+        let reports = key_reports(&[KEY_LEFTCTRL, KEY_C], ms(10));
+        let expected: Vec<(Duration, InputEvent)> = vec![
+            (ms(0), key(KEY_LEFTCTRL, 1)),
+            (ms(10), key(KEY_C, 1)),
+            (ms(20), key(KEY_C, 0)),
+            (ms(30), key(KEY_LEFTCTRL, 0)),
+        ];
+        assert_eq!(reports.len(), expected.len());
+        for (report, (time, event)) in reports.iter().zip(expected) {
+            assert_eq!(report.time, time);
+            assert_eq!(report.events, [event, InputEvent::syn()]);
+        }
+    }
+
+    #[test]
+    fn text() {
+        let reports = text_reports("a!", Duration::ZERO).unwrap();
+        let events: Vec<Vec<InputEvent>> = reports.into_iter().map(|r| r.events).collect();
+        assert_eq!(
+            events,
+            [
+                vec![key(KEY_A, 1), InputEvent::syn()],
+                vec![key(KEY_A, 0), InputEvent::syn()],
+                vec![key(KEY_LEFTSHIFT, 1), key(KEY_1, 1), InputEvent::syn()],
+                vec![key(KEY_1, 0), key(KEY_LEFTSHIFT, 0), InputEvent::syn()],
+            ]
+        );
+        assert!(matches!(
+            text_reports("€", Duration::ZERO),
+            Err(ModifyInputError::UnsupportedCharacter('€'))
+        ));
+    }
+
+    #[test]
+    fn touch_gesture() {
+        let stroke = TouchStroke {
+            start: (0, 100),
+            end: (100, 0),
+        };
+        let reports = touch_reports(&[stroke, stroke], 2, Duration::from_millis(100)).unwrap();
+        let times: Vec<u128> = reports.iter().map(|r| r.time.as_millis()).collect();
+        assert_eq!(times, [0, 50, 100, 100]);
+        assert!(reports
+            .iter()
+            .all(|r| r.events.last() == Some(&InputEvent::syn())));
+
+        // Both fingers go down in their own slot, and the first one also drives the single-touch
+        // events.
+        let abs = |code, value| InputEvent::new(EV_ABS, code, value);
+        assert_eq!(
+            reports[0].events,
+            [
+                abs(ABS_MT_SLOT, 0),
+                abs(ABS_MT_TRACKING_ID, 0),
+                abs(ABS_MT_POSITION_X, 0),
+                abs(ABS_MT_POSITION_Y, 100),
+                abs(ABS_MT_SLOT, 1),
+                abs(ABS_MT_TRACKING_ID, 1),
+                abs(ABS_MT_POSITION_X, 0),
+                abs(ABS_MT_POSITION_Y, 100),
+                key(BTN_TOUCH, 1),
+                abs(ABS_X, 0),
+                abs(ABS_Y, 100),
+                InputEvent::syn(),
+            ]
+        );
+        assert_eq!(reports[1].events[1], abs(ABS_MT_POSITION_X, 50));
+        assert_eq!(
+            reports[3].events,
+            [
+                abs(ABS_MT_SLOT, 0),
+                abs(ABS_MT_TRACKING_ID, -1),
+                abs(ABS_MT_SLOT, 1),
+                abs(ABS_MT_TRACKING_ID, -1),
+                key(BTN_TOUCH, 0),
+                InputEvent::syn(),
+            ]
+        );
+
+        assert!(matches!(
+            touch_reports(&[], 2, Duration::from_millis(100)),
+            Err(ModifyInputError::NoTouchStrokes)
+        ));
+    }
+
+    #[test]
+    fn evemu_replay() {
+        let recording = "\
+# EVEMU 1.3
+N: test device
+E: 10.500000 0001 001e 0001\t# EV_KEY / KEY_A 1
+E: 10.500000 0000 0000 0000\t# ------------ SYN_REPORT (0) ----------
+E: 10.750000 0001 001e 0000
+E: 10.750000 0000 0000 0000
+E: 11.000000 0002 0000 -3
+";
+        let reports = parse_evemu_events(recording.as_bytes()).unwrap();
+        assert_eq!(
+            reports,
+            [
+                InputReport {
+                    time: Duration::ZERO,
+                    events: vec![key(KEY_A, 1), InputEvent::syn()],
+                },
+                InputReport {
+                    time: Duration::from_millis(250),
+                    events: vec![key(KEY_A, 0), InputEvent::syn()],
+                },
+                InputReport {
+                    time: Duration::from_millis(500),
+                    events: vec![InputEvent::new(EV_REL, REL_X, -3), InputEvent::syn()],
+                },
+            ]
+        );
+
+        assert!(matches!(
+            parse_evemu_events("E: 0.000000 0001 001e\n".as_bytes()),
+            Err(ModifyInputError::InvalidEvent(1, _))
+        ));
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn full_device_queue() {
+        use base::Tube;
+
+        use crate::handle_input_command;
+
+        // The device end is never read, as when the worker's queue is full.
+        let (host_tube, _device_tube) = Tube::pair().unwrap();
+        host_tube
+            .set_send_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let input_host_tubes = [(
+            InputDeviceInfo {
+                kind: InputDeviceKind::Keyboard,
+                path: None,
+            },
+            host_tube,
+        )];
+        let command = InputControlCommand::SendEvents {
+            device_index: 0,
+            events: vec![key(KEY_A, 1), InputEvent::syn()],
+        };
+        let response = (0..100_000)
+            .map(|_| handle_input_command(&command, &input_host_tubes))
+            .find(|response| !matches!(response, VmResponse::Ok))
+            .expect("the socket never filled up");
+        assert!(matches!(response, VmResponse::Err(e) if e.errno() == libc::EAGAIN));
+    }
+}
@@ -15,6 +15,7 @@ pub mod api;
 pub mod gdb;
 #[cfg(feature = "gpu")]
 pub mod gpu;
+pub mod input;
 
 #[cfg(any(target_os = "android", target_os = "linux"))]
 use base::linux::MemoryMappingBuilderUnix;
@@ -26,6 +27,8 @@ use hypervisor::MemRegion;
 #[cfg(feature = "balloon")]
 mod balloon_tube;
 pub mod client;
+pub mod migration;
+pub mod snapshot_format;
 pub mod sys;
 
 #[cfg(target_arch = "x86_64")]
@@ -37,6 +40,7 @@ use std::convert::TryInto;
 use std::fmt;
 use std::fmt::Display;
 use std::fs::File;
+use std::io;
 use std::path::PathBuf;
 use std::result::Result as StdResult;
 use std::str::FromStr;
@@ -63,6 +67,7 @@ use base::Result;
 use base::SafeDescriptor;
 use base::SharedMemory;
 use base::Tube;
+use base::TubeError;
 use hypervisor::Datamatch;
 use hypervisor::IoEventAddress;
 use hypervisor::IrqRoute;
@@ -70,6 +75,7 @@ use hypervisor::IrqSource;
 pub use hypervisor::MemSlot;
 use hypervisor::VcpuSnapshot;
 use hypervisor::Vm;
+use libc::EAGAIN;
 use libc::EINVAL;
 use libc::EIO;
 use libc::ENODEV;
@@ -102,6 +108,7 @@ pub use vm_control_product::GpuSendToMain;
 pub use vm_control_product::GpuSendToService;
 pub use vm_control_product::ServiceSendToGpu;
 use vm_memory::GuestAddress;
+use vm_memory::MemoryCompression;
 
 #[cfg(feature = "balloon")]
 pub use crate::balloon_tube::*;
@@ -115,6 +122,17 @@ pub use crate::gdb::VcpuDebugStatusMessage;
 use crate::gpu::GpuControlCommand;
 #[cfg(feature = "gpu")]
 use crate::gpu::GpuControlResult;
+use crate::input::InputControlCommand;
+use crate::input::InputDeviceInfo;
+use crate::input::InputEvent;
+use crate::migration::MigrationAddress;
+use crate::snapshot_format::DevicesSnapshotConfig;
+use crate::snapshot_format::MemoryDirtyLog;
+use crate::snapshot_format::SnapshotReader;
+use crate::snapshot_format::SnapshotVmConfig;
+use crate::snapshot_format::SnapshotWriter;
+use crate::snapshot_format::IRQCHIP_SECTION;
+use crate::snapshot_format::VCPU_SECTION;
 
 /// Control the state of a particular VM CPU.
 #[derive(Clone, Debug)]
@@ -197,6 +215,16 @@ pub const USB_CONTROL_MAX_PORTS: usize = 16;
 pub enum DiskControlCommand {
     /// Resize a disk to `new_size` in bytes.
     Resize { new_size: u64 },
+    /// List the internal snapshots of a disk image.
+    ListSnapshots,
+    /// Create an internal snapshot named `name` of a disk image.
+    CreateSnapshot { name: String },
+    /// Revert a disk image to the internal snapshot with the given name or ID.
+    ApplySnapshot { name: String },
+    /// Delete the internal snapshot with the given name or ID from a disk image.
+    DeleteSnapshot { name: String },
+    /// Replace the I/O limits of a disk.
+    Throttle { limits: DiskThrottleLimits },
 }
 
 impl Display for DiskControlCommand {
@@ -205,14 +233,91 @@ impl Display for DiskControlCommand {
 
         match self {
             Resize { new_size } => write!(f, "disk_resize {}", new_size),
+            ListSnapshots => write!(f, "disk_snapshot_list"),
+            CreateSnapshot { name } => write!(f, "disk_snapshot_create {}", name),
+            ApplySnapshot { name } => write!(f, "disk_snapshot_apply {}", name),
+            DeleteSnapshot { name } => write!(f, "disk_snapshot_delete {}", name),
+            Throttle { limits } => write!(f, "disk_throttle {:?}", limits),
         }
     }
 }
 
+/// I/O limits of a disk. A limit that is `None` is not enforced.
+///
+/// Each `*_burst` value is the amount the guest can use at once after it stayed below the limit
+/// for a while. It defaults to one second worth of the limit.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DiskThrottleLimits {
+    /// Read and write requests per second.
+    pub iops: Option<u64>,
+    pub iops_burst: Option<u64>,
+    /// Bytes read and written per second.
+    pub bps: Option<u64>,
+    pub bps_burst: Option<u64>,
+    /// Bytes read per second.
+    pub read_bps: Option<u64>,
+    pub read_bps_burst: Option<u64>,
+    /// Bytes written per second.
+    pub write_bps: Option<u64>,
+    pub write_bps_burst: Option<u64>,
+}
+
+/// An internal snapshot of a disk image.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DiskSnapshotInfo {
+    pub id: String,
+    pub name: String,
+    /// Time the snapshot was taken, as seconds and nanoseconds since the Unix epoch.
+    pub date_sec: u32,
+    pub date_nsec: u32,
+    /// Guest time in nanoseconds when the snapshot was taken.
+    pub vm_clock_nsec: u64,
+    /// Size of the VM state saved with the snapshot.
+    pub vm_state_size: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum DiskControlResult {
     Ok,
     Err(SysError),
+    Snapshots(Vec<DiskSnapshotInfo>),
+}
+
+/// SCSI control commands for attaching and detaching the logical units of the virtio-scsi
+/// controller, and changing the medium of its CD-ROM drives, at runtime.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ScsiControlCommand {
+    /// Attach the disk image `file`, opened from `path`, as the logical unit `lun` of `target`.
+    /// If `cdrom` is set, the logical unit is a CD-ROM drive with the disk image as its medium.
+    AttachLun {
+        target: u8,
+        lun: u16,
+        path: PathBuf,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+        block_size: u32,
+        read_only: bool,
+        cdrom: bool,
+    },
+    /// Detach the logical unit `lun` of `target`.
+    DetachLun { target: u8, lun: u16 },
+    /// Insert the disk image `file`, opened from `path`, in the CD-ROM drive `lun` of `target`,
+    /// replacing its current medium.
+    InsertMedium {
+        target: u8,
+        lun: u16,
+        path: PathBuf,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    /// Eject the medium of the CD-ROM drive `lun` of `target`.
+    EjectMedium { target: u8, lun: u16 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ScsiControlResult {
+    Ok,
+    Err(SysError),
 }
 
 /// Net control commands for adding and removing tap devices.
@@ -229,6 +334,16 @@ pub enum UsbControlCommand {
         #[serde(with = "with_as_descriptor")]
         file: File,
     },
+    /// Attach a device emulated by crosvm.
+    AttachEmulatedDevice {
+        device: EmulatedUsbDevice,
+    },
+    /// Attach the device `busid` exported by a USB/IP server. `socket` is connected to the server.
+    AttachUsbipDevice {
+        #[serde(with = "with_as_descriptor")]
+        socket: File,
+        busid: String,
+    },
     DetachDevice {
         port: u8,
     },
@@ -237,6 +352,31 @@ pub enum UsbControlCommand {
     },
 }
 
+/// A USB device emulated by crosvm that can be attached to the xHCI controller.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum EmulatedUsbDevice {
+    /// A mass storage device exposing the disk image `file`, opened from `path`.
+    MassStorage {
+        path: PathBuf,
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+        read_only: bool,
+    },
+    /// A HID device reading `virtio_input_event`s from the socket `events`.
+    Hid {
+        kind: UsbHidKind,
+        #[serde(with = "with_as_descriptor")]
+        events: File,
+    },
+}
+
+/// The kinds of emulated USB HID devices.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UsbHidKind {
+    Keyboard,
+    Tablet,
+}
+
 #[derive(Serialize, Deserialize, Copy, Clone, Debug, Default)]
 pub struct UsbControlAttachedDevice {
     pub port: u8,
@@ -309,7 +449,16 @@ impl Display for UsbControlResult {
 /// Commands for snapshot feature
 #[derive(Serialize, Deserialize, Debug)]
 pub enum SnapshotCommand {
-    Take { snapshot_path: PathBuf },
+    Take {
+        snapshot_path: PathBuf,
+        compression: MemoryCompression,
+        /// Only saves the guest memory that changed since this snapshot, which must be the last
+        /// one taken of the VM.
+        parent: Option<PathBuf>,
+        /// Keeps logging the pages written by the guest after the snapshot, so that the next
+        /// snapshot can be based on this one. Logging is stopped otherwise.
+        incremental_base: bool,
+    },
 }
 
 /// Commands for restore feature
@@ -318,14 +467,59 @@ pub enum RestoreCommand {
     Apply { restore_path: PathBuf },
 }
 
+/// Commands for live migration
+#[derive(Serialize, Deserialize, Debug)]
+pub enum MigrateCommand {
+    /// Moves the running VM to the crosvm process waiting at `address`, then exits.
+    Send { address: MigrationAddress },
+    /// Waits at `address` for the VM sent by another crosvm process and replaces this VM with it.
+    ///
+    /// TCP addresses must be loopback addresses unless `allow_remote` is set, since anyone who can
+    /// connect can send a VM.
+    Receive {
+        address: MigrationAddress,
+        allow_remote: bool,
+    },
+}
+
 /// Commands for actions on devices and the devices control thread.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum DeviceControlCommand {
     SleepDevices,
     WakeDevices,
-    SnapshotDevices { snapshot_path: PathBuf },
-    RestoreDevices { restore_path: PathBuf },
+    SnapshotDevices {
+        snapshot_writer: SnapshotWriter,
+        compression: MemoryCompression,
+        parent: Option<SnapshotReader>,
+        /// Pages of guest memory written by the guest since the last snapshot, if the hypervisor
+        /// logs them.
+        dirty_log: Option<MemoryDirtyLog>,
+        /// Whether the pages written by the guest keep being logged, so that the next snapshot can
+        /// be based on this one.
+        incremental_base: bool,
+    },
+    RestoreDevices {
+        snapshot_reader: SnapshotReader,
+        /// The chain of snapshots an incremental snapshot is based on, from its parent to the
+        /// first full snapshot.
+        parents: Vec<SnapshotReader>,
+    },
+    /// Writes the state of the devices, without guest memory, to `file`.
+    SnapshotDevicesState {
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    /// Restores the state of the devices, without guest memory, from `file`.
+    RestoreDevicesState {
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    /// The dirty page log was read for something else than a snapshot, so the next snapshot can't
+    /// be incremental.
+    ResetSnapshotBase,
     GetDevicesState,
+    /// Gets the memory layout and devices of the VM, which a snapshot has to match.
+    GetSnapshotConfig,
     Exit,
 }
 
@@ -1267,6 +1461,10 @@ pub enum VmRequest {
         disk_index: usize,
         command: DiskControlCommand,
     },
+    /// Command to attach or detach a logical unit of the SCSI controller.
+    ScsiCommand(ScsiControlCommand),
+    /// Command to list the virtio-input devices or inject events through one of them.
+    InputCommand(InputControlCommand),
     /// Command to use controller.
     UsbCommand(UsbControlCommand),
     #[cfg(feature = "gpu")]
@@ -1286,6 +1484,8 @@ pub enum VmRequest {
     Snapshot(SnapshotCommand),
     /// Command to Restore devices
     Restore(RestoreCommand),
+    /// Command to move the VM to another crosvm process
+    Migrate(MigrateCommand),
     /// Register for event notification
     #[cfg(feature = "registered_events")]
     RegisterListener {
@@ -1392,6 +1592,7 @@ pub fn handle_disk_command(command: &DiskControlCommand, disk_host_tube: &Tube)
     match disk_host_tube.recv() {
         Ok(DiskControlResult::Ok) => VmResponse::Ok,
         Ok(DiskControlResult::Err(e)) => VmResponse::Err(e),
+        Ok(DiskControlResult::Snapshots(snapshots)) => VmResponse::DiskSnapshots(snapshots),
         Err(e) => {
             error!("disk socket recv failed: {}", e);
             VmResponse::Err(SysError::new(EINVAL))
@@ -1399,6 +1600,66 @@ pub fn handle_disk_command(command: &DiskControlCommand, disk_host_tube: &Tube)
     }
 }
 
+pub fn handle_scsi_command(command: &ScsiControlCommand, scsi_host_tube: &Tube) -> VmResponse {
+    // Forward the request to the SCSI controller process via its control socket.
+    if let Err(e) = scsi_host_tube.send(command) {
+        error!("scsi socket send failed: {}", e);
+        return VmResponse::Err(SysError::new(EINVAL));
+    }
+
+    match scsi_host_tube.recv() {
+        Ok(ScsiControlResult::Ok) => VmResponse::Ok,
+        Ok(ScsiControlResult::Err(e)) => VmResponse::Err(e),
+        Err(e) => {
+            error!("scsi socket recv failed: {}", e);
+            VmResponse::Err(SysError::new(EINVAL))
+        }
+    }
+}
+
+/// Lists the virtio-input devices, or forwards the events to inject to the device chosen by the
+/// command through its control socket.
+pub fn handle_input_command(
+    command: &InputControlCommand,
+    input_host_tubes: &[(InputDeviceInfo, Tube)],
+) -> VmResponse {
+    match command {
+        InputControlCommand::ListDevices => VmResponse::InputDevices(
+            input_host_tubes
+                .iter()
+                .map(|(info, _)| info.clone())
+                .collect(),
+        ),
+        InputControlCommand::SendEvents {
+            device_index,
+            events,
+        } => {
+            let Some((_, tube)) = input_host_tubes.get(*device_index) else {
+                return VmResponse::Err(SysError::new(ENODEV));
+            };
+            let mut events = events.clone();
+            // Don't let a client leave the device in the middle of a report.
+            if events.last().map_or(false, |event| !event.is_syn_report()) {
+                events.push(InputEvent::syn());
+            }
+            // The device doesn't reply, so that the run loop never waits for its worker, which only
+            // runs while the guest driver is active. The socket buffers the events meanwhile, and
+            // also once the worker stops reading it because its queue is full, until the send
+            // times out with EAGAIN.
+            match tube.send(&events) {
+                Ok(()) => VmResponse::Ok,
+                Err(TubeError::Send(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                    VmResponse::Err(SysError::new(EAGAIN))
+                }
+                Err(e) => {
+                    error!("input socket send failed: {}", e);
+                    VmResponse::Err(SysError::new(EIO))
+                }
+            }
+        }
+    }
+}
+
 /// WARNING: descriptor must be a mapping handle on Windows.
 fn map_descriptor(
     descriptor: &dyn AsRawDescriptor,
@@ -1494,11 +1755,26 @@ impl<'a> VcpuSuspendGuard<'a> {
     }
 }
 
+impl VcpuSuspendGuard<'_> {
+    /// Leaves the vCPUs suspended when the guard goes away, and returns the state to give back to
+    /// [`VcpuSuspendGuard::resume`] to roll them back later.
+    pub fn release(self) -> VmRunMode {
+        let saved_run_mode = self.saved_run_mode;
+        std::mem::forget(self);
+        saved_run_mode
+    }
+
+    /// Rolls back the state of the vCPUs suspended by a guard that was released.
+    pub fn resume(kick_vcpus: &dyn Fn(VcpuControl), saved_run_mode: VmRunMode) {
+        if saved_run_mode != VmRunMode::Suspending {
+            kick_vcpus(VcpuControl::RunState(saved_run_mode));
+        }
+    }
+}
+
 impl Drop for VcpuSuspendGuard<'_> {
     fn drop(&mut self) {
-        if self.saved_run_mode != VmRunMode::Suspending {
-            (self.kick_vcpus)(VcpuControl::RunState(self.saved_run_mode));
-        }
+        Self::resume(self.kick_vcpus, self.saved_run_mode);
     }
 }
 
@@ -1541,21 +1817,38 @@ impl<'a> DeviceSleepGuard<'a> {
     }
 }
 
-impl Drop for DeviceSleepGuard<'_> {
-    fn drop(&mut self) {
-        if let DevicesState::Wake = self.devices_state {
-            if let Err(e) = self
-                .device_control_tube
+impl DeviceSleepGuard<'_> {
+    /// Leaves the devices asleep when the guard goes away, and returns the state to give back to
+    /// [`DeviceSleepGuard::wake`] to wake them later.
+    pub fn release(self) -> DevicesState {
+        let devices_state = self.devices_state.clone();
+        std::mem::forget(self);
+        devices_state
+    }
+
+    /// Wakes the devices put to sleep by a guard that was released, if they were awake before.
+    pub fn wake(device_control_tube: &Tube, devices_state: &DevicesState) -> anyhow::Result<()> {
+        if let DevicesState::Wake = devices_state {
+            device_control_tube
                 .send(&DeviceControlCommand::WakeDevices)
+                .context("failed to request device wake")?;
+            match device_control_tube
+                .recv()
+                .context("failed to get reply for device wake request")?
             {
-                panic!("failed to request device wake after snapshot: {}", e);
-            }
-            match self.device_control_tube.recv() {
-                Ok(VmResponse::Ok) => (),
-                Ok(resp) => panic!("unexpected response to device wake request: {}", resp),
-                Err(e) => panic!("failed to get reply for device wake request: {}", e),
+                VmResponse::Ok => (),
+                resp => bail!("unexpected response to device wake request: {}", resp),
             }
         }
+        Ok(())
+    }
+}
+
+impl Drop for DeviceSleepGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = Self::wake(self.device_control_tube, &self.devices_state) {
+            panic!("failed to wake the devices after snapshot: {:#}", e);
+        }
     }
 }
 
@@ -1582,6 +1875,8 @@ impl VmRequest {
         irq_handler_control: &Tube,
         snapshot_irqchip: impl Fn() -> anyhow::Result<serde_json::Value>,
         restore_irqchip: impl FnMut(serde_json::Value) -> anyhow::Result<()>,
+        set_memory_dirty_log: impl Fn(bool) -> anyhow::Result<()>,
+        get_memory_dirty_log: impl Fn() -> anyhow::Result<Vec<Vec<u8>>>,
     ) -> VmResponse {
         match *self {
             VmRequest::Exit => {
@@ -1840,6 +2135,14 @@ impl VmRequest {
                 Some(tube) => handle_disk_command(command, tube),
                 None => VmResponse::Err(SysError::new(ENODEV)),
             },
+            // The SCSI controller is only reachable from the run loop of Linux hosts, which handles
+            // this request itself.
+            VmRequest::ScsiCommand(_) => VmResponse::Err(SysError::new(ENOTSUP)),
+            // Likewise for the control sockets of the virtio-input devices.
+            VmRequest::InputCommand(_) => VmResponse::Err(SysError::new(ENOTSUP)),
+            // Live migrations run on a worker thread started by the run loop of Linux hosts, which
+            // handles this request itself.
+            VmRequest::Migrate(_) => VmResponse::Err(SysError::new(ENOTSUP)),
             #[cfg(feature = "gpu")]
             VmRequest::GpuCommand(ref cmd) => match gpu_control_tube {
                 Some(gpu_control) => {
@@ -1912,15 +2215,25 @@ impl VmRequest {
             VmRequest::HotPlugNetCommand(ref _net_cmd) => {
                 VmResponse::ErrString("hot plug not supported".to_owned())
             }
-            VmRequest::Snapshot(SnapshotCommand::Take { ref snapshot_path }) => {
+            VmRequest::Snapshot(SnapshotCommand::Take {
+                ref snapshot_path,
+                compression,
+                ref parent,
+                incremental_base,
+            }) => {
                 info!("Starting crosvm snapshot");
                 match do_snapshot(
                     snapshot_path.to_path_buf(),
+                    compression,
+                    parent.clone(),
+                    incremental_base,
                     kick_vcpus,
                     irq_handler_control,
                     device_control_tube,
                     vcpu_size,
                     snapshot_irqchip,
+                    set_memory_dirty_log,
+                    get_memory_dirty_log,
                 ) {
                     Ok(()) => {
                         info!("Finished crosvm snapshot successfully");
@@ -1928,7 +2241,7 @@ impl VmRequest {
                     }
                     Err(e) => {
                         error!("failed to handle snapshot: {:?}", e);
-                        VmResponse::Err(SysError::new(EIO))
+                        VmResponse::ErrString(format!("failed to snapshot: {:#}", e))
                     }
                 }
             }
@@ -1949,7 +2262,8 @@ impl VmRequest {
                     }
                     Err(e) => {
                         error!("failed to handle restore: {:?}", e);
-                        VmResponse::Err(SysError::new(EIO))
+                        // Report the reason, e.g. how the snapshot differs from the VM.
+                        VmResponse::ErrString(format!("failed to restore: {:#}", e))
                     }
                 }
             }
@@ -1969,18 +2283,26 @@ impl VmRequest {
     }
 }
 
-/// Snapshot the VM to file at `snapshot_path`
-fn do_snapshot(
-    snapshot_path: PathBuf,
-    kick_vcpus: impl Fn(VcpuControl),
-    irq_handler_control: &Tube,
+// Asks the devices control thread for the parts of the VM configuration that it knows about.
+fn get_snapshot_vm_config(
     device_control_tube: &Tube,
     vcpu_size: usize,
-    snapshot_irqchip: impl Fn() -> anyhow::Result<serde_json::Value>,
-) -> anyhow::Result<()> {
-    let _vcpu_guard = VcpuSuspendGuard::new(&kick_vcpus, vcpu_size)?;
-    let _device_guard = DeviceSleepGuard::new(device_control_tube)?;
+) -> anyhow::Result<SnapshotVmConfig> {
+    device_control_tube
+        .send(&DeviceControlCommand::GetSnapshotConfig)
+        .context("send command to devices control socket")?;
+    match device_control_tube
+        .recv()
+        .context("receive from devices control socket")?
+    {
+        VmResponse::DevicesSnapshotConfig(config) => Ok(SnapshotVmConfig::new(vcpu_size, config)),
+        resp => bail!("unexpected GetSnapshotConfig response: {resp}"),
+    }
+}
 
+// Flushes all the pending IRQs to the irqchip, so its state holds every pending interrupt. The
+// devices must be sleeping and the vCPUs suspended.
+fn flush_irqs(irq_handler_control: &Tube) -> anyhow::Result<()> {
     // We want to flush all pending IRQs to the LAPICs. There are two cases:
     //
     // MSIs: these are directly delivered to the LAPIC. We must verify the handler
@@ -2023,11 +2345,14 @@ fn do_snapshot(
         }
     }
     info!("flushed IRQs in {} iterations", flush_attempts);
+    Ok(())
+}
 
-    // Snapshot Vcpus
-    let vcpu_path = snapshot_path.with_extension("vcpu");
-    let cpu_file = File::create(&vcpu_path)
-        .with_context(|| format!("failed to open path {}", vcpu_path.display()))?;
+// Gets the state of all the vCPUs, which must be suspended.
+fn snapshot_vcpus(
+    kick_vcpus: &impl Fn(VcpuControl),
+    vcpu_size: usize,
+) -> anyhow::Result<Vec<VcpuSnapshot>> {
     let (send_chan, recv_chan) = mpsc::channel();
     kick_vcpus(VcpuControl::Snapshot(send_chan));
     // Validate all Vcpus snapshot successfully
@@ -2043,56 +2368,15 @@ fn do_snapshot(
             Err(e) => bail!("Failed to snapshot Vcpu, aborting snapshot: {}", e),
         }
     }
-    serde_json::to_writer(cpu_file, &cpu_vec).expect("Failed to write Vcpu state");
-
-    // Snapshot irqchip
-    let irqchip_path = snapshot_path.with_extension("irqchip");
-    let irqchip_file = File::create(&irqchip_path)
-        .with_context(|| format!("failed to open path {}", irqchip_path.display()))?;
-    let irqchip_snap = snapshot_irqchip()?;
-    serde_json::to_writer(irqchip_file, &irqchip_snap).expect("Failed to write irqchip state");
-
-    // Snapshot devices
-    device_control_tube
-        .send(&DeviceControlCommand::SnapshotDevices { snapshot_path })
-        .context("send command to devices control socket")?;
-    let resp: VmResponse = device_control_tube
-        .recv()
-        .context("receive from devices control socket")?;
-    if !matches!(resp, VmResponse::Ok) {
-        bail!("unexpected SnapshotDevices response: {resp}");
-    }
-    Ok(())
+    Ok(cpu_vec)
 }
 
-/// Restore the VM to the snapshot at `restore_path`.
-///
-/// Same as `VmRequest::execute` with a `VmRequest::Restore`. Exposed as a separate function
-/// because not all the `VmRequest::execute` arguments are available in the "cold restore" flow.
-pub fn do_restore(
-    restore_path: PathBuf,
-    kick_vcpus: impl Fn(VcpuControl),
-    kick_vcpu: impl Fn(VcpuControl, usize),
-    irq_handler_control: &Tube,
-    device_control_tube: &Tube,
+// Restores the state of the vCPUs, which must be suspended.
+fn restore_vcpus(
+    kick_vcpu: &impl Fn(VcpuControl, usize),
     vcpu_size: usize,
-    mut restore_irqchip: impl FnMut(serde_json::Value) -> anyhow::Result<()>,
+    vcpu_snapshots: Vec<VcpuSnapshot>,
 ) -> anyhow::Result<()> {
-    let _guard = VcpuSuspendGuard::new(&kick_vcpus, vcpu_size);
-    let _devices_guard = DeviceSleepGuard::new(device_control_tube)?;
-
-    // Restore IrqChip
-    let irq_path = restore_path.with_extension("irqchip");
-    let irq_file = File::open(&irq_path)
-        .with_context(|| format!("failed to open path {}", irq_path.display()))?;
-    let irq_snapshot: serde_json::Value = serde_json::from_reader(irq_file)?;
-    restore_irqchip(irq_snapshot)?;
-
-    // Restore Vcpu(s)
-    let vcpu_path = restore_path.with_extension("vcpu");
-    let cpu_file = File::open(&vcpu_path)
-        .with_context(|| format!("failed to open path {}", vcpu_path.display()))?;
-    let vcpu_snapshots: Vec<VcpuSnapshot> = serde_json::from_reader(cpu_file)?;
     if vcpu_snapshots.len() != vcpu_size {
         bail!(
             "bad cpu count in snapshot: expected={} got={}",
@@ -2124,18 +2408,11 @@ pub fn do_restore(
             bail!("Failed to restore vcpu: {}", e);
         }
     }
+    Ok(())
+}
 
-    // Restore devices
-    device_control_tube
-        .send(&DeviceControlCommand::RestoreDevices { restore_path })
-        .context("send command to devices control socket")?;
-    let resp: VmResponse = device_control_tube
-        .recv()
-        .context("receive from devices control socket")?;
-    if !matches!(resp, VmResponse::Ok) {
-        bail!("unexpected RestoreDevices response: {resp}");
-    }
-
+// Makes the IRQ handler thread wait on the events of the irqchip again, after it was restored.
+fn refresh_irq_event_tokens(irq_handler_control: &Tube) -> anyhow::Result<()> {
     irq_handler_control
         .send(&IrqHandlerRequest::RefreshIrqEventTokens)
         .context("failed to send refresh irq event token command to IRQ handler thread")?;
@@ -2151,6 +2428,150 @@ pub fn do_restore(
     Ok(())
 }
 
+/// Snapshot the VM to a new snapshot directory at `snapshot_path`
+///
+/// If `parent` is given, only the guest memory that changed since that snapshot is saved. If
+/// `incremental_base` is set, the pages written by the guest keep being logged after the snapshot,
+/// so that it can be the parent of the next one.
+///
+/// `set_memory_dirty_log` starts or stops logging the pages written by the guest, and
+/// `get_memory_dirty_log` returns the pages written since it was last called.
+fn do_snapshot(
+    snapshot_path: PathBuf,
+    compression: MemoryCompression,
+    parent: Option<PathBuf>,
+    incremental_base: bool,
+    kick_vcpus: impl Fn(VcpuControl),
+    irq_handler_control: &Tube,
+    device_control_tube: &Tube,
+    vcpu_size: usize,
+    snapshot_irqchip: impl Fn() -> anyhow::Result<serde_json::Value>,
+    set_memory_dirty_log: impl Fn(bool) -> anyhow::Result<()>,
+    get_memory_dirty_log: impl Fn() -> anyhow::Result<Vec<Vec<u8>>>,
+) -> anyhow::Result<()> {
+    let vm_config = get_snapshot_vm_config(device_control_tube, vcpu_size)?;
+    let parent = match parent {
+        Some(path) => {
+            let reader = SnapshotReader::open(path)?;
+            reader
+                .manifest()
+                .check_compatible(&vm_config)
+                .context("parent snapshot doesn't match the VM")?;
+            Some(reader)
+        }
+        None => None,
+    };
+    let snapshot_writer = SnapshotWriter::create(snapshot_path)?;
+    let _vcpu_guard = VcpuSuspendGuard::new(&kick_vcpus, vcpu_size)?;
+    let _device_guard = DeviceSleepGuard::new(device_control_tube)?;
+
+    flush_irqs(irq_handler_control)?;
+
+    let cpu_vec = snapshot_vcpus(&kick_vcpus, vcpu_size)?;
+    snapshot_writer.write_json_section(VCPU_SECTION, &cpu_vec)?;
+
+    // Snapshot irqchip
+    let irqchip_snap = snapshot_irqchip()?;
+    snapshot_writer.write_json_section(IRQCHIP_SECTION, &irqchip_snap)?;
+
+    // Getting the dirty log also clears it, so the next snapshot gets the pages written since this
+    // one. Logging slows down the writes of the guest, so it is only kept on for the next snapshot
+    // when asked to.
+    if incremental_base {
+        set_memory_dirty_log(true)
+            .context("snapshots can't be incremental bases without the dirty page log")?;
+    }
+    let dirty_log = if parent.is_some() || incremental_base {
+        let bitmaps = get_memory_dirty_log()
+            .context("incremental snapshots need the dirty page log of their parent")?;
+        Some(MemoryDirtyLog::new(&bitmaps)?)
+    } else {
+        None
+    };
+    if !incremental_base {
+        set_memory_dirty_log(false).context("failed to stop the dirty page log")?;
+    }
+
+    // Snapshot devices
+    device_control_tube
+        .send(&DeviceControlCommand::SnapshotDevices {
+            snapshot_writer: snapshot_writer.clone(),
+            compression,
+            parent: parent.clone(),
+            dirty_log,
+            incremental_base,
+        })
+        .context("send command to devices control socket")?;
+    let resp: VmResponse = device_control_tube
+        .recv()
+        .context("receive from devices control socket")?;
+    if !matches!(resp, VmResponse::Ok) {
+        bail!("unexpected SnapshotDevices response: {resp}");
+    }
+
+    // The manifest makes the snapshot complete, so it is written last.
+    snapshot_writer.finish(vm_config, parent.as_ref())?;
+    Ok(())
+}
+
+/// Restore the VM to the snapshot at `restore_path`.
+///
+/// The snapshot, and every snapshot it is based on, is checked against the configuration of the VM
+/// and its sections are validated before any state is restored.
+///
+/// Same as `VmRequest::execute` with a `VmRequest::Restore`. Exposed as a separate function
+/// because not all the `VmRequest::execute` arguments are available in the "cold restore" flow.
+pub fn do_restore(
+    restore_path: PathBuf,
+    kick_vcpus: impl Fn(VcpuControl),
+    kick_vcpu: impl Fn(VcpuControl, usize),
+    irq_handler_control: &Tube,
+    device_control_tube: &Tube,
+    vcpu_size: usize,
+    mut restore_irqchip: impl FnMut(serde_json::Value) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let snapshot_reader = SnapshotReader::open(restore_path)?;
+    let vm_config = get_snapshot_vm_config(device_control_tube, vcpu_size)?;
+    snapshot_reader.manifest().check_compatible(&vm_config)?;
+    snapshot_reader.verify_sections()?;
+    let parents = snapshot_reader.open_parents()?;
+    for parent in &parents {
+        parent
+            .manifest()
+            .check_compatible(&vm_config)
+            .with_context(|| format!("parent snapshot {}", parent.dir().display()))?;
+        parent.verify_sections()?;
+    }
+
+    let _guard = VcpuSuspendGuard::new(&kick_vcpus, vcpu_size);
+    let _devices_guard = DeviceSleepGuard::new(device_control_tube)?;
+
+    // Restore IrqChip
+    let irq_snapshot: serde_json::Value = snapshot_reader.read_json_section(IRQCHIP_SECTION)?;
+    restore_irqchip(irq_snapshot)?;
+
+    // Restore Vcpu(s)
+    let vcpu_snapshots: Vec<VcpuSnapshot> = snapshot_reader.read_json_section(VCPU_SECTION)?;
+    restore_vcpus(&kick_vcpu, vcpu_size, vcpu_snapshots)?;
+
+    // Restore devices
+    device_control_tube
+        .send(&DeviceControlCommand::RestoreDevices {
+            snapshot_reader,
+            parents,
+        })
+        .context("send command to devices control socket")?;
+    let resp: VmResponse = device_control_tube
+        .recv()
+        .context("receive from devices control socket")?;
+    if !matches!(resp, VmResponse::Ok) {
+        bail!("unexpected RestoreDevices response: {resp}");
+    }
+
+    refresh_irq_event_tokens(irq_handler_control)?;
+    Ok(())
+}
+
 /// Indication of success or failure of a `VmRequest`.
 ///
 /// Success is usually indicated `VmResponse::Ok` unless there is data associated with the response.
@@ -2189,6 +2610,12 @@ pub enum VmResponse {
     SwapStatus(SwapStatus),
     /// Gets the state of Devices (sleep/wake)
     DevicesState(DevicesState),
+    /// The memory layout and devices of the VM.
+    DevicesSnapshotConfig(DevicesSnapshotConfig),
+    /// Internal snapshots of a disk image.
+    DiskSnapshots(Vec<DiskSnapshotInfo>),
+    /// The virtio-input devices, in the order of their indices.
+    InputDevices(Vec<InputDeviceInfo>),
 }
 
 impl Display for VmResponse {
@@ -2242,6 +2669,36 @@ impl Display for VmResponse {
                 )
             }
             DevicesState(status) => write!(f, "devices status: {:?}", status),
+            DevicesSnapshotConfig(config) => write!(f, "devices snapshot config: {:?}", config),
+            DiskSnapshots(snapshots) => {
+                write!(
+                    f,
+                    "{:<8} {:<24} {:>12} {:>14}",
+                    "ID", "NAME", "DATE", "VM SIZE"
+                )?;
+                snapshots.iter().try_for_each(|snapshot| {
+                    write!(
+                        f,
+                        "\n{:<8} {:<24} {:>12} {:>14}",
+                        snapshot.id, snapshot.name, snapshot.date_sec, snapshot.vm_state_size
+                    )
+                })
+            }
+            InputDevices(devices) => {
+                write!(f, "{:<8} {:<24} PATH", "INDEX", "KIND")?;
+                devices.iter().enumerate().try_for_each(|(index, device)| {
+                    write!(
+                        f,
+                        "\n{:<8} {:<24} {}",
+                        index,
+                        device.kind.to_string(),
+                        device
+                            .path
+                            .as_ref()
+                            .map_or_else(|| "-".into(), |path| path.display().to_string())
+                    )
+                })
+            }
         }
     }
 }
@@ -0,0 +1,1331 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Live migration of a running VM to another crosvm process.
+//!
+//! The destination is a crosvm process started with the same configuration as the source, waiting
+//! for the source to connect. The source first sends the configuration of its VM, which the
+//! destination checks against its own. Then it copies all of guest memory while the guest keeps
+//! running, and copies again the pages that the guest wrote in the meantime, according to the
+//! hypervisor's dirty page log, until few enough pages are written during a round. Finally it
+//! suspends the vCPUs and the devices, copies the remaining pages and the state of the vCPUs, the
+//! irqchip and the devices, and exits once the destination has restored all of it.
+//!
+//! After a header, the stream is a sequence of messages, each starting with a one byte tag.
+//! Integers are little-endian.
+//!
+//! * `PAGES`: region index (u32), first page (u64), page count (u32), then the pages.
+//! * `ZERO_PAGES`: region index (u32), first page (u64), page count (u32).
+//! * `SECTION`: name length (u32), name, data length (u64), data. The sections are the same as the
+//!   ones of a snapshot, without guest memory.
+//! * `END`: the whole VM was sent.
+//!
+//! The destination replies to the header and to `END` with a status byte, followed by an error
+//! message if it isn't 0.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Display;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use base::error;
+use base::info;
+use base::pagesize;
+use base::warn;
+use base::with_as_descriptor;
+use base::Event;
+use base::EventWaitResult;
+use base::FileSerdeWrapper;
+use base::Tube;
+use base::VolatileSlice;
+use base::WorkerThread;
+use hypervisor::VcpuSnapshot;
+use serde::Deserialize;
+use serde::Serialize;
+use vm_memory::page_hash;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+use crate::flush_irqs;
+use crate::get_snapshot_vm_config;
+use crate::refresh_irq_event_tokens;
+use crate::restore_vcpus;
+use crate::snapshot_format::SnapshotVmConfig;
+use crate::snapshot_format::DEVICES_SECTION;
+use crate::snapshot_format::IRQCHIP_SECTION;
+use crate::snapshot_format::VCPU_SECTION;
+use crate::snapshot_vcpus;
+use crate::DeviceControlCommand;
+use crate::DeviceSleepGuard;
+use crate::DevicesState;
+use crate::VcpuControl;
+use crate::VcpuSuspendGuard;
+use crate::VmResponse;
+use crate::VmRunMode;
+
+const MIGRATION_MAGIC: [u8; 8] = *b"crosvmLM";
+const MIGRATION_VERSION: u32 = 1;
+
+const MSG_PAGES: u8 = 1;
+const MSG_ZERO_PAGES: u8 = 2;
+const MSG_SECTION: u8 = 3;
+const MSG_END: u8 = 4;
+
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+
+// Limits on the sizes read from the stream, so a corrupted stream doesn't make the destination
+// allocate huge buffers.
+const MAX_HEADER_SIZE: u64 = 1 << 20;
+const MAX_SECTION_NAME_SIZE: u32 = 64;
+const MAX_SECTION_SIZE: u64 = 1 << 30;
+const MAX_ERROR_SIZE: u32 = 1 << 16;
+
+// Most pages sent in a single `PAGES` or `ZERO_PAGES` message.
+const MAX_PAGES_PER_MESSAGE: usize = 256;
+
+// Pre-copy stops once fewer pages than this were written by the guest during a round.
+const CONVERGED_DIRTY_PAGES: usize = 1024;
+// Pre-copy stops after this many rounds even if the guest keeps writing too many pages.
+const MAX_PRECOPY_ROUNDS: usize = 30;
+
+// Timeouts of the transfer, so a peer that vanished doesn't keep the migration worker forever.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(300);
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+// How often waiting for the source checks whether the migration was cancelled.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where the destination of a migration waits for the source.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MigrationAddress {
+    /// A Unix domain socket, for a destination on the same host.
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// A TCP address, as `host:port`.
+    Tcp(String),
+}
+
+impl FromStr for MigrationAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once(':') {
+            #[cfg(unix)]
+            Some(("unix", path)) if !path.is_empty() => Ok(MigrationAddress::Unix(path.into())),
+            Some(("tcp", addr))
+                if addr.rsplit_once(':').map_or(false, |(host, port)| {
+                    !host.is_empty() && port.parse::<u16>().is_ok()
+                }) =>
+            {
+                Ok(MigrationAddress::Tcp(addr.to_string()))
+            }
+            _ => Err(format!(
+                "invalid migration address `{}`, expected `unix:PATH` or `tcp:HOST:PORT`",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for MigrationAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(unix)]
+            MigrationAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+            MigrationAddress::Tcp(addr) => write!(f, "tcp:{}", addr),
+        }
+    }
+}
+
+impl MigrationAddress {
+    /// Connects to the destination waiting at this address.
+    pub fn connect(&self) -> Result<MigrationStream> {
+        match self {
+            #[cfg(unix)]
+            MigrationAddress::Unix(path) => {
+                let stream = UnixStream::connect(path)
+                    .with_context(|| format!("failed to connect to {}", self))?;
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                Ok(MigrationStream::new(stream.try_clone()?, stream))
+            }
+            MigrationAddress::Tcp(addr) => {
+                let mut connected = Err(io::Error::from(io::ErrorKind::AddrNotAvailable));
+                for addr in addr
+                    .to_socket_addrs()
+                    .with_context(|| format!("failed to resolve {}", self))?
+                {
+                    connected = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT);
+                    if connected.is_ok() {
+                        break;
+                    }
+                }
+                let stream = connected.with_context(|| format!("failed to connect to {}", self))?;
+                stream.set_nodelay(true)?;
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                Ok(MigrationStream::new(stream.try_clone()?, stream))
+            }
+        }
+    }
+
+    /// Fails if other hosts can connect to this address.
+    pub fn check_local(&self) -> Result<()> {
+        match self {
+            #[cfg(unix)]
+            MigrationAddress::Unix(_) => Ok(()),
+            MigrationAddress::Tcp(addr) => {
+                let mut addrs = addr
+                    .to_socket_addrs()
+                    .with_context(|| format!("failed to resolve {}", self))?;
+                if addrs.all(|addr| addr.ip().is_loopback()) {
+                    Ok(())
+                } else {
+                    bail!(
+                        "{} isn't a loopback address, so any host could send a VM to it",
+                        self
+                    )
+                }
+            }
+        }
+    }
+
+    /// Waits for the source of a migration to connect to this address, until `stop_evt` is
+    /// signaled or `ACCEPT_TIMEOUT` passed.
+    pub fn accept(&self, stop_evt: &Event) -> Result<MigrationStream> {
+        // Polls the listener, which is non-blocking, so the wait can be interrupted.
+        fn poll_accept<T>(
+            mut accept: impl FnMut() -> io::Result<T>,
+            stop_evt: &Event,
+        ) -> Result<T> {
+            let deadline = Instant::now() + ACCEPT_TIMEOUT;
+            loop {
+                match accept() {
+                    Ok(accepted) => return Ok(accepted),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e).context("failed to accept the migration source"),
+                }
+                if Instant::now() >= deadline {
+                    bail!("no migration source connected");
+                }
+                if let EventWaitResult::Signaled = stop_evt.wait_timeout(ACCEPT_POLL_INTERVAL)? {
+                    bail!("stopped waiting for the migration source");
+                }
+            }
+        }
+
+        match self {
+            #[cfg(unix)]
+            MigrationAddress::Unix(path) => {
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("failed to listen on {}", self))?;
+                listener.set_nonblocking(true)?;
+                let accepted = poll_accept(|| listener.accept(), stop_evt);
+                // Only one source ever connects.
+                if let Err(e) = std::fs::remove_file(path) {
+                    warn!("failed to remove {}: {}", path.display(), e);
+                }
+                let (stream, _) = accepted?;
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                Ok(MigrationStream::new(stream.try_clone()?, stream))
+            }
+            MigrationAddress::Tcp(addr) => {
+                let listener = TcpListener::bind(addr)
+                    .with_context(|| format!("failed to listen on {}", self))?;
+                listener.set_nonblocking(true)?;
+                let (stream, peer) = poll_accept(|| listener.accept(), stop_evt)?;
+                info!("migration source connected from {}", peer);
+                stream.set_nonblocking(false)?;
+                stream.set_nodelay(true)?;
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                Ok(MigrationStream::new(stream.try_clone()?, stream))
+            }
+        }
+    }
+}
+
+// Sent by the source before anything else.
+#[derive(Serialize, Deserialize)]
+struct MigrationHeader {
+    vm: SnapshotVmConfig,
+    page_size: usize,
+}
+
+// A message sent by the source after the header.
+enum Message {
+    // Pages of a region of guest memory. `data` is `None` if they only hold zeros.
+    Pages {
+        region: usize,
+        first_page: usize,
+        count: usize,
+        data: Option<Vec<u8>>,
+    },
+    Section {
+        name: String,
+        data: Vec<u8>,
+    },
+    End,
+}
+
+/// A connection between the source and the destination of a migration.
+pub struct MigrationStream {
+    reader: BufReader<Box<dyn Read>>,
+    writer: BufWriter<Box<dyn Write>>,
+}
+
+impl MigrationStream {
+    /// Returns a stream reading from `reader` and writing to `writer`, usually the two halves of a
+    /// socket.
+    pub fn new(reader: impl Read + 'static, writer: impl Write + 'static) -> MigrationStream {
+        MigrationStream {
+            reader: BufReader::new(Box::new(reader)),
+            writer: BufWriter::new(Box::new(writer)),
+        }
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        let mut value = [0u8; 1];
+        self.reader.read_exact(&mut value)?;
+        Ok(value[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let mut value = [0u8; 4];
+        self.reader.read_exact(&mut value)?;
+        Ok(u32::from_le_bytes(value))
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        let mut value = [0u8; 8];
+        self.reader.read_exact(&mut value)?;
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut bytes = vec![0u8; len];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn write_header(&mut self, header: &MigrationHeader) -> Result<()> {
+        let header = serde_json::to_vec(header)?;
+        self.writer.write_all(&MIGRATION_MAGIC)?;
+        self.writer.write_all(&MIGRATION_VERSION.to_le_bytes())?;
+        self.writer
+            .write_all(&(header.len() as u64).to_le_bytes())?;
+        self.writer.write_all(&header)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn read_header(&mut self) -> Result<MigrationHeader> {
+        let mut magic = [0u8; 8];
+        self.reader.read_exact(&mut magic)?;
+        if magic != MIGRATION_MAGIC {
+            bail!("the source is not a crosvm migration");
+        }
+        let version = self.read_u32()?;
+        if version != MIGRATION_VERSION {
+            bail!(
+                "unsupported migration version {}, expected {}",
+                version,
+                MIGRATION_VERSION
+            );
+        }
+        let len = self.read_u64()?;
+        if len > MAX_HEADER_SIZE {
+            bail!("migration header is too large: {} bytes", len);
+        }
+        let header = self.read_bytes(len as usize)?;
+        serde_json::from_slice(&header).context("failed to parse the migration header")
+    }
+
+    // Tells the source whether the destination accepted the header, or restored the VM.
+    fn write_status(&mut self, status: &Result<()>) -> Result<()> {
+        match status {
+            Ok(()) => self.writer.write_all(&[STATUS_OK])?,
+            Err(e) => {
+                let message = format!("{:#}", e);
+                let message = &message.as_bytes()[..message.len().min(MAX_ERROR_SIZE as usize)];
+                self.writer.write_all(&[STATUS_ERROR])?;
+                self.writer
+                    .write_all(&(message.len() as u32).to_le_bytes())?;
+                self.writer.write_all(message)?;
+            }
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    // Returns the error reported by the destination, if any.
+    fn read_status(&mut self) -> Result<()> {
+        match self.read_u8()? {
+            STATUS_OK => Ok(()),
+            STATUS_ERROR => {
+                let len = self.read_u32()?.min(MAX_ERROR_SIZE);
+                let message = self.read_bytes(len as usize)?;
+                Err(anyhow!(
+                    "destination error: {}",
+                    String::from_utf8_lossy(&message)
+                ))
+            }
+            status => bail!("invalid migration status {}", status),
+        }
+    }
+
+    fn write_pages(
+        &mut self,
+        region: usize,
+        first_page: usize,
+        count: usize,
+        data: Option<&[u8]>,
+    ) -> Result<()> {
+        let tag = if data.is_some() {
+            MSG_PAGES
+        } else {
+            MSG_ZERO_PAGES
+        };
+        self.writer.write_all(&[tag])?;
+        self.writer.write_all(&(region as u32).to_le_bytes())?;
+        self.writer.write_all(&(first_page as u64).to_le_bytes())?;
+        self.writer.write_all(&(count as u32).to_le_bytes())?;
+        if let Some(data) = data {
+            self.writer.write_all(data)?;
+        }
+        Ok(())
+    }
+
+    fn write_section(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        self.writer.write_all(&[MSG_SECTION])?;
+        self.writer.write_all(&(name.len() as u32).to_le_bytes())?;
+        self.writer.write_all(name.as_bytes())?;
+        self.writer.write_all(&(data.len() as u64).to_le_bytes())?;
+        self.writer.write_all(data)?;
+        Ok(())
+    }
+
+    fn write_end(&mut self) -> Result<()> {
+        self.writer.write_all(&[MSG_END])?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn read_message(&mut self, page_size: usize) -> Result<Message> {
+        match self.read_u8()? {
+            tag @ (MSG_PAGES | MSG_ZERO_PAGES) => {
+                let region = self.read_u32()? as usize;
+                let first_page = self.read_u64()? as usize;
+                let count = self.read_u32()? as usize;
+                if count == 0 || count > MAX_PAGES_PER_MESSAGE {
+                    bail!("invalid page count {}", count);
+                }
+                let data = if tag == MSG_PAGES {
+                    Some(self.read_bytes(count * page_size)?)
+                } else {
+                    None
+                };
+                Ok(Message::Pages {
+                    region,
+                    first_page,
+                    count,
+                    data,
+                })
+            }
+            MSG_SECTION => {
+                let name_len = self.read_u32()?;
+                if name_len > MAX_SECTION_NAME_SIZE {
+                    bail!("section name is too long: {} bytes", name_len);
+                }
+                let name = String::from_utf8(self.read_bytes(name_len as usize)?)
+                    .context("section name is not valid UTF-8")?;
+                let len = self.read_u64()?;
+                if len > MAX_SECTION_SIZE {
+                    bail!("section {} is too large: {} bytes", name, len);
+                }
+                let data = self.read_bytes(len as usize)?;
+                Ok(Message::Section { name, data })
+            }
+            MSG_END => Ok(Message::End),
+            tag => bail!("invalid migration message {}", tag),
+        }
+    }
+}
+
+fn is_zero(data: &[u8]) -> bool {
+    data.iter().all(|b| *b == 0)
+}
+
+fn is_dirty(bitmap: &[u8], page: usize) -> bool {
+    bitmap[page / 8] & (1 << (page % 8)) != 0
+}
+
+// Returns the number of pages marked in `dirty_log`.
+fn count_dirty(dirty_log: &[Vec<u8>]) -> usize {
+    dirty_log
+        .iter()
+        .flatten()
+        .map(|b| b.count_ones() as usize)
+        .sum()
+}
+
+// Marks in `dirty_log` the pages marked in `other`.
+fn merge_dirty(dirty_log: &mut [Vec<u8>], other: &[Vec<u8>]) {
+    for (bitmap, other) in dirty_log.iter_mut().zip(other) {
+        for (b, o) in bitmap.iter_mut().zip(other) {
+            *b |= o;
+        }
+    }
+}
+
+// The pages of each region of guest memory, in the order of the dirty page log.
+struct MemoryPages<'a> {
+    mem: &'a GuestMemory,
+    // Start address and number of pages of each region.
+    regions: Vec<(GuestAddress, usize)>,
+    page_size: usize,
+}
+
+impl<'a> MemoryPages<'a> {
+    fn new(mem: &'a GuestMemory, page_size: usize) -> Self {
+        MemoryPages {
+            mem,
+            regions: mem
+                .regions()
+                .map(|region| (region.guest_addr, region.size / page_size))
+                .collect(),
+            page_size,
+        }
+    }
+
+    fn num_pages(&self, region: usize) -> usize {
+        self.regions[region].1
+    }
+
+    fn page(&self, region: usize, page: usize) -> Result<VolatileSlice> {
+        let addr = self.regions[region]
+            .0
+            .unchecked_add((page * self.page_size) as u64);
+        self.mem
+            .get_slice_at_addr(addr, self.page_size)
+            .with_context(|| format!("failed to get page at {}", addr))
+    }
+
+    fn check_dirty_log(&self, dirty_log: &[Vec<u8>]) -> Result<()> {
+        if dirty_log.len() != self.regions.len()
+            || dirty_log
+                .iter()
+                .zip(&self.regions)
+                .any(|(bitmap, (_, num_pages))| bitmap.len() * 8 < *num_pages)
+        {
+            bail!("the dirty page log doesn't match guest memory");
+        }
+        Ok(())
+    }
+
+    // Writes the pages of a `PAGES` or `ZERO_PAGES` message to guest memory.
+    fn write(
+        &self,
+        region: usize,
+        first_page: usize,
+        count: usize,
+        data: Option<&[u8]>,
+    ) -> Result<()> {
+        if region >= self.regions.len()
+            || first_page
+                .checked_add(count)
+                .map_or(true, |end| end > self.num_pages(region))
+        {
+            bail!(
+                "pages {}+{} of region {} are out of guest memory",
+                first_page,
+                count,
+                region
+            );
+        }
+        let mut page_data = vec![0u8; self.page_size];
+        for i in 0..count {
+            let slice = self.page(region, first_page + i)?;
+            match data {
+                Some(data) => slice.copy_from(&data[i * self.page_size..(i + 1) * self.page_size]),
+                None => {
+                    // Avoids allocating memory for pages that were never touched.
+                    slice.copy_to(&mut page_data);
+                    if !is_zero(&page_data) {
+                        slice.write_bytes(0);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Consecutive pages of a region waiting to be sent in a single message.
+#[derive(Default)]
+struct PageRun {
+    region: usize,
+    first_page: usize,
+    count: usize,
+    zero: bool,
+    data: Vec<u8>,
+}
+
+// Sends guest memory to the destination, keeping track of what was sent.
+struct MemorySender<'a> {
+    pages: MemoryPages<'a>,
+    // Hash of each page when it was last sent, for each region.
+    sent_hashes: Vec<Vec<u128>>,
+    run: PageRun,
+    page_data: Vec<u8>,
+}
+
+impl<'a> MemorySender<'a> {
+    fn new(mem: &'a GuestMemory, page_size: usize) -> Self {
+        let pages = MemoryPages::new(mem, page_size);
+        let sent_hashes = pages
+            .regions
+            .iter()
+            .map(|(_, num_pages)| vec![0; *num_pages])
+            .collect();
+        MemorySender {
+            pages,
+            sent_hashes,
+            run: PageRun::default(),
+            page_data: vec![0u8; page_size],
+        }
+    }
+
+    // Sends the current run of pages, if there is one.
+    fn flush(&mut self, stream: &mut MigrationStream) -> Result<()> {
+        if self.run.count > 0 {
+            let data = if self.run.zero {
+                None
+            } else {
+                Some(&self.run.data[..])
+            };
+            stream.write_pages(self.run.region, self.run.first_page, self.run.count, data)?;
+            self.run.count = 0;
+            self.run.data.clear();
+        }
+        Ok(())
+    }
+
+    fn send_page(
+        &mut self,
+        stream: &mut MigrationStream,
+        region: usize,
+        page: usize,
+    ) -> Result<()> {
+        self.pages.page(region, page)?.copy_to(&mut self.page_data);
+        self.sent_hashes[region][page] = page_hash(&self.page_data);
+        let zero = is_zero(&self.page_data);
+        let run = &self.run;
+        let extends_run = run.count > 0
+            && run.region == region
+            && run.first_page + run.count == page
+            && run.zero == zero
+            && run.count < MAX_PAGES_PER_MESSAGE;
+        if !extends_run {
+            self.flush(stream)?;
+            self.run.region = region;
+            self.run.first_page = page;
+            self.run.zero = zero;
+        }
+        if !zero {
+            self.run.data.extend_from_slice(&self.page_data);
+        }
+        self.run.count += 1;
+        Ok(())
+    }
+
+    // Sends all of guest memory. Returns the number of pages sent.
+    fn send_all(&mut self, stream: &mut MigrationStream) -> Result<usize> {
+        let mut sent = 0;
+        for region in 0..self.pages.regions.len() {
+            for page in 0..self.pages.num_pages(region) {
+                self.send_page(stream, region, page)?;
+                sent += 1;
+            }
+        }
+        self.flush(stream)?;
+        Ok(sent)
+    }
+
+    // Sends the pages marked in `dirty_log`. Returns the number of pages sent.
+    fn send_dirty(&mut self, stream: &mut MigrationStream, dirty_log: &[Vec<u8>]) -> Result<usize> {
+        self.pages.check_dirty_log(dirty_log)?;
+        let mut sent = 0;
+        for (region, bitmap) in dirty_log.iter().enumerate() {
+            for page in 0..self.pages.num_pages(region) {
+                if is_dirty(bitmap, page) {
+                    self.send_page(stream, region, page)?;
+                    sent += 1;
+                }
+            }
+        }
+        self.flush(stream)?;
+        Ok(sent)
+    }
+
+    // Sends the pages marked in `dirty_log`, and the pages that changed since they were last sent
+    // without being logged, like the ones written by devices. Returns the number of pages sent.
+    fn send_changed(
+        &mut self,
+        stream: &mut MigrationStream,
+        dirty_log: &[Vec<u8>],
+    ) -> Result<usize> {
+        self.pages.check_dirty_log(dirty_log)?;
+        let mut sent = 0;
+        for (region, bitmap) in dirty_log.iter().enumerate() {
+            for page in 0..self.pages.num_pages(region) {
+                let changed = is_dirty(bitmap, page) || {
+                    self.pages.page(region, page)?.copy_to(&mut self.page_data);
+                    page_hash(&self.page_data) != self.sent_hashes[region][page]
+                };
+                if changed {
+                    self.send_page(stream, region, page)?;
+                    sent += 1;
+                }
+            }
+        }
+        self.flush(stream)?;
+        Ok(sent)
+    }
+}
+
+// Writes the pages received from the source to guest memory until `END`, and returns the sections
+// received.
+fn receive_memory_and_sections(
+    stream: &mut MigrationStream,
+    pages: &MemoryPages,
+) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut sections = BTreeMap::new();
+    loop {
+        match stream.read_message(pages.page_size)? {
+            Message::Pages {
+                region,
+                first_page,
+                count,
+                data,
+            } => pages.write(region, first_page, count, data.as_deref())?,
+            Message::Section { name, data } => {
+                if sections.insert(name.clone(), data).is_some() {
+                    bail!("section {} was received twice", name);
+                }
+            }
+            Message::End => return Ok(sections),
+        }
+    }
+}
+
+// Sends `command` to the devices control thread and checks that it succeeded.
+fn device_control_request(
+    device_control_tube: &Tube,
+    command: &DeviceControlCommand,
+) -> Result<()> {
+    device_control_tube
+        .send(command)
+        .context("send command to devices control socket")?;
+    match device_control_tube
+        .recv()
+        .context("receive from devices control socket")?
+    {
+        VmResponse::Ok => Ok(()),
+        resp => bail!("unexpected devices control response: {}", resp),
+    }
+}
+
+// Writes `sections` to a new temporary file, to pass them between the control loop and the
+// migration worker.
+fn write_sections<'a>(sections: impl IntoIterator<Item = (&'a str, &'a [u8])>) -> Result<File> {
+    let mut file = tempfile::tempfile().context("failed to create VM state file")?;
+    let mut stream = MigrationStream::new(io::empty(), file.try_clone()?);
+    for (name, data) in sections {
+        stream.write_section(name, data)?;
+    }
+    stream.write_end()?;
+    drop(stream);
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+// Reads the sections written by `write_sections`.
+fn read_sections(file: File) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut stream = MigrationStream::new(file, io::sink());
+    let mut sections = BTreeMap::new();
+    loop {
+        match stream.read_message(pagesize())? {
+            Message::Section { name, data } => {
+                sections.insert(name, data);
+            }
+            Message::End => return Ok(sections),
+            Message::Pages { .. } => bail!("unexpected pages in VM state file"),
+        }
+    }
+}
+
+// Requests of the migration worker to the control loop, which owns the VCPUs and the devices.
+#[derive(Serialize, Deserialize)]
+enum WorkerRequest {
+    // Suspend the VCPUs and put the devices to sleep. If `snapshot` is set, reply with their state
+    // and the state of the irqchip.
+    StopVm {
+        snapshot: bool,
+    },
+    // Restore the VM from the state in `file`, as written by `write_sections`.
+    RestoreVm {
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+    },
+    // The migration is over, and failed with the given error if any.
+    Done(Option<String>),
+}
+
+// Replies of the control loop to a `WorkerRequest`.
+#[derive(Serialize, Deserialize)]
+enum WorkerReply {
+    // The VM is stopped. `state` holds its sections, as written by `write_sections`, if they were
+    // asked for.
+    VmStopped { state: Option<FileSerdeWrapper> },
+    VmRestored,
+    Failed(String),
+}
+
+// Sends `request` to the control loop and waits for its reply.
+fn call_control_loop(tube: &Tube, request: &WorkerRequest) -> Result<WorkerReply> {
+    tube.send(request)
+        .context("failed to send request to the control loop")?;
+    match tube
+        .recv()
+        .context("failed to receive reply from the control loop")?
+    {
+        WorkerReply::Failed(e) => Err(anyhow!(e)),
+        reply => Ok(reply),
+    }
+}
+
+// Sends the VM to the destination at `address`, on the migration worker thread.
+fn send_worker(
+    address: &MigrationAddress,
+    header: &MigrationHeader,
+    guest_memory: &GuestMemory,
+    mut get_memory_dirty_log: impl FnMut() -> Result<Vec<Vec<u8>>>,
+    tube: &Tube,
+) -> Result<()> {
+    let mut stream = address.connect()?;
+    stream.write_header(header)?;
+    stream.read_status().context("destination refused the VM")?;
+
+    let mut sender = MemorySender::new(guest_memory, header.page_size);
+    let sent = sender.send_all(&mut stream)?;
+    info!("migration: sent all {} pages of guest memory", sent);
+
+    // Pages written by the guest that weren't sent again yet.
+    let mut dirty_log = get_memory_dirty_log()?;
+    let mut rounds = 0;
+    while count_dirty(&dirty_log) > CONVERGED_DIRTY_PAGES && rounds < MAX_PRECOPY_ROUNDS {
+        rounds += 1;
+        let sent = sender.send_dirty(&mut stream, &dirty_log)?;
+        let next_dirty_log = get_memory_dirty_log()?;
+        let dirty = count_dirty(&next_dirty_log);
+        info!(
+            "migration: round {} sent {} pages, {} pages written meanwhile",
+            rounds, sent, dirty
+        );
+        dirty_log = next_dirty_log;
+        if dirty >= sent {
+            info!("migration: the guest writes memory as fast as it is sent");
+            break;
+        }
+    }
+
+    let state = match call_control_loop(tube, &WorkerRequest::StopVm { snapshot: true })? {
+        WorkerReply::VmStopped {
+            state: Some(FileSerdeWrapper(state)),
+        } => state,
+        _ => bail!("unexpected reply from the control loop"),
+    };
+    merge_dirty(&mut dirty_log, &get_memory_dirty_log()?);
+    let sent = sender.send_changed(&mut stream, &dirty_log)?;
+    info!(
+        "migration: sent the last {} pages with the VM stopped",
+        sent
+    );
+
+    for (name, data) in read_sections(state)? {
+        stream.write_section(&name, &data)?;
+    }
+    stream.write_end()?;
+    stream
+        .read_status()
+        .context("destination failed to restore the VM")
+}
+
+// Receives the VM from the source connecting to `address`, on the migration worker thread.
+fn receive_worker(
+    address: &MigrationAddress,
+    vm: &SnapshotVmConfig,
+    guest_memory: &GuestMemory,
+    tube: &Tube,
+    stop_evt: &Event,
+) -> Result<()> {
+    let mut stream = address.accept(stop_evt)?;
+    let header = stream.read_header()?;
+    let diff = header.vm.incompatibilities(vm);
+    let accepted = if header.page_size != pagesize() {
+        Err(anyhow!(
+            "page size: source {}, destination {}",
+            header.page_size,
+            pagesize()
+        ))
+    } else if !diff.is_empty() {
+        Err(anyhow!(
+            "the source VM doesn't match the VM configuration:\n  {}",
+            diff.join("\n  ")
+        ))
+    } else {
+        Ok(())
+    };
+    stream.write_status(&accepted)?;
+    accepted?;
+
+    let restored = (|| {
+        call_control_loop(tube, &WorkerRequest::StopVm { snapshot: false })?;
+        let pages = MemoryPages::new(guest_memory, pagesize());
+        let sections = receive_memory_and_sections(&mut stream, &pages)?;
+        let file = write_sections(
+            sections
+                .iter()
+                .map(|(name, data)| (name.as_str(), data.as_slice())),
+        )?;
+        call_control_loop(tube, &WorkerRequest::RestoreVm { file })?;
+        Ok(())
+    })();
+    stream.write_status(&restored)?;
+    restored
+}
+
+/// A live migration in progress.
+///
+/// The transfer runs on a worker thread, so a slow or stalled peer doesn't block the control loop.
+/// The worker asks the control loop to stop the VM, and to save or restore its state, through the
+/// tube returned by [`Migration::tube`]. The control loop must call
+/// [`Migration::handle_worker_request`] whenever the tube is readable.
+pub struct Migration {
+    // Dropped before the worker, so a worker waiting for the control loop stops.
+    tube: Tube,
+    _worker: WorkerThread<()>,
+    sending: bool,
+    // The state of the VCPUs and the devices before the VM was stopped, once it was.
+    stopped: Option<(VmRunMode, DevicesState)>,
+}
+
+impl Migration {
+    /// Starts moving the running VM to the destination waiting at `address`.
+    ///
+    /// `get_memory_dirty_log` returns the pages written by the guest since it was last called. It
+    /// is called from the worker thread. Once the migration succeeded, the VCPUs stay suspended and
+    /// the devices asleep, and the VM must exit. If it fails, the VM goes back to the state it was
+    /// in.
+    pub fn send(
+        address: &MigrationAddress,
+        guest_memory: &GuestMemory,
+        device_control_tube: &Tube,
+        vcpu_size: usize,
+        mut get_memory_dirty_log: impl FnMut() -> Result<Vec<Vec<u8>>> + Send + 'static,
+    ) -> Result<Migration> {
+        let header = MigrationHeader {
+            vm: get_snapshot_vm_config(device_control_tube, vcpu_size)?,
+            page_size: pagesize(),
+        };
+        // Reading the dirty log clears it, so only the pages written from now on are logged.
+        get_memory_dirty_log().context("live migration needs the dirty page log")?;
+        device_control_request(
+            device_control_tube,
+            &DeviceControlCommand::ResetSnapshotBase,
+        )?;
+
+        let address = address.clone();
+        let guest_memory = guest_memory.clone();
+        Migration::start(true, move |tube, _stop_evt| {
+            send_worker(&address, &header, &guest_memory, get_memory_dirty_log, tube)
+        })
+    }
+
+    /// Starts waiting at `address` for the source of a migration, to replace the state of the VM
+    /// with the one it sends.
+    ///
+    /// TCP addresses must be loopback addresses unless `allow_remote` is set. The VM stays in the
+    /// state it was in, so it is usually started suspended and resumed after the migration. If the
+    /// migration fails after the source was accepted, the state of the VM is undefined.
+    pub fn receive(
+        address: &MigrationAddress,
+        allow_remote: bool,
+        guest_memory: &GuestMemory,
+        device_control_tube: &Tube,
+        vcpu_size: usize,
+    ) -> Result<Migration> {
+        if !allow_remote {
+            address.check_local()?;
+        }
+        let vm = get_snapshot_vm_config(device_control_tube, vcpu_size)?;
+
+        let address = address.clone();
+        let guest_memory = guest_memory.clone();
+        Migration::start(false, move |tube, stop_evt| {
+            receive_worker(&address, &vm, &guest_memory, tube, stop_evt)
+        })
+    }
+
+    fn start(
+        sending: bool,
+        migrate: impl FnOnce(&Tube, &Event) -> Result<()> + Send + 'static,
+    ) -> Result<Migration> {
+        let (tube, worker_tube) = Tube::pair().context("failed to create migration tube")?;
+        let worker = WorkerThread::start("migration", move |stop_evt| {
+            let error = migrate(&worker_tube, &stop_evt)
+                .err()
+                .map(|e| format!("{:#}", e));
+            if let Err(e) = worker_tube.send(&WorkerRequest::Done(error)) {
+                warn!("failed to send the migration result: {}", e);
+            }
+        });
+        Ok(Migration {
+            tube,
+            _worker: worker,
+            sending,
+            stopped: None,
+        })
+    }
+
+    /// Returns whether this migration sends the VM to another process.
+    pub fn is_sending(&self) -> bool {
+        self.sending
+    }
+
+    /// Returns the tube to wait on for the requests of the worker.
+    pub fn tube(&self) -> &Tube {
+        &self.tube
+    }
+
+    /// Handles a request of the worker. Returns the result of the migration once it is over.
+    ///
+    /// After a successful send, the VCPUs stay suspended and the devices asleep, and the VM must
+    /// exit. Otherwise, the VM goes back to the state it was in before the migration.
+    pub fn handle_worker_request(
+        &mut self,
+        kick_vcpus: impl Fn(VcpuControl),
+        kick_vcpu: impl Fn(VcpuControl, usize),
+        irq_handler_control: &Tube,
+        device_control_tube: &Tube,
+        vcpu_size: usize,
+        snapshot_irqchip: impl Fn() -> Result<serde_json::Value>,
+        restore_irqchip: impl FnMut(serde_json::Value) -> Result<()>,
+    ) -> Option<Result<()>> {
+        let result = match self.tube.recv::<WorkerRequest>() {
+            Ok(WorkerRequest::StopVm { snapshot }) => {
+                let reply = self
+                    .stop_vm(
+                        &kick_vcpus,
+                        irq_handler_control,
+                        device_control_tube,
+                        vcpu_size,
+                        snapshot,
+                        snapshot_irqchip,
+                    )
+                    .map(|state| WorkerReply::VmStopped {
+                        state: state.map(FileSerdeWrapper),
+                    });
+                self.reply(reply);
+                return None;
+            }
+            Ok(WorkerRequest::RestoreVm { file }) => {
+                let reply = restore_vm(
+                    file,
+                    &kick_vcpu,
+                    irq_handler_control,
+                    device_control_tube,
+                    vcpu_size,
+                    restore_irqchip,
+                )
+                .map(|()| WorkerReply::VmRestored);
+                self.reply(reply);
+                return None;
+            }
+            Ok(WorkerRequest::Done(None)) => Ok(()),
+            Ok(WorkerRequest::Done(Some(e))) => Err(anyhow!(e)),
+            Err(e) => Err(anyhow!("the migration worker stopped: {}", e)),
+        };
+
+        // The VM now runs on the destination, so this one must not run anymore.
+        if self.sending && result.is_ok() {
+            return Some(result);
+        }
+        if let Some((run_mode, devices_state)) = self.stopped.take() {
+            if let Err(e) = DeviceSleepGuard::wake(device_control_tube, &devices_state) {
+                error!("failed to wake the devices after the migration: {:#}", e);
+            }
+            VcpuSuspendGuard::resume(&kick_vcpus, run_mode);
+        }
+        Some(result)
+    }
+
+    fn reply(&self, reply: Result<WorkerReply>) {
+        let reply = reply.unwrap_or_else(|e| WorkerReply::Failed(format!("{:#}", e)));
+        if let Err(e) = self.tube.send(&reply) {
+            error!("failed to reply to the migration worker: {}", e);
+        }
+    }
+
+    // Suspends the VCPUs and puts the devices to sleep, and returns the state of the VM if
+    // `snapshot` is set.
+    fn stop_vm(
+        &mut self,
+        kick_vcpus: &impl Fn(VcpuControl),
+        irq_handler_control: &Tube,
+        device_control_tube: &Tube,
+        vcpu_size: usize,
+        snapshot: bool,
+        snapshot_irqchip: impl Fn() -> Result<serde_json::Value>,
+    ) -> Result<Option<File>> {
+        // The guards wake the VM again if it can't be stopped or saved.
+        let vcpu_guard = VcpuSuspendGuard::new(kick_vcpus, vcpu_size)?;
+        let device_guard = DeviceSleepGuard::new(device_control_tube)?;
+        flush_irqs(irq_handler_control)?;
+
+        let state = if snapshot {
+            let vcpus = serde_json::to_vec(&snapshot_vcpus(kick_vcpus, vcpu_size)?)?;
+            let irqchip = serde_json::to_vec(&snapshot_irqchip()?)?;
+            // The state of the devices can be too large for a Tube message.
+            let mut devices_file =
+                tempfile::tempfile().context("failed to create devices state file")?;
+            device_control_request(
+                device_control_tube,
+                &DeviceControlCommand::SnapshotDevicesState {
+                    file: devices_file.try_clone()?,
+                },
+            )?;
+            let mut devices = Vec::new();
+            devices_file.seek(SeekFrom::Start(0))?;
+            devices_file.read_to_end(&mut devices)?;
+            Some(write_sections([
+                (VCPU_SECTION, vcpus.as_slice()),
+                (IRQCHIP_SECTION, irqchip.as_slice()),
+                (DEVICES_SECTION, devices.as_slice()),
+            ])?)
+        } else {
+            None
+        };
+
+        let devices_state = device_guard.release();
+        self.stopped = Some((vcpu_guard.release(), devices_state));
+        Ok(state)
+    }
+}
+
+// Restores the VM from the sections received from the source. The VCPUs must be suspended and the
+// devices asleep.
+fn restore_vm(
+    file: File,
+    kick_vcpu: &impl Fn(VcpuControl, usize),
+    irq_handler_control: &Tube,
+    device_control_tube: &Tube,
+    vcpu_size: usize,
+    mut restore_irqchip: impl FnMut(serde_json::Value) -> Result<()>,
+) -> Result<()> {
+    let mut sections = read_sections(file)?;
+    let mut section = |name: &str| {
+        sections
+            .remove(name)
+            .with_context(|| format!("the source didn't send section {}", name))
+    };
+
+    let irqchip: serde_json::Value = serde_json::from_slice(&section(IRQCHIP_SECTION)?)?;
+    restore_irqchip(irqchip)?;
+
+    let vcpus: Vec<VcpuSnapshot> = serde_json::from_slice(&section(VCPU_SECTION)?)?;
+    restore_vcpus(kick_vcpu, vcpu_size, vcpus)?;
+
+    let mut devices_file = tempfile::tempfile().context("failed to create devices state file")?;
+    devices_file.write_all(&section(DEVICES_SECTION)?)?;
+    devices_file.seek(SeekFrom::Start(0))?;
+    device_control_request(
+        device_control_tube,
+        &DeviceControlCommand::RestoreDevicesState { file: devices_file },
+    )?;
+
+    refresh_irq_event_tokens(irq_handler_control)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_address() {
+        assert_eq!(
+            "tcp:localhost:1234".parse(),
+            Ok(MigrationAddress::Tcp("localhost:1234".to_string()))
+        );
+        assert_eq!(
+            "tcp:[::1]:1234".parse(),
+            Ok(MigrationAddress::Tcp("[::1]:1234".to_string()))
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            "unix:/run/migrate.sock".parse(),
+            Ok(MigrationAddress::Unix("/run/migrate.sock".into()))
+        );
+        assert!("tcp:localhost".parse::<MigrationAddress>().is_err());
+        assert!("tcp::1234".parse::<MigrationAddress>().is_err());
+        assert!("unix:".parse::<MigrationAddress>().is_err());
+        assert!("/run/migrate.sock".parse::<MigrationAddress>().is_err());
+
+        let address: MigrationAddress = "tcp:127.0.0.1:4444".parse().unwrap();
+        assert_eq!(address.to_string(), "tcp:127.0.0.1:4444");
+    }
+
+    #[test]
+    fn check_local_address() {
+        let local: MigrationAddress = "tcp:127.0.0.1:4444".parse().unwrap();
+        assert!(local.check_local().is_ok());
+        let local: MigrationAddress = "tcp:[::1]:4444".parse().unwrap();
+        assert!(local.check_local().is_ok());
+        let any: MigrationAddress = "tcp:0.0.0.0:4444".parse().unwrap();
+        assert!(any.check_local().is_err());
+        #[cfg(unix)]
+        {
+            let unix: MigrationAddress = "unix:/run/migrate.sock".parse().unwrap();
+            assert!(unix.check_local().is_ok());
+        }
+    }
+
+    #[test]
+    fn accept_stops() {
+        let address: MigrationAddress = "tcp:127.0.0.1:0".parse().unwrap();
+        let stop_evt = Event::new().unwrap();
+        stop_evt.signal().unwrap();
+        assert!(address.accept(&stop_evt).is_err());
+    }
+
+    #[test]
+    fn sections_file() {
+        let file = write_sections([("a", &[1u8, 2, 3][..]), ("b", &[][..])]).unwrap();
+        let sections = read_sections(file).unwrap();
+        assert_eq!(
+            sections,
+            BTreeMap::from([("a".to_string(), vec![1, 2, 3]), ("b".to_string(), vec![])])
+        );
+    }
+
+    #[test]
+    fn dirty_log_helpers() {
+        let mut dirty_log = vec![vec![0b0000_0101, 0], vec![0x80]];
+        assert_eq!(count_dirty(&dirty_log), 3);
+        merge_dirty(&mut dirty_log, &[vec![0b0000_0011, 1], vec![0]]);
+        assert_eq!(dirty_log, vec![vec![0b0000_0111, 1], vec![0x80]]);
+        assert!(is_dirty(&dirty_log[0], 8));
+        assert!(!is_dirty(&dirty_log[0], 9));
+    }
+
+    #[cfg(unix)]
+    fn fill(mem: &GuestMemory, page: u64, value: u8) {
+        let page_size = pagesize();
+        mem.get_slice_at_addr(GuestAddress(page * page_size as u64), page_size)
+            .unwrap()
+            .write_bytes(value);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn memory_transfer() {
+        let page_size = pagesize();
+        let layout = [
+            (GuestAddress(0), page_size as u64 * 600),
+            (GuestAddress(page_size as u64 * 1000), page_size as u64 * 4),
+        ];
+        let source = GuestMemory::new(&layout).unwrap();
+        let destination = GuestMemory::new(&layout).unwrap();
+        for page in 0..300 {
+            fill(&source, page, page as u8 + 1);
+        }
+        fill(&source, 1002, 0xaa);
+        // Overwritten with zeros by the transfer.
+        fill(&destination, 500, 0x55);
+
+        let (source_socket, destination_socket) = UnixStream::pair().unwrap();
+        let source_thread = {
+            let source = source.clone();
+            std::thread::spawn(move || {
+                let mut stream =
+                    MigrationStream::new(source_socket.try_clone().unwrap(), source_socket);
+                let mut sender = MemorySender::new(&source, page_size);
+                assert_eq!(sender.send_all(&mut stream).unwrap(), 604);
+
+                // A page written by the guest and one written by a device.
+                fill(&source, 10, 0xee);
+                fill(&source, 1003, 0xdd);
+                let mut dirty_log = vec![vec![0u8; 600 / 8], vec![0u8; 1]];
+                dirty_log[0][1] = 1 << 2;
+                assert_eq!(sender.send_dirty(&mut stream, &dirty_log).unwrap(), 1);
+                assert_eq!(sender.send_changed(&mut stream, &dirty_log).unwrap(), 2);
+
+                stream.write_section("test", b"section data").unwrap();
+                stream.write_end().unwrap();
+            })
+        };
+
+        let mut stream =
+            MigrationStream::new(destination_socket.try_clone().unwrap(), destination_socket);
+        let pages = MemoryPages::new(&destination, page_size);
+        let sections = receive_memory_and_sections(&mut stream, &pages).unwrap();
+        source_thread.join().unwrap();
+
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections["test"], b"section data");
+        let mut source_data = vec![0u8; page_size];
+        let mut destination_data = vec![0u8; page_size];
+        for (addr, size) in layout {
+            for page in 0..size / page_size as u64 {
+                let addr = addr.unchecked_add(page * page_size as u64);
+                source
+                    .get_slice_at_addr(addr, page_size)
+                    .unwrap()
+                    .copy_to(&mut source_data);
+                destination
+                    .get_slice_at_addr(addr, page_size)
+                    .unwrap()
+                    .copy_to(&mut destination_data);
+                assert_eq!(source_data, destination_data, "page at {}", addr);
+            }
+        }
+    }
+}
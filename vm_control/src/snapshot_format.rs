@@ -0,0 +1,716 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The on-disk format of VM snapshots.
+//!
+//! A snapshot is a directory holding one file per section of VM state (vCPUs, irqchip, guest
+//! memory, devices) and a manifest. The manifest records the format version, the configuration of
+//! the VM that was snapshotted, and the size and CRC32 of each section, so a snapshot can be
+//! validated and checked against the VM it is restored to before any state is touched. The
+//! manifest is written last, so a snapshot without one is incomplete.
+//!
+//! An incremental snapshot only saves the guest memory that changed since its parent snapshot, and
+//! its manifest records where the parent is and the CRC32 of the parent's memory section, so the
+//! chain of snapshots can be checked before restoring it.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use base::warn;
+use base::with_as_descriptor;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Version of the snapshot format. Snapshots with a different version can't be restored.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 2;
+
+/// Name of the manifest file in a snapshot directory.
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Most snapshots that an incremental snapshot can be based on, directly or not.
+pub const MAX_SNAPSHOT_PARENTS: usize = 64;
+
+/// Section holding the state of the vCPUs.
+pub const VCPU_SECTION: &str = "vcpu";
+/// Section holding the state of the irqchip.
+pub const IRQCHIP_SECTION: &str = "irqchip";
+/// Section holding the contents of guest memory.
+pub const MEMORY_SECTION: &str = "memory";
+/// Section holding the state of the devices.
+pub const DEVICES_SECTION: &str = "devices";
+
+/// A region of guest memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotMemoryRegion {
+    pub guest_addr: u64,
+    pub size: u64,
+}
+
+/// A device whose state is saved in a snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotDevice {
+    /// The `DeviceId` of the device, which is used to match saved state to devices on restore.
+    pub id: u32,
+    /// Debug label of the device, only used in messages.
+    pub name: String,
+}
+
+/// The parts of the VM configuration known to the devices control thread.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DevicesSnapshotConfig {
+    pub memory_regions: Vec<SnapshotMemoryRegion>,
+    pub devices: Vec<SnapshotDevice>,
+}
+
+/// The configuration of a VM that a snapshot can only be restored to if it matches.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotVmConfig {
+    /// Version of crosvm that took the snapshot. Only informational.
+    pub crosvm_version: String,
+    pub arch: String,
+    pub vcpu_count: usize,
+    pub memory_regions: Vec<SnapshotMemoryRegion>,
+    pub devices: Vec<SnapshotDevice>,
+}
+
+impl SnapshotVmConfig {
+    /// Returns the configuration of a VM with `vcpu_count` vCPUs run by this build of crosvm.
+    pub fn new(vcpu_count: usize, devices_config: DevicesSnapshotConfig) -> SnapshotVmConfig {
+        SnapshotVmConfig {
+            crosvm_version: env!("CARGO_PKG_VERSION").to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            vcpu_count,
+            memory_regions: devices_config.memory_regions,
+            devices: devices_config.devices,
+        }
+    }
+
+    /// Returns a description of each difference between a snapshot taken of a VM with this
+    /// configuration and the VM configured by `vm` that prevents restoring it.
+    pub fn incompatibilities(&self, vm: &SnapshotVmConfig) -> Vec<String> {
+        let mut diff = Vec::new();
+        if self.arch != vm.arch {
+            diff.push(format!("arch: snapshot {}, VM {}", self.arch, vm.arch));
+        }
+        if self.vcpu_count != vm.vcpu_count {
+            diff.push(format!(
+                "vcpu count: snapshot {}, VM {}",
+                self.vcpu_count, vm.vcpu_count
+            ));
+        }
+        if self.memory_regions != vm.memory_regions {
+            diff.push(format!(
+                "memory regions: snapshot {}, VM {}",
+                format_memory_regions(&self.memory_regions),
+                format_memory_regions(&vm.memory_regions)
+            ));
+        }
+
+        // Devices are matched by ID, in order, the same way their state is restored.
+        let mut device_counts: BTreeMap<u32, (Vec<&str>, usize)> = BTreeMap::new();
+        for device in &self.devices {
+            device_counts
+                .entry(device.id)
+                .or_default()
+                .0
+                .push(&device.name);
+        }
+        for device in &vm.devices {
+            let (names, count) = device_counts.entry(device.id).or_default();
+            *count += 1;
+            if *count > names.len() {
+                diff.push(format!(
+                    "device {} ({:#x}): not in snapshot",
+                    device.name, device.id
+                ));
+            }
+        }
+        for (id, (names, count)) in device_counts {
+            for name in names.iter().skip(count) {
+                diff.push(format!("device {} ({:#x}): not in VM", name, id));
+            }
+        }
+        diff
+    }
+}
+
+fn format_memory_regions(regions: &[SnapshotMemoryRegion]) -> String {
+    let regions: Vec<String> = regions
+        .iter()
+        .map(|region| format!("{:#x}+{:#x}", region.guest_addr, region.size))
+        .collect();
+    format!("[{}]", regions.join(", "))
+}
+
+/// A section of a snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotSection {
+    pub name: String,
+    pub size: u64,
+    pub crc32: u32,
+}
+
+/// The snapshot an incremental snapshot is based on.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotParent {
+    /// Path of the parent snapshot directory. Relative to the directory holding the incremental
+    /// snapshot when both are in the same directory, so they can be moved together.
+    pub path: PathBuf,
+    /// CRC32 of the memory section of the parent.
+    pub memory_crc32: u32,
+}
+
+/// Describes a complete snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub format_version: u32,
+    pub vm: SnapshotVmConfig,
+    pub sections: Vec<SnapshotSection>,
+    /// Set for incremental snapshots.
+    pub parent: Option<SnapshotParent>,
+}
+
+impl SnapshotManifest {
+    /// Returns an error listing every difference that prevents restoring the snapshot to the VM
+    /// configured by `vm`.
+    pub fn check_compatible(&self, vm: &SnapshotVmConfig) -> Result<()> {
+        if self.vm.crosvm_version != vm.crosvm_version {
+            warn!(
+                "snapshot was taken by crosvm {}, restoring with {}",
+                self.vm.crosvm_version, vm.crosvm_version
+            );
+        }
+        let diff = self.vm.incompatibilities(vm);
+        if !diff.is_empty() {
+            bail!(
+                "snapshot doesn't match the VM configuration:\n  {}",
+                diff.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the section `name`, if the snapshot has it.
+    pub fn section(&self, name: &str) -> Option<&SnapshotSection> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+}
+
+// Returns the directory holding the snapshot directory `dir`.
+fn containing_dir(dir: &Path) -> &Path {
+    dir.parent().unwrap_or_else(|| Path::new(""))
+}
+
+/// The bitmaps of the pages of each region of guest memory written by the guest.
+///
+/// They are kept in an unnamed temporary file, since they are too large to be sent in a message.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MemoryDirtyLog {
+    #[serde(with = "with_as_descriptor")]
+    file: File,
+}
+
+impl MemoryDirtyLog {
+    pub fn new(bitmaps: &[Vec<u8>]) -> Result<MemoryDirtyLog> {
+        let file = tempfile::tempfile().context("failed to create dirty log file")?;
+        let mut writer = BufWriter::new(&file);
+        for bitmap in bitmaps {
+            writer.write_all(&(bitmap.len() as u64).to_le_bytes())?;
+            writer.write_all(bitmap)?;
+        }
+        writer.flush().context("failed to write dirty log")?;
+        drop(writer);
+        Ok(MemoryDirtyLog { file })
+    }
+
+    pub fn read(mut self) -> Result<Vec<Vec<u8>>> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(self.file);
+        let mut bitmaps = Vec::new();
+        let mut len = [0u8; 8];
+        loop {
+            match reader.read_exact(&mut len) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e).context("failed to read dirty log"),
+            }
+            let mut bitmap = vec![0u8; u64::from_le_bytes(len) as usize];
+            reader
+                .read_exact(&mut bitmap)
+                .context("failed to read dirty log")?;
+            bitmaps.push(bitmap);
+        }
+        Ok(bitmaps)
+    }
+}
+
+fn check_section_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name == MANIFEST_FILE_NAME
+        || name.starts_with('.')
+        || name.contains(std::path::is_separator)
+    {
+        bail!("invalid snapshot section name {:?}", name);
+    }
+    Ok(())
+}
+
+// Returns the size and CRC32 of the file at `path`.
+fn checksum_file(path: &Path) -> Result<(u64, u32)> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut size = 0;
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        let len = file
+            .read(&mut buf)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+        size += len as u64;
+    }
+    Ok((size, hasher.finalize()))
+}
+
+/// Writes the sections of a snapshot and then its manifest.
+///
+/// The writer can be sent to other processes or threads so they write their own sections.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotWriter {
+    dir: PathBuf,
+}
+
+impl SnapshotWriter {
+    /// Creates the directory `dir` for a new snapshot. Fails if it already exists.
+    pub fn create(dir: PathBuf) -> Result<SnapshotWriter> {
+        std::fs::create_dir(&dir)
+            .with_context(|| format!("failed to create snapshot directory {}", dir.display()))?;
+        Ok(SnapshotWriter { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Creates the file for the section `name`.
+    pub fn create_section(&self, name: &str) -> Result<File> {
+        check_section_name(name)?;
+        let path = self.dir.join(name);
+        File::create(&path).with_context(|| format!("failed to create {}", path.display()))
+    }
+
+    /// Writes `value` as JSON to the section `name`.
+    pub fn write_json_section<T: Serialize>(&self, name: &str, value: &T) -> Result<()> {
+        let mut writer = BufWriter::new(self.create_section(name)?);
+        serde_json::to_writer(&mut writer, value)
+            .with_context(|| format!("failed to write snapshot section {}", name))?;
+        writer
+            .flush()
+            .with_context(|| format!("failed to write snapshot section {}", name))
+    }
+
+    /// Completes the snapshot by writing a manifest covering every section in the directory.
+    /// `parent` is the snapshot an incremental snapshot is based on.
+    pub fn finish(
+        self,
+        vm: SnapshotVmConfig,
+        parent: Option<&SnapshotReader>,
+    ) -> Result<SnapshotManifest> {
+        let parent = parent
+            .map(|parent| parent.as_parent_of(&self.dir))
+            .transpose()?;
+
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)
+            .with_context(|| format!("failed to read {}", self.dir.display()))?
+        {
+            let name = entry?
+                .file_name()
+                .into_string()
+                .map_err(|name| anyhow::anyhow!("invalid snapshot section name {:?}", name))?;
+            if check_section_name(&name).is_ok() {
+                names.push(name);
+            }
+        }
+        names.sort();
+
+        let mut sections = Vec::with_capacity(names.len());
+        for name in names {
+            let (size, crc32) = checksum_file(&self.dir.join(&name))?;
+            sections.push(SnapshotSection { name, size, crc32 });
+        }
+        let manifest = SnapshotManifest {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            vm,
+            sections,
+            parent,
+        };
+
+        // Write the manifest under a temporary name first so it is never seen partially written.
+        let tmp_path = self.dir.join(format!(".{}", MANIFEST_FILE_NAME));
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+        serde_json::to_writer_pretty(&file, &manifest).context("failed to write manifest")?;
+        file.sync_all().context("failed to write manifest")?;
+        std::fs::rename(&tmp_path, self.dir.join(MANIFEST_FILE_NAME))
+            .context("failed to write manifest")?;
+        Ok(manifest)
+    }
+}
+
+/// Reads the sections of a complete snapshot.
+///
+/// The reader can be sent to other processes or threads so they read their own sections.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotReader {
+    dir: PathBuf,
+    manifest: SnapshotManifest,
+}
+
+impl SnapshotReader {
+    /// Opens the snapshot in `dir` and reads its manifest.
+    pub fn open(dir: PathBuf) -> Result<SnapshotReader> {
+        let manifest_path = dir.join(MANIFEST_FILE_NAME);
+        let file = File::open(&manifest_path).with_context(|| {
+            format!(
+                "failed to open {}, the snapshot may be incomplete",
+                manifest_path.display()
+            )
+        })?;
+        let manifest: SnapshotManifest = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("failed to parse {}", manifest_path.display()))?;
+        if manifest.format_version != SNAPSHOT_FORMAT_VERSION {
+            bail!(
+                "unsupported snapshot format version {}, expected {}",
+                manifest.format_version,
+                SNAPSHOT_FORMAT_VERSION
+            );
+        }
+        Ok(SnapshotReader { dir, manifest })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn manifest(&self) -> &SnapshotManifest {
+        &self.manifest
+    }
+
+    // Returns the reference to this snapshot to record in the manifest of the incremental snapshot
+    // in `child_dir`.
+    fn as_parent_of(&self, child_dir: &Path) -> Result<SnapshotParent> {
+        let memory_crc32 = match self.manifest.section(MEMORY_SECTION) {
+            Some(section) => section.crc32,
+            None => bail!("parent snapshot has no {} section", MEMORY_SECTION),
+        };
+        let dir = self
+            .dir
+            .canonicalize()
+            .with_context(|| format!("failed to resolve {}", self.dir.display()))?;
+        let child_containing_dir = containing_dir(child_dir).canonicalize().ok();
+        let path = match dir.file_name() {
+            Some(name) if child_containing_dir.as_deref() == dir.parent() => PathBuf::from(name),
+            _ => dir,
+        };
+        Ok(SnapshotParent { path, memory_crc32 })
+    }
+
+    /// Opens the snapshot an incremental snapshot is based on. Returns `None` if this snapshot
+    /// isn't incremental.
+    pub fn open_parent(&self) -> Result<Option<SnapshotReader>> {
+        let parent = match &self.manifest.parent {
+            Some(parent) => parent,
+            None => return Ok(None),
+        };
+        let dir = containing_dir(&self.dir).join(&parent.path);
+        let reader = SnapshotReader::open(dir.clone())
+            .with_context(|| format!("failed to open parent snapshot {}", dir.display()))?;
+        if reader.manifest.section(MEMORY_SECTION).map(|s| s.crc32) != Some(parent.memory_crc32) {
+            bail!(
+                "parent snapshot {} was modified or replaced since {} was taken",
+                dir.display(),
+                self.dir.display()
+            );
+        }
+        Ok(Some(reader))
+    }
+
+    /// Opens the chain of snapshots an incremental snapshot is based on, from its parent to the
+    /// first full snapshot. Fails if a snapshot of the chain is based on itself, directly or
+    /// through symlinks, or if the chain is longer than `MAX_SNAPSHOT_PARENTS`.
+    pub fn open_parents(&self) -> Result<Vec<SnapshotReader>> {
+        let canonical_dir = |reader: &SnapshotReader| {
+            reader
+                .dir
+                .canonicalize()
+                .with_context(|| format!("failed to resolve {}", reader.dir.display()))
+        };
+        let mut visited = BTreeSet::new();
+        visited.insert(canonical_dir(self)?);
+        let mut parents = Vec::new();
+        let mut next_parent = self.open_parent()?;
+        while let Some(parent) = next_parent {
+            if parents.len() == MAX_SNAPSHOT_PARENTS {
+                bail!(
+                    "snapshot {} is based on more than {} snapshots",
+                    self.dir.display(),
+                    MAX_SNAPSHOT_PARENTS
+                );
+            }
+            if !visited.insert(canonical_dir(&parent)?) {
+                bail!(
+                    "the parents of snapshot {} loop back to {}",
+                    self.dir.display(),
+                    parent.dir.display()
+                );
+            }
+            next_parent = parent.open_parent()?;
+            parents.push(parent);
+        }
+        Ok(parents)
+    }
+
+    /// Checks that every section has the size and checksum recorded in the manifest.
+    pub fn verify_sections(&self) -> Result<()> {
+        for section in &self.manifest.sections {
+            check_section_name(&section.name)?;
+            let (size, crc32) = checksum_file(&self.dir.join(&section.name))?;
+            if size != section.size || crc32 != section.crc32 {
+                bail!(
+                    "snapshot section {} is corrupted: expected {} bytes with crc32 {:#010x}, \
+                     found {} bytes with crc32 {:#010x}",
+                    section.name,
+                    section.size,
+                    section.crc32,
+                    size,
+                    crc32
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens the section `name`.
+    pub fn open_section(&self, name: &str) -> Result<File> {
+        if self.manifest.section(name).is_none() {
+            bail!("snapshot has no section {}", name);
+        }
+        let path = self.dir.join(name);
+        File::open(&path).with_context(|| format!("failed to open {}", path.display()))
+    }
+
+    /// Reads the JSON value stored in the section `name`.
+    pub fn read_json_section<T: DeserializeOwned>(&self, name: &str) -> Result<T> {
+        let reader = BufReader::new(self.open_section(name)?);
+        serde_json::from_reader(reader)
+            .with_context(|| format!("failed to parse snapshot section {}", name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn test_config() -> SnapshotVmConfig {
+        SnapshotVmConfig::new(
+            2,
+            DevicesSnapshotConfig {
+                memory_regions: vec![SnapshotMemoryRegion {
+                    guest_addr: 0,
+                    size: 0x1000_0000,
+                }],
+                devices: vec![
+                    SnapshotDevice {
+                        id: 1,
+                        name: "serial".to_string(),
+                    },
+                    SnapshotDevice {
+                        id: 1,
+                        name: "serial".to_string(),
+                    },
+                    SnapshotDevice {
+                        id: 7,
+                        name: "virtio-block".to_string(),
+                    },
+                ],
+            },
+        )
+    }
+
+    #[test]
+    fn write_and_read() {
+        let tmp = tempdir().unwrap();
+        let dir = tmp.path().join("snapshot");
+        let writer = SnapshotWriter::create(dir.clone()).unwrap();
+        writer
+            .write_json_section(VCPU_SECTION, &vec![1, 2])
+            .unwrap();
+        writer
+            .create_section(MEMORY_SECTION)
+            .unwrap()
+            .write_all(&[0x55; 0x1000])
+            .unwrap();
+
+        // A snapshot isn't complete until the manifest is written.
+        SnapshotReader::open(dir.clone()).expect_err("opened incomplete snapshot");
+        let manifest = writer.finish(test_config(), None).unwrap();
+        assert_eq!(manifest.sections.len(), 2);
+        assert_eq!(manifest.sections[0].name, MEMORY_SECTION);
+        assert_eq!(manifest.sections[0].size, 0x1000);
+
+        let reader = SnapshotReader::open(dir.clone()).unwrap();
+        assert_eq!(reader.manifest(), &manifest);
+        reader.verify_sections().unwrap();
+        let vcpus: Vec<u32> = reader.read_json_section(VCPU_SECTION).unwrap();
+        assert_eq!(vcpus, vec![1, 2]);
+        reader
+            .open_section(IRQCHIP_SECTION)
+            .expect_err("opened missing section");
+
+        // Corrupt the memory section.
+        std::fs::write(dir.join(MEMORY_SECTION), [0x55; 0xfff]).unwrap();
+        reader
+            .verify_sections()
+            .expect_err("corrupted section was not detected");
+    }
+
+    #[test]
+    fn dirty_log_round_trip() {
+        let bitmaps = vec![vec![0xff; 0x10_0000], Vec::new(), vec![1, 2, 3]];
+        let dirty_log = MemoryDirtyLog::new(&bitmaps).unwrap();
+        assert_eq!(dirty_log.read().unwrap(), bitmaps);
+    }
+
+    #[test]
+    fn existing_directory() {
+        let tmp = tempdir().unwrap();
+        SnapshotWriter::create(tmp.path().to_path_buf()).expect_err("reused directory");
+    }
+
+    #[test]
+    fn invalid_section_names() {
+        let tmp = tempdir().unwrap();
+        let writer = SnapshotWriter::create(tmp.path().join("snapshot")).unwrap();
+        for name in ["", MANIFEST_FILE_NAME, "../memory", ".hidden"] {
+            writer
+                .create_section(name)
+                .expect_err("created invalid section");
+        }
+    }
+
+    #[test]
+    fn incompatible_config() {
+        let snapshot = test_config();
+        let manifest = SnapshotManifest {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            vm: snapshot.clone(),
+            sections: Vec::new(),
+            parent: None,
+        };
+        manifest.check_compatible(&snapshot).unwrap();
+
+        let mut vm = snapshot.clone();
+        vm.vcpu_count = 4;
+        vm.memory_regions[0].size = 0x2000_0000;
+        vm.devices.remove(0);
+        vm.devices.push(SnapshotDevice {
+            id: 9,
+            name: "virtio-net".to_string(),
+        });
+        assert_eq!(
+            snapshot.incompatibilities(&vm),
+            vec![
+                "vcpu count: snapshot 2, VM 4".to_string(),
+                "memory regions: snapshot [0x0+0x10000000], VM [0x0+0x20000000]".to_string(),
+                "device virtio-net (0x9): not in snapshot".to_string(),
+                "device serial (0x1): not in VM".to_string(),
+            ]
+        );
+        manifest
+            .check_compatible(&vm)
+            .expect_err("restored to a different VM");
+    }
+
+    fn write_snapshot(dir: PathBuf, memory: u8, parent: Option<&SnapshotReader>) -> SnapshotReader {
+        let writer = SnapshotWriter::create(dir.clone()).unwrap();
+        writer
+            .create_section(MEMORY_SECTION)
+            .unwrap()
+            .write_all(&[memory; 0x1000])
+            .unwrap();
+        writer.finish(test_config(), parent).unwrap();
+        SnapshotReader::open(dir).unwrap()
+    }
+
+    #[test]
+    fn parent_chain() {
+        let tmp = tempdir().unwrap();
+        let base = write_snapshot(tmp.path().join("base"), 1, None);
+        assert!(base.open_parent().unwrap().is_none());
+        let child = write_snapshot(tmp.path().join("child"), 2, Some(&base));
+        let parent = child.manifest().parent.as_ref().unwrap();
+        assert_eq!(parent.path, PathBuf::from("base"));
+        assert_eq!(
+            child.open_parent().unwrap().unwrap().manifest(),
+            base.manifest()
+        );
+
+        // Snapshots in different directories refer to the parent by its absolute path.
+        std::fs::create_dir(tmp.path().join("other")).unwrap();
+        let other = write_snapshot(tmp.path().join("other/child"), 3, Some(&base));
+        assert!(other.manifest().parent.as_ref().unwrap().path.is_absolute());
+        other.open_parent().unwrap().unwrap();
+
+        // Replacing the parent breaks the chain.
+        std::fs::remove_dir_all(tmp.path().join("base")).unwrap();
+        write_snapshot(tmp.path().join("base"), 4, None);
+        child
+            .open_parent()
+            .expect_err("opened replaced parent snapshot");
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn parent_loop() {
+        let tmp = tempdir().unwrap();
+        let base = write_snapshot(tmp.path().join("base"), 1, None);
+        let child = write_snapshot(tmp.path().join("child"), 2, Some(&base));
+        assert_eq!(child.open_parents().unwrap().len(), 1);
+
+        // Make the base snapshot, reached through a symlink, a child of its own child.
+        std::os::unix::fs::symlink(tmp.path().join("child"), tmp.path().join("link")).unwrap();
+        let mut manifest = base.manifest().clone();
+        manifest.parent = Some(SnapshotParent {
+            path: PathBuf::from("link"),
+            memory_crc32: child.manifest().section(MEMORY_SECTION).unwrap().crc32,
+        });
+        std::fs::write(
+            tmp.path().join("base").join(MANIFEST_FILE_NAME),
+            serde_json::to_vec(&manifest).unwrap(),
+        )
+        .unwrap();
+        child
+            .open_parents()
+            .expect_err("opened snapshot based on itself");
+    }
+}
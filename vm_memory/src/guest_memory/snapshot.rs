@@ -0,0 +1,562 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The format guest memory is saved in by `GuestMemory::snapshot`.
+//!
+//! Memory is saved one host page at a time. Pages that only hold zeros are recorded without any
+//! data, and in an incremental snapshot, pages that didn't change since the parent snapshot are
+//! recorded as coming from the parent. The data of the remaining pages is stored in chunks of up
+//! to `PAGES_PER_CHUNK` pages, each compressed on its own.
+//!
+//! The memory file holds the chunks of every region, in order, followed by the index: the state
+//! of each page, the hash of each page, and the stored size of each chunk. A chunk whose stored
+//! size is the size of its pages isn't compressed.
+
+use std::fmt;
+use std::fmt::Display;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::str::FromStr;
+
+use anyhow::bail;
+use anyhow::Context;
+use base::pagesize;
+use base::VolatileSlice;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::GuestAddress;
+use crate::GuestMemory;
+
+/// Returns the hash of the contents of a page of guest memory, which tells whether the page changed
+/// when the dirty log doesn't, e.g. when it was written by a device.
+///
+/// The 128-bit XXH3 hash is used, since a 32-bit checksum lets too many pages that changed look
+/// unchanged in large VMs.
+pub fn page_hash(data: &[u8]) -> u128 {
+    twox_hash::xxh3::hash128(data)
+}
+
+/// Maximum number of pages whose data is compressed together.
+const PAGES_PER_CHUNK: usize = 64;
+
+// States of a page in the index.
+const PAGE_ZERO: u8 = 0;
+const PAGE_DATA: u8 = 1;
+const PAGE_PARENT: u8 = 2;
+
+/// Compression of the guest memory saved in a snapshot.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MemoryCompression {
+    #[default]
+    None,
+    /// Requires the `zstd` feature.
+    Zstd,
+    /// Requires the `lz4` feature.
+    Lz4,
+}
+
+impl Display for MemoryCompression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryCompression::None => write!(f, "none"),
+            MemoryCompression::Zstd => write!(f, "zstd"),
+            MemoryCompression::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+impl FromStr for MemoryCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(MemoryCompression::None),
+            "zstd" => Ok(MemoryCompression::Zstd),
+            "lz4" => Ok(MemoryCompression::Lz4),
+            _ => Err(format!(
+                "invalid compression {:?}, expected none, zstd or lz4",
+                s
+            )),
+        }
+    }
+}
+
+/// The snapshot an incremental snapshot of guest memory is based on.
+pub struct MemorySnapshotParent<'a> {
+    /// Metadata returned by `GuestMemory::snapshot` when the parent was taken.
+    pub metadata: serde_json::Value,
+    /// The file the parent was written to.
+    pub file: File,
+    /// For each region of guest memory, the bitmap of the pages written by the guest since the
+    /// parent was taken.
+    pub dirty_log: &'a [Vec<u8>],
+}
+
+#[derive(Serialize, Deserialize)]
+struct MemorySnapshotMetadata {
+    // Guest base and size for each memory region.
+    regions: Vec<(u64, usize)>,
+    page_size: usize,
+    compression: MemoryCompression,
+    // Offset of the index in the memory file.
+    index_offset: u64,
+}
+
+impl MemorySnapshotMetadata {
+    fn num_pages(&self) -> usize {
+        self.regions
+            .iter()
+            .map(|(_, size)| size / self.page_size)
+            .sum()
+    }
+
+    fn num_chunks(&self) -> usize {
+        self.regions
+            .iter()
+            .map(|(_, size)| (size / self.page_size + PAGES_PER_CHUNK - 1) / PAGES_PER_CHUNK)
+            .sum()
+    }
+
+    // Checks that the snapshot was taken of memory laid out like `mem`.
+    fn check_regions(&self, mem: &GuestMemory) -> anyhow::Result<()> {
+        if mem.regions.len() != self.regions.len() {
+            bail!(
+                "snapshot expected {} memory regions but VM has {}",
+                self.regions.len(),
+                mem.regions.len()
+            );
+        }
+        for (region, (guest_base, size)) in mem.regions.iter().zip(self.regions.iter()) {
+            if region.guest_base.0 != *guest_base || region.mapping.size() != *size {
+                bail!("snapshot memory regions don't match VM memory regions");
+            }
+        }
+        if self.page_size != pagesize() {
+            bail!(
+                "snapshot has {} byte pages but the host has {} byte pages",
+                self.page_size,
+                pagesize()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The index of a memory snapshot.
+struct MemorySnapshotIndex {
+    page_states: Vec<u8>,
+    page_hashes: Vec<u128>,
+    chunk_sizes: Vec<u32>,
+}
+
+impl MemorySnapshotIndex {
+    fn read(metadata: &MemorySnapshotMetadata, file: &mut File) -> anyhow::Result<Self> {
+        let num_pages = metadata.num_pages();
+        let num_chunks = metadata.num_chunks();
+        let file_size = file.seek(SeekFrom::End(0))?;
+        if file_size != metadata.index_offset + (num_pages * 17 + num_chunks * 4) as u64 {
+            bail!("memory snapshot has an unexpected size");
+        }
+        file.seek(SeekFrom::Start(metadata.index_offset))?;
+        let mut reader = BufReader::new(file);
+        let mut page_states = vec![0u8; num_pages];
+        reader.read_exact(&mut page_states)?;
+        let mut page_hashes = vec![0u8; num_pages * 16];
+        reader.read_exact(&mut page_hashes)?;
+        let page_hashes = page_hashes
+            .chunks_exact(16)
+            .map(|b| u128::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        let chunk_sizes = read_u32s(&mut reader, num_chunks)?;
+        if page_states.iter().any(|state| *state > PAGE_PARENT) {
+            bail!("memory snapshot index is corrupted");
+        }
+        Ok(MemorySnapshotIndex {
+            page_states,
+            page_hashes,
+            chunk_sizes,
+        })
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.page_states)?;
+        for hash in &self.page_hashes {
+            w.write_all(&hash.to_le_bytes())?;
+        }
+        for size in &self.chunk_sizes {
+            w.write_all(&size.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn read_u32s<R: Read>(r: &mut R, count: usize) -> io::Result<Vec<u32>> {
+    let mut bytes = vec![0u8; count * 4];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .collect())
+}
+
+fn is_dirty(bitmap: &[u8], page: usize) -> bool {
+    bitmap
+        .get(page / 8)
+        .map_or(true, |byte| byte & (1 << (page % 8)) != 0)
+}
+
+// Compresses the data of a chunk. Returns `data` unchanged if it doesn't get smaller.
+fn compress_chunk(compression: MemoryCompression, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let compressed: Vec<u8> = match compression {
+        MemoryCompression::None => return Ok(data),
+        #[cfg(feature = "zstd")]
+        MemoryCompression::Zstd => zstd::bulk::compress(&data, 1)?,
+        #[cfg(feature = "lz4")]
+        MemoryCompression::Lz4 => lz4_flex::block::compress(&data),
+        #[allow(unreachable_patterns)]
+        _ => bail!("crosvm was built without {} support", compression),
+    };
+    if compressed.len() < data.len() {
+        Ok(compressed)
+    } else {
+        Ok(data)
+    }
+}
+
+fn decompress_chunk(
+    compression: MemoryCompression,
+    stored: Vec<u8>,
+    size: usize,
+) -> anyhow::Result<Vec<u8>> {
+    if stored.len() == size {
+        return Ok(stored);
+    }
+    let data: Vec<u8> = match compression {
+        MemoryCompression::None => bail!("memory snapshot chunk has an unexpected size"),
+        #[cfg(feature = "zstd")]
+        MemoryCompression::Zstd => zstd::bulk::decompress(&stored, size)?,
+        #[cfg(feature = "lz4")]
+        MemoryCompression::Lz4 => lz4_flex::block::decompress(&stored, size)?,
+        #[allow(unreachable_patterns)]
+        _ => bail!("crosvm was built without {} support", compression),
+    };
+    if data.len() != size {
+        bail!("memory snapshot chunk decompressed to an unexpected size");
+    }
+    Ok(data)
+}
+
+fn is_zero(data: &[u8]) -> bool {
+    data.iter().all(|b| *b == 0)
+}
+
+impl GuestMemory {
+    // Returns the slice of the page at `page` in region `region_index`.
+    fn snapshot_page(&self, region_index: usize, page: usize, page_size: usize) -> VolatileSlice {
+        let region = &self.regions[region_index];
+        // The regions were checked to match the snapshot, so the page is always in bounds.
+        self.get_slice_at_addr(
+            GuestAddress(region.guest_base.0 + (page * page_size) as u64),
+            page_size,
+        )
+        .expect("snapshot page out of bounds")
+    }
+
+    /// Saves guest memory into `w`. If `parent` is given, the pages that didn't change since the
+    /// parent was taken aren't saved.
+    ///
+    /// Assumes exclusive access to the guest memory for the duration of the call (e.g. all vCPUs
+    /// and devices must be stopped).
+    ///
+    /// Returns a JSON object that contains metadata about the underlying memory regions to allow
+    /// validation checks at restore time.
+    pub fn snapshot(
+        &self,
+        w: &mut File,
+        compression: MemoryCompression,
+        parent: Option<MemorySnapshotParent>,
+    ) -> anyhow::Result<serde_json::Value> {
+        let page_size = pagesize();
+        let mut metadata = MemorySnapshotMetadata {
+            regions: self
+                .regions
+                .iter()
+                .map(|region| (region.guest_base.0, region.mapping.size()))
+                .collect(),
+            page_size,
+            compression,
+            index_offset: 0,
+        };
+
+        let parent = match parent {
+            Some(mut parent) => {
+                let parent_metadata: MemorySnapshotMetadata =
+                    serde_json::from_value(parent.metadata)?;
+                parent_metadata
+                    .check_regions(self)
+                    .context("parent snapshot doesn't match")?;
+                if parent.dirty_log.len() != self.regions.len() {
+                    bail!("no dirty log for some memory regions");
+                }
+                let parent_index = MemorySnapshotIndex::read(&parent_metadata, &mut parent.file)
+                    .context("failed to read parent snapshot")?;
+                Some((parent_index, parent.dirty_log))
+            }
+            None => None,
+        };
+
+        let mut index = MemorySnapshotIndex {
+            page_states: Vec::with_capacity(metadata.num_pages()),
+            page_hashes: Vec::with_capacity(metadata.num_pages()),
+            chunk_sizes: Vec::with_capacity(metadata.num_chunks()),
+        };
+        let mut writer = BufWriter::new(w);
+        let mut page_data = vec![0u8; page_size];
+        for (region_index, region) in self.regions.iter().enumerate() {
+            let num_pages = region.mapping.size() / page_size;
+            for chunk_start in (0..num_pages).step_by(PAGES_PER_CHUNK) {
+                let mut chunk_data = Vec::new();
+                for page in chunk_start..num_pages.min(chunk_start + PAGES_PER_CHUNK) {
+                    self.snapshot_page(region_index, page, page_size)
+                        .copy_to(&mut page_data);
+                    let hash = page_hash(&page_data);
+                    // The dirty log only has the pages written by the guest, so the hash is also
+                    // compared to catch the pages written by devices.
+                    let unchanged = parent.as_ref().map_or(false, |(parent_index, dirty_log)| {
+                        !is_dirty(&dirty_log[region_index], page)
+                            && parent_index.page_hashes[index.page_hashes.len()] == hash
+                    });
+                    let state = if unchanged {
+                        PAGE_PARENT
+                    } else if is_zero(&page_data) {
+                        PAGE_ZERO
+                    } else {
+                        chunk_data.extend_from_slice(&page_data);
+                        PAGE_DATA
+                    };
+                    index.page_states.push(state);
+                    index.page_hashes.push(hash);
+                }
+                let stored = compress_chunk(compression, chunk_data)?;
+                writer.write_all(&stored)?;
+                metadata.index_offset += stored.len() as u64;
+                index.chunk_sizes.push(stored.len() as u32);
+            }
+        }
+        index.write(&mut writer)?;
+        writer.flush()?;
+        Ok(serde_json::to_value(metadata)?)
+    }
+
+    /// Restores guest memory from `snapshots`, a list of the metadata and file of each memory
+    /// snapshot. An incremental snapshot must come right after its parent, so the first snapshot
+    /// must be a full one and the last is the one restored.
+    ///
+    /// Assumes exclusive access to the guest memory for the duration of the call (e.g. all vCPUs
+    /// and devices must be stopped).
+    ///
+    /// Returns an error if the metadata doesn't match the configuration of the `GuestMemory` or if
+    /// a file doesn't have the expected contents.
+    pub fn restore(&self, snapshots: Vec<(serde_json::Value, File)>) -> anyhow::Result<()> {
+        for (layer, (metadata, mut file)) in snapshots.into_iter().enumerate() {
+            let metadata: MemorySnapshotMetadata = serde_json::from_value(metadata)?;
+            metadata.check_regions(self)?;
+            let index = MemorySnapshotIndex::read(&metadata, &mut file)?;
+            if layer == 0 && index.page_states.contains(&PAGE_PARENT) {
+                bail!("memory snapshot needs a parent snapshot");
+            }
+
+            let page_size = metadata.page_size;
+            let mut page_data = vec![0u8; page_size];
+            file.seek(SeekFrom::Start(0))?;
+            let mut reader = BufReader::new(file);
+            let mut page_states = index.page_states.iter();
+            let mut chunk_sizes = index.chunk_sizes.iter();
+            for (region_index, region) in self.regions.iter().enumerate() {
+                let num_pages = region.mapping.size() / page_size;
+                for chunk_start in (0..num_pages).step_by(PAGES_PER_CHUNK) {
+                    let pages = chunk_start..num_pages.min(chunk_start + PAGES_PER_CHUNK);
+                    let states: Vec<u8> = page_states.by_ref().take(pages.len()).copied().collect();
+                    let mut stored = vec![0u8; *chunk_sizes.next().unwrap() as usize];
+                    reader.read_exact(&mut stored)?;
+                    let num_data_pages = states.iter().filter(|s| **s == PAGE_DATA).count();
+                    let chunk_data =
+                        decompress_chunk(metadata.compression, stored, num_data_pages * page_size)?;
+                    let mut chunk_pages = chunk_data.chunks_exact(page_size);
+                    for (page, state) in pages.zip(states) {
+                        let slice = self.snapshot_page(region_index, page, page_size);
+                        match state {
+                            PAGE_DATA => slice.copy_from(chunk_pages.next().unwrap()),
+                            PAGE_ZERO => {
+                                // Pages that are already zero aren't written, so memory that
+                                // the guest never touched stays unallocated.
+                                slice.copy_to(&mut page_data);
+                                if !is_zero(&page_data) {
+                                    slice.write_bytes(0);
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempfile;
+
+    use super::*;
+
+    fn test_memory() -> GuestMemory {
+        let page_size = pagesize() as u64;
+        GuestMemory::new(&[
+            (GuestAddress(0), page_size * 100),
+            (GuestAddress(page_size * 1000), page_size * 3),
+        ])
+        .unwrap()
+    }
+
+    fn fill(mem: &GuestMemory, page: u64, value: u8) {
+        let page_size = pagesize();
+        mem.get_slice_at_addr(GuestAddress(page * page_size as u64), page_size)
+            .unwrap()
+            .write_bytes(value);
+    }
+
+    fn snapshot(
+        mem: &GuestMemory,
+        compression: MemoryCompression,
+        parent: Option<MemorySnapshotParent>,
+    ) -> (serde_json::Value, File) {
+        let mut file = tempfile().unwrap();
+        let metadata = mem.snapshot(&mut file, compression, parent).unwrap();
+        (metadata, file)
+    }
+
+    fn assert_same_contents(a: &GuestMemory, b: &GuestMemory) {
+        for (region_a, region_b) in a.regions().zip(b.regions()) {
+            let mut data_a = vec![0u8; region_a.size];
+            let mut data_b = vec![0u8; region_b.size];
+            a.read_exact_at_addr(&mut data_a, region_a.guest_addr)
+                .unwrap();
+            b.read_exact_at_addr(&mut data_b, region_b.guest_addr)
+                .unwrap();
+            assert!(data_a == data_b, "memory contents differ");
+        }
+    }
+
+    fn round_trip(compression: MemoryCompression) {
+        let mem = test_memory();
+        fill(&mem, 3, 0x55);
+        fill(&mem, 64, 0xaa);
+        fill(&mem, 1001, 0x11);
+        let (metadata, file) = snapshot(&mem, compression, None);
+
+        // Only the three pages with data take space.
+        let page_size = pagesize() as u64;
+        let data_size: u64 = serde_json::from_value::<MemorySnapshotMetadata>(metadata.clone())
+            .unwrap()
+            .index_offset;
+        assert!(data_size <= page_size * 3);
+        assert!(file.metadata().unwrap().len() < page_size * 4);
+
+        let restored = test_memory();
+        fill(&restored, 5, 0x77);
+        restored.restore(vec![(metadata, file)]).unwrap();
+        assert_same_contents(&mem, &restored);
+    }
+
+    #[test]
+    fn uncompressed_round_trip() {
+        round_trip(MemoryCompression::None);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trip() {
+        round_trip(MemoryCompression::Zstd);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_round_trip() {
+        round_trip(MemoryCompression::Lz4);
+    }
+
+    #[test]
+    fn incremental() {
+        let mem = test_memory();
+        fill(&mem, 1, 0x11);
+        fill(&mem, 2, 0x22);
+        fill(&mem, 3, 0x33);
+        let (base_metadata, base_file) = snapshot(&mem, MemoryCompression::None, None);
+
+        // Page 1 is written by the guest, page 2 by a device and page 3 is cleared by the guest.
+        fill(&mem, 1, 0x44);
+        fill(&mem, 2, 0x55);
+        fill(&mem, 3, 0);
+        let dirty_log = vec![vec![0b1010u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], vec![0]];
+        let (metadata, file) = snapshot(
+            &mem,
+            MemoryCompression::None,
+            Some(MemorySnapshotParent {
+                metadata: base_metadata.clone(),
+                file: base_file.try_clone().unwrap(),
+                dirty_log: &dirty_log,
+            }),
+        );
+        let page_size = pagesize() as u64;
+        let data_size = serde_json::from_value::<MemorySnapshotMetadata>(metadata.clone())
+            .unwrap()
+            .index_offset;
+        assert_eq!(data_size, page_size * 2);
+
+        let restored = test_memory();
+        restored
+            .restore(vec![(metadata.clone(), file.try_clone().unwrap())])
+            .expect_err("restored incremental snapshot without its parent");
+        restored
+            .restore(vec![(base_metadata, base_file), (metadata, file)])
+            .unwrap();
+        assert_same_contents(&mem, &restored);
+    }
+
+    #[test]
+    fn mismatched_regions() {
+        let mem = test_memory();
+        let (metadata, file) = snapshot(&mem, MemoryCompression::None, None);
+        let page_size = pagesize() as u64;
+        let other = GuestMemory::new(&[(GuestAddress(0), page_size * 100)]).unwrap();
+        other
+            .restore(vec![(metadata, file)])
+            .expect_err("restored to different memory layout");
+    }
+
+    #[test]
+    fn parse_compression() {
+        for compression in [
+            MemoryCompression::None,
+            MemoryCompression::Zstd,
+            MemoryCompression::Lz4,
+        ] {
+            assert_eq!(
+                compression.to_string().parse::<MemoryCompression>(),
+                Ok(compression)
+            );
+        }
+        assert!("gzip".parse::<MemoryCompression>().is_err());
+    }
+}
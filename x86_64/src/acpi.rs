@@ -176,6 +176,41 @@ const MCFG_FIELD_START_BUS_NUMBER: usize = 54;
 const MCFG_FIELD_END_BUS_NUMBER: usize = 55;
 
 const SSDT_REVISION: u8 = 2;
+
+// TPM2
+const TPM2_LEN: u32 = 64;
+const TPM2_REVISION: u8 = 4;
+const TPM2_FIELD_CONTROL_AREA: usize = 40;
+const TPM2_FIELD_START_METHOD: usize = 48;
+const TPM2_START_METHOD_CRB: u32 = 7;
+
+/// Creates the TPM2 table of a TPM with a CRB interface whose control area is at `control_area`,
+/// and the SSDT describing the device with `device_aml`.
+pub fn create_tpm_tables(control_area: u64, device_aml: &[u8]) -> [SDT; 2] {
+    // The platform class is left at 0, for a client platform.
+    let mut tpm2 = SDT::new(
+        *b"TPM2",
+        TPM2_LEN,
+        TPM2_REVISION,
+        *b"CROSVM",
+        *b"CROSVMDT",
+        OEM_REVISION,
+    );
+    tpm2.write(TPM2_FIELD_CONTROL_AREA, control_area);
+    tpm2.write(TPM2_FIELD_START_METHOD, TPM2_START_METHOD_CRB);
+
+    let mut ssdt = SDT::new(
+        *b"SSDT",
+        acpi_tables::HEADER_LEN,
+        SSDT_REVISION,
+        *b"CROSVM",
+        *b"CROSVMTP",
+        OEM_REVISION,
+    );
+    ssdt.append_slice(&aml::Scope::raw("\\_SB_".into(), device_aml.to_vec()));
+
+    [tpm2, ssdt]
+}
 pub fn create_customize_ssdt(
     pci_root: Arc<Mutex<PciRoot>>,
     amls: BTreeMap<PciAddress, Vec<u8>>,
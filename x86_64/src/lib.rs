@@ -72,6 +72,10 @@ use chrono::Utc;
 pub use cpuid::adjust_cpuid;
 pub use cpuid::CpuIdContext;
 use devices::acpi::PM_WAKEUP_GPIO;
+use devices::tpm::TpmBackend;
+use devices::tpm::TpmCrb;
+use devices::tpm::TPM_CRB_CONTROL_AREA_OFFSET;
+use devices::tpm::TPM_CRB_MMIO_SIZE;
 use devices::Bus;
 use devices::BusDevice;
 use devices::BusDeviceObj;
@@ -358,6 +362,8 @@ pub const X86_64_SCI_IRQ: u32 = 5;
 // The CMOS RTC uses IRQ 8; start allocating IRQs at 9.
 pub const X86_64_IRQ_BASE: u32 = 9;
 const ACPI_HI_RSDP_WINDOW_BASE: u64 = 0x000E_0000;
+// The TPM CRB registers, where the firmware of PCs expects them.
+const TPM_CRB_MMIO_BASE: u64 = 0xfed4_0000;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum CpuManufacturer {
@@ -875,6 +881,10 @@ impl arch::LinuxArch for X8664arch {
             )?;
         }
 
+        if let Some(tpm) = components.tpm.take() {
+            Self::setup_tpm_crb(tpm, &mmio_bus, &mut components.acpi_sdts)?;
+        }
+
         // Functions that use/create jails MUST be used before the call to
         // setup_acpi_devices below, as this move us into a multiprocessing state
         // from which we can no longer fork.
@@ -1561,6 +1571,35 @@ impl X8664arch {
         Ok(())
     }
 
+    /// Sets up the TPM CRB device, and the ACPI tables describing it.
+    ///
+    /// # Arguments
+    ///
+    /// * - `backend` - the backend executing the TPM commands
+    /// * - `mmio_bus` - the MMIO bus to add the device to
+    /// * - `sdts` - ACPI system description tables to add the tables to
+    fn setup_tpm_crb(
+        backend: Box<dyn TpmBackend>,
+        mmio_bus: &Bus,
+        sdts: &mut Vec<SDT>,
+    ) -> Result<()> {
+        let tpm = TpmCrb::new(backend, TPM_CRB_MMIO_BASE);
+        let mut aml = Vec::new();
+        tpm.to_aml_bytes(&mut aml);
+        sdts.extend(acpi::create_tpm_tables(
+            TPM_CRB_MMIO_BASE + TPM_CRB_CONTROL_AREA_OFFSET,
+            &aml,
+        ));
+        mmio_bus
+            .insert(
+                Arc::new(Mutex::new(tpm)),
+                TPM_CRB_MMIO_BASE,
+                TPM_CRB_MMIO_SIZE,
+            )
+            .map_err(Error::InsertBus)?;
+        Ok(())
+    }
+
     /// Loads the kernel from an open file.
     ///
     /// # Arguments